@@ -1,84 +1,469 @@
+pub mod account_store;
 pub mod banks;
+pub mod candidates;
+pub mod competitors;
+pub mod confirmations;
+pub mod events;
+pub mod hydration;
 pub mod marginfi_accounts;
+pub mod metrics;
+pub mod mints;
+pub mod prices;
+pub mod priority_fees;
+pub mod reconciliation;
 pub mod snapshot;
+pub mod snapshot_mirror;
 
 mod luts;
-mod mints;
 mod oracles;
+mod token_accounts;
+mod versions;
 
 use mints::MintsCache;
-use oracles::OraclesCache;
+use oracles::{is_supported_oracle, OraclesCache};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt,
     sync::{Arc, RwLock},
+    time::{Duration, Instant},
 };
+use token_accounts::WalletTokenAccountsCache;
+use versions::UpdateVersions;
 
 use anyhow::{anyhow, Result};
-use log::{error, info, trace};
-use marginfi::state::{marginfi_account::MarginfiAccount, marginfi_group::Bank};
-use solana_program::clock::Clock;
-use solana_sdk::{
-    account::Account,
-    address_lookup_table::{state::AddressLookupTable, AddressLookupTableAccount},
-    pubkey::Pubkey,
-};
-
-use anchor_lang::AccountDeserialize;
+use fixed::types::I80F48;
+use log::{debug, error, info, trace, warn};
+use marginfi::state::price::OracleSetup;
+use solana_program::clock::{Clock, Slot, UnixTimestamp};
+use solana_sdk::{account::Account, clock::DEFAULT_MS_PER_SLOT, pubkey::Pubkey, signer::Signer};
 
 use crate::{
-    cache::{banks::BanksCache, luts::LutsCache, marginfi_accounts::MarginfiAccountsCache},
+    cache::{
+        banks::{BanksCache, CachedBank},
+        candidates::RankedCandidates,
+        competitors::CompetitorLiquidations,
+        confirmations::ProcessedConfirmations,
+        events::CacheEvents,
+        luts::LutsCache,
+        marginfi_accounts::{CachedMarginfiAccount, MarginfiAccountsCache},
+        metrics::{CacheHealthMetrics, CacheMetrics, StoreEntries},
+        prices::CachedPrice,
+        priority_fees::PriorityFees,
+        snapshot::now_unix,
+    },
     common::{get_marginfi_message_type, MessageType},
-    comms::CommsClient,
+    comms::{CommsClient, MarginfiAccountHeader, ProgramAccounts},
     config::Config,
+    liquidation::{
+        health::{compute_account_health, AccountHealth},
+        profitability::ProfitEstimates,
+    },
 };
 
+// The clock is extrapolated over the short Geyser gaps only, not over an outage.
+const MAX_CLOCK_EXTRAPOLATION: Duration = Duration::from_secs(30);
+
 // TODO: not completely sure that this trait is really needed.
 #[allow(dead_code)]
 pub trait CacheEntry {}
 
+/// The approximate memory held by the cache sections, in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheMemoryUsage {
+    pub accounts: usize,
+    pub banks: usize,
+    pub oracles: usize,
+}
+
+impl CacheMemoryUsage {
+    pub fn total(&self) -> usize {
+        self.accounts + self.banks + self.oracles
+    }
+}
+
+impl fmt::Display for CacheMemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mib = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+        write!(
+            f,
+            "Accounts: {:.1} MiB; Banks: {:.1} MiB; Oracles: {:.1} MiB",
+            mib(self.accounts),
+            mib(self.banks),
+            mib(self.oracles)
+        )
+    }
+}
+
+/// The latest clock update, and when it was received.
+struct ObservedClock {
+    clock: Clock,
+    observed_at: Instant,
+    // The latest extrapolation before the update, which the next ones never go behind.
+    floor: (Slot, UnixTimestamp),
+    // The slot of the latest clock sysvar update, the slot notifications advancing the slot only.
+    sysvar_slot: Slot,
+}
+
+impl ObservedClock {
+    fn new(clock: Clock, observed_at: Instant) -> Self {
+        Self {
+            floor: (clock.slot, clock.unix_timestamp),
+            sysvar_slot: clock.slot,
+            clock,
+            observed_at,
+        }
+    }
+
+    fn update(&mut self, clock: Clock, now: Instant) {
+        let extrapolated = self.extrapolate(now);
+        *self = Self {
+            floor: (extrapolated.slot, extrapolated.unix_timestamp),
+            sysvar_slot: self.sysvar_slot,
+            clock,
+            observed_at: now,
+        };
+    }
+
+    /// Applies the clock sysvar, unless the one of a later slot was applied already, e.g. streamed by the other
+    /// Geyser endpoint first. The slot never goes behind the slot notifications. Returns whether it was applied.
+    fn update_sysvar(&mut self, mut clock: Clock, now: Instant) -> bool {
+        if clock.slot < self.sysvar_slot {
+            return false;
+        }
+        let sysvar_slot = clock.slot;
+        clock.slot = clock.slot.max(self.clock.slot);
+        self.update(clock, now);
+        self.sysvar_slot = sysvar_slot;
+        true
+    }
+
+    /// Advances the slot of the clock, its other fields waiting for the next clock update.
+    fn advance_slot(&mut self, slot: Slot, now: Instant) {
+        if slot > self.clock.slot {
+            let mut clock = self.clock.clone();
+            clock.slot = slot;
+            self.update(clock, now);
+        }
+    }
+
+    /// The clock advanced by the time elapsed since its update, up to the max extrapolation.
+    fn extrapolate(&self, now: Instant) -> Clock {
+        let elapsed = now
+            .saturating_duration_since(self.observed_at)
+            .min(MAX_CLOCK_EXTRAPOLATION);
+        let mut clock = self.clock.clone();
+        clock.slot =
+            (clock.slot + elapsed.as_millis() as u64 / DEFAULT_MS_PER_SLOT).max(self.floor.0);
+        clock.unix_timestamp = (clock.unix_timestamp + elapsed.as_secs() as i64).max(self.floor.1);
+        clock
+    }
+}
+
 pub struct Cache {
-    pub clock: RwLock<Clock>,
+    clock: RwLock<ObservedClock>,
     pub marginfi_accounts: MarginfiAccountsCache,
     pub banks: BanksCache,
     pub mints: MintsCache,
     pub oracles: OraclesCache,
     pub luts: LutsCache,
+    pub wallet_token_accounts: WalletTokenAccountsCache,
+    pub events: CacheEvents,
+    pub update_versions: UpdateVersions,
+    pub metrics: CacheMetrics,
+    pub candidates: RankedCandidates,
+    pub competitors: CompetitorLiquidations,
+    pub priority_fees: PriorityFees,
+    pub profit_estimates: ProfitEstimates,
+    pub confirmations: ProcessedConfirmations,
 }
 
 impl Cache {
     pub fn new(clock: Clock) -> Self {
         Self {
-            clock: RwLock::new(clock),
+            clock: RwLock::new(ObservedClock::new(clock, Instant::now())),
             marginfi_accounts: MarginfiAccountsCache::default(),
             banks: BanksCache::default(),
             mints: MintsCache::default(),
             oracles: OraclesCache::default(),
             luts: LutsCache::default(),
+            wallet_token_accounts: WalletTokenAccountsCache::default(),
+            events: CacheEvents::default(),
+            update_versions: UpdateVersions::default(),
+            metrics: CacheMetrics::default(),
+            candidates: RankedCandidates::default(),
+            competitors: CompetitorLiquidations::default(),
+            priority_fees: PriorityFees::default(),
+            profit_estimates: ProfitEstimates::default(),
+            confirmations: ProcessedConfirmations::default(),
         }
     }
 
+    /// Drops the Marginfi account closed at the slot from the cache and from the ranked candidates. Returns
+    /// whether it was cached.
+    pub fn remove_closed_account(&self, slot: u64, address: &Pubkey) -> Result<bool> {
+        self.candidates.remove(address)?;
+        self.marginfi_accounts.remove_closed(slot, address)
+    }
+
+    /// The cache with the Marginfi accounts store of the config.
+    pub fn open(config: &Config, clock: Clock) -> Result<Self> {
+        Ok(Self {
+            marginfi_accounts: MarginfiAccountsCache::open(config)?,
+            ..Self::new(clock)
+        })
+    }
+
+    /// Updates the clock from the clock sysvar, as streamed by Geyser, ignoring the ones older than the cached one.
     pub fn update_clock(&self, clock: Clock) -> Result<()> {
         trace!("Updating Clock in cache: {:?}", clock);
-        *self
+        let slot = clock.slot;
+        if !self
             .clock
             .write()
-            .map_err(|e| anyhow!("Failed to lock Clock for the update: {}", e))? = clock;
+            .map_err(|e| anyhow!("Failed to lock Clock for the update: {}", e))?
+            .update_sysvar(clock, Instant::now())
+        {
+            trace!("Ignoring the Clock of the past slot {}", slot);
+        }
+        Ok(())
+    }
+
+    /// Advances the clock to the slot of a Geyser slot notification, unless it is already there.
+    pub fn advance_clock_slot(&self, slot: Slot) -> Result<()> {
+        trace!("Advancing the Clock in cache to the slot {}", slot);
+        self.clock
+            .write()
+            .map_err(|e| anyhow!("Failed to lock Clock for the update: {}", e))?
+            .advance_slot(slot, Instant::now());
         Ok(())
     }
 
+    /// The clock as last updated, e.g. for the minimum context slot of the RPC reads.
     pub fn get_clock(&self) -> Result<Clock> {
         Ok(self
             .clock
             .read()
             .map_err(|e| anyhow!("Failed to lock Clock for reading: {}", e))?
+            .clock
             .clone())
     }
+
+    /// The clock with the slot and the timestamp extrapolated since the last update, for the time dependent
+    /// math to stay accurate over the short Geyser gaps. It never goes backwards, the updates included.
+    pub fn get_extrapolated_clock(&self) -> Result<Clock> {
+        Ok(self
+            .clock
+            .read()
+            .map_err(|e| anyhow!("Failed to lock Clock for reading: {}", e))?
+            .extrapolate(Instant::now()))
+    }
+
+    /// Registers the supported oracles of the Banks which are not cached yet, e.g. of a new Bank or of a Bank
+    /// whose oracle changed, for the Geyser subscriptions to include them without a restart.
+    pub fn register_bank_oracles(&self, banks: &[CachedBank]) {
+        for bank in banks {
+            if !is_supported_oracle(&bank.oracle_type()) {
+                continue;
+            }
+            for oracle in bank.oracle_addresses() {
+                if self.oracles.register(oracle, bank.oracle_type()) {
+                    info!(
+                        "Registered the Oracle {} of the Bank {}, priced from its next update.",
+                        oracle, bank.address
+                    );
+                }
+            }
+        }
+    }
+
+    /// The types of the supported oracles referenced by the cached Banks (an oracle can be shared by several Banks).
+    pub fn supported_oracle_types(&self) -> Result<HashMap<Pubkey, OracleSetup>> {
+        let mut oracle_types: HashMap<Pubkey, OracleSetup> = HashMap::new();
+        for oracle_data in self.banks.get_oracles_data()? {
+            if !is_supported_oracle(&oracle_data.oracle_type) {
+                debug!(
+                    "Skipping the unsupported {:?} oracles {:?}",
+                    oracle_data.oracle_type, oracle_data.oracle_addresses
+                );
+                continue;
+            }
+            for oracle_address in oracle_data.oracle_addresses {
+                oracle_types.insert(oracle_address, oracle_data.oracle_type);
+            }
+        }
+        Ok(oracle_types)
+    }
+
+    pub fn memory_usage(&self) -> Result<CacheMemoryUsage> {
+        Ok(CacheMemoryUsage {
+            accounts: self.marginfi_accounts.memory_bytes(),
+            banks: self.banks.memory_bytes()?,
+            oracles: self.oracles.memory_bytes(),
+        })
+    }
+
+    /// The entries of the stores, the update counts, the update rates of the last sampling and the last snapshot
+    /// metrics.
+    pub fn health_metrics(&self) -> Result<CacheHealthMetrics> {
+        Ok(CacheHealthMetrics {
+            entries: StoreEntries {
+                marginfi_accounts: self.marginfi_accounts.count(),
+                banks: self.banks.count()?,
+                oracles: self.oracles.count(),
+                mints: self.mints.count()?,
+            },
+            update_counts: self.metrics.update_counts(),
+            update_rates: self.metrics.update_rates()?,
+            last_restore_ms: self.metrics.last_restore_ms(),
+            last_persist_ms: self.metrics.last_persist_ms(),
+            last_snapshot_age_sec: self.metrics.last_snapshot_age_sec(now_unix()),
+            confirmations: self.confirmations.stats()?,
+        })
+    }
+
+    /// Evicts the healthiest Marginfi accounts until the cache holds about `max_bytes`, the Banks and Oracles
+    /// being all needed. Returns the number of evicted accounts.
+    pub fn enforce_memory_cap(&self, max_bytes: usize) -> Result<usize> {
+        let usage = self.memory_usage()?;
+        if usage.total() <= max_bytes {
+            return Ok(0);
+        }
+        self.marginfi_accounts
+            .evict_healthiest(max_bytes.saturating_sub(usage.banks + usage.oracles))
+    }
+
+    /// The Bank or Oracle of the account positions updated the earliest, with its slot. The Oracles with no
+    /// price yet are not considered.
+    pub fn stalest_dependency(
+        &self,
+        account: &CachedMarginfiAccount,
+    ) -> Result<Option<(Pubkey, u64)>> {
+        let mut stalest: Option<(Pubkey, u64)> = None;
+        for balance in account._positions() {
+            let bank = self.banks.get_bank(&balance.bank_pk)?;
+            let oracle_slots = bank
+                .oracle_addresses()
+                .iter()
+                .filter_map(|oracle| self.oracles.get_slot(oracle).map(|slot| (*oracle, slot)));
+            for (address, slot) in std::iter::once((bank.address, bank.slot)).chain(oracle_slots) {
+                if stalest.map_or(true, |(_, stalest_slot)| slot < stalest_slot) {
+                    stalest = Some((address, slot));
+                }
+            }
+        }
+        Ok(stalest)
+    }
+
+    /// The first valid price of the Bank Oracles, like the health computation.
+    pub fn get_bank_price(&self, bank: &CachedBank, unix_timestamp: i64) -> Option<I80F48> {
+        bank.oracle_addresses().iter().find_map(|oracle| {
+            self.oracles
+                .get_valid_price(oracle, bank.oracle_max_age(), unix_timestamp)
+        })
+    }
+
+    /// The USD value of the native token amount of the Bank mint, at the first valid price of the Bank Oracles.
+    pub fn get_value_usd(
+        &self,
+        bank: &CachedBank,
+        amount: u64,
+        unix_timestamp: i64,
+    ) -> Option<f64> {
+        let price = self.get_bank_price(bank, unix_timestamp)?;
+        I80F48::from_num(amount)
+            .checked_div(I80F48::from_num(
+                10u64.checked_pow(bank.mint_decimals() as u32)?,
+            ))?
+            .checked_mul(price)
+            .map(|value| value.to_num())
+    }
+
+    /// The USD value of the native token amount of the mint, priced by the first of its Banks with a valid price.
+    pub fn get_mint_value_usd(
+        &self,
+        mint: &Pubkey,
+        amount: u64,
+        unix_timestamp: i64,
+    ) -> Result<Option<f64>> {
+        Ok(self
+            .banks
+            .get_banks()?
+            .iter()
+            .filter(|bank| bank.mint() == mint)
+            .find_map(|bank| self.get_value_usd(bank, amount, unix_timestamp)))
+    }
+
+    /// The initial and maintenance health of the account at the cached Bank share values, projected to the
+    /// timestamp, and Oracle prices. None when a Bank or a valid price of the positions is missing.
+    pub fn compute_account_health(
+        &self,
+        account: &CachedMarginfiAccount,
+        unix_timestamp: i64,
+    ) -> Option<AccountHealth> {
+        compute_account_health(
+            account._positions(),
+            |address| self.get_priced_bank(address, unix_timestamp),
+            unix_timestamp,
+        )
+    }
+
+    /// The Bank with the first valid price of its Oracles, with its confidence interval.
+    pub fn get_priced_bank(
+        &self,
+        address: &Pubkey,
+        unix_timestamp: i64,
+    ) -> Option<(CachedBank, CachedPrice)> {
+        let bank = self.banks.get_bank(address).ok()?;
+        let price = bank.oracle_addresses().iter().find_map(|oracle| {
+            self.oracles
+                .get_cached_price(oracle)
+                .filter(|price| price.is_valid(bank.oracle_max_age(), unix_timestamp))
+        })?;
+        Some((bank, price))
+    }
+
+    /// The maintenance health of the account at the cached Bank share values, projected to the timestamp, and
+    /// Oracle prices. Falls back to the health cached on-chain when a Bank or a price of the positions is missing.
+    pub fn compute_health(
+        &self,
+        account: &CachedMarginfiAccount,
+        unix_timestamp: i64,
+    ) -> Option<i64> {
+        match self.compute_account_health(account, unix_timestamp) {
+            Some(health) => health.maintenance.health(),
+            None => account.health(),
+        }
+    }
+
+    /// Recomputes the health of the accounts updated since the last refresh, and of the accounts with
+    /// positions in the Banks or priced by the Oracles updated since. Returns the number of recomputed accounts.
+    pub fn refresh_health(&self) -> Result<usize> {
+        let mut updated_banks = self.banks.take_updated()?;
+        let updated_oracles = self.oracles.take_updated()?;
+        if !updated_oracles.is_empty() {
+            for bank in self.banks.get_banks()? {
+                if bank
+                    .oracle_addresses()
+                    .iter()
+                    .any(|oracle| updated_oracles.contains(oracle))
+                {
+                    updated_banks.insert(bank.address);
+                }
+            }
+        }
+        let unix_timestamp = self.get_extrapolated_clock()?.unix_timestamp;
+        self.marginfi_accounts
+            .recompute_health(&updated_banks, |account| {
+                self.compute_health(account, unix_timestamp)
+            })
+    }
 }
 
 //TODO: consider moving out to it's own module if it grows larger
 pub struct CacheLoader<T: CommsClient> {
     program_id: Pubkey,
     lut_addresses: Vec<Pubkey>,
+    liquidator: Pubkey,
     cache: Arc<Cache>,
     comms_client: T,
 }
@@ -90,6 +475,7 @@ impl<T: CommsClient> CacheLoader<T> {
         Ok(Self {
             program_id: config.marginfi_program_id,
             lut_addresses,
+            liquidator: config.wallet.pubkey(),
             comms_client,
             cache,
         })
@@ -105,31 +491,76 @@ impl<T: CommsClient> CacheLoader<T> {
         self.load_mints()?;
         self.load_oracles()?;
         self.load_luts()?;
+        self.load_wallet_token_accounts()?;
+        Ok(())
+    }
+
+    /// Loads the auxiliary accounts a restored snapshot lacks: the Mints and the Oracles of the Banks
+    /// created since (or all of them for the snapshots without them), the LUTs and the wallet token accounts.
+    pub fn load_missing_auxiliary_accounts(&self) -> Result<()> {
+        let cached_oracles: HashSet<Pubkey> = self
+            .cache
+            .oracles
+            .get_oracle_addresses()
+            .into_iter()
+            .collect();
+        self.load_mints_where(|address| matches!(self.cache.mints.get(address), Ok(None)))?;
+        self.load_oracles_where(|address| !cached_oracles.contains(address))?;
+        self.load_luts()?;
+        self.load_wallet_token_accounts()?;
         Ok(())
     }
 
     pub fn load_accounts(&self) -> Result<()> {
         info!("Loading Accounts for the Program id {}...", self.program_id);
 
-        let slot = self.cache.get_clock()?.slot;
+        // Geyser may have already updated the cache, so older results would regress its state.
+        let min_context_slot = self.cache.get_clock()?.slot;
+        let program_accounts = self
+            .comms_client
+            .get_program_accounts(&self.program_id, min_context_slot)?;
+        debug!(
+            "Fetched the program accounts at the slot {} (cache slot {})",
+            program_accounts.context_slot, min_context_slot
+        );
+
+        self.load_program_accounts(program_accounts)
+    }
+
+    /// Loads the Banks, and returns the headers of the Marginfi accounts to hydrate. The Marginfi accounts
+    /// are loaded in full, with no headers, by the clients that cannot fetch the headers.
+    pub fn load_account_headers(&self) -> Result<Vec<MarginfiAccountHeader>> {
+        info!(
+            "Loading the Account headers for the Program id {}...",
+            self.program_id
+        );
+
+        let min_context_slot = self.cache.get_clock()?.slot;
+        let (program_accounts, headers) = self
+            .comms_client
+            .get_program_account_headers(&self.program_id, min_context_slot)?;
+        self.load_program_accounts(program_accounts)?;
 
-        let accounts = self.comms_client.get_program_accounts(&self.program_id)?;
+        info!("Loaded the headers of {} Marginfi accounts.", headers.len());
+        Ok(headers)
+    }
+
+    /// Loads the Marginfi accounts and the Banks of the program accounts, however they were fetched.
+    pub fn load_program_accounts(&self, program_accounts: ProgramAccounts) -> Result<()> {
+        let slot = program_accounts.context_slot;
         let mut marginfi_accounts_count = 0;
         let mut banks_count = 0;
-        for (address, account) in accounts {
+        for (address, account) in program_accounts.accounts {
             match get_marginfi_message_type(&account.data) {
                 Some(MessageType::MarginfiAccount) => {
-                    let marginfi_account: MarginfiAccount =
-                        MarginfiAccount::try_deserialize(&mut account.data.as_slice())?;
                     self.cache
                         .marginfi_accounts
-                        .update(slot, address, marginfi_account)?;
+                        .update_data(slot, address, &account.data)?;
                     trace!("Added the Marginfi Account {:?} to cache.", address);
                     marginfi_accounts_count += 1;
                 }
                 Some(MessageType::Bank) => {
-                    let bank: Bank = Bank::try_deserialize(&mut account.data.as_slice())?;
-                    self.cache.banks.update(slot, address, &bank)?;
+                    self.cache.banks.update_data(slot, address, &account.data)?;
                     info!("Added the Bank {:?} to cache.", address);
                     banks_count += 1;
                 }
@@ -148,13 +579,26 @@ impl<T: CommsClient> CacheLoader<T> {
     }
 
     pub fn load_mints(&self) -> Result<()> {
+        self.load_mints_where(|_| true)
+    }
+
+    fn load_mints_where(&self, include: impl Fn(&Pubkey) -> bool) -> Result<()> {
         info!("Loading Mints...");
 
-        let mint_addresses = self.cache.banks.get_mints()?;
+        let mint_addresses: Vec<Pubkey> = self
+            .cache
+            .banks
+            .get_mints()?
+            .into_iter()
+            .filter(|address| include(address))
+            .collect();
 
         let mut mints_counter = 0;
         for (address, mint) in self.comms_client.get_accounts(&mint_addresses)? {
-            self.cache.mints.update(address, &mint)?;
+            if let Err(err) = self.cache.mints.update(address, &mint) {
+                warn!("Skipping the Mint {}: {}", address, err);
+                continue;
+            }
             info!("Added the Mint {:?} to cache.", address);
             mints_counter += 1;
         }
@@ -164,51 +608,63 @@ impl<T: CommsClient> CacheLoader<T> {
     }
 
     pub fn load_oracles(&self) -> Result<()> {
+        self.load_oracles_where(|_| true)
+    }
+
+    fn load_oracles_where(&self, include: impl Fn(&Pubkey) -> bool) -> Result<()> {
         info!("Loading Oracles...");
 
         let slot = self.cache.get_clock()?.slot;
 
-        let oracles_data = self.cache.banks.get_oracles_data()?;
-        let oracle_addresses: Vec<Pubkey> = oracles_data
-            .iter()
-            .flat_map(|oracle: &banks::CachedBankOracle| oracle.oracle_addresses.clone())
-            .collect();
+        let mut oracle_counter = 0;
+        for (oracle_address, (oracle_type, account)) in self.fetch_oracle_accounts(include)? {
+            if let Err(err) = self
+                .cache
+                .oracles
+                .insert(slot, &oracle_address, oracle_type, account)
+            {
+                error!(
+                    "Failed to add Oracle {:?} to cache: {}",
+                    oracle_address, err
+                );
+            } else {
+                info!("Added the Oracle {:?} to cache.", oracle_address);
+                oracle_counter += 1;
+            }
+        }
 
-        let oracle_accounts: HashMap<Pubkey, Account> = self
+        info!("Loaded {} Oracles.", oracle_counter);
+        Ok(())
+    }
+
+    // Collects the selected supported oracles referenced by the cached Banks and fetches them in batches.
+    fn fetch_oracle_accounts(
+        &self,
+        include: impl Fn(&Pubkey) -> bool,
+    ) -> Result<HashMap<Pubkey, (OracleSetup, Account)>> {
+        let mut oracle_types = self.cache.supported_oracle_types()?;
+        oracle_types.retain(|address, _| include(address));
+
+        let oracle_addresses: Vec<Pubkey> = oracle_types.keys().copied().collect();
+        let mut fetched_accounts: HashMap<Pubkey, Account> = self
             .comms_client
             .get_accounts(&oracle_addresses)?
             .into_iter()
             .collect();
 
-        let mut oracle_counter = 0;
-        for oracle_data in oracles_data {
-            for oracle_address in oracle_data.oracle_addresses {
-                match oracle_accounts.get(&oracle_address) {
-                    Some(account) => {
-                        if let Err(err) = self.cache.oracles.insert(
-                            slot,
-                            &oracle_address,
-                            oracle_data.oracle_type,
-                            account.clone(),
-                        ) {
-                            error!(
-                                "Failed to add Oracle {:?} to cache: {}",
-                                oracle_address, err
-                            );
-                        } else {
-                            info!("Added the Oracle {:?} to cache.", oracle_address);
-                            oracle_counter += 1;
-                        }
-                    }
-                    None => {
-                        error!("Failed to fetch the Oracle account {}", oracle_address);
-                    }
+        let mut oracle_accounts = HashMap::with_capacity(fetched_accounts.len());
+        for (oracle_address, oracle_type) in oracle_types {
+            match fetched_accounts.remove(&oracle_address) {
+                Some(account) => {
+                    oracle_accounts.insert(oracle_address, (oracle_type, account));
+                }
+                None => {
+                    error!("Failed to fetch the Oracle account {}", oracle_address);
                 }
             }
         }
 
-        info!("Loaded {} Oracles.", oracle_counter);
-        Ok(())
+        Ok(oracle_accounts)
     }
 
     pub fn load_luts(&self) -> Result<()> {
@@ -219,17 +675,9 @@ impl<T: CommsClient> CacheLoader<T> {
 
         info!("Loading Luts...");
 
-        let lut_accounts = self.comms_client.get_accounts(&self.lut_addresses)?;
-
-        let mut luts: Vec<AddressLookupTableAccount> = Vec::new();
-        for (lut_address, lut_account) in lut_accounts {
-            let lut = AddressLookupTable::deserialize(&lut_account.data)
-                .map_err(|e| anyhow!("Failed to deserialize the {} LUT : {:?}", lut_address, e))?;
-            luts.push(AddressLookupTableAccount {
-                key: lut_address,
-                addresses: lut.addresses.to_vec(),
-            });
-        }
+        let luts = self
+            .comms_client
+            .get_address_lookup_tables(&self.lut_addresses)?;
 
         let luts_total = luts.len();
         self.cache.luts.populate(luts)?;
@@ -237,6 +685,26 @@ impl<T: CommsClient> CacheLoader<T> {
         info!("Loaded {} Luts.", luts_total);
         Ok(())
     }
+
+    pub fn load_wallet_token_accounts(&self) -> Result<()> {
+        info!(
+            "Loading the token accounts of the liquidator {}...",
+            self.liquidator
+        );
+
+        // Geyser may have already updated the accounts, so they are loaded as of the cache slot.
+        let slot = self.cache.get_clock()?.slot;
+        let token_accounts = self
+            .comms_client
+            .get_token_accounts_by_owner(&self.liquidator)?;
+        let loaded = self
+            .cache
+            .wallet_token_accounts
+            .load(slot, &token_accounts)?;
+
+        info!("Loaded {} wallet token accounts.", loaded);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -271,9 +739,14 @@ pub mod test_util {
 #[cfg(test)]
 mod tests {
     use super::test_util::generate_test_clock;
-    use crate::cache::{banks::test_util::create_bank_with_oracles, test_util::create_dummy_cache};
+    use crate::cache::{
+        banks::test_util::create_bank_with_oracles,
+        marginfi_accounts::test_util::{create_balance, create_marginfi_account},
+        test_util::create_dummy_cache,
+    };
     use crate::comms::test_util::MockedCommsClient;
     use crate::config::test_util::create_dummy_config;
+    use crate::liquidation::wallet::test_util::create_token_account;
     use solana_sdk::pubkey::Pubkey;
     use solana_sdk::{account::Account, address_lookup_table::state::LookupTableMeta};
     use solana_sdk::{address_lookup_table::state::AddressLookupTable, signature::Keypair};
@@ -288,6 +761,22 @@ mod tests {
         assert_eq!(cache.get_clock().unwrap().slot, 1);
     }
 
+    #[test]
+    fn test_register_bank_oracles() {
+        let cache = create_dummy_cache();
+        let oracle = Pubkey::new_unique();
+        let bank = CachedBank::from(
+            1,
+            Pubkey::new_unique(),
+            create_bank_with_oracles(vec![oracle]),
+        );
+
+        cache.register_bank_oracles(&[bank.clone()]);
+        cache.register_bank_oracles(&[bank]);
+        assert_eq!(cache.oracles.get_oracle_addresses(), vec![oracle]);
+        assert_eq!(cache.oracles.generation(), 1);
+    }
+
     #[test]
     fn test_cache_update_clock() {
         let initial_clock = generate_test_clock(1);
@@ -307,6 +796,153 @@ mod tests {
         assert_eq!(cached_clock.unix_timestamp, updated_clock.unix_timestamp);
     }
 
+    #[test]
+    fn test_clock_extrapolation() {
+        let start = Instant::now();
+        let mut observed = ObservedClock::new(generate_test_clock(100), start);
+        let timestamp = observed.clock.unix_timestamp;
+
+        let extrapolated = observed.extrapolate(start + Duration::from_secs(2));
+        assert_eq!(extrapolated.slot, 105);
+        assert_eq!(extrapolated.unix_timestamp, timestamp + 2);
+        // Up to the max extrapolation.
+        let extrapolated = observed.extrapolate(start + Duration::from_secs(3600));
+        assert_eq!(
+            extrapolated.slot,
+            100 + MAX_CLOCK_EXTRAPOLATION.as_millis() as u64 / DEFAULT_MS_PER_SLOT
+        );
+
+        // An update behind the extrapolation does not move it backwards.
+        let mut behind = generate_test_clock(101);
+        behind.unix_timestamp = timestamp;
+        observed.update(behind, start + Duration::from_secs(2));
+        assert_eq!(observed.clock.slot, 101);
+        let extrapolated = observed.extrapolate(start + Duration::from_secs(2));
+        assert_eq!(extrapolated.slot, 105);
+        assert_eq!(extrapolated.unix_timestamp, timestamp + 2);
+        assert_eq!(
+            observed
+                .extrapolate(start + Duration::from_secs(6))
+                .unix_timestamp,
+            timestamp + 4
+        );
+    }
+
+    #[test]
+    fn test_clock_advance_slot() {
+        let start = Instant::now();
+        let mut observed = ObservedClock::new(generate_test_clock(100), start);
+        let timestamp = observed.clock.unix_timestamp;
+
+        observed.advance_slot(103, start + Duration::from_millis(800));
+        assert_eq!(observed.clock.slot, 103);
+        assert_eq!(observed.clock.unix_timestamp, timestamp);
+        // Extrapolated from the advanced slot.
+        assert_eq!(
+            observed
+                .extrapolate(start + Duration::from_millis(1_600))
+                .slot,
+            105
+        );
+
+        // The older slots are ignored.
+        observed.advance_slot(101, start + Duration::from_secs(1));
+        assert_eq!(observed.clock.slot, 103);
+    }
+
+    #[test]
+    fn test_clock_sysvar_updates() {
+        let start = Instant::now();
+        let mut observed = ObservedClock::new(generate_test_clock(100), start);
+
+        // The slot notifications run ahead of the clock sysvar.
+        observed.advance_slot(104, start);
+        let mut sysvar = generate_test_clock(102);
+        sysvar.unix_timestamp += 1;
+        assert!(observed.update_sysvar(sysvar.clone(), start));
+        assert_eq!(observed.clock.slot, 104);
+        assert_eq!(observed.clock.unix_timestamp, sysvar.unix_timestamp);
+
+        // An older sysvar, e.g. streamed late by the other endpoint.
+        let mut older = generate_test_clock(101);
+        older.unix_timestamp += 2;
+        assert!(!observed.update_sysvar(older, start));
+        assert_eq!(observed.clock.unix_timestamp, sysvar.unix_timestamp);
+
+        assert!(observed.update_sysvar(generate_test_clock(105), start));
+        assert_eq!(observed.clock.slot, 105);
+    }
+
+    #[test]
+    fn test_refresh_health() {
+        let cache = Cache::new(generate_test_clock(1));
+        let bank = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        let mut marginfi_account =
+            create_marginfi_account(Pubkey::new_unique(), vec![create_balance(bank, 1, 0)]);
+        marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
+        cache
+            .marginfi_accounts
+            .update(1, address, marginfi_account)
+            .unwrap();
+        assert_eq!(cache.refresh_health().unwrap(), 1);
+        assert_eq!(cache.refresh_health().unwrap(), 0);
+
+        // With no price of the Bank, the health cached on-chain is kept.
+        cache
+            .banks
+            .update(2, bank, &create_bank_with_oracles(vec![]))
+            .unwrap();
+        assert_eq!(cache.refresh_health().unwrap(), 1);
+        assert_eq!(
+            cache.marginfi_accounts.get_accounts_with_health().unwrap()[&address],
+            1
+        );
+        assert_eq!(cache.refresh_health().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_stalest_dependency() {
+        let cache = create_dummy_cache();
+        let bank_a = Pubkey::new_unique();
+        let bank_b = Pubkey::new_unique();
+        cache
+            .banks
+            .update(
+                30,
+                bank_a,
+                &create_bank_with_oracles(vec![Pubkey::new_unique()]),
+            )
+            .unwrap();
+        cache
+            .banks
+            .update(20, bank_b, &create_bank_with_oracles(vec![]))
+            .unwrap();
+
+        let account = CachedMarginfiAccount::from(
+            50,
+            Pubkey::new_unique(),
+            create_marginfi_account(
+                Pubkey::new_unique(),
+                vec![create_balance(bank_a, 1, 0), create_balance(bank_b, 0, 1)],
+            ),
+        );
+        assert_eq!(
+            cache.stalest_dependency(&account).unwrap(),
+            Some((bank_b, 20))
+        );
+
+        let unknown_bank = CachedMarginfiAccount::from(
+            50,
+            Pubkey::new_unique(),
+            create_marginfi_account(
+                Pubkey::new_unique(),
+                vec![create_balance(Pubkey::new_unique(), 1, 0)],
+            ),
+        );
+        assert!(cache.stalest_dependency(&unknown_bank).is_err());
+    }
+
     #[test]
     fn test_cache_loader_new() {
         // Prepare dummy config and cache
@@ -353,6 +989,7 @@ mod tests {
         let loader = CacheLoader {
             program_id: config.marginfi_program_id,
             lut_addresses: vec![],
+            liquidator: Pubkey::new_unique(),
             comms_client: mocked_client,
             cache: cache.clone(),
         };
@@ -412,6 +1049,7 @@ mod tests {
         let loader = CacheLoader {
             program_id: config.marginfi_program_id,
             lut_addresses: vec![],
+            liquidator: Pubkey::new_unique(),
             comms_client: mocked_client,
             cache: cache.clone(),
         };
@@ -426,6 +1064,51 @@ mod tests {
         assert!(oracles_cache._get(&oracle_pubkey2).is_ok());
     }
 
+    #[test]
+    fn test_cache_loader_load_oracles_skips_unsupported_oracles() {
+        let config = create_dummy_config();
+        let cache = Arc::new(create_dummy_cache());
+
+        let supported_oracle = Pubkey::new_unique();
+        let unsupported_oracle = Pubkey::new_unique();
+        let supported_bank = create_bank_with_oracles(vec![supported_oracle]);
+        let mut unsupported_bank = create_bank_with_oracles(vec![unsupported_oracle]);
+        unsupported_bank.config.oracle_setup = OracleSetup::PythLegacy;
+        // A second Bank sharing the same oracle
+        let sharing_bank = create_bank_with_oracles(vec![supported_oracle]);
+
+        for bank in [supported_bank, unsupported_bank, sharing_bank] {
+            cache.banks.update(1, Pubkey::new_unique(), &bank).unwrap();
+        }
+
+        let account = Account {
+            lamports: 1,
+            data: vec![0u8; 100],
+            owner: Pubkey::new_unique(),
+            executable: false,
+            rent_epoch: 0,
+        };
+        let mut accounts = HashMap::new();
+        accounts.insert(supported_oracle, account.clone());
+        accounts.insert(unsupported_oracle, account);
+
+        let loader = CacheLoader {
+            program_id: config.marginfi_program_id,
+            lut_addresses: vec![],
+            liquidator: Pubkey::new_unique(),
+            comms_client: MockedCommsClient::with_accounts(accounts),
+            cache: cache.clone(),
+        };
+
+        let oracle_accounts = loader.fetch_oracle_accounts(|_| true).unwrap();
+        assert_eq!(oracle_accounts.len(), 1);
+        assert!(oracle_accounts.contains_key(&supported_oracle));
+
+        loader.load_oracles().unwrap();
+        assert!(cache.oracles._get(&supported_oracle).unwrap().is_some());
+        assert!(cache.oracles._get(&unsupported_oracle).unwrap().is_none());
+    }
+
     #[test]
     fn test_cache_loader_load_luts() {
         let mut config = create_dummy_config();
@@ -458,6 +1141,7 @@ mod tests {
         let loader = CacheLoader {
             program_id: config.marginfi_program_id,
             lut_addresses: config.lut_addresses.clone(),
+            liquidator: Pubkey::new_unique(),
             comms_client: mocked_client,
             cache: cache.clone(),
         };
@@ -472,4 +1156,34 @@ mod tests {
         assert!(!luts.is_empty());
         assert!(luts.iter().any(|lut| lut.key == lut_address));
     }
+
+    #[test]
+    fn test_cache_loader_load_wallet_token_accounts() {
+        let config = create_dummy_config();
+        let cache = Arc::new(create_dummy_cache());
+        let liquidator = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let ata = Pubkey::new_unique();
+        let accounts = HashMap::from([
+            (ata, create_token_account(mint, liquidator, 100)),
+            (
+                Pubkey::new_unique(),
+                create_token_account(mint, Pubkey::new_unique(), 1_000),
+            ),
+        ]);
+
+        let loader = CacheLoader {
+            program_id: config.marginfi_program_id,
+            lut_addresses: vec![],
+            liquidator,
+            comms_client: MockedCommsClient::with_accounts(accounts),
+            cache: cache.clone(),
+        };
+        loader.load_wallet_token_accounts().unwrap();
+
+        let by_mint = cache.wallet_token_accounts.by_mint().unwrap();
+        assert_eq!(by_mint.len(), 1);
+        assert_eq!(by_mint[&mint].address, ata);
+        assert_eq!(by_mint[&mint].amount, 100);
+    }
 }
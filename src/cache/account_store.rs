@@ -0,0 +1,353 @@
+use std::{mem::size_of, sync::Arc};
+
+use anyhow::Result;
+use dashmap::DashMap;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{cache::marginfi_accounts::CachedMarginfiAccount, config::Config};
+
+#[cfg(feature = "rocksdb")]
+use rocksdb_store::RocksDbAccountStore;
+
+/// Where the Marginfi accounts are stored: in memory, or in RocksDB for the operators tracking every
+/// Marginfi account ever created, with only the slots indexed in memory. The RocksDB accounts survive the
+/// restarts, so that they are available as soon as the store is open. The in-memory maps are sharded, for
+/// the Geyser updates not to contend with the full scans. The in-memory accounts are copy-on-write: the
+/// updates replace them rather than mutating them, so that the snapshots share them instead of copying them.
+pub enum MarginfiAccountStore {
+    Memory(DashMap<Pubkey, Arc<CachedMarginfiAccount>>),
+    #[cfg(feature = "rocksdb")]
+    RocksDb(RocksDbAccountStore),
+}
+
+impl Default for MarginfiAccountStore {
+    fn default() -> Self {
+        Self::Memory(DashMap::new())
+    }
+}
+
+impl MarginfiAccountStore {
+    pub fn open(config: &Config) -> Result<Self> {
+        match &config.cache_rocksdb_path {
+            None => Ok(Self::default()),
+            #[cfg(feature = "rocksdb")]
+            Some(path) => Ok(Self::RocksDb(RocksDbAccountStore::open(
+                std::path::Path::new(path),
+            )?)),
+            #[cfg(not(feature = "rocksdb"))]
+            Some(_) => Err(anyhow::anyhow!(
+                "CACHE_ROCKSDB_PATH requires the build with the `rocksdb` feature"
+            )),
+        }
+    }
+
+    pub fn slot(&self, address: &Pubkey) -> Result<Option<u64>> {
+        match self {
+            Self::Memory(accounts) => Ok(accounts.get(address).map(|cached| cached.slot())),
+            #[cfg(feature = "rocksdb")]
+            Self::RocksDb(store) => store.slot(address),
+        }
+    }
+
+    pub fn get(&self, address: &Pubkey) -> Result<Option<CachedMarginfiAccount>> {
+        match self {
+            Self::Memory(accounts) => {
+                Ok(accounts.get(address).map(|cached| cached.as_ref().clone()))
+            }
+            #[cfg(feature = "rocksdb")]
+            Self::RocksDb(store) => store.get(address),
+        }
+    }
+
+    pub fn insert(&self, account: CachedMarginfiAccount) -> Result<()> {
+        match self {
+            Self::Memory(accounts) => {
+                accounts.insert(account.address(), Arc::new(account));
+                Ok(())
+            }
+            #[cfg(feature = "rocksdb")]
+            Self::RocksDb(store) => store.insert(&account),
+        }
+    }
+
+    /// Returns whether the account was stored.
+    pub fn remove(&self, address: &Pubkey) -> Result<bool> {
+        match self {
+            Self::Memory(accounts) => Ok(accounts.remove(address).is_some()),
+            #[cfg(feature = "rocksdb")]
+            Self::RocksDb(store) => store.remove(address),
+        }
+    }
+
+    /// The approximate memory held by the stored accounts, the RocksDB store only holding their slots.
+    pub fn memory_bytes(&self) -> usize {
+        match self {
+            Self::Memory(accounts) => accounts
+                .iter()
+                .map(|cached| size_of::<Pubkey>() + cached.memory_bytes())
+                .sum(),
+            #[cfg(feature = "rocksdb")]
+            Self::RocksDb(store) => store.memory_bytes(),
+        }
+    }
+
+    /// Removes the accounts not to keep, returning their addresses.
+    pub fn retain(&self, keep: impl Fn(&Pubkey) -> bool) -> Result<Vec<Pubkey>> {
+        match self {
+            Self::Memory(accounts) => {
+                let mut removed = vec![];
+                accounts.retain(|address, _| {
+                    let kept = keep(address);
+                    if !kept {
+                        removed.push(*address);
+                    }
+                    kept
+                });
+                Ok(removed)
+            }
+            #[cfg(feature = "rocksdb")]
+            Self::RocksDb(store) => store.retain(keep),
+        }
+    }
+
+    /// Visits the accounts selected by their address and slot, one shard locked at a time.
+    pub fn for_each_where(
+        &self,
+        include: impl Fn(&Pubkey, u64) -> bool,
+        mut visit: impl FnMut(&CachedMarginfiAccount),
+    ) -> Result<()> {
+        match self {
+            Self::Memory(accounts) => {
+                accounts
+                    .iter()
+                    .filter(|cached| include(cached.key(), cached.slot()))
+                    .for_each(|cached| visit(cached.value().as_ref()));
+                Ok(())
+            }
+            #[cfg(feature = "rocksdb")]
+            Self::RocksDb(store) => store.for_each_where(include, &mut visit),
+        }
+    }
+
+    /// Captures the accounts selected by their address and slot, one shard locked at a time, just long enough
+    /// to share the in-memory accounts. The RocksDB accounts are read from the disk.
+    pub fn capture_where(
+        &self,
+        include: impl Fn(&Pubkey, u64) -> bool,
+    ) -> Result<Vec<Arc<CachedMarginfiAccount>>> {
+        match self {
+            Self::Memory(accounts) => Ok(accounts
+                .iter()
+                .filter(|cached| include(cached.key(), cached.slot()))
+                .map(|cached| cached.value().clone())
+                .collect()),
+            #[cfg(feature = "rocksdb")]
+            Self::RocksDb(store) => {
+                let mut captured = vec![];
+                store.for_each_where(include, &mut |cached: &CachedMarginfiAccount| {
+                    captured.push(Arc::new(cached.clone()))
+                })?;
+                Ok(captured)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::marginfi_accounts::test_util::create_marginfi_account;
+
+    #[test]
+    fn test_capture_is_not_affected_by_later_updates() {
+        let store = MarginfiAccountStore::default();
+        let address = Pubkey::new_unique();
+        let group = Pubkey::new_unique();
+        store
+            .insert(CachedMarginfiAccount::from(
+                1,
+                address,
+                create_marginfi_account(group, vec![]),
+            ))
+            .unwrap();
+
+        let captured = store.capture_where(|_, _| true).unwrap();
+        store
+            .insert(CachedMarginfiAccount::from(
+                2,
+                address,
+                create_marginfi_account(group, vec![]),
+            ))
+            .unwrap();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].slot(), 1);
+        assert_eq!(store.slot(&address).unwrap(), Some(2));
+        assert!(store.capture_where(|_, slot| slot < 2).unwrap().is_empty());
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+mod rocksdb_store {
+    use std::path::Path;
+
+    use anyhow::{anyhow, Context, Result};
+    use dashmap::DashMap;
+    use log::info;
+    use rocksdb::{IteratorMode, Options, WriteBatch, DB};
+    use solana_sdk::pubkey::Pubkey;
+
+    use crate::cache::marginfi_accounts::CachedMarginfiAccount;
+
+    const SLOT_LEN: usize = size_of::<u64>();
+
+    /// The accounts keyed by address, the values being the slot followed by the account data.
+    pub struct RocksDbAccountStore {
+        db: DB,
+        // The hot index of the stored accounts, for the updates not to read the accounts back.
+        slots: DashMap<Pubkey, u64>,
+    }
+
+    impl RocksDbAccountStore {
+        pub fn open(path: &Path) -> Result<Self> {
+            let mut options = Options::default();
+            options.create_if_missing(true);
+            let db = DB::open(&options, path)
+                .with_context(|| format!("Failed to open the RocksDB cache {}", path.display()))?;
+
+            let slots = DashMap::new();
+            for entry in db.iterator(IteratorMode::Start) {
+                let (key, value) = entry?;
+                slots.insert(decode_address(&key)?, decode(&key, &value)?.slot());
+            }
+            info!(
+                "Opened the RocksDB cache {} with {} Marginfi accounts",
+                path.display(),
+                slots.len()
+            );
+
+            Ok(Self { db, slots })
+        }
+
+        pub fn slot(&self, address: &Pubkey) -> Result<Option<u64>> {
+            Ok(self.slots.get(address).map(|slot| *slot))
+        }
+
+        pub fn memory_bytes(&self) -> usize {
+            self.slots.len() * (size_of::<Pubkey>() + SLOT_LEN)
+        }
+
+        pub fn get(&self, address: &Pubkey) -> Result<Option<CachedMarginfiAccount>> {
+            self.db
+                .get(address.as_ref())?
+                .map(|value| decode(address.as_ref(), &value))
+                .transpose()
+        }
+
+        pub fn insert(&self, account: &CachedMarginfiAccount) -> Result<()> {
+            let mut value = Vec::with_capacity(SLOT_LEN + account.data().len());
+            value.extend_from_slice(&account.slot().to_le_bytes());
+            value.extend_from_slice(account.data());
+            self.db.put(account.address().as_ref(), value)?;
+
+            self.slots.insert(account.address(), account.slot());
+            Ok(())
+        }
+
+        pub fn remove(&self, address: &Pubkey) -> Result<bool> {
+            if self.slots.remove(address).is_none() {
+                return Ok(false);
+            }
+            self.db.delete(address.as_ref())?;
+            Ok(true)
+        }
+
+        pub fn retain(&self, keep: impl Fn(&Pubkey) -> bool) -> Result<Vec<Pubkey>> {
+            let removed: Vec<Pubkey> = self
+                .slots
+                .iter()
+                .map(|slot| *slot.key())
+                .filter(|address| !keep(address))
+                .collect();
+
+            let mut batch = WriteBatch::default();
+            for address in &removed {
+                batch.delete(address.as_ref());
+            }
+            self.db.write(batch)?;
+            for address in &removed {
+                self.slots.remove(address);
+            }
+            Ok(removed)
+        }
+
+        // Only the selected accounts are read from the disk.
+        pub fn for_each_where(
+            &self,
+            include: impl Fn(&Pubkey, u64) -> bool,
+            visit: &mut impl FnMut(&CachedMarginfiAccount),
+        ) -> Result<()> {
+            let selected: Vec<Pubkey> = self
+                .slots
+                .iter()
+                .filter(|slot| include(slot.key(), *slot.value()))
+                .map(|slot| *slot.key())
+                .collect();
+            for address in selected {
+                if let Some(account) = self.get(&address)? {
+                    visit(&account);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn decode_address(key: &[u8]) -> Result<Pubkey> {
+        Pubkey::try_from(key).map_err(|_| anyhow!("Invalid RocksDB cache key {:?}", key))
+    }
+
+    fn decode(key: &[u8], value: &[u8]) -> Result<CachedMarginfiAccount> {
+        let address = decode_address(key)?;
+        if value.len() < SLOT_LEN {
+            return Err(anyhow!("Truncated RocksDB cache entry of {}", address));
+        }
+        let (slot, data) = value.split_at(SLOT_LEN);
+        CachedMarginfiAccount::from_data(u64::from_le_bytes(slot.try_into()?), address, data)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::cache::marginfi_accounts::test_util::create_marginfi_account;
+
+        #[test]
+        fn test_rocksdb_store_reopen() {
+            let path = std::env::temp_dir().join(format!("mary_rocksdb_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            let kept = Pubkey::new_unique();
+            let removed = Pubkey::new_unique();
+            {
+                let store = RocksDbAccountStore::open(&path).unwrap();
+                for (slot, address) in [(5, kept), (6, removed)] {
+                    store
+                        .insert(&CachedMarginfiAccount::from(
+                            slot,
+                            address,
+                            create_marginfi_account(Pubkey::new_unique(), vec![]),
+                        ))
+                        .unwrap();
+                }
+                assert_eq!(
+                    store.retain(|address| *address == kept).unwrap(),
+                    vec![removed]
+                );
+            }
+
+            let store = RocksDbAccountStore::open(&path).unwrap();
+            assert_eq!(store.slot(&kept).unwrap(), Some(5));
+            assert_eq!(store.get(&kept).unwrap().unwrap().slot(), 5);
+            assert!(store.get(&removed).unwrap().is_none());
+            drop(store);
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+    }
+}
@@ -1,7 +1,11 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
+};
 
 use anchor_lang::AccountDeserialize;
 use anyhow::{anyhow, Result};
+use fixed::types::I80F48;
 use log::trace;
 use marginfi::state::{
     emode::EmodeConfig,
@@ -15,24 +19,39 @@ use crate::common::{MARGINFI_BANK_DISCRIMINATOR, MARGINFI_BANK_DISCRIMINATOR_LEN
 use bytemuck::bytes_of;
 use std::mem::size_of;
 
+const SECONDS_PER_YEAR: i64 = 31_536_000;
+
 #[derive(Debug, Clone)]
 pub struct CachedBankOracle {
     pub oracle_type: OracleSetup,
     pub oracle_addresses: Vec<Pubkey>,
 }
 
-#[derive(Debug)]
+/// The Bank as deserialized once on ingest, with the data it was deserialized from for the snapshots.
+#[derive(Debug, Clone)]
 pub struct CachedBank {
     pub slot: u64,
     pub address: Pubkey,
     bank: Bank,
     oracle: CachedBankOracle,
+    data: Arc<[u8]>,
 }
 
 impl CacheEntry for CachedBank {}
 
 impl CachedBank {
     pub fn from(slot: u64, address: Pubkey, bank: Bank) -> Self {
+        Self::with_data(slot, address, bank, serialize_bank(&bank).into())
+    }
+
+    /// Deserializes the Bank data, which is kept as is.
+    pub fn from_data(slot: u64, address: Pubkey, data: &[u8]) -> Result<Self> {
+        let bank = Bank::try_deserialize(&mut &data[..])
+            .map_err(|e| anyhow!("Failed to deserialize the Bank {}: {}", address, e))?;
+        Ok(Self::with_data(slot, address, bank, data.into()))
+    }
+
+    fn with_data(slot: u64, address: Pubkey, bank: Bank, data: Arc<[u8]>) -> Self {
         Self {
             slot,
             address,
@@ -41,6 +60,7 @@ impl CachedBank {
                 oracle_type: bank.config.oracle_setup,
                 oracle_addresses: get_oracle_accounts(&bank.config),
             },
+            data,
         }
     }
 
@@ -48,34 +68,266 @@ impl CachedBank {
         &self.bank.mint
     }
 
+    pub fn group(&self) -> &Pubkey {
+        &self.bank.group
+    }
+
+    pub fn liquidity_vault(&self) -> &Pubkey {
+        &self.bank.liquidity_vault
+    }
+
+    pub fn insurance_vault(&self) -> &Pubkey {
+        &self.bank.insurance_vault
+    }
+
+    pub fn oracle_addresses(&self) -> &[Pubkey] {
+        &self.oracle.oracle_addresses
+    }
+
+    pub fn config(&self) -> &BankConfig {
+        &self.bank.config
+    }
+
+    pub fn oracle_type(&self) -> OracleSetup {
+        self.oracle.oracle_type
+    }
+
+    /// The Bank data as it is cached, serialized like the on-chain account.
+    pub fn to_snapshot_account(&self) -> SnapshotAccount {
+        SnapshotAccount::new(self.address, self.slot, self.data.to_vec())
+    }
+
     pub fn _emode_config(&self) -> &EmodeConfig {
         &self.bank.emode.emode_config
     }
+
+    /// The emode tag of the Bank, which the emode configs of the borrowed Banks pair with, 0 when untagged.
+    pub fn emode_tag(&self) -> u16 {
+        self.bank.emode.emode_tag
+    }
+
+    /// The max age of the oracle prices in seconds, 0 for the default of the program.
+    pub fn oracle_max_age(&self) -> u16 {
+        self.bank.config.oracle_max_age
+    }
+
+    pub fn mint_decimals(&self) -> u8 {
+        self.bank.mint_decimals
+    }
+
+    /// The total deposits in the native token amount.
+    pub fn total_assets(&self) -> I80F48 {
+        I80F48::from(self.bank.total_asset_shares)
+            .saturating_mul(self.bank.asset_share_value.into())
+    }
+
+    /// The total borrows in the native token amount.
+    pub fn total_liabilities(&self) -> I80F48 {
+        I80F48::from(self.bank.total_liability_shares)
+            .saturating_mul(self.bank.liability_share_value.into())
+    }
+
+    /// Converts asset shares into the native token amount deposited, rounded down.
+    pub fn asset_amount(&self, asset_shares: I80F48) -> Option<u64> {
+        asset_shares
+            .checked_mul(self.bank.asset_share_value.into())?
+            .checked_floor()?
+            .checked_to_num::<u64>()
+    }
+
+    /// Converts liability shares into the native token amount owed, rounded up.
+    pub fn liability_amount(&self, liability_shares: I80F48) -> Option<u64> {
+        liability_shares
+            .checked_mul(self.bank.liability_share_value.into())?
+            .checked_ceil()?
+            .checked_to_num::<u64>()
+    }
+
+    /// The asset and the liability share values projected to the timestamp, with the interest accrued since
+    /// the last on-chain accrual: the liquidation accrues it before checking the health. The cached share
+    /// values are kept when the projection fails, e.g. with no borrows or an unset interest rate config.
+    pub fn projected_share_values(&self, unix_timestamp: i64) -> (I80F48, I80F48) {
+        let asset_share_value: I80F48 = self.bank.asset_share_value.into();
+        let liability_share_value: I80F48 = self.bank.liability_share_value.into();
+        let elapsed = unix_timestamp.saturating_sub(self.bank.last_update);
+        if elapsed <= 0 {
+            return (asset_share_value, liability_share_value);
+        }
+
+        self.interest_rates()
+            .and_then(|(lending_rate, borrowing_rate)| {
+                let period =
+                    I80F48::from_num(elapsed).checked_div(I80F48::from_num(SECONDS_PER_YEAR))?;
+                let accrue = |share_value: I80F48, rate: I80F48| {
+                    share_value.checked_mul(I80F48::ONE.checked_add(rate.checked_mul(period)?)?)
+                };
+                Some((
+                    accrue(asset_share_value, lending_rate)?,
+                    accrue(liability_share_value, borrowing_rate)?,
+                ))
+            })
+            .unwrap_or((asset_share_value, liability_share_value))
+    }
+
+    /// The lending and the borrowing APRs at the current utilization, following the Bank interest rate curve:
+    /// linear up to the plateau rate at the optimal utilization, then up to the max rate at full utilization.
+    fn interest_rates(&self) -> Option<(I80F48, I80F48)> {
+        let total_assets = self.total_assets();
+        let total_liabilities = self.total_liabilities();
+        if total_assets <= I80F48::ZERO || total_liabilities <= I80F48::ZERO {
+            return None;
+        }
+        let utilization = total_liabilities.checked_div(total_assets)?;
+
+        let config = &self.bank.config.interest_rate_config;
+        let optimal_utilization: I80F48 = config.optimal_utilization_rate.into();
+        let plateau_rate: I80F48 = config.plateau_interest_rate.into();
+        let max_rate: I80F48 = config.max_interest_rate.into();
+        let base_rate = if utilization <= optimal_utilization {
+            utilization
+                .checked_div(optimal_utilization)?
+                .checked_mul(plateau_rate)?
+        } else {
+            utilization
+                .checked_sub(optimal_utilization)?
+                .checked_div(I80F48::ONE.checked_sub(optimal_utilization)?)?
+                .checked_mul(max_rate.checked_sub(plateau_rate)?)?
+                .checked_add(plateau_rate)?
+        };
+
+        let lending_rate = base_rate.checked_mul(utilization)?;
+        let ir_fees =
+            I80F48::from(config.insurance_ir_fee).checked_add(config.protocol_ir_fee.into())?;
+        let fixed_fees = I80F48::from(config.insurance_fee_fixed_apr)
+            .checked_add(config.protocol_fixed_fee_apr.into())?;
+        let borrowing_rate = base_rate
+            .checked_mul(I80F48::ONE.checked_add(ir_fees)?)?
+            .checked_add(fixed_fees)?;
+        Some((lending_rate, borrowing_rate))
+    }
 }
 
 #[derive(Default)]
 pub struct BanksCache {
     banks: RwLock<HashMap<Pubkey, CachedBank>>,
+    // The Banks updated since the last recomputation of the health of their accounts.
+    updated: Mutex<HashSet<Pubkey>>,
 }
 
 impl BanksCache {
     pub fn update(&self, slot: u64, address: Pubkey, bank: &Bank) -> Result<()> {
-        let upd_cached_bank = CachedBank::from(slot, address, *bank);
+        self.update_cached(CachedBank::from(slot, address, *bank))
+    }
+
+    /// Updates the Bank with its data as ingested, deserialized only once.
+    pub fn update_data(&self, slot: u64, address: Pubkey, data: &[u8]) -> Result<()> {
+        self.update_cached(CachedBank::from_data(slot, address, data)?)
+    }
 
+    fn update_cached(&self, upd_cached_bank: CachedBank) -> Result<()> {
+        self.update_batch(vec![upd_cached_bank]).map(|_| ())
+    }
+
+    /// Applies the decoded Banks in order, under a single acquisition of the cache lock. Returns the Banks
+    /// which were not cached yet.
+    pub fn update_batch(&self, upd_cached_banks: Vec<CachedBank>) -> Result<Vec<Pubkey>> {
         let mut banks = self
             .banks
             .write()
             .map_err(|e| anyhow!("Failed to lock the Banks cache for update! {}", e))?;
 
-        if banks
-            .get(&address)
-            .map_or(true, |existing| existing.slot < upd_cached_bank.slot)
-        {
-            trace!("Updating the Bank in cache: {:?}", upd_cached_bank.address);
-            banks.insert(address, upd_cached_bank);
+        let mut updated = vec![];
+        let mut created = vec![];
+        for upd_cached_bank in upd_cached_banks {
+            let address = upd_cached_bank.address;
+            if !banks.contains_key(&address) {
+                created.push(address);
+            }
+            if banks
+                .get(&address)
+                .map_or(true, |existing| existing.slot <= upd_cached_bank.slot)
+            {
+                trace!("Updating the Bank in cache: {:?}", address);
+                banks.insert(address, upd_cached_bank);
+                updated.push(address);
+            }
+        }
+        if !updated.is_empty() {
+            self.updated
+                .lock()
+                .map_err(|e| anyhow!("Failed to lock the updated Banks: {}", e))?
+                .extend(updated);
         }
 
-        Ok(())
+        Ok(created)
+    }
+
+    /// Drains the Banks updated since the last call.
+    pub fn take_updated(&self) -> Result<HashSet<Pubkey>> {
+        Ok(std::mem::take(&mut *self.updated.lock().map_err(|e| {
+            anyhow!("Failed to lock the updated Banks: {}", e)
+        })?))
+    }
+
+    pub fn get_bank(&self, address: &Pubkey) -> Result<CachedBank> {
+        self.banks
+            .read()
+            .map_err(|e| anyhow!("Failed to lock the Banks cache for getting a bank: {}", e))?
+            .get(address)
+            .cloned()
+            .ok_or_else(|| anyhow!("Bank {} not found in cache", address))
+    }
+
+    pub fn contains(&self, address: &Pubkey) -> Result<bool> {
+        Ok(self
+            .banks
+            .read()
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to lock the Banks cache for looking up a bank: {}",
+                    e
+                )
+            })?
+            .contains_key(address))
+    }
+
+    pub fn get_banks(&self) -> Result<Vec<CachedBank>> {
+        Ok(self
+            .banks
+            .read()
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to lock the Banks cache for reading the banks: {}",
+                    e
+                )
+            })?
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    /// The approximate memory held by the cached Banks.
+    pub fn count(&self) -> Result<usize> {
+        Ok(self
+            .banks
+            .read()
+            .map_err(|e| anyhow!("Failed to lock the Banks cache for counting: {}", e))?
+            .len())
+    }
+
+    pub fn memory_bytes(&self) -> Result<usize> {
+        Ok(self
+            .banks
+            .read()
+            .map_err(|e| anyhow!("Failed to lock the Banks cache for sizing: {}", e))?
+            .values()
+            .map(|bank| {
+                size_of::<Pubkey>()
+                    + size_of::<CachedBank>()
+                    + bank.oracle.oracle_addresses.capacity() * size_of::<Pubkey>()
+                    + bank.data.len()
+            })
+            .sum())
     }
 
     pub fn get_mints(&self) -> Result<Vec<Pubkey>> {
@@ -104,23 +356,33 @@ impl BanksCache {
     }
 
     pub(crate) fn snapshot_entries(&self) -> Result<Vec<SnapshotAccount>> {
-        let banks = self.banks.read().map_err(|e| {
-            anyhow!(
-                "Failed to lock the Banks cache for snapshot generation: {}",
-                e
-            )
-        })?;
-
-        let mut entries = Vec::with_capacity(banks.len());
-        for bank in banks.values() {
-            entries.push(SnapshotAccount::new(
-                bank.address,
-                bank.slot,
-                serialize_bank(&bank.bank),
-            ));
-        }
+        self.snapshot_entries_where(|_, _| true)
+    }
+
+    /// The snapshot entries of the Banks selected by their address and slot. The Banks are copied under
+    /// the lock and serialized once it is released, for the Geyser updates not to wait on the serialization.
+    pub(crate) fn snapshot_entries_where(
+        &self,
+        include: impl Fn(&Pubkey, u64) -> bool,
+    ) -> Result<Vec<SnapshotAccount>> {
+        let selected: Vec<CachedBank> = self
+            .banks
+            .read()
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to lock the Banks cache for snapshot generation: {}",
+                    e
+                )
+            })?
+            .values()
+            .filter(|bank| include(&bank.address, bank.slot))
+            .cloned()
+            .collect();
 
-        Ok(entries)
+        Ok(selected
+            .iter()
+            .map(|bank| bank.to_snapshot_account())
+            .collect())
     }
 
     pub(crate) fn restore_from_snapshot(&self, entries: &[SnapshotAccount]) -> Result<()> {
@@ -129,16 +391,14 @@ impl BanksCache {
             .map_err(|e| anyhow!("Failed to lock Banks cache for reset: {}", e))?
             .clear();
 
+        self.apply_snapshot_entries(entries)
+    }
+
+    /// Updates the cache with the snapshot entries, on top of the cached Banks.
+    pub(crate) fn apply_snapshot_entries(&self, entries: &[SnapshotAccount]) -> Result<()> {
         for entry in entries {
-            let mut data_slice = entry.data.as_slice();
-            let bank = Bank::try_deserialize(&mut data_slice).map_err(|err| {
-                anyhow!(
-                    "Failed to deserialize Bank {} from snapshot: {}",
-                    entry.address,
-                    err
-                )
-            })?;
-            self.update(entry.slot, entry.address, &bank)?;
+            self.update_data(entry.slot, entry.address, &entry.data)
+                .map_err(|err| anyhow!("Failed to restore from snapshot: {}", err))?;
         }
 
         Ok(())
@@ -200,6 +460,40 @@ mod tests {
     use std::sync::Arc;
     use std::thread;
 
+    #[test]
+    fn test_projected_share_values() {
+        let mut bank = create_bank_with_oracles(vec![]);
+        bank.asset_share_value = I80F48::ONE.into();
+        bank.liability_share_value = I80F48::ONE.into();
+        bank.total_asset_shares = I80F48::from_num(1000).into();
+        bank.total_liability_shares = I80F48::from_num(500).into();
+        bank.last_update = 1_000;
+        bank.config.interest_rate_config.optimal_utilization_rate = I80F48::from_num(0.8).into();
+        bank.config.interest_rate_config.plateau_interest_rate = I80F48::from_num(0.1).into();
+        bank.config.interest_rate_config.max_interest_rate = I80F48::ONE.into();
+        let cached_bank = CachedBank::from(1, Pubkey::new_unique(), bank);
+
+        // Nothing accrued yet.
+        assert_eq!(
+            cached_bank.projected_share_values(1_000),
+            (I80F48::ONE, I80F48::ONE)
+        );
+
+        // At 50% utilization the borrowing APR is 6.25% and the lending one half of it.
+        let (asset_share_value, liability_share_value) =
+            cached_bank.projected_share_values(1_000 + SECONDS_PER_YEAR);
+        assert!((asset_share_value - I80F48::from_num(1.03125)).abs() < I80F48::from_num(1e-9));
+        assert!((liability_share_value - I80F48::from_num(1.0625)).abs() < I80F48::from_num(1e-9));
+
+        // Without borrows, there is no interest.
+        bank.total_liability_shares = I80F48::ZERO.into();
+        assert_eq!(
+            CachedBank::from(1, Pubkey::new_unique(), bank)
+                .projected_share_values(1_000 + SECONDS_PER_YEAR),
+            (I80F48::ONE, I80F48::ONE)
+        );
+    }
+
     #[test]
     fn test_cached_bank_from() {
         let slot = 123;
@@ -257,6 +551,43 @@ mod tests {
         assert_eq!(cached.slot, 10);
     }
 
+    #[test]
+    fn test_banks_cache_get_bank() {
+        let cache = BanksCache::default();
+        let address = Pubkey::new_unique();
+        let bank = create_bank_with_oracles(vec![]);
+        cache.update(7, address, &bank).unwrap();
+
+        let cached = cache.get_bank(&address).unwrap();
+        assert_eq!(cached.slot, 7);
+        assert_eq!(cached.mint(), &bank.mint);
+
+        let result = cache.get_bank(&Pubkey::new_unique());
+        assert!(format!("{}", result.unwrap_err()).contains("not found in cache"));
+    }
+
+    #[test]
+    fn test_cached_bank_liability_amount_rounds_up() {
+        let mut bank = create_bank_with_oracles(vec![]);
+        bank.liability_share_value = I80F48::from_num(1.5).into();
+        let cached = CachedBank::from(1, Pubkey::new_unique(), bank);
+
+        assert_eq!(cached.liability_amount(I80F48::from_num(10)), Some(15));
+        assert_eq!(cached.liability_amount(I80F48::from_num(3)), Some(5));
+        assert_eq!(cached.liability_amount(I80F48::from_num(-1)), None);
+    }
+
+    #[test]
+    fn test_cached_bank_asset_amount_rounds_down() {
+        let mut bank = create_bank_with_oracles(vec![]);
+        bank.asset_share_value = I80F48::from_num(1.5).into();
+        let cached = CachedBank::from(1, Pubkey::new_unique(), bank);
+
+        assert_eq!(cached.asset_amount(I80F48::from_num(10)), Some(15));
+        assert_eq!(cached.asset_amount(I80F48::from_num(3)), Some(4));
+        assert_eq!(cached.asset_amount(I80F48::from_num(-1)), None);
+    }
+
     #[test]
     fn test_get_oracle_accounts_filters_default() {
         let oracle1 = Pubkey::new_unique();
@@ -400,7 +731,7 @@ mod tests {
     }
 
     #[test]
-    fn test_banks_cache_update_same_slot_does_not_overwrite() {
+    fn test_banks_cache_update_same_slot_overwrites() {
         let cache = BanksCache::default();
         let address = Pubkey::new_unique();
         let bank1 = create_bank_with_oracles(vec![]);
@@ -410,8 +741,8 @@ mod tests {
 
         let banks = cache.banks.read().unwrap();
         let cached = banks.get(&address).unwrap();
-        // Should be the last inserted bank with the same slot
-        assert_eq!(cached.mint(), &bank1.mint);
+        // The Geyser updates of the slot are applied in their write version order.
+        assert_eq!(cached.mint(), &bank2.mint);
     }
 
     #[test]
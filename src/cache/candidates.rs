@@ -0,0 +1,52 @@
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+
+/// A liquidation candidate as ranked by the last evaluation cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RankedCandidate {
+    pub address: Pubkey,
+    pub health: i64,
+    pub liquidatable_since_unix: u64,
+    pub ranked_at_unix: u64,
+}
+
+/// The liquidation candidates of the last evaluation cycle, in their processing order. They are persisted
+/// with the snapshots, so that a restarted bot attempts them first, before the health of all the accounts
+/// is recomputed.
+#[derive(Default)]
+pub struct RankedCandidates {
+    candidates: RwLock<Vec<RankedCandidate>>,
+}
+
+impl RankedCandidates {
+    pub fn replace(&self, candidates: Vec<RankedCandidate>) -> Result<()> {
+        *self.write()? = candidates;
+        Ok(())
+    }
+
+    pub fn get(&self) -> Result<Vec<RankedCandidate>> {
+        Ok(self.read()?.clone())
+    }
+
+    /// Drops the candidate, e.g. closed since it was ranked.
+    pub fn remove(&self, address: &Pubkey) -> Result<()> {
+        self.write()?
+            .retain(|candidate| candidate.address != *address);
+        Ok(())
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, Vec<RankedCandidate>>> {
+        self.candidates
+            .read()
+            .map_err(|e| anyhow!("Failed to lock the ranked candidates for read: {}", e))
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, Vec<RankedCandidate>>> {
+        self.candidates
+            .write()
+            .map_err(|e| anyhow!("Failed to lock the ranked candidates for update: {}", e))
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{anyhow, Result};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+
+/// A liquidation of another liquidator, as streamed by Geyser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObservedLiquidation {
+    pub signature: Signature,
+    pub slot: u64,
+    // The signer of the liquidation.
+    pub liquidator: Pubkey,
+    pub liquidatee: Pubkey,
+    pub asset_bank: Pubkey,
+    pub liab_bank: Pubkey,
+    pub asset_amount: u64,
+}
+
+/// The liquidations of a competitor since the start.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompetitorStats {
+    pub liquidations: u64,
+    pub last_slot: u64,
+}
+
+#[derive(Default)]
+struct Observed {
+    // The latest liquidation of every liquidatee.
+    latest: HashMap<Pubkey, ObservedLiquidation>,
+    competitors: HashMap<Pubkey, CompetitorStats>,
+}
+
+/// The liquidations of the other liquidators, as parsed from the Marginfi transactions streamed by Geyser. An
+/// account they liquidated is skipped until its cached state reflects the liquidation, rather than racing for
+/// a liquidation which already happened.
+#[derive(Default)]
+pub struct CompetitorLiquidations {
+    observed: Mutex<Observed>,
+}
+
+impl CompetitorLiquidations {
+    /// Records the liquidation, unless it is the latest one of the liquidatee already, e.g. streamed by both
+    /// Geyser endpoints. Returns whether it was recorded.
+    pub fn record(&self, liquidation: ObservedLiquidation) -> Result<bool> {
+        let mut observed = self.lock()?;
+        let latest = observed.latest.get(&liquidation.liquidatee).copied();
+        if latest.is_some_and(|latest| latest.signature == liquidation.signature) {
+            return Ok(false);
+        }
+        if latest.is_none_or(|latest| latest.slot <= liquidation.slot) {
+            observed.latest.insert(liquidation.liquidatee, liquidation);
+        }
+        let stats = observed
+            .competitors
+            .entry(liquidation.liquidator)
+            .or_default();
+        stats.liquidations += 1;
+        stats.last_slot = stats.last_slot.max(liquidation.slot);
+        Ok(true)
+    }
+
+    /// The latest liquidation of the account by a competitor, if its cached state as of the slot predates it.
+    pub fn pending_liquidation(
+        &self,
+        liquidatee: &Pubkey,
+        account_slot: u64,
+    ) -> Result<Option<ObservedLiquidation>> {
+        Ok(self
+            .lock()?
+            .latest
+            .get(liquidatee)
+            .filter(|liquidation| liquidation.slot > account_slot)
+            .copied())
+    }
+
+    /// The competitors with their stats, the most liquidations first.
+    pub fn competitors(&self) -> Result<Vec<(Pubkey, CompetitorStats)>> {
+        let mut competitors: Vec<(Pubkey, CompetitorStats)> = self
+            .lock()?
+            .competitors
+            .iter()
+            .map(|(liquidator, stats)| (*liquidator, *stats))
+            .collect();
+        competitors.sort_by(|(a, a_stats), (b, b_stats)| {
+            b_stats
+                .liquidations
+                .cmp(&a_stats.liquidations)
+                .then(a.cmp(b))
+        });
+        Ok(competitors)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Observed>> {
+        self.observed
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the competitor liquidations: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn liquidation(liquidator: Pubkey, liquidatee: Pubkey, slot: u64) -> ObservedLiquidation {
+        ObservedLiquidation {
+            signature: Signature::new_unique(),
+            slot,
+            liquidator,
+            liquidatee,
+            asset_bank: Pubkey::new_unique(),
+            liab_bank: Pubkey::new_unique(),
+            asset_amount: 100,
+        }
+    }
+
+    #[test]
+    fn test_competitor_liquidations() {
+        let competitors = CompetitorLiquidations::default();
+        let (liquidator, other_liquidator) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let liquidatee = Pubkey::new_unique();
+
+        let first = liquidation(liquidator, liquidatee, 10);
+        assert!(competitors.record(first).unwrap());
+        // The same transaction streamed again.
+        assert!(!competitors.record(first).unwrap());
+        assert!(competitors
+            .record(liquidation(other_liquidator, liquidatee, 12))
+            .unwrap());
+        assert!(competitors
+            .record(liquidation(liquidator, Pubkey::new_unique(), 11))
+            .unwrap());
+        // An older liquidation does not replace the latest one of the liquidatee.
+        assert!(competitors
+            .record(liquidation(liquidator, liquidatee, 9))
+            .unwrap());
+
+        let pending = competitors
+            .pending_liquidation(&liquidatee, 11)
+            .unwrap()
+            .unwrap();
+        assert_eq!((pending.liquidator, pending.slot), (other_liquidator, 12));
+        assert_eq!(
+            competitors.pending_liquidation(&liquidatee, 12).unwrap(),
+            None
+        );
+        assert_eq!(
+            competitors
+                .pending_liquidation(&Pubkey::new_unique(), 0)
+                .unwrap(),
+            None
+        );
+
+        assert_eq!(
+            competitors.competitors().unwrap(),
+            vec![
+                (
+                    liquidator,
+                    CompetitorStats {
+                        liquidations: 3,
+                        last_slot: 11
+                    }
+                ),
+                (
+                    other_liquidator,
+                    CompetitorStats {
+                        liquidations: 1,
+                        last_slot: 12
+                    }
+                ),
+            ]
+        );
+    }
+}
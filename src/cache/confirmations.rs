@@ -0,0 +1,301 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::common::MessageType;
+
+// The slots for which the processed updates are kept, in case the slot dies. A slot is dead well before it
+// would have been confirmed.
+const DEAD_SLOT_WINDOW: u64 = 150;
+
+/// The counters of the processed updates, as logged with the stats and served by the admin API.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ConfirmationStats {
+    // The accounts whose latest processed update is not confirmed yet.
+    pub pending: usize,
+    pub confirmed: u64,
+    pub rolled_back: u64,
+    // The slots notified dead, whose updates are rolled back at once.
+    pub dead_slots: u64,
+}
+
+#[derive(Default)]
+struct Tracked {
+    // The slot and the type of the latest processed update of every account not confirmed yet.
+    pending: HashMap<Pubkey, (u64, MessageType)>,
+    // The accounts whose processed update never confirmed, for the reconciliation to re-fetch.
+    rollbacks: HashMap<Pubkey, MessageType>,
+    // The accounts updated at the recent processed slots.
+    by_slot: BTreeMap<u64, HashMap<Pubkey, MessageType>>,
+    dead: BTreeSet<u64>,
+    latest_slot: u64,
+    confirmed: u64,
+    rolled_back: u64,
+    dead_slots: u64,
+}
+
+impl Tracked {
+    fn oldest_slot(&self) -> u64 {
+        self.latest_slot.saturating_sub(DEAD_SLOT_WINDOW)
+    }
+
+    // Forgets the slots too old to die.
+    fn prune(&mut self, slot: u64) {
+        self.latest_slot = self.latest_slot.max(slot);
+        let oldest = self.oldest_slot();
+        if self
+            .by_slot
+            .first_key_value()
+            .is_some_and(|(first, _)| *first < oldest)
+        {
+            self.by_slot = self.by_slot.split_off(&oldest);
+        }
+        if self.dead.first().is_some_and(|first| *first < oldest) {
+            self.dead = self.dead.split_off(&oldest);
+        }
+    }
+}
+
+/// The updates applied from the processed Geyser stream, until the confirmed stream confirms them. The
+/// updates of a slot which is not confirmed by the time the confirmed slot is past it by the margin were on
+/// an abandoned fork, and their accounts are rolled back by re-fetching them. The updates of a slot notified
+/// dead are rolled back at once.
+#[derive(Default)]
+pub struct ProcessedConfirmations {
+    tracked: Mutex<Tracked>,
+}
+
+impl ProcessedConfirmations {
+    pub fn observe_processed(
+        &self,
+        address: Pubkey,
+        slot: u64,
+        message_type: MessageType,
+    ) -> Result<()> {
+        let mut tracked = self.lock()?;
+        let pending = tracked
+            .pending
+            .entry(address)
+            .or_insert((slot, message_type));
+        if pending.0 < slot {
+            *pending = (slot, message_type);
+        }
+        Ok(())
+    }
+
+    /// Records the processed update of the account at the slot, for its rollback if the slot dies.
+    pub fn observe_slot_update(
+        &self,
+        address: Pubkey,
+        slot: u64,
+        message_type: MessageType,
+    ) -> Result<()> {
+        let mut tracked = self.lock()?;
+        tracked
+            .by_slot
+            .entry(slot)
+            .or_default()
+            .insert(address, message_type);
+        tracked.prune(slot);
+        Ok(())
+    }
+
+    /// Rolls back the updates of the slot, skipped or on an abandoned fork, whose later updates are ignored.
+    /// Returns how many were rolled back.
+    pub fn observe_dead_slot(&self, slot: u64) -> Result<usize> {
+        let mut tracked = self.lock()?;
+        if slot < tracked.oldest_slot() || !tracked.dead.insert(slot) {
+            return Ok(0);
+        }
+        tracked.dead_slots += 1;
+        let orphaned = tracked.by_slot.remove(&slot).unwrap_or_default();
+        for (address, message_type) in &orphaned {
+            if tracked
+                .pending
+                .get(address)
+                .is_some_and(|(pending_slot, _)| *pending_slot == slot)
+            {
+                tracked.pending.remove(address);
+            }
+            tracked.rollbacks.insert(*address, *message_type);
+        }
+        tracked.rolled_back += orphaned.len() as u64;
+        tracked.prune(slot);
+        Ok(orphaned.len())
+    }
+
+    pub fn is_dead_slot(&self, slot: u64) -> Result<bool> {
+        Ok(self.lock()?.dead.contains(&slot))
+    }
+
+    /// Confirms the processed updates of the account up to the slot.
+    pub fn confirm(&self, address: &Pubkey, slot: u64) -> Result<()> {
+        let mut tracked = self.lock()?;
+        if tracked
+            .pending
+            .get(address)
+            .is_some_and(|(pending_slot, _)| *pending_slot <= slot)
+        {
+            tracked.pending.remove(address);
+            tracked.confirmed += 1;
+        }
+        Ok(())
+    }
+
+    /// Rolls back the processed updates not confirmed while the confirmed stream reached the slot, past them
+    /// by more than the margin. Returns how many were rolled back.
+    pub fn observe_confirmed_slot(&self, confirmed_slot: u64, margin_slots: u64) -> Result<usize> {
+        let rollback_before = confirmed_slot.saturating_sub(margin_slots);
+        let mut tracked = self.lock()?;
+        let unconfirmed: Vec<(Pubkey, MessageType)> = tracked
+            .pending
+            .iter()
+            .filter(|(_, (slot, _))| *slot < rollback_before)
+            .map(|(address, (_, message_type))| (*address, *message_type))
+            .collect();
+        for (address, message_type) in &unconfirmed {
+            tracked.pending.remove(address);
+            tracked.rollbacks.insert(*address, *message_type);
+        }
+        tracked.rolled_back += unconfirmed.len() as u64;
+        Ok(unconfirmed.len())
+    }
+
+    /// The accounts to roll back since the previous call.
+    pub fn take_rollbacks(&self) -> Result<Vec<(Pubkey, MessageType)>> {
+        Ok(self.lock()?.rollbacks.drain().collect())
+    }
+
+    pub fn stats(&self) -> Result<ConfirmationStats> {
+        let tracked = self.lock()?;
+        Ok(ConfirmationStats {
+            pending: tracked.pending.len(),
+            confirmed: tracked.confirmed,
+            rolled_back: tracked.rolled_back,
+            dead_slots: tracked.dead_slots,
+        })
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Tracked>> {
+        self.tracked
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the processed confirmations: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_processed_confirmations() {
+        let confirmations = ProcessedConfirmations::default();
+        let (account, bank, oracle) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        confirmations
+            .observe_processed(account, 100, MessageType::MarginfiAccount)
+            .unwrap();
+        confirmations
+            .observe_processed(account, 101, MessageType::MarginfiAccount)
+            .unwrap();
+        confirmations
+            .observe_processed(bank, 100, MessageType::Bank)
+            .unwrap();
+        confirmations
+            .observe_processed(oracle, 102, MessageType::Oracle)
+            .unwrap();
+
+        // The confirmation of an older update leaves the latest one pending.
+        confirmations.confirm(&account, 100).unwrap();
+        confirmations.confirm(&bank, 100).unwrap();
+        confirmations.confirm(&Pubkey::new_unique(), 100).unwrap();
+        assert_eq!(
+            confirmations.stats().unwrap(),
+            ConfirmationStats {
+                pending: 2,
+                confirmed: 1,
+                rolled_back: 0,
+                dead_slots: 0,
+            }
+        );
+
+        // Within the margin, nothing is rolled back.
+        assert_eq!(confirmations.observe_confirmed_slot(103, 2).unwrap(), 0);
+        assert_eq!(confirmations.observe_confirmed_slot(104, 2).unwrap(), 1);
+        assert_eq!(
+            confirmations.take_rollbacks().unwrap(),
+            vec![(account, MessageType::MarginfiAccount)]
+        );
+        assert!(confirmations.take_rollbacks().unwrap().is_empty());
+
+        confirmations.confirm(&oracle, 102).unwrap();
+        assert_eq!(confirmations.observe_confirmed_slot(110, 2).unwrap(), 0);
+        assert_eq!(
+            confirmations.stats().unwrap(),
+            ConfirmationStats {
+                pending: 0,
+                confirmed: 2,
+                rolled_back: 1,
+                dead_slots: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_dead_slot_rollback() {
+        let confirmations = ProcessedConfirmations::default();
+        let (account, bank, oracle) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        for (address, slot, message_type) in [
+            (account, 100, MessageType::MarginfiAccount),
+            (bank, 101, MessageType::Bank),
+            (oracle, 101, MessageType::Oracle),
+        ] {
+            confirmations
+                .observe_slot_update(address, slot, message_type)
+                .unwrap();
+            confirmations
+                .observe_processed(address, slot, message_type)
+                .unwrap();
+        }
+
+        assert_eq!(confirmations.observe_dead_slot(101).unwrap(), 2);
+        // Notified again, e.g. by the other endpoint.
+        assert_eq!(confirmations.observe_dead_slot(101).unwrap(), 0);
+        assert!(confirmations.is_dead_slot(101).unwrap());
+        assert!(!confirmations.is_dead_slot(100).unwrap());
+        let mut rollbacks = confirmations.take_rollbacks().unwrap();
+        rollbacks.sort_by_key(|(_, message_type)| *message_type as u8);
+        let mut expected = vec![(bank, MessageType::Bank), (oracle, MessageType::Oracle)];
+        expected.sort_by_key(|(_, message_type)| *message_type as u8);
+        assert_eq!(rollbacks, expected);
+        assert_eq!(
+            confirmations.stats().unwrap(),
+            ConfirmationStats {
+                pending: 1,
+                confirmed: 0,
+                rolled_back: 2,
+                dead_slots: 1,
+            }
+        );
+
+        // The slots too old to die are forgotten.
+        confirmations
+            .observe_slot_update(bank, 102 + DEAD_SLOT_WINDOW, MessageType::Bank)
+            .unwrap();
+        assert!(!confirmations.is_dead_slot(101).unwrap());
+        assert_eq!(confirmations.observe_dead_slot(100).unwrap(), 0);
+        assert_eq!(confirmations.stats().unwrap().dead_slots, 1);
+    }
+}
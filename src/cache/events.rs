@@ -0,0 +1,94 @@
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::{anyhow, Result};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use solana_sdk::pubkey::Pubkey;
+
+// The events a subscriber can lag behind, the next ones being dropped for it until it catches up.
+const SUBSCRIBER_CAPACITY: usize = 10_000;
+
+/// A live change of the cache, as streamed by Geyser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheEvent {
+    BankUpdated(Pubkey),
+    OraclePriceChanged(Pubkey),
+    AccountUpdated(Pubkey),
+    AccountClosed(Pubkey),
+}
+
+/// Broadcasts the cache changes to the subscribers, for them to react to the changes rather than to poll
+/// the cache. The events are a notification only: a lagging subscriber misses some of them, and reads
+/// the cache anyway.
+#[derive(Default)]
+pub struct CacheEvents {
+    subscribers: Mutex<Vec<Sender<CacheEvent>>>,
+}
+
+impl CacheEvents {
+    /// The subscriber receives the events published from now on, until the receiver is dropped.
+    pub fn subscribe(&self) -> Result<Receiver<CacheEvent>> {
+        let (sender, receiver) = channel::bounded(SUBSCRIBER_CAPACITY);
+        self.lock_subscribers()?.push(sender);
+        Ok(receiver)
+    }
+
+    /// Publishes the events in order, under a single lock acquisition.
+    pub fn publish(&self, events: &[CacheEvent]) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        self.lock_subscribers()?.retain(|subscriber| {
+            events.iter().all(|event| {
+                !matches!(
+                    subscriber.try_send(*event),
+                    Err(TrySendError::Disconnected(_))
+                )
+            })
+        });
+        Ok(())
+    }
+
+    fn lock_subscribers(&self) -> Result<MutexGuard<'_, Vec<Sender<CacheEvent>>>> {
+        self.subscribers
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the cache event subscribers: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_events() {
+        let events = CacheEvents::default();
+        let bank = Pubkey::new_unique();
+        // No subscriber yet.
+        events.publish(&[CacheEvent::BankUpdated(bank)]).unwrap();
+
+        let first = events.subscribe().unwrap();
+        let second = events.subscribe().unwrap();
+        let oracle = Pubkey::new_unique();
+        events
+            .publish(&[
+                CacheEvent::BankUpdated(bank),
+                CacheEvent::OraclePriceChanged(oracle),
+            ])
+            .unwrap();
+        assert_eq!(first.try_recv().unwrap(), CacheEvent::BankUpdated(bank));
+        assert_eq!(
+            first.try_recv().unwrap(),
+            CacheEvent::OraclePriceChanged(oracle)
+        );
+        assert_eq!(second.try_recv().unwrap(), CacheEvent::BankUpdated(bank));
+        assert!(first.try_recv().is_err());
+
+        // The dropped subscribers are removed, the lagging ones miss the events over their capacity.
+        drop(second);
+        for _ in 0..SUBSCRIBER_CAPACITY + 1 {
+            events.publish(&[CacheEvent::BankUpdated(bank)]).unwrap();
+        }
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(first.try_iter().count(), SUBSCRIBER_CAPACITY);
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{
+    cache::Cache,
+    comms::{CommsClient, MarginfiAccountHeader},
+    config::Config,
+};
+
+/// Fills in the full bodies of the Marginfi accounts loaded as headers, the lowest headroom first, so
+/// that the bot is effective on the riskiest accounts within seconds while the long tail loads. The
+/// batches are throttled not to compete with the latency sensitive RPC reads.
+pub struct MarginfiAccountsHydrator<T: CommsClient> {
+    stop: Arc<AtomicBool>,
+    cache: Arc<Cache>,
+    comms_client: T,
+    batch_size: usize,
+    interval: Duration,
+    // The headers still to hydrate, the riskiest first.
+    pending: Mutex<VecDeque<MarginfiAccountHeader>>,
+}
+
+impl<T: CommsClient> MarginfiAccountsHydrator<T> {
+    pub fn new(config: &Config, stop: Arc<AtomicBool>, cache: Arc<Cache>) -> Result<Self> {
+        Ok(Self {
+            stop,
+            cache,
+            comms_client: T::new(config)?,
+            batch_size: config.cold_hydration_batch_size.max(1),
+            interval: Duration::from_millis(config.cold_hydration_interval_ms),
+            pending: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    pub fn enqueue(&self, mut headers: Vec<MarginfiAccountHeader>) -> Result<()> {
+        headers.sort_by(|a, b| a.headroom().total_cmp(&b.headroom()));
+        self.lock_pending()?.extend(headers);
+        Ok(())
+    }
+
+    /// Whether some accounts are yet to be hydrated, i.e. the cache is incomplete.
+    pub fn is_hydrating(&self) -> Result<bool> {
+        Ok(!self.lock_pending()?.is_empty())
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let started = Instant::now();
+        let mut hydrated = 0;
+        loop {
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let batch: Vec<Pubkey> = self
+                .lock_pending()?
+                .iter()
+                .take(self.batch_size)
+                .map(|header| header.address)
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            // A failed batch is retried after the interval, it stays pending meanwhile.
+            match self.hydrate(&batch) {
+                Ok(count) => {
+                    hydrated += count;
+                    self.lock_pending()?.drain(..batch.len());
+                    debug!("Hydrated {} Marginfi accounts", hydrated);
+                }
+                Err(err) => warn!("Failed to hydrate the Marginfi accounts: {}", err),
+            }
+            thread::sleep(self.interval);
+        }
+
+        info!(
+            "Hydrated {} Marginfi accounts in {:?}",
+            hydrated,
+            started.elapsed()
+        );
+        Ok(())
+    }
+
+    fn hydrate(&self, addresses: &[Pubkey]) -> Result<usize> {
+        // The accounts Geyser has already delivered in full are not fetched again.
+        let addresses: Vec<Pubkey> = addresses
+            .iter()
+            .filter(|address| self.cache.marginfi_accounts.get_account(address).is_err())
+            .copied()
+            .collect();
+        // The accounts are read at a slot not older than the cache one.
+        let slot = self.cache.get_clock()?.slot;
+        let accounts = self.comms_client.get_accounts(&addresses)?;
+        let hydrated = accounts.len();
+        for (address, account) in accounts {
+            self.cache
+                .marginfi_accounts
+                .update_data(slot, address, &account.data)?;
+        }
+        Ok(hydrated)
+    }
+
+    fn lock_pending(&self) -> Result<MutexGuard<'_, VecDeque<MarginfiAccountHeader>>> {
+        self.pending
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the pending hydrations: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use fixed::types::I80F48;
+    use solana_sdk::account::Account;
+
+    use super::*;
+    use crate::{
+        cache::{
+            marginfi_accounts::{test_util::create_marginfi_account, CachedMarginfiAccount},
+            test_util::create_dummy_cache,
+        },
+        comms::test_util::MockedCommsClient,
+    };
+
+    fn header(assets: i64, liabilities: i64) -> MarginfiAccountHeader {
+        MarginfiAccountHeader {
+            address: Pubkey::new_unique(),
+            asset_value_maint: I80F48::from_num(assets),
+            liability_value_maint: I80F48::from_num(liabilities),
+        }
+    }
+
+    fn create_hydrator(
+        accounts: HashMap<Pubkey, Account>,
+    ) -> MarginfiAccountsHydrator<MockedCommsClient> {
+        MarginfiAccountsHydrator {
+            stop: Arc::new(AtomicBool::new(false)),
+            cache: Arc::new(create_dummy_cache()),
+            comms_client: MockedCommsClient::with_accounts(accounts),
+            batch_size: 2,
+            interval: Duration::ZERO,
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    #[test]
+    fn test_enqueue_riskiest_first() {
+        let hydrator = create_hydrator(HashMap::new());
+        let safe = header(100, 10);
+        let risky = header(100, 95);
+        let empty = header(0, 0);
+        let underwater = header(0, 5);
+        hydrator
+            .enqueue(vec![safe, empty, risky, underwater])
+            .unwrap();
+
+        let order: Vec<Pubkey> = hydrator
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|header| header.address)
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                underwater.address,
+                risky.address,
+                safe.address,
+                empty.address
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let headers: Vec<MarginfiAccountHeader> = (0..3).map(|_| header(100, 50)).collect();
+        let accounts = headers
+            .iter()
+            .map(|header| {
+                let data = CachedMarginfiAccount::from(
+                    0,
+                    header.address,
+                    create_marginfi_account(Pubkey::new_unique(), vec![]),
+                )
+                .to_snapshot_account()
+                .data;
+                let account = Account {
+                    lamports: 0,
+                    data,
+                    owner: Pubkey::new_unique(),
+                    executable: false,
+                    rent_epoch: 0,
+                };
+                (header.address, account)
+            })
+            .collect();
+        let hydrator = create_hydrator(accounts);
+        hydrator.enqueue(headers.clone()).unwrap();
+        assert!(hydrator.is_hydrating().unwrap());
+
+        hydrator.run().unwrap();
+
+        assert!(!hydrator.is_hydrating().unwrap());
+        for header in headers {
+            assert!(hydrator
+                .cache
+                .marginfi_accounts
+                .get_account(&header.address)
+                .is_ok());
+        }
+    }
+}
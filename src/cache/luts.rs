@@ -1,7 +1,9 @@
 use std::sync::RwLock;
 
 use anyhow::{anyhow, Result};
-use solana_sdk::address_lookup_table::AddressLookupTableAccount;
+use solana_sdk::{
+    address_lookup_table::AddressLookupTableAccount, message::VersionedMessage, pubkey::Pubkey,
+};
 
 #[derive(Default)]
 // TODO: the LUTs cache is effectively read-only after population. Come up with better way to share it lock free
@@ -19,7 +21,6 @@ impl LutsCache {
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn get_all(&self) -> Result<Vec<AddressLookupTableAccount>> {
         let read_guard = self
             .luts
@@ -27,11 +28,86 @@ impl LutsCache {
             .map_err(|e| anyhow!("Failed to lock the LUTs cache for reading: {}", e))?;
         Ok(read_guard.clone())
     }
+
+    /// Adds the LUT or replaces the cached one with the same address.
+    pub fn insert(&self, lut: AddressLookupTableAccount) -> Result<()> {
+        let mut write_guard = self
+            .luts
+            .write()
+            .map_err(|e| anyhow!("Failed to lock the LUTs cache for update: {}", e))?;
+        match write_guard.iter_mut().find(|cached| cached.key == lut.key) {
+            Some(cached) => *cached = lut,
+            None => write_guard.push(lut),
+        }
+        Ok(())
+    }
+
+    /// Returns the addresses of the given LUTs that are not cached yet.
+    pub fn missing(&self, addresses: &[Pubkey]) -> Result<Vec<Pubkey>> {
+        let read_guard = self
+            .luts
+            .read()
+            .map_err(|e| anyhow!("Failed to lock the LUTs cache for reading: {}", e))?;
+        let mut missing: Vec<Pubkey> = addresses
+            .iter()
+            .filter(|address| !read_guard.iter().any(|lut| lut.key == **address))
+            .copied()
+            .collect();
+        missing.sort();
+        missing.dedup();
+        Ok(missing)
+    }
+
+    /// Expands the account keys of the message with the cached LUTs, in the order used by the runtime:
+    /// the static keys, then the writable and finally the readonly loaded addresses.
+    pub fn resolve_message(&self, message: &VersionedMessage) -> Result<Vec<Pubkey>> {
+        let mut keys = message.static_account_keys().to_vec();
+        let Some(lookups) = message.address_table_lookups() else {
+            return Ok(keys);
+        };
+
+        let read_guard = self
+            .luts
+            .read()
+            .map_err(|e| anyhow!("Failed to lock the LUTs cache for reading: {}", e))?;
+        let mut writable = Vec::new();
+        let mut readonly = Vec::new();
+        for lookup in lookups {
+            let lut = read_guard
+                .iter()
+                .find(|lut| lut.key == lookup.account_key)
+                .ok_or_else(|| anyhow!("LUT {} not found in cache", lookup.account_key))?;
+            for (indexes, resolved) in [
+                (&lookup.writable_indexes, &mut writable),
+                (&lookup.readonly_indexes, &mut readonly),
+            ] {
+                for index in indexes {
+                    let address = lut.addresses.get(*index as usize).ok_or_else(|| {
+                        anyhow!(
+                            "Index {} is out of the bounds of the LUT {} ({} addresses)",
+                            index,
+                            lut.key,
+                            lut.addresses.len()
+                        )
+                    })?;
+                    resolved.push(*address);
+                }
+            }
+        }
+
+        keys.append(&mut writable);
+        keys.append(&mut readonly);
+        Ok(keys)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use solana_sdk::pubkey::Pubkey;
+    use solana_sdk::{
+        hash::Hash,
+        instruction::{AccountMeta, Instruction},
+        message::v0,
+    };
 
     use super::*;
 
@@ -79,4 +155,89 @@ mod tests {
         let read_guard = cache.luts.read().unwrap();
         assert_eq!(read_guard.len(), 0);
     }
+
+    #[test]
+    fn test_insert_replaces_by_key() {
+        let cache = LutsCache::default();
+        let key = Pubkey::new_unique();
+        cache.insert(dummy_lut(key)).unwrap();
+        cache.insert(dummy_lut(Pubkey::new_unique())).unwrap();
+        let replacement = dummy_lut(key);
+        cache.insert(replacement.clone()).unwrap();
+
+        let luts = cache.get_all().unwrap();
+        assert_eq!(luts.len(), 2);
+        assert_eq!(luts[0], replacement);
+    }
+
+    #[test]
+    fn test_missing() {
+        let cache = LutsCache::default();
+        let cached = Pubkey::new_unique();
+        let missing = Pubkey::new_unique();
+        cache.insert(dummy_lut(cached)).unwrap();
+
+        assert_eq!(
+            cache.missing(&[cached, missing, missing]).unwrap(),
+            vec![missing]
+        );
+    }
+
+    fn compile_message(
+        payer: Pubkey,
+        writable: Pubkey,
+        readonly: Pubkey,
+        luts: &[AddressLookupTableAccount],
+    ) -> VersionedMessage {
+        let program_id = Pubkey::new_unique();
+        let instruction = Instruction::new_with_bytes(
+            program_id,
+            &[],
+            vec![
+                AccountMeta::new(writable, false),
+                AccountMeta::new_readonly(readonly, false),
+            ],
+        );
+        VersionedMessage::V0(
+            v0::Message::try_compile(&payer, &[instruction], luts, Hash::new_unique()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_resolve_message() {
+        let cache = LutsCache::default();
+        let payer = Pubkey::new_unique();
+        let lut = dummy_lut(Pubkey::new_unique());
+        let (writable, readonly) = (lut.addresses[0], lut.addresses[1]);
+        let message = compile_message(payer, writable, readonly, &[lut.clone()]);
+
+        assert!(cache.resolve_message(&message).is_err());
+
+        cache.insert(lut).unwrap();
+        let keys = cache.resolve_message(&message).unwrap();
+        let static_keys = message.static_account_keys();
+        assert_eq!(&keys[..static_keys.len()], static_keys);
+        assert_eq!(&keys[static_keys.len()..], &[writable, readonly]);
+    }
+
+    #[test]
+    fn test_resolve_message_out_of_bounds_index() {
+        let cache = LutsCache::default();
+        let lut = dummy_lut(Pubkey::new_unique());
+        let message = compile_message(
+            Pubkey::new_unique(),
+            lut.addresses[0],
+            lut.addresses[1],
+            &[lut.clone()],
+        );
+        cache
+            .insert(AddressLookupTableAccount {
+                key: lut.key,
+                addresses: vec![lut.addresses[0]],
+            })
+            .unwrap();
+
+        let result = cache.resolve_message(&message);
+        assert!(format!("{}", result.unwrap_err()).contains("out of the bounds"));
+    }
 }
@@ -1,29 +1,81 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry as HashMapEntry, BTreeSet, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
 
 use anchor_lang::AccountDeserialize;
 use anyhow::{anyhow, Result};
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use fixed::types::I80F48;
-use log::{info, trace, warn};
+use log::{debug, info, trace, warn};
 use marginfi::state::marginfi_account::{Balance, MarginfiAccount};
 use solana_sdk::pubkey::Pubkey;
 use std::mem::size_of;
 
 use crate::cache::snapshot::SnapshotAccount;
 use crate::{
-    cache::CacheEntry,
+    cache::{account_store::MarginfiAccountStore, CacheEntry},
     common::{MARGINFI_ACCOUNT_DISCRIMINATOR, MARGINFI_ACCOUNT_DISCRIMINATOR_LEN},
+    config::Config,
 };
 use bytemuck::bytes_of;
 
+/// The account as deserialized once on ingest, with the data it was deserialized from, so that neither the
+/// scans cast the health cache again nor the snapshots and the stores serialize the account again.
 #[derive(Clone)]
 pub struct CachedMarginfiAccount {
     slot: u64,
     address: Pubkey,
     _marginfi_account: MarginfiAccount,
     _positions: Vec<Balance>,
+    asset_value_maint: I80F48,
+    liability_value_maint: I80F48,
+    data: Arc<[u8]>,
 }
 
-const INVALID_HEALTH: i64 = i64::MIN;
+pub const INVALID_HEALTH: i64 = i64::MIN;
+
+/// The number of accounts in each health bucket.
+#[derive(Debug, Default, PartialEq)]
+pub struct HealthDistribution {
+    pub hot: usize,  // HF < 1.1
+    pub warm: usize, // HF < 1.3
+    pub cold: usize, // HF >= 1.3
+    pub invalid: usize,
+}
+
+impl HealthDistribution {
+    pub fn from(accounts: &HashMap<Pubkey, i64>) -> Self {
+        Self::from_healths(accounts.values().copied())
+    }
+
+    pub fn from_healths(healths: impl IntoIterator<Item = i64>) -> Self {
+        let mut distribution = Self::default();
+        for health in healths {
+            if health == INVALID_HEALTH {
+                distribution.invalid += 1;
+                continue;
+            }
+            let hf = health as f64;
+            if hf < 1.1 {
+                distribution.hot += 1;
+            } else if hf < 1.3 {
+                distribution.warm += 1;
+            } else {
+                distribution.cold += 1;
+            }
+        }
+        distribution
+    }
+
+    pub fn total(&self) -> usize {
+        self.hot + self.warm + self.cold + self.invalid
+    }
+}
 
 impl std::fmt::Debug for CachedMarginfiAccount {
     // TODO: add more relevant fields
@@ -39,6 +91,33 @@ impl CacheEntry for CachedMarginfiAccount {}
 
 impl CachedMarginfiAccount {
     pub fn from(slot: u64, address: Pubkey, marginfi_account: MarginfiAccount) -> Self {
+        let data = serialize_marginfi_account(&marginfi_account).into();
+        Self::with_data(slot, address, marginfi_account, data)
+    }
+
+    /// Deserializes the account data, which is kept as is.
+    pub fn from_data(slot: u64, address: Pubkey, data: &[u8]) -> Result<Self> {
+        let marginfi_account = MarginfiAccount::try_deserialize(&mut &data[..]).map_err(|e| {
+            anyhow!(
+                "Failed to deserialize the Marginfi account {}: {}",
+                address,
+                e
+            )
+        })?;
+        Ok(Self::with_data(
+            slot,
+            address,
+            marginfi_account,
+            data.into(),
+        ))
+    }
+
+    fn with_data(
+        slot: u64,
+        address: Pubkey,
+        marginfi_account: MarginfiAccount,
+        data: Arc<[u8]>,
+    ) -> Self {
         let positions = marginfi_account
             .lending_account
             .balances
@@ -52,193 +131,571 @@ impl CachedMarginfiAccount {
             address,
             _marginfi_account: marginfi_account,
             _positions: positions,
+            asset_value_maint: marginfi_account.health_cache.asset_value_maint.into(),
+            liability_value_maint: marginfi_account.health_cache.liability_value_maint.into(),
+            data,
         }
     }
 
+    pub fn address(&self) -> Pubkey {
+        self.address
+    }
+
+    pub fn slot(&self) -> u64 {
+        self.slot
+    }
+
+    pub fn authority(&self) -> Pubkey {
+        self._marginfi_account.authority
+    }
+
+    pub fn group(&self) -> Pubkey {
+        self._marginfi_account.group
+    }
+
     #[inline]
     pub fn asset_value_maint(&self) -> I80F48 {
-        self._marginfi_account.health_cache.asset_value_maint.into()
+        self.asset_value_maint
     }
 
     #[inline]
     pub fn liability_value_maint(&self) -> I80F48 {
-        self._marginfi_account
-            .health_cache
-            .liability_value_maint
-            .into()
+        self.liability_value_maint
     }
 
+    /// The health as cached on-chain at the last operation of the account.
     #[inline]
     pub fn health(&self) -> Option<i64> {
-        (self.asset_value_maint() - self.liability_value_maint())
-            .checked_div(self.asset_value_maint())
-            .map(|v| v.to_num::<i64>())
+        health_of(self.asset_value_maint(), self.liability_value_maint())
+    }
+
+    /// The approximate memory held by the cached account.
+    pub fn memory_bytes(&self) -> usize {
+        size_of::<Self>() + self._positions.capacity() * size_of::<Balance>() + self.data.len()
     }
 
     pub fn _positions(&self) -> &Vec<Balance> {
         &self._positions
     }
+
+    /// Whether any active position borrows, which only such accounts can be liquidated for.
+    pub fn has_liabilities(&self) -> bool {
+        self._positions
+            .iter()
+            .any(|balance| I80F48::from(balance.liability_shares).is_positive())
+    }
+
+    /// The Banks of the active positions.
+    pub fn bank_addresses(&self) -> Vec<Pubkey> {
+        self._positions
+            .iter()
+            .map(|balance| balance.bank_pk)
+            .collect()
+    }
+
+    /// The account data as it is cached, serialized like the on-chain account.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn to_snapshot_account(&self) -> SnapshotAccount {
+        SnapshotAccount::new(self.address, self.slot, self.data.to_vec())
+    }
 }
 
+pub fn health_of(asset_value_maint: I80F48, liability_value_maint: I80F48) -> Option<i64> {
+    (asset_value_maint - liability_value_maint)
+        .checked_div(asset_value_maint)
+        .map(|v| v.to_num::<i64>())
+}
+
+// The accounts by descending health, partitioned by their group.
+type HealthPartitions = HashMap<Pubkey, BTreeSet<(Reverse<i64>, Pubkey)>>;
+
+// The health index is sharded like the store, for the Geyser updates not to contend with the full
+// scans of the LiquidationService. The scans and the snapshots only cover the partitions of the
+// configured groups. The updated accounts take their on-chain health until it is recomputed from
+// the cached Banks and Oracles, which is then kept until the account or one of its Banks or Oracles
+// changes.
 #[derive(Default)]
 pub struct MarginfiAccountsCache {
-    accounts: RwLock<HashMap<Pubkey, CachedMarginfiAccount>>,
-    account_to_health: RwLock<HashMap<Pubkey, i64>>,
+    accounts: MarginfiAccountStore,
+    account_to_health: DashMap<Pubkey, i64>,
+    // The accounts by descending health, as the LiquidationService scans them, one partition per group.
+    sorted_by_health: Mutex<HealthPartitions>,
+    // The group of every account, which never changes.
+    account_to_group: DashMap<Pubkey, Pubkey>,
+    // The groups the scans and the snapshots cover, all of them when empty.
+    groups: HashSet<Pubkey>,
+    // The accounts with a position in every Bank, and the reverse, updated with the accounts.
+    bank_to_accounts: DashMap<Pubkey, HashSet<Pubkey>>,
+    account_to_banks: DashMap<Pubkey, Vec<Pubkey>>,
+    // The accounts of every authority, and the reverse, the authority being transferable.
+    authority_to_accounts: DashMap<Pubkey, HashSet<Pubkey>>,
+    account_to_authority: DashMap<Pubkey, Pubkey>,
+    // The accounts updated since their health was last recomputed.
+    pending_health: DashSet<Pubkey>,
+    // Whether the accounts with no active balance are evicted instead of cached.
+    evict_empty: bool,
+    evictions: AtomicU64,
 }
 
 impl MarginfiAccountsCache {
+    /// Opens the configured store, with the health index of the accounts it already holds.
+    pub fn open(config: &Config) -> Result<Self> {
+        let cache = Self {
+            accounts: MarginfiAccountStore::open(config)?,
+            evict_empty: config.cache_evict_empty_accounts,
+            groups: config.marginfi_groups.iter().copied().collect(),
+            ..Self::default()
+        };
+        cache.accounts.for_each_where(
+            |_, _| true,
+            |cached| {
+                let health = cached.health().unwrap_or(INVALID_HEALTH);
+                cache.account_to_health.insert(cached.address, health);
+                cache.index(cached);
+                cache.pending_health.insert(cached.address);
+            },
+        )?;
+        let mut sorted_by_health = HealthPartitions::new();
+        for health in cache.account_to_health.iter() {
+            sorted_by_health
+                .entry(cache.group_of(health.key()))
+                .or_default()
+                .insert((Reverse(*health.value()), *health.key()));
+        }
+        *cache.lock_sorted_by_health()? = sorted_by_health;
+
+        Ok(cache)
+    }
+
     pub fn update(&self, slot: u64, address: Pubkey, account: MarginfiAccount) -> Result<()> {
-        let upd_cached_account = CachedMarginfiAccount::from(slot, address, account);
+        self.update_cached(CachedMarginfiAccount::from(slot, address, account))
+    }
+
+    /// Updates the account with its data as ingested, deserialized only once.
+    pub fn update_data(&self, slot: u64, address: Pubkey, data: &[u8]) -> Result<()> {
+        self.update_cached(CachedMarginfiAccount::from_data(slot, address, data)?)
+    }
+
+    /// Updates the account as deserialized ahead, e.g. by the Geyser decode workers.
+    pub fn update_cached(&self, upd_cached_account: CachedMarginfiAccount) -> Result<()> {
+        let address = upd_cached_account.address;
         let upd_cached_account_health = upd_cached_account.health();
 
-        let mut accounts = self.accounts.write().map_err(|e| {
-            anyhow!(
-                "Failed to lock the Marginfi accounts cache for update! {}",
-                e
-            )
-        })?;
-        let mut health = self.account_to_health.write().map_err(|e| {
-            anyhow!(
-                "Failed to lock the Marginfi account health cache for update! {}",
-                e
-            )
-        })?;
+        // The health entry locks its shard until the update is done, serializing the updates of the account.
+        let health_entry = self.account_to_health.entry(address);
+
+        if self.accounts.slot(&address)?.map_or(true, |existing_slot| {
+            existing_slot <= upd_cached_account.slot
+        }) {
+            // Marginfi only closes the accounts with no balance, so the closed accounts are evicted as they get empty.
+            if self.evict_empty && upd_cached_account._positions.is_empty() {
+                if self.evict(&address, health_entry)? {
+                    debug!("Evicted the empty Marginfi account {}", address);
+                }
+                return Ok(());
+            }
 
-        if accounts
-            .get(&address)
-            .map_or(true, |existing| existing.slot < upd_cached_account.slot)
-        {
             trace!(
                 "Updating the Marginfi Account in cache: {:?}",
                 upd_cached_account
             );
-            accounts.insert(address, upd_cached_account);
+            self.index(&upd_cached_account);
+            self.accounts.insert(upd_cached_account)?;
+
+            let upd_health = upd_cached_account_health.unwrap_or_else(|| {
+                warn!(
+                    "Failed to compute health for account {}, invalidating it",
+                    address
+                );
+                INVALID_HEALTH
+            });
+            self.set_health(health_entry, upd_health)?;
+            self.pending_health.insert(address);
+        }
 
-            match upd_cached_account_health {
-                Some(upd_health) => {
-                    health.insert(address, upd_health);
-                }
-                None => {
-                    warn!(
-                        "Failed to compute health for account {}, invalidating it",
-                        address
-                    );
-                    health.insert(address, INVALID_HEALTH);
+        Ok(())
+    }
+
+    /// Evicts the account closed, or reassigned to another program, at the slot, unless it was updated since.
+    /// Returns whether it was cached.
+    pub fn remove_closed(&self, slot: u64, address: &Pubkey) -> Result<bool> {
+        let health_entry = self.account_to_health.entry(*address);
+        if self
+            .accounts
+            .slot(address)?
+            .is_some_and(|existing_slot| existing_slot > slot)
+        {
+            return Ok(false);
+        }
+        self.evict(address, health_entry)
+    }
+
+    /// Returns whether the account was cached.
+    fn evict(&self, address: &Pubkey, health_entry: Entry<'_, Pubkey, i64>) -> Result<bool> {
+        if !self.accounts.remove(address)? {
+            return Ok(false);
+        }
+        self.remove_health(health_entry)?;
+        self.unindex(address);
+        self.pending_health.remove(address);
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    fn remove_health(&self, health_entry: Entry<'_, Pubkey, i64>) -> Result<()> {
+        if let Entry::Occupied(health) = health_entry {
+            let group = self.group_of(health.key());
+            let (address, health) = health.remove_entry();
+            if let HashMapEntry::Occupied(mut partition) =
+                self.lock_sorted_by_health()?.entry(group)
+            {
+                partition.get_mut().remove(&(Reverse(health), address));
+                if partition.get().is_empty() {
+                    partition.remove();
                 }
             }
         }
+        Ok(())
+    }
 
+    // Keeps the sorted index in line with the health entry, whose shard lock is held meanwhile.
+    fn set_health(&self, health_entry: Entry<'_, Pubkey, i64>, health: i64) -> Result<()> {
+        let address = *health_entry.key();
+        let group = self.group_of(&address);
+        let mut partitions = self.lock_sorted_by_health()?;
+        let sorted_by_health = partitions.entry(group).or_default();
+        match health_entry {
+            Entry::Occupied(mut entry) => {
+                let previous = entry.insert(health);
+                sorted_by_health.remove(&(Reverse(previous), address));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(health);
+            }
+        }
+        sorted_by_health.insert((Reverse(health), address));
         Ok(())
     }
 
-    pub fn get_account(&self, address: &Pubkey) -> Result<CachedMarginfiAccount> {
-        self.accounts
-            .read()
-            .map_err(|e| {
-                anyhow!(
-                    "Failed to lock the Marginfi accounts cache for getting an account: {}",
-                    e
-                )
-            })?
+    fn lock_sorted_by_health(&self) -> Result<MutexGuard<'_, HealthPartitions>> {
+        self.sorted_by_health
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the sorted health index: {}", e))
+    }
+
+    // The group is unknown for the accounts not cached, which are then in the default partition.
+    fn group_of(&self, address: &Pubkey) -> Pubkey {
+        self.account_to_group
             .get(address)
-            .cloned()
+            .map(|group| *group)
+            .unwrap_or_default()
+    }
+
+    /// Whether the group is one the scans and the snapshots cover.
+    pub fn is_scanned_group(&self, group: &Pubkey) -> bool {
+        self.groups.is_empty() || self.groups.contains(group)
+    }
+
+    // Called with the health entry of the account held, for its index entries to be updated at once.
+    fn index(&self, account: &CachedMarginfiAccount) {
+        self.account_to_group
+            .insert(account.address, account.group());
+
+        let banks = account.bank_addresses();
+        let previous = self
+            .account_to_banks
+            .insert(account.address, banks.clone())
+            .unwrap_or_default();
+        for bank in previous.iter().filter(|bank| !banks.contains(bank)) {
+            remove_from_index(&self.bank_to_accounts, bank, &account.address);
+        }
+        for bank in banks.iter().filter(|bank| !previous.contains(bank)) {
+            self.bank_to_accounts
+                .entry(*bank)
+                .or_default()
+                .insert(account.address);
+        }
+
+        let authority = account.authority();
+        let previous = self.account_to_authority.insert(account.address, authority);
+        if previous != Some(authority) {
+            if let Some(previous) = previous {
+                remove_from_index(&self.authority_to_accounts, &previous, &account.address);
+            }
+            self.authority_to_accounts
+                .entry(authority)
+                .or_default()
+                .insert(account.address);
+        }
+    }
+
+    fn unindex(&self, address: &Pubkey) {
+        self.account_to_group.remove(address);
+        if let Some((_, banks)) = self.account_to_banks.remove(address) {
+            for bank in &banks {
+                remove_from_index(&self.bank_to_accounts, bank, address);
+            }
+        }
+        if let Some((_, authority)) = self.account_to_authority.remove(address) {
+            remove_from_index(&self.authority_to_accounts, &authority, address);
+        }
+    }
+
+    /// The accounts controlled by the authority.
+    pub fn get_accounts_by_authority(&self, authority: &Pubkey) -> Vec<Pubkey> {
+        self.authority_to_accounts
+            .get(authority)
+            .map(|accounts| accounts.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// The health of the account, as of its last update or recomputation.
+    pub fn get_health(&self, address: &Pubkey) -> Option<i64> {
+        self.account_to_health.get(address).map(|health| *health)
+    }
+
+    /// The accounts with a position in the Bank.
+    pub fn get_accounts_by_bank(&self, bank: &Pubkey) -> Vec<Pubkey> {
+        self.bank_to_accounts
+            .get(bank)
+            .map(|accounts| accounts.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Recomputes the health of the accounts updated since the last recomputation, and of the accounts with
+    /// positions in the given Banks. The accounts whose health cannot be computed are invalidated. Returns
+    /// the number of recomputed accounts.
+    pub fn recompute_health(
+        &self,
+        updated_banks: &HashSet<Pubkey>,
+        compute: impl Fn(&CachedMarginfiAccount) -> Option<i64>,
+    ) -> Result<usize> {
+        let pending: Vec<Pubkey> = self.pending_health.iter().map(|address| *address).collect();
+        let mut dependents: HashSet<Pubkey> = pending
+            .into_iter()
+            .filter(|address| self.pending_health.remove(address).is_some())
+            .collect();
+        for bank in updated_banks {
+            dependents.extend(self.get_accounts_by_bank(bank));
+        }
+
+        let mut recomputed = 0;
+        for address in dependents {
+            let health_entry = self.account_to_health.entry(address);
+            // Evicted since.
+            let Some(account) = self.accounts.get(&address)? else {
+                continue;
+            };
+            self.set_health(health_entry, compute(&account).unwrap_or(INVALID_HEALTH))?;
+            recomputed += 1;
+        }
+        Ok(recomputed)
+    }
+
+    /// The number of cached accounts, of all the groups.
+    pub fn count(&self) -> usize {
+        self.account_to_health.len()
+    }
+
+    /// The number of accounts evicted so far.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// The approximate memory held by the accounts and their health indexes.
+    pub fn memory_bytes(&self) -> usize {
+        // Every position is indexed both ways.
+        let bank_to_accounts: usize = self
+            .bank_to_accounts
+            .iter()
+            .map(|accounts| (1 + 2 * accounts.len()) * size_of::<Pubkey>())
+            .sum();
+        self.accounts.memory_bytes()
+            + 2 * self.account_to_health.len() * (size_of::<Pubkey>() + size_of::<i64>())
+            + bank_to_accounts
+            + self.account_to_banks.len() * size_of::<Pubkey>()
+            // Both ways for the authorities.
+            + self.account_to_authority.len() * 4 * size_of::<Pubkey>()
+            + self.account_to_group.len() * 2 * size_of::<Pubkey>()
+            + self.pending_health.len() * size_of::<Pubkey>()
+    }
+
+    /// Evicts the healthiest accounts, the least likely to be liquidated, until the accounts hold about
+    /// `max_bytes`. The accounts with an invalid health are kept. Returns the number of evicted accounts.
+    pub fn evict_healthiest(&self, max_bytes: usize) -> Result<usize> {
+        let bytes = self.memory_bytes();
+        let count = self.account_to_health.len();
+        if bytes <= max_bytes || count == 0 {
+            return Ok(0);
+        }
+        let excess = (bytes - max_bytes).div_ceil((bytes / count).max(1));
+
+        let mut healths: Vec<(Pubkey, i64)> = self
+            .account_to_health
+            .iter()
+            .filter(|health| *health.value() != INVALID_HEALTH)
+            .map(|health| (*health.key(), *health.value()))
+            .collect();
+        healths.sort_unstable_by_key(|(_, health)| Reverse(*health));
+
+        let mut evicted = 0;
+        for (address, _) in healths.into_iter().take(excess) {
+            if self.evict(&address, self.account_to_health.entry(address))? {
+                evicted += 1;
+            }
+        }
+        Ok(evicted)
+    }
+
+    pub fn get_account(&self, address: &Pubkey) -> Result<CachedMarginfiAccount> {
+        self.find_account(address)?
             .ok_or_else(|| anyhow!("Account {} not found in cache", address))
     }
 
+    /// None when the account is not cached.
+    pub fn find_account(&self, address: &Pubkey) -> Result<Option<CachedMarginfiAccount>> {
+        self.accounts.get(address)
+    }
+
+    /// The health of the accounts of the scanned groups, the other partitions being left out.
     pub fn get_accounts_with_health(&self) -> Result<HashMap<Pubkey, i64>> {
-        let snapshot = self
-            .account_to_health
-            .read()
-            .map_err(|e| {
-                anyhow!(
-                    "Failed to lock the Marginfi account health cache for cloning: {}",
-                    e
-                )
-            })?
-            .clone();
+        let snapshot: HashMap<Pubkey, i64> = self
+            .lock_sorted_by_health()?
+            .iter()
+            .filter(|(group, _)| self.is_scanned_group(group))
+            .flat_map(|(_, partition)| partition.iter())
+            .map(|(Reverse(health), address)| (*address, *health))
+            .collect();
 
         Self::log_health_distribution(&snapshot);
         Ok(snapshot)
     }
 
-    fn log_health_distribution(accounts: &HashMap<Pubkey, i64>) {
-        let mut hot = 0usize; // HF < 1.1
-        let mut warm = 0usize; // HF < 1.3
-        let mut cold = 0usize; // HF >= 1.3
-        let mut invalid = 0usize;
-
-        for &health in accounts.values() {
-            if health == INVALID_HEALTH {
-                invalid += 1;
-                continue;
-            }
-            let hf = health as f64;
-            if hf < 1.1 {
-                hot += 1;
-            } else if hf < 1.3 {
-                warm += 1;
-            } else {
-                cold += 1;
-            }
+    /// The accounts of the scanned groups by descending health. The partitions are kept sorted, so only the
+    /// accounts of several scanned groups are sorted, once merged.
+    pub fn get_accounts_sorted_by_health(&self) -> Result<Vec<Pubkey>> {
+        let partitions = self.lock_sorted_by_health()?;
+        let scanned: Vec<&BTreeSet<(Reverse<i64>, Pubkey)>> = partitions
+            .iter()
+            .filter(|(group, _)| self.is_scanned_group(group))
+            .map(|(_, partition)| partition)
+            .collect();
+        let mut sorted: Vec<(Reverse<i64>, Pubkey)> = scanned
+            .iter()
+            .flat_map(|partition| partition.iter())
+            .copied()
+            .collect();
+        if scanned.len() > 1 {
+            sorted.sort_unstable();
         }
+        Ok(sorted.into_iter().map(|(_, address)| address).collect())
+    }
 
+    pub fn get_health_distribution(&self) -> Result<HealthDistribution> {
+        Ok(HealthDistribution::from_healths(
+            self.account_to_health.iter().map(|health| *health.value()),
+        ))
+    }
+
+    fn log_health_distribution(accounts: &HashMap<Pubkey, i64>) {
+        let distribution = HealthDistribution::from(accounts);
         info!(
             "Marginfi accounts health buckets: 🔴 Hot (<1.1): {}, 🟠 Warm (<1.3): {}, 🟢 Cold (>=1.3): {}, ⚪ Invalid: {} (total: {})",
-            hot,
-            warm,
-            cold,
-            invalid,
-            accounts.len()
+            distribution.hot,
+            distribution.warm,
+            distribution.cold,
+            distribution.invalid,
+            distribution.total()
         );
     }
 
     pub(crate) fn snapshot_entries(&self) -> Result<Vec<SnapshotAccount>> {
-        let accounts = self.accounts.read().map_err(|e| {
-            anyhow!(
-                "Failed to lock the Marginfi accounts cache for snapshot: {}",
-                e
-            )
-        })?;
+        self.partition_snapshot_entries_where(|_, _| true)
+    }
 
-        let mut entries = Vec::with_capacity(accounts.len());
-        for cached in accounts.values() {
-            entries.push(SnapshotAccount::new(
-                cached.address,
-                cached.slot,
-                serialize_marginfi_account(&cached._marginfi_account),
-            ));
-        }
+    /// The snapshot entries of the accounts of the scanned groups selected by their address and slot.
+    pub(crate) fn partition_snapshot_entries_where(
+        &self,
+        include: impl Fn(&Pubkey, u64) -> bool,
+    ) -> Result<Vec<SnapshotAccount>> {
+        self.snapshot_entries_where(|address, slot| {
+            self.is_scanned_group(&self.group_of(address)) && include(address, slot)
+        })
+    }
 
-        Ok(entries)
+    /// The snapshot entries of the accounts selected by their address and slot. The accounts are captured
+    /// under the shard locks and serialized once they are released, for the Geyser updates not to wait on
+    /// the serialization.
+    pub(crate) fn snapshot_entries_where(
+        &self,
+        include: impl Fn(&Pubkey, u64) -> bool,
+    ) -> Result<Vec<SnapshotAccount>> {
+        Ok(self
+            .accounts
+            .capture_where(include)?
+            .iter()
+            .map(|cached| cached.to_snapshot_account())
+            .collect())
+    }
+
+    /// The accounts of the scanned groups with liabilities, selected by their address and slot.
+    pub fn get_indebted_accounts_where(
+        &self,
+        include: impl Fn(&Pubkey, u64) -> bool,
+    ) -> Result<Vec<Pubkey>> {
+        let mut addresses = vec![];
+        self.accounts.for_each_where(
+            |address, slot| {
+                self.is_scanned_group(&self.group_of(address)) && include(address, slot)
+            },
+            |cached| {
+                if cached.has_liabilities() {
+                    addresses.push(cached.address());
+                }
+            },
+        )?;
+        Ok(addresses)
     }
 
+    /// Drops the accounts missing from the snapshot, e.g. the closed ones, and applies the snapshot entries.
+    /// The accounts a persistent store holds at a newer slot than the snapshot are kept as they are.
     pub(crate) fn restore_from_snapshot(&self, entries: &[SnapshotAccount]) -> Result<()> {
-        self.accounts
-            .write()
-            .map_err(|e| anyhow!("Failed to lock Marginfi accounts cache for reset: {}", e))?
-            .clear();
-        self.account_to_health
-            .write()
-            .map_err(|e| anyhow!("Failed to lock health cache for reset: {}", e))?
-            .clear();
+        let snapshot_addresses: HashSet<Pubkey> =
+            entries.iter().map(|entry| entry.address).collect();
+        for address in self
+            .accounts
+            .retain(|address| snapshot_addresses.contains(address))?
+        {
+            self.remove_health(self.account_to_health.entry(address))?;
+            self.unindex(&address);
+        }
 
+        self.apply_snapshot_entries(entries)
+    }
+
+    /// Updates the cache with the snapshot entries, on top of the cached accounts.
+    pub(crate) fn apply_snapshot_entries(&self, entries: &[SnapshotAccount]) -> Result<()> {
         for entry in entries {
-            let mut data_slice = entry.data.as_slice();
-            let marginfi_account =
-                MarginfiAccount::try_deserialize(&mut data_slice).map_err(|err| {
-                    anyhow!(
-                        "Failed to deserialize Marginfi account {} from snapshot: {}",
-                        entry.address,
-                        err
-                    )
-                })?;
-            self.update(entry.slot, entry.address, marginfi_account)?;
+            self.update_data(entry.slot, entry.address, &entry.data)
+                .map_err(|err| anyhow!("Failed to restore from snapshot: {}", err))?;
         }
 
         Ok(())
     }
 }
 
+fn remove_from_index(index: &DashMap<Pubkey, HashSet<Pubkey>>, key: &Pubkey, address: &Pubkey) {
+    if let Entry::Occupied(mut accounts) = index.entry(*key) {
+        accounts.get_mut().remove(address);
+        if accounts.get().is_empty() {
+            accounts.remove();
+        }
+    }
+}
+
 fn serialize_marginfi_account(account: &MarginfiAccount) -> Vec<u8> {
     let mut data =
         Vec::with_capacity(MARGINFI_ACCOUNT_DISCRIMINATOR_LEN + size_of::<MarginfiAccount>());
@@ -360,6 +817,31 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cached_marginfi_account_from_data() {
+        let address = Pubkey::new_unique();
+        let mut marginfi_account = create_marginfi_account(
+            Pubkey::new_unique(),
+            vec![create_balance(Pubkey::new_unique(), 100, 50)],
+        );
+        marginfi_account.health_cache.asset_value_maint = I80F48::from_num(10).into();
+        marginfi_account.health_cache.liability_value_maint = I80F48::from_num(4).into();
+        let data = CachedMarginfiAccount::from(1, address, marginfi_account)
+            .data()
+            .to_vec();
+
+        // The data is kept as ingested, and the health cache decoded once.
+        let cached = CachedMarginfiAccount::from_data(2, address, &data).unwrap();
+        assert_eq!(cached.slot(), 2);
+        assert_eq!(cached.data(), data.as_slice());
+        assert_eq!(cached.to_snapshot_account().data, data);
+        assert_eq!(cached._positions().len(), 1);
+        assert_eq!(cached.asset_value_maint(), I80F48::from_num(10));
+        assert_eq!(cached.liability_value_maint(), I80F48::from_num(4));
+
+        assert!(CachedMarginfiAccount::from_data(2, address, &data[..8]).is_err());
+    }
+
     #[test]
     fn test_marginfi_accounts_cache_update_and_retrieve() {
         let cache = MarginfiAccountsCache::default();
@@ -413,6 +895,115 @@ mod tests {
         assert_eq!(health_map.get(&address), Some(&INVALID_HEALTH));
     }
 
+    #[test]
+    fn test_evict_healthiest() {
+        let cache = MarginfiAccountsCache::default();
+        let group = Pubkey::new_unique();
+        let mut accounts = vec![];
+        for liabilities in [0, 1500, 0] {
+            let address = Pubkey::new_unique();
+            let mut marginfi_account =
+                create_marginfi_account(group, vec![create_balance(Pubkey::new_unique(), 1, 0)]);
+            marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
+            marginfi_account.health_cache.liability_value_maint =
+                I80F48::from_num(liabilities).into();
+            cache.update(1, address, marginfi_account).unwrap();
+            accounts.push(address);
+        }
+        // No health can be computed without assets.
+        let invalid = Pubkey::new_unique();
+        cache
+            .update(
+                1,
+                invalid,
+                create_marginfi_account(group, vec![create_balance(Pubkey::new_unique(), 1, 0)]),
+            )
+            .unwrap();
+
+        let bytes = cache.memory_bytes();
+        assert!(bytes > 0);
+        assert_eq!(cache.evict_healthiest(bytes).unwrap(), 0);
+
+        // Over the cap by less than an account, a single account is evicted: one of the healthiest.
+        assert_eq!(cache.evict_healthiest(bytes - 1).unwrap(), 1);
+        assert!(cache.memory_bytes() < bytes);
+        assert_eq!(cache.evictions(), 1);
+        assert!(cache.get_account(&accounts[1]).is_ok());
+        assert!(cache.get_account(&invalid).is_ok());
+
+        // The accounts with an invalid health are kept.
+        cache.evict_healthiest(0).unwrap();
+        assert!(cache.get_account(&invalid).is_ok());
+        assert_eq!(cache.get_accounts_with_health().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_update_evicts_empty_account() {
+        let cache = MarginfiAccountsCache {
+            evict_empty: true,
+            ..Default::default()
+        };
+        let address = Pubkey::new_unique();
+        let group = Pubkey::new_unique();
+
+        cache
+            .update(
+                1,
+                address,
+                create_marginfi_account(group, vec![create_balance(Pubkey::new_unique(), 1, 0)]),
+            )
+            .unwrap();
+        assert!(cache.get_account(&address).is_ok());
+
+        // Older empty states do not evict the account.
+        cache
+            .update(0, address, create_marginfi_account(group, vec![]))
+            .unwrap();
+        assert!(cache.get_account(&address).is_ok());
+
+        cache
+            .update(2, address, create_marginfi_account(group, vec![]))
+            .unwrap();
+        assert!(cache.get_account(&address).is_err());
+        assert!(cache.get_accounts_with_health().unwrap().is_empty());
+        assert_eq!(cache.evictions(), 1);
+
+        // The empty accounts are not cached in the first place.
+        cache
+            .update(
+                3,
+                Pubkey::new_unique(),
+                create_marginfi_account(group, vec![]),
+            )
+            .unwrap();
+        assert!(cache.get_accounts_with_health().unwrap().is_empty());
+        assert_eq!(cache.evictions(), 1);
+    }
+
+    #[test]
+    fn test_remove_closed_account() {
+        let cache = MarginfiAccountsCache::default();
+        let (address, bank) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let group = Pubkey::new_unique();
+        cache
+            .update(
+                5,
+                address,
+                create_marginfi_account(group, vec![create_balance(bank, 1, 0)]),
+            )
+            .unwrap();
+
+        // A closure older than the cached state is stale.
+        assert!(!cache.remove_closed(4, &address).unwrap());
+        assert!(cache.get_account(&address).is_ok());
+
+        assert!(cache.remove_closed(6, &address).unwrap());
+        assert!(cache.find_account(&address).unwrap().is_none());
+        assert!(cache.get_accounts_by_bank(&bank).is_empty());
+        assert!(cache.get_accounts_with_health().unwrap().is_empty());
+        assert!(!cache.remove_closed(7, &address).unwrap());
+    }
+
     #[test]
     fn test_update_with_older_slot_does_not_overwrite() {
         let cache = MarginfiAccountsCache::default();
@@ -451,6 +1042,227 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sorted_by_health() {
+        let cache = MarginfiAccountsCache::default();
+        let group = Pubkey::new_unique();
+        let account = |liabilities: i64| {
+            let mut marginfi_account =
+                create_marginfi_account(group, vec![create_balance(Pubkey::new_unique(), 1, 0)]);
+            marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
+            marginfi_account.health_cache.liability_value_maint =
+                I80F48::from_num(liabilities).into();
+            marginfi_account
+        };
+        let healthy = Pubkey::new_unique();
+        let unhealthy = Pubkey::new_unique();
+        cache.update(1, healthy, account(0)).unwrap();
+        cache.update(1, unhealthy, account(3000)).unwrap();
+        assert_eq!(
+            cache.get_accounts_sorted_by_health().unwrap(),
+            vec![healthy, unhealthy]
+        );
+
+        // The updated health moves the account in the index.
+        cache.update(2, healthy, account(5000)).unwrap();
+        assert_eq!(
+            cache.get_accounts_sorted_by_health().unwrap(),
+            vec![unhealthy, healthy]
+        );
+
+        cache.evict_healthiest(0).unwrap();
+        assert!(cache.get_accounts_sorted_by_health().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_group_partitions() {
+        let (group, other_group, third_group) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let cache = MarginfiAccountsCache {
+            groups: HashSet::from([group, third_group]),
+            ..MarginfiAccountsCache::default()
+        };
+        let account = |group: Pubkey, liabilities: i64| {
+            let mut marginfi_account =
+                create_marginfi_account(group, vec![create_balance(Pubkey::new_unique(), 1, 0)]);
+            marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
+            marginfi_account.health_cache.liability_value_maint =
+                I80F48::from_num(liabilities).into();
+            marginfi_account
+        };
+        let (healthy, unhealthy, other) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        cache.update(1, healthy, account(group, 0)).unwrap();
+        cache
+            .update(1, unhealthy, account(third_group, 3000))
+            .unwrap();
+        cache.update(1, other, account(other_group, 500)).unwrap();
+
+        // The accounts of the other groups are cached, but neither scanned nor snapshotted.
+        assert!(cache.get_account(&other).is_ok());
+        assert_eq!(
+            cache.get_accounts_sorted_by_health().unwrap(),
+            vec![healthy, unhealthy]
+        );
+        let with_health = cache.get_accounts_with_health().unwrap();
+        assert_eq!(with_health.len(), 2);
+        assert!(!with_health.contains_key(&other));
+        let mut snapshotted: Vec<Pubkey> = cache
+            .snapshot_entries()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.address)
+            .collect();
+        snapshotted.sort();
+        let mut expected = vec![healthy, unhealthy];
+        expected.sort();
+        assert_eq!(snapshotted, expected);
+
+        // The evicted accounts leave their partition.
+        cache.evict_healthiest(0).unwrap();
+        assert!(cache.get_accounts_sorted_by_health().unwrap().is_empty());
+        assert!(cache.lock_sorted_by_health().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recompute_health() {
+        let cache = MarginfiAccountsCache::default();
+        let group = Pubkey::new_unique();
+        let bank = Pubkey::new_unique();
+        let other_bank = Pubkey::new_unique();
+        let in_bank = Pubkey::new_unique();
+        let in_other_bank = Pubkey::new_unique();
+        cache
+            .update(
+                1,
+                in_bank,
+                create_marginfi_account(group, vec![create_balance(bank, 1, 0)]),
+            )
+            .unwrap();
+        cache
+            .update(
+                1,
+                in_other_bank,
+                create_marginfi_account(group, vec![create_balance(other_bank, 1, 0)]),
+            )
+            .unwrap();
+
+        // The updated accounts are recomputed once.
+        let no_banks = HashSet::new();
+        assert_eq!(cache.recompute_health(&no_banks, |_| Some(7)).unwrap(), 2);
+        assert_eq!(cache.recompute_health(&no_banks, |_| Some(8)).unwrap(), 0);
+        assert_eq!(cache.get_accounts_with_health().unwrap()[&in_bank], 7);
+
+        // Then only the accounts with positions in the updated Banks.
+        let banks = HashSet::from([bank]);
+        assert_eq!(cache.recompute_health(&banks, |_| Some(9)).unwrap(), 1);
+        let healths = cache.get_accounts_with_health().unwrap();
+        assert_eq!(healths[&in_bank], 9);
+        assert_eq!(healths[&in_other_bank], 7);
+        assert_eq!(
+            cache.get_accounts_sorted_by_health().unwrap(),
+            vec![in_bank, in_other_bank]
+        );
+
+        // The account which left the Bank is no longer recomputed with it.
+        cache
+            .update(
+                2,
+                in_bank,
+                create_marginfi_account(group, vec![create_balance(other_bank, 1, 0)]),
+            )
+            .unwrap();
+        assert_eq!(cache.recompute_health(&banks, |_| None).unwrap(), 1);
+        assert_eq!(
+            cache.get_accounts_with_health().unwrap()[&in_bank],
+            INVALID_HEALTH
+        );
+        assert_eq!(cache.recompute_health(&banks, |_| None).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_get_accounts_by_bank() {
+        let cache = MarginfiAccountsCache::default();
+        let group = Pubkey::new_unique();
+        let bank1 = Pubkey::new_unique();
+        let bank2 = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        let other_address = Pubkey::new_unique();
+        cache
+            .update(
+                1,
+                address,
+                create_marginfi_account(
+                    group,
+                    vec![create_balance(bank1, 1, 0), create_balance(bank2, 0, 1)],
+                ),
+            )
+            .unwrap();
+        cache
+            .update(
+                1,
+                other_address,
+                create_marginfi_account(group, vec![create_balance(bank1, 1, 0)]),
+            )
+            .unwrap();
+        let mut by_bank1 = cache.get_accounts_by_bank(&bank1);
+        by_bank1.sort();
+        let mut expected = vec![address, other_address];
+        expected.sort();
+        assert_eq!(by_bank1, expected);
+        assert_eq!(cache.get_accounts_by_bank(&bank2), vec![address]);
+
+        // The closed positions leave the index.
+        cache
+            .update(
+                2,
+                address,
+                create_marginfi_account(group, vec![create_balance(bank1, 1, 0)]),
+            )
+            .unwrap();
+        assert!(cache.get_accounts_by_bank(&bank2).is_empty());
+
+        // So do the accounts dropped by a snapshot restore.
+        cache
+            .restore_from_snapshot(&[cache.get_account(&address).unwrap().to_snapshot_account()])
+            .unwrap();
+        assert_eq!(cache.get_accounts_by_bank(&bank1), vec![address]);
+    }
+
+    #[test]
+    fn test_get_accounts_by_authority() {
+        let cache = MarginfiAccountsCache::default();
+        let group = Pubkey::new_unique();
+        let authority = Pubkey::new_unique();
+        let new_authority = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        let account = |authority: Pubkey| {
+            let mut marginfi_account =
+                create_marginfi_account(group, vec![create_balance(Pubkey::new_unique(), 1, 0)]);
+            marginfi_account.authority = authority;
+            marginfi_account
+        };
+        cache.update(1, address, account(authority)).unwrap();
+        assert_eq!(cache.get_accounts_by_authority(&authority), vec![address]);
+
+        // The transferred account moves to its new authority.
+        cache.update(2, address, account(new_authority)).unwrap();
+        assert!(cache.get_accounts_by_authority(&authority).is_empty());
+        assert_eq!(
+            cache.get_accounts_by_authority(&new_authority),
+            vec![address]
+        );
+
+        cache.restore_from_snapshot(&[]).unwrap();
+        assert!(cache.get_accounts_by_authority(&new_authority).is_empty());
+    }
+
     #[test]
     fn test_get_account_returns_error_for_missing_account() {
         let cache = MarginfiAccountsCache::default();
@@ -467,6 +1279,26 @@ mod tests {
         assert!(health_map.is_empty());
     }
 
+    #[test]
+    fn test_health_distribution() {
+        let accounts = HashMap::from([
+            (Pubkey::new_unique(), 0),
+            (Pubkey::new_unique(), 1),
+            (Pubkey::new_unique(), 2),
+            (Pubkey::new_unique(), INVALID_HEALTH),
+        ]);
+        assert_eq!(
+            HealthDistribution::from(&accounts),
+            HealthDistribution {
+                hot: 2,
+                warm: 0,
+                cold: 1,
+                invalid: 1,
+            }
+        );
+        assert_eq!(HealthDistribution::from(&accounts).total(), 4);
+    }
+
     #[test]
     fn test_multiple_accounts_in_cache() {
         let cache = MarginfiAccountsCache::default();
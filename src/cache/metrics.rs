@@ -0,0 +1,303 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::{cache::confirmations::ConfirmationStats, common::MessageType};
+
+/// The number of entries of every store of the cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StoreEntries {
+    pub marginfi_accounts: usize,
+    pub banks: usize,
+    pub oracles: usize,
+    pub mints: usize,
+}
+
+/// The updates of every account type since the startup, with the updates of no known type and the updates which
+/// failed to decode.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct UpdateCounts {
+    pub marginfi_accounts: u64,
+    pub banks: u64,
+    pub oracles: u64,
+    pub token_accounts: u64,
+    pub unknown: u64,
+    pub decode_failures: u64,
+}
+
+/// The updates per second of every account type, over the last sampling interval.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct UpdateRates {
+    pub marginfi_accounts: f64,
+    pub banks: f64,
+    pub oracles: f64,
+    pub token_accounts: f64,
+    pub unknown: f64,
+}
+
+impl UpdateRates {
+    fn from_counts(previous: [u64; 5], counts: [u64; 5], elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64();
+        let rate = |i: usize| {
+            if secs > 0.0 {
+                counts[i].saturating_sub(previous[i]) as f64 / secs
+            } else {
+                0.0
+            }
+        };
+        Self {
+            marginfi_accounts: rate(0),
+            banks: rate(1),
+            oracles: rate(2),
+            token_accounts: rate(3),
+            unknown: rate(4),
+        }
+    }
+}
+
+/// The health of the cache, as logged with the stats and served by the admin API.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CacheHealthMetrics {
+    pub entries: StoreEntries,
+    pub update_counts: UpdateCounts,
+    pub update_rates: UpdateRates,
+    pub last_restore_ms: Option<u64>,
+    pub last_persist_ms: Option<u64>,
+    // Since the generation of the last snapshot persisted or restored, None when there was none yet.
+    pub last_snapshot_age_sec: Option<u64>,
+    // The processed updates reconciled with the confirmed stream, all 0 unless both are subscribed.
+    pub confirmations: ConfirmationStats,
+}
+
+impl fmt::Display for CacheHealthMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_none = |value: Option<u64>, unit: &str| {
+            value.map_or_else(|| "None".to_string(), |value| format!("{}{}", value, unit))
+        };
+        write!(
+            f,
+            "Entries: {} Accounts, {} Banks, {} Oracles, {} Mints; Updates: {} Accounts, {} Banks, {} Oracles, {} Token Accounts, {} Unknown, {} Decode Failures; Updates/s: {:.1} Accounts, {:.1} Banks, {:.1} Oracles, {:.1} Token Accounts, {:.1} Unknown; Restore: {}; Persist: {}; Snapshot Age: {}; Processed Updates: {} Pending, {} Confirmed, {} Rolled Back, {} Dead Slots",
+            self.entries.marginfi_accounts,
+            self.entries.banks,
+            self.entries.oracles,
+            self.entries.mints,
+            self.update_counts.marginfi_accounts,
+            self.update_counts.banks,
+            self.update_counts.oracles,
+            self.update_counts.token_accounts,
+            self.update_counts.unknown,
+            self.update_counts.decode_failures,
+            self.update_rates.marginfi_accounts,
+            self.update_rates.banks,
+            self.update_rates.oracles,
+            self.update_rates.token_accounts,
+            self.update_rates.unknown,
+            or_none(self.last_restore_ms, "ms"),
+            or_none(self.last_persist_ms, "ms"),
+            or_none(self.last_snapshot_age_sec, "s"),
+            self.confirmations.pending,
+            self.confirmations.confirmed,
+            self.confirmations.rolled_back,
+            self.confirmations.dead_slots,
+        )
+    }
+}
+
+// The update counts at the last sampling, with the rates since the previous one.
+struct RatesSample {
+    sampled_at: Instant,
+    counts: [u64; 5],
+    rates: UpdateRates,
+}
+
+/// The counters of the updates applied to the cache and of its snapshots. The durations, rounded up to the
+/// millisecond, and the snapshot generation time are 0 until recorded.
+#[derive(Default)]
+pub struct CacheMetrics {
+    // The Marginfi account, Bank, Oracle, token account and unknown updates, in the order of the rates.
+    updates: [AtomicU64; 5],
+    decode_failures: AtomicU64,
+    last_restore_ms: AtomicU64,
+    last_persist_ms: AtomicU64,
+    last_snapshot_unix: AtomicU64,
+    sample: Mutex<Option<RatesSample>>,
+}
+
+impl CacheMetrics {
+    pub fn record_update(&self, message_type: &MessageType) {
+        let index = match message_type {
+            MessageType::MarginfiAccount | MessageType::ClosedAccount => 0,
+            MessageType::Bank => 1,
+            MessageType::Oracle => 2,
+            MessageType::TokenAccount => 3,
+            MessageType::Clock | MessageType::Slot => return,
+        };
+        self.updates[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an account update streamed by Geyser which matched none of the cached account types, as when the
+    /// filters subscribe to more than the cache uses.
+    pub fn record_unknown_update(&self) {
+        self.updates[4].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_decode_failure(&self) {
+        self.decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn update_counts(&self) -> UpdateCounts {
+        let [marginfi_accounts, banks, oracles, token_accounts, unknown] = self
+            .updates
+            .each_ref()
+            .map(|count| count.load(Ordering::Relaxed));
+        UpdateCounts {
+            marginfi_accounts,
+            banks,
+            oracles,
+            token_accounts,
+            unknown,
+            decode_failures: self.decode_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn record_restore(&self, duration: Duration, generated_at_unix: u64) {
+        self.last_restore_ms
+            .store(rounded_up_ms(duration), Ordering::Relaxed);
+        self.last_snapshot_unix
+            .store(generated_at_unix, Ordering::Relaxed);
+    }
+
+    pub fn record_persist(&self, duration: Duration, generated_at_unix: u64) {
+        self.last_persist_ms
+            .store(rounded_up_ms(duration), Ordering::Relaxed);
+        self.last_snapshot_unix
+            .store(generated_at_unix, Ordering::Relaxed);
+    }
+
+    /// Samples the update counts, returning the rates since the previous sampling. The stats sample them
+    /// every interval, so that the other readers get the rates of the last interval.
+    pub fn sample_update_rates(&self) -> Result<UpdateRates> {
+        self.sample_update_rates_at(Instant::now())
+    }
+
+    fn sample_update_rates_at(&self, now: Instant) -> Result<UpdateRates> {
+        let counts = self
+            .updates
+            .each_ref()
+            .map(|count| count.load(Ordering::Relaxed));
+        let mut sample = self.lock_sample()?;
+        let rates = sample
+            .as_ref()
+            .map_or_else(UpdateRates::default, |previous| {
+                UpdateRates::from_counts(
+                    previous.counts,
+                    counts,
+                    now.saturating_duration_since(previous.sampled_at),
+                )
+            });
+        *sample = Some(RatesSample {
+            sampled_at: now,
+            counts,
+            rates,
+        });
+        Ok(rates)
+    }
+
+    /// The rates of the last sampling.
+    pub fn update_rates(&self) -> Result<UpdateRates> {
+        Ok(self
+            .lock_sample()?
+            .as_ref()
+            .map_or_else(UpdateRates::default, |sample| sample.rates))
+    }
+
+    pub fn last_restore_ms(&self) -> Option<u64> {
+        recorded(self.last_restore_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn last_persist_ms(&self) -> Option<u64> {
+        recorded(self.last_persist_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn last_snapshot_age_sec(&self, now_unix: u64) -> Option<u64> {
+        recorded(self.last_snapshot_unix.load(Ordering::Relaxed))
+            .map(|generated_at_unix| now_unix.saturating_sub(generated_at_unix))
+    }
+
+    fn lock_sample(&self) -> Result<MutexGuard<'_, Option<RatesSample>>> {
+        self.sample
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the cache update rates: {}", e))
+    }
+}
+
+fn rounded_up_ms(duration: Duration) -> u64 {
+    duration.as_micros().div_ceil(1_000) as u64
+}
+
+fn recorded(value: u64) -> Option<u64> {
+    (value > 0).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_metrics() {
+        let metrics = CacheMetrics::default();
+        assert_eq!(metrics.update_rates().unwrap(), UpdateRates::default());
+        assert_eq!(metrics.last_persist_ms(), None);
+        assert_eq!(metrics.last_snapshot_age_sec(1_000), None);
+
+        let start = Instant::now();
+        metrics.sample_update_rates_at(start).unwrap();
+        for _ in 0..4 {
+            metrics.record_update(&MessageType::MarginfiAccount);
+        }
+        metrics.record_update(&MessageType::Oracle);
+        metrics.record_update(&MessageType::Clock);
+        metrics.record_unknown_update();
+        metrics.record_decode_failure();
+        assert_eq!(
+            metrics.update_counts(),
+            UpdateCounts {
+                marginfi_accounts: 4,
+                banks: 0,
+                oracles: 1,
+                token_accounts: 0,
+                unknown: 1,
+                decode_failures: 1,
+            }
+        );
+        let rates = metrics
+            .sample_update_rates_at(start + Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(
+            rates,
+            UpdateRates {
+                marginfi_accounts: 2.0,
+                banks: 0.0,
+                oracles: 0.5,
+                token_accounts: 0.0,
+                unknown: 0.5,
+            }
+        );
+        assert_eq!(metrics.update_rates().unwrap(), rates);
+
+        metrics.record_restore(Duration::from_millis(1_500), 900);
+        assert_eq!(metrics.last_restore_ms(), Some(1_500));
+        assert_eq!(metrics.last_snapshot_age_sec(1_000), Some(100));
+        metrics.record_persist(Duration::from_millis(20), 990);
+        assert_eq!(metrics.last_persist_ms(), Some(20));
+        assert_eq!(metrics.last_snapshot_age_sec(1_000), Some(10));
+    }
+}
@@ -1,17 +1,147 @@
-use crate::cache::CacheEntry;
+use crate::cache::{
+    snapshot::{SnapshotAccount, SnapshotOwnedAccount},
+    CacheEntry,
+};
+use crate::common::SPL_TOKEN_2022_PROGRAM_ID;
 use anyhow::{anyhow, Result};
-use log::trace;
+use log::{debug, trace};
 use solana_sdk::{account::Account, pubkey::Pubkey};
 use std::{collections::HashMap, sync::RwLock};
 
+// Offsets of the base SPL mint layout, shared by Token-2022 mints.
+const MINT_DECIMALS_OFFSET: usize = 44;
+const MINT_BASE_LEN: usize = 82;
+// The Token-2022 extensions follow the account type, after the mints are padded to the token accounts length.
+const TOKEN_2022_EXTENSIONS_OFFSET: usize = 166;
+const TRANSFER_FEE_CONFIG_EXTENSION: u16 = 1;
+// The transfer fee config authority, the withdraw withheld authority and the withheld amount come first.
+const TRANSFER_FEE_CONFIG_FEES_OFFSET: usize = 72;
+const TRANSFER_FEE_LEN: usize = 18;
+const MAX_FEE_BASIS_POINTS: u128 = 10_000;
+
+/// A Token-2022 transfer fee, applying from its epoch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransferFee {
+    pub epoch: u64,
+    pub maximum_fee: u64,
+    pub basis_points: u16,
+}
+
+impl TransferFee {
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        Some(Self {
+            epoch: u64::from_le_bytes(data.get(0..8)?.try_into().ok()?),
+            maximum_fee: u64::from_le_bytes(data.get(8..16)?.try_into().ok()?),
+            basis_points: u16::from_le_bytes(data.get(16..18)?.try_into().ok()?),
+        })
+    }
+
+    /// The amount to transfer for the recipient to receive the net amount, the withheld fee being rounded up
+    /// and capped at the maximum fee.
+    pub fn gross_amount(&self, net_amount: u64) -> u64 {
+        let capped = net_amount.saturating_add(self.maximum_fee);
+        let Some(net_share) = MAX_FEE_BASIS_POINTS.checked_sub(self.basis_points as u128) else {
+            return capped;
+        };
+        if net_share == 0 {
+            return capped;
+        }
+        let gross = (net_amount as u128 * MAX_FEE_BASIS_POINTS).div_ceil(net_share);
+        u64::try_from(gross).unwrap_or(u64::MAX).min(capped)
+    }
+}
+
+/// The transfer fees of a Token-2022 mint, the newer one applying from its epoch on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransferFeeConfig {
+    pub older: TransferFee,
+    pub newer: TransferFee,
+}
+
+impl TransferFeeConfig {
+    pub fn at_epoch(&self, epoch: u64) -> &TransferFee {
+        if epoch >= self.newer.epoch {
+            &self.newer
+        } else {
+            &self.older
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CachedMint {
     pub _address: Pubkey,
+    // The token program.
     pub _owner: Pubkey,
+    pub decimals: u8,
+    pub transfer_fee_config: Option<TransferFeeConfig>,
+    // The raw account data, kept for the snapshots.
+    data: Vec<u8>,
 }
 
 impl CacheEntry for CachedMint {}
 
+impl CachedMint {
+    pub fn from(address: Pubkey, mint: &Account) -> Result<Self> {
+        if mint.data.len() < MINT_BASE_LEN {
+            return Err(anyhow!(
+                "Invalid Mint {} account length {}",
+                address,
+                mint.data.len()
+            ));
+        }
+        let transfer_fee_config = if mint.owner == SPL_TOKEN_2022_PROGRAM_ID {
+            parse_transfer_fee_config(&mint.data)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            _address: address,
+            _owner: mint.owner,
+            decimals: mint.data[MINT_DECIMALS_OFFSET],
+            transfer_fee_config,
+            data: mint.data.clone(),
+        })
+    }
+
+    pub fn token_program(&self) -> &Pubkey {
+        &self._owner
+    }
+
+    /// The Mint data as it is cached. The Mints are not versioned by slot.
+    pub fn to_snapshot_account(&self) -> SnapshotAccount {
+        SnapshotAccount::new(self._address, 0, self.data.clone())
+    }
+
+    /// The amount to transfer at the epoch for the recipient to receive the net amount, the net amount
+    /// itself for the mints without a transfer fee.
+    pub fn gross_transfer_amount(&self, net_amount: u64, epoch: u64) -> u64 {
+        self.transfer_fee_config.map_or(net_amount, |config| {
+            config.at_epoch(epoch).gross_amount(net_amount)
+        })
+    }
+}
+
+// Walks the TLV entries of the Token-2022 extensions.
+fn parse_transfer_fee_config(data: &[u8]) -> Option<TransferFeeConfig> {
+    let mut offset = TOKEN_2022_EXTENSIONS_OFFSET;
+    while let Some(header) = data.get(offset..offset + 4) {
+        let extension_type = u16::from_le_bytes([header[0], header[1]]);
+        let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let value = data.get(offset + 4..offset + 4 + length)?;
+        if extension_type == TRANSFER_FEE_CONFIG_EXTENSION {
+            let fees = value.get(TRANSFER_FEE_CONFIG_FEES_OFFSET..)?;
+            return Some(TransferFeeConfig {
+                older: TransferFee::from_bytes(fees)?,
+                newer: TransferFee::from_bytes(fees.get(TRANSFER_FEE_LEN..)?)?,
+            });
+        }
+        offset += 4 + length;
+    }
+    None
+}
+
 #[derive(Default)]
 pub struct MintsCache {
     mints: RwLock<HashMap<Pubkey, CachedMint>>,
@@ -19,10 +149,7 @@ pub struct MintsCache {
 
 impl MintsCache {
     pub fn update(&self, address: Pubkey, mint: &Account) -> Result<()> {
-        let upd_cached_mint = CachedMint {
-            _address: address,
-            _owner: mint.owner,
-        };
+        let upd_cached_mint = CachedMint::from(address, mint)?;
 
         trace!("Updating the Mint in cache: {:?}", upd_cached_mint);
 
@@ -34,7 +161,6 @@ impl MintsCache {
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn get(&self, address: &Pubkey) -> Result<Option<CachedMint>> {
         Ok(self
             .mints
@@ -43,23 +169,117 @@ impl MintsCache {
             .get(address)
             .cloned())
     }
+
+    pub fn count(&self) -> Result<usize> {
+        Ok(self
+            .mints
+            .read()
+            .map_err(|e| anyhow!("Failed to lock the Mints cache for counting: {}", e))?
+            .len())
+    }
+
+    pub(crate) fn snapshot_entries(&self) -> Result<Vec<SnapshotOwnedAccount>> {
+        Ok(self
+            .mints
+            .read()
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to lock the Mints cache for snapshot generation: {}",
+                    e
+                )
+            })?
+            .values()
+            .map(|mint| SnapshotOwnedAccount {
+                address: mint._address,
+                owner: mint._owner,
+                slot: 0,
+                data: mint.data.clone(),
+            })
+            .collect())
+    }
+
+    /// The entries of the older snapshots have no data: these Mints are left to be loaded again.
+    pub(crate) fn restore_from_snapshot(&self, entries: &[SnapshotOwnedAccount]) -> Result<()> {
+        self.mints
+            .write()
+            .map_err(|e| anyhow!("Failed to lock the Mints cache for reset: {}", e))?
+            .clear();
+
+        for entry in entries {
+            if let Err(err) = self.update(entry.address, &entry.to_account()) {
+                debug!(
+                    "Skipping the snapshot entry of the Mint {}: {}",
+                    entry.address, err
+                );
+            }
+        }
+        Ok(())
+    }
 }
+
+#[cfg(test)]
+pub mod test_util {
+    use super::*;
+
+    /// A mint account of the token program, with the transfer fees as a Token-2022 extension if any.
+    pub fn create_mint_account(
+        owner: Pubkey,
+        decimals: u8,
+        transfer_fee_config: Option<TransferFeeConfig>,
+    ) -> Account {
+        let mut data = vec![0u8; MINT_BASE_LEN];
+        data[MINT_DECIMALS_OFFSET] = decimals;
+        if let Some(config) = transfer_fee_config {
+            data.resize(TOKEN_2022_EXTENSIONS_OFFSET, 0);
+            // The account type of the mints.
+            data[TOKEN_2022_EXTENSIONS_OFFSET - 1] = 1;
+            data.extend_from_slice(&TRANSFER_FEE_CONFIG_EXTENSION.to_le_bytes());
+            let length = TRANSFER_FEE_CONFIG_FEES_OFFSET + 2 * TRANSFER_FEE_LEN;
+            data.extend_from_slice(&(length as u16).to_le_bytes());
+            data.extend_from_slice(&[0u8; TRANSFER_FEE_CONFIG_FEES_OFFSET]);
+            for fee in [config.older, config.newer] {
+                data.extend_from_slice(&fee.epoch.to_le_bytes());
+                data.extend_from_slice(&fee.maximum_fee.to_le_bytes());
+                data.extend_from_slice(&fee.basis_points.to_le_bytes());
+            }
+        }
+
+        Account {
+            lamports: 0,
+            data,
+            owner,
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::test_util::create_mint_account;
     use super::*;
+    use crate::common::SPL_TOKEN_PROGRAM_ID;
+
+    fn transfer_fee(epoch: u64, maximum_fee: u64, basis_points: u16) -> TransferFee {
+        TransferFee {
+            epoch,
+            maximum_fee,
+            basis_points,
+        }
+    }
+
+    // The fee withheld from a transfer of the amount, as Token-2022 computes it.
+    fn withheld_fee(fee: &TransferFee, amount: u64) -> u64 {
+        ((amount as u128 * fee.basis_points as u128).div_ceil(MAX_FEE_BASIS_POINTS) as u64)
+            .min(fee.maximum_fee)
+    }
 
     #[test]
     fn test_update_inserts_new_mint() {
         let cache = MintsCache::default();
         let address = Pubkey::new_unique();
         let owner = Pubkey::new_unique();
-        let account = Account {
-            lamports: 0,
-            data: vec![],
-            owner,
-            executable: false,
-            rent_epoch: 0,
-        };
+        let account = create_mint_account(owner, 6, None);
 
         assert!(cache.update(address, &account).is_ok());
 
@@ -67,6 +287,7 @@ mod tests {
         let cached = mints.get(&address).unwrap();
         assert_eq!(cached._address, address);
         assert_eq!(cached._owner, owner);
+        assert_eq!(cached.decimals, 6);
     }
 
     #[test]
@@ -76,20 +297,8 @@ mod tests {
         let owner1 = Pubkey::new_unique();
         let owner2 = Pubkey::new_unique();
 
-        let account1 = Account {
-            lamports: 0,
-            data: vec![],
-            owner: owner1,
-            executable: false,
-            rent_epoch: 0,
-        };
-        let account2 = Account {
-            lamports: 0,
-            data: vec![],
-            owner: owner2,
-            executable: false,
-            rent_epoch: 0,
-        };
+        let account1 = create_mint_account(owner1, 6, None);
+        let account2 = create_mint_account(owner2, 9, None);
 
         cache.update(address, &account1).unwrap();
         cache.update(address, &account2).unwrap();
@@ -97,6 +306,16 @@ mod tests {
         let mints = cache.mints.read().unwrap();
         let cached = mints.get(&address).unwrap();
         assert_eq!(cached._owner, owner2);
+        assert_eq!(cached.decimals, 9);
+    }
+
+    #[test]
+    fn test_update_rejects_invalid_mint() {
+        let cache = MintsCache::default();
+        let mut account = create_mint_account(SPL_TOKEN_PROGRAM_ID, 6, None);
+        account.data.truncate(MINT_BASE_LEN - 1);
+
+        assert!(cache.update(Pubkey::new_unique(), &account).is_err());
     }
 
     #[test]
@@ -112,13 +331,7 @@ mod tests {
         let cache = MintsCache::default();
         let address = Pubkey::new_unique();
         let owner = Pubkey::new_unique();
-        let account = Account {
-            lamports: 0,
-            data: vec![],
-            owner,
-            executable: false,
-            rent_epoch: 0,
-        };
+        let account = create_mint_account(owner, 6, None);
 
         cache.update(address, &account).unwrap();
         let result = cache.get(&address).unwrap();
@@ -127,4 +340,42 @@ mod tests {
         assert_eq!(cached._address, address);
         assert_eq!(cached._owner, owner);
     }
+
+    #[test]
+    fn test_transfer_fee_config() {
+        let config = TransferFeeConfig {
+            older: transfer_fee(0, 1_000, 100),
+            newer: transfer_fee(10, 50, 200),
+        };
+        let account = create_mint_account(SPL_TOKEN_2022_PROGRAM_ID, 6, Some(config));
+        let mint = CachedMint::from(Pubkey::new_unique(), &account).unwrap();
+        assert_eq!(mint.token_program(), &SPL_TOKEN_2022_PROGRAM_ID);
+        assert_eq!(mint.transfer_fee_config, Some(config));
+
+        // 1% until the epoch 10, then 2% capped at 50.
+        assert_eq!(mint.gross_transfer_amount(990, 9), 1_000);
+        assert_eq!(mint.gross_transfer_amount(980, 10), 1_000);
+        assert_eq!(mint.gross_transfer_amount(10_000, 10), 10_050);
+
+        // The extensions of the legacy token program mints are not parsed.
+        let legacy = create_mint_account(SPL_TOKEN_PROGRAM_ID, 6, None);
+        let mint = CachedMint::from(Pubkey::new_unique(), &legacy).unwrap();
+        assert!(mint.transfer_fee_config.is_none());
+        assert_eq!(mint.gross_transfer_amount(1_000, 10), 1_000);
+    }
+
+    #[test]
+    fn test_transfer_fee() {
+        let fee = transfer_fee(0, 5, 100);
+        assert_eq!(fee.gross_amount(0), 0);
+        // The smallest amount the recipient receives the net amount of.
+        for net_amount in [1, 99, 100, 495, 496, 10_000] {
+            let gross_amount = fee.gross_amount(net_amount);
+            assert!(gross_amount - withheld_fee(&fee, gross_amount) >= net_amount);
+            assert!(gross_amount - 1 - withheld_fee(&fee, gross_amount - 1) < net_amount);
+        }
+
+        // All the transfer is withheld, up to the maximum fee.
+        assert_eq!(transfer_fee(0, 5, 10_000).gross_amount(100), 105);
+    }
 }
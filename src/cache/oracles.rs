@@ -1,24 +1,50 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use fixed::types::I80F48;
 
 use marginfi::state::price::{
-    OraclePriceFeedAdapter, OracleSetup, PythPushOraclePriceFeed, SwitchboardPullPriceFeed,
+    OraclePriceFeedAdapter, OraclePriceType, OracleSetup, PriceAdapter, PythPushOraclePriceFeed,
+    SwitchboardPullPriceFeed,
 };
 use solana_sdk::{account::Account, pubkey::Pubkey};
 
-use crate::cache::CacheEntry;
+use crate::cache::{
+    prices::{CachedPrice, PricesCache},
+    snapshot::{SnapshotAccount, SnapshotOwnedAccount},
+    CacheEntry,
+};
 use anyhow::{anyhow, Result};
 
-use log::{trace, warn};
+use log::{debug, trace, warn};
 
 use anchor_lang::prelude::AccountInfo;
 
 use solana_sdk::account_info::IntoAccountInfo;
 use switchboard_on_demand::{Discriminator, PullFeedAccountData};
 
+/// Oracle setups the cache can build price adapters for.
+pub fn is_supported_oracle(oracle_type: &OracleSetup) -> bool {
+    matches!(
+        oracle_type,
+        OracleSetup::SwitchboardPull | OracleSetup::PythPushOracle
+    )
+}
+
 #[derive(Clone)]
 pub struct CachedPriceAdapter {
     pub slot: u64,
     _adapter: OraclePriceFeedAdapter,
+    // The raw account data the adapter was parsed from, kept for the post-mortems and the snapshots.
+    data: Vec<u8>,
+    owner: Pubkey,
 }
 
 impl CachedPriceAdapter {
@@ -37,9 +63,18 @@ impl CachedPriceAdapter {
         Ok(Self {
             slot,
             _adapter: adapter,
+            data: account.data.clone(),
+            owner: account.owner,
         })
     }
 
+    /// The real-time price, with the default confidence check of the program.
+    pub fn price(&self) -> Option<I80F48> {
+        self._adapter
+            .get_price_of_type(OraclePriceType::RealTime, None, 0)
+            .ok()
+    }
+
     fn parse_swb_adapter(data: &[u8]) -> Result<OraclePriceFeedAdapter> {
         if data.len() < 8 {
             return Err(anyhow!("Invalid Swb oracle account length"));
@@ -102,9 +137,15 @@ impl CachedOracle {
     }
 }
 
+// Sharded, for the Geyser updates not to contend with the reads of the other Oracles.
 #[derive(Default)]
 pub struct OraclesCache {
-    oracles: RwLock<HashMap<Pubkey, CachedOracle>>,
+    oracles: DashMap<Pubkey, CachedOracle>,
+    // The Oracles whose price changed since the last recomputation of the health of the accounts.
+    updated: Mutex<HashSet<Pubkey>>,
+    prices: PricesCache,
+    // Bumped whenever an Oracle is added, for the Geyser subscriptions to include it.
+    generation: AtomicU64,
 }
 
 impl OraclesCache {
@@ -127,27 +168,87 @@ impl OraclesCache {
                 }
             };
 
-        self.oracles
-            .write()
-            .map_err(|e| anyhow::anyhow!("Failed to lock the Oracles cache for insert: {}", e))?
-            .insert(*address, CachedOracle::from(*address, oracle_type, adapter));
+        let priced = adapter.is_some();
+        if priced {
+            self.update_price(slot, address, &oracle_type, &account.data);
+        } else {
+            self.prices.remove(address);
+        }
+        if self
+            .oracles
+            .insert(*address, CachedOracle::from(*address, oracle_type, adapter))
+            .is_none()
+        {
+            self.generation.fetch_add(1, Ordering::Relaxed);
+        }
+        if priced {
+            self.mark_updated(address)?;
+        }
 
         Ok(())
     }
 
+    /// Caches the Oracle without a price, unless it is cached already, its price coming with its next update.
+    /// Returns whether it was added.
+    pub fn register(&self, address: &Pubkey, oracle_type: OracleSetup) -> bool {
+        match self.oracles.entry(*address) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(CachedOracle::from(*address, oracle_type, None));
+                self.generation.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        }
+    }
+
+    /// Changes whenever an Oracle is added.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
     pub fn update(&self, slot: u64, address: &Pubkey, account: &mut Account) -> Result<()> {
-        let mut oracles = self
-            .oracles
-            .write()
-            .map_err(|e| anyhow::anyhow!("Failed to lock the Oracles cache for update: {}", e))?;
+        if self.apply_update(slot, address, account) {
+            self.mark_updated(address)?;
+        }
+        Ok(())
+    }
 
-        if let Some(cached_oracle) = oracles.get_mut(address) {
-            if slot > cached_oracle.adapter.as_ref().map_or(0, |a| a.slot) {
+    /// Applies the updates in order, the updated Oracles being marked under a single lock acquisition.
+    pub fn update_batch<'a>(
+        &self,
+        updates: impl IntoIterator<Item = (u64, Pubkey, &'a mut Account)>,
+    ) -> Result<()> {
+        let mut updated = vec![];
+        for (slot, address, account) in updates {
+            if self.apply_update(slot, &address, account) {
+                updated.push(address);
+            }
+        }
+        if !updated.is_empty() {
+            self.updated
+                .lock()
+                .map_err(|e| anyhow!("Failed to lock the updated Oracles: {}", e))?
+                .extend(updated);
+        }
+        Ok(())
+    }
+
+    // Returns whether the Oracle was updated.
+    fn apply_update(&self, slot: u64, address: &Pubkey, account: &mut Account) -> bool {
+        if let Some(mut cached_oracle) = self.oracles.get_mut(address) {
+            if slot >= cached_oracle.adapter.as_ref().map_or(0, |a| a.slot) {
                 match CachedPriceAdapter::from(slot, &cached_oracle._oracle_type, address, account)
                 {
                     Ok(adapter) => {
                         cached_oracle.adapter = Some(adapter);
+                        self.update_price(
+                            slot,
+                            address,
+                            &cached_oracle._oracle_type,
+                            &account.data,
+                        );
                         trace!("Updated OraclePriceAdapter for {:?}", address);
+                        return true;
                     }
                     Err(err) => {
                         warn!(
@@ -158,27 +259,159 @@ impl OraclesCache {
                 }
             }
         }
+        false
+    }
 
+    pub fn _get(&self, address: &Pubkey) -> Result<Option<CachedOracle>> {
+        Ok(self.oracles.get(address).map(|oracle| oracle.clone()))
+    }
+
+    fn update_price(&self, slot: u64, address: &Pubkey, oracle_type: &OracleSetup, data: &[u8]) {
+        match CachedPrice::parse(slot, oracle_type, data) {
+            Ok(price) => self.prices.update(*address, price),
+            Err(err) => warn!(
+                "Failed to parse the price of the Oracle {}: {}",
+                address, err
+            ),
+        }
+    }
+
+    fn mark_updated(&self, address: &Pubkey) -> Result<()> {
+        self.updated
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the updated Oracles: {}", e))?
+            .insert(*address);
         Ok(())
     }
 
-    pub fn _get(&self, address: &Pubkey) -> Result<Option<CachedOracle>> {
+    /// Drains the Oracles whose price changed since the last call.
+    pub fn take_updated(&self) -> Result<HashSet<Pubkey>> {
+        Ok(std::mem::take(&mut *self.updated.lock().map_err(|e| {
+            anyhow!("Failed to lock the updated Oracles: {}", e)
+        })?))
+    }
+
+    /// The current price of the Oracle, None until it has one.
+    pub fn get_price(&self, address: &Pubkey) -> Option<I80F48> {
+        self.oracles.get(address)?.adapter.as_ref()?.price()
+    }
+
+    /// The price of the Oracle with its staleness metadata, None until it has one.
+    pub fn get_cached_price(&self, address: &Pubkey) -> Option<CachedPrice> {
+        self.prices.get(address)
+    }
+
+    /// The price of the Oracle the program would accept at the timestamp, with the oracle max age of
+    /// the Bank in seconds. None when the price is stale or too uncertain.
+    pub fn get_valid_price(
+        &self,
+        address: &Pubkey,
+        max_age_sec: u16,
+        unix_timestamp: i64,
+    ) -> Option<I80F48> {
+        self.prices
+            .get_valid_price(address, max_age_sec, unix_timestamp)
+    }
+
+    /// The slot of the last price update of the Oracle, None until it has a price.
+    pub fn get_slot(&self, address: &Pubkey) -> Option<u64> {
+        self.oracles
+            .get(address)?
+            .adapter
+            .as_ref()
+            .map(|adapter| adapter.slot)
+    }
+
+    /// The raw data of the oracle account the cached price was parsed from.
+    pub fn get_snapshot_account(&self, address: &Pubkey) -> Result<Option<SnapshotAccount>> {
+        Ok(self.oracles.get(address).and_then(|oracle| {
+            oracle
+                .adapter
+                .as_ref()
+                .map(|adapter| SnapshotAccount::new(*address, adapter.slot, adapter.data.clone()))
+        }))
+    }
+
+    pub(crate) fn snapshot_entries(&self) -> Result<Vec<SnapshotOwnedAccount>> {
+        self.snapshot_entries_where(|_, _| true)
+    }
+
+    /// The snapshot entries of the Oracles with a price, selected by their address and slot.
+    pub(crate) fn snapshot_entries_where(
+        &self,
+        include: impl Fn(&Pubkey, u64) -> bool,
+    ) -> Result<Vec<SnapshotOwnedAccount>> {
         Ok(self
             .oracles
-            .read()
-            .map_err(|e| anyhow::anyhow!("Failed to lock the Oracles cache for read: {}", e))?
-            .get(address)
-            .cloned())
+            .iter()
+            .filter_map(|oracle| {
+                let address = oracle.key();
+                let adapter = oracle.adapter.as_ref()?;
+                include(address, adapter.slot).then(|| SnapshotOwnedAccount {
+                    address: *address,
+                    owner: adapter.owner,
+                    slot: adapter.slot,
+                    data: adapter.data.clone(),
+                })
+            })
+            .collect())
     }
 
-    pub fn get_oracle_addresses(&self) -> Vec<Pubkey> {
+    pub(crate) fn restore_from_snapshot(
+        &self,
+        entries: &[SnapshotOwnedAccount],
+        oracle_types: &HashMap<Pubkey, OracleSetup>,
+    ) -> Result<()> {
+        self.oracles.clear();
+        self.prices.clear();
+
+        self.apply_snapshot_entries(entries, oracle_types)
+    }
+
+    /// Updates the cache with the snapshot entries of the Oracles of the given types. The ones no Bank
+    /// references anymore are skipped.
+    pub(crate) fn apply_snapshot_entries(
+        &self,
+        entries: &[SnapshotOwnedAccount],
+        oracle_types: &HashMap<Pubkey, OracleSetup>,
+    ) -> Result<()> {
+        for entry in entries {
+            match oracle_types.get(&entry.address) {
+                Some(oracle_type) => {
+                    self.insert(entry.slot, &entry.address, *oracle_type, entry.to_account())?
+                }
+                None => debug!(
+                    "Skipping the snapshot entry of the unreferenced Oracle {}",
+                    entry.address
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The approximate memory held by the cached Oracles, their raw data included.
+    pub fn count(&self) -> usize {
+        self.oracles.len()
+    }
+
+    pub fn memory_bytes(&self) -> usize {
         self.oracles
-            .read()
-            .map_err(|e| anyhow::anyhow!("Failed to lock the Oracles cache for read: {}", e))
-            .unwrap()
-            .keys()
-            .cloned()
-            .collect()
+            .iter()
+            .map(|oracle| {
+                size_of::<Pubkey>()
+                    + size_of::<CachedOracle>()
+                    + oracle
+                        .adapter
+                        .as_ref()
+                        .map_or(0, |adapter| adapter.data.capacity())
+            })
+            .sum::<usize>()
+            + self.prices.memory_bytes()
+    }
+
+    pub fn get_oracle_addresses(&self) -> Vec<Pubkey> {
+        self.oracles.iter().map(|oracle| *oracle.key()).collect()
     }
 }
 
@@ -236,9 +469,9 @@ mod tests {
         // Update with a higher slot
         cache.update(2, &address, &mut account).unwrap();
 
-        let oracles = cache.oracles.read().unwrap();
-        let cached = oracles.get(&address).unwrap();
+        let cached = cache.oracles.get(&address).unwrap();
         assert_eq!(cached.adapter.as_ref().unwrap().slot, 2);
+        assert_eq!(cache.get_cached_price(&address).unwrap().slot, 2);
     }
 
     #[test]
@@ -254,8 +487,7 @@ mod tests {
         // Try to update with a lower slot, should not update
         cache.update(3, &address, &mut account).unwrap();
 
-        let oracles = cache.oracles.read().unwrap();
-        let cached = oracles.get(&address).unwrap();
+        let cached = cache.oracles.get(&address).unwrap();
         assert_eq!(cached.adapter.as_ref().unwrap().slot, 5);
     }
 
@@ -279,6 +511,28 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_register_oracle() {
+        let cache = OraclesCache::default();
+        let address = Pubkey::new_unique();
+        let oracle_type = OracleSetup::PythPushOracle;
+        let mut account = dummy_account(oracle_type);
+        account.owner = pyth_solana_receiver_sdk::id();
+
+        assert!(cache.register(&address, oracle_type));
+        assert!(!cache.register(&address, oracle_type));
+        assert_eq!(cache.generation(), 1);
+        assert_eq!(cache.get_oracle_addresses(), vec![address]);
+        assert!(cache.get_cached_price(&address).is_none());
+
+        // Priced by its first update.
+        cache.update(2, &address, &mut account).unwrap();
+        assert_eq!(cache.get_cached_price(&address).unwrap().slot, 2);
+        // Replacing a cached Oracle does not add one.
+        cache.insert(3, &address, oracle_type, account).unwrap();
+        assert_eq!(cache.generation(), 1);
+    }
+
     #[test]
     fn test_update_nonexistent_oracle_does_nothing() {
         let cache = OraclesCache::default();
@@ -291,6 +545,14 @@ mod tests {
         assert!(addresses.is_empty());
     }
 
+    #[test]
+    fn test_is_supported_oracle() {
+        assert!(is_supported_oracle(&OracleSetup::SwitchboardPull));
+        assert!(is_supported_oracle(&OracleSetup::PythPushOracle));
+        assert!(!is_supported_oracle(&OracleSetup::PythLegacy));
+        assert!(!is_supported_oracle(&OracleSetup::None));
+    }
+
     #[test]
     fn test_parse_swb_adapter() {
         // Construct valid data: discriminator + PullFeedAccountData bytes
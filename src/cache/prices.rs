@@ -0,0 +1,269 @@
+use std::mem::size_of;
+
+use anchor_lang::AccountDeserialize;
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use fixed::types::I80F48;
+use marginfi::state::price::OracleSetup;
+use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
+use solana_sdk::pubkey::Pubkey;
+use switchboard_on_demand::{Discriminator, PullFeedAccountData};
+
+// The Marginfi program defaults: the price older than the oracle max age of the Bank, 60 seconds when not
+// set, is stale, and so is the price with a confidence interval over 10% of the price.
+const DEFAULT_ORACLE_MAX_AGE_SEC: i64 = 60;
+const MAX_CONFIDENCE_PERCENT: u32 = 10;
+// The program widens the Pyth confidence and the Switchboard standard deviation into its confidence interval.
+const PYTH_CONFIDENCE_MULTIPLE_PERCENT: u32 = 212;
+const SWB_STD_DEV_MULTIPLE_PERCENT: u32 = 196;
+const SWB_PRECISION: u32 = 18;
+
+/// The price of an Oracle with what the Marginfi program checks before using it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CachedPrice {
+    pub price: I80F48,
+    // The confidence interval, already widened like the program does.
+    pub confidence: I80F48,
+    // The time-weighted price and its widened confidence interval, which the initial requirement uses.
+    pub time_weighted_price: I80F48,
+    pub time_weighted_confidence: I80F48,
+    pub publish_time: i64,
+    pub slot: u64,
+}
+
+impl CachedPrice {
+    pub fn parse(slot: u64, oracle_type: &OracleSetup, data: &[u8]) -> Result<Self> {
+        match oracle_type {
+            OracleSetup::SwitchboardPull => Self::parse_swb(slot, data),
+            OracleSetup::PythPushOracle => Self::parse_pyth(slot, data),
+            _ => Err(anyhow!("Unsupported oracle type {:?}", oracle_type)),
+        }
+    }
+
+    /// The price with the time-weighted price and confidence interval in place of the last ones.
+    pub fn time_weighted(&self) -> Self {
+        Self {
+            price: self.time_weighted_price,
+            confidence: self.time_weighted_confidence,
+            ..*self
+        }
+    }
+
+    /// Whether the program would accept the price at the timestamp, given the oracle max age of the Bank.
+    pub fn is_valid(&self, max_age_sec: u16, unix_timestamp: i64) -> bool {
+        let max_age_sec = match max_age_sec {
+            0 => DEFAULT_ORACLE_MAX_AGE_SEC,
+            max_age_sec => max_age_sec as i64,
+        };
+        let max_confidence =
+            self.price * I80F48::from_num(MAX_CONFIDENCE_PERCENT) / I80F48::from_num(100);
+        self.price > I80F48::ZERO
+            && unix_timestamp.saturating_sub(self.publish_time) <= max_age_sec
+            && self.confidence <= max_confidence
+    }
+
+    fn parse_pyth(slot: u64, mut data: &[u8]) -> Result<Self> {
+        let price_update = PriceUpdateV2::try_deserialize(&mut data)
+            .map_err(|err| anyhow!("Failed to parse the Pyth oracle account: {}", err))?;
+        let message = &price_update.price_message;
+        let scale = |value: I80F48| {
+            let factor = I80F48::from_num(10u64.checked_pow(message.exponent.unsigned_abs())?);
+            if message.exponent < 0 {
+                value.checked_div(factor)
+            } else {
+                value.checked_mul(factor)
+            }
+        };
+        let price = scale(I80F48::from_num(message.price))
+            .ok_or_else(|| anyhow!("The Pyth price overflows"))?;
+        let confidence = scale(I80F48::from_num(message.conf))
+            .and_then(|conf| widen(conf, PYTH_CONFIDENCE_MULTIPLE_PERCENT))
+            .ok_or_else(|| anyhow!("The Pyth confidence overflows"))?;
+        let time_weighted_price = scale(I80F48::from_num(message.ema_price))
+            .ok_or_else(|| anyhow!("The Pyth EMA price overflows"))?;
+        let time_weighted_confidence = scale(I80F48::from_num(message.ema_conf))
+            .and_then(|conf| widen(conf, PYTH_CONFIDENCE_MULTIPLE_PERCENT))
+            .ok_or_else(|| anyhow!("The Pyth EMA confidence overflows"))?;
+
+        Ok(Self {
+            price,
+            confidence,
+            time_weighted_price,
+            time_weighted_confidence,
+            publish_time: message.publish_time,
+            slot,
+        })
+    }
+
+    fn parse_swb(slot: u64, data: &[u8]) -> Result<Self> {
+        if data.len() < 8 + size_of::<PullFeedAccountData>()
+            || data[..8] != PullFeedAccountData::DISCRIMINATOR
+        {
+            return Err(anyhow!("Invalid Swb oracle account"));
+        }
+        let feed = bytemuck::try_pod_read_unaligned::<PullFeedAccountData>(
+            &data[8..8 + size_of::<PullFeedAccountData>()],
+        )
+        .map_err(|err| anyhow!("Failed to parse the Swb oracle account: {:?}", err))?;
+        let scale = |value: i128| {
+            I80F48::checked_from_num(value)?.checked_div(I80F48::from_num(10u64.pow(SWB_PRECISION)))
+        };
+        let price = scale(feed.result.value).ok_or_else(|| anyhow!("The Swb price overflows"))?;
+        let confidence = scale(feed.result.std_dev)
+            .and_then(|std_dev| widen(std_dev, SWB_STD_DEV_MULTIPLE_PERCENT))
+            .ok_or_else(|| anyhow!("The Swb standard deviation overflows"))?;
+
+        // The Swb feeds have no time-weighted price, the program uses the last one instead.
+        Ok(Self {
+            price,
+            confidence,
+            time_weighted_price: price,
+            time_weighted_confidence: confidence,
+            publish_time: feed.last_update_timestamp,
+            slot,
+        })
+    }
+}
+
+fn widen(value: I80F48, multiple_percent: u32) -> Option<I80F48> {
+    value
+        .checked_mul(I80F48::from_num(multiple_percent))?
+        .checked_div(I80F48::from_num(100))
+}
+
+/// The last price of every Oracle, with its publish time, confidence and slot, for the health to be
+/// computed with the prices the program would accept only.
+#[derive(Default)]
+pub struct PricesCache {
+    prices: DashMap<Pubkey, CachedPrice>,
+}
+
+impl PricesCache {
+    /// Applies the price unless an older one.
+    pub fn update(&self, address: Pubkey, price: CachedPrice) {
+        let mut cached = self.prices.entry(address).or_insert(price);
+        if price.slot >= cached.slot {
+            *cached = price;
+        }
+    }
+
+    pub fn remove(&self, address: &Pubkey) {
+        self.prices.remove(address);
+    }
+
+    pub fn clear(&self) {
+        self.prices.clear();
+    }
+
+    pub fn get(&self, address: &Pubkey) -> Option<CachedPrice> {
+        self.prices.get(address).map(|price| *price)
+    }
+
+    /// The price of the Oracle, None when the program would reject it at the timestamp.
+    pub fn get_valid_price(
+        &self,
+        address: &Pubkey,
+        max_age_sec: u16,
+        unix_timestamp: i64,
+    ) -> Option<I80F48> {
+        self.get(address)
+            .filter(|price| price.is_valid(max_age_sec, unix_timestamp))
+            .map(|price| price.price)
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.prices.len() * (size_of::<Pubkey>() + size_of::<CachedPrice>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::prelude::AnchorSerialize;
+    use pyth_solana_receiver_sdk::price_update::{PriceFeedMessage, VerificationLevel};
+
+    fn pyth_data(price: i64, conf: u64, publish_time: i64) -> Vec<u8> {
+        let mut data = <PriceUpdateV2 as anchor_lang::Discriminator>::DISCRIMINATOR.to_vec();
+        PriceUpdateV2 {
+            write_authority: Pubkey::new_unique(),
+            verification_level: VerificationLevel::Full,
+            price_message: PriceFeedMessage {
+                feed_id: [0; 32],
+                // The time-weighted price trails 10% below.
+                ema_conf: conf,
+                ema_price: price / 10 * 9,
+                price,
+                conf,
+                exponent: -2,
+                prev_publish_time: publish_time - 1,
+                publish_time,
+            },
+            posted_slot: 0,
+        }
+        .serialize(&mut data)
+        .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_cached_price_validity() {
+        let price = CachedPrice::parse(
+            7,
+            &OracleSetup::PythPushOracle,
+            &pyth_data(200_000, 5_000, 1_000),
+        )
+        .unwrap();
+        assert_eq!(price.price, I80F48::from_num(2_000));
+        assert_eq!(price.confidence, I80F48::from_num(106));
+        assert_eq!(price.time_weighted_price, I80F48::from_num(1_800));
+        assert_eq!(price.time_weighted_confidence, I80F48::from_num(106));
+        assert_eq!(price.publish_time, 1_000);
+        assert_eq!(price.slot, 7);
+
+        // The default max age applies when the Bank sets none.
+        assert!(price.is_valid(0, 1_060));
+        assert!(!price.is_valid(0, 1_061));
+        assert!(price.is_valid(120, 1_120));
+        assert!(!price.is_valid(30, 1_031));
+
+        // Too wide a confidence interval, or no price.
+        let unsure = CachedPrice::parse(
+            7,
+            &OracleSetup::PythPushOracle,
+            &pyth_data(200_000, 10_000, 1_000),
+        )
+        .unwrap();
+        assert!(!unsure.is_valid(0, 1_000));
+        let unpriced =
+            CachedPrice::parse(7, &OracleSetup::PythPushOracle, &pyth_data(0, 0, 1_000)).unwrap();
+        assert!(!unpriced.is_valid(0, 1_000));
+
+        assert!(CachedPrice::parse(7, &OracleSetup::PythLegacy, &[]).is_err());
+    }
+
+    #[test]
+    fn test_prices_cache() {
+        let cache = PricesCache::default();
+        let oracle = Pubkey::new_unique();
+        let parse = |slot, price| {
+            CachedPrice::parse(
+                slot,
+                &OracleSetup::PythPushOracle,
+                &pyth_data(price, 0, 1_000),
+            )
+            .unwrap()
+        };
+        cache.update(oracle, parse(5, 100));
+        // The older prices are ignored.
+        cache.update(oracle, parse(4, 200));
+        assert_eq!(cache.get(&oracle).unwrap().price, I80F48::ONE);
+
+        cache.update(oracle, parse(6, 200));
+        assert_eq!(
+            cache.get_valid_price(&oracle, 0, 1_000),
+            Some(I80F48::from_num(2))
+        );
+        assert_eq!(cache.get_valid_price(&oracle, 0, 2_000), None);
+        assert_eq!(cache.get_valid_price(&Pubkey::new_unique(), 0, 1_000), None);
+    }
+}
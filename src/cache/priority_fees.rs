@@ -0,0 +1,201 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{anyhow, Result};
+use solana_sdk::signature::Signature;
+
+// The blocks of the rolling window, about a minute of blocks.
+const FEE_WINDOW_BLOCKS: usize = 150;
+// The slots after which the fees of a block whose meta was not streamed are dropped.
+const PENDING_SLOTS: u64 = 32;
+
+/// The compute unit prices, in micro-lamports, of the Marginfi transactions touching the banks in the recent blocks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PriorityFeeStats {
+    pub blocks: usize,
+    pub transactions: usize,
+    pub min: u64,
+    pub median: u64,
+    pub p75: u64,
+    pub p90: u64,
+    pub max: u64,
+}
+
+#[derive(Default)]
+struct Blocks {
+    // The fees of the blocks whose meta has not been streamed yet, by slot.
+    pending: BTreeMap<u64, HashMap<Signature, u64>>,
+    // The fees of the recent blocks, the oldest first.
+    window: VecDeque<(u64, Vec<u64>)>,
+}
+
+/// The rolling statistics of the priority fees paid for the banks, as streamed by Geyser: the fees of the
+/// transactions are collected by block, and a block joins the window once its meta is streamed.
+#[derive(Default)]
+pub struct PriorityFees {
+    blocks: Mutex<Blocks>,
+}
+
+impl PriorityFees {
+    /// Records the compute unit price of the transaction of the slot, once even if it is streamed by both
+    /// Geyser endpoints.
+    pub fn record_transaction(
+        &self,
+        slot: u64,
+        signature: Signature,
+        compute_unit_price: u64,
+    ) -> Result<()> {
+        let mut blocks = self.lock()?;
+        if blocks
+            .window
+            .iter()
+            .any(|(window_slot, _)| *window_slot == slot)
+        {
+            return Ok(());
+        }
+        blocks
+            .pending
+            .entry(slot)
+            .or_default()
+            .insert(signature, compute_unit_price);
+        Ok(())
+    }
+
+    /// Moves the fees of the block to the window, its meta having been streamed, and drops the oldest block of
+    /// the window when it is full. The blocks without a fee are counted too.
+    pub fn complete_block(&self, slot: u64) -> Result<()> {
+        let mut blocks = self.lock()?;
+        if blocks
+            .window
+            .iter()
+            .any(|(window_slot, _)| *window_slot == slot)
+        {
+            return Ok(());
+        }
+        let fees = blocks
+            .pending
+            .remove(&slot)
+            .map(|fees| fees.into_values().collect())
+            .unwrap_or_default();
+        blocks.window.push_back((slot, fees));
+        if blocks.window.len() > FEE_WINDOW_BLOCKS {
+            blocks.window.pop_front();
+        }
+        // The transactions of a block whose meta was missed, e.g. during a reconnect.
+        blocks.pending = blocks
+            .pending
+            .split_off(&slot.saturating_sub(PENDING_SLOTS));
+        Ok(())
+    }
+
+    /// The compute unit price at the percentile (0 to 100) of the transactions of the window, if any.
+    pub fn percentile(&self, percentile: f64) -> Result<Option<u64>> {
+        Ok(percentile_of(&self.window_fees()?, percentile))
+    }
+
+    pub fn stats(&self) -> Result<PriorityFeeStats> {
+        let blocks = self.lock()?.window.len();
+        let fees = self.window_fees()?;
+        Ok(PriorityFeeStats {
+            blocks,
+            transactions: fees.len(),
+            min: fees.first().copied().unwrap_or_default(),
+            median: percentile_of(&fees, 50.0).unwrap_or_default(),
+            p75: percentile_of(&fees, 75.0).unwrap_or_default(),
+            p90: percentile_of(&fees, 90.0).unwrap_or_default(),
+            max: fees.last().copied().unwrap_or_default(),
+        })
+    }
+
+    // The fees of the window, sorted.
+    fn window_fees(&self) -> Result<Vec<u64>> {
+        let mut fees: Vec<u64> = self
+            .lock()?
+            .window
+            .iter()
+            .flat_map(|(_, fees)| fees.iter().copied())
+            .collect();
+        fees.sort_unstable();
+        Ok(fees)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Blocks>> {
+        self.blocks
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the priority fees: {}", e))
+    }
+}
+
+// The nearest-rank percentile of the sorted fees.
+fn percentile_of(sorted_fees: &[u64], percentile: f64) -> Option<u64> {
+    if sorted_fees.is_empty() {
+        return None;
+    }
+    let rank = (percentile.clamp(0.0, 100.0) / 100.0 * sorted_fees.len() as f64).ceil() as usize;
+    Some(sorted_fees[rank.saturating_sub(1).min(sorted_fees.len() - 1)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority_fee_window() {
+        let fees = PriorityFees::default();
+        assert_eq!(fees.percentile(50.0).unwrap(), None);
+        assert_eq!(fees.stats().unwrap(), PriorityFeeStats::default());
+
+        let streamed_twice = Signature::new_unique();
+        fees.record_transaction(10, streamed_twice, 300).unwrap();
+        fees.record_transaction(10, streamed_twice, 300).unwrap();
+        fees.record_transaction(10, Signature::new_unique(), 100)
+            .unwrap();
+        fees.record_transaction(11, Signature::new_unique(), 1_000)
+            .unwrap();
+        // The block 11 is still pending.
+        fees.complete_block(10).unwrap();
+        assert_eq!(
+            fees.stats().unwrap(),
+            PriorityFeeStats {
+                blocks: 1,
+                transactions: 2,
+                min: 100,
+                median: 100,
+                p75: 300,
+                p90: 300,
+                max: 300,
+            }
+        );
+
+        // A transaction of a completed block streamed again by the other endpoint.
+        fees.record_transaction(10, Signature::new_unique(), 5_000)
+            .unwrap();
+        fees.complete_block(11).unwrap();
+        fees.complete_block(11).unwrap();
+        fees.complete_block(12).unwrap();
+        let stats = fees.stats().unwrap();
+        assert_eq!((stats.blocks, stats.transactions), (3, 3));
+        assert_eq!(fees.percentile(100.0).unwrap(), Some(1_000));
+        assert_eq!(fees.percentile(0.0).unwrap(), Some(100));
+    }
+
+    #[test]
+    fn test_priority_fee_window_drops_old_blocks() {
+        let fees = PriorityFees::default();
+        fees.record_transaction(0, Signature::new_unique(), 100)
+            .unwrap();
+        // The meta of the block 1 is missed.
+        fees.record_transaction(1, Signature::new_unique(), 200)
+            .unwrap();
+        fees.complete_block(0).unwrap();
+        for slot in 2..=FEE_WINDOW_BLOCKS as u64 + PENDING_SLOTS {
+            fees.complete_block(slot).unwrap();
+        }
+
+        let stats = fees.stats().unwrap();
+        assert_eq!((stats.blocks, stats.transactions), (FEE_WINDOW_BLOCKS, 0));
+        assert!(fees.lock().unwrap().pending.is_empty());
+    }
+}
@@ -0,0 +1,584 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use crossbeam::channel::Receiver;
+use log::{debug, info, warn};
+use solana_sdk::{account::Account, clock::DEFAULT_MS_PER_SLOT, pubkey::Pubkey};
+
+use crate::{
+    cache::{Cache, CacheLoader},
+    common::MessageType,
+    comms::CommsClient,
+    config::Config,
+};
+
+// The stale entries fetched at once, the stop being checked in between.
+const RECONCILIATION_BATCH_SIZE: usize = 1_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EntryKind {
+    MarginfiAccount,
+    Bank,
+    Oracle,
+    Mint,
+}
+
+/// The interruption of the Geyser stream, from the cache slot when it dropped until it was resubscribed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamGap {
+    pub dropped_at_slot: u64,
+    pub resubscribed_after: Duration,
+}
+
+/// Re-fetches the cache entries Geyser has not updated for a while, to catch the updates the subscription
+/// dropped silently, and the entries that may have changed while the stream was down. The refreshed entries
+/// take the cache slot, so that the idle accounts are re-fetched once per staleness period only. After a long
+/// outage of the stream, the cache is fully reloaded instead, for the accounts created meanwhile. It also loads
+/// the Mints and the Oracles of the Banks created since the startup, which Geyser does not stream, and rolls
+/// back the processed updates which never confirmed.
+pub struct CacheReconciler<T: CommsClient> {
+    stop: Arc<AtomicBool>,
+    cache: Arc<Cache>,
+    comms_client: T,
+    // None when only the stream gaps are reconciled.
+    stale_after_slots: Option<u64>,
+    interval: Duration,
+    gaps: Receiver<StreamGap>,
+    // The outage of the stream from which the cache is fully reloaded rather than resynced, None never.
+    full_reload_after: Option<Duration>,
+    loader: CacheLoader<T>,
+    new_banks: Receiver<Pubkey>,
+}
+
+impl<T: CommsClient> CacheReconciler<T> {
+    pub fn new(
+        config: &Config,
+        stop: Arc<AtomicBool>,
+        cache: Arc<Cache>,
+        gaps: Receiver<StreamGap>,
+        new_banks: Receiver<Pubkey>,
+    ) -> Result<Self> {
+        Ok(Self {
+            stop,
+            cache,
+            comms_client: T::new(config)?,
+            stale_after_slots: (config.cache_reconcile_after_min > 0)
+                .then(|| config.cache_reconcile_after_min * 60_000 / DEFAULT_MS_PER_SLOT),
+            interval: Duration::from_secs(config.cache_reconcile_interval_sec.max(1)),
+            gaps,
+            full_reload_after: (config.geyser_full_reload_after_sec > 0)
+                .then(|| Duration::from_secs(config.geyser_full_reload_after_sec)),
+            loader: CacheLoader::new(config, cache.clone())?,
+            new_banks,
+        })
+    }
+
+    pub fn run(&self) -> Result<()> {
+        info!("Entering the CacheReconciler loop.");
+        let mut last_run = Instant::now();
+        while !self.stop.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_secs(1));
+            // The latest gap covers the entries of the previous ones, all of them dropped before it.
+            if let Some(gap) = self.gaps.try_iter().last() {
+                self.recover(&gap);
+            }
+            let new_banks: Vec<Pubkey> = self.new_banks.try_iter().collect();
+            if !new_banks.is_empty() {
+                match self.load_new_banks(&new_banks) {
+                    Ok(loaded) => info!(
+                        "Loaded {} Mints and Oracles of {} new Banks",
+                        loaded,
+                        new_banks.len()
+                    ),
+                    Err(err) => warn!(
+                        "Failed to load the Mints and Oracles of the new Banks: {}",
+                        err
+                    ),
+                }
+            }
+            match self.roll_back() {
+                Ok(0) => {}
+                Ok(refreshed) => info!(
+                    "Rolled back {} cache entries updated by unconfirmed processed updates",
+                    refreshed
+                ),
+                Err(err) => warn!("Failed to roll back the unconfirmed cache entries: {}", err),
+            }
+            if last_run.elapsed() < self.interval {
+                continue;
+            }
+            match self.reconcile() {
+                Ok(0) => {}
+                Ok(refreshed) => info!("Refreshed {} stale cache entries", refreshed),
+                Err(err) => warn!("Failed to reconcile the cache: {}", err),
+            }
+            last_run = Instant::now();
+        }
+
+        info!("The CacheReconciler loop is stopped.");
+        Ok(())
+    }
+
+    /// Re-fetches the entries not updated within the staleness period, returning how many were refreshed.
+    /// The missing accounts, e.g. closed, are left as they are.
+    pub fn reconcile(&self) -> Result<usize> {
+        let Some(stale_after_slots) = self.stale_after_slots else {
+            return Ok(0);
+        };
+        // The accounts are read at a slot not older than the cache one.
+        let slot = self.cache.get_clock()?.slot;
+        let stale_before = slot.saturating_sub(stale_after_slots);
+        let is_stale = |_: &Pubkey, entry_slot: u64| entry_slot < stale_before;
+
+        let mut stale: Vec<(Pubkey, EntryKind)> = vec![];
+        stale.extend(
+            self.cache
+                .marginfi_accounts
+                .snapshot_entries_where(is_stale)?
+                .into_iter()
+                .map(|entry| (entry.address, EntryKind::MarginfiAccount)),
+        );
+        stale.extend(
+            self.cache
+                .banks
+                .snapshot_entries_where(is_stale)?
+                .into_iter()
+                .map(|entry| (entry.address, EntryKind::Bank)),
+        );
+        stale.extend(
+            self.cache
+                .oracles
+                .snapshot_entries_where(is_stale)?
+                .into_iter()
+                .map(|entry| (entry.address, EntryKind::Oracle)),
+        );
+        if stale.is_empty() {
+            return Ok(0);
+        }
+        debug!(
+            "Reconciling {} cache entries not updated since the slot {}",
+            stale.len(),
+            stale_before
+        );
+        self.refresh_all(slot, &stale)
+    }
+
+    /// Re-fetches the entries not updated since the stream dropped, returning how many were refreshed. The
+    /// Marginfi accounts without liabilities are left to the staleness reconciliation: a missed update can
+    /// only make them liquidatable by borrowing, which leaves them healthy.
+    pub fn resync(&self, gap: &StreamGap) -> Result<usize> {
+        let slot = self.cache.get_clock()?.slot;
+        let is_missed = |_: &Pubkey, entry_slot: u64| entry_slot <= gap.dropped_at_slot;
+
+        let mut missed: Vec<(Pubkey, EntryKind)> = vec![];
+        missed.extend(
+            self.cache
+                .banks
+                .snapshot_entries_where(is_missed)?
+                .into_iter()
+                .map(|entry| (entry.address, EntryKind::Bank)),
+        );
+        missed.extend(
+            self.cache
+                .oracles
+                .snapshot_entries_where(is_missed)?
+                .into_iter()
+                .map(|entry| (entry.address, EntryKind::Oracle)),
+        );
+        missed.extend(
+            self.cache
+                .marginfi_accounts
+                .get_indebted_accounts_where(is_missed)?
+                .into_iter()
+                .map(|address| (address, EntryKind::MarginfiAccount)),
+        );
+        debug!(
+            "Resyncing {} cache entries not updated since the Geyser stream dropped at the slot {}",
+            missed.len(),
+            gap.dropped_at_slot
+        );
+        self.refresh_all(slot, &missed)
+    }
+
+    /// Reloads the cache after the gap of the stream once it lasted the full reload window, as the resync refreshes
+    /// the cached entries only. Resyncs it otherwise.
+    pub fn recover(&self, gap: &StreamGap) {
+        if self
+            .full_reload_after
+            .is_some_and(|full_reload_after| gap.resubscribed_after >= full_reload_after)
+        {
+            info!(
+                "Reloading the cache after the Geyser stream was down for {:?}...",
+                gap.resubscribed_after
+            );
+            match self.loader.load_cache() {
+                Ok(()) => info!("Reloaded the cache after the Geyser outage"),
+                Err(err) => warn!(
+                    "Failed to reload the cache after the Geyser outage: {}",
+                    err
+                ),
+            }
+            return;
+        }
+        match self.resync(gap) {
+            Ok(refreshed) => info!(
+                "Refreshed {} cache entries possibly missed while the Geyser stream was down for {:?}",
+                refreshed, gap.resubscribed_after
+            ),
+            Err(err) => warn!("Failed to resync the cache after the Geyser gap: {}", err),
+        }
+    }
+
+    /// Fetches the Mints of the new Banks, and their registered Oracles not priced yet, for their positions to be
+    /// valued at once rather than from the next update of their Oracles. Returns how many were loaded.
+    pub fn load_new_banks(&self, banks: &[Pubkey]) -> Result<usize> {
+        let slot = self.cache.get_clock()?.slot;
+        let registered: HashSet<Pubkey> = self
+            .cache
+            .oracles
+            .get_oracle_addresses()
+            .into_iter()
+            .collect();
+        let mut missing: HashMap<Pubkey, EntryKind> = HashMap::new();
+        for address in banks {
+            let bank = self.cache.banks.get_bank(address)?;
+            if self.cache.mints.get(bank.mint())?.is_none() {
+                missing.insert(*bank.mint(), EntryKind::Mint);
+            }
+            for oracle in bank.oracle_addresses() {
+                if registered.contains(oracle) && self.cache.oracles.get_slot(oracle).is_none() {
+                    missing.insert(*oracle, EntryKind::Oracle);
+                }
+            }
+        }
+        let missing: Vec<(Pubkey, EntryKind)> = missing.into_iter().collect();
+        self.refresh_all(slot, &missing)
+    }
+
+    /// Re-fetches the entries of the processed updates which the confirmed Geyser stream left behind, e.g. on an
+    /// abandoned fork, returning how many were refreshed.
+    pub fn roll_back(&self) -> Result<usize> {
+        let unconfirmed: Vec<(Pubkey, EntryKind)> = self
+            .cache
+            .confirmations
+            .take_rollbacks()?
+            .into_iter()
+            .filter_map(|(address, message_type)| {
+                let kind = match message_type {
+                    MessageType::MarginfiAccount => EntryKind::MarginfiAccount,
+                    MessageType::Bank => EntryKind::Bank,
+                    MessageType::Oracle => EntryKind::Oracle,
+                    _ => return None,
+                };
+                Some((address, kind))
+            })
+            .collect();
+        if unconfirmed.is_empty() {
+            return Ok(0);
+        }
+        let slot = self.cache.get_clock()?.slot;
+        self.refresh_all(slot, &unconfirmed)
+    }
+
+    fn refresh_all(&self, slot: u64, entries: &[(Pubkey, EntryKind)]) -> Result<usize> {
+        let mut refreshed = 0;
+        for batch in entries.chunks(RECONCILIATION_BATCH_SIZE) {
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let kinds: HashMap<Pubkey, EntryKind> = batch.iter().copied().collect();
+            let addresses: Vec<Pubkey> = batch.iter().map(|(address, _)| *address).collect();
+            for (address, account) in self.comms_client.get_accounts(&addresses)? {
+                let Some(kind) = kinds.get(&address) else {
+                    continue;
+                };
+                match self.refresh(slot, *kind, address, account) {
+                    Ok(()) => refreshed += 1,
+                    Err(err) => warn!("Failed to refresh the {:?} {}: {}", kind, address, err),
+                }
+            }
+        }
+        Ok(refreshed)
+    }
+
+    fn refresh(
+        &self,
+        slot: u64,
+        kind: EntryKind,
+        address: Pubkey,
+        mut account: Account,
+    ) -> Result<()> {
+        match kind {
+            EntryKind::MarginfiAccount => {
+                self.cache
+                    .marginfi_accounts
+                    .update_data(slot, address, &account.data)
+            }
+            EntryKind::Bank => self.cache.banks.update_data(slot, address, &account.data),
+            EntryKind::Oracle => self.cache.oracles.update(slot, &address, &mut account),
+            EntryKind::Mint => self.cache.mints.update(address, &account),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam::channel;
+    use marginfi::state::marginfi_account::MarginfiAccount;
+    use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
+
+    use super::*;
+    use crate::{
+        cache::{
+            banks::{test_util::create_bank_with_oracles, CachedBank},
+            marginfi_accounts::{
+                test_util::{create_balance, create_marginfi_account},
+                CachedMarginfiAccount,
+            },
+            mints::test_util::create_mint_account,
+            test_util::generate_test_clock,
+        },
+        comms::test_util::MockedCommsClient,
+    };
+
+    fn account(data: Vec<u8>) -> Account {
+        Account {
+            lamports: 0,
+            data,
+            owner: Pubkey::new_unique(),
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    fn marginfi_account_data(address: Pubkey, marginfi_account: MarginfiAccount) -> Vec<u8> {
+        CachedMarginfiAccount::from(0, address, marginfi_account)
+            .to_snapshot_account()
+            .data
+    }
+
+    fn create_reconciler(
+        cache: Arc<Cache>,
+        accounts: HashMap<Pubkey, Account>,
+        stale_after_slots: Option<u64>,
+    ) -> CacheReconciler<MockedCommsClient> {
+        CacheReconciler {
+            stop: Arc::new(AtomicBool::new(false)),
+            cache: cache.clone(),
+            comms_client: MockedCommsClient::with_accounts(accounts.clone()),
+            stale_after_slots,
+            interval: Duration::ZERO,
+            gaps: channel::unbounded().1,
+            full_reload_after: None,
+            loader: CacheLoader {
+                program_id: Pubkey::new_unique(),
+                lut_addresses: vec![],
+                liquidator: Pubkey::new_unique(),
+                comms_client: MockedCommsClient::with_accounts(accounts),
+                cache,
+            },
+            new_banks: channel::unbounded().1,
+        }
+    }
+
+    #[test]
+    fn test_reconcile_refreshes_stale_entries() {
+        let cache = Arc::new(Cache::new(generate_test_clock(1_000)));
+        let stale = Pubkey::new_unique();
+        let fresh = Pubkey::new_unique();
+        let closed = Pubkey::new_unique();
+        let bank = Pubkey::new_unique();
+        let group = Pubkey::new_unique();
+        for (slot, address) in [(10, stale), (950, fresh), (10, closed)] {
+            cache
+                .marginfi_accounts
+                .update(slot, address, create_marginfi_account(group, vec![]))
+                .unwrap();
+        }
+        cache
+            .banks
+            .update(10, bank, &create_bank_with_oracles(vec![]))
+            .unwrap();
+
+        let mut accounts = HashMap::new();
+        for address in [stale, fresh] {
+            let data = marginfi_account_data(address, create_marginfi_account(group, vec![]));
+            accounts.insert(address, account(data));
+        }
+        let data = CachedBank::from(0, bank, create_bank_with_oracles(vec![]))
+            .to_snapshot_account()
+            .data;
+        accounts.insert(bank, account(data));
+
+        let reconciler = create_reconciler(cache.clone(), accounts, Some(100));
+        assert_eq!(reconciler.reconcile().unwrap(), 2);
+
+        let slot = |address| cache.marginfi_accounts.get_account(address).unwrap().slot();
+        assert_eq!(slot(&stale), 1_000);
+        assert_eq!(slot(&fresh), 950);
+        assert_eq!(slot(&closed), 10);
+        assert_eq!(cache.banks.get_bank(&bank).unwrap().slot, 1_000);
+        // The refreshed entries are not stale anymore.
+        assert_eq!(reconciler.reconcile().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_resync_refreshes_missed_entries() {
+        let cache = Arc::new(Cache::new(generate_test_clock(1_000)));
+        let (missed, no_liabilities, updated) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let bank = Pubkey::new_unique();
+        let group = Pubkey::new_unique();
+        let indebted = create_marginfi_account(group, vec![create_balance(bank, 10, 5)]);
+        let lending = create_marginfi_account(group, vec![create_balance(bank, 10, 0)]);
+        let mut accounts = HashMap::new();
+        for (slot, address, marginfi_account) in [
+            (500, missed, indebted),
+            (500, no_liabilities, lending),
+            (900, updated, indebted),
+        ] {
+            cache
+                .marginfi_accounts
+                .update(slot, address, marginfi_account)
+                .unwrap();
+            accounts.insert(
+                address,
+                account(marginfi_account_data(address, marginfi_account)),
+            );
+        }
+        cache
+            .banks
+            .update(500, bank, &create_bank_with_oracles(vec![]))
+            .unwrap();
+        let data = CachedBank::from(0, bank, create_bank_with_oracles(vec![]))
+            .to_snapshot_account()
+            .data;
+        accounts.insert(bank, account(data));
+
+        // Without a staleness period, only the gaps are reconciled.
+        let reconciler = create_reconciler(cache.clone(), accounts, None);
+        assert_eq!(reconciler.reconcile().unwrap(), 0);
+        let gap = StreamGap {
+            dropped_at_slot: 600,
+            resubscribed_after: Duration::from_secs(5),
+        };
+        assert_eq!(reconciler.resync(&gap).unwrap(), 2);
+
+        let slot = |address| cache.marginfi_accounts.get_account(address).unwrap().slot();
+        assert_eq!(slot(&missed), 1_000);
+        assert_eq!(slot(&no_liabilities), 500);
+        assert_eq!(slot(&updated), 900);
+        assert_eq!(cache.banks.get_bank(&bank).unwrap().slot, 1_000);
+    }
+
+    #[test]
+    fn test_recover_reloads_after_long_gap() {
+        let cache = Arc::new(Cache::new(generate_test_clock(1_000)));
+        // The mocked program accounts are the account at the program address.
+        let created = Pubkey::new_unique();
+        let marginfi_account = create_marginfi_account(Pubkey::new_unique(), vec![]);
+        let accounts = HashMap::from([(
+            created,
+            account(marginfi_account_data(created, marginfi_account)),
+        )]);
+        let mut reconciler = create_reconciler(cache.clone(), accounts, None);
+        reconciler.loader.program_id = created;
+        reconciler.full_reload_after = Some(Duration::from_secs(60));
+        let gap = |secs| StreamGap {
+            dropped_at_slot: 900,
+            resubscribed_after: Duration::from_secs(secs),
+        };
+        let is_cached = || {
+            cache
+                .marginfi_accounts
+                .find_account(&created)
+                .unwrap()
+                .is_some()
+        };
+
+        // The resync does not tell the accounts created during the gap.
+        reconciler.recover(&gap(59));
+        assert!(!is_cached());
+        reconciler.recover(&gap(60));
+        assert!(is_cached());
+    }
+
+    #[test]
+    fn test_roll_back_unconfirmed_entries() {
+        let cache = Arc::new(Cache::new(generate_test_clock(1_000)));
+        let (unconfirmed, confirmed) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let group = Pubkey::new_unique();
+        let mut accounts = HashMap::new();
+        for address in [unconfirmed, confirmed] {
+            cache
+                .marginfi_accounts
+                .update(990, address, create_marginfi_account(group, vec![]))
+                .unwrap();
+            cache
+                .confirmations
+                .observe_processed(address, 990, MessageType::MarginfiAccount)
+                .unwrap();
+            let data = marginfi_account_data(address, create_marginfi_account(group, vec![]));
+            accounts.insert(address, account(data));
+        }
+        cache
+            .confirmations
+            .observe_processed(Pubkey::new_unique(), 990, MessageType::TokenAccount)
+            .unwrap();
+        cache.confirmations.confirm(&confirmed, 990).unwrap();
+
+        let reconciler = create_reconciler(cache.clone(), accounts, None);
+        assert_eq!(reconciler.roll_back().unwrap(), 0);
+        assert_eq!(
+            cache
+                .confirmations
+                .observe_confirmed_slot(1_000, 5)
+                .unwrap(),
+            2
+        );
+        // Only the Marginfi accounts, Banks and Oracles are refreshed.
+        assert_eq!(reconciler.roll_back().unwrap(), 1);
+
+        let slot = |address| cache.marginfi_accounts.get_account(address).unwrap().slot();
+        assert_eq!(slot(&unconfirmed), 1_000);
+        assert_eq!(slot(&confirmed), 990);
+        assert_eq!(reconciler.roll_back().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_load_new_banks() {
+        let cache = Arc::new(Cache::new(generate_test_clock(1_000)));
+        let (bank, oracle) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let cached_bank = CachedBank::from(900, bank, create_bank_with_oracles(vec![oracle]));
+        let mint = *cached_bank.mint();
+        cache.register_bank_oracles(&[cached_bank.clone()]);
+        cache.banks.update_batch(vec![cached_bank]).unwrap();
+
+        let mut oracle_data = <PriceUpdateV2 as anchor_lang::Discriminator>::DISCRIMINATOR.to_vec();
+        oracle_data.extend_from_slice(&[0u8; std::mem::size_of::<PriceUpdateV2>()]);
+        let mut oracle_account = account(oracle_data);
+        oracle_account.owner = pyth_solana_receiver_sdk::id();
+        let accounts = HashMap::from([
+            (mint, create_mint_account(Pubkey::new_unique(), 6, None)),
+            (oracle, oracle_account),
+        ]);
+
+        let reconciler = create_reconciler(cache.clone(), accounts, None);
+        assert_eq!(reconciler.load_new_banks(&[bank]).unwrap(), 2);
+        assert_eq!(cache.mints.get(&mint).unwrap().unwrap().decimals, 6);
+        assert_eq!(cache.oracles.get_slot(&oracle), Some(1_000));
+        // Nothing is missing anymore.
+        assert_eq!(reconciler.load_new_banks(&[bank]).unwrap(), 0);
+    }
+}
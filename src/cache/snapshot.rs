@@ -1,18 +1,32 @@
 use std::{
-    fs,
-    path::Path,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
-use solana_sdk::{clock::Clock, pubkey::Pubkey};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use solana_sdk::{account::Account, clock::Clock, pubkey::Pubkey};
 
-use super::Cache;
+use super::{candidates::RankedCandidate, Cache};
+use migrations::MIGRATIONS;
 
-const SNAPSHOT_VERSION: u32 = 1;
+pub mod diff;
+mod migrations;
 
-#[derive(Serialize, Deserialize)]
+const SNAPSHOT_VERSION: u32 = 3;
+// The magic number of the zstd frames, telling the compressed snapshots from the raw bincode ones.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const DELTA_FILE_INFIX: &str = "delta";
+// The header of the snapshot files: the magic followed by the BLAKE3 hash of the rest of the file.
+const CHECKSUM_MAGIC: [u8; 4] = *b"MRYS";
+const CHECKSUM_HEADER_LEN: usize = CHECKSUM_MAGIC.len() + blake3::OUT_LEN;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SnapshotAccount {
     pub address: Pubkey,
     pub slot: u64,
@@ -29,6 +43,27 @@ impl SnapshotAccount {
     }
 }
 
+/// The snapshot entry of an account which is parsed along with its owner, e.g. the Pyth oracles.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotOwnedAccount {
+    pub address: Pubkey,
+    pub owner: Pubkey,
+    pub slot: u64,
+    pub data: Vec<u8>,
+}
+
+impl SnapshotOwnedAccount {
+    pub fn to_account(&self) -> Account {
+        Account {
+            lamports: 0,
+            data: self.data.clone(),
+            owner: self.owner,
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct CacheSnapshot {
     version: u32,
@@ -36,35 +71,84 @@ struct CacheSnapshot {
     clock: Clock,
     marginfi_accounts: Vec<SnapshotAccount>,
     banks: Vec<SnapshotAccount>,
+    // The oracles and the mints, so that the health can be computed as soon as the snapshot is restored
+    // rather than once Geyser has delivered every price feed.
+    oracles: Vec<SnapshotOwnedAccount>,
+    mints: Vec<SnapshotOwnedAccount>,
+    candidates: Vec<RankedCandidate>,
 }
 
 impl CacheSnapshot {
     fn capture(cache: &Cache) -> Result<Self> {
         Ok(Self {
             version: SNAPSHOT_VERSION,
-            generated_at_unix: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .map(|duration| duration.as_secs())
-                .unwrap_or_default(),
+            generated_at_unix: now_unix(),
             clock: cache.get_clock()?,
             marginfi_accounts: cache.marginfi_accounts.snapshot_entries()?,
             banks: cache.banks.snapshot_entries()?,
+            oracles: cache.oracles.snapshot_entries()?,
+            mints: cache.mints.snapshot_entries()?,
+            candidates: cache.candidates.get()?,
         })
     }
 }
 
-pub fn restore_cache_snapshot(cache: &Cache, path: &Path) -> Result<bool> {
+/// Encodes a full snapshot of the cache in memory, e.g. for a peer instance to restore it.
+pub fn encode_cache_snapshot(cache: &Cache, zstd_level: i32) -> Result<Vec<u8>> {
+    encode(&CacheSnapshot::capture(cache)?, zstd_level)
+}
+
+/// The accounts updated since the previous snapshot of the baseline, in the `<snapshot>.delta.<sequence>` files.
+#[derive(Serialize, Deserialize)]
+struct CacheSnapshotDelta {
+    version: u32,
+    // The `generated_at_unix` of the baseline the delta applies on.
+    base_generated_at_unix: u64,
+    generated_at_unix: u64,
+    sequence: u32,
+    clock: Clock,
+    marginfi_accounts: Vec<SnapshotAccount>,
+    banks: Vec<SnapshotAccount>,
+    oracles: Vec<SnapshotOwnedAccount>,
+    // The latest ranking, replacing the one of the baseline.
+    candidates: Vec<RankedCandidate>,
+}
+
+/// Restores the baseline snapshot, then applies its deltas in sequence. The snapshots older than the max age,
+/// if any, are not restored: the updates missed since then would be worth as many wasted liquidation attempts.
+pub fn restore_cache_snapshot(
+    cache: &Cache,
+    path: &Path,
+    max_age_sec: Option<u64>,
+) -> Result<bool> {
     if !path.exists() {
         return Ok(false);
     }
+    let started = Instant::now();
 
     let bytes = fs::read(path)
         .with_context(|| format!("Failed to read cache snapshot from {}", path.display()))?;
 
-    let snapshot: CacheSnapshot = bincode::deserialize(&bytes)
-        .with_context(|| format!("Failed to deserialize cache snapshot {}", path.display()))?;
+    let Some(snapshot) = decode_snapshot(bytes)
+        .with_context(|| format!("Failed to deserialize cache snapshot {}", path.display()))?
+    else {
+        return Ok(false);
+    };
+
+    // The deltas are all read and verified first, so that a corrupted one cannot leave a partially restored cache.
+    let deltas = read_deltas(path, snapshot.generated_at_unix)?;
 
-    if snapshot.version != SNAPSHOT_VERSION {
+    let generated_at_unix = deltas
+        .last()
+        .map_or(snapshot.generated_at_unix, |delta| delta.generated_at_unix);
+    let age_sec = now_unix().saturating_sub(generated_at_unix);
+    if let Some(max_age_sec) = max_age_sec.filter(|max_age_sec| age_sec > *max_age_sec) {
+        warn!(
+            "The cache snapshot {} is {}s old, beyond the max age of {}s",
+            path.display(),
+            age_sec,
+            max_age_sec
+        );
         return Ok(false);
     }
 
@@ -73,21 +157,954 @@ pub fn restore_cache_snapshot(cache: &Cache, path: &Path) -> Result<bool> {
         .marginfi_accounts
         .restore_from_snapshot(&snapshot.marginfi_accounts)?;
     cache.banks.restore_from_snapshot(&snapshot.banks)?;
+    // The oracle types are the ones of the restored Banks.
+    cache
+        .oracles
+        .restore_from_snapshot(&snapshot.oracles, &cache.supported_oracle_types()?)?;
+    cache.mints.restore_from_snapshot(&snapshot.mints)?;
+    cache.candidates.replace(snapshot.candidates)?;
+
+    let applied = deltas.len();
+    for delta in deltas {
+        cache.update_clock(delta.clock)?;
+        cache
+            .marginfi_accounts
+            .apply_snapshot_entries(&delta.marginfi_accounts)?;
+        cache.banks.apply_snapshot_entries(&delta.banks)?;
+        cache
+            .oracles
+            .apply_snapshot_entries(&delta.oracles, &cache.supported_oracle_types()?)?;
+        cache.candidates.replace(delta.candidates)?;
+    }
+    if applied > 0 {
+        info!("Applied {} cache snapshot deltas", applied);
+    }
+    cache
+        .metrics
+        .record_restore(started.elapsed(), generated_at_unix);
     Ok(true)
 }
 
-pub fn persist_cache_snapshot(cache: &Cache, path: &Path) -> Result<()> {
-    let snapshot = CacheSnapshot::capture(cache)?;
-    let data = bincode::serialize(&snapshot)?;
-    let tmp_path = path.with_extension("tmp");
-    fs::write(&tmp_path, data)
-        .with_context(|| format!("Failed to write cache snapshot to {}", tmp_path.display()))?;
-    fs::rename(&tmp_path, path).with_context(|| {
-        format!(
-            "Failed to finalize cache snapshot rename from {} to {}",
+// The deltas are read up to the first missing one. The ones of another baseline, left behind by a
+// crash between the baseline write and the removal of the previous deltas, are ignored.
+fn read_deltas(path: &Path, base_generated_at_unix: u64) -> Result<Vec<CacheSnapshotDelta>> {
+    let mut deltas = vec![];
+    loop {
+        let delta_path = delta_file_path(path, deltas.len() as u32 + 1)?;
+        if !delta_path.exists() {
+            return Ok(deltas);
+        }
+
+        let bytes = fs::read(&delta_path).with_context(|| {
+            format!(
+                "Failed to read cache snapshot delta from {}",
+                delta_path.display()
+            )
+        })?;
+        let bytes = decompress(bytes).with_context(|| {
+            format!(
+                "Failed to decompress cache snapshot delta {}",
+                delta_path.display()
+            )
+        })?;
+        // The deltas are not migrated, the ones of another version cannot even be deserialized.
+        if migrations::encoded_version(&bytes)? != SNAPSHOT_VERSION {
+            warn!(
+                "Ignoring the cache snapshot delta {} of another version",
+                delta_path.display()
+            );
+            return Ok(deltas);
+        }
+        let delta: CacheSnapshotDelta = bincode::deserialize(&bytes).with_context(|| {
+            format!(
+                "Failed to deserialize cache snapshot delta {}",
+                delta_path.display()
+            )
+        })?;
+        if delta.base_generated_at_unix != base_generated_at_unix {
+            warn!(
+                "Ignoring the cache snapshot delta {} of another baseline",
+                delta_path.display()
+            );
+            return Ok(deltas);
+        }
+        deltas.push(delta);
+    }
+}
+
+struct SnapshotWriterState {
+    base_generated_at_unix: u64,
+    // Of the last persisted baseline or delta.
+    generated_at_unix: u64,
+    deltas: u32,
+    // The slot of every account as of the last persisted snapshot.
+    persisted_slots: HashMap<Pubkey, u64>,
+    // The Marginfi accounts evicted as of the baseline. The deltas cannot remove accounts, so a new baseline is
+    // persisted after the evictions.
+    evictions: u64,
+}
+
+/// Persists a full baseline snapshot every `full_every` snapshots and, in between, deltas of only the
+/// accounts updated since the previous snapshot, so that frequent snapshots do not rewrite all the accounts.
+/// The snapshots are compressed at the zstd level, or uncompressed with the level 0. The last `retain` baselines
+/// are also kept as `<path>.<generated_at_unix>` files, for the operators to roll back to.
+pub struct SnapshotWriter {
+    path: PathBuf,
+    tmp_dir: PathBuf,
+    zstd_level: i32,
+    full_every: u32,
+    retain: u32,
+    // None until the first baseline of the process is written.
+    state: Mutex<Option<SnapshotWriterState>>,
+}
+
+impl SnapshotWriter {
+    pub fn new(
+        path: PathBuf,
+        tmp_dir: PathBuf,
+        zstd_level: i32,
+        full_every: u32,
+        retain: u32,
+    ) -> Self {
+        Self {
+            path,
+            tmp_dir,
+            zstd_level,
+            full_every: full_every.max(1),
+            retain,
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Returns whether a full baseline snapshot was written.
+    pub fn persist(&self, cache: &Cache) -> Result<bool> {
+        let started = Instant::now();
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the snapshot writer: {}", e))?;
+        match state.as_mut() {
+            Some(state)
+                if state.deltas + 1 < self.full_every
+                    && state.evictions == cache.marginfi_accounts.evictions() =>
+            {
+                self.persist_delta(cache, state)?;
+                cache
+                    .metrics
+                    .record_persist(started.elapsed(), state.generated_at_unix);
+                Ok(false)
+            }
+            _ => {
+                // A failed baseline is retried on the next snapshot.
+                *state = None;
+                let persisted = self.persist_baseline(cache)?;
+                cache
+                    .metrics
+                    .record_persist(started.elapsed(), persisted.generated_at_unix);
+                *state = Some(persisted);
+                Ok(true)
+            }
+        }
+    }
+
+    fn persist_baseline(&self, cache: &Cache) -> Result<SnapshotWriterState> {
+        let evictions = cache.marginfi_accounts.evictions();
+        let snapshot = CacheSnapshot::capture(cache)?;
+        let data = encode(&snapshot, self.zstd_level)?;
+        debug!(
+            "Encoded the cache snapshot of {} Marginfi accounts into {} bytes",
+            snapshot.marginfi_accounts.len(),
+            data.len()
+        );
+        write_atomically(&self.path, &self.tmp_dir, &data)?;
+        remove_delta_files(&self.path)?;
+        if self.retain > 0 {
+            // The live snapshot is written anyway, so a failed retention is not worth a retried baseline.
+            if let Err(err) = self.retain_baseline(snapshot.generated_at_unix) {
+                warn!("Failed to retain the cache snapshot: {}", err);
+            }
+        }
+
+        Ok(SnapshotWriterState {
+            base_generated_at_unix: snapshot.generated_at_unix,
+            generated_at_unix: snapshot.generated_at_unix,
+            deltas: 0,
+            persisted_slots: snapshot
+                .marginfi_accounts
+                .iter()
+                .chain(&snapshot.banks)
+                .map(|entry| (entry.address, entry.slot))
+                .chain(
+                    snapshot
+                        .oracles
+                        .iter()
+                        .map(|entry| (entry.address, entry.slot)),
+                )
+                .collect(),
+            evictions,
+        })
+    }
+
+    // The baseline is hard linked rather than copied when the filesystem allows, the next baseline replacing
+    // the live snapshot by a rename.
+    fn retain_baseline(&self, generated_at_unix: u64) -> Result<()> {
+        let retained_path = retained_file_path(&self.path, generated_at_unix)?;
+        if retained_path.exists() {
+            fs::remove_file(&retained_path)?;
+        }
+        if fs::hard_link(&self.path, &retained_path).is_err() {
+            fs::copy(&self.path, &retained_path).with_context(|| {
+                format!(
+                    "Failed to copy the cache snapshot to {}",
+                    retained_path.display()
+                )
+            })?;
+        }
+
+        let retained = list_retained_snapshots(&self.path)?;
+        let pruned = retained.len().saturating_sub(self.retain as usize);
+        for (_, pruned_path) in &retained[..pruned] {
+            fs::remove_file(pruned_path).with_context(|| {
+                format!(
+                    "Failed to prune the cache snapshot {}",
+                    pruned_path.display()
+                )
+            })?;
+            debug!("Pruned the cache snapshot {}", pruned_path.display());
+        }
+        Ok(())
+    }
+
+    fn persist_delta(&self, cache: &Cache, state: &mut SnapshotWriterState) -> Result<()> {
+        let is_updated =
+            |address: &Pubkey, slot: u64| state.persisted_slots.get(address) != Some(&slot);
+        let delta = CacheSnapshotDelta {
+            version: SNAPSHOT_VERSION,
+            base_generated_at_unix: state.base_generated_at_unix,
+            generated_at_unix: now_unix(),
+            sequence: state.deltas + 1,
+            clock: cache.get_clock()?,
+            marginfi_accounts: cache
+                .marginfi_accounts
+                .partition_snapshot_entries_where(is_updated)?,
+            banks: cache.banks.snapshot_entries_where(is_updated)?,
+            oracles: cache.oracles.snapshot_entries_where(is_updated)?,
+            candidates: cache.candidates.get()?,
+        };
+        let data = encode(&delta, self.zstd_level)?;
+        debug!(
+            "Encoded the cache snapshot delta {} of {} Marginfi accounts, {} Banks and {} Oracles into {} bytes",
+            delta.sequence,
+            delta.marginfi_accounts.len(),
+            delta.banks.len(),
+            delta.oracles.len(),
+            data.len()
+        );
+        write_atomically(
+            &delta_file_path(&self.path, delta.sequence)?,
+            &self.tmp_dir,
+            &data,
+        )?;
+
+        for entry in delta.marginfi_accounts.iter().chain(&delta.banks) {
+            state.persisted_slots.insert(entry.address, entry.slot);
+        }
+        for entry in &delta.oracles {
+            state.persisted_slots.insert(entry.address, entry.slot);
+        }
+        state.deltas = delta.sequence;
+        state.generated_at_unix = delta.generated_at_unix;
+        Ok(())
+    }
+}
+
+fn retained_file_path(path: &Path, generated_at_unix: u64) -> Result<PathBuf> {
+    let prefix = tmp_file_prefix(path)
+        .ok_or_else(|| anyhow!("Invalid snapshot file path {}", path.display()))?;
+    Ok(path.with_file_name(format!("{}{}", prefix, generated_at_unix)))
+}
+
+/// The retained snapshots of the path with their generation time, the oldest first.
+fn list_retained_snapshots(path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let Some(prefix) = tmp_file_prefix(path) else {
+        return Ok(vec![]);
+    };
+    let dir = default_tmp_dir(path);
+    let mut retained = vec![];
+    for entry in fs::read_dir(&dir)
+        .with_context(|| format!("Failed to list the snapshot dir {}", dir.display()))?
+    {
+        let entry_path = entry?.path();
+        let generated_at_unix = entry_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|generated_at_unix| generated_at_unix.parse::<u64>().ok());
+        if let Some(generated_at_unix) = generated_at_unix {
+            retained.push((generated_at_unix, entry_path));
+        }
+    }
+    retained.sort();
+    Ok(retained)
+}
+
+fn delta_file_path(path: &Path, sequence: u32) -> Result<PathBuf> {
+    let prefix = delta_file_prefix(path)
+        .ok_or_else(|| anyhow!("Invalid snapshot file path {}", path.display()))?;
+    Ok(path.with_file_name(format!("{}{}", prefix, sequence)))
+}
+
+fn delta_file_prefix(path: &Path) -> Option<String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| format!("{}.{}.", name, DELTA_FILE_INFIX))
+}
+
+// The deltas only apply on the baseline they were written after.
+fn remove_delta_files(path: &Path) -> Result<()> {
+    let Some(prefix) = delta_file_prefix(path) else {
+        return Ok(());
+    };
+    let dir = default_tmp_dir(path);
+    for entry in fs::read_dir(&dir)
+        .with_context(|| format!("Failed to list the snapshot dir {}", dir.display()))?
+    {
+        let entry_path = entry?.path();
+        let is_delta = entry_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(&prefix))
+            .is_some_and(|sequence| sequence.parse::<u32>().is_ok());
+        if is_delta {
+            fs::remove_file(&entry_path).with_context(|| {
+                format!(
+                    "Failed to remove the cache snapshot delta {}",
+                    entry_path.display()
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn encode<T: Serialize>(value: &T, zstd_level: i32) -> Result<Vec<u8>> {
+    let data = if zstd_level == 0 {
+        bincode::serialize(value)?
+    } else {
+        let mut encoder = zstd::Encoder::new(Vec::new(), zstd_level)
+            .with_context(|| format!("Invalid zstd level {}", zstd_level))?;
+        bincode::serialize_into(&mut encoder, value)?;
+        encoder.finish()?
+    };
+    Ok(seal(&data))
+}
+
+/// Prefixes the data with its checksum header.
+fn seal(data: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(CHECKSUM_HEADER_LEN + data.len());
+    sealed.extend_from_slice(&CHECKSUM_MAGIC);
+    sealed.extend_from_slice(blake3::hash(data).as_bytes());
+    sealed.extend_from_slice(data);
+    sealed
+}
+
+// Verifies and strips the checksum header, so that a partially written or corrupted file is rejected
+// rather than restored. The snapshots written before the checksums have no header.
+fn unseal(mut bytes: Vec<u8>) -> Result<Vec<u8>> {
+    if !bytes.starts_with(&CHECKSUM_MAGIC) {
+        return Ok(bytes);
+    }
+    check_seal(&bytes)?;
+    bytes.drain(..CHECKSUM_HEADER_LEN);
+    Ok(bytes)
+}
+
+/// Verifies a snapshot transferred over the network, which unlike the legacy files must be sealed.
+pub fn verify_sealed(bytes: &[u8]) -> Result<()> {
+    if !bytes.starts_with(&CHECKSUM_MAGIC) {
+        return Err(anyhow!("The snapshot has no checksum header"));
+    }
+    check_seal(bytes)
+}
+
+fn check_seal(bytes: &[u8]) -> Result<()> {
+    if bytes.len() < CHECKSUM_HEADER_LEN {
+        return Err(anyhow!("The snapshot checksum header is truncated"));
+    }
+    let (header, data) = bytes.split_at(CHECKSUM_HEADER_LEN);
+    if blake3::hash(data).as_bytes() != &header[CHECKSUM_MAGIC.len()..] {
+        return Err(anyhow!(
+            "The snapshot checksum does not match its content, the file is corrupted"
+        ));
+    }
+    Ok(())
+}
+
+fn decode<T: DeserializeOwned>(bytes: Vec<u8>) -> Result<T> {
+    Ok(bincode::deserialize(&decompress(bytes)?)?)
+}
+
+// The snapshots written before the compression are raw bincode.
+fn decompress(bytes: Vec<u8>) -> Result<Vec<u8>> {
+    let bytes = unseal(bytes)?;
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        Ok(zstd::decode_all(bytes.as_slice())?)
+    } else {
+        Ok(bytes)
+    }
+}
+
+// The snapshots of the previous versions are migrated, None if they cannot be.
+fn decode_snapshot(bytes: Vec<u8>) -> Result<Option<CacheSnapshot>> {
+    let bytes = decompress(bytes)?;
+
+    let version = migrations::encoded_version(&bytes)?;
+    match migrations::migrate(bytes, SNAPSHOT_VERSION, MIGRATIONS)? {
+        Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+        None => {
+            warn!(
+                "The cache snapshot version {} cannot be migrated to the version {}",
+                version, SNAPSHOT_VERSION
+            );
+            Ok(None)
+        }
+    }
+}
+
+const TMP_FILE_EXTENSION: &str = "tmp";
+
+/// The directory of the snapshot temp files when none is configured: the one of the snapshot itself,
+/// so that the final rename never crosses filesystems.
+pub fn default_tmp_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Writes the file so that readers either see the previous or the complete new content: the data is
+/// written and fsynced to a temp file, which is then renamed over the target.
+pub fn write_atomically(path: &Path, tmp_dir: &Path, data: &[u8]) -> Result<()> {
+    write_atomically_with(path, tmp_dir, |file| Ok(file.write_all(data)?))
+}
+
+/// Same as `write_atomically`, with the content streamed by `write`. The target is left untouched when it fails.
+pub fn write_atomically_with(
+    path: &Path,
+    tmp_dir: &Path,
+    write: impl FnOnce(&mut File) -> Result<()>,
+) -> Result<()> {
+    let tmp_path = tmp_file_path(path, tmp_dir)?;
+    let result = write_synced(&tmp_path, write).and_then(|_| replace_with(&tmp_path, path));
+    if result.is_err() && tmp_path.exists() {
+        if let Err(err) = fs::remove_file(&tmp_path) {
+            warn!(
+                "Failed to remove the temp file {}: {}",
+                tmp_path.display(),
+                err
+            );
+        }
+    }
+    result
+}
+
+/// Removes the temp files left behind by the snapshots interrupted by a crash.
+pub fn remove_orphaned_tmp_files(path: &Path, tmp_dir: &Path) -> Result<usize> {
+    let Some(prefix) = tmp_file_prefix(path) else {
+        return Ok(0);
+    };
+    if !tmp_dir.is_dir() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for entry in fs::read_dir(tmp_dir)
+        .with_context(|| format!("Failed to list the temp dir {}", tmp_dir.display()))?
+    {
+        let entry_path = entry?.path();
+        let is_orphan = entry_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                name.starts_with(&prefix) && name.ends_with(&format!(".{}", TMP_FILE_EXTENSION))
+            });
+        if is_orphan {
+            fs::remove_file(&entry_path).with_context(|| {
+                format!(
+                    "Failed to remove the orphaned temp file {}",
+                    entry_path.display()
+                )
+            })?;
+            debug!("Removed the orphaned temp file {}", entry_path.display());
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn tmp_file_prefix(path: &Path) -> Option<String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| format!("{}.", name))
+}
+
+// The temp file name is unique per process, so that concurrent writers do not clobber each other's temp files.
+fn tmp_file_path(path: &Path, tmp_dir: &Path) -> Result<PathBuf> {
+    let prefix = tmp_file_prefix(path)
+        .ok_or_else(|| anyhow!("Invalid snapshot file path {}", path.display()))?;
+    Ok(tmp_dir.join(format!(
+        "{}{}.{}",
+        prefix,
+        std::process::id(),
+        TMP_FILE_EXTENSION
+    )))
+}
+
+fn write_synced(path: &Path, write: impl FnOnce(&mut File) -> Result<()>) -> Result<()> {
+    let mut file = File::create(path)
+        .with_context(|| format!("Failed to create the temp file {}", path.display()))?;
+    write(&mut file)
+        .with_context(|| format!("Failed to write the temp file {}", path.display()))?;
+    file.sync_all()
+        .with_context(|| format!("Failed to fsync the temp file {}", path.display()))?;
+    Ok(())
+}
+
+fn replace_with(tmp_path: &Path, path: &Path) -> Result<()> {
+    if let Err(err) = fs::rename(tmp_path, path) {
+        // The rename fails when the temp dir is on another filesystem (EXDEV). Falling back to a copy
+        // next to the target keeps the final rename atomic.
+        debug!(
+            "Failed to rename {} to {} ({}), copying it instead",
             tmp_path.display(),
-            path.display()
-        )
-    })?;
+            path.display(),
+            err
+        );
+        let local_tmp_path = tmp_file_path(path, &default_tmp_dir(path))?;
+        if local_tmp_path == tmp_path {
+            return Err(err).with_context(|| {
+                format!(
+                    "Failed to rename {} to {}",
+                    tmp_path.display(),
+                    path.display()
+                )
+            });
+        }
+        let data = fs::read(tmp_path)
+            .with_context(|| format!("Failed to read the temp file {}", tmp_path.display()))?;
+        write_synced(&local_tmp_path, |file| Ok(file.write_all(&data)?))?;
+        fs::remove_file(tmp_path)
+            .with_context(|| format!("Failed to remove the temp file {}", tmp_path.display()))?;
+        fs::rename(&local_tmp_path, path).with_context(|| {
+            format!(
+                "Failed to rename {} to {}",
+                local_tmp_path.display(),
+                path.display()
+            )
+        })?;
+    }
+    sync_parent_dir(path)
+}
+
+// Persists the rename itself. Directories cannot be opened for syncing on Windows.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let dir = default_tmp_dir(path);
+    File::open(&dir)
+        .and_then(|dir_file| dir_file.sync_all())
+        .with_context(|| format!("Failed to fsync the directory {}", dir.display()))
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<()> {
     Ok(())
 }
+
+#[cfg(test)]
+pub mod test_util {
+    use std::{fs, path::PathBuf};
+
+    /// An empty directory for the files of a test, named after it.
+    pub fn create_test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mary_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use marginfi::state::price::OracleSetup;
+    use switchboard_on_demand::{Discriminator, PullFeedAccountData};
+
+    use super::*;
+    use crate::{
+        cache::{
+            banks::test_util::create_bank_with_oracles,
+            marginfi_accounts::test_util::{create_balance, create_marginfi_account},
+            mints::test_util::create_mint_account,
+            test_util::{create_dummy_cache, generate_test_clock},
+        },
+        config::test_util::create_dummy_config,
+    };
+    use test_util::create_test_dir;
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = create_test_dir("snapshot_write");
+        let path = dir.join("snapshot.bin");
+
+        write_atomically(&path, &dir, b"first").unwrap();
+        write_atomically(&path, &dir, b"second").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomically_with_separate_tmp_dir() {
+        let dir = create_test_dir("snapshot_separate");
+        let tmp_dir = dir.join("tmp");
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let path = dir.join("snapshot.bin");
+
+        write_atomically(&path, &tmp_dir, b"data").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+        assert_eq!(fs::read_dir(&tmp_dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomically_missing_tmp_dir() {
+        let dir = create_test_dir("snapshot_missing");
+        let path = dir.join("snapshot.bin");
+
+        assert!(write_atomically(&path, &dir.join("missing"), b"data").is_err());
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_orphaned_tmp_files() {
+        let dir = create_test_dir("snapshot_orphans");
+        let path = dir.join("snapshot.bin");
+        fs::write(dir.join("snapshot.bin.123.tmp"), b"orphan").unwrap();
+        fs::write(dir.join("other.bin.123.tmp"), b"unrelated").unwrap();
+        fs::write(&path, b"snapshot").unwrap();
+
+        assert_eq!(remove_orphaned_tmp_files(&path, &dir).unwrap(), 1);
+        assert!(path.exists());
+        assert!(dir.join("other.bin.123.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_persist_and_restore_compressed_snapshot() {
+        let dir = create_test_dir("snapshot_compressed");
+        let path = dir.join("snapshot.bin");
+        let cache = Cache::new(generate_test_clock(42));
+
+        SnapshotWriter::new(path.clone(), dir.clone(), 3, 1, 0)
+            .persist(&cache)
+            .unwrap();
+        assert!(unseal(fs::read(&path).unwrap())
+            .unwrap()
+            .starts_with(&ZSTD_MAGIC));
+        assert!(cache.metrics.last_persist_ms().is_some());
+        assert!(cache
+            .metrics
+            .last_snapshot_age_sec(now_unix())
+            .is_some_and(|age_sec| age_sec <= 1));
+
+        let restored = create_dummy_cache();
+        assert!(restore_cache_snapshot(&restored, &path, None).unwrap());
+        assert_eq!(restored.get_clock().unwrap().slot, 42);
+        assert!(restored.metrics.last_restore_ms().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_uncompressed_snapshot() {
+        let dir = create_test_dir("snapshot_uncompressed");
+        let path = dir.join("snapshot.bin");
+        let cache = Cache::new(generate_test_clock(42));
+
+        SnapshotWriter::new(path.clone(), dir.clone(), 0, 1, 0)
+            .persist(&cache)
+            .unwrap();
+        assert!(!unseal(fs::read(&path).unwrap())
+            .unwrap()
+            .starts_with(&ZSTD_MAGIC));
+
+        let restored = create_dummy_cache();
+        assert!(restore_cache_snapshot(&restored, &path, None).unwrap());
+        assert_eq!(restored.get_clock().unwrap().slot, 42);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_newer_snapshot_version() {
+        let dir = create_test_dir("snapshot_newer");
+        let path = dir.join("snapshot.bin");
+        let mut snapshot = CacheSnapshot::capture(&create_dummy_cache()).unwrap();
+        snapshot.version = SNAPSHOT_VERSION + 1;
+        fs::write(&path, encode(&snapshot, 3).unwrap()).unwrap();
+
+        assert!(!restore_cache_snapshot(&create_dummy_cache(), &path, None).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_corrupted_snapshot() {
+        let dir = create_test_dir("snapshot_corrupted");
+        let path = dir.join("snapshot.bin");
+        SnapshotWriter::new(path.clone(), dir.clone(), 3, 1, 0)
+            .persist(&Cache::new(generate_test_clock(42)))
+            .unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        fs::write(&path, &bytes).unwrap();
+
+        let restored = create_dummy_cache();
+        let err = restore_cache_snapshot(&restored, &path, None).unwrap_err();
+        assert!(format!("{:#}", err).contains("checksum does not match"));
+        assert_eq!(restored.get_clock().unwrap().slot, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unseal_legacy_snapshot() {
+        assert_eq!(unseal(b"legacy".to_vec()).unwrap(), b"legacy");
+        assert_eq!(unseal(seal(b"data")).unwrap(), b"data");
+        assert!(unseal(CHECKSUM_MAGIC.to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_verify_sealed() {
+        assert!(verify_sealed(&seal(b"data")).is_ok());
+        // The transferred snapshots must be sealed, and intact.
+        assert!(verify_sealed(b"legacy").is_err());
+        let mut truncated = seal(b"data");
+        truncated.pop();
+        assert!(verify_sealed(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_persist_and_restore_deltas() {
+        let dir = create_test_dir("snapshot_deltas");
+        let path = dir.join("snapshot.bin");
+        let cache = Cache::new(generate_test_clock(42));
+        let bank_a = Pubkey::new_unique();
+        let bank_b = Pubkey::new_unique();
+        let writer = SnapshotWriter::new(path.clone(), dir.clone(), 3, 3, 0);
+
+        cache
+            .banks
+            .update(42, bank_a, &create_bank_with_oracles(vec![]))
+            .unwrap();
+        assert!(writer.persist(&cache).unwrap());
+        cache
+            .banks
+            .update(43, bank_b, &create_bank_with_oracles(vec![]))
+            .unwrap();
+        cache.update_clock(generate_test_clock(43)).unwrap();
+        assert!(!writer.persist(&cache).unwrap());
+
+        let delta: CacheSnapshotDelta =
+            decode(fs::read(delta_file_path(&path, 1).unwrap()).unwrap()).unwrap();
+        assert_eq!(delta.banks.len(), 1);
+        assert_eq!(delta.banks[0].address, bank_b);
+
+        let restored = create_dummy_cache();
+        assert!(restore_cache_snapshot(&restored, &path, None).unwrap());
+        assert_eq!(restored.get_clock().unwrap().slot, 43);
+        assert_eq!(restored.banks.get_banks().unwrap().len(), 2);
+
+        // The next snapshots are an empty delta and then a new baseline, which removes the deltas.
+        writer.persist(&cache).unwrap();
+        assert!(delta_file_path(&path, 2).unwrap().exists());
+        writer.persist(&cache).unwrap();
+        assert!(!delta_file_path(&path, 1).unwrap().exists());
+        assert!(!delta_file_path(&path, 2).unwrap().exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_persist_and_restore_candidates() {
+        let dir = create_test_dir("snapshot_candidates");
+        let path = dir.join("snapshot.bin");
+        let cache = Cache::new(generate_test_clock(42));
+        let writer = SnapshotWriter::new(path.clone(), dir.clone(), 3, 2, 0);
+        let candidate = |health| RankedCandidate {
+            address: Pubkey::new_unique(),
+            health,
+            liquidatable_since_unix: 1_700_000_000,
+            ranked_at_unix: 1_700_000_060,
+        };
+
+        let ranked = vec![candidate(-5), candidate(-1)];
+        cache.candidates.replace(ranked.clone()).unwrap();
+        assert!(writer.persist(&cache).unwrap());
+        let restored = create_dummy_cache();
+        assert!(restore_cache_snapshot(&restored, &path, None).unwrap());
+        assert_eq!(restored.candidates.get().unwrap(), ranked);
+
+        // The ranking of the delta replaces the one of the baseline.
+        let reranked = vec![candidate(-3)];
+        cache.candidates.replace(reranked.clone()).unwrap();
+        assert!(!writer.persist(&cache).unwrap());
+        let restored = create_dummy_cache();
+        assert!(restore_cache_snapshot(&restored, &path, None).unwrap());
+        assert_eq!(restored.candidates.get().unwrap(), reranked);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_persist_baseline_after_evictions() {
+        let dir = create_test_dir("snapshot_evictions");
+        let path = dir.join("snapshot.bin");
+        let mut config = create_dummy_config();
+        config.cache_evict_empty_accounts = true;
+        let cache = Cache::open(&config, generate_test_clock(42)).unwrap();
+        let address = Pubkey::new_unique();
+        let group = Pubkey::new_unique();
+        let writer = SnapshotWriter::new(path.clone(), dir.clone(), 3, 3, 0);
+
+        cache
+            .marginfi_accounts
+            .update(
+                42,
+                address,
+                create_marginfi_account(group, vec![create_balance(Pubkey::new_unique(), 1, 0)]),
+            )
+            .unwrap();
+        writer.persist(&cache).unwrap();
+        cache
+            .marginfi_accounts
+            .update(43, address, create_marginfi_account(group, vec![]))
+            .unwrap();
+        writer.persist(&cache).unwrap();
+
+        // The eviction cannot be expressed as a delta.
+        assert!(!delta_file_path(&path, 1).unwrap().exists());
+        let restored = create_dummy_cache();
+        assert!(restore_cache_snapshot(&restored, &path, None).unwrap());
+        assert!(restored.marginfi_accounts.get_account(&address).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retain_snapshots() {
+        let dir = create_test_dir("snapshot_retain");
+        let path = dir.join("snapshot.bin");
+        // The older retained snapshots, a delta and an unrelated file.
+        for name in [
+            "snapshot.bin.100",
+            "snapshot.bin.200",
+            "snapshot.bin.delta.1",
+            "other.bin.50",
+        ] {
+            fs::write(dir.join(name), b"old").unwrap();
+        }
+        let writer = SnapshotWriter::new(path.clone(), dir.clone(), 3, 1, 2);
+        let cache = Cache::new(generate_test_clock(42));
+        assert!(writer.persist(&cache).unwrap());
+
+        let retained = list_retained_snapshots(&path).unwrap();
+        assert_eq!(retained.len(), 2);
+        assert_eq!(retained[0].1, dir.join("snapshot.bin.200"));
+        // The latest retained snapshot is the live one, which restores.
+        assert_eq!(fs::read(&retained[1].1).unwrap(), fs::read(&path).unwrap());
+        assert!(!dir.join("snapshot.bin.100").exists());
+        assert!(dir.join("other.bin.50").exists());
+        assert!(restore_cache_snapshot(&create_dummy_cache(), &retained[1].1, None).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_persist_and_restore_oracles_and_mints() {
+        let dir = create_test_dir("snapshot_oracles");
+        let path = dir.join("snapshot.bin");
+        let cache = Cache::new(generate_test_clock(42));
+        let oracle = Pubkey::new_unique();
+        let mut bank = create_bank_with_oracles(vec![oracle]);
+        bank.config.oracle_setup = OracleSetup::SwitchboardPull;
+        cache.banks.update(42, Pubkey::new_unique(), &bank).unwrap();
+
+        let mut data = PullFeedAccountData::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&[0u8; std::mem::size_of::<PullFeedAccountData>()]);
+        let oracle_account = SnapshotOwnedAccount {
+            address: oracle,
+            owner: Pubkey::new_unique(),
+            slot: 41,
+            data,
+        };
+        cache
+            .oracles
+            .insert(
+                41,
+                &oracle,
+                OracleSetup::SwitchboardPull,
+                oracle_account.to_account(),
+            )
+            .unwrap();
+        let mint_owner = Pubkey::new_unique();
+        cache
+            .mints
+            .update(bank.mint, &create_mint_account(mint_owner, 6, None))
+            .unwrap();
+        SnapshotWriter::new(path.clone(), dir.clone(), 3, 1, 0)
+            .persist(&cache)
+            .unwrap();
+
+        let restored = create_dummy_cache();
+        assert!(restore_cache_snapshot(&restored, &path, None).unwrap());
+        assert_eq!(
+            restored.oracles.snapshot_entries().unwrap(),
+            vec![oracle_account]
+        );
+        let mint = restored.mints.get(&bank.mint).unwrap().unwrap();
+        assert_eq!(mint._owner, mint_owner);
+        assert_eq!(mint.decimals, 6);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_snapshot_over_max_age() {
+        let dir = create_test_dir("snapshot_max_age");
+        let path = dir.join("snapshot.bin");
+        let mut snapshot = CacheSnapshot::capture(&Cache::new(generate_test_clock(42))).unwrap();
+        snapshot.generated_at_unix -= 600;
+        fs::write(&path, encode(&snapshot, 3).unwrap()).unwrap();
+
+        let restored = create_dummy_cache();
+        assert!(!restore_cache_snapshot(&restored, &path, Some(300)).unwrap());
+        assert_eq!(restored.get_clock().unwrap().slot, 1);
+        assert!(restore_cache_snapshot(&restored, &path, Some(900)).unwrap());
+        assert_eq!(restored.get_clock().unwrap().slot, 42);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_default_tmp_dir() {
+        assert_eq!(
+            default_tmp_dir(Path::new("/data/snapshot.bin")),
+            PathBuf::from("/data")
+        );
+        assert_eq!(
+            default_tmp_dir(Path::new("snapshot.bin")),
+            PathBuf::from(".")
+        );
+    }
+}
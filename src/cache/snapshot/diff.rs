@@ -0,0 +1,230 @@
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+use anchor_lang::AccountDeserialize;
+use anyhow::{anyhow, Context, Result};
+use bytemuck::bytes_of;
+use marginfi::state::marginfi_group::Bank;
+use solana_sdk::pubkey::Pubkey;
+
+use super::{decode_snapshot, read_deltas, SnapshotAccount};
+
+/// The differences between two cache snapshots, each with its deltas applied, for the post-incident analysis.
+#[derive(Debug, Default, PartialEq)]
+pub struct SnapshotDiff {
+    pub added_accounts: Vec<Pubkey>,
+    pub removed_accounts: Vec<Pubkey>,
+    pub changed_accounts: Vec<Pubkey>,
+    pub added_banks: Vec<Pubkey>,
+    pub removed_banks: Vec<Pubkey>,
+    // The Banks whose config changed, the ones with only their balances or rates updated being left out.
+    pub reconfigured_banks: Vec<Pubkey>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sections = [
+            ("Added Marginfi accounts", &self.added_accounts),
+            ("Removed Marginfi accounts", &self.removed_accounts),
+            ("Changed Marginfi accounts", &self.changed_accounts),
+            ("Added Banks", &self.added_banks),
+            ("Removed Banks", &self.removed_banks),
+            ("Reconfigured Banks", &self.reconfigured_banks),
+        ];
+        for (title, addresses) in sections {
+            writeln!(f, "{}: {}", title, addresses.len())?;
+            for address in addresses {
+                writeln!(f, "  {}", address)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares the snapshot files, as they would be restored.
+pub fn diff_cache_snapshots(old_path: &Path, new_path: &Path) -> Result<SnapshotDiff> {
+    let old = SnapshotState::read(old_path)?;
+    let new = SnapshotState::read(new_path)?;
+
+    let (added_accounts, removed_accounts, changed_accounts) = diff_entries(
+        &old.marginfi_accounts,
+        &new.marginfi_accounts,
+        |old, new| Ok(old != new),
+    )?;
+    let (added_banks, removed_banks, reconfigured_banks) =
+        diff_entries(&old.banks, &new.banks, |old, new| {
+            Ok(
+                bytes_of(&deserialize_bank(old)?.config)
+                    != bytes_of(&deserialize_bank(new)?.config),
+            )
+        })?;
+
+    Ok(SnapshotDiff {
+        added_accounts,
+        removed_accounts,
+        changed_accounts,
+        added_banks,
+        removed_banks,
+        reconfigured_banks,
+    })
+}
+
+// The data of the accounts of the snapshot, by address.
+struct SnapshotState {
+    marginfi_accounts: HashMap<Pubkey, Vec<u8>>,
+    banks: HashMap<Pubkey, Vec<u8>>,
+}
+
+impl SnapshotState {
+    fn read(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path)
+            .with_context(|| format!("Failed to read cache snapshot from {}", path.display()))?;
+        let snapshot = decode_snapshot(bytes)
+            .with_context(|| format!("Failed to deserialize cache snapshot {}", path.display()))?
+            .ok_or_else(|| anyhow!("Unsupported cache snapshot version {}", path.display()))?;
+
+        let mut state = Self {
+            marginfi_accounts: by_address(snapshot.marginfi_accounts),
+            banks: by_address(snapshot.banks),
+        };
+        for delta in read_deltas(path, snapshot.generated_at_unix)? {
+            state
+                .marginfi_accounts
+                .extend(by_address(delta.marginfi_accounts));
+            state.banks.extend(by_address(delta.banks));
+        }
+        Ok(state)
+    }
+}
+
+fn by_address(entries: Vec<SnapshotAccount>) -> HashMap<Pubkey, Vec<u8>> {
+    entries
+        .into_iter()
+        .map(|entry| (entry.address, entry.data))
+        .collect()
+}
+
+fn deserialize_bank(data: &[u8]) -> Result<Bank> {
+    Bank::try_deserialize(&mut &data[..])
+        .map_err(|err| anyhow!("Failed to deserialize the snapshot Bank: {}", err))
+}
+
+// The added, removed and changed entries, each sorted by address.
+fn diff_entries(
+    old: &HashMap<Pubkey, Vec<u8>>,
+    new: &HashMap<Pubkey, Vec<u8>>,
+    is_changed: impl Fn(&[u8], &[u8]) -> Result<bool>,
+) -> Result<(Vec<Pubkey>, Vec<Pubkey>, Vec<Pubkey>)> {
+    let mut added: Vec<Pubkey> = new
+        .keys()
+        .filter(|address| !old.contains_key(address))
+        .copied()
+        .collect();
+    let mut removed: Vec<Pubkey> = old
+        .keys()
+        .filter(|address| !new.contains_key(address))
+        .copied()
+        .collect();
+    let mut changed = vec![];
+    for (address, old_data) in old {
+        if let Some(new_data) = new.get(address) {
+            if is_changed(old_data, new_data)? {
+                changed.push(*address);
+            }
+        }
+    }
+    added.sort();
+    removed.sort();
+    changed.sort();
+    Ok((added, removed, changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{
+        banks::test_util::create_bank_with_oracles,
+        marginfi_accounts::test_util::create_marginfi_account, snapshot::SnapshotWriter,
+        test_util::generate_test_clock, Cache,
+    };
+    use marginfi::state::marginfi_account::MarginfiAccount;
+
+    fn update_account(cache: &Cache, slot: u64, address: Pubkey, account: &MarginfiAccount) {
+        cache
+            .marginfi_accounts
+            .update(slot, address, *account)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_diff_cache_snapshots() {
+        let dir = std::env::temp_dir().join(format!("mary_snapshot_diff_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (old_path, new_path) = (dir.join("old.bin"), dir.join("new.bin"));
+
+        let (kept, removed, changed, added) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let (reconfigured_bank, updated_bank) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let account = create_marginfi_account(Pubkey::new_unique(), vec![]);
+        let bank = create_bank_with_oracles(vec![]);
+
+        let cache = Cache::new(generate_test_clock(42));
+        for address in [kept, removed, changed] {
+            update_account(&cache, 42, address, &account);
+        }
+        for address in [reconfigured_bank, updated_bank] {
+            cache.banks.update(42, address, &bank).unwrap();
+        }
+        SnapshotWriter::new(old_path.clone(), dir.clone(), 3, 1, 0)
+            .persist(&cache)
+            .unwrap();
+        assert!(diff_cache_snapshots(&old_path, &old_path)
+            .unwrap()
+            .is_empty());
+
+        let cache = Cache::new(generate_test_clock(43));
+        update_account(&cache, 42, kept, &account);
+        update_account(
+            &cache,
+            43,
+            changed,
+            &create_marginfi_account(Pubkey::new_unique(), vec![]),
+        );
+        update_account(&cache, 43, added, &account);
+        let mut reconfigured = bank;
+        reconfigured.config.oracle_max_age += 1;
+        cache
+            .banks
+            .update(43, reconfigured_bank, &reconfigured)
+            .unwrap();
+        let mut updated = bank;
+        updated.last_update += 1;
+        cache.banks.update(43, updated_bank, &updated).unwrap();
+        SnapshotWriter::new(new_path.clone(), dir.clone(), 3, 1, 0)
+            .persist(&cache)
+            .unwrap();
+
+        assert_eq!(
+            diff_cache_snapshots(&old_path, &new_path).unwrap(),
+            SnapshotDiff {
+                added_accounts: vec![added],
+                removed_accounts: vec![removed],
+                changed_accounts: vec![changed],
+                added_banks: vec![],
+                removed_banks: vec![],
+                reconfigured_banks: vec![reconfigured_bank],
+            }
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use solana_sdk::clock::Clock;
+
+use super::{CacheSnapshot, SnapshotAccount, SnapshotOwnedAccount};
+
+// The version is the first field of all the snapshot versions, encoded as a fixed size integer.
+const VERSION_LEN: usize = 4;
+
+/// Upgrades an encoded snapshot of the version `from` to the version `from + 1`.
+pub(super) struct SnapshotMigration {
+    pub from: u32,
+    pub migrate: fn(&[u8]) -> Result<Vec<u8>>,
+}
+
+/// The migrations of the previous snapshot versions. When the `CacheSnapshot` layout changes, bump the
+/// SNAPSHOT_VERSION, keep the previous layout here and register its migration, so that the existing
+/// snapshots are upgraded rather than discarded for a full reload.
+pub(super) const MIGRATIONS: &[SnapshotMigration] = &[
+    SnapshotMigration {
+        from: 1,
+        migrate: migrate_v1,
+    },
+    SnapshotMigration {
+        from: 2,
+        migrate: migrate_v2,
+    },
+];
+
+/// The snapshots without the oracles and the mints, which are loaded from the RPC after the restore.
+#[derive(Serialize, Deserialize)]
+struct CacheSnapshotV1 {
+    version: u32,
+    generated_at_unix: u64,
+    clock: Clock,
+    marginfi_accounts: Vec<SnapshotAccount>,
+    banks: Vec<SnapshotAccount>,
+}
+
+fn migrate_v1(bytes: &[u8]) -> Result<Vec<u8>> {
+    let snapshot: CacheSnapshotV1 = bincode::deserialize(bytes)?;
+    Ok(bincode::serialize(&CacheSnapshotV2 {
+        version: snapshot.version + 1,
+        generated_at_unix: snapshot.generated_at_unix,
+        clock: snapshot.clock,
+        marginfi_accounts: snapshot.marginfi_accounts,
+        banks: snapshot.banks,
+        oracles: vec![],
+        mints: vec![],
+    })?)
+}
+
+/// The snapshots without the ranked liquidation candidates, which the first evaluation cycle ranks.
+#[derive(Serialize, Deserialize)]
+struct CacheSnapshotV2 {
+    version: u32,
+    generated_at_unix: u64,
+    clock: Clock,
+    marginfi_accounts: Vec<SnapshotAccount>,
+    banks: Vec<SnapshotAccount>,
+    oracles: Vec<SnapshotOwnedAccount>,
+    mints: Vec<SnapshotOwnedAccount>,
+}
+
+fn migrate_v2(bytes: &[u8]) -> Result<Vec<u8>> {
+    let snapshot: CacheSnapshotV2 = bincode::deserialize(bytes)?;
+    Ok(bincode::serialize(&CacheSnapshot {
+        version: snapshot.version + 1,
+        generated_at_unix: snapshot.generated_at_unix,
+        clock: snapshot.clock,
+        marginfi_accounts: snapshot.marginfi_accounts,
+        banks: snapshot.banks,
+        oracles: snapshot.oracles,
+        mints: snapshot.mints,
+        candidates: vec![],
+    })?)
+}
+
+pub(super) fn encoded_version(bytes: &[u8]) -> Result<u32> {
+    let version = bytes
+        .get(..VERSION_LEN)
+        .ok_or_else(|| anyhow!("The snapshot is too short for a version"))?;
+    Ok(bincode::deserialize(version)?)
+}
+
+/// Migrates the encoded snapshot one version at a time up to the target version. Returns None when
+/// there is no migration path, e.g. for the snapshots of a newer version.
+pub(super) fn migrate(
+    mut bytes: Vec<u8>,
+    target_version: u32,
+    migrations: &[SnapshotMigration],
+) -> Result<Option<Vec<u8>>> {
+    let mut version = encoded_version(&bytes)?;
+    while version < target_version {
+        let Some(migration) = migrations
+            .iter()
+            .find(|migration| migration.from == version)
+        else {
+            return Ok(None);
+        };
+        info!(
+            "Migrating the cache snapshot from the version {} to {}",
+            version,
+            version + 1
+        );
+        bytes = (migration.migrate)(&bytes)?;
+
+        let migrated_version = encoded_version(&bytes)?;
+        if migrated_version != version + 1 {
+            return Err(anyhow!(
+                "The migration of the snapshot version {} produced the version {}",
+                version,
+                migrated_version
+            ));
+        }
+        version = migrated_version;
+    }
+
+    Ok((version == target_version).then_some(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::pubkey::Pubkey;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct SnapshotV1 {
+        version: u32,
+        slot: u64,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct SnapshotV2 {
+        version: u32,
+        slot: u64,
+        banks: Vec<u64>,
+    }
+
+    fn migrate_v1(bytes: &[u8]) -> Result<Vec<u8>> {
+        let snapshot: SnapshotV1 = bincode::deserialize(bytes)?;
+        Ok(bincode::serialize(&SnapshotV2 {
+            version: 2,
+            slot: snapshot.slot,
+            banks: Vec::new(),
+        })?)
+    }
+
+    fn migrate_to_wrong_version(_bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&SnapshotV1 {
+            version: 5,
+            slot: 0,
+        })?)
+    }
+
+    const TEST_MIGRATIONS: &[SnapshotMigration] = &[SnapshotMigration {
+        from: 1,
+        migrate: migrate_v1,
+    }];
+
+    #[test]
+    fn test_migrate() {
+        let v1 = bincode::serialize(&SnapshotV1 {
+            version: 1,
+            slot: 42,
+        })
+        .unwrap();
+
+        let migrated = migrate(v1, 2, TEST_MIGRATIONS).unwrap().unwrap();
+        assert_eq!(
+            bincode::deserialize::<SnapshotV2>(&migrated).unwrap(),
+            SnapshotV2 {
+                version: 2,
+                slot: 42,
+                banks: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_migrate_current_version() {
+        let v1 = bincode::serialize(&SnapshotV1 {
+            version: 1,
+            slot: 42,
+        })
+        .unwrap();
+        assert_eq!(migrate(v1.clone(), 1, &[]).unwrap(), Some(v1));
+    }
+
+    #[test]
+    fn test_migrate_without_path() {
+        let v1 = bincode::serialize(&SnapshotV1 {
+            version: 1,
+            slot: 42,
+        })
+        .unwrap();
+        assert_eq!(migrate(v1.clone(), 3, TEST_MIGRATIONS).unwrap(), None);
+        // A newer snapshot cannot be downgraded.
+        assert_eq!(migrate(v1, 0, TEST_MIGRATIONS).unwrap(), None);
+    }
+
+    #[test]
+    fn test_migrate_to_wrong_version() {
+        let v1 = bincode::serialize(&SnapshotV1 {
+            version: 1,
+            slot: 42,
+        })
+        .unwrap();
+        let migrations = [SnapshotMigration {
+            from: 1,
+            migrate: migrate_to_wrong_version,
+        }];
+        assert!(migrate(v1, 2, &migrations).is_err());
+    }
+
+    #[test]
+    fn test_migrate_v1() {
+        let v1 = bincode::serialize(&CacheSnapshotV1 {
+            version: 1,
+            generated_at_unix: 1_700_000_000,
+            clock: Clock::default(),
+            marginfi_accounts: vec![SnapshotAccount::new(Pubkey::new_unique(), 42, vec![1])],
+            banks: vec![],
+        })
+        .unwrap();
+
+        // Through the version 2.
+        let migrated = migrate(v1, 3, MIGRATIONS).unwrap().unwrap();
+        let snapshot: CacheSnapshot = bincode::deserialize(&migrated).unwrap();
+        assert_eq!(snapshot.version, 3);
+        assert_eq!(snapshot.generated_at_unix, 1_700_000_000);
+        assert_eq!(snapshot.marginfi_accounts[0].slot, 42);
+        assert!(snapshot.oracles.is_empty());
+        assert!(snapshot.mints.is_empty());
+        assert!(snapshot.candidates.is_empty());
+    }
+
+    #[test]
+    fn test_encoded_version_too_short() {
+        assert!(encoded_version(&[1, 0]).is_err());
+    }
+}
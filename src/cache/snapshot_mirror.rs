@@ -0,0 +1,333 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use reqwest::blocking::{Client, RequestBuilder};
+use sha2::{Digest, Sha256};
+
+use super::snapshot::{verify_sealed, write_atomically, write_atomically_with};
+use crate::config::redact_url;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// The snapshots of the main group are hundreds of MB even compressed.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(3600);
+const CHECKSUM_FILE_EXTENSION: &str = "sha256";
+
+/// Downloads the cache snapshot published on the mirror to the snapshot path, once its SHA-256 checksum
+/// is verified. Without an expected checksum, it is read from the `<url>.sha256` file next to the snapshot.
+/// The token, if any, is sent as a bearer token, e.g. for the private object stores.
+pub fn download_cache_snapshot(
+    url: &str,
+    expected_sha256: Option<&str>,
+    token: Option<&str>,
+    path: &Path,
+    tmp_dir: &Path,
+) -> Result<u64> {
+    let client = create_client()?;
+
+    let expected_sha256 = match expected_sha256 {
+        Some(checksum) => checksum.trim().to_lowercase(),
+        None => fetch_checksum(
+            &client,
+            &format!("{}.{}", url, CHECKSUM_FILE_EXTENSION),
+            token,
+        )?,
+    };
+
+    info!("Downloading the cache snapshot from {}...", redact_url(url));
+    let mut response = authorize(client.get(url), token)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| anyhow!("Failed to download the snapshot {}: {}", redact_url(url), e))?;
+
+    let mut size = 0;
+    write_atomically_with(path, tmp_dir, |file| {
+        size = copy_verified(&mut response, file, &expected_sha256)?;
+        Ok(())
+    })?;
+    info!(
+        "Downloaded the {} bytes cache snapshot to {}",
+        size,
+        path.display()
+    );
+    Ok(size)
+}
+
+/// Uploads the cache snapshot to the object store URL with HTTP PUT, followed by its `<url>.sha256` checksum
+/// file for `download_cache_snapshot` to verify it. A snapshot downloaded in between the two fails the
+/// verification, so that the cache is loaded from the RPC rather than from a torn snapshot.
+pub fn upload_cache_snapshot(url: &str, token: Option<&str>, path: &Path) -> Result<u64> {
+    let client = create_client()?;
+    let open = || {
+        File::open(path).with_context(|| format!("Failed to open the snapshot {}", path.display()))
+    };
+    let (size, checksum) = hash(&mut open()?)?;
+
+    authorize(client.put(url), token)
+        .body(open()?)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| anyhow!("Failed to upload the snapshot {}: {}", redact_url(url), e))?;
+
+    let checksum_url = format!("{}.{}", url, CHECKSUM_FILE_EXTENSION);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    authorize(client.put(&checksum_url), token)
+        .body(format!("{}  {}\n", checksum, file_name))
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| {
+            anyhow!(
+                "Failed to upload the checksum {}: {}",
+                redact_url(&checksum_url),
+                e
+            )
+        })?;
+
+    info!(
+        "Uploaded the {} bytes cache snapshot to {}",
+        size,
+        redact_url(url)
+    );
+    Ok(size)
+}
+
+/// Fetches a fresh snapshot of the cache of a running peer instance from its admin API, e.g. for the
+/// blue/green restarts, and writes it to the snapshot path once its checksum is verified.
+pub fn fetch_peer_cache_snapshot(url: &str, path: &Path, tmp_dir: &Path) -> Result<u64> {
+    info!(
+        "Fetching the cache snapshot from the peer {}...",
+        redact_url(url)
+    );
+    let mut response = create_client()?
+        .get(url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| {
+            anyhow!(
+                "Failed to fetch the peer snapshot {}: {}",
+                redact_url(url),
+                e
+            )
+        })?;
+    let mut bytes = vec![];
+    response
+        .read_to_end(&mut bytes)
+        .context("Failed to read the peer snapshot")?;
+    verify_sealed(&bytes)?;
+
+    write_atomically(path, tmp_dir, &bytes)?;
+    info!(
+        "Fetched the {} bytes cache snapshot of the peer to {}",
+        bytes.len(),
+        path.display()
+    );
+    Ok(bytes.len() as u64)
+}
+
+fn create_client() -> Result<Client> {
+    Ok(Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(TRANSFER_TIMEOUT)
+        .build()?)
+}
+
+fn authorize(request: RequestBuilder, token: Option<&str>) -> RequestBuilder {
+    match token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+fn fetch_checksum(client: &Client, url: &str, token: Option<&str>) -> Result<String> {
+    let body = authorize(client.get(url), token)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(|e| anyhow!("Failed to fetch the checksum {}: {}", redact_url(url), e))?;
+    parse_checksum(&body)
+}
+
+// The checksum files are in the `sha256sum` format: the hex digest, optionally followed by the file name.
+fn parse_checksum(body: &str) -> Result<String> {
+    let checksum = body.split_whitespace().next().unwrap_or_default();
+    if checksum.len() != 64 || hex::decode(checksum).is_err() {
+        return Err(anyhow!("Invalid SHA-256 checksum {:?}", checksum));
+    }
+    Ok(checksum.to_lowercase())
+}
+
+/// Copies the content while hashing it, failing if its checksum is not the expected one.
+fn copy_verified(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    expected_sha256: &str,
+) -> Result<u64> {
+    let (size, checksum) = hash_with(reader, |chunk| Ok(writer.write_all(chunk)?))?;
+    if checksum != expected_sha256 {
+        return Err(anyhow!(
+            "The snapshot checksum {} does not match the expected {}",
+            checksum,
+            expected_sha256
+        ));
+    }
+    Ok(size)
+}
+
+/// The size and the hex SHA-256 checksum of the content.
+fn hash(reader: &mut impl Read) -> Result<(u64, String)> {
+    hash_with(reader, |_| Ok(()))
+}
+
+fn hash_with(
+    reader: &mut impl Read,
+    mut visit: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    let mut size = 0;
+    loop {
+        let read = reader
+            .read(&mut buffer)
+            .context("Failed to read the snapshot")?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        visit(&buffer[..read])?;
+        size += read as u64;
+    }
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use tiny_http::{Response, Server};
+
+    use super::*;
+    use crate::cache::snapshot::test_util::create_test_dir;
+
+    // The SHA-256 of "test".
+    const TEST_SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    // Serves the snapshot and its checksum file for the given number of requests.
+    fn serve_snapshot(requests: usize) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/cache.bin", server.server_addr());
+        thread::spawn(move || {
+            for request in server.incoming_requests().take(requests) {
+                let body = match request.url() {
+                    "/cache.bin" => "test".to_string(),
+                    _ => format!("{}  cache.bin\n", TEST_SHA256),
+                };
+                request.respond(Response::from_string(body)).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_download_cache_snapshot() {
+        let dir = create_test_dir("mirror_download");
+        let path = dir.join("cache.bin");
+
+        let size = download_cache_snapshot(&serve_snapshot(2), None, None, &path, &dir).unwrap();
+
+        assert_eq!(size, 4);
+        assert_eq!(fs::read(&path).unwrap(), b"test");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_download_cache_snapshot_checksum_mismatch() {
+        let dir = create_test_dir("mirror_mismatch");
+        let path = dir.join("cache.bin");
+
+        let result =
+            download_cache_snapshot(&serve_snapshot(1), Some(&"0".repeat(64)), None, &path, &dir);
+
+        assert!(format!("{:#}", result.unwrap_err()).contains("does not match"));
+        assert!(!path.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upload_cache_snapshot() {
+        let dir = create_test_dir("mirror_upload");
+        let path = dir.join("cache.bin");
+        fs::write(&path, b"test").unwrap();
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/cache.bin", server.server_addr());
+        // The method, the path, the authorization and the body of the requests.
+        let uploads = thread::spawn(move || {
+            server
+                .incoming_requests()
+                .take(2)
+                .map(|mut request| {
+                    let mut body = String::new();
+                    request.as_reader().read_to_string(&mut body).unwrap();
+                    let authorization = request
+                        .headers()
+                        .iter()
+                        .find(|header| header.field.equiv("Authorization"))
+                        .map(|header| header.value.to_string());
+                    let upload = (
+                        request.method().to_string(),
+                        request.url().to_string(),
+                        authorization,
+                        body,
+                    );
+                    request.respond(Response::empty(200)).unwrap();
+                    upload
+                })
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(
+            upload_cache_snapshot(&url, Some("secret"), &path).unwrap(),
+            4
+        );
+
+        let uploads = uploads.join().unwrap();
+        let bearer = Some("Bearer secret".to_string());
+        assert_eq!(
+            uploads[0],
+            (
+                "PUT".to_string(),
+                "/cache.bin".to_string(),
+                bearer.clone(),
+                "test".to_string()
+            )
+        );
+        assert_eq!(
+            uploads[1],
+            (
+                "PUT".to_string(),
+                "/cache.bin.sha256".to_string(),
+                bearer,
+                format!("{}  cache.bin\n", TEST_SHA256)
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_checksum() {
+        assert_eq!(
+            parse_checksum(&format!("{}  cache.bin\n", TEST_SHA256.to_uppercase())).unwrap(),
+            TEST_SHA256
+        );
+        assert!(parse_checksum("").is_err());
+        assert!(parse_checksum("not a checksum").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use anyhow::{anyhow, Result};
+use log::trace;
+use solana_sdk::{account::Account, pubkey::Pubkey};
+
+use crate::liquidation::wallet::{index_by_mint, WalletTokenAccount};
+
+/// The token accounts of the liquidator wallet, loaded at startup and then updated by Geyser, so that
+/// the liquidation knows at once whether the wallet can repay a liability. A token account can only be
+/// closed once empty, so the closed accounts Geyser no longer streams are left with no balance.
+#[derive(Default)]
+pub struct WalletTokenAccountsCache {
+    token_accounts: RwLock<HashMap<Pubkey, (u64, WalletTokenAccount)>>,
+}
+
+impl WalletTokenAccountsCache {
+    /// Replaces the cached token accounts, the other accounts of the wallet being skipped.
+    pub fn load(&self, slot: u64, token_accounts: &[(Pubkey, Account)]) -> Result<usize> {
+        let loaded: HashMap<Pubkey, (u64, WalletTokenAccount)> = token_accounts
+            .iter()
+            .filter_map(|(address, account)| {
+                WalletTokenAccount::from_account(*address, account)
+                    .map(|token_account| (*address, (slot, token_account)))
+            })
+            .collect();
+        let loaded_total = loaded.len();
+
+        *self.token_accounts.write().map_err(|e| {
+            anyhow!(
+                "Failed to lock the wallet token accounts cache for update: {}",
+                e
+            )
+        })? = loaded;
+        Ok(loaded_total)
+    }
+
+    /// Applies the updates in order, under a single acquisition of the cache lock, unless older than the
+    /// cached ones. The accounts which are no longer token accounts are removed.
+    pub fn update_batch<'a>(
+        &self,
+        updates: impl IntoIterator<Item = (u64, Pubkey, &'a Account)>,
+    ) -> Result<()> {
+        let mut token_accounts = self.token_accounts.write().map_err(|e| {
+            anyhow!(
+                "Failed to lock the wallet token accounts cache for update: {}",
+                e
+            )
+        })?;
+        for (slot, address, account) in updates {
+            apply_update(&mut token_accounts, slot, address, account);
+        }
+        Ok(())
+    }
+
+    /// The best funded token account of the wallet for every mint.
+    pub fn by_mint(&self) -> Result<HashMap<Pubkey, WalletTokenAccount>> {
+        let token_accounts = self.token_accounts.read().map_err(|e| {
+            anyhow!(
+                "Failed to lock the wallet token accounts cache for reading: {}",
+                e
+            )
+        })?;
+        Ok(index_by_mint(
+            token_accounts
+                .values()
+                .map(|(_, token_account)| token_account.clone()),
+        ))
+    }
+}
+
+fn apply_update(
+    token_accounts: &mut HashMap<Pubkey, (u64, WalletTokenAccount)>,
+    slot: u64,
+    address: Pubkey,
+    account: &Account,
+) {
+    if matches!(token_accounts.get(&address), Some((cached_slot, _)) if *cached_slot > slot) {
+        return;
+    }
+
+    match WalletTokenAccount::from_account(address, account) {
+        Some(token_account) => {
+            trace!(
+                "Updating the wallet token account {} in cache: {} of the mint {}",
+                address,
+                token_account.amount,
+                token_account.mint
+            );
+            token_accounts.insert(address, (slot, token_account));
+        }
+        None => {
+            token_accounts.remove(&address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liquidation::wallet::test_util::create_token_account;
+
+    #[test]
+    fn test_wallet_token_accounts_cache() {
+        let cache = WalletTokenAccountsCache::default();
+        let wallet = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let ata = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let mut not_token_account = create_token_account(mint, wallet, 1_000);
+        not_token_account.owner = Pubkey::new_unique();
+
+        assert_eq!(
+            cache
+                .load(
+                    10,
+                    &[
+                        (ata, create_token_account(mint, wallet, 100)),
+                        (Pubkey::new_unique(), not_token_account),
+                    ],
+                )
+                .unwrap(),
+            1
+        );
+        assert_eq!(cache.by_mint().unwrap()[&mint].amount, 100);
+
+        // The older updates are ignored.
+        cache
+            .update_batch([(9, ata, &create_token_account(mint, wallet, 0))])
+            .unwrap();
+        assert_eq!(cache.by_mint().unwrap()[&mint].amount, 100);
+
+        // The best funded account of the mint is used.
+        cache
+            .update_batch([(11, other, &create_token_account(mint, wallet, 500))])
+            .unwrap();
+        assert_eq!(cache.by_mint().unwrap()[&mint].address, other);
+        cache
+            .update_batch([(12, other, &create_token_account(mint, wallet, 50))])
+            .unwrap();
+        assert_eq!(cache.by_mint().unwrap()[&mint].address, ata);
+
+        // The closed account is removed.
+        let mut closed = create_token_account(mint, wallet, 0);
+        closed.data.clear();
+        cache.update_batch([(13, ata, &closed)]).unwrap();
+        assert_eq!(cache.by_mint().unwrap()[&mint].address, other);
+
+        // The batched updates are applied in order.
+        cache
+            .update_batch([
+                (14, ata, &create_token_account(mint, wallet, 900)),
+                (15, ata, &create_token_account(mint, wallet, 20)),
+            ])
+            .unwrap();
+        assert_eq!(cache.by_mint().unwrap()[&mint].address, other);
+    }
+}
@@ -0,0 +1,49 @@
+use dashmap::{mapref::entry::Entry, DashMap};
+use solana_sdk::pubkey::Pubkey;
+
+/// The version of the last Geyser update applied to every cache entry: its slot, then its write version
+/// within the slot. The reconnect replays and the multiple sources stream the same updates again or out
+/// of order, so the updates not newer than the applied one are dropped rather than regressing the entry.
+#[derive(Default)]
+pub struct UpdateVersions {
+    versions: DashMap<Pubkey, (u64, u64)>,
+}
+
+impl UpdateVersions {
+    /// Records the version of the update, unless it is not newer than the applied one. Returns whether
+    /// the update is to be applied.
+    pub fn advance(&self, address: Pubkey, slot: u64, write_version: u64) -> bool {
+        match self.versions.entry(address) {
+            Entry::Occupied(mut applied) => {
+                if *applied.get() >= (slot, write_version) {
+                    return false;
+                }
+                applied.insert((slot, write_version));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((slot, write_version));
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_versions() {
+        let versions = UpdateVersions::default();
+        let address = Pubkey::new_unique();
+        assert!(versions.advance(address, 10, 5));
+        // The replayed and the older updates are dropped.
+        assert!(!versions.advance(address, 10, 5));
+        assert!(!versions.advance(address, 10, 4));
+        assert!(!versions.advance(address, 9, 100));
+        // The later writes of the slot, and the later slots whatever their write version.
+        assert!(versions.advance(address, 10, 6));
+        assert!(versions.advance(address, 11, 0));
+        assert!(versions.advance(Pubkey::new_unique(), 1, 0));
+    }
+}
@@ -1,15 +1,33 @@
+use solana_sdk::{pubkey, pubkey::Pubkey};
+
 pub const MARGINFI_ACCOUNT_DISCRIMINATOR: [u8; 8] = [67, 178, 130, 109, 126, 114, 28, 42];
 pub const MARGINFI_ACCOUNT_DISCRIMINATOR_LEN: usize = MARGINFI_ACCOUNT_DISCRIMINATOR.len();
 pub const MARGINFI_BANK_DISCRIMINATOR: [u8; 8] = [142, 49, 166, 242, 50, 66, 97, 188];
 pub const MARGINFI_BANK_DISCRIMINATOR_LEN: usize = MARGINFI_BANK_DISCRIMINATOR.len();
+pub const MARGINFI_LIQUIDATE_DISCRIMINATOR: [u8; 8] = [214, 169, 151, 213, 251, 167, 86, 219];
+pub const MARGINFI_DEPOSIT_DISCRIMINATOR: [u8; 8] = [171, 94, 235, 103, 82, 64, 212, 140];
+pub const MARGINFI_WITHDRAW_DISCRIMINATOR: [u8; 8] = [36, 72, 74, 19, 210, 210, 192, 192];
+pub const MARGINFI_REPAY_DISCRIMINATOR: [u8; 8] = [79, 209, 172, 177, 222, 51, 173, 151];
+pub const MARGINFI_START_FLASHLOAN_DISCRIMINATOR: [u8; 8] = [14, 131, 33, 220, 81, 186, 180, 107];
+pub const MARGINFI_END_FLASHLOAN_DISCRIMINATOR: [u8; 8] = [105, 124, 201, 106, 153, 2, 8, 156];
+pub const SPL_TOKEN_PROGRAM_ID: Pubkey = pubkey!("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+pub const SPL_TOKEN_2022_PROGRAM_ID: Pubkey =
+    pubkey!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PjnqkiXzWLkiNnj");
+pub const ASSOCIATED_TOKEN_PROGRAM_ID: Pubkey =
+    pubkey!("ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL");
 
 // TODO: Is there better home for Geysermessage and GeyserMessageType?
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageType {
     Clock,
+    // A Geyser slot notification, with no account.
+    Slot,
     MarginfiAccount,
     Bank,
     Oracle,
+    TokenAccount,
+    // A Marginfi account closed, or reassigned to another program.
+    ClosedAccount,
 }
 
 pub fn get_marginfi_message_type(account_data: &[u8]) -> Option<MessageType> {
@@ -1,11 +1,37 @@
+pub mod circuit_breaker;
+pub mod file_comms_client;
+pub mod jito_client;
+pub mod leader_schedule;
 pub mod rpc_comms_client;
+pub mod signature_subscriber;
 
+pub use file_comms_client::FileCommsClient;
+pub use jito_client::JitoClient;
 pub use rpc_comms_client::RpcCommsClient;
+pub use signature_subscriber::{PendingSignatures, SignatureStatus, SignatureSubscriber};
 
-use anyhow::Result;
-use solana_sdk::{account::Account, pubkey::Pubkey};
+use std::mem::{offset_of, size_of};
 
-use crate::config::Config;
+use anyhow::{anyhow, Result};
+use fixed::types::I80F48;
+use log::info;
+use marginfi::state::{
+    health_cache::HealthCache, marginfi_account::MarginfiAccount, marginfi_group::WrappedI80F48,
+};
+use solana_sdk::{
+    account::Account,
+    address_lookup_table::{state::AddressLookupTable, AddressLookupTableAccount},
+    epoch_info::EpochInfo,
+    hash::Hash,
+    pubkey::Pubkey,
+    signature::Signature,
+    transaction::VersionedTransaction,
+};
+
+use crate::{
+    common::MARGINFI_ACCOUNT_DISCRIMINATOR_LEN, comms::leader_schedule::LeaderSchedule,
+    config::Config,
+};
 
 // TODO: consider renaming this trait to something more descriptive. Fetcher for example.
 pub trait CommsClient: Send + Sync {
@@ -15,9 +41,185 @@ pub trait CommsClient: Send + Sync {
 
     fn get_account(&self, address: &Pubkey) -> Result<Account>;
 
-    fn get_program_accounts(&self, program_id: &Pubkey) -> Result<Vec<(Pubkey, Account)>>;
+    /// Returns the program accounts as of a slot not older than `min_context_slot`, failing otherwise.
+    fn get_program_accounts(
+        &self,
+        program_id: &Pubkey,
+        min_context_slot: u64,
+    ) -> Result<ProgramAccounts>;
+
+    /// Returns the program accounts like `get_program_accounts`, but only the headers of the Marginfi accounts,
+    /// which are then hydrated in the background. The clients which cannot fetch the headers return all the
+    /// accounts in full, with no headers.
+    fn get_program_account_headers(
+        &self,
+        program_id: &Pubkey,
+        min_context_slot: u64,
+    ) -> Result<(ProgramAccounts, Vec<MarginfiAccountHeader>)> {
+        Ok((
+            self.get_program_accounts(program_id, min_context_slot)?,
+            vec![],
+        ))
+    }
 
     fn get_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<(Pubkey, Account)>>;
+
+    /// Returns the accounts as of the given past slot, for the post-mortems and the backtests, when an archival
+    /// endpoint is configured. The missing accounts, e.g. not yet created at the slot, are omitted.
+    fn get_accounts_at_slot(
+        &self,
+        _addresses: &[Pubkey],
+        slot: u64,
+    ) -> Result<Vec<(Pubkey, Account)>> {
+        Err(anyhow!(
+            "The accounts as of the slot {} cannot be read without an archival endpoint",
+            slot
+        ))
+    }
+
+    /// Returns the block time of the latest transaction of the address, None when unknown.
+    fn get_latest_transaction_time(&self, _address: &Pubkey) -> Result<Option<i64>> {
+        Ok(None)
+    }
+
+    /// Returns the SPL Token and Token-2022 accounts owned by the given wallet.
+    fn get_token_accounts_by_owner(&self, owner: &Pubkey) -> Result<Vec<(Pubkey, Account)>>;
+
+    fn simulate_transaction(
+        &self,
+        transaction: &VersionedTransaction,
+    ) -> Result<TransactionSimulation>;
+
+    fn get_epoch_info(&self) -> Result<EpochInfo>;
+
+    /// Returns the latest blockhash to sign the liquidation transactions with.
+    fn get_latest_blockhash(&self) -> Result<Hash>;
+
+    /// Returns the leader schedule of the epoch.
+    fn get_leader_schedule(&self, epoch_info: &EpochInfo) -> Result<LeaderSchedule>;
+
+    fn get_address_lookup_tables(
+        &self,
+        addresses: &[Pubkey],
+    ) -> Result<Vec<AddressLookupTableAccount>> {
+        self.get_accounts(addresses)?
+            .into_iter()
+            .map(|(address, account)| {
+                let lut = AddressLookupTable::deserialize(&account.data)
+                    .map_err(|e| anyhow!("Failed to deserialize the {} LUT : {:?}", address, e))?;
+                Ok(AddressLookupTableAccount {
+                    key: address,
+                    addresses: lut.addresses.to_vec(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The program accounts together with the slot they were read at.
+#[derive(Debug)]
+pub struct ProgramAccounts {
+    pub context_slot: u64,
+    pub accounts: Vec<(Pubkey, Account)>,
+}
+
+impl ProgramAccounts {
+    // The context slot of the merged results is the oldest one, so an empty accumulator starts from the newest possible.
+    pub fn empty() -> Self {
+        Self {
+            context_slot: u64::MAX,
+            accounts: Vec::new(),
+        }
+    }
+
+    pub fn merge(&mut self, mut other: ProgramAccounts) {
+        self.context_slot = self.context_slot.min(other.context_slot);
+        self.accounts.append(&mut other.accounts);
+    }
+}
+
+// The header of the Marginfi accounts is the maintenance values of their health cache, fetched with a
+// dataSlice of the account data.
+const HEALTH_CACHE_OFFSET: usize =
+    MARGINFI_ACCOUNT_DISCRIMINATOR_LEN + offset_of!(MarginfiAccount, health_cache);
+const LIABILITY_VALUE_MAINT_OFFSET: usize =
+    offset_of!(HealthCache, liability_value_maint) - offset_of!(HealthCache, asset_value_maint);
+pub const MARGINFI_ACCOUNT_HEADER_OFFSET: usize =
+    HEALTH_CACHE_OFFSET + offset_of!(HealthCache, asset_value_maint);
+pub const MARGINFI_ACCOUNT_HEADER_LEN: usize =
+    LIABILITY_VALUE_MAINT_OFFSET + size_of::<WrappedI80F48>();
+
+/// The health of a Marginfi account as of its last health cache update, to estimate its risk before the
+/// whole account is loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarginfiAccountHeader {
+    pub address: Pubkey,
+    pub asset_value_maint: I80F48,
+    pub liability_value_maint: I80F48,
+}
+
+impl MarginfiAccountHeader {
+    pub fn from_slice(address: Pubkey, data: &[u8]) -> Result<Self> {
+        let read_value = |offset: usize| {
+            data.get(offset..offset + size_of::<WrappedI80F48>())
+                .and_then(|bytes| bytemuck::try_pod_read_unaligned::<WrappedI80F48>(bytes).ok())
+                .map(I80F48::from)
+                .ok_or_else(|| anyhow!("Invalid header of the Marginfi account {}", address))
+        };
+        Ok(Self {
+            address,
+            asset_value_maint: read_value(0)?,
+            liability_value_maint: read_value(LIABILITY_VALUE_MAINT_OFFSET)?,
+        })
+    }
+
+    /// The share of the maintenance asset value left after the liabilities, the lower the riskier. The
+    /// accounts without assets are the riskiest if they have liabilities, and riskless otherwise.
+    pub fn headroom(&self) -> f64 {
+        let assets = self.asset_value_maint.to_num::<f64>();
+        let liabilities = self.liability_value_maint.to_num::<f64>();
+        if assets > 0.0 {
+            (assets - liabilities) / assets
+        } else if liabilities > 0.0 {
+            f64::NEG_INFINITY
+        } else {
+            f64::INFINITY
+        }
+    }
+}
+
+/// The result of simulating a transaction against the latest state of the cluster.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionSimulation {
+    pub error: Option<String>,
+    pub units_consumed: Option<u64>,
+}
+
+/// Submits the signed liquidation transactions to the cluster, tipping the lamports when sent as a Jito bundle.
+pub trait TransactionSender: Send + Sync {
+    fn send_transactions(
+        &self,
+        transactions: &[VersionedTransaction],
+        tip_lamports: u64,
+    ) -> Result<Vec<Signature>>;
+}
+
+/// Chooses the Jito bundle submission when a block engine is configured, the RPC otherwise.
+/// Nothing is sent when running offline from the account fixtures.
+pub fn create_transaction_sender(config: &Config) -> Result<Box<dyn TransactionSender>> {
+    if config.comms_fixtures_dir.is_some() {
+        return Ok(Box::new(FileCommsClient::new(config)?));
+    }
+    match &config.jito_block_engine_url {
+        Some(block_engine_url) => {
+            info!(
+                "Sending the transactions as Jito bundles via {}",
+                block_engine_url
+            );
+            Ok(Box::new(JitoClient::new(config, block_engine_url)?))
+        }
+        None => Ok(Box::new(RpcCommsClient::new(config)?)),
+    }
 }
 
 #[cfg(test)]
@@ -26,6 +228,7 @@ pub mod test_util {
     use std::collections::HashMap;
 
     use super::*;
+    use crate::common::{SPL_TOKEN_2022_PROGRAM_ID, SPL_TOKEN_PROGRAM_ID};
 
     pub struct MockedCommsClient {
         accounts: HashMap<Pubkey, Account>,
@@ -51,13 +254,20 @@ pub mod test_util {
                 .ok_or_else(|| anyhow!("Account not found"))
         }
 
-        fn get_program_accounts(&self, program_id: &Pubkey) -> Result<Vec<(Pubkey, Account)>> {
-            Ok(self
-                .accounts
-                .iter()
-                .filter(|(&pubkey, _)| pubkey == *program_id)
-                .map(|(pubkey, account)| (pubkey.clone(), account.clone()))
-                .collect())
+        fn get_program_accounts(
+            &self,
+            program_id: &Pubkey,
+            min_context_slot: u64,
+        ) -> Result<ProgramAccounts> {
+            Ok(ProgramAccounts {
+                context_slot: min_context_slot,
+                accounts: self
+                    .accounts
+                    .iter()
+                    .filter(|(&pubkey, _)| pubkey == *program_id)
+                    .map(|(pubkey, account)| (pubkey.clone(), account.clone()))
+                    .collect(),
+            })
         }
 
         fn get_accounts(&self, pubkeys: &[Pubkey]) -> Result<Vec<(Pubkey, Account)>> {
@@ -69,5 +279,97 @@ pub mod test_util {
             }
             Ok(accounts)
         }
+
+        // The mocked accounts do not change over the slots.
+        fn get_accounts_at_slot(
+            &self,
+            pubkeys: &[Pubkey],
+            _slot: u64,
+        ) -> Result<Vec<(Pubkey, Account)>> {
+            self.get_accounts(pubkeys)
+        }
+
+        fn get_token_accounts_by_owner(&self, owner: &Pubkey) -> Result<Vec<(Pubkey, Account)>> {
+            Ok(self
+                .accounts
+                .iter()
+                .filter(|(_, account)| {
+                    (account.owner == SPL_TOKEN_PROGRAM_ID
+                        || account.owner == SPL_TOKEN_2022_PROGRAM_ID)
+                        && account.data.len() >= 64
+                        && account.data[32..64] == owner.to_bytes()
+                })
+                .map(|(pubkey, account)| (*pubkey, account.clone()))
+                .collect())
+        }
+
+        // Fails like the cluster does when the fee payer does not exist.
+        fn simulate_transaction(
+            &self,
+            transaction: &VersionedTransaction,
+        ) -> Result<TransactionSimulation> {
+            let payer = transaction.message.static_account_keys()[0];
+            if self.accounts.contains_key(&payer) {
+                Ok(TransactionSimulation {
+                    error: None,
+                    units_consumed: Some(transaction.message.instructions().len() as u64 * 1_000),
+                })
+            } else {
+                Ok(TransactionSimulation {
+                    error: Some("AccountNotFound".to_string()),
+                    units_consumed: Some(0),
+                })
+            }
+        }
+
+        fn get_epoch_info(&self) -> Result<EpochInfo> {
+            Ok(EpochInfo {
+                epoch: 0,
+                slot_index: 0,
+                slots_in_epoch: 32,
+                absolute_slot: 0,
+                block_height: 0,
+                transaction_count: None,
+            })
+        }
+
+        fn get_latest_blockhash(&self) -> Result<Hash> {
+            Ok(Hash::default())
+        }
+
+        fn get_leader_schedule(&self, epoch_info: &EpochInfo) -> Result<LeaderSchedule> {
+            LeaderSchedule::new(epoch_info, &HashMap::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::marginfi_accounts::{
+        test_util::create_marginfi_account, CachedMarginfiAccount,
+    };
+
+    #[test]
+    fn test_marginfi_account_header_from_slice() {
+        let address = Pubkey::new_unique();
+        let mut marginfi_account = create_marginfi_account(Pubkey::new_unique(), vec![]);
+        marginfi_account.health_cache.asset_value_maint = I80F48::from_num(200).into();
+        marginfi_account.health_cache.liability_value_maint = I80F48::from_num(150).into();
+        let data = CachedMarginfiAccount::from(0, address, marginfi_account)
+            .to_snapshot_account()
+            .data;
+
+        let header = MarginfiAccountHeader::from_slice(
+            address,
+            &data[MARGINFI_ACCOUNT_HEADER_OFFSET
+                ..MARGINFI_ACCOUNT_HEADER_OFFSET + MARGINFI_ACCOUNT_HEADER_LEN],
+        )
+        .unwrap();
+        assert_eq!(header.asset_value_maint, I80F48::from_num(200));
+        assert_eq!(header.liability_value_maint, I80F48::from_num(150));
+        assert_eq!(header.headroom(), 0.25);
+
+        assert!(MarginfiAccountHeader::from_slice(address, &data[..4]).is_err());
     }
 }
@@ -0,0 +1,253 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind, Result as ClientResult},
+    rpc_request::RpcError,
+};
+
+use crate::config::{redact_url, Config};
+
+// The circuit breakers by namespace and endpoint.
+type CircuitBreakers = HashMap<(String, String), Arc<CircuitBreaker>>;
+
+static CIRCUIT_BREAKERS: OnceLock<Mutex<CircuitBreakers>> = OnceLock::new();
+
+/// Returns the circuit breaker of the endpoint, shared by all the clients of that endpoint in the namespace
+/// of the configuration, with its thresholds.
+pub fn circuit_breaker_for(config: &Config, endpoint: &str) -> Arc<CircuitBreaker> {
+    let breakers = CIRCUIT_BREAKERS.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut breakers = breakers
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    breakers
+        .entry((config.namespace().name.clone(), endpoint.to_string()))
+        .or_insert_with(|| {
+            Arc::new(CircuitBreaker::new(
+                redact_url(endpoint),
+                config.rpc_circuit_failure_threshold,
+                Duration::from_secs(config.rpc_circuit_cooldown_sec),
+            ))
+        })
+        .clone()
+}
+
+/// The state of the endpoint circuit breakers of the namespace, for its stats.
+pub fn circuit_breaker_states(namespace: &str) -> Vec<String> {
+    let Some(breakers) = CIRCUIT_BREAKERS.get() else {
+        return Vec::new();
+    };
+    let breakers = breakers
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut states: Vec<String> = breakers
+        .iter()
+        .filter(|((breaker_namespace, _), _)| breaker_namespace == namespace)
+        .map(|(_, breaker)| breaker.to_string())
+        .collect();
+    states.sort();
+    states
+}
+
+#[derive(Default)]
+struct CircuitState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    // Whether the single call let through once the cool-down expired is still running.
+    trial_in_flight: bool,
+}
+
+/// Stops calling an endpoint for a cool-down period after a number of consecutive failures,
+/// rather than hammering a dead node. Once the cool-down expires a single call is let through,
+/// the others being rejected until it completes, and the circuit is opened again right away if it fails.
+pub struct CircuitBreaker {
+    endpoint: String,
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<CircuitState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(endpoint: String, failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            endpoint,
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            state: Mutex::new(CircuitState::default()),
+        }
+    }
+
+    /// Calls the endpoint unless the circuit is open, recording the outcome.
+    pub fn call<R>(&self, call: impl FnOnce() -> ClientResult<R>) -> Result<R> {
+        self.check(Instant::now())?;
+        let result = call();
+        match &result {
+            Err(err) if is_endpoint_failure(err) => self.record_failure(Instant::now()),
+            _ => self.record_success(),
+        }
+        result.map_err(|e| anyhow!(e))
+    }
+
+    fn check(&self, now: Instant) -> Result<()> {
+        let mut state = self.lock_state();
+        match state.open_until {
+            Some(open_until) if now < open_until => Err(anyhow!(
+                "The circuit of the endpoint {} is open for another {:?}",
+                self.endpoint,
+                open_until - now
+            )),
+            Some(_) if state.trial_in_flight => Err(anyhow!(
+                "The circuit of the endpoint {} is half-open, a trial call is in flight",
+                self.endpoint
+            )),
+            Some(_) => {
+                // Half-open: let this call through, a failure reopens the circuit.
+                state.trial_in_flight = true;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.lock_state();
+        if state.consecutive_failures >= self.failure_threshold {
+            info!("The circuit of the endpoint {} is closed.", self.endpoint);
+        }
+        state.consecutive_failures = 0;
+        state.open_until = None;
+        state.trial_in_flight = false;
+    }
+
+    fn record_failure(&self, now: Instant) {
+        let mut state = self.lock_state();
+        state.consecutive_failures += 1;
+        state.trial_in_flight = false;
+        if state.consecutive_failures >= self.failure_threshold {
+            warn!(
+                "Opening the circuit of the endpoint {} for {:?} after {} consecutive failures.",
+                self.endpoint, self.cooldown, state.consecutive_failures
+            );
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, CircuitState> {
+        self.state.lock().unwrap_or_else(|poisoned| {
+            error!("The circuit breaker state of {} is poisoned", self.endpoint);
+            poisoned.into_inner()
+        })
+    }
+}
+
+impl fmt::Display for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock_state();
+        match state.open_until {
+            Some(open_until) if open_until <= Instant::now() => {
+                write!(f, "{}: half-open", self.endpoint)
+            }
+            Some(open_until) => write!(
+                f,
+                "{}: open for {:?}",
+                self.endpoint,
+                open_until.saturating_duration_since(Instant::now())
+            ),
+            None => write!(
+                f,
+                "{}: closed ({} consecutive failures)",
+                self.endpoint, state.consecutive_failures
+            ),
+        }
+    }
+}
+
+// Only the failures of the node count, not the errors of the requests themselves.
+fn is_endpoint_failure(err: &ClientError) -> bool {
+    match err.kind() {
+        ClientErrorKind::Io(_) | ClientErrorKind::Reqwest(_) | ClientErrorKind::Middleware(_) => {
+            true
+        }
+        ClientErrorKind::RpcError(RpcError::RpcRequestError(_)) => true,
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. }) => {
+            // JSON-RPC internal errors and the node being behind or unhealthy.
+            *code == -32603 || *code == -32005
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint_failure() -> ClientResult<()> {
+        Err(ClientError::from(ClientErrorKind::RpcError(
+            RpcError::RpcRequestError("connection refused".to_string()),
+        )))
+    }
+
+    fn request_failure() -> ClientResult<()> {
+        Err(ClientError::from(ClientErrorKind::Custom(
+            "AccountNotFound".to_string(),
+        )))
+    }
+
+    #[test]
+    fn test_circuit_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new("http://rpc".to_string(), 2, Duration::from_secs(60));
+
+        assert!(breaker.call(endpoint_failure).is_err());
+        assert!(breaker.call(|| Ok(1)).is_ok());
+        assert!(breaker.call(endpoint_failure).is_err());
+        assert!(breaker.lock_state().open_until.is_none());
+        assert!(breaker.call(endpoint_failure).is_err());
+        assert!(breaker.lock_state().open_until.is_some());
+
+        let mut called = false;
+        let result = breaker.call(|| {
+            called = true;
+            Ok(())
+        });
+        assert!(!called);
+        assert!(result.unwrap_err().to_string().contains("is open"));
+        assert!(breaker.to_string().starts_with("http://rpc: open"));
+    }
+
+    #[test]
+    fn test_request_failures_do_not_open_the_circuit() {
+        let breaker = CircuitBreaker::new("http://rpc".to_string(), 1, Duration::from_secs(60));
+
+        assert!(breaker.call(request_failure).is_err());
+        assert!(breaker.lock_state().open_until.is_none());
+        assert!(breaker.call(|| Ok(())).is_ok());
+    }
+
+    #[test]
+    fn test_circuit_half_open_after_cooldown() {
+        let breaker = CircuitBreaker::new("http://rpc".to_string(), 1, Duration::from_secs(10));
+        let now = Instant::now();
+
+        breaker.record_failure(now);
+        assert!(breaker.check(now + Duration::from_secs(5)).is_err());
+        assert!(breaker.check(now + Duration::from_secs(10)).is_ok());
+        // A single trial call is let through.
+        assert!(breaker.check(now + Duration::from_secs(10)).is_err());
+
+        // A failure of the trial call reopens the circuit right away.
+        breaker.record_failure(now + Duration::from_secs(10));
+        assert!(breaker.check(now + Duration::from_secs(11)).is_err());
+
+        assert!(breaker.check(now + Duration::from_secs(20)).is_ok());
+        breaker.record_success();
+        assert_eq!(breaker.lock_state().consecutive_failures, 0);
+        assert!(breaker.check(now + Duration::from_secs(20)).is_ok());
+        assert!(breaker.check(now + Duration::from_secs(20)).is_ok());
+    }
+}
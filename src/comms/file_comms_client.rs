@@ -0,0 +1,262 @@
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use solana_client::rpc_response::RpcKeyedAccount;
+use solana_sdk::{
+    account::Account,
+    clock::{Clock, DEFAULT_SLOTS_PER_EPOCH},
+    epoch_info::EpochInfo,
+    epoch_schedule::EpochSchedule,
+    hash::Hash,
+    pubkey::Pubkey,
+    signature::Signature,
+    sysvar,
+    transaction::VersionedTransaction,
+};
+
+use crate::{
+    common::{SPL_TOKEN_2022_PROGRAM_ID, SPL_TOKEN_PROGRAM_ID},
+    comms::{
+        leader_schedule::LeaderSchedule, CommsClient, ProgramAccounts, TransactionSender,
+        TransactionSimulation,
+    },
+    config::Config,
+};
+
+/// Serves the accounts from a directory of fixtures, for running the service without network access.
+/// The fixtures are either the `solana account <ADDRESS> --output json` JSON files or the
+/// `<ADDRESS>.bin` bincode serialized accounts. The Clock sysvar fixture is required.
+pub struct FileCommsClient {
+    accounts: HashMap<Pubkey, Account>,
+}
+
+impl FileCommsClient {
+    fn load_fixtures(dir: &Path) -> Result<HashMap<Pubkey, Account>> {
+        let mut accounts = HashMap::new();
+        for entry in fs::read_dir(dir)
+            .map_err(|e| anyhow!("Failed to read the fixtures dir {}: {}", dir.display(), e))?
+        {
+            let path = entry?.path();
+            let fixture = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("json") => load_json_fixture(&path),
+                Some("bin") => load_bincode_fixture(&path),
+                _ => {
+                    debug!("Skipping the non-fixture file {}", path.display());
+                    continue;
+                }
+            };
+            let (address, account) =
+                fixture.map_err(|e| anyhow!("Invalid fixture {}: {}", path.display(), e))?;
+            accounts.insert(address, account);
+        }
+        Ok(accounts)
+    }
+}
+
+impl CommsClient for FileCommsClient {
+    fn new(config: &Config) -> Result<Self> {
+        let dir = config
+            .comms_fixtures_dir
+            .as_ref()
+            .ok_or_else(|| anyhow!("COMMS_FIXTURES_DIR is not set"))?;
+        let accounts = Self::load_fixtures(Path::new(dir))?;
+        info!("Loaded {} account fixtures from {}", accounts.len(), dir);
+        Ok(Self { accounts })
+    }
+
+    fn get_account(&self, address: &Pubkey) -> Result<Account> {
+        self.accounts
+            .get(address)
+            .cloned()
+            .ok_or_else(|| anyhow!("No fixture for the account {}", address))
+    }
+
+    fn get_program_accounts(
+        &self,
+        program_id: &Pubkey,
+        min_context_slot: u64,
+    ) -> Result<ProgramAccounts> {
+        Ok(ProgramAccounts {
+            context_slot: min_context_slot,
+            accounts: self
+                .accounts
+                .iter()
+                .filter(|(_, account)| account.owner == *program_id)
+                .map(|(address, account)| (*address, account.clone()))
+                .collect(),
+        })
+    }
+
+    fn get_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<(Pubkey, Account)>> {
+        Ok(addresses
+            .iter()
+            .filter_map(|address| {
+                self.accounts
+                    .get(address)
+                    .map(|account| (*address, account.clone()))
+            })
+            .collect())
+    }
+
+    fn get_token_accounts_by_owner(&self, owner: &Pubkey) -> Result<Vec<(Pubkey, Account)>> {
+        Ok(self
+            .accounts
+            .iter()
+            .filter(|(_, account)| {
+                (account.owner == SPL_TOKEN_PROGRAM_ID
+                    || account.owner == SPL_TOKEN_2022_PROGRAM_ID)
+                    && account.data.len() >= 64
+                    && account.data[32..64] == owner.to_bytes()
+            })
+            .map(|(address, account)| (*address, account.clone()))
+            .collect())
+    }
+
+    // There is nothing to execute the transactions against, so they are reported as passed.
+    fn simulate_transaction(
+        &self,
+        transaction: &VersionedTransaction,
+    ) -> Result<TransactionSimulation> {
+        debug!(
+            "Offline: skipping the simulation of the transaction {}",
+            transaction.signatures[0]
+        );
+        Ok(TransactionSimulation {
+            error: None,
+            units_consumed: None,
+        })
+    }
+
+    // Mainnet epochs have a fixed length, without the warmup.
+    fn get_epoch_info(&self) -> Result<EpochInfo> {
+        let clock: Clock = bincode::deserialize(&self.get_account(&sysvar::clock::ID)?.data)?;
+        let (epoch, slot_index) =
+            EpochSchedule::without_warmup().get_epoch_and_slot_index(clock.slot);
+        Ok(EpochInfo {
+            epoch,
+            slot_index,
+            slots_in_epoch: DEFAULT_SLOTS_PER_EPOCH,
+            absolute_slot: clock.slot,
+            block_height: 0,
+            transaction_count: None,
+        })
+    }
+
+    // The transactions are neither simulated nor sent offline, any blockhash does.
+    fn get_latest_blockhash(&self) -> Result<Hash> {
+        Ok(Hash::default())
+    }
+
+    // The leaders are unknown offline.
+    fn get_leader_schedule(&self, epoch_info: &EpochInfo) -> Result<LeaderSchedule> {
+        LeaderSchedule::new(epoch_info, &HashMap::new())
+    }
+}
+
+impl TransactionSender for FileCommsClient {
+    fn send_transactions(
+        &self,
+        transactions: &[VersionedTransaction],
+        _tip_lamports: u64,
+    ) -> Result<Vec<Signature>> {
+        if !transactions.is_empty() {
+            warn!(
+                "Offline: dropping {} liquidation transactions",
+                transactions.len()
+            );
+        }
+        Ok(Vec::new())
+    }
+}
+
+fn load_json_fixture(path: &Path) -> Result<(Pubkey, Account)> {
+    let keyed_account: RpcKeyedAccount = serde_json::from_slice(&fs::read(path)?)?;
+    let address = Pubkey::from_str(&keyed_account.pubkey)
+        .map_err(|e| anyhow!("Invalid address {}: {}", keyed_account.pubkey, e))?;
+    let account = keyed_account
+        .account
+        .decode::<Account>()
+        .ok_or_else(|| anyhow!("Failed to decode the account {}", address))?;
+    Ok((address, account))
+}
+
+fn load_bincode_fixture(path: &Path) -> Result<(Pubkey, Account)> {
+    let address = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| anyhow!("Missing the account address in the file name"))?;
+    let address =
+        Pubkey::from_str(address).map_err(|e| anyhow!("Invalid address {}: {}", address, e))?;
+    Ok((address, bincode::deserialize(&fs::read(path)?)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_account_decoder::{UiAccount, UiAccountEncoding};
+
+    use super::*;
+    use crate::cache::snapshot::test_util::create_test_dir;
+
+    fn write_json_fixture(dir: &Path, address: &Pubkey, account: &Account) {
+        let keyed_account = RpcKeyedAccount {
+            pubkey: address.to_string(),
+            account: UiAccount::encode(address, account, UiAccountEncoding::Base64, None, None),
+        };
+        fs::write(
+            dir.join(format!("{}.json", address)),
+            serde_json::to_vec(&keyed_account).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_load_fixtures() {
+        let dir = create_test_dir("fixtures_load");
+        let program_id = Pubkey::new_unique();
+        let json_address = Pubkey::new_unique();
+        let json_account = Account {
+            lamports: 10,
+            data: vec![1, 2, 3],
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        };
+        write_json_fixture(&dir, &json_address, &json_account);
+        let bin_address = Pubkey::new_unique();
+        let bin_account = Account::new(20, 4, &Pubkey::new_unique());
+        fs::write(
+            dir.join(format!("{}.bin", bin_address)),
+            bincode::serialize(&bin_account).unwrap(),
+        )
+        .unwrap();
+        fs::write(dir.join("README.md"), "not a fixture").unwrap();
+
+        let client = FileCommsClient {
+            accounts: FileCommsClient::load_fixtures(&dir).unwrap(),
+        };
+        assert_eq!(client.get_account(&json_address).unwrap(), json_account);
+        assert_eq!(client.get_account(&bin_address).unwrap(), bin_account);
+        assert!(client.get_account(&Pubkey::new_unique()).is_err());
+
+        let program_accounts = client.get_program_accounts(&program_id, 42).unwrap();
+        assert_eq!(program_accounts.context_slot, 42);
+        assert_eq!(
+            program_accounts.accounts,
+            vec![(json_address, json_account)]
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_invalid_fixture() {
+        let dir = create_test_dir("fixtures_invalid");
+        fs::write(dir.join("not_a_pubkey.bin"), [0u8; 4]).unwrap();
+
+        assert!(FileCommsClient::load_fixtures(&dir).is_err());
+        assert!(FileCommsClient::load_fixtures(&dir.join("missing")).is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{debug, info};
+use serde_json::json;
+use solana_client::{rpc_client::RpcClient, rpc_request::RpcRequest};
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    hash::Hash,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature},
+    signer::Signer,
+    system_instruction,
+    transaction::{Transaction, VersionedTransaction},
+};
+
+use crate::{
+    comms::{
+        circuit_breaker::{circuit_breaker_for, CircuitBreaker},
+        TransactionSender,
+    },
+    config::Config,
+};
+
+// Block engine limit of transactions in a bundle, including the tip transaction.
+const MAX_BUNDLE_SIZE: usize = 5;
+const BUNDLES_PATH: &str = "/api/v1/bundles";
+
+/// Submits the liquidation transactions as Jito bundles, followed by the transaction of the liquidator wallet
+/// tipping one of the block engine tip accounts.
+pub struct JitoClient {
+    bundle_rpc_client: RpcClient,
+    circuit_breaker: Arc<CircuitBreaker>,
+    // The liquidator wallet, which pays the tips out of the liquidation profits.
+    wallet: Keypair,
+    tip_accounts: RwLock<Vec<Pubkey>>,
+    next_tip_account: AtomicUsize,
+}
+
+impl JitoClient {
+    pub fn new(config: &Config, block_engine_url: &str) -> Result<Self> {
+        let bundle_rpc_client = RpcClient::new_with_timeout_and_commitment(
+            format!("{}{}", block_engine_url.trim_end_matches('/'), BUNDLES_PATH),
+            Duration::from_secs(config.rpc_timeout_sec),
+            CommitmentConfig::confirmed(),
+        );
+        Ok(Self {
+            bundle_rpc_client,
+            circuit_breaker: circuit_breaker_for(config, block_engine_url),
+            wallet: config.wallet.insecure_clone(),
+            tip_accounts: RwLock::new(Vec::new()),
+            next_tip_account: AtomicUsize::new(0),
+        })
+    }
+
+    fn tip_account(&self) -> Result<Pubkey> {
+        let cached = self
+            .tip_accounts
+            .read()
+            .map_err(|e| anyhow!("Failed to lock the Jito tip accounts for reading: {}", e))?
+            .clone();
+        let tip_accounts =
+            if cached.is_empty() {
+                let fetched = self.fetch_tip_accounts()?;
+                *self.tip_accounts.write().map_err(|e| {
+                    anyhow!("Failed to lock the Jito tip accounts for update: {}", e)
+                })? = fetched.clone();
+                fetched
+            } else {
+                cached
+            };
+
+        // Rotate the tip accounts to spread the write locks.
+        let index = self.next_tip_account.fetch_add(1, Ordering::Relaxed);
+        Ok(tip_accounts[index % tip_accounts.len()])
+    }
+
+    fn fetch_tip_accounts(&self) -> Result<Vec<Pubkey>> {
+        let tip_accounts: Vec<String> = self
+            .circuit_breaker
+            .call(|| {
+                self.bundle_rpc_client.send(
+                    RpcRequest::Custom {
+                        method: "getTipAccounts",
+                    },
+                    json!([]),
+                )
+            })
+            .map_err(|e| anyhow!("Failed to get the Jito tip accounts: {}", e))?;
+        let tip_accounts = parse_tip_accounts(&tip_accounts)?;
+        info!("Fetched {} Jito tip accounts.", tip_accounts.len());
+        Ok(tip_accounts)
+    }
+}
+
+impl TransactionSender for JitoClient {
+    fn send_transactions(
+        &self,
+        transactions: &[VersionedTransaction],
+        tip_lamports: u64,
+    ) -> Result<Vec<Signature>> {
+        let Some(first) = transactions.first() else {
+            return Ok(Vec::new());
+        };
+        if transactions.len() >= MAX_BUNDLE_SIZE {
+            return Err(anyhow!(
+                "Too many transactions for a Jito bundle: {} (max {} with the tip)",
+                transactions.len(),
+                MAX_BUNDLE_SIZE - 1
+            ));
+        }
+
+        let tip_transaction = create_tip_transaction(
+            &self.wallet,
+            &self.tip_account()?,
+            tip_lamports,
+            *first.message.recent_blockhash(),
+        );
+        let bundle: Vec<&VersionedTransaction> = transactions
+            .iter()
+            .chain(std::iter::once(&tip_transaction))
+            .collect();
+
+        let encoded = bundle
+            .iter()
+            .map(|transaction| Ok(STANDARD.encode(bincode::serialize(transaction)?)))
+            .collect::<Result<Vec<String>>>()?;
+        let bundle_id: String = self
+            .circuit_breaker
+            .call(|| {
+                self.bundle_rpc_client.send(
+                    RpcRequest::Custom {
+                        method: "sendBundle",
+                    },
+                    json!([encoded, { "encoding": "base64" }]),
+                )
+            })
+            .map_err(|e| anyhow!("Failed to send the Jito bundle: {}", e))?;
+        debug!(
+            "Sent the Jito bundle {} of {} transactions tipping {} lamports",
+            bundle_id,
+            bundle.len(),
+            tip_lamports
+        );
+
+        Ok(bundle
+            .iter()
+            .map(|transaction| transaction.signatures[0])
+            .collect())
+    }
+}
+
+fn parse_tip_accounts(tip_accounts: &[String]) -> Result<Vec<Pubkey>> {
+    let tip_accounts = tip_accounts
+        .iter()
+        .map(|account| {
+            Pubkey::from_str(account)
+                .map_err(|e| anyhow!("Invalid Jito tip account {}: {}", account, e))
+        })
+        .collect::<Result<Vec<Pubkey>>>()?;
+    if tip_accounts.is_empty() {
+        return Err(anyhow!("The block engine returned no tip accounts"));
+    }
+    Ok(tip_accounts)
+}
+
+fn create_tip_transaction(
+    payer: &Keypair,
+    tip_account: &Pubkey,
+    tip_lamports: u64,
+    recent_blockhash: Hash,
+) -> VersionedTransaction {
+    let instruction = system_instruction::transfer(&payer.pubkey(), tip_account, tip_lamports);
+    VersionedTransaction::from(Transaction::new_signed_with_payer(
+        &[instruction],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::system_program;
+
+    use super::*;
+
+    #[test]
+    fn test_create_tip_transaction() {
+        let payer = Keypair::new();
+        let tip_account = Pubkey::new_unique();
+        let blockhash = Hash::new_unique();
+
+        let transaction = create_tip_transaction(&payer, &tip_account, 10_000, blockhash);
+
+        assert_eq!(transaction.message.recent_blockhash(), &blockhash);
+        assert_eq!(transaction.message.static_account_keys()[0], payer.pubkey());
+        assert!(transaction
+            .message
+            .static_account_keys()
+            .contains(&tip_account));
+        assert!(transaction
+            .message
+            .static_account_keys()
+            .contains(&system_program::id()));
+        assert!(transaction.verify_with_results().iter().all(|valid| *valid));
+    }
+
+    #[test]
+    fn test_parse_tip_accounts() {
+        let tip_account = Pubkey::new_unique();
+        assert_eq!(
+            parse_tip_accounts(&[tip_account.to_string()]).unwrap(),
+            vec![tip_account]
+        );
+        assert!(parse_tip_accounts(&[]).is_err());
+        assert!(parse_tip_accounts(&["invalid".to_string()]).is_err());
+    }
+}
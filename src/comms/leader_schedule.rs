@@ -0,0 +1,197 @@
+use std::{collections::HashSet, str::FromStr, sync::RwLock};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use solana_client::rpc_response::RpcLeaderSchedule;
+use solana_sdk::{epoch_info::EpochInfo, pubkey::Pubkey};
+
+use crate::{comms::CommsClient, config::Config};
+
+/// The leaders of the slots of an epoch.
+#[derive(Debug)]
+pub struct LeaderSchedule {
+    epoch: u64,
+    first_slot: u64,
+    // Indexed by the slot index in the epoch, None where the leader is unknown.
+    leaders: Vec<Option<Pubkey>>,
+}
+
+impl LeaderSchedule {
+    pub fn new(epoch_info: &EpochInfo, schedule: &RpcLeaderSchedule) -> Result<Self> {
+        let mut leaders = vec![None; epoch_info.slots_in_epoch as usize];
+        for (leader, slot_indexes) in schedule {
+            let leader = Pubkey::from_str(leader)
+                .map_err(|e| anyhow!("Invalid leader {}: {}", leader, e))?;
+            for &slot_index in slot_indexes {
+                let slot_leader = leaders.get_mut(slot_index).ok_or_else(|| {
+                    anyhow!(
+                        "The slot index {} of the leader {} is out of the epoch",
+                        slot_index,
+                        leader
+                    )
+                })?;
+                *slot_leader = Some(leader);
+            }
+        }
+
+        Ok(Self {
+            epoch: epoch_info.epoch,
+            first_slot: epoch_info.absolute_slot - epoch_info.slot_index,
+            leaders,
+        })
+    }
+
+    pub fn contains(&self, slot: u64) -> bool {
+        slot >= self.first_slot && slot - self.first_slot < self.leaders.len() as u64
+    }
+
+    pub fn leader_at(&self, slot: u64) -> Option<Pubkey> {
+        if !self.contains(slot) {
+            return None;
+        }
+        self.leaders[(slot - self.first_slot) as usize]
+    }
+
+    /// The first slot from `from_slot` on, within the lookahead, led by an eligible leader.
+    pub fn next_leader(
+        &self,
+        from_slot: u64,
+        lookahead_slots: u64,
+        is_eligible: impl Fn(&Pubkey) -> bool,
+    ) -> Option<(u64, Pubkey)> {
+        (from_slot..=from_slot.saturating_add(lookahead_slots)).find_map(|slot| {
+            self.leader_at(slot)
+                .filter(|leader| is_eligible(leader))
+                .map(|leader| (slot, leader))
+        })
+    }
+}
+
+/// Where the liquidation transactions are headed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeaderTarget {
+    /// The slot and the leader the transactions are expected to land with.
+    Leader(u64, Pubkey),
+    /// No eligible leader within the lookahead, the submission is better deferred.
+    NoEligibleLeader,
+    /// The leader schedule is not known.
+    Unknown,
+}
+
+/// Keeps the leader schedule of the current epoch, to time the submissions toward the Jito-enabled leaders.
+pub struct LeaderTracker {
+    schedule: RwLock<Option<LeaderSchedule>>,
+    // When empty all the leaders are eligible.
+    jito_validators: HashSet<Pubkey>,
+    lookahead_slots: u64,
+}
+
+impl LeaderTracker {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            schedule: RwLock::new(None),
+            jito_validators: config.jito_validators.iter().copied().collect(),
+            lookahead_slots: config.leader_lookahead_slots,
+        }
+    }
+
+    /// Fetches the leader schedule of the epoch of the slot, unless it is already known.
+    pub fn refresh<T: CommsClient>(&self, comms_client: &T, slot: u64) -> Result<()> {
+        if self
+            .schedule
+            .read()
+            .map_err(|e| anyhow!("Failed to lock the leader schedule: {}", e))?
+            .as_ref()
+            .is_some_and(|schedule| schedule.contains(slot))
+        {
+            return Ok(());
+        }
+
+        let epoch_info = comms_client.get_epoch_info()?;
+        let schedule = comms_client.get_leader_schedule(&epoch_info)?;
+        info!(
+            "Fetched the leader schedule of the epoch {} starting at the slot {}.",
+            schedule.epoch, schedule.first_slot
+        );
+        *self
+            .schedule
+            .write()
+            .map_err(|e| anyhow!("Failed to lock the leader schedule: {}", e))? = Some(schedule);
+        Ok(())
+    }
+
+    pub fn target(&self, slot: u64) -> LeaderTarget {
+        let schedule = match self.schedule.read() {
+            Ok(schedule) => schedule,
+            Err(err) => {
+                warn!("Failed to lock the leader schedule: {}", err);
+                return LeaderTarget::Unknown;
+            }
+        };
+        // Without the leader of the current slot, e.g. offline, the schedule is as good as unknown.
+        match schedule.as_ref() {
+            Some(schedule) if schedule.leader_at(slot).is_some() => {
+                match schedule.next_leader(slot, self.lookahead_slots, |leader| {
+                    self.jito_validators.is_empty() || self.jito_validators.contains(leader)
+                }) {
+                    Some((slot, leader)) => LeaderTarget::Leader(slot, leader),
+                    None => LeaderTarget::NoEligibleLeader,
+                }
+            }
+            _ => LeaderTarget::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn test_epoch_info() -> EpochInfo {
+        EpochInfo {
+            epoch: 7,
+            slot_index: 2,
+            slots_in_epoch: 8,
+            absolute_slot: 102,
+            block_height: 90,
+            transaction_count: None,
+        }
+    }
+
+    #[test]
+    fn test_leader_schedule() {
+        let leader_a = Pubkey::new_unique();
+        let leader_b = Pubkey::new_unique();
+        let rpc_schedule: RpcLeaderSchedule = HashMap::from([
+            (leader_a.to_string(), vec![0, 1, 2, 3]),
+            (leader_b.to_string(), vec![4, 5]),
+        ]);
+
+        let schedule = LeaderSchedule::new(&test_epoch_info(), &rpc_schedule).unwrap();
+        assert!(schedule.contains(100));
+        assert!(schedule.contains(107));
+        assert!(!schedule.contains(99));
+        assert!(!schedule.contains(108));
+        assert_eq!(schedule.leader_at(103), Some(leader_a));
+        assert_eq!(schedule.leader_at(104), Some(leader_b));
+        assert_eq!(schedule.leader_at(106), None);
+
+        assert_eq!(
+            schedule.next_leader(101, 4, |leader| *leader == leader_b),
+            Some((104, leader_b))
+        );
+        assert_eq!(
+            schedule.next_leader(101, 2, |leader| *leader == leader_b),
+            None
+        );
+    }
+
+    #[test]
+    fn test_leader_schedule_out_of_epoch() {
+        let rpc_schedule: RpcLeaderSchedule =
+            HashMap::from([(Pubkey::new_unique().to_string(), vec![8])]);
+        assert!(LeaderSchedule::new(&test_epoch_info(), &rpc_schedule).is_err());
+    }
+}
@@ -1,20 +1,44 @@
-use std::mem::size_of;
+use std::{
+    mem::size_of,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
 use anchor_lang::Discriminator;
-use log::{debug, info};
+use log::{debug, info, warn};
 use marginfi::state::{
     marginfi_account::MarginfiAccount,
     marginfi_group::{Bank, MarginfiGroup},
 };
-use solana_account_decoder::UiAccountEncoding;
+use serde_json::json;
+use solana_account_decoder::{UiAccountEncoding, UiDataSliceConfig};
 use solana_client::{
-    rpc_client::RpcClient,
-    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_client::{GetConfirmedSignaturesForAddress2Config, RpcClient},
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcSimulateTransactionConfig},
     rpc_filter::{Memcmp, RpcFilterType},
+    rpc_request::RpcRequest,
+    rpc_response::{Response, RpcKeyedAccount},
+};
+use solana_sdk::{
+    account::Account, commitment_config::CommitmentConfig, epoch_info::EpochInfo, hash::Hash,
+    pubkey::Pubkey, signature::Signature, transaction::VersionedTransaction,
 };
-use solana_sdk::{account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey};
 
-use crate::{comms::CommsClient, config::Config};
+use crate::{
+    common::{SPL_TOKEN_2022_PROGRAM_ID, SPL_TOKEN_PROGRAM_ID},
+    comms::{
+        circuit_breaker::{circuit_breaker_for, CircuitBreaker},
+        leader_schedule::LeaderSchedule,
+        CommsClient, MarginfiAccountHeader, ProgramAccounts, TransactionSender,
+        TransactionSimulation, MARGINFI_ACCOUNT_HEADER_LEN, MARGINFI_ACCOUNT_HEADER_OFFSET,
+    },
+    config::Config,
+};
 use anyhow::{anyhow, Result};
 
 const ADDRESSES_CHUNK_SIZE: usize = 100;
@@ -25,57 +49,102 @@ const MARGINFI_BANK_DATA_LEN: usize = ANCHOR_DISCRIMINATOR_LEN + size_of::<Bank>
 const MARGINFI_ACCOUNT_DATA_LEN: usize = ANCHOR_DISCRIMINATOR_LEN + size_of::<MarginfiAccount>();
 const MARGINFI_ACCOUNT_GROUP_OFFSET: usize = ANCHOR_DISCRIMINATOR_LEN;
 const MARGINFI_ACCOUNT_AUTHORITY_OFFSET: usize = ANCHOR_DISCRIMINATOR_LEN + PUBKEY_BYTES;
+// The deepest authority prefix a group scan is planned at, i.e. 65536 scans. The scans that still hit
+// the scan limit are split further on the fly.
+const MAX_PLANNED_PREFIX_LEN: usize = 2;
 
 pub struct RpcCommsClient {
     solana_rpc_client: RpcClient,
+    // Separate client for the getProgramAccounts scans, which need a much longer timeout than the
+    // latency sensitive reads and must not hold them up.
+    scan_rpc_client: RpcClient,
+    circuit_breaker: Arc<CircuitBreaker>,
+    // The archival endpoint of the historical reads, with its own circuit breaker.
+    archive_rpc_client: Option<(RpcClient, Arc<CircuitBreaker>)>,
+    // Whether the scans request the base64+zstd encoding, until the provider rejects it.
+    scan_zstd: AtomicBool,
+    scan_concurrency: usize,
+    scan_max_accounts: usize,
 }
 
 impl CommsClient for RpcCommsClient {
     fn new(config: &Config) -> Result<Self> {
-        let solana_rpc_client =
-            RpcClient::new_with_commitment(&config.rpc_url, CommitmentConfig::confirmed());
-        Ok(RpcCommsClient { solana_rpc_client })
+        let solana_rpc_client = RpcClient::new_with_timeout_and_commitment(
+            config.rpc_url.clone(),
+            Duration::from_secs(config.rpc_timeout_sec),
+            CommitmentConfig::confirmed(),
+        );
+        let scan_rpc_client = RpcClient::new_with_timeout_and_commitment(
+            config.rpc_url.clone(),
+            Duration::from_secs(config.rpc_scan_timeout_sec),
+            CommitmentConfig::confirmed(),
+        );
+        let archive_rpc_client = config.archive_rpc_url.as_ref().map(|archive_rpc_url| {
+            (
+                RpcClient::new_with_timeout_and_commitment(
+                    archive_rpc_url.clone(),
+                    Duration::from_secs(config.rpc_timeout_sec),
+                    CommitmentConfig::confirmed(),
+                ),
+                circuit_breaker_for(config, archive_rpc_url),
+            )
+        });
+        Ok(RpcCommsClient {
+            solana_rpc_client,
+            scan_rpc_client,
+            circuit_breaker: circuit_breaker_for(config, &config.rpc_url),
+            archive_rpc_client,
+            scan_zstd: AtomicBool::new(config.rpc_scan_zstd),
+            scan_concurrency: config.rpc_scan_concurrency,
+            scan_max_accounts: config.rpc_scan_max_accounts,
+        })
     }
 
     fn get_account(&self, pubkey: &Pubkey) -> Result<Account> {
-        self.solana_rpc_client
-            .get_account(pubkey)
+        self.circuit_breaker
+            .call(|| self.solana_rpc_client.get_account(pubkey))
             .map_err(|e| anyhow!("Failed to get account {}: {}", pubkey, e))
     }
 
-    fn get_program_accounts(&self, program_id: &Pubkey) -> Result<Vec<(Pubkey, Account)>> {
-        let mut accounts = Vec::new();
-
-        info!("Fetching Marginfi groups...");
-        let mut groups =
-            self.get_program_accounts_for_type(program_id, MarginfiProgramAccountType::Group)?;
-        info!("Fetched {} Marginfi groups", groups.len());
-        let group_pubkeys: Vec<Pubkey> = groups.iter().map(|(pubkey, _)| *pubkey).collect();
-        accounts.append(&mut groups);
-
-        info!("Fetching Marginfi banks...");
-        let mut banks =
-            self.get_program_accounts_for_type(program_id, MarginfiProgramAccountType::Bank)?;
-        info!("Fetched {} Marginfi banks", banks.len());
-        accounts.append(&mut banks);
-
-        info!(
-            "Fetching Marginfi accounts for {} groups",
-            group_pubkeys.len()
-        );
-        let mut marginfi_accounts =
-            self.get_marginfi_accounts_by_group(program_id, &group_pubkeys)?;
-        info!("Fetched {} Marginfi accounts", marginfi_accounts.len());
-        accounts.append(&mut marginfi_accounts);
-
+    fn get_program_accounts(
+        &self,
+        program_id: &Pubkey,
+        min_context_slot: u64,
+    ) -> Result<ProgramAccounts> {
+        let (mut accounts, marginfi_accounts) =
+            self.scan_program_accounts(program_id, min_context_slot, None)?;
+        accounts.merge(marginfi_accounts);
         Ok(accounts)
     }
 
+    fn get_program_account_headers(
+        &self,
+        program_id: &Pubkey,
+        min_context_slot: u64,
+    ) -> Result<(ProgramAccounts, Vec<MarginfiAccountHeader>)> {
+        let (accounts, marginfi_accounts) = self.scan_program_accounts(
+            program_id,
+            min_context_slot,
+            Some(UiDataSliceConfig {
+                offset: MARGINFI_ACCOUNT_HEADER_OFFSET,
+                length: MARGINFI_ACCOUNT_HEADER_LEN,
+            }),
+        )?;
+        let headers = marginfi_accounts
+            .accounts
+            .into_iter()
+            .map(|(address, account)| MarginfiAccountHeader::from_slice(address, &account.data))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((accounts, headers))
+    }
+
     fn get_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<(Pubkey, Account)>> {
         let mut tuples: Vec<(Pubkey, Account)> = Vec::new();
 
         for chunk in addresses.chunks(ADDRESSES_CHUNK_SIZE) {
-            let accounts = self.solana_rpc_client.get_multiple_accounts(chunk)?;
+            let accounts = self
+                .circuit_breaker
+                .call(|| self.solana_rpc_client.get_multiple_accounts(chunk))?;
             for (address, account_opt) in chunk.iter().zip(accounts.iter()) {
                 if let Some(account) = account_opt {
                     tuples.push((*address, account.clone()));
@@ -85,16 +154,259 @@ impl CommsClient for RpcCommsClient {
 
         Ok(tuples)
     }
+
+    // The standard RPC methods cannot read the past state, the archival providers serve the minContextSlot itself
+    // instead. The context slot of the response tells whether they did.
+    fn get_accounts_at_slot(
+        &self,
+        addresses: &[Pubkey],
+        slot: u64,
+    ) -> Result<Vec<(Pubkey, Account)>> {
+        let (archive_rpc_client, archive_circuit_breaker) =
+            self.archive_rpc_client.as_ref().ok_or_else(|| {
+                anyhow!(
+                    "The accounts as of the slot {} cannot be read without ARCHIVE_RPC_URL",
+                    slot
+                )
+            })?;
+
+        let mut tuples: Vec<(Pubkey, Account)> = Vec::new();
+        for chunk in addresses.chunks(ADDRESSES_CHUNK_SIZE) {
+            let response = archive_circuit_breaker
+                .call(|| {
+                    archive_rpc_client.get_multiple_accounts_with_config(
+                        chunk,
+                        RpcAccountInfoConfig {
+                            encoding: Some(UiAccountEncoding::Base64),
+                            commitment: Some(CommitmentConfig::confirmed()),
+                            min_context_slot: Some(slot),
+                            ..Default::default()
+                        },
+                    )
+                })
+                .map_err(|e| {
+                    anyhow!("Failed to get the accounts as of the slot {}: {}", slot, e)
+                })?;
+            check_archive_slot(response.context.slot, slot)?;
+            tuples.extend(
+                chunk
+                    .iter()
+                    .zip(response.value)
+                    .filter_map(|(address, account)| account.map(|account| (*address, account))),
+            );
+        }
+
+        Ok(tuples)
+    }
+
+    fn get_latest_transaction_time(&self, address: &Pubkey) -> Result<Option<i64>> {
+        let signatures = self
+            .circuit_breaker
+            .call(|| {
+                self.solana_rpc_client
+                    .get_signatures_for_address_with_config(
+                        address,
+                        GetConfirmedSignaturesForAddress2Config {
+                            limit: Some(1),
+                            commitment: Some(CommitmentConfig::confirmed()),
+                            ..Default::default()
+                        },
+                    )
+            })
+            .map_err(|e| anyhow!("Failed to get the signatures of {}: {}", address, e))?;
+        Ok(signatures
+            .first()
+            .and_then(|signature| signature.block_time))
+    }
+
+    fn get_token_accounts_by_owner(&self, owner: &Pubkey) -> Result<Vec<(Pubkey, Account)>> {
+        let mut token_accounts = Vec::new();
+
+        for token_program_id in [SPL_TOKEN_PROGRAM_ID, SPL_TOKEN_2022_PROGRAM_ID] {
+            // The typed RpcClient helper forces jsonParsed encoding, so the raw request is used to get binary data.
+            let response: Response<Vec<RpcKeyedAccount>> = self
+                .circuit_breaker
+                .call(|| {
+                    self.solana_rpc_client.send(
+                        RpcRequest::GetTokenAccountsByOwner,
+                        json!([
+                            owner.to_string(),
+                            { "programId": token_program_id.to_string() },
+                            { "encoding": "base64", "commitment": "confirmed" }
+                        ]),
+                    )
+                })
+                .map_err(|e| {
+                    anyhow!(
+                        "Failed to get token accounts of {} for program {}: {}",
+                        owner,
+                        token_program_id,
+                        e
+                    )
+                })?;
+
+            for keyed_account in response.value {
+                token_accounts.push(decode_keyed_account(keyed_account)?);
+            }
+        }
+
+        debug!(
+            "Fetched {} token accounts owned by {}",
+            token_accounts.len(),
+            owner
+        );
+        Ok(token_accounts)
+    }
+
+    fn simulate_transaction(
+        &self,
+        transaction: &VersionedTransaction,
+    ) -> Result<TransactionSimulation> {
+        let config = RpcSimulateTransactionConfig {
+            sig_verify: false,
+            replace_recent_blockhash: true,
+            commitment: Some(CommitmentConfig::confirmed()),
+            ..Default::default()
+        };
+        let result = self
+            .circuit_breaker
+            .call(|| {
+                self.solana_rpc_client
+                    .simulate_transaction_with_config(transaction, config)
+            })
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to simulate the transaction {}: {}",
+                    transaction.signatures[0],
+                    e
+                )
+            })?
+            .value;
+
+        Ok(TransactionSimulation {
+            error: result.err.map(|err| err.to_string()),
+            units_consumed: result.units_consumed,
+        })
+    }
+
+    fn get_epoch_info(&self) -> Result<EpochInfo> {
+        self.circuit_breaker
+            .call(|| self.solana_rpc_client.get_epoch_info())
+            .map_err(|e| anyhow!("Failed to get the epoch info: {}", e))
+    }
+
+    fn get_latest_blockhash(&self) -> Result<Hash> {
+        self.circuit_breaker
+            .call(|| self.solana_rpc_client.get_latest_blockhash())
+            .map_err(|e| anyhow!("Failed to get the latest blockhash: {}", e))
+    }
+
+    fn get_leader_schedule(&self, epoch_info: &EpochInfo) -> Result<LeaderSchedule> {
+        let schedule = self
+            .circuit_breaker
+            .call(|| {
+                self.solana_rpc_client
+                    .get_leader_schedule(Some(epoch_info.absolute_slot))
+            })
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to get the leader schedule of the epoch {}: {}",
+                    epoch_info.epoch,
+                    e
+                )
+            })?
+            .ok_or_else(|| anyhow!("No leader schedule for the epoch {}", epoch_info.epoch))?;
+        LeaderSchedule::new(epoch_info, &schedule)
+    }
+}
+
+// The transactions sent to the RPC are not tipped.
+impl TransactionSender for RpcCommsClient {
+    fn send_transactions(
+        &self,
+        transactions: &[VersionedTransaction],
+        _tip_lamports: u64,
+    ) -> Result<Vec<Signature>> {
+        transactions
+            .iter()
+            .map(|transaction| {
+                self.circuit_breaker
+                    .call(|| self.solana_rpc_client.send_transaction(transaction))
+                    .map_err(|e| {
+                        anyhow!(
+                            "Failed to send the transaction {}: {}",
+                            transaction.signatures[0],
+                            e
+                        )
+                    })
+            })
+            .collect()
+    }
 }
 
 impl RpcCommsClient {
+    // Returns the Groups and the Banks, and separately the Marginfi accounts, fetched in full or only the
+    // slice of their data.
+    fn scan_program_accounts(
+        &self,
+        program_id: &Pubkey,
+        min_context_slot: u64,
+        marginfi_account_slice: Option<UiDataSliceConfig>,
+    ) -> Result<(ProgramAccounts, ProgramAccounts)> {
+        let mut accounts = ProgramAccounts::empty();
+
+        info!("Fetching Marginfi groups...");
+        let groups = self.get_program_accounts_for_type(
+            program_id,
+            MarginfiProgramAccountType::Group,
+            min_context_slot,
+        )?;
+        info!("Fetched {} Marginfi groups", groups.accounts.len());
+        let group_pubkeys: Vec<Pubkey> =
+            groups.accounts.iter().map(|(pubkey, _)| *pubkey).collect();
+        accounts.merge(groups);
+
+        info!("Fetching Marginfi banks...");
+        let banks = self.get_program_accounts_for_type(
+            program_id,
+            MarginfiProgramAccountType::Bank,
+            min_context_slot,
+        )?;
+        info!("Fetched {} Marginfi banks", banks.accounts.len());
+        accounts.merge(banks);
+
+        info!(
+            "Fetching Marginfi accounts for {} groups",
+            group_pubkeys.len()
+        );
+        let marginfi_accounts = self.get_marginfi_accounts_by_group(
+            program_id,
+            &group_pubkeys,
+            min_context_slot,
+            marginfi_account_slice,
+        )?;
+        info!(
+            "Fetched {} Marginfi accounts",
+            marginfi_accounts.accounts.len()
+        );
+
+        Ok((accounts, marginfi_accounts))
+    }
+
     fn get_program_accounts_for_type(
         &self,
         program_id: &Pubkey,
         account_kind: MarginfiProgramAccountType,
-    ) -> Result<Vec<(Pubkey, Account)>> {
+        min_context_slot: u64,
+    ) -> Result<ProgramAccounts> {
         let filters = account_kind.filters();
-        self.get_program_accounts_with_filters(program_id, filters, account_kind)
+        self.get_program_accounts_with_filters(
+            program_id,
+            filters,
+            account_kind,
+            min_context_slot,
+            None,
+        )
     }
 
     fn get_program_accounts_with_filters(
@@ -102,7 +414,9 @@ impl RpcCommsClient {
         program_id: &Pubkey,
         filters: Vec<RpcFilterType>,
         account_kind: MarginfiProgramAccountType,
-    ) -> Result<Vec<(Pubkey, Account)>> {
+        min_context_slot: u64,
+        data_slice: Option<UiDataSliceConfig>,
+    ) -> Result<ProgramAccounts> {
         let filter_summary = Self::summarize_filters(&filters);
         debug!(
             "Querying {} accounts with filters: {}",
@@ -110,66 +424,193 @@ impl RpcCommsClient {
             filter_summary
         );
 
+        let zstd = self.scan_zstd.load(Ordering::Relaxed);
+        let response = match self.request_program_accounts(
+            program_id,
+            filters.clone(),
+            min_context_slot,
+            zstd,
+            data_slice,
+        ) {
+            Err(err) if zstd && Self::is_unsupported_encoding_error(&err) => {
+                warn!(
+                    "The RPC does not support the base64+zstd encoding, falling back to base64: {}",
+                    err
+                );
+                self.scan_zstd.store(false, Ordering::Relaxed);
+                self.request_program_accounts(
+                    program_id,
+                    filters,
+                    min_context_slot,
+                    false,
+                    data_slice,
+                )
+            }
+            result => result,
+        }
+        .map_err(|e| {
+            anyhow!(
+                "Failed to get {} accounts for program {}: {}",
+                account_kind.as_str(),
+                program_id,
+                e
+            )
+        })?;
+        if response.context.slot < min_context_slot {
+            return Err(anyhow!(
+                "The {} accounts were read at the slot {}, older than the minimum slot {}",
+                account_kind.as_str(),
+                response.context.slot,
+                min_context_slot
+            ));
+        }
+
+        let accounts = response
+            .value
+            .into_iter()
+            .map(decode_keyed_account)
+            .collect::<Result<Vec<_>>>()?;
+        debug!(
+            "Fetched {} {} accounts at the slot {} (filters: {})",
+            accounts.len(),
+            account_kind.as_str(),
+            response.context.slot,
+            filter_summary
+        );
+        Ok(ProgramAccounts {
+            context_slot: response.context.slot,
+            accounts,
+        })
+    }
+
+    // The typed RpcClient helper drops the context, so the raw request is used to get the slot.
+    fn request_program_accounts(
+        &self,
+        program_id: &Pubkey,
+        filters: Vec<RpcFilterType>,
+        min_context_slot: u64,
+        zstd: bool,
+        data_slice: Option<UiDataSliceConfig>,
+    ) -> Result<Response<Vec<RpcKeyedAccount>>> {
         let config = RpcProgramAccountsConfig {
             account_config: RpcAccountInfoConfig {
-                encoding: Some(UiAccountEncoding::Base64),
+                encoding: Some(if zstd {
+                    UiAccountEncoding::Base64Zstd
+                } else {
+                    UiAccountEncoding::Base64
+                }),
                 commitment: Some(CommitmentConfig::confirmed()),
-                ..Default::default()
+                min_context_slot: Some(min_context_slot),
+                data_slice,
             },
             filters: Some(filters),
-            with_context: None,
+            with_context: Some(true),
             sort_results: None,
         };
-
-        self.solana_rpc_client
-            .get_program_accounts_with_config(program_id, config)
-            .map_err(|e| {
-                anyhow!(
-                    "Failed to get {} accounts for program {}: {}",
-                    account_kind.as_str(),
-                    program_id,
-                    e
-                )
-            })
-            .map(|accounts| {
-                debug!(
-                    "Fetched {} {} accounts (filters: {})",
-                    accounts.len(),
-                    account_kind.as_str(),
-                    filter_summary
-                );
-                accounts
-            })
+        self.circuit_breaker.call(|| {
+            self.scan_rpc_client.send(
+                RpcRequest::GetProgramAccounts,
+                json!([program_id.to_string(), config]),
+            )
+        })
     }
 
     fn get_marginfi_accounts_by_group(
         &self,
         program_id: &Pubkey,
         group_pubkeys: &[Pubkey],
-    ) -> Result<Vec<(Pubkey, Account)>> {
+        min_context_slot: u64,
+        data_slice: Option<UiDataSliceConfig>,
+    ) -> Result<ProgramAccounts> {
         if group_pubkeys.is_empty() {
-            return self.get_program_accounts_for_type(
+            return self.get_program_accounts_with_filters(
                 program_id,
+                MarginfiProgramAccountType::MarginfiAccount.filters(),
                 MarginfiProgramAccountType::MarginfiAccount,
+                min_context_slot,
+                data_slice,
             );
         }
 
-        let mut accounts = Vec::new();
-        for group_pubkey in group_pubkeys {
-            let mut group_accounts =
-                self.fetch_marginfi_accounts_for_prefix(program_id, *group_pubkey, Vec::new())?;
-            accounts.append(&mut group_accounts);
+        // Most groups are small enough for a single scan, while the main group is split by authority prefix
+        // up front rather than after hitting the scan limit, so the startup time follows the number of accounts.
+        let group_sizes = scan_concurrently(
+            group_pubkeys,
+            self.scan_concurrency,
+            |group_pubkey| match self.count_marginfi_accounts(
+                program_id,
+                *group_pubkey,
+                min_context_slot,
+            ) {
+                Ok(accounts) => Ok(Some(accounts)),
+                Err(err) => {
+                    warn!(
+                        "Failed to count the Marginfi accounts of the group {}, splitting its scan: {}",
+                        group_pubkey, err
+                    );
+                    Ok(None)
+                }
+            },
+        )?;
+
+        let mut scans = Vec::new();
+        for (group_pubkey, group_size) in group_pubkeys.iter().zip(group_sizes) {
+            let prefix_len = match group_size {
+                Some(accounts) => scan_prefix_len(accounts, self.scan_max_accounts),
+                None => 1,
+            };
+            let prefixes = authority_prefixes(prefix_len);
+            info!(
+                "Scanning the Marginfi accounts of the group {} ({} accounts) with {} requests",
+                group_pubkey,
+                group_size.map_or("unknown".to_string(), |accounts| accounts.to_string()),
+                prefixes.len()
+            );
+            scans.extend(prefixes.into_iter().map(|prefix| (*group_pubkey, prefix)));
+        }
+
+        let mut accounts = ProgramAccounts::empty();
+        for scan_accounts in
+            scan_concurrently(&scans, self.scan_concurrency, |(group_pubkey, prefix)| {
+                self.fetch_marginfi_accounts_for_prefix(
+                    program_id,
+                    *group_pubkey,
+                    prefix.clone(),
+                    min_context_slot,
+                    data_slice,
+                )
+            })?
+        {
+            accounts.merge(scan_accounts);
         }
 
         Ok(accounts)
     }
 
-    fn fetch_marginfi_accounts_for_prefix(
+    // Fetches the addresses only, which is a fraction of the scan of the account data.
+    fn count_marginfi_accounts(
         &self,
         program_id: &Pubkey,
         group_pubkey: Pubkey,
-        authority_prefix: Vec<u8>,
-    ) -> Result<Vec<(Pubkey, Account)>> {
+        min_context_slot: u64,
+    ) -> Result<usize> {
+        let response = self.request_program_accounts(
+            program_id,
+            Self::marginfi_account_filters(group_pubkey, &[]),
+            min_context_slot,
+            false,
+            Some(UiDataSliceConfig {
+                offset: 0,
+                length: 0,
+            }),
+        )?;
+        Ok(response.value.len())
+    }
+
+    fn marginfi_account_filters(
+        group_pubkey: Pubkey,
+        authority_prefix: &[u8],
+    ) -> Vec<RpcFilterType> {
         let mut filters = MarginfiProgramAccountType::MarginfiAccount.filters();
         filters.push(RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
             MARGINFI_ACCOUNT_GROUP_OFFSET,
@@ -178,9 +619,21 @@ impl RpcCommsClient {
         if !authority_prefix.is_empty() {
             filters.push(RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
                 MARGINFI_ACCOUNT_AUTHORITY_OFFSET,
-                authority_prefix.clone(),
+                authority_prefix.to_vec(),
             )));
         }
+        filters
+    }
+
+    fn fetch_marginfi_accounts_for_prefix(
+        &self,
+        program_id: &Pubkey,
+        group_pubkey: Pubkey,
+        authority_prefix: Vec<u8>,
+        min_context_slot: u64,
+        data_slice: Option<UiDataSliceConfig>,
+    ) -> Result<ProgramAccounts> {
+        let filters = Self::marginfi_account_filters(group_pubkey, &authority_prefix);
 
         if authority_prefix.is_empty() {
             info!("Fetching Marginfi accounts for group {}", group_pubkey);
@@ -196,6 +649,8 @@ impl RpcCommsClient {
             program_id,
             filters,
             MarginfiProgramAccountType::MarginfiAccount,
+            min_context_slot,
+            data_slice,
         ) {
             Ok(accounts) => Ok(accounts),
             Err(err) if Self::is_scan_limit_error(&err) => {
@@ -208,16 +663,18 @@ impl RpcCommsClient {
                     return Err(err);
                 }
 
-                let mut chunked_accounts = Vec::new();
+                let mut chunked_accounts = ProgramAccounts::empty();
                 for byte in 0u8..=u8::MAX {
                     let mut next_prefix = authority_prefix.clone();
                     next_prefix.push(byte);
-                    let mut accounts = self.fetch_marginfi_accounts_for_prefix(
+                    let accounts = self.fetch_marginfi_accounts_for_prefix(
                         program_id,
                         group_pubkey,
                         next_prefix,
+                        min_context_slot,
+                        data_slice,
                     )?;
-                    chunked_accounts.append(&mut accounts);
+                    chunked_accounts.merge(accounts);
                 }
 
                 Ok(chunked_accounts)
@@ -231,6 +688,11 @@ impl RpcCommsClient {
             .contains("scan aborted: The accumulated scan results exceeded the limit")
     }
 
+    // The providers that disable the compression reject the encoding as an invalid param.
+    fn is_unsupported_encoding_error(err: &anyhow::Error) -> bool {
+        err.to_string().to_lowercase().contains("zstd")
+    }
+
     fn summarize_filters(filters: &[RpcFilterType]) -> String {
         filters
             .iter()
@@ -264,6 +726,98 @@ impl RpcCommsClient {
     }
 }
 
+/// The authority prefix length the scans of a group start at, for each scan to return at most about
+/// `max_accounts` accounts. The authorities are random, so the prefixes split the accounts evenly.
+fn scan_prefix_len(accounts: usize, max_accounts: usize) -> usize {
+    let mut prefix_len = 0;
+    let mut accounts_per_scan = accounts;
+    while accounts_per_scan > max_accounts.max(1) && prefix_len < MAX_PLANNED_PREFIX_LEN {
+        accounts_per_scan = accounts_per_scan.div_ceil(u8::MAX as usize + 1);
+        prefix_len += 1;
+    }
+    prefix_len
+}
+
+/// All the authority prefixes of the length.
+fn authority_prefixes(prefix_len: usize) -> Vec<Vec<u8>> {
+    (0..prefix_len).fold(vec![Vec::new()], |prefixes, _| {
+        prefixes
+            .into_iter()
+            .flat_map(|prefix| {
+                (0..=u8::MAX).map(move |byte| {
+                    let mut next_prefix = prefix.clone();
+                    next_prefix.push(byte);
+                    next_prefix
+                })
+            })
+            .collect()
+    })
+}
+
+/// Runs the scans concurrently, with at most `concurrency` in flight, stopping at the first failure.
+/// The results are in the order of the items.
+fn scan_concurrently<I: Sync, R: Send>(
+    items: &[I],
+    concurrency: usize,
+    scan: impl Fn(&I) -> Result<R> + Sync,
+) -> Result<Vec<R>> {
+    let next_item = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<Result<R>>>> =
+        Mutex::new((0..items.len()).map(|_| None).collect());
+    let workers = concurrency.clamp(1, items.len().max(1));
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while !failed.load(Ordering::Relaxed) {
+                    let index = next_item.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(index) else {
+                        break;
+                    };
+
+                    let result = scan(item);
+                    if result.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    results
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    // The items not scanned after a failure are skipped, the failure is returned.
+    results
+        .into_inner()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+fn check_archive_slot(context_slot: u64, slot: u64) -> Result<()> {
+    if context_slot != slot {
+        return Err(anyhow!(
+            "The archival endpoint served the slot {} instead of {}",
+            context_slot,
+            slot
+        ));
+    }
+    Ok(())
+}
+
+fn decode_keyed_account(keyed_account: RpcKeyedAccount) -> Result<(Pubkey, Account)> {
+    let address = Pubkey::from_str(&keyed_account.pubkey)
+        .map_err(|e| anyhow!("Invalid account address {}: {}", keyed_account.pubkey, e))?;
+    let account = keyed_account
+        .account
+        .decode::<Account>()
+        .ok_or_else(|| anyhow!("Failed to decode the account {}", address))?;
+    Ok((address, account))
+}
+
 #[derive(Clone, Copy)]
 enum MarginfiProgramAccountType {
     Group,
@@ -303,3 +857,93 @@ impl MarginfiProgramAccountType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use solana_account_decoder::UiAccount;
+
+    use super::*;
+
+    #[test]
+    fn test_decode_zstd_keyed_account() {
+        let address = Pubkey::new_unique();
+        let account = Account {
+            lamports: 1_000,
+            data: vec![7; MARGINFI_ACCOUNT_DATA_LEN],
+            owner: Pubkey::new_unique(),
+            executable: false,
+            rent_epoch: 0,
+        };
+        let keyed_account = RpcKeyedAccount {
+            pubkey: address.to_string(),
+            account: UiAccount::encode(
+                &address,
+                &account,
+                UiAccountEncoding::Base64Zstd,
+                None,
+                None,
+            ),
+        };
+
+        assert_eq!(
+            decode_keyed_account(keyed_account).unwrap(),
+            (address, account)
+        );
+    }
+
+    #[test]
+    fn test_scan_prefix_len() {
+        assert_eq!(scan_prefix_len(0, 1_000), 0);
+        assert_eq!(scan_prefix_len(1_000, 1_000), 0);
+        assert_eq!(scan_prefix_len(1_001, 1_000), 1);
+        assert_eq!(scan_prefix_len(256_000, 1_000), 1);
+        assert_eq!(scan_prefix_len(256_001, 1_000), 2);
+        assert_eq!(scan_prefix_len(usize::MAX, 1), MAX_PLANNED_PREFIX_LEN);
+    }
+
+    #[test]
+    fn test_authority_prefixes() {
+        assert_eq!(authority_prefixes(0), vec![Vec::<u8>::new()]);
+        let prefixes = authority_prefixes(2);
+        assert_eq!(prefixes.len(), 65_536);
+        assert_eq!(prefixes[0], vec![0, 0]);
+        assert_eq!(prefixes[257], vec![1, 1]);
+        assert_eq!(prefixes[65_535], vec![255, 255]);
+    }
+
+    #[test]
+    fn test_scan_concurrently() {
+        let items: Vec<u64> = (0..50).collect();
+        let results = scan_concurrently(&items, 4, |item| Ok(item * 2)).unwrap();
+        assert_eq!(results, (0..50).map(|item| item * 2).collect::<Vec<_>>());
+
+        let result = scan_concurrently(&items, 4, |item| {
+            if *item == 10 {
+                Err(anyhow!("scan failed"))
+            } else {
+                Ok(*item)
+            }
+        });
+        assert_eq!(result.unwrap_err().to_string(), "scan failed");
+
+        assert!(scan_concurrently(&Vec::<u64>::new(), 4, |item| Ok(*item))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_is_unsupported_encoding_error() {
+        assert!(RpcCommsClient::is_unsupported_encoding_error(&anyhow!(
+            "RPC response error -32602: Invalid params: unknown variant `base64+zstd`"
+        )));
+        assert!(!RpcCommsClient::is_unsupported_encoding_error(&anyhow!(
+            "scan aborted: The accumulated scan results exceeded the limit"
+        )));
+    }
+
+    #[test]
+    fn test_check_archive_slot() {
+        assert!(check_archive_slot(42, 42).is_ok());
+        assert!(check_archive_slot(43, 42).is_err());
+    }
+}
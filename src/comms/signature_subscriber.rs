@@ -0,0 +1,254 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use crossbeam::channel::{bounded, Receiver};
+use futures::stream::{select_all, BoxStream, SelectAll, StreamExt};
+use log::debug;
+use solana_client::{
+    nonblocking::pubsub_client::PubsubClient,
+    rpc_config::RpcSignatureSubscribeConfig,
+    rpc_response::{ProcessedSignatureResult, RpcSignatureResult},
+};
+use solana_sdk::{commitment_config::CommitmentConfig, signature::Signature};
+use tokio::runtime::{Builder, Runtime};
+
+use crate::config::Config;
+
+/// Whether a submitted transaction landed, as reported by the signature subscription.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignatureStatus {
+    Landed { slot: u64 },
+    Failed { slot: u64, error: String },
+    TimedOut,
+}
+
+/// Learns the outcome of the submitted transactions through WebSocket signature subscriptions,
+/// instead of polling `getSignatureStatuses`.
+pub struct SignatureSubscriber {
+    ws_url: String,
+    timeout: Duration,
+    tokio_rt: Runtime,
+}
+
+impl SignatureSubscriber {
+    pub fn new(config: &Config) -> Result<Self> {
+        let tokio_rt = Builder::new_multi_thread()
+            .thread_name("SignatureSubscriber")
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+
+        Ok(Self {
+            ws_url: config
+                .rpc_ws_url
+                .clone()
+                .unwrap_or_else(|| ws_url_from_rpc_url(&config.rpc_url)),
+            timeout: Duration::from_secs(config.signature_timeout_sec),
+            tokio_rt,
+        })
+    }
+
+    /// Subscribes to the signatures of the transactions about to be sent, over a single connection, so that no
+    /// notification is missed. The notifications are awaited in the background, all of them within the same timeout.
+    pub fn subscribe(&self, signatures: &[Signature]) -> Result<PendingSignatures> {
+        let deadline = Instant::now() + self.timeout;
+        let (subscribed_tx, subscribed_rx) = bounded(1);
+        let (statuses_tx, statuses_rx) = bounded(1);
+        let ws_url = self.ws_url.clone();
+        let signatures = signatures.to_vec();
+        let count = signatures.len();
+        self.tokio_rt.spawn(async move {
+            let client = match PubsubClient::new(&ws_url).await {
+                Ok(client) => client,
+                Err(e) => {
+                    let _ = subscribed_tx.send(Err(anyhow!(
+                        "Failed to connect to the signature subscriptions: {}",
+                        e
+                    )));
+                    return;
+                }
+            };
+            let mut notifications = select_all(Vec::new());
+            for (index, signature) in signatures.iter().enumerate() {
+                match subscribe_signature(&client, index, signature).await {
+                    Ok(stream) => notifications.push(stream),
+                    Err(e) => {
+                        let _ = subscribed_tx.send(Err(e));
+                        return;
+                    }
+                }
+            }
+            let _ = subscribed_tx.send(Ok(()));
+
+            let mut statuses = vec![SignatureStatus::TimedOut; signatures.len()];
+            let _ = tokio::time::timeout_at(
+                tokio::time::Instant::from_std(deadline),
+                collect_statuses(&mut notifications, &mut statuses),
+            )
+            .await;
+            for (signature, status) in signatures.iter().zip(&statuses) {
+                debug!("Signature {} status: {:?}", signature, status);
+            }
+            // Sent before the shutdown, which may linger on a dead connection.
+            let _ = statuses_tx.send(statuses);
+
+            drop(notifications);
+            if let Err(err) = client.shutdown().await {
+                debug!("Failed to shut down the signature subscriptions: {:?}", err);
+            }
+        });
+
+        subscribed_rx
+            .recv_timeout(self.timeout)
+            .map_err(|_| anyhow!("Timed out subscribing to the signatures"))??;
+        Ok(PendingSignatures {
+            statuses: statuses_rx,
+            count,
+        })
+    }
+}
+
+/// The subscriptions to the signatures of sent transactions, until they are confirmed.
+pub struct PendingSignatures {
+    statuses: Receiver<Vec<SignatureStatus>>,
+    count: usize,
+}
+
+impl PendingSignatures {
+    /// Waits for the confirmation of every signature, in order, until the deadline of the subscriptions.
+    pub fn wait(self) -> Vec<SignatureStatus> {
+        self.statuses
+            .recv()
+            .unwrap_or_else(|_| vec![SignatureStatus::TimedOut; self.count])
+    }
+}
+
+async fn subscribe_signature<'a>(
+    client: &'a PubsubClient,
+    index: usize,
+    signature: &Signature,
+) -> Result<BoxStream<'a, (usize, SignatureStatus)>> {
+    let (stream, _unsubscribe) = client
+        .signature_subscribe(
+            signature,
+            Some(RpcSignatureSubscribeConfig {
+                commitment: Some(CommitmentConfig::confirmed()),
+                enable_received_notification: Some(false),
+            }),
+        )
+        .await
+        .map_err(|e| anyhow!("Failed to subscribe to the signature {}: {}", signature, e))?;
+    Ok(stream
+        .map(move |response| {
+            (
+                index,
+                to_signature_status(response.context.slot, response.value),
+            )
+        })
+        .boxed())
+}
+
+// The signature subscriptions notify once, but their streams are only ended by the shutdown of the client.
+async fn collect_statuses(
+    notifications: &mut SelectAll<BoxStream<'_, (usize, SignatureStatus)>>,
+    statuses: &mut [SignatureStatus],
+) {
+    let mut pending = statuses.len();
+    while pending > 0 {
+        match notifications.next().await {
+            Some((index, status)) => {
+                statuses[index] = status;
+                pending -= 1;
+            }
+            None => break,
+        }
+    }
+}
+
+fn to_signature_status(slot: u64, result: RpcSignatureResult) -> SignatureStatus {
+    match result {
+        RpcSignatureResult::ProcessedSignature(ProcessedSignatureResult { err: None }) => {
+            SignatureStatus::Landed { slot }
+        }
+        RpcSignatureResult::ProcessedSignature(ProcessedSignatureResult { err: Some(err) }) => {
+            SignatureStatus::Failed {
+                slot,
+                error: err.to_string(),
+            }
+        }
+        // Not expected with the received notifications disabled
+        RpcSignatureResult::ReceivedSignature(_) => SignatureStatus::TimedOut,
+    }
+}
+
+fn ws_url_from_rpc_url(rpc_url: &str) -> String {
+    if let Some(rest) = rpc_url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = rpc_url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        rpc_url.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::transaction::TransactionError;
+
+    use super::*;
+    use crate::config::test_util::create_dummy_config;
+
+    #[test]
+    fn test_to_signature_status() {
+        assert_eq!(
+            to_signature_status(
+                10,
+                RpcSignatureResult::ProcessedSignature(ProcessedSignatureResult { err: None })
+            ),
+            SignatureStatus::Landed { slot: 10 }
+        );
+        assert_eq!(
+            to_signature_status(
+                11,
+                RpcSignatureResult::ProcessedSignature(ProcessedSignatureResult {
+                    err: Some(TransactionError::AccountNotFound)
+                })
+            ),
+            SignatureStatus::Failed {
+                slot: 11,
+                error: TransactionError::AccountNotFound.to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_ws_url_from_rpc_url() {
+        assert_eq!(
+            ws_url_from_rpc_url("https://rpc.example.com/token"),
+            "wss://rpc.example.com/token"
+        );
+        assert_eq!(
+            ws_url_from_rpc_url("http://localhost:8899"),
+            "ws://localhost:8899"
+        );
+        assert_eq!(
+            ws_url_from_rpc_url("ws://localhost:8900"),
+            "ws://localhost:8900"
+        );
+    }
+
+    #[test]
+    fn test_signature_subscriber_ws_url() {
+        let mut config = create_dummy_config();
+        assert_eq!(
+            SignatureSubscriber::new(&config).unwrap().ws_url,
+            "ws://dummy_rpc_url"
+        );
+
+        config.rpc_ws_url = Some("wss://dummy_ws_url".to_string());
+        assert_eq!(
+            SignatureSubscriber::new(&config).unwrap().ws_url,
+            "wss://dummy_ws_url"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::{collections::HashSet, fmt, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use solana_program::pubkey::Pubkey;
+
+const DEFAULT_NAMESPACE: &str = "default";
+const MONITOR_FLAG: &str = "monitor";
+
+/// A marginfi deployment monitored by the process, with its own cache, snapshot, event log and stats,
+/// in the `<name>=<program id>[:monitor]` format. The `monitor` flag disables its liquidations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Namespace {
+    pub name: String,
+    pub marginfi_program_id: Pubkey,
+    pub liquidation_enabled: bool,
+}
+
+impl Namespace {
+    /// The single namespace of the MARGINFI_PROGRAM_ID deployment, when no namespaces are configured.
+    pub fn default_for(marginfi_program_id: Pubkey) -> Self {
+        Self {
+            name: DEFAULT_NAMESPACE.to_string(),
+            marginfi_program_id,
+            liquidation_enabled: true,
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (name, rest) = spec
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected <name>=<program id>[:monitor], got {:?}", spec))?;
+        let name = name.trim();
+        // The name ends up in the file names of the snapshots and the event logs.
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow!("Invalid namespace name {:?}", name));
+        }
+        let (program_id, liquidation_enabled) = match rest.trim().split_once(':') {
+            Some((program_id, flag)) if flag.trim() == MONITOR_FLAG => (program_id, false),
+            Some((_, flag)) => return Err(anyhow!("Unknown namespace flag {:?}", flag.trim())),
+            None => (rest, true),
+        };
+        let marginfi_program_id = Pubkey::from_str(program_id.trim())
+            .map_err(|e| anyhow!("Invalid program id {:?}: {}", program_id.trim(), e))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            marginfi_program_id,
+            liquidation_enabled,
+        })
+    }
+
+    /// Suffixes the file name of the path with the namespace, e.g. `cache.bin` -> `cache.mainnet.bin`.
+    pub fn scope_path(&self, path: &str) -> String {
+        let path = Path::new(path);
+        let file_name = match (
+            path.file_stem().and_then(|stem| stem.to_str()),
+            path.extension().and_then(|ext| ext.to_str()),
+        ) {
+            (Some(stem), Some(ext)) => format!("{}.{}.{}", stem, self.name, ext),
+            (Some(stem), None) => format!("{}.{}", stem, self.name),
+            _ => self.name.clone(),
+        };
+        path.with_file_name(file_name)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.marginfi_program_id)?;
+        if !self.liquidation_enabled {
+            write!(f, ":{}", MONITOR_FLAG)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the `;` separated namespaces, which must have distinct names and program ids.
+pub fn parse_namespaces(spec: &str) -> Result<Vec<Namespace>> {
+    let namespaces = spec
+        .split(';')
+        .filter(|namespace| !namespace.trim().is_empty())
+        .map(Namespace::parse)
+        .collect::<Result<Vec<_>>>()?;
+    if namespaces.is_empty() {
+        return Err(anyhow!("No namespaces"));
+    }
+
+    let mut names = HashSet::new();
+    let mut program_ids = HashSet::new();
+    for namespace in &namespaces {
+        if !names.insert(namespace.name.as_str()) {
+            return Err(anyhow!("Duplicate namespace {:?}", namespace.name));
+        }
+        if !program_ids.insert(namespace.marginfi_program_id) {
+            return Err(anyhow!(
+                "The program {} is in several namespaces",
+                namespace.marginfi_program_id
+            ));
+        }
+    }
+    Ok(namespaces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM_A: &str = "MFv2hWf31Z9kbCa1snEPYctwafyhdvnV7FZnsebVacA";
+    const PROGRAM_B: &str = "11111111111111111111111111111111";
+
+    #[test]
+    fn test_parse_namespaces() {
+        let namespaces = parse_namespaces(&format!(
+            "mainnet={}; fork={}:monitor;",
+            PROGRAM_A, PROGRAM_B
+        ))
+        .unwrap();
+        assert_eq!(namespaces.len(), 2);
+        assert_eq!(namespaces[0].name, "mainnet");
+        assert_eq!(
+            namespaces[0].marginfi_program_id,
+            Pubkey::from_str(PROGRAM_A).unwrap()
+        );
+        assert!(namespaces[0].liquidation_enabled);
+        assert_eq!(namespaces[1].name, "fork");
+        assert!(!namespaces[1].liquidation_enabled);
+        assert_eq!(
+            namespaces[1].to_string(),
+            format!("fork={}:monitor", PROGRAM_B)
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_namespaces() {
+        assert!(parse_namespaces("").is_err());
+        assert!(parse_namespaces(PROGRAM_A).is_err());
+        assert!(parse_namespaces(&format!("main net={}", PROGRAM_A)).is_err());
+        assert!(parse_namespaces(&format!("mainnet={}:liquidate", PROGRAM_A)).is_err());
+        assert!(parse_namespaces("mainnet=not_a_pubkey").is_err());
+        assert!(parse_namespaces(&format!("a={};a={}", PROGRAM_A, PROGRAM_B)).is_err());
+        assert!(parse_namespaces(&format!("a={};b={}", PROGRAM_A, PROGRAM_A)).is_err());
+    }
+
+    #[test]
+    fn test_scope_path() {
+        let namespace = Namespace::default_for(Pubkey::default());
+        assert_eq!(
+            namespace.scope_path("/data/cache.bin"),
+            "/data/cache.default.bin"
+        );
+        assert_eq!(namespace.scope_path("events"), "events.default");
+    }
+}
@@ -1,30 +1,145 @@
 mod basic_liquidation_strategy;
+pub mod candidates;
+pub mod compute_unit_price;
+pub mod event_log;
+pub mod health;
+pub mod hooks;
+pub mod inventory;
+pub mod latency_budget;
+pub mod pause_windows;
+pub mod profitability;
+pub mod repay;
+pub mod risk_hook;
+pub mod simulation;
+pub mod sizing;
+pub mod swap;
+pub mod traces;
+pub mod wallet;
+
 use basic_liquidation_strategy::BasicLiquidationStrategy;
+use event_log::DecisionAccounts;
+use repay::RepaySource;
+use solana_sdk::{
+    hash::Hash,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature},
+    transaction::VersionedTransaction,
+};
 use std::sync::Arc;
+use swap::SwapInstructions;
 
 use crate::{
     cache::{marginfi_accounts::CachedMarginfiAccount, Cache},
-    comms::CommsClient,
+    comms::{CommsClient, TransactionSender},
+    config::Config,
 };
 
 pub trait LiquidationStrategy {
     fn prepare(&self, account: &CachedMarginfiAccount)
         -> anyhow::Result<Option<LiquidationParams>>;
+    fn build_transactions(
+        &self,
+        liquidation_params: &LiquidationParams,
+        recent_blockhash: Hash,
+        compute_unit_price: u64,
+        compute_unit_limit: u32,
+    ) -> anyhow::Result<Vec<VersionedTransaction>>;
     fn liquidate<T: CommsClient>(
         &self,
-        liquidation_params: LiquidationParams,
+        liquidation_params: &LiquidationParams,
+        transactions: &[VersionedTransaction],
         comms_client: &T,
-    ) -> anyhow::Result<()>;
+        transaction_sender: &dyn TransactionSender,
+    ) -> anyhow::Result<Vec<Signature>>;
 }
 
 #[derive(Debug)]
-pub struct LiquidationParams {}
+pub struct LiquidationParams {
+    pub liquidatee: Pubkey,
+    pub liab_bank: Pubkey,
+    pub liab_mint: Pubkey,
+    pub liab_amount: u64,
+    pub asset_bank: Pubkey,
+    // The collateral seized for repaying the liability.
+    pub asset_amount: u64,
+    pub repay_source: RepaySource,
+    // The Jito tip of the bundle, in lamports, when sent as one.
+    pub tip_lamports: u64,
+    // The swap of the seized collateral into the liability mint, within the flashloan.
+    pub collateral_swap: Option<SwapInstructions>,
+    // The accounts as they were when the liquidation was decided, for the event log.
+    pub decision_accounts: DecisionAccounts,
+}
+
+impl LiquidationParams {
+    /// Resizes the repaid liability, seizing proportionally less or more collateral.
+    pub fn resize(&mut self, liab_amount: u64) {
+        if self.liab_amount > 0 {
+            self.asset_amount = (self.asset_amount as u128 * liab_amount as u128
+                / self.liab_amount as u128)
+                .min(u64::MAX as u128) as u64;
+        }
+        self.liab_amount = liab_amount;
+    }
+}
+
+/// The wallet signing the liquidations and the Marginfi account of the liquidator.
+pub struct Liquidator {
+    pub program_id: Pubkey,
+    pub wallet: Keypair,
+    pub account: Option<Pubkey>,
+    // Whether the liquidations the account cannot fund run within marginfi flashloans.
+    pub flashloans: bool,
+}
+
+impl Liquidator {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            program_id: config.marginfi_program_id,
+            wallet: config.wallet.insecure_clone(),
+            account: config.liquidator_account,
+            flashloans: config.flashloan_liquidations,
+        }
+    }
+}
 
 // TODO: create static reusable strategy objects instead of initializing them each time
 pub fn choose_liquidation_strategy(
     _account: &CachedMarginfiAccount,
-    _cache: &Arc<Cache>,
+    cache: &Arc<Cache>,
+    liquidator: &Arc<Liquidator>,
 ) -> anyhow::Result<impl LiquidationStrategy> {
     // For now, we'll just use the basic strategy
-    Ok(BasicLiquidationStrategy {})
+    Ok(BasicLiquidationStrategy {
+        cache: cache.clone(),
+        liquidator: liquidator.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event_log::test_util::create_decision_accounts;
+
+    #[test]
+    fn test_resize_liquidation_params() {
+        let mut params = LiquidationParams {
+            liquidatee: Pubkey::new_unique(),
+            liab_bank: Pubkey::new_unique(),
+            liab_mint: Pubkey::new_unique(),
+            liab_amount: 300,
+            asset_bank: Pubkey::new_unique(),
+            asset_amount: 900,
+            repay_source: RepaySource::default(),
+            tip_lamports: 0,
+            collateral_swap: None,
+            decision_accounts: create_decision_accounts(),
+        };
+        params.resize(100);
+        assert_eq!((params.liab_amount, params.asset_amount), (100, 300));
+
+        params.liab_amount = 0;
+        params.resize(50);
+        assert_eq!((params.liab_amount, params.asset_amount), (50, 300));
+    }
 }
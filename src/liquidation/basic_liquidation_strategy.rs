@@ -1,21 +1,56 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use fixed::types::I80F48;
 use log::debug;
+use marginfi::constants::{
+    LIQUIDATION_INSURANCE_FEE, LIQUIDATION_LIQUIDATOR_FEE, LIQUIDITY_VAULT_AUTHORITY_SEED,
+};
+use solana_sdk::{
+    compute_budget::ComputeBudgetInstruction,
+    hash::Hash,
+    instruction::{AccountMeta, Instruction},
+    message::{v0, VersionedMessage},
+    pubkey::Pubkey,
+    signature::Signature,
+    signer::Signer,
+    sysvar,
+    transaction::VersionedTransaction,
+};
 
 use crate::{
-    cache::marginfi_accounts::CachedMarginfiAccount,
-    liquidation::{CommsClient, LiquidationParams},
+    cache::{banks::CachedBank, marginfi_accounts::CachedMarginfiAccount, Cache},
+    common::{
+        MARGINFI_DEPOSIT_DISCRIMINATOR, MARGINFI_END_FLASHLOAN_DISCRIMINATOR,
+        MARGINFI_LIQUIDATE_DISCRIMINATOR, MARGINFI_REPAY_DISCRIMINATOR,
+        MARGINFI_START_FLASHLOAN_DISCRIMINATOR, MARGINFI_WITHDRAW_DISCRIMINATOR,
+        SPL_TOKEN_2022_PROGRAM_ID,
+    },
+    comms::TransactionSender,
+    liquidation::{
+        candidates::is_liquidatable,
+        event_log::DecisionAccounts,
+        repay::{repayable_amount, RepaySource},
+        sizing::{borrowable_amount, size_liquidation},
+        wallet::{associated_token_address, create_associated_token_account_idempotent},
+        CommsClient, LiquidationParams, Liquidator,
+    },
 };
 
 // Make sure to import or define the LiquidationStrategy trait
 use crate::liquidation::LiquidationStrategy;
 
-pub struct BasicLiquidationStrategy {}
+pub struct BasicLiquidationStrategy {
+    pub cache: Arc<Cache>,
+    pub liquidator: Arc<Liquidator>,
+}
 
 impl LiquidationStrategy for BasicLiquidationStrategy {
     fn prepare(
         &self,
-        _account: &CachedMarginfiAccount,
+        account: &CachedMarginfiAccount,
     ) -> anyhow::Result<Option<LiquidationParams>> {
-        debug!("Evaluating account {:?} for liquidation.", _account);
+        debug!("Evaluating account {:?} for liquidation.", account);
         /*
         1. Calc total account's  assets amount in USD.
         2. Calc total account's liab amount in USD.
@@ -27,15 +62,1017 @@ impl LiquidationStrategy for BasicLiquidationStrategy {
         6. Confirm that the liquidation profit in USD > the configured min liquidation profit.
         7. Create the LiquidationParams object.
         */
-        Ok(Some(LiquidationParams {}))
+        // The health cached on-chain is only updated with the account, missing the price moves.
+        let unix_timestamp = self.cache.get_extrapolated_clock()?.unix_timestamp;
+        if !self
+            .cache
+            .compute_health(account, unix_timestamp)
+            .is_some_and(is_liquidatable)
+        {
+            return Ok(None);
+        }
+        let decision_accounts = DecisionAccounts::capture(&self.cache, account)?;
+
+        // TODO: select the liability by its USD value once the prices are available
+        let Some(liab_balance) = account
+            ._positions()
+            .iter()
+            .find(|balance| I80F48::from(balance.liability_shares) > I80F48::ZERO)
+        else {
+            return Ok(None);
+        };
+
+        let liab_bank = self.cache.banks.get_bank(&liab_balance.bank_pk)?;
+        let liab_amount = liab_bank
+            .liability_amount(liab_balance.liability_shares.into())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Failed to compute the liability amount of {} in the Bank {}",
+                    account.address(),
+                    liab_balance.bank_pk
+                )
+            })?;
+
+        let Some(liab_price) = self.cache.get_bank_price(&liab_bank, unix_timestamp) else {
+            debug!(
+                "Skipping {}: no valid price for the liability Bank {}",
+                account.address(),
+                liab_bank.address
+            );
+            return Ok(None);
+        };
+
+        // The collateral of the largest value, as long as it is priced.
+        let mut collateral: Option<(CachedBank, u64, I80F48, I80F48)> = None;
+        for balance in account._positions() {
+            if I80F48::from(balance.asset_shares) <= I80F48::ZERO {
+                continue;
+            }
+            let asset_bank = self.cache.banks.get_bank(&balance.bank_pk)?;
+            let Some(asset_price) = self.cache.get_bank_price(&asset_bank, unix_timestamp) else {
+                continue;
+            };
+            let Some(deposited) = asset_bank.asset_amount(balance.asset_shares.into()) else {
+                continue;
+            };
+            let Some(value) = token_value(deposited, asset_bank.mint_decimals(), asset_price)
+            else {
+                continue;
+            };
+            if collateral
+                .as_ref()
+                .is_none_or(|(_, _, _, largest)| value > *largest)
+            {
+                collateral = Some((asset_bank, deposited, asset_price, value));
+            }
+        }
+        let Some((asset_bank, deposited, asset_price, _)) = collateral else {
+            debug!("Skipping {}: no priced collateral", account.address());
+            return Ok(None);
+        };
+
+        // The largest liability amount the program accepts, the collateral deposit covers and the liquidator
+        // can repay.
+        let capital = self.liquidator_capital(&liab_bank, unix_timestamp)?;
+        let seized = |liab_amount: u64| {
+            collateral_amount(
+                liab_amount,
+                &liab_bank,
+                liab_price,
+                &asset_bank,
+                asset_price,
+            )
+            .filter(|asset_amount| *asset_amount <= deposited)
+        };
+        let Some(liab_amount) = size_liquidation(
+            account._positions(),
+            |address| self.cache.get_priced_bank(address, unix_timestamp),
+            &asset_bank.address,
+            &liab_bank.address,
+            liab_amount.min(capital),
+            seized,
+            unix_timestamp,
+        ) else {
+            debug!(
+                "Skipping {}: no liquidation size is accepted by the program within the capital of {} of the liquidator",
+                account.address(),
+                capital
+            );
+            return Ok(None);
+        };
+        let asset_amount = seized(liab_amount).ok_or_else(|| {
+            anyhow!(
+                "Failed to compute the collateral amount of {} in the Bank {}",
+                account.address(),
+                asset_bank.address
+            )
+        })?;
+
+        Ok(Some(LiquidationParams {
+            liquidatee: account.address(),
+            liab_bank: liab_balance.bank_pk,
+            liab_mint: *liab_bank.mint(),
+            liab_amount,
+            asset_bank: asset_bank.address,
+            asset_amount,
+            repay_source: RepaySource::default(),
+            tip_lamports: 0,
+            collateral_swap: None,
+            decision_accounts,
+        }))
+    }
+
+    fn build_transactions(
+        &self,
+        liquidation_params: &LiquidationParams,
+        recent_blockhash: Hash,
+        compute_unit_price: u64,
+        compute_unit_limit: u32,
+    ) -> anyhow::Result<Vec<VersionedTransaction>> {
+        let liquidator_account = self.liquidator.account.ok_or_else(|| {
+            anyhow!(
+                "Cannot liquidate {} without a liquidator account",
+                liquidation_params.liquidatee
+            )
+        })?;
+        let liquidator = self
+            .cache
+            .marginfi_accounts
+            .get_account(&liquidator_account)?;
+        let liquidatee = self
+            .cache
+            .marginfi_accounts
+            .get_account(&liquidation_params.liquidatee)?;
+        let asset_bank = self.cache.banks.get_bank(&liquidation_params.asset_bank)?;
+        let liab_bank = self.cache.banks.get_bank(&liquidation_params.liab_bank)?;
+        let liab_mint = self
+            .cache
+            .mints
+            .get(&liquidation_params.liab_mint)?
+            .ok_or_else(|| anyhow!("Mint {} not found in cache", liquidation_params.liab_mint))?;
+        let token_program = *liab_mint.token_program();
+        let signer = self.liquidator.wallet.pubkey();
+
+        let mut remaining_accounts = Vec::new();
+        if token_program == SPL_TOKEN_2022_PROGRAM_ID {
+            remaining_accounts.push(AccountMeta::new_readonly(*liab_bank.mint(), false));
+        }
+        for bank in [&asset_bank, &liab_bank] {
+            remaining_accounts.extend(
+                bank.oracle_addresses()
+                    .iter()
+                    .map(|oracle| AccountMeta::new_readonly(*oracle, false)),
+            );
+        }
+        // The liquidator takes on the liability and receives the collateral.
+        let liquidator_banks = observed_banks(
+            liquidator.bank_addresses(),
+            &[asset_bank.address, liab_bank.address],
+        );
+        remaining_accounts.extend(self.observation_accounts(&liquidator_banks)?);
+        remaining_accounts
+            .extend(self.observation_accounts(&observed_banks(liquidatee.bank_addresses(), &[]))?);
+
+        let liquidate = liquidate_instruction(
+            &self.liquidator.program_id,
+            &signer,
+            &liquidator_account,
+            liquidation_params,
+            &liab_bank,
+            &token_program,
+            remaining_accounts,
+        );
+
+        let mut instructions = vec![
+            ComputeBudgetInstruction::set_compute_unit_limit(compute_unit_limit),
+            ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price),
+        ];
+        match &liquidation_params.repay_source {
+            RepaySource::LiquidatorAccount => instructions.push(liquidate),
+            RepaySource::WalletDeposit {
+                token_account,
+                amount,
+            } => {
+                instructions.push(deposit_instruction(
+                    &self.liquidator.program_id,
+                    &signer,
+                    &liquidator_account,
+                    &liab_bank,
+                    token_account,
+                    *amount,
+                    &token_program,
+                ));
+                instructions.push(liquidate);
+            }
+            RepaySource::Flashloan { repay_amount } => {
+                let first_index = instructions.len();
+                instructions.extend(self.flashloan_instructions(
+                    liquidation_params,
+                    (&liquidator_account, liquidator.bank_addresses()),
+                    (&asset_bank, &liab_bank),
+                    liquidate,
+                    *repay_amount,
+                    first_index,
+                )?);
+            }
+        }
+
+        let message = v0::Message::try_compile(
+            &signer,
+            &instructions,
+            &self.cache.luts.get_all()?,
+            recent_blockhash,
+        )
+        .map_err(|e| {
+            anyhow!(
+                "Failed to compile the liquidation of {}: {}",
+                liquidation_params.liquidatee,
+                e
+            )
+        })?;
+        let transaction = VersionedTransaction::try_new(
+            VersionedMessage::V0(message),
+            &[&self.liquidator.wallet],
+        )
+        .map_err(|e| {
+            anyhow!(
+                "Failed to sign the liquidation of {}: {}",
+                liquidation_params.liquidatee,
+                e
+            )
+        })?;
+        Ok(vec![transaction])
     }
 
     fn liquidate<T: CommsClient>(
         &self,
-        liquidation_params: LiquidationParams,
+        liquidation_params: &LiquidationParams,
+        transactions: &[VersionedTransaction],
         _comms_client: &T,
-    ) -> anyhow::Result<()> {
-        debug!("Liquidating {:?}", liquidation_params);
-        Ok(())
+        transaction_sender: &dyn TransactionSender,
+    ) -> anyhow::Result<Vec<Signature>> {
+        debug!(
+            "Liquidating {} by repaying {} to the Bank {} for {} of the Bank {}",
+            liquidation_params.liquidatee,
+            liquidation_params.liab_amount,
+            liquidation_params.liab_bank,
+            liquidation_params.asset_amount,
+            liquidation_params.asset_bank
+        );
+        if let RepaySource::WalletDeposit {
+            token_account,
+            amount,
+        } = &liquidation_params.repay_source
+        {
+            debug!(
+                "Depositing {} of the mint {} from the wallet token account {} before liquidating",
+                amount, liquidation_params.liab_mint, token_account
+            );
+        }
+        if let RepaySource::Flashloan { repay_amount } = &liquidation_params.repay_source {
+            debug!(
+                "Liquidating within a flashloan, repaying {} of the mint {} with the swapped collateral",
+                repay_amount, liquidation_params.liab_mint
+            );
+        }
+        transaction_sender.send_transactions(transactions, liquidation_params.tip_lamports)
+    }
+}
+
+impl BasicLiquidationStrategy {
+    // The liability amount the liquidator can repay: the amount whose liquidator liability the borrowing
+    // capacity of its account covers when it has any, as the repay source prefers it, otherwise the wallet
+    // balance of the liability mint. Unbounded with the flashloans, which repay the liability with the seized
+    // collateral itself.
+    fn liquidator_capital(
+        &self,
+        liab_bank: &CachedBank,
+        unix_timestamp: i64,
+    ) -> anyhow::Result<u64> {
+        if self.liquidator.flashloans {
+            return Ok(u64::MAX);
+        }
+        if let Some(liquidator_account) = self.liquidator.account {
+            let account = self
+                .cache
+                .marginfi_accounts
+                .get_account(&liquidator_account)?;
+            let borrowable = self
+                .cache
+                .compute_account_health(&account, unix_timestamp)
+                .zip(
+                    self.cache
+                        .get_priced_bank(&liab_bank.address, unix_timestamp),
+                )
+                .and_then(|(health, (_, liab_price))| {
+                    borrowable_amount(health.initial.free_collateral(), liab_bank, &liab_price)
+                });
+            if let Some(borrowable) = borrowable.filter(|amount| *amount > 0) {
+                return Ok(repayable_amount(borrowable).unwrap_or(0));
+            }
+        }
+        Ok(self
+            .cache
+            .wallet_token_accounts
+            .by_mint()?
+            .get(liab_bank.mint())
+            .and_then(|token_account| repayable_amount(token_account.amount))
+            .unwrap_or(0))
+    }
+
+    // The liquidation within a flashloan, which the liquidator account must start without a balance in the
+    // asset and the liability Banks: the collateral account of the wallet is created if missing, the swap set
+    // up, then the liquidation is followed by the withdrawal of the whole collateral, its swap into the
+    // liability mint and the repayment of the whole liability, the flashloan checking the health of the
+    // account once both balances are closed.
+    fn flashloan_instructions(
+        &self,
+        liquidation_params: &LiquidationParams,
+        (liquidator_account, liquidator_banks): (&Pubkey, Vec<Pubkey>),
+        (asset_bank, liab_bank): (&CachedBank, &CachedBank),
+        liquidate: Instruction,
+        repay_amount: u64,
+        first_index: usize,
+    ) -> anyhow::Result<Vec<Instruction>> {
+        let swap = liquidation_params.collateral_swap.as_ref().ok_or_else(|| {
+            anyhow!(
+                "Cannot liquidate {} within a flashloan without the collateral swap",
+                liquidation_params.liquidatee
+            )
+        })?;
+        let program_id = &self.liquidator.program_id;
+        let signer = self.liquidator.wallet.pubkey();
+        let token_program = |bank: &CachedBank| -> anyhow::Result<Pubkey> {
+            self.cache
+                .mints
+                .get(bank.mint())?
+                .map(|mint| *mint.token_program())
+                .ok_or_else(|| anyhow!("Mint {} not found in cache", bank.mint()))
+        };
+        let asset_token_program = token_program(asset_bank)?;
+        let liab_token_program = token_program(liab_bank)?;
+
+        let mut instructions = vec![create_associated_token_account_idempotent(
+            &signer,
+            &signer,
+            asset_bank.mint(),
+            &asset_token_program,
+        )];
+        instructions.extend(swap.setup.iter().cloned());
+        // The flashloan ends after the liquidation, the withdrawal, the swap and the repayment.
+        let end_index = first_index + instructions.len() + 5;
+        instructions.push(start_flashloan_instruction(
+            program_id,
+            &signer,
+            liquidator_account,
+            end_index as u64,
+        ));
+        instructions.push(liquidate);
+        instructions.push(withdraw_all_instruction(
+            program_id,
+            &signer,
+            liquidator_account,
+            asset_bank,
+            &associated_token_address(&signer, asset_bank.mint(), &asset_token_program),
+            &asset_token_program,
+        ));
+        instructions.push(swap.swap.clone());
+        instructions.push(repay_all_instruction(
+            program_id,
+            &signer,
+            liquidator_account,
+            liab_bank,
+            &associated_token_address(&signer, liab_bank.mint(), &liab_token_program),
+            repay_amount,
+            &liab_token_program,
+        ));
+        instructions.push(end_flashloan_instruction(
+            program_id,
+            &signer,
+            liquidator_account,
+            self.observation_accounts(&observed_banks(liquidator_banks, &[]))?,
+        ));
+        instructions.extend(swap.cleanup.iter().cloned());
+        Ok(instructions)
+    }
+
+    // The Banks with their Oracles, for the program to check the health of the account.
+    fn observation_accounts(&self, banks: &[Pubkey]) -> anyhow::Result<Vec<AccountMeta>> {
+        let mut accounts = Vec::new();
+        for address in banks {
+            let bank = self.cache.banks.get_bank(address)?;
+            accounts.push(AccountMeta::new_readonly(*address, false));
+            accounts.extend(
+                bank.oracle_addresses()
+                    .iter()
+                    .map(|oracle| AccountMeta::new_readonly(*oracle, false)),
+            );
+        }
+        Ok(accounts)
+    }
+}
+
+/// The Banks of the account balances once the new Banks are added, in the order the program keeps the
+/// balances: sorted by the Bank address, descending.
+fn observed_banks(mut banks: Vec<Pubkey>, new_banks: &[Pubkey]) -> Vec<Pubkey> {
+    banks.extend_from_slice(new_banks);
+    banks.sort_unstable_by(|a, b| b.cmp(a));
+    banks.dedup();
+    banks
+}
+
+// The value of the native token amount at the price.
+fn token_value(amount: u64, decimals: u8, price: I80F48) -> Option<I80F48> {
+    I80F48::from_num(amount)
+        .checked_div(I80F48::from_num(10u64.checked_pow(decimals as u32)?))?
+        .checked_mul(price)
+}
+
+/// The collateral amount to seize for repaying the liability amount: the program credits the collateral to
+/// the liquidatee at its price discounted by the liquidator and the insurance fees.
+fn collateral_amount(
+    liab_amount: u64,
+    liab_bank: &CachedBank,
+    liab_price: I80F48,
+    asset_bank: &CachedBank,
+    asset_price: I80F48,
+) -> Option<u64> {
+    let discounted_price = asset_price.checked_mul(
+        I80F48::ONE
+            .checked_sub(LIQUIDATION_LIQUIDATOR_FEE)?
+            .checked_sub(LIQUIDATION_INSURANCE_FEE)?,
+    )?;
+    token_value(liab_amount, liab_bank.mint_decimals(), liab_price)?
+        .checked_mul(I80F48::from_num(
+            10u64.checked_pow(asset_bank.mint_decimals() as u32)?,
+        ))?
+        .checked_div(discounted_price)?
+        .checked_ceil()?
+        .checked_to_num::<u64>()
+}
+
+/// The `lending_account_deposit` instruction depositing the wallet tokens into the liquidator account.
+fn deposit_instruction(
+    program_id: &Pubkey,
+    signer: &Pubkey,
+    liquidator_account: &Pubkey,
+    bank: &CachedBank,
+    token_account: &Pubkey,
+    amount: u64,
+    token_program: &Pubkey,
+) -> Instruction {
+    let mut data = MARGINFI_DEPOSIT_DISCRIMINATOR.to_vec();
+    data.extend_from_slice(&amount.to_le_bytes());
+    // No deposit_up_to_limit.
+    data.push(0);
+    wallet_to_bank_instruction(
+        program_id,
+        signer,
+        liquidator_account,
+        bank,
+        token_account,
+        token_program,
+        data,
+    )
+}
+
+/// The `lending_account_repay` instruction repaying the whole liability of the liquidator account in the Bank
+/// with the wallet tokens, closing its balance.
+fn repay_all_instruction(
+    program_id: &Pubkey,
+    signer: &Pubkey,
+    liquidator_account: &Pubkey,
+    bank: &CachedBank,
+    token_account: &Pubkey,
+    amount: u64,
+    token_program: &Pubkey,
+) -> Instruction {
+    let mut data = MARGINFI_REPAY_DISCRIMINATOR.to_vec();
+    data.extend_from_slice(&amount.to_le_bytes());
+    // Some(true) repay_all.
+    data.extend_from_slice(&[1, 1]);
+    wallet_to_bank_instruction(
+        program_id,
+        signer,
+        liquidator_account,
+        bank,
+        token_account,
+        token_program,
+        data,
+    )
+}
+
+// The deposit and the repay instructions share their accounts: the wallet tokens move into the liquidity
+// vault of the Bank.
+fn wallet_to_bank_instruction(
+    program_id: &Pubkey,
+    signer: &Pubkey,
+    liquidator_account: &Pubkey,
+    bank: &CachedBank,
+    token_account: &Pubkey,
+    token_program: &Pubkey,
+    data: Vec<u8>,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*bank.group(), false),
+        AccountMeta::new(*liquidator_account, false),
+        AccountMeta::new_readonly(*signer, true),
+        AccountMeta::new(bank.address, false),
+        AccountMeta::new(*token_account, false),
+        AccountMeta::new(*bank.liquidity_vault(), false),
+        AccountMeta::new_readonly(*token_program, false),
+    ];
+    if *token_program == SPL_TOKEN_2022_PROGRAM_ID {
+        accounts.push(AccountMeta::new_readonly(*bank.mint(), false));
+    }
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
+
+/// The `lending_account_withdraw` instruction withdrawing the whole balance of the liquidator account in the
+/// Bank to the wallet token account, closing it. Within a flashloan, the program checks the health of the
+/// account at its end only, so no Bank is observed.
+fn withdraw_all_instruction(
+    program_id: &Pubkey,
+    signer: &Pubkey,
+    liquidator_account: &Pubkey,
+    bank: &CachedBank,
+    token_account: &Pubkey,
+    token_program: &Pubkey,
+) -> Instruction {
+    let (vault_authority, _) = Pubkey::find_program_address(
+        &[
+            LIQUIDITY_VAULT_AUTHORITY_SEED.as_bytes(),
+            bank.address.as_ref(),
+        ],
+        program_id,
+    );
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*bank.group(), false),
+        AccountMeta::new(*liquidator_account, false),
+        AccountMeta::new_readonly(*signer, true),
+        AccountMeta::new(bank.address, false),
+        AccountMeta::new(*token_account, false),
+        AccountMeta::new(vault_authority, false),
+        AccountMeta::new(*bank.liquidity_vault(), false),
+        AccountMeta::new_readonly(*token_program, false),
+    ];
+    if *token_program == SPL_TOKEN_2022_PROGRAM_ID {
+        accounts.push(AccountMeta::new_readonly(*bank.mint(), false));
+    }
+
+    let mut data = MARGINFI_WITHDRAW_DISCRIMINATOR.to_vec();
+    // The amount is ignored with Some(true) withdraw_all.
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data.extend_from_slice(&[1, 1]);
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
+
+/// The `lending_account_start_flashloan` instruction, whose flashloan ends with the instruction at the end
+/// index of the transaction.
+fn start_flashloan_instruction(
+    program_id: &Pubkey,
+    signer: &Pubkey,
+    liquidator_account: &Pubkey,
+    end_index: u64,
+) -> Instruction {
+    let mut data = MARGINFI_START_FLASHLOAN_DISCRIMINATOR.to_vec();
+    data.extend_from_slice(&end_index.to_le_bytes());
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*liquidator_account, false),
+            AccountMeta::new_readonly(*signer, true),
+            AccountMeta::new_readonly(sysvar::instructions::ID, false),
+        ],
+        data,
+    }
+}
+
+/// The `lending_account_end_flashloan` instruction, followed by the Banks and Oracles of the liquidator for
+/// the program to check its health.
+fn end_flashloan_instruction(
+    program_id: &Pubkey,
+    signer: &Pubkey,
+    liquidator_account: &Pubkey,
+    observation_accounts: Vec<AccountMeta>,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*liquidator_account, false),
+        AccountMeta::new_readonly(*signer, true),
+    ];
+    accounts.extend(observation_accounts);
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: MARGINFI_END_FLASHLOAN_DISCRIMINATOR.to_vec(),
+    }
+}
+
+/// The `lending_account_liquidate` instruction, followed by the remaining accounts: the liability mint for
+/// Token-2022, the Oracles of the asset and the liability Banks, then the Banks and Oracles of the liquidator
+/// and of the liquidatee.
+fn liquidate_instruction(
+    program_id: &Pubkey,
+    signer: &Pubkey,
+    liquidator_account: &Pubkey,
+    liquidation_params: &LiquidationParams,
+    liab_bank: &CachedBank,
+    token_program: &Pubkey,
+    remaining_accounts: Vec<AccountMeta>,
+) -> Instruction {
+    let (vault_authority, _) = Pubkey::find_program_address(
+        &[
+            LIQUIDITY_VAULT_AUTHORITY_SEED.as_bytes(),
+            liab_bank.address.as_ref(),
+        ],
+        program_id,
+    );
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*liab_bank.group(), false),
+        AccountMeta::new(liquidation_params.asset_bank, false),
+        AccountMeta::new(liab_bank.address, false),
+        AccountMeta::new(*liquidator_account, false),
+        AccountMeta::new_readonly(*signer, true),
+        AccountMeta::new(liquidation_params.liquidatee, false),
+        AccountMeta::new_readonly(vault_authority, false),
+        AccountMeta::new(*liab_bank.liquidity_vault(), false),
+        AccountMeta::new(*liab_bank.insurance_vault(), false),
+        AccountMeta::new_readonly(*token_program, false),
+    ];
+    accounts.extend(remaining_accounts);
+
+    let mut data = MARGINFI_LIQUIDATE_DISCRIMINATOR.to_vec();
+    data.extend_from_slice(&liquidation_params.asset_amount.to_le_bytes());
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::signature::Keypair;
+
+    use super::*;
+    use crate::{
+        cache::{
+            banks::test_util::create_bank_with_oracles,
+            marginfi_accounts::test_util::{create_balance, create_marginfi_account},
+            mints::test_util::create_mint_account,
+            test_util::generate_test_clock,
+        },
+        common::{ASSOCIATED_TOKEN_PROGRAM_ID, SPL_TOKEN_PROGRAM_ID},
+        liquidation::{
+            event_log::test_util::create_decision_accounts, simulation::MAX_COMPUTE_UNIT_LIMIT,
+            swap::test_util::create_swap_instructions,
+        },
+    };
+
+    fn create_strategy(liquidator_account: Option<Pubkey>) -> BasicLiquidationStrategy {
+        BasicLiquidationStrategy {
+            cache: Arc::new(Cache::new(generate_test_clock(1))),
+            liquidator: Arc::new(Liquidator {
+                program_id: Pubkey::new_unique(),
+                wallet: Keypair::new(),
+                account: liquidator_account,
+                flashloans: false,
+            }),
+        }
+    }
+
+    fn add_bank(cache: &Cache, oracle: Pubkey) -> CachedBank {
+        let mut bank = create_bank_with_oracles(vec![oracle]);
+        bank.liquidity_vault = Pubkey::new_unique();
+        bank.insurance_vault = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        cache.banks.update(1, address, &bank).unwrap();
+        cache.banks.get_bank(&address).unwrap()
+    }
+
+    fn readonly(address: Pubkey) -> AccountMeta {
+        AccountMeta::new_readonly(address, false)
+    }
+
+    #[test]
+    fn test_observed_banks() {
+        let [a, b, c] = [
+            Pubkey::new_from_array([1; 32]),
+            Pubkey::new_from_array([2; 32]),
+            Pubkey::new_from_array([3; 32]),
+        ];
+        assert_eq!(observed_banks(vec![a, c], &[b, c]), vec![c, b, a]);
+        assert_eq!(observed_banks(vec![], &[]), Vec::<Pubkey>::new());
+    }
+
+    #[test]
+    fn test_collateral_amount() {
+        let cache = Cache::new(generate_test_clock(1));
+        // 6 decimals, see create_bank_with_oracles.
+        let liab_bank = add_bank(&cache, Pubkey::new_unique());
+        let asset_bank = add_bank(&cache, Pubkey::new_unique());
+
+        // 95 USD of liability repaid with the collateral at 2 USD discounted by 5%, give or take the
+        // fixed-point rounding of the fees.
+        let amount = collateral_amount(
+            95_000_000,
+            &liab_bank,
+            I80F48::ONE,
+            &asset_bank,
+            I80F48::from_num(2),
+        )
+        .unwrap();
+        assert!((50_000_000..=50_000_001).contains(&amount));
+        assert_eq!(
+            collateral_amount(1, &liab_bank, I80F48::ONE, &asset_bank, I80F48::ZERO),
+            None
+        );
+    }
+
+    #[test]
+    fn test_build_transactions() {
+        let existing_bank = Pubkey::new_unique();
+        let liquidator_account = Pubkey::new_unique();
+        let strategy = create_strategy(Some(liquidator_account));
+        let cache = &strategy.cache;
+        let (asset_oracle, liab_oracle, existing_oracle) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let asset_bank = add_bank(cache, asset_oracle);
+        let liab_bank = add_bank(cache, liab_oracle);
+        cache
+            .banks
+            .update(
+                1,
+                existing_bank,
+                &create_bank_with_oracles(vec![existing_oracle]),
+            )
+            .unwrap();
+        cache
+            .mints
+            .update(
+                *liab_bank.mint(),
+                &create_mint_account(SPL_TOKEN_PROGRAM_ID, 6, None),
+            )
+            .unwrap();
+
+        let liquidatee = Pubkey::new_unique();
+        cache
+            .marginfi_accounts
+            .update(
+                1,
+                liquidatee,
+                create_marginfi_account(
+                    *liab_bank.group(),
+                    vec![
+                        create_balance(asset_bank.address, 100, 0),
+                        create_balance(liab_bank.address, 0, 50),
+                    ],
+                ),
+            )
+            .unwrap();
+        cache
+            .marginfi_accounts
+            .update(
+                1,
+                liquidator_account,
+                create_marginfi_account(
+                    *liab_bank.group(),
+                    vec![create_balance(existing_bank, 10, 0)],
+                ),
+            )
+            .unwrap();
+
+        let params = LiquidationParams {
+            liquidatee,
+            liab_bank: liab_bank.address,
+            liab_mint: *liab_bank.mint(),
+            liab_amount: 50,
+            asset_bank: asset_bank.address,
+            asset_amount: 42,
+            repay_source: RepaySource::WalletDeposit {
+                token_account: Pubkey::new_unique(),
+                amount: 50,
+            },
+            tip_lamports: 0,
+            collateral_swap: None,
+            decision_accounts: create_decision_accounts(),
+        };
+        let blockhash = Hash::new_unique();
+        let transactions = strategy
+            .build_transactions(&params, blockhash, 5_000, 150_000)
+            .unwrap();
+        assert_eq!(transactions.len(), 1);
+        let transaction = &transactions[0];
+        assert_eq!(transaction.message.recent_blockhash(), &blockhash);
+        assert_eq!(
+            transaction.message.static_account_keys()[0],
+            strategy.liquidator.wallet.pubkey()
+        );
+        assert!(transaction.verify_with_results().iter().all(|valid| *valid));
+        // The compute budget, the deposit and the liquidation.
+        assert_eq!(transaction.message.instructions().len(), 4);
+        assert_eq!(
+            transaction.message.instructions()[0].data,
+            ComputeBudgetInstruction::set_compute_unit_limit(150_000).data
+        );
+        assert_eq!(
+            transaction.message.instructions()[1].data,
+            ComputeBudgetInstruction::set_compute_unit_price(5_000).data
+        );
+
+        let signer = strategy.liquidator.wallet.pubkey();
+        let mut remaining_accounts = vec![readonly(asset_oracle), readonly(liab_oracle)];
+        for bank in observed_banks(
+            vec![existing_bank],
+            &[asset_bank.address, liab_bank.address],
+        ) {
+            remaining_accounts.push(readonly(bank));
+            remaining_accounts.push(readonly(
+                cache.banks.get_bank(&bank).unwrap().oracle_addresses()[0],
+            ));
+        }
+        for bank in observed_banks(vec![asset_bank.address, liab_bank.address], &[]) {
+            remaining_accounts.push(readonly(bank));
+            remaining_accounts.push(readonly(
+                cache.banks.get_bank(&bank).unwrap().oracle_addresses()[0],
+            ));
+        }
+        let instruction = liquidate_instruction(
+            &strategy.liquidator.program_id,
+            &signer,
+            &liquidator_account,
+            &params,
+            &liab_bank,
+            &SPL_TOKEN_PROGRAM_ID,
+            remaining_accounts,
+        );
+        assert_eq!(instruction.accounts.len(), 10 + 2 + 6 + 4);
+        assert_eq!(instruction.accounts[1].pubkey, asset_bank.address);
+        assert_eq!(instruction.accounts[2].pubkey, liab_bank.address);
+        assert_eq!(
+            instruction.accounts[4],
+            AccountMeta::new_readonly(signer, true)
+        );
+        assert_eq!(instruction.accounts[5].pubkey, liquidatee);
+        assert_eq!(instruction.data[..8], MARGINFI_LIQUIDATE_DISCRIMINATOR);
+        assert_eq!(instruction.data[8..], 42u64.to_le_bytes());
+
+        // The compiled liquidation references the same accounts, in order.
+        let keys = transaction.message.static_account_keys();
+        let compiled = &transaction.message.instructions()[3];
+        assert_eq!(
+            keys[compiled.program_id_index as usize],
+            strategy.liquidator.program_id
+        );
+        assert_eq!(compiled.data, instruction.data);
+        assert_eq!(
+            compiled
+                .accounts
+                .iter()
+                .map(|index| keys[*index as usize])
+                .collect::<Vec<_>>(),
+            instruction
+                .accounts
+                .iter()
+                .map(|account| account.pubkey)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_build_flashloan_transactions() {
+        let liquidator_account = Pubkey::new_unique();
+        let strategy = create_strategy(Some(liquidator_account));
+        let cache = &strategy.cache;
+        let asset_bank = add_bank(cache, Pubkey::new_unique());
+        let liab_bank = add_bank(cache, Pubkey::new_unique());
+        for bank in [&asset_bank, &liab_bank] {
+            cache
+                .mints
+                .update(
+                    *bank.mint(),
+                    &create_mint_account(SPL_TOKEN_PROGRAM_ID, 6, None),
+                )
+                .unwrap();
+        }
+        let liquidatee = Pubkey::new_unique();
+        for (address, balances) in [
+            (
+                liquidatee,
+                vec![
+                    create_balance(asset_bank.address, 100, 0),
+                    create_balance(liab_bank.address, 0, 50),
+                ],
+            ),
+            (liquidator_account, vec![]),
+        ] {
+            cache
+                .marginfi_accounts
+                .update(
+                    1,
+                    address,
+                    create_marginfi_account(*liab_bank.group(), balances),
+                )
+                .unwrap();
+        }
+
+        let signer = strategy.liquidator.wallet.pubkey();
+        let swap = create_swap_instructions(&signer);
+        let mut params = LiquidationParams {
+            liquidatee,
+            liab_bank: liab_bank.address,
+            liab_mint: *liab_bank.mint(),
+            liab_amount: 50,
+            asset_bank: asset_bank.address,
+            asset_amount: 42,
+            repay_source: RepaySource::Flashloan { repay_amount: 52 },
+            tip_lamports: 0,
+            collateral_swap: None,
+            decision_accounts: create_decision_accounts(),
+        };
+        let err = strategy
+            .build_transactions(&params, Hash::new_unique(), 0, MAX_COMPUTE_UNIT_LIMIT)
+            .unwrap_err();
+        assert!(err.to_string().contains("without the collateral swap"));
+
+        params.collateral_swap = Some(swap.clone());
+        let transactions = strategy
+            .build_transactions(&params, Hash::new_unique(), 0, MAX_COMPUTE_UNIT_LIMIT)
+            .unwrap();
+        let message = &transactions[0].message;
+        let keys = message.static_account_keys();
+        let instructions = message.instructions();
+        // The compute budget, the collateral account, the flashloan start, the liquidation, the withdrawal,
+        // the swap, the repayment and the flashloan end.
+        assert_eq!(instructions.len(), 9);
+        assert_eq!(
+            keys[instructions[2].program_id_index as usize],
+            ASSOCIATED_TOKEN_PROGRAM_ID
+        );
+        let discriminators = instructions[3..]
+            .iter()
+            .filter(|instruction| {
+                keys[instruction.program_id_index as usize] == strategy.liquidator.program_id
+            })
+            .map(|instruction| instruction.data[..8].to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            discriminators,
+            vec![
+                MARGINFI_START_FLASHLOAN_DISCRIMINATOR.to_vec(),
+                MARGINFI_LIQUIDATE_DISCRIMINATOR.to_vec(),
+                MARGINFI_WITHDRAW_DISCRIMINATOR.to_vec(),
+                MARGINFI_REPAY_DISCRIMINATOR.to_vec(),
+                MARGINFI_END_FLASHLOAN_DISCRIMINATOR.to_vec(),
+            ]
+        );
+        // The flashloan ends with the last instruction.
+        assert_eq!(instructions[3].data[8..], 8u64.to_le_bytes());
+        assert_eq!(
+            instructions[7].data[8..],
+            [52u64.to_le_bytes().as_slice(), &[1, 1]].concat()
+        );
+        assert_eq!(instructions[6].data, swap.swap.data);
+        // The collateral is withdrawn to the account the swap takes it from.
+        let collateral_account =
+            associated_token_address(&signer, asset_bank.mint(), &SPL_TOKEN_PROGRAM_ID);
+        assert_eq!(
+            keys[instructions[5].accounts[4] as usize],
+            collateral_account
+        );
+        // The liquidator account has no balance left to observe.
+        assert_eq!(instructions[8].accounts.len(), 2);
+    }
+
+    #[test]
+    fn test_build_transactions_requires_liquidator_account() {
+        let strategy = create_strategy(None);
+        let params = LiquidationParams {
+            liquidatee: Pubkey::new_unique(),
+            liab_bank: Pubkey::new_unique(),
+            liab_mint: Pubkey::new_unique(),
+            liab_amount: 50,
+            asset_bank: Pubkey::new_unique(),
+            asset_amount: 42,
+            repay_source: RepaySource::default(),
+            tip_lamports: 0,
+            collateral_swap: None,
+            decision_accounts: create_decision_accounts(),
+        };
+        let err = strategy
+            .build_transactions(&params, Hash::new_unique(), 0, MAX_COMPUTE_UNIT_LIMIT)
+            .unwrap_err();
+        assert!(err.to_string().contains("without a liquidator account"));
     }
 }
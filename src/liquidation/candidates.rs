@@ -0,0 +1,405 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::cache::marginfi_accounts::INVALID_HEALTH;
+
+/// An account is a liquidation candidate once its maintenance health turns negative.
+pub fn is_liquidatable(health: i64) -> bool {
+    health != INVALID_HEALTH && health < 0
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CandidateAge {
+    actionable_since: Instant,
+    last_attempt: Option<Instant>,
+    starvation_alerted: bool,
+}
+
+impl CandidateAge {
+    fn new(now: Instant) -> Self {
+        Self {
+            actionable_since: now,
+            last_attempt: None,
+            starvation_alerted: false,
+        }
+    }
+
+    // Time since the candidate became actionable or was last attempted, whichever is later.
+    fn waiting_time(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_attempt.unwrap_or(self.actionable_since))
+    }
+}
+
+/// Tracks how long every liquidatable account has been waiting for an attempt, so that
+/// prioritization heuristics cannot starve valid (but less attractive) opportunities forever.
+pub struct CandidateTracker {
+    starvation_threshold: Duration,
+    candidates: HashMap<Pubkey, CandidateAge>,
+}
+
+impl CandidateTracker {
+    pub fn new(starvation_threshold: Duration) -> Self {
+        Self {
+            starvation_threshold,
+            candidates: HashMap::new(),
+        }
+    }
+
+    /// Registers the newly liquidatable accounts and forgets the ones that are no longer liquidatable,
+    /// returning the changes since the previous refresh.
+    pub fn refresh(
+        &mut self,
+        accounts_by_health: &HashMap<Pubkey, i64>,
+        now: Instant,
+    ) -> CandidateDiff {
+        let mut diff = CandidateDiff::default();
+        self.candidates.retain(|address, age| {
+            let liquidatable = accounts_by_health
+                .get(address)
+                .is_some_and(|&health| is_liquidatable(health));
+            if liquidatable {
+                diff.pending.push((
+                    *address,
+                    now.saturating_duration_since(age.actionable_since),
+                ));
+            } else {
+                diff.resolved.push(*address);
+            }
+            liquidatable
+        });
+
+        for (address, &health) in accounts_by_health {
+            if is_liquidatable(health) && !self.candidates.contains_key(address) {
+                self.candidates.insert(*address, CandidateAge::new(now));
+                diff.newly_liquidatable.push(*address);
+            }
+        }
+
+        diff.newly_liquidatable.sort();
+        diff.resolved.sort();
+        diff.pending
+            .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        diff
+    }
+
+    pub fn record_attempt(&mut self, address: &Pubkey, now: Instant) {
+        if let Some(age) = self.candidates.get_mut(address) {
+            age.last_attempt = Some(now);
+            age.starvation_alerted = false;
+        }
+    }
+
+    /// The time since the candidate became liquidatable.
+    pub fn liquidatable_for(&self, address: &Pubkey, now: Instant) -> Option<Duration> {
+        self.candidates
+            .get(address)
+            .map(|age| now.saturating_duration_since(age.actionable_since))
+    }
+
+    pub fn waiting_time(&self, address: &Pubkey, now: Instant) -> Option<Duration> {
+        self.candidates
+            .get(address)
+            .map(|age| age.waiting_time(now))
+    }
+
+    /// Returns the starving candidates, the longest waiting first.
+    pub fn starving(&self, now: Instant) -> Vec<Pubkey> {
+        let mut starving: Vec<(Pubkey, Duration)> = self
+            .candidates
+            .iter()
+            .map(|(address, age)| (*address, age.waiting_time(now)))
+            .filter(|(_, waiting)| *waiting >= self.starvation_threshold)
+            .collect();
+        starving.sort_by(|a, b| b.1.cmp(&a.1));
+        starving.into_iter().map(|(address, _)| address).collect()
+    }
+
+    /// Moves the starving candidates to the front of the given processing order and alerts
+    /// (once per starvation period) about each of them.
+    pub fn prioritize(&mut self, ordered: Vec<Pubkey>, now: Instant) -> Vec<Pubkey> {
+        let starving = self.starving(now);
+        if starving.is_empty() {
+            return ordered;
+        }
+
+        for address in &starving {
+            if let Some(age) = self.candidates.get_mut(address) {
+                if !age.starvation_alerted {
+                    warn!(
+                        "Liquidation candidate {} is starving: actionable for {:?} without an attempt",
+                        address,
+                        age.waiting_time(now)
+                    );
+                    age.starvation_alerted = true;
+                }
+            }
+        }
+
+        let mut prioritized = starving.clone();
+        prioritized.extend(ordered.into_iter().filter(|a| !starving.contains(a)));
+        prioritized
+    }
+
+    pub fn count(&self) -> usize {
+        self.candidates.len()
+    }
+}
+
+/// The changes of the liquidatable set between two evaluation cycles.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CandidateDiff {
+    pub newly_liquidatable: Vec<Pubkey>,
+    pub resolved: Vec<Pubkey>,
+    /// The candidates still liquidatable, with the time since they became liquidatable, the oldest first.
+    pub pending: Vec<(Pubkey, Duration)>,
+}
+
+impl CandidateDiff {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "newly_liquidatable": self
+                .newly_liquidatable
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>(),
+            "resolved": self
+                .resolved
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>(),
+            "pending": self
+                .pending
+                .iter()
+                .map(|(address, age)| json!({ "address": address.to_string(), "age_sec": age.as_secs() }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl fmt::Display for CandidateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} newly liquidatable, {} resolved, {} pending",
+            self.newly_liquidatable.len(),
+            self.resolved.len(),
+            self.pending.len()
+        )?;
+        if let Some((address, age)) = self.pending.first() {
+            write!(f, " (the oldest {} for {:?})", address, age)?;
+        }
+        Ok(())
+    }
+}
+
+/// The diff of the latest evaluation cycle, shared with the API.
+#[derive(Default)]
+pub struct LatestCandidateDiff {
+    latest: Mutex<Option<(u64, CandidateDiff)>>,
+}
+
+impl LatestCandidateDiff {
+    pub fn publish(&self, diff: CandidateDiff) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        *self
+            .latest
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the latest candidate diff: {}", e))? =
+            Some((timestamp, diff));
+        Ok(())
+    }
+
+    /// The latest diff, null before the first evaluation cycle.
+    pub fn to_json(&self) -> Result<Value> {
+        Ok(
+            match self
+                .latest
+                .lock()
+                .map_err(|e| anyhow!("Failed to lock the latest candidate diff: {}", e))?
+                .as_ref()
+            {
+                Some((timestamp, diff)) => {
+                    let mut json = diff.to_json();
+                    json["timestamp_unix"] = json!(timestamp);
+                    json
+                }
+                None => Value::Null,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn test_is_liquidatable() {
+        assert!(is_liquidatable(-1));
+        assert!(!is_liquidatable(0));
+        assert!(!is_liquidatable(1));
+        assert!(!is_liquidatable(INVALID_HEALTH));
+    }
+
+    #[test]
+    fn test_refresh_tracks_only_liquidatable_accounts() {
+        let mut tracker = CandidateTracker::new(THRESHOLD);
+        let liquidatable = Pubkey::new_unique();
+        let healthy = Pubkey::new_unique();
+        let invalid = Pubkey::new_unique();
+        let accounts = HashMap::from([(liquidatable, -1), (healthy, 1), (invalid, INVALID_HEALTH)]);
+
+        tracker.refresh(&accounts, Instant::now());
+
+        assert_eq!(tracker.count(), 1);
+        assert!(tracker
+            .waiting_time(&liquidatable, Instant::now())
+            .is_some());
+        assert!(tracker.waiting_time(&healthy, Instant::now()).is_none());
+    }
+
+    #[test]
+    fn test_refresh_forgets_resolved_candidates() {
+        let mut tracker = CandidateTracker::new(THRESHOLD);
+        let address = Pubkey::new_unique();
+        let now = Instant::now();
+
+        tracker.refresh(&HashMap::from([(address, -1)]), now);
+        assert_eq!(tracker.count(), 1);
+
+        tracker.refresh(&HashMap::from([(address, 0)]), now);
+        assert_eq!(tracker.count(), 0);
+    }
+
+    #[test]
+    fn test_refresh_keeps_the_original_age() {
+        let mut tracker = CandidateTracker::new(THRESHOLD);
+        let address = Pubkey::new_unique();
+        let start = Instant::now();
+
+        tracker.refresh(&HashMap::from([(address, -1)]), start);
+        tracker.refresh(&HashMap::from([(address, -1)]), start + THRESHOLD);
+
+        assert_eq!(
+            tracker.waiting_time(&address, start + THRESHOLD),
+            Some(THRESHOLD)
+        );
+    }
+
+    #[test]
+    fn test_refresh_diff() {
+        let mut tracker = CandidateTracker::new(THRESHOLD);
+        let pending = Pubkey::new_unique();
+        let resolved = Pubkey::new_unique();
+        let new = Pubkey::new_unique();
+        let start = Instant::now();
+
+        let diff = tracker.refresh(&HashMap::from([(pending, -1), (resolved, -1)]), start);
+        assert_eq!(diff.newly_liquidatable.len(), 2);
+        assert!(diff.resolved.is_empty());
+        assert!(diff.pending.is_empty());
+
+        let diff = tracker.refresh(
+            &HashMap::from([(pending, -1), (resolved, 1), (new, -1)]),
+            start + THRESHOLD,
+        );
+        assert_eq!(
+            diff,
+            CandidateDiff {
+                newly_liquidatable: vec![new],
+                resolved: vec![resolved],
+                pending: vec![(pending, THRESHOLD)],
+            }
+        );
+        assert_eq!(diff.to_json()["pending"][0]["age_sec"], 30);
+    }
+
+    #[test]
+    fn test_latest_candidate_diff() {
+        let latest = LatestCandidateDiff::default();
+        assert_eq!(latest.to_json().unwrap(), Value::Null);
+
+        let address = Pubkey::new_unique();
+        latest
+            .publish(CandidateDiff {
+                newly_liquidatable: vec![address],
+                ..Default::default()
+            })
+            .unwrap();
+        let json = latest.to_json().unwrap();
+        assert_eq!(json["newly_liquidatable"][0], address.to_string());
+        assert!(json["timestamp_unix"].is_u64());
+    }
+
+    #[test]
+    fn test_record_attempt_resets_waiting_time() {
+        let mut tracker = CandidateTracker::new(THRESHOLD);
+        let address = Pubkey::new_unique();
+        let start = Instant::now();
+
+        tracker.refresh(&HashMap::from([(address, -1)]), start);
+        tracker.record_attempt(&address, start + Duration::from_secs(10));
+
+        assert_eq!(
+            tracker.waiting_time(&address, start + Duration::from_secs(15)),
+            Some(Duration::from_secs(5))
+        );
+        // Still liquidatable since the first refresh.
+        assert_eq!(
+            tracker.liquidatable_for(&address, start + Duration::from_secs(15)),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(tracker.liquidatable_for(&Pubkey::new_unique(), start), None);
+    }
+
+    #[test]
+    fn test_prioritize_moves_starving_candidates_first() {
+        let mut tracker = CandidateTracker::new(THRESHOLD);
+        let old = Pubkey::new_unique();
+        let older = Pubkey::new_unique();
+        let fresh = Pubkey::new_unique();
+        let healthy = Pubkey::new_unique();
+        let start = Instant::now();
+
+        tracker.refresh(&HashMap::from([(older, -1)]), start);
+        tracker.refresh(
+            &HashMap::from([(older, -1), (old, -1)]),
+            start + Duration::from_secs(10),
+        );
+        tracker.refresh(
+            &HashMap::from([(older, -1), (old, -1), (fresh, -1), (healthy, 1)]),
+            start + Duration::from_secs(40),
+        );
+
+        let now = start + Duration::from_secs(45);
+        assert_eq!(tracker.starving(now), vec![older, old]);
+
+        let prioritized = tracker.prioritize(vec![healthy, fresh, old, older], now);
+        assert_eq!(prioritized, vec![older, old, healthy, fresh]);
+    }
+
+    #[test]
+    fn test_prioritize_without_starving_candidates_keeps_order() {
+        let mut tracker = CandidateTracker::new(THRESHOLD);
+        let a = Pubkey::new_unique();
+        let b = Pubkey::new_unique();
+        let now = Instant::now();
+        tracker.refresh(&HashMap::from([(a, -1), (b, -1)]), now);
+
+        assert_eq!(tracker.prioritize(vec![b, a], now), vec![b, a]);
+    }
+}
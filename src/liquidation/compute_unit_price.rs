@@ -0,0 +1,115 @@
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::{anyhow, Result};
+
+use crate::{cache::priority_fees::PriorityFeeStats, config::Config};
+
+// The premium over the competitor fees is raised by a quarter after a liquidation which did not land, and
+// lowered by a tenth after a landed one, within its bounds.
+const MISSED_ADJUSTMENT: f64 = 1.25;
+const LANDED_ADJUSTMENT: f64 = 0.9;
+const MIN_PREMIUM: f64 = 0.5;
+const MAX_PREMIUM: f64 = 4.0;
+
+/// Tunes the compute unit price of the liquidations, in micro-lamports: the 75th percentile of the prices paid
+/// by the other Marginfi transactions touching the Banks in the recent blocks, times a premium raised after
+/// every liquidation which did not land, lost to a competitor or not included, and lowered after every landed
+/// one. The price is kept within the configured floor and ceiling, and is the floor until fees are observed.
+pub struct ComputeUnitPriceTuner {
+    floor: u64,
+    ceiling: u64,
+    premium: Mutex<f64>,
+}
+
+impl ComputeUnitPriceTuner {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            floor: config.compute_unit_price_floor,
+            ceiling: config.compute_unit_price_ceiling,
+            premium: Mutex::new(1.0),
+        }
+    }
+
+    /// The compute unit price of the next liquidation given the recent competitor fees.
+    pub fn price(&self, competitor_fees: &PriorityFeeStats) -> Result<u64> {
+        if competitor_fees.transactions == 0 {
+            return Ok(self.floor);
+        }
+        let price = competitor_fees.p75 as f64 * *self.lock()?;
+        Ok((price.round() as u64).clamp(self.floor, self.ceiling))
+    }
+
+    /// Adjusts the premium to the outcome of a liquidation, returning the new premium.
+    pub fn record_outcome(&self, landed: bool) -> Result<f64> {
+        let mut premium = self.lock()?;
+        let adjustment = if landed {
+            LANDED_ADJUSTMENT
+        } else {
+            MISSED_ADJUSTMENT
+        };
+        *premium = (*premium * adjustment).clamp(MIN_PREMIUM, MAX_PREMIUM);
+        Ok(*premium)
+    }
+
+    /// The premium over the competitor fees, as tuned so far.
+    pub fn premium(&self) -> Result<f64> {
+        Ok(*self.lock()?)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, f64>> {
+        self.premium
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the compute unit price premium: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util::create_dummy_config;
+
+    fn create_tuner(floor: u64, ceiling: u64) -> ComputeUnitPriceTuner {
+        let mut config = create_dummy_config();
+        config.compute_unit_price_floor = floor;
+        config.compute_unit_price_ceiling = ceiling;
+        ComputeUnitPriceTuner::new(&config)
+    }
+
+    fn competitor_fees(p75: u64) -> PriorityFeeStats {
+        PriorityFeeStats {
+            blocks: 10,
+            transactions: 20,
+            p75,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_price_follows_the_competitor_fees() {
+        let tuner = create_tuner(1_000, 100_000);
+        assert_eq!(tuner.price(&PriorityFeeStats::default()).unwrap(), 1_000);
+        assert_eq!(tuner.price(&competitor_fees(10_000)).unwrap(), 10_000);
+        // Within the floor and the ceiling.
+        assert_eq!(tuner.price(&competitor_fees(10)).unwrap(), 1_000);
+        assert_eq!(tuner.price(&competitor_fees(1_000_000)).unwrap(), 100_000);
+    }
+
+    #[test]
+    fn test_price_adjusts_to_the_outcomes() {
+        let tuner = create_tuner(1_000, 100_000);
+        assert_eq!(tuner.record_outcome(false).unwrap(), 1.25);
+        assert_eq!(tuner.price(&competitor_fees(10_000)).unwrap(), 12_500);
+        tuner.record_outcome(true).unwrap();
+        assert_eq!(tuner.price(&competitor_fees(10_000)).unwrap(), 11_250);
+
+        // The premium is bounded.
+        for _ in 0..20 {
+            tuner.record_outcome(false).unwrap();
+        }
+        assert_eq!(tuner.record_outcome(false).unwrap(), MAX_PREMIUM);
+        for _ in 0..20 {
+            tuner.record_outcome(true).unwrap();
+        }
+        assert_eq!(tuner.record_outcome(true).unwrap(), MIN_PREMIUM);
+    }
+}
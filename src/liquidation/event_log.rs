@@ -0,0 +1,264 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+
+use crate::cache::{marginfi_accounts::CachedMarginfiAccount, snapshot::SnapshotAccount, Cache};
+
+/// The exact cached bytes of the accounts a liquidation decision was made on.
+#[derive(Debug)]
+pub struct DecisionAccounts {
+    marginfi_account: SnapshotAccount,
+    banks: Vec<SnapshotAccount>,
+    oracles: Vec<SnapshotAccount>,
+    mints: Vec<SnapshotAccount>,
+}
+
+impl DecisionAccounts {
+    /// Captures the liquidatee account as it is evaluated, together with its Banks, their oracles and their mints.
+    pub fn capture(cache: &Cache, account: &CachedMarginfiAccount) -> Result<Self> {
+        let mut banks = Vec::new();
+        let mut oracles = Vec::new();
+        let mut mints: Vec<SnapshotAccount> = Vec::new();
+        for bank_address in account.bank_addresses() {
+            let bank = cache.banks.get_bank(&bank_address)?;
+            for oracle_address in bank.oracle_addresses() {
+                if let Some(oracle) = cache.oracles.get_snapshot_account(oracle_address)? {
+                    oracles.push(oracle);
+                }
+            }
+            if !mints.iter().any(|mint| mint.address == *bank.mint()) {
+                if let Some(mint) = cache.mints.get(bank.mint())? {
+                    mints.push(mint.to_snapshot_account());
+                }
+            }
+            banks.push(bank.to_snapshot_account());
+        }
+
+        Ok(Self {
+            marginfi_account: account.to_snapshot_account(),
+            banks,
+            oracles,
+            mints,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "marginfi_account": snapshot_account_json(&self.marginfi_account),
+            "banks": self.banks.iter().map(snapshot_account_json).collect::<Vec<_>>(),
+            "oracles": self.oracles.iter().map(snapshot_account_json).collect::<Vec<_>>(),
+            "mints": self.mints.iter().map(snapshot_account_json).collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn snapshot_account_json(account: &SnapshotAccount) -> Value {
+    json!({
+        "address": account.address.to_string(),
+        "slot": account.slot,
+        "data": STANDARD.encode(&account.data),
+    })
+}
+
+const RECENT_EVENTS_CAPACITY: usize = 100;
+
+/// The outcome of a liquidation attempt, as published by the public API. The details, e.g. the errors which may
+/// carry the URLs of the endpoints, are only logged to the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventOutcome {
+    Landed,
+    // Failed to send, or sent and failed or timed out.
+    Failed,
+    // Sent, but the confirmation could not be awaited.
+    Unconfirmed,
+}
+
+impl EventOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Landed => "landed",
+            Self::Failed => "failed",
+            Self::Unconfirmed => "unconfirmed",
+        }
+    }
+}
+
+impl fmt::Display for EventOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Append-only JSON lines log of the executed and failed liquidations, archiving the accounts
+/// they were decided on, so that the disagreements with the on-chain outcomes can be reproduced later.
+pub struct LiquidationEventLog {
+    file: Mutex<File>,
+    // The latest events without the details and the accounts, newest first.
+    recent: Mutex<VecDeque<Value>>,
+}
+
+impl LiquidationEventLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("Failed to open the event log {}: {}", path.display(), e))?;
+        Ok(Self {
+            file: Mutex::new(file),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_EVENTS_CAPACITY)),
+        })
+    }
+
+    pub fn record(
+        &self,
+        liquidatee: &Pubkey,
+        outcome: EventOutcome,
+        detail: Option<&str>,
+        signatures: &[Signature],
+        accounts: &DecisionAccounts,
+    ) -> Result<()> {
+        let summary = json!({
+            "timestamp_unix": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            "liquidatee": liquidatee.to_string(),
+            "outcome": outcome.as_str(),
+            "signatures": signatures.iter().map(|signature| signature.to_string()).collect::<Vec<_>>(),
+        });
+        let mut event = summary.clone();
+        if let Some(detail) = detail {
+            event["detail"] = json!(detail);
+        }
+        event["accounts"] = accounts.to_json();
+
+        {
+            let mut file = self
+                .file
+                .lock()
+                .map_err(|e| anyhow!("Failed to lock the event log: {}", e))?;
+            writeln!(file, "{}", event)?;
+            file.flush()?;
+        }
+
+        let mut recent = self
+            .recent
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the recent events: {}", e))?;
+        if recent.len() == RECENT_EVENTS_CAPACITY {
+            recent.pop_back();
+        }
+        recent.push_front(summary);
+        Ok(())
+    }
+
+    /// The latest events, newest first, without the details and the archived accounts.
+    pub fn recent(&self, limit: usize) -> Result<Vec<Value>> {
+        Ok(self
+            .recent
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the recent events: {}", e))?
+            .iter()
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+pub mod test_util {
+    use super::*;
+
+    pub fn create_decision_accounts() -> DecisionAccounts {
+        DecisionAccounts {
+            marginfi_account: SnapshotAccount::new(Pubkey::new_unique(), 0, vec![]),
+            banks: vec![],
+            oracles: vec![],
+            mints: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_record_liquidation_event() {
+        let path =
+            std::env::temp_dir().join(format!("mary_event_log_{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let liquidatee = Pubkey::new_unique();
+        let bank = Pubkey::new_unique();
+        let accounts = DecisionAccounts {
+            marginfi_account: SnapshotAccount::new(liquidatee, 10, vec![1, 2, 3]),
+            banks: vec![SnapshotAccount::new(bank, 9, vec![4, 5])],
+            oracles: vec![],
+            mints: vec![SnapshotAccount::new(Pubkey::new_unique(), 0, vec![6])],
+        };
+
+        let event_log = LiquidationEventLog::open(&path).unwrap();
+        event_log
+            .record(
+                &liquidatee,
+                EventOutcome::Landed,
+                None,
+                &[Signature::default()],
+                &accounts,
+            )
+            .unwrap();
+        event_log
+            .record(
+                &liquidatee,
+                EventOutcome::Failed,
+                Some("https://rpc.example.com/?api-key=secret timed out"),
+                &[],
+                &accounts,
+            )
+            .unwrap();
+
+        let lines: Vec<Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["liquidatee"], liquidatee.to_string());
+        assert_eq!(lines[0]["outcome"], "landed");
+        assert_eq!(lines[0]["accounts"]["marginfi_account"]["data"], "AQID");
+        assert_eq!(
+            lines[0]["accounts"]["banks"][0]["address"],
+            bank.to_string()
+        );
+        assert_eq!(lines[0]["accounts"]["banks"][0]["slot"], 9);
+        assert_eq!(lines[0]["accounts"]["mints"][0]["data"], "Bg==");
+        assert_eq!(lines[1]["outcome"], "failed");
+        assert_eq!(
+            lines[1]["detail"],
+            "https://rpc.example.com/?api-key=secret timed out"
+        );
+
+        let recent = event_log.recent(10).unwrap();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0]["outcome"], "failed");
+        assert!(recent[0].get("detail").is_none());
+        assert!(recent[0].get("accounts").is_none());
+        assert_eq!(event_log.recent(1).unwrap().len(), 1);
+
+        let _ = fs::remove_file(&path);
+    }
+}
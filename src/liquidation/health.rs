@@ -0,0 +1,536 @@
+use fixed::types::I80F48;
+use marginfi::state::{
+    emode::{reconcile_emode_configs, EmodeConfig},
+    marginfi_account::Balance,
+    marginfi_group::WrappedI80F48,
+};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::cache::{banks::CachedBank, marginfi_accounts::health_of, prices::CachedPrice};
+
+/// The health requirements of the Marginfi program: the initial one gates the new borrows and withdrawals,
+/// the maintenance one the liquidations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequirementType {
+    Initial,
+    Maintenance,
+}
+
+/// The weighted values of the deposits and the borrows of an account, in USD.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HealthComponents {
+    pub assets: I80F48,
+    pub liabilities: I80F48,
+}
+
+impl HealthComponents {
+    /// The weighted assets in excess of the weighted liabilities, negative when the requirement is not met.
+    pub fn free_collateral(&self) -> I80F48 {
+        self.assets.saturating_sub(self.liabilities)
+    }
+
+    /// The health as indexed by the cache, negative when the requirement is not met.
+    pub fn health(&self) -> Option<i64> {
+        health_of(self.assets, self.liabilities)
+    }
+}
+
+/// The health of an account for both the requirements.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AccountHealth {
+    pub initial: HealthComponents,
+    pub maintenance: HealthComponents,
+}
+
+/// Computes the health of the account positions like the program does: the deposits are valued at the
+/// price less its confidence interval, the borrows at the price plus its confidence interval, and both are
+/// weighted by the Bank weights of the requirement, with the share values projected to the timestamp. The
+/// initial requirement takes the time-weighted prices, and the deposits paired by the emode of the borrowed
+/// Banks take the emode weights when higher. `priced_bank` returns the Bank of a position with the valid price
+/// of its Oracle; None when it is missing for any position.
+pub fn compute_account_health(
+    positions: &[Balance],
+    priced_bank: impl Fn(&Pubkey) -> Option<(CachedBank, CachedPrice)>,
+    unix_timestamp: i64,
+) -> Option<AccountHealth> {
+    let priced_positions = positions
+        .iter()
+        .map(|balance| Some((balance, priced_bank(&balance.bank_pk)?)))
+        .collect::<Option<Vec<_>>>()?;
+    let emode_config = reconciled_emode_config(
+        priced_positions
+            .iter()
+            .map(|(balance, (bank, _))| (*balance, bank)),
+    );
+
+    let mut health = AccountHealth::default();
+    for (balance, (bank, price)) in &priced_positions {
+        for (requirement, components) in [
+            (RequirementType::Initial, &mut health.initial),
+            (RequirementType::Maintenance, &mut health.maintenance),
+        ] {
+            let (assets, liabilities) = weighted_values(
+                bank,
+                price,
+                balance,
+                &emode_config,
+                requirement,
+                unix_timestamp,
+            )?;
+            components.assets = components.assets.checked_add(assets)?;
+            components.liabilities = components.liabilities.checked_add(liabilities)?;
+        }
+    }
+    Some(health)
+}
+
+/// The emode config of the positions, reconciled like the program does from the configs of the borrowed
+/// Banks: only the pairs that all of them offer, at their lowest weights.
+pub fn reconciled_emode_config<'a>(
+    positions: impl IntoIterator<Item = (&'a Balance, &'a CachedBank)>,
+) -> EmodeConfig {
+    reconcile_emode_configs(
+        positions
+            .into_iter()
+            .filter(|(balance, _)| I80F48::from(balance.liability_shares).is_positive())
+            .map(|(_, bank)| *bank._emode_config()),
+    )
+}
+
+/// The weighted values of the deposits and the borrows of the balance for the requirement, given the emode
+/// config of the account.
+pub fn weighted_values(
+    bank: &CachedBank,
+    price: &CachedPrice,
+    balance: &Balance,
+    emode_config: &EmodeConfig,
+    requirement: RequirementType,
+    unix_timestamp: i64,
+) -> Option<(I80F48, I80F48)> {
+    let (asset_share_value, liability_share_value) = bank.projected_share_values(unix_timestamp);
+    let asset_amount = I80F48::from(balance.asset_shares).checked_mul(asset_share_value)?;
+    let liability_amount =
+        I80F48::from(balance.liability_shares).checked_mul(liability_share_value)?;
+    let config = bank.config();
+    let emode_entry = emode_config.find_with_tag(bank.emode_tag());
+    let (price, asset_weight, liability_weight) = match requirement {
+        RequirementType::Initial => {
+            let price = price.time_weighted();
+            let asset_weight = emode_asset_weight(
+                config.asset_weight_init,
+                emode_entry.map(|entry| entry.asset_weight_init),
+            );
+            (
+                price,
+                initial_asset_weight(bank, &price, asset_weight)?,
+                I80F48::from(config.liability_weight_init),
+            )
+        }
+        RequirementType::Maintenance => (
+            *price,
+            emode_asset_weight(
+                config.asset_weight_maint,
+                emode_entry.map(|entry| entry.asset_weight_maint),
+            ),
+            I80F48::from(config.liability_weight_maint),
+        ),
+    };
+
+    Some((
+        value(asset_amount, low_price(&price)?, bank.mint_decimals())?.checked_mul(asset_weight)?,
+        value(liability_amount, high_price(&price)?, bank.mint_decimals())?
+            .checked_mul(liability_weight)?,
+    ))
+}
+
+// The asset weight of the Bank, raised to the weight of its emode pair when higher.
+fn emode_asset_weight(bank_weight: WrappedI80F48, emode_weight: Option<WrappedI80F48>) -> I80F48 {
+    let bank_weight = I80F48::from(bank_weight);
+    emode_weight.map_or(bank_weight, |emode_weight| {
+        bank_weight.max(I80F48::from(emode_weight))
+    })
+}
+
+// The initial asset weight, discounted once the deposits of the Bank are worth more than their limit, so
+// that the Bank collateralizes the limit value at most.
+fn initial_asset_weight(bank: &CachedBank, price: &CachedPrice, weight: I80F48) -> Option<I80F48> {
+    let config = bank.config();
+    if config.total_asset_value_init_limit == 0 {
+        return Some(weight);
+    }
+
+    let total_value = value(bank.total_assets(), low_price(price)?, bank.mint_decimals())?;
+    let limit = I80F48::from_num(config.total_asset_value_init_limit);
+    if total_value > limit {
+        weight.checked_mul(limit.checked_div(total_value)?)
+    } else {
+        Some(weight)
+    }
+}
+
+fn low_price(price: &CachedPrice) -> Option<I80F48> {
+    price.price.checked_sub(price.confidence)
+}
+
+pub(super) fn high_price(price: &CachedPrice) -> Option<I80F48> {
+    price.price.checked_add(price.confidence)
+}
+
+// The value of the native token amount.
+fn value(amount: I80F48, price: I80F48, mint_decimals: u8) -> Option<I80F48> {
+    amount
+        .checked_mul(price)?
+        .checked_div(I80F48::from_num(10u64.checked_pow(mint_decimals as u32)?))
+}
+
+#[cfg(test)]
+pub mod test_util {
+    use bytemuck::Zeroable;
+    use fixed::types::I80F48;
+    use marginfi::state::{emode::EmodeEntry, marginfi_group::Bank};
+    use solana_sdk::pubkey::Pubkey;
+
+    use crate::cache::{
+        banks::{test_util::create_bank_with_oracles, CachedBank},
+        prices::CachedPrice,
+    };
+
+    pub const NOW: i64 = 1_700_000_000;
+
+    // A Bank of a 6 decimals mint, with unit share values accrued up to NOW.
+    pub fn create_bank(
+        asset_weight_init: f64,
+        asset_weight_maint: f64,
+        liability_weight_init: f64,
+        liability_weight_maint: f64,
+    ) -> Bank {
+        let mut bank = create_bank_with_oracles(vec![Pubkey::new_unique()]);
+        bank.asset_share_value = I80F48::ONE.into();
+        bank.liability_share_value = I80F48::ONE.into();
+        bank.last_update = NOW;
+        bank.config.asset_weight_init = I80F48::from_num(asset_weight_init).into();
+        bank.config.asset_weight_maint = I80F48::from_num(asset_weight_maint).into();
+        bank.config.liability_weight_init = I80F48::from_num(liability_weight_init).into();
+        bank.config.liability_weight_maint = I80F48::from_num(liability_weight_maint).into();
+        bank
+    }
+
+    pub fn create_price(price: f64, confidence: f64) -> CachedPrice {
+        CachedPrice {
+            price: I80F48::from_num(price),
+            confidence: I80F48::from_num(confidence),
+            time_weighted_price: I80F48::from_num(price),
+            time_weighted_confidence: I80F48::from_num(confidence),
+            publish_time: NOW,
+            slot: 1,
+        }
+    }
+
+    // The emode pair of the collateral Banks tagged `collateral_tag`, with its weights.
+    pub fn create_emode_entry(
+        collateral_tag: u16,
+        asset_weight_init: f64,
+        asset_weight_maint: f64,
+    ) -> EmodeEntry {
+        EmodeEntry {
+            collateral_bank_emode_tag: collateral_tag,
+            asset_weight_init: I80F48::from_num(asset_weight_init).into(),
+            asset_weight_maint: I80F48::from_num(asset_weight_maint).into(),
+            ..EmodeEntry::zeroed()
+        }
+    }
+
+    pub fn cached(bank: Bank) -> CachedBank {
+        CachedBank::from(1, Pubkey::new_unique(), bank)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::{cached, create_bank, create_emode_entry, create_price, NOW};
+    use super::*;
+    use crate::cache::marginfi_accounts::test_util::create_balance;
+    use bytemuck::Zeroable;
+
+    #[test]
+    fn test_maintenance_weighted_values() {
+        let bank = cached(create_bank(0.5, 0.75, 1.5, 1.25));
+        let price = create_price(2.0, 0.0);
+
+        // 100 tokens deposited, 40 borrowed.
+        let balance = create_balance(bank.address, 100_000_000, 40_000_000);
+        assert_eq!(
+            weighted_values(
+                &bank,
+                &price,
+                &balance,
+                &no_emode(),
+                RequirementType::Maintenance,
+                NOW
+            ),
+            Some((I80F48::from_num(150), I80F48::from_num(100)))
+        );
+        assert_eq!(
+            weighted_values(
+                &bank,
+                &price,
+                &balance,
+                &no_emode(),
+                RequirementType::Initial,
+                NOW
+            ),
+            Some((I80F48::from_num(100), I80F48::from_num(120)))
+        );
+    }
+
+    #[test]
+    fn test_weighted_values_apply_the_confidence_interval() {
+        let bank = cached(create_bank(1.0, 1.0, 1.0, 1.0));
+        let price = create_price(10.0, 0.5);
+
+        let balance = create_balance(bank.address, 2_000_000, 2_000_000);
+        // The deposits at 9.5 USD, the borrows at 10.5 USD.
+        assert_eq!(
+            weighted_values(
+                &bank,
+                &price,
+                &balance,
+                &no_emode(),
+                RequirementType::Maintenance,
+                NOW
+            ),
+            Some((I80F48::from_num(19), I80F48::from_num(21)))
+        );
+    }
+
+    #[test]
+    fn test_initial_asset_weight_discounted_over_the_limit() {
+        let mut bank = create_bank(0.5, 0.75, 1.0, 1.0);
+        // 1000 tokens deposited in total, worth 2000 USD over the limit of 1000 USD.
+        bank.total_asset_shares = I80F48::from_num(1_000_000_000).into();
+        bank.config.total_asset_value_init_limit = 1_000;
+        let bank = cached(bank);
+        let price = create_price(2.0, 0.0);
+
+        assert_eq!(
+            initial_asset_weight(&bank, &price, I80F48::from_num(0.5)),
+            Some(I80F48::from_num(0.25))
+        );
+        let balance = create_balance(bank.address, 100_000_000, 0);
+        assert_eq!(
+            weighted_values(
+                &bank,
+                &price,
+                &balance,
+                &no_emode(),
+                RequirementType::Initial,
+                NOW
+            ),
+            Some((I80F48::from_num(50), I80F48::ZERO))
+        );
+        // The maintenance weight is not discounted.
+        assert_eq!(
+            weighted_values(
+                &bank,
+                &price,
+                &balance,
+                &no_emode(),
+                RequirementType::Maintenance,
+                NOW
+            ),
+            Some((I80F48::from_num(150), I80F48::ZERO))
+        );
+
+        // Under the limit, the weight is kept.
+        assert_eq!(
+            initial_asset_weight(&bank, &create_price(0.5, 0.0), I80F48::from_num(0.5)),
+            Some(I80F48::from_num(0.5))
+        );
+    }
+
+    #[test]
+    fn test_compute_account_health() {
+        // SOL-like collateral at 100 USD, USDC-like liability at 1 USD.
+        let collateral = cached(create_bank(0.75, 0.875, 1.0, 1.0));
+        let liability = cached(create_bank(0.0, 0.0, 1.25, 1.125));
+        let positions = vec![
+            // 10 deposited
+            create_balance(collateral.address, 10_000_000, 0),
+            // 800 borrowed
+            create_balance(liability.address, 0, 800_000_000),
+        ];
+        let priced_bank = |address: &Pubkey| {
+            if *address == collateral.address {
+                Some((collateral.clone(), create_price(100.0, 0.0)))
+            } else if *address == liability.address {
+                Some((liability.clone(), create_price(1.0, 0.0)))
+            } else {
+                None
+            }
+        };
+
+        let health = compute_account_health(&positions, priced_bank, NOW).unwrap();
+        assert_eq!(
+            health,
+            AccountHealth {
+                initial: HealthComponents {
+                    assets: I80F48::from_num(750),
+                    liabilities: I80F48::from_num(1_000),
+                },
+                maintenance: HealthComponents {
+                    assets: I80F48::from_num(875),
+                    liabilities: I80F48::from_num(900),
+                },
+            }
+        );
+        // Liquidatable: under the maintenance requirement too.
+        assert_eq!(health.maintenance.free_collateral(), I80F48::from_num(-25));
+        assert!(health.maintenance.health().unwrap() < 0);
+        assert_eq!(health.initial.free_collateral(), I80F48::from_num(-250));
+
+        // Healthy once half of the liability is repaid.
+        let positions = vec![
+            positions[0],
+            create_balance(liability.address, 0, 400_000_000),
+        ];
+        let health = compute_account_health(&positions, priced_bank, NOW).unwrap();
+        assert_eq!(health.maintenance.free_collateral(), I80F48::from_num(425));
+        assert_eq!(health.initial.free_collateral(), I80F48::from_num(250));
+        assert_eq!(health.maintenance.health(), Some(0));
+
+        // No health without the price of every position.
+        let positions = vec![
+            positions[0],
+            create_balance(Pubkey::new_unique(), 1_000_000, 0),
+        ];
+        assert_eq!(compute_account_health(&positions, priced_bank, NOW), None);
+    }
+
+    #[test]
+    fn test_compute_account_health_without_positions() {
+        let health = compute_account_health(&[], |_| None, NOW).unwrap();
+        assert_eq!(health, AccountHealth::default());
+        assert_eq!(health.maintenance.health(), None);
+    }
+
+    #[test]
+    fn test_weighted_values_accrue_the_interest() {
+        let mut bank = create_bank(1.0, 1.0, 1.0, 1.0);
+        // The whole deposits borrowed at the max rate of 100% APR, for half a year.
+        bank.total_asset_shares = I80F48::from_num(1_000_000).into();
+        bank.total_liability_shares = I80F48::from_num(1_000_000).into();
+        bank.config.interest_rate_config.optimal_utilization_rate = I80F48::from_num(0.5).into();
+        bank.config.interest_rate_config.plateau_interest_rate = I80F48::from_num(0.5).into();
+        bank.config.interest_rate_config.max_interest_rate = I80F48::ONE.into();
+        bank.last_update = NOW - 31_536_000 / 2;
+        let bank = cached(bank);
+
+        let balance = create_balance(bank.address, 0, 1_000_000);
+        let (_, liabilities) = weighted_values(
+            &bank,
+            &create_price(1.0, 0.0),
+            &balance,
+            &no_emode(),
+            RequirementType::Maintenance,
+            NOW,
+        )
+        .unwrap();
+        assert_eq!(liabilities, I80F48::from_num(1.5));
+    }
+
+    #[test]
+    fn test_initial_requirement_takes_the_time_weighted_price() {
+        let bank = cached(create_bank(1.0, 1.0, 1.0, 1.0));
+        let price = CachedPrice {
+            time_weighted_price: I80F48::from_num(8),
+            time_weighted_confidence: I80F48::from_num(0.5),
+            ..create_price(10.0, 1.0)
+        };
+
+        let balance = create_balance(bank.address, 1_000_000, 1_000_000);
+        assert_eq!(
+            weighted_values(
+                &bank,
+                &price,
+                &balance,
+                &no_emode(),
+                RequirementType::Initial,
+                NOW
+            ),
+            Some((I80F48::from_num(7.5), I80F48::from_num(8.5)))
+        );
+        assert_eq!(
+            weighted_values(
+                &bank,
+                &price,
+                &balance,
+                &no_emode(),
+                RequirementType::Maintenance,
+                NOW
+            ),
+            Some((I80F48::from_num(9), I80F48::from_num(11)))
+        );
+    }
+
+    #[test]
+    fn test_compute_account_health_applies_the_emode_weights() {
+        // A staked SOL-like collateral tagged 1, and a SOL-like liability paired with the tag.
+        let mut collateral = create_bank(0.5, 0.6, 1.0, 1.0);
+        collateral.emode.emode_tag = 1;
+        let collateral = cached(collateral);
+        let mut liability = create_bank(0.0, 0.0, 1.0, 1.0);
+        liability.emode.emode_config.entries[0] = create_emode_entry(1, 0.8, 0.9);
+        let liability = cached(liability);
+        let unpaired = cached(create_bank(0.0, 0.0, 1.0, 1.0));
+        let priced_bank = |address: &Pubkey| {
+            [&collateral, &liability, &unpaired]
+                .into_iter()
+                .find(|bank| bank.address == *address)
+                .map(|bank| (bank.clone(), create_price(1.0, 0.0)))
+        };
+
+        let positions = vec![
+            create_balance(collateral.address, 100_000_000, 0),
+            create_balance(liability.address, 0, 50_000_000),
+        ];
+        let health = compute_account_health(&positions, priced_bank, NOW).unwrap();
+        assert_eq!(health.initial.assets, I80F48::from_num(80));
+        assert_eq!(health.maintenance.assets, I80F48::from_num(90));
+
+        // The pair must be offered by every borrowed Bank.
+        let positions = vec![
+            positions[0],
+            positions[1],
+            create_balance(unpaired.address, 0, 10_000_000),
+        ];
+        let health = compute_account_health(&positions, priced_bank, NOW).unwrap();
+        assert_eq!(health.initial.assets, I80F48::from_num(50));
+        assert_eq!(health.maintenance.assets, I80F48::from_num(60));
+
+        // The emode weights only raise the Bank weights.
+        let mut collateral = create_bank(0.85, 0.95, 1.0, 1.0);
+        collateral.emode.emode_tag = 1;
+        let collateral = cached(collateral);
+        let positions = vec![
+            create_balance(collateral.address, 100_000_000, 0),
+            create_balance(liability.address, 0, 50_000_000),
+        ];
+        let health = compute_account_health(
+            &positions,
+            |address| {
+                [&collateral, &liability]
+                    .into_iter()
+                    .find(|bank| bank.address == *address)
+                    .map(|bank| (bank.clone(), create_price(1.0, 0.0)))
+            },
+            NOW,
+        )
+        .unwrap();
+        assert_eq!(health.initial.assets, I80F48::from_num(85));
+        assert_eq!(health.maintenance.assets, I80F48::from_num(95));
+    }
+
+    fn no_emode() -> EmodeConfig {
+        EmodeConfig::zeroed()
+    }
+}
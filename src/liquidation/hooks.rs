@@ -0,0 +1,231 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::{error, info};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::liquidation::{inventory::CollateralInventory, LiquidationParams};
+
+/// The collateral seized by a liquidation and the USD value of the liability repaid for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeizedCollateral {
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub cost_usd: f64,
+}
+
+/// The landed liquidation, as seen by the post-liquidation hooks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiquidationOutcome {
+    pub liquidatee: Pubkey,
+    pub liab_bank: Pubkey,
+    pub liab_mint: Pubkey,
+    pub liab_amount: u64,
+    pub seized: Option<SeizedCollateral>,
+}
+
+impl LiquidationOutcome {
+    /// The outcome of the liquidation, seizing its asset amount of the collateral mint at the cost of the
+    /// liability repaid, when it is priced.
+    pub fn new(params: &LiquidationParams, asset_mint: Pubkey, cost_usd: Option<f64>) -> Self {
+        Self {
+            liquidatee: params.liquidatee,
+            liab_bank: params.liab_bank,
+            liab_mint: params.liab_mint,
+            liab_amount: params.liab_amount,
+            seized: cost_usd.map(|cost_usd| SeizedCollateral {
+                mint: asset_mint,
+                amount: params.asset_amount,
+                cost_usd,
+            }),
+        }
+    }
+}
+
+/// Custom behavior executed after a liquidation has landed, e.g. depositing the proceeds into a yield venue
+/// or notifying an external treasury system.
+pub trait PostLiquidationHook: Send + Sync {
+    fn name(&self) -> &str;
+    fn on_liquidation_landed(&self, outcome: &LiquidationOutcome) -> Result<()>;
+}
+
+/// The default hook: keeps the seized collateral in the wallet as is, and records it in the inventory at the
+/// cost of the repaid liability. The collateral is sold, if ever, out of the liquidations.
+pub struct HoldHook {
+    inventory: Arc<CollateralInventory>,
+}
+
+impl HoldHook {
+    pub fn new(inventory: Arc<CollateralInventory>) -> Self {
+        Self { inventory }
+    }
+}
+
+impl PostLiquidationHook for HoldHook {
+    fn name(&self) -> &str {
+        "hold"
+    }
+
+    fn on_liquidation_landed(&self, outcome: &LiquidationOutcome) -> Result<()> {
+        info!(
+            "Holding the proceeds of the {} liquidation (repaid {} of the mint {} to the Bank {})",
+            outcome.liquidatee, outcome.liab_amount, outcome.liab_mint, outcome.liab_bank
+        );
+        if let Some(seized) = &outcome.seized {
+            self.inventory
+                .acquire(seized.mint, seized.amount, seized.cost_usd)?;
+        }
+        Ok(())
+    }
+}
+
+/// The hooks run when no custom ones are plugged in.
+pub fn default_hooks(inventory: Arc<CollateralInventory>) -> Vec<Box<dyn PostLiquidationHook>> {
+    vec![Box::new(HoldHook::new(inventory))]
+}
+
+/// The ordered chain of hooks run after every landed liquidation. A failing hook is reported and
+/// does not prevent the following ones from running.
+pub struct PostLiquidationHooks {
+    hooks: Vec<Box<dyn PostLiquidationHook>>,
+}
+
+impl PostLiquidationHooks {
+    pub fn new(hooks: Vec<Box<dyn PostLiquidationHook>>) -> Self {
+        Self { hooks }
+    }
+
+    pub fn run(&self, outcome: &LiquidationOutcome) {
+        for hook in &self.hooks {
+            if let Err(err) = hook.on_liquidation_landed(outcome) {
+                error!(
+                    "The post-liquidation hook {} failed for {}: {}",
+                    hook.name(),
+                    outcome.liquidatee,
+                    err
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Mutex};
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::liquidation::{event_log::test_util::create_decision_accounts, repay::RepaySource};
+
+    struct RecordingHook {
+        fail: bool,
+        landed: Arc<Mutex<Vec<LiquidationOutcome>>>,
+    }
+
+    impl PostLiquidationHook for RecordingHook {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn on_liquidation_landed(&self, outcome: &LiquidationOutcome) -> Result<()> {
+            self.landed.lock().unwrap().push(outcome.clone());
+            if self.fail {
+                return Err(anyhow!("Recording hook failure"));
+            }
+            Ok(())
+        }
+    }
+
+    fn create_params() -> LiquidationParams {
+        LiquidationParams {
+            liquidatee: Pubkey::new_unique(),
+            liab_bank: Pubkey::new_unique(),
+            liab_mint: Pubkey::new_unique(),
+            liab_amount: 100,
+            asset_bank: Pubkey::new_unique(),
+            asset_amount: 100,
+            repay_source: RepaySource::default(),
+            tip_lamports: 0,
+            collateral_swap: None,
+            decision_accounts: create_decision_accounts(),
+        }
+    }
+
+    fn create_outcome() -> LiquidationOutcome {
+        LiquidationOutcome::new(&create_params(), Pubkey::new_unique(), None)
+    }
+
+    #[test]
+    fn test_liquidation_outcome_seized_collateral() {
+        let params = create_params();
+        let asset_mint = Pubkey::new_unique();
+
+        let outcome = LiquidationOutcome::new(&params, asset_mint, Some(99.5));
+        assert_eq!(outcome.liab_amount, 100);
+        assert_eq!(
+            outcome.seized,
+            Some(SeizedCollateral {
+                mint: asset_mint,
+                amount: 100,
+                cost_usd: 99.5,
+            })
+        );
+        // Not reported without the price of the liability.
+        assert_eq!(
+            LiquidationOutcome::new(&params, asset_mint, None).seized,
+            None
+        );
+    }
+
+    fn create_inventory(name: &str) -> (Arc<CollateralInventory>, PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("mary_hooks_{}_{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        (Arc::new(CollateralInventory::open(&path).unwrap()), path)
+    }
+
+    #[test]
+    fn test_hold_hook() {
+        let (inventory, path) = create_inventory("hold");
+        let hook = HoldHook::new(inventory.clone());
+
+        // Nothing to record without the cost of the seized collateral.
+        assert!(hook.on_liquidation_landed(&create_outcome()).is_ok());
+        assert!(inventory.positions(|_, _| None).unwrap().is_empty());
+
+        let mut outcome = create_outcome();
+        outcome.seized = Some(SeizedCollateral {
+            mint: Pubkey::new_unique(),
+            amount: 50,
+            cost_usd: 95.0,
+        });
+        assert!(hook.on_liquidation_landed(&outcome).is_ok());
+        let positions = inventory.positions(|_, _| None).unwrap();
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].amount, 50);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_hooks_run_in_order_despite_failures() {
+        let landed = Arc::new(Mutex::new(vec![]));
+        let (inventory, path) = create_inventory("order");
+        let mut hooks = default_hooks(inventory);
+        hooks.push(Box::new(RecordingHook {
+            fail: true,
+            landed: landed.clone(),
+        }));
+        hooks.push(Box::new(RecordingHook {
+            fail: false,
+            landed: landed.clone(),
+        }));
+        let hooks = PostLiquidationHooks::new(hooks);
+
+        let outcome = create_outcome();
+        hooks.run(&outcome);
+
+        assert_eq!(*landed.lock().unwrap(), vec![outcome.clone(), outcome]);
+        let _ = std::fs::remove_file(&path);
+    }
+}
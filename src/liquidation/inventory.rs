@@ -0,0 +1,271 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::cache::snapshot::{default_tmp_dir, write_atomically};
+
+/// A quantity of seized collateral held instead of sold, at the cost it was acquired for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InventoryLot {
+    pub mint: Pubkey,
+    pub amount: u64,
+    /// The USD value of the liability repaid to seize the lot.
+    pub cost_usd: f64,
+    pub acquired_at_unix: u64,
+}
+
+impl InventoryLot {
+    fn to_json(&self) -> Value {
+        json!({
+            "mint": self.mint.to_string(),
+            "amount": self.amount,
+            "cost_usd": self.cost_usd,
+            "acquired_at_unix": self.acquired_at_unix,
+        })
+    }
+
+    fn from_json(value: &Value) -> Result<Self> {
+        let mint = value["mint"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing lot mint"))?;
+        Ok(Self {
+            mint: Pubkey::from_str(mint)
+                .map_err(|e| anyhow!("Invalid lot mint {}: {}", mint, e))?,
+            amount: value["amount"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("Missing lot amount"))?,
+            cost_usd: value["cost_usd"]
+                .as_f64()
+                .ok_or_else(|| anyhow!("Missing lot cost"))?,
+            acquired_at_unix: value["acquired_at_unix"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("Missing lot acquisition time"))?,
+        })
+    }
+}
+
+/// The held inventory of a mint, at its average cost basis.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InventoryPosition {
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub lots: usize,
+    pub cost_basis_usd: f64,
+    /// None while the holdings cannot be valued.
+    pub unrealized_pnl_usd: Option<f64>,
+    pub oldest_lot_age_sec: u64,
+}
+
+impl InventoryPosition {
+    pub fn average_cost_usd(&self) -> f64 {
+        if self.amount == 0 {
+            return 0.0;
+        }
+        self.cost_basis_usd / self.amount as f64
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "mint": self.mint.to_string(),
+            "amount": self.amount,
+            "lots": self.lots,
+            "cost_basis_usd": self.cost_basis_usd,
+            "average_cost_usd": self.average_cost_usd(),
+            "unrealized_pnl_usd": self.unrealized_pnl_usd,
+            "oldest_lot_age_sec": self.oldest_lot_age_sec,
+        })
+    }
+}
+
+/// The lots of seized collateral held by the liquidator, persisted as JSON so that their cost basis and
+/// age survive the restarts. Reports the unrealized PnL and the age of the inventory of every mint, for
+/// deciding when to finally sell it.
+// TODO: dispose of the lots at their average cost once the rebalancer sells them
+pub struct CollateralInventory {
+    path: PathBuf,
+    lots: Mutex<Vec<InventoryLot>>,
+}
+
+impl CollateralInventory {
+    pub fn open(path: &Path) -> Result<Self> {
+        let lots = if path.exists() {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the inventory {}", path.display()))?;
+            let value: Value = serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse the inventory {}", path.display()))?;
+            value["lots"]
+                .as_array()
+                .ok_or_else(|| anyhow!("Missing lots in the inventory {}", path.display()))?
+                .iter()
+                .map(InventoryLot::from_json)
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            vec![]
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            lots: Mutex::new(lots),
+        })
+    }
+
+    pub fn acquire(&self, mint: Pubkey, amount: u64, cost_usd: f64) -> Result<()> {
+        let mut lots = self
+            .lots
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the collateral inventory: {}", e))?;
+        lots.push(InventoryLot {
+            mint,
+            amount,
+            cost_usd,
+            acquired_at_unix: now_unix(),
+        });
+        self.persist(&lots)
+    }
+
+    /// The positions by mint, with the unrealized PnL of the ones `value_usd` can value.
+    pub fn positions(
+        &self,
+        value_usd: impl Fn(&Pubkey, u64) -> Option<f64>,
+    ) -> Result<Vec<InventoryPosition>> {
+        let lots = self
+            .lots
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the collateral inventory: {}", e))?;
+        Ok(aggregate_positions(&lots, value_usd, now_unix()))
+    }
+
+    pub fn to_json(&self, value_usd: impl Fn(&Pubkey, u64) -> Option<f64>) -> Result<Value> {
+        Ok(Value::Array(
+            self.positions(value_usd)?
+                .iter()
+                .map(InventoryPosition::to_json)
+                .collect(),
+        ))
+    }
+
+    fn persist(&self, lots: &[InventoryLot]) -> Result<()> {
+        let lots: Vec<Value> = lots.iter().map(InventoryLot::to_json).collect();
+        write_atomically(
+            &self.path,
+            &default_tmp_dir(&self.path),
+            json!({ "lots": lots }).to_string().as_bytes(),
+        )
+    }
+}
+
+fn aggregate_positions(
+    lots: &[InventoryLot],
+    value_usd: impl Fn(&Pubkey, u64) -> Option<f64>,
+    now_unix: u64,
+) -> Vec<InventoryPosition> {
+    let mut positions: BTreeMap<Pubkey, InventoryPosition> = BTreeMap::new();
+    for lot in lots {
+        let position = positions.entry(lot.mint).or_insert(InventoryPosition {
+            mint: lot.mint,
+            amount: 0,
+            lots: 0,
+            cost_basis_usd: 0.0,
+            unrealized_pnl_usd: None,
+            oldest_lot_age_sec: 0,
+        });
+        position.amount += lot.amount;
+        position.lots += 1;
+        position.cost_basis_usd += lot.cost_usd;
+        position.oldest_lot_age_sec = position
+            .oldest_lot_age_sec
+            .max(now_unix.saturating_sub(lot.acquired_at_unix));
+    }
+
+    positions
+        .into_values()
+        .map(|mut position| {
+            position.unrealized_pnl_usd = value_usd(&position.mint, position.amount)
+                .map(|value| value - position.cost_basis_usd);
+            position
+        })
+        .collect()
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lot(mint: Pubkey, amount: u64, cost_usd: f64, acquired_at_unix: u64) -> InventoryLot {
+        InventoryLot {
+            mint,
+            amount,
+            cost_usd,
+            acquired_at_unix,
+        }
+    }
+
+    #[test]
+    fn test_aggregate_positions() {
+        let valued_mint = Pubkey::new_unique();
+        let unvalued_mint = Pubkey::new_unique();
+        let lots = vec![
+            lot(valued_mint, 100, 90.0, 1_000),
+            lot(unvalued_mint, 5, 10.0, 1_500),
+            lot(valued_mint, 300, 330.0, 1_800),
+        ];
+
+        let positions = aggregate_positions(
+            &lots,
+            |mint, amount| (*mint == valued_mint).then_some(amount as f64 * 1.1),
+            2_000,
+        );
+
+        let valued = positions
+            .iter()
+            .find(|position| position.mint == valued_mint)
+            .unwrap();
+        assert_eq!(valued.amount, 400);
+        assert_eq!(valued.lots, 2);
+        assert_eq!(valued.cost_basis_usd, 420.0);
+        assert_eq!(valued.average_cost_usd(), 1.05);
+        assert!((valued.unrealized_pnl_usd.unwrap() - 20.0).abs() < 1e-9);
+        assert_eq!(valued.oldest_lot_age_sec, 1_000);
+
+        let unvalued = positions
+            .iter()
+            .find(|position| position.mint == unvalued_mint)
+            .unwrap();
+        assert_eq!(unvalued.unrealized_pnl_usd, None);
+        assert_eq!(unvalued.oldest_lot_age_sec, 500);
+    }
+
+    #[test]
+    fn test_inventory_persistence() {
+        let path = std::env::temp_dir().join(format!("mary_inventory_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mint = Pubkey::new_unique();
+
+        let inventory = CollateralInventory::open(&path).unwrap();
+        inventory.acquire(mint, 100, 95.0).unwrap();
+        inventory.acquire(mint, 50, 50.0).unwrap();
+
+        let reopened = CollateralInventory::open(&path).unwrap();
+        let positions = reopened.positions(|_, _| None).unwrap();
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].amount, 150);
+        assert_eq!(positions[0].cost_basis_usd, 145.0);
+        fs::remove_file(&path).unwrap();
+    }
+}
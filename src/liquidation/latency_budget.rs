@@ -0,0 +1,233 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::{error, info};
+
+/// The stages of a liquidation attempt, in their execution order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LiquidationStage {
+    Sizing,
+    Quote,
+    Build,
+    Simulate,
+    Send,
+}
+
+impl LiquidationStage {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sizing => "sizing",
+            Self::Quote => "quote",
+            Self::Build => "build",
+            Self::Simulate => "simulate",
+            Self::Send => "send",
+        }
+    }
+}
+
+impl fmt::Display for LiquidationStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for LiquidationStage {
+    type Err = anyhow::Error;
+
+    fn from_str(stage: &str) -> Result<Self> {
+        match stage.trim().to_ascii_lowercase().as_str() {
+            "sizing" => Ok(Self::Sizing),
+            "quote" => Ok(Self::Quote),
+            "build" => Ok(Self::Build),
+            "simulate" => Ok(Self::Simulate),
+            "send" => Ok(Self::Send),
+            _ => Err(anyhow!("Unknown liquidation stage {:?}", stage)),
+        }
+    }
+}
+
+/// Parses the `<stage>:<millis>` comma separated stage timeouts, e.g. `sizing:50,simulate:400`.
+pub fn parse_stage_timeouts(timeouts: &str) -> Result<BTreeMap<LiquidationStage, u64>> {
+    timeouts
+        .split(',')
+        .filter(|timeout| !timeout.trim().is_empty())
+        .map(|timeout| {
+            let (stage, millis) = timeout
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Expected <stage>:<millis>, got {:?}", timeout))?;
+            let millis = millis
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| anyhow!("Invalid timeout {:?}: {}", timeout, e))?;
+            Ok((stage.parse()?, millis))
+        })
+        .collect()
+}
+
+/// The overall deadline of a liquidation attempt and the optional deadlines of its stages.
+/// The attempts blowing any of them are abandoned, as the opportunity is likely gone by then.
+pub struct LatencyBudget {
+    total: Duration,
+    stage_timeouts: BTreeMap<LiquidationStage, Duration>,
+    aborts: Mutex<BTreeMap<LiquidationStage, u64>>,
+}
+
+impl LatencyBudget {
+    pub fn new(total_ms: u64, stage_timeouts_ms: &BTreeMap<LiquidationStage, u64>) -> Self {
+        Self {
+            total: Duration::from_millis(total_ms),
+            stage_timeouts: stage_timeouts_ms
+                .iter()
+                .map(|(stage, millis)| (*stage, Duration::from_millis(*millis)))
+                .collect(),
+            aborts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn start(&self) -> BudgetedAttempt<'_> {
+        let now = Instant::now();
+        BudgetedAttempt {
+            budget: self,
+            started: now,
+            stage_started: now,
+        }
+    }
+
+    /// The number of the abandoned attempts by the stage which caused the abort.
+    pub fn aborts(&self) -> BTreeMap<LiquidationStage, u64> {
+        match self.aborts.lock() {
+            Ok(aborts) => aborts.clone(),
+            Err(e) => {
+                error!("Failed to lock the latency budget aborts: {}", e);
+                BTreeMap::new()
+            }
+        }
+    }
+
+    fn record_abort(&self, stage: LiquidationStage) {
+        match self.aborts.lock() {
+            Ok(mut aborts) => *aborts.entry(stage).or_default() += 1,
+            Err(e) => error!("Failed to lock the latency budget aborts: {}", e),
+        }
+    }
+
+    fn exceeded(
+        &self,
+        stage: LiquidationStage,
+        stage_elapsed: Duration,
+        elapsed: Duration,
+    ) -> bool {
+        elapsed > self.total
+            || self
+                .stage_timeouts
+                .get(&stage)
+                .is_some_and(|timeout| stage_elapsed > *timeout)
+    }
+}
+
+/// The timing of a single liquidation attempt against its latency budget.
+pub struct BudgetedAttempt<'a> {
+    budget: &'a LatencyBudget,
+    started: Instant,
+    stage_started: Instant,
+}
+
+impl BudgetedAttempt<'_> {
+    /// Completes the stage, returning whether the attempt is still within its budget.
+    /// The stage blowing the budget is recorded as the cause of the abort.
+    pub fn finish_stage(&mut self, address: &impl fmt::Display, stage: LiquidationStage) -> bool {
+        self.finish_stage_at(address, stage, Instant::now())
+    }
+
+    fn finish_stage_at(
+        &mut self,
+        address: &impl fmt::Display,
+        stage: LiquidationStage,
+        now: Instant,
+    ) -> bool {
+        let stage_elapsed = now.saturating_duration_since(self.stage_started);
+        let elapsed = now.saturating_duration_since(self.started);
+        self.stage_started = now;
+
+        if self.budget.exceeded(stage, stage_elapsed, elapsed) {
+            info!(
+                "Abandoning the liquidation of {}: the {} stage took {:?} ({:?} in total) and blew the latency budget",
+                address, stage, stage_elapsed, elapsed
+            );
+            self.budget.record_abort(stage);
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stage_timeouts() {
+        let timeouts = parse_stage_timeouts("sizing:50, Simulate:400,").unwrap();
+        assert_eq!(
+            timeouts.into_iter().collect::<Vec<_>>(),
+            vec![
+                (LiquidationStage::Sizing, 50),
+                (LiquidationStage::Simulate, 400)
+            ]
+        );
+        assert!(parse_stage_timeouts("").unwrap().is_empty());
+        assert!(parse_stage_timeouts("sizing").is_err());
+        assert!(parse_stage_timeouts("sizing:soon").is_err());
+        assert!(parse_stage_timeouts("signing:10").is_err());
+    }
+
+    #[test]
+    fn test_budgeted_attempt_stage_timeout() {
+        let budget = LatencyBudget::new(1_000, &BTreeMap::from([(LiquidationStage::Build, 100)]));
+        let mut attempt = budget.start();
+        let started = attempt.started;
+
+        assert!(attempt.finish_stage_at(
+            &"account",
+            LiquidationStage::Sizing,
+            started + Duration::from_millis(300)
+        ));
+        assert!(!attempt.finish_stage_at(
+            &"account",
+            LiquidationStage::Build,
+            started + Duration::from_millis(450)
+        ));
+        assert_eq!(
+            budget.aborts(),
+            BTreeMap::from([(LiquidationStage::Build, 1)])
+        );
+    }
+
+    #[test]
+    fn test_budgeted_attempt_total_budget() {
+        let budget = LatencyBudget::new(500, &BTreeMap::new());
+        let mut attempt = budget.start();
+        let started = attempt.started;
+
+        assert!(attempt.finish_stage_at(
+            &"account",
+            LiquidationStage::Sizing,
+            started + Duration::from_millis(400)
+        ));
+        assert!(!attempt.finish_stage_at(
+            &"account",
+            LiquidationStage::Simulate,
+            started + Duration::from_millis(600)
+        ));
+        assert_eq!(
+            budget.aborts(),
+            BTreeMap::from([(LiquidationStage::Simulate, 1)])
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A weekly recurring window (UTC) during which the liquidations are paused, in the
+/// `<days> <HH:MM>-<HH:MM>` format, e.g. `Mon-Fri 13:00-14:30` or `* 23:00-01:00`.
+/// The days are `*` or a comma separated list of days and day ranges. A window ending
+/// before its start crosses midnight and ends on the following day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PauseWindow {
+    spec: String,
+    // Indexed from Monday
+    days: [bool; 7],
+    start_minute: u32,
+    end_minute: u32,
+}
+
+impl PauseWindow {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        let (days, times) = spec
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("Expected <days> <HH:MM>-<HH:MM>, got {:?}", spec))?;
+        let (start, end) = times
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Invalid time range {:?}", times))?;
+        let start_minute = parse_time(start)?;
+        let end_minute = parse_time(end)?;
+        if start_minute == end_minute {
+            return Err(anyhow!("Empty pause window {:?}", spec));
+        }
+
+        Ok(Self {
+            spec: spec.to_string(),
+            days: parse_days(days)?,
+            start_minute,
+            end_minute,
+        })
+    }
+
+    /// Whether the given UNIX timestamp falls into the window.
+    pub fn contains(&self, unix_timestamp: u64) -> bool {
+        let days_since_epoch = unix_timestamp / 86_400;
+        // 1970-01-01 was a Thursday
+        let day = ((days_since_epoch + 3) % 7) as usize;
+        let previous_day = (day + 6) % 7;
+        let minute = ((unix_timestamp % 86_400) / 60) as u32;
+
+        if self.start_minute < self.end_minute {
+            self.days[day] && minute >= self.start_minute && minute < self.end_minute
+        } else {
+            (self.days[day] && minute >= self.start_minute)
+                || (self.days[previous_day] && minute < self.end_minute)
+        }
+    }
+}
+
+impl fmt::Display for PauseWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.spec)
+    }
+}
+
+/// Parses the `;` separated pause windows.
+pub fn parse_pause_windows(specs: &str) -> Result<Vec<PauseWindow>> {
+    specs
+        .split(';')
+        .filter(|spec| !spec.trim().is_empty())
+        .map(PauseWindow::parse)
+        .collect()
+}
+
+fn parse_time(time: &str) -> Result<u32> {
+    let (hours, minutes) = time
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid time {:?}, expected HH:MM", time))?;
+    let hours: u32 = hours
+        .parse()
+        .map_err(|e| anyhow!("Invalid hours in {:?}: {}", time, e))?;
+    let minutes: u32 = minutes
+        .parse()
+        .map_err(|e| anyhow!("Invalid minutes in {:?}: {}", time, e))?;
+    let minute = hours * 60 + minutes;
+    if minutes >= 60 || minute > MINUTES_PER_DAY {
+        return Err(anyhow!("Time {:?} is out of range", time));
+    }
+    Ok(minute)
+}
+
+fn parse_day(day: &str) -> Result<usize> {
+    DAYS.iter()
+        .position(|name| name.eq_ignore_ascii_case(day.trim()))
+        .ok_or_else(|| anyhow!("Invalid day {:?}, expected one of {:?}", day, DAYS))
+}
+
+fn parse_days(days: &str) -> Result<[bool; 7]> {
+    if days.trim() == "*" {
+        return Ok([true; 7]);
+    }
+
+    let mut selected = [false; 7];
+    for item in days.split(',') {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let first = parse_day(first)?;
+                let last = parse_day(last)?;
+                // Ranges may wrap around the week, e.g. Sat-Mon
+                let mut day = first;
+                loop {
+                    selected[day] = true;
+                    if day == last {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => selected[parse_day(item)?] = true,
+        }
+    }
+    Ok(selected)
+}
+
+/// Tracks whether the liquidations are paused, alerting on entering and leaving the pause windows.
+pub struct PauseSchedule {
+    windows: Vec<PauseWindow>,
+    paused: AtomicBool,
+}
+
+impl PauseSchedule {
+    pub fn new(windows: Vec<PauseWindow>) -> Self {
+        Self {
+            windows,
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the liquidations are paused now.
+    pub fn is_paused(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        self.is_paused_at(now)
+    }
+
+    fn is_paused_at(&self, unix_timestamp: u64) -> bool {
+        let window = self
+            .windows
+            .iter()
+            .find(|window| window.contains(unix_timestamp));
+        let paused = window.is_some();
+
+        if self.paused.swap(paused, Ordering::Relaxed) != paused {
+            match window {
+                Some(window) => warn!(
+                    "Entering the pause window {}: the liquidations are paused, monitoring only.",
+                    window
+                ),
+                None => warn!("Leaving the pause window: the liquidations are resumed."),
+            }
+        } else if paused {
+            info!("The liquidations are paused, monitoring only.");
+        }
+        paused
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Monday 2024-01-01 00:00:00 UTC
+    const MONDAY: u64 = 1_704_067_200;
+
+    fn at(day: u64, hours: u64, minutes: u64) -> u64 {
+        MONDAY + day * 86_400 + hours * 3_600 + minutes * 60
+    }
+
+    #[test]
+    fn test_parse_pause_window() {
+        let window = PauseWindow::parse("Mon-Wed,sun 13:00-14:30").unwrap();
+        assert_eq!(window.days, [true, true, true, false, false, false, true]);
+        assert_eq!(window.start_minute, 13 * 60);
+        assert_eq!(window.end_minute, 14 * 60 + 30);
+        assert_eq!(window.to_string(), "Mon-Wed,sun 13:00-14:30");
+
+        assert_eq!(PauseWindow::parse("* 00:00-24:00").unwrap().days, [true; 7]);
+        assert_eq!(
+            PauseWindow::parse("Sat-Mon 00:00-01:00").unwrap().days,
+            [true, false, false, false, false, true, true]
+        );
+    }
+
+    #[test]
+    fn test_parse_pause_window_invalid() {
+        assert!(PauseWindow::parse("Mon").is_err());
+        assert!(PauseWindow::parse("Mon 13:00").is_err());
+        assert!(PauseWindow::parse("Mon 13:00-13:00").is_err());
+        assert!(PauseWindow::parse("Mon 13:60-14:00").is_err());
+        assert!(PauseWindow::parse("Mon 13:00-25:00").is_err());
+        assert!(PauseWindow::parse("Someday 13:00-14:00").is_err());
+    }
+
+    #[test]
+    fn test_parse_pause_windows() {
+        let windows = parse_pause_windows("Mon 01:00-02:00; Sat,Sun 00:00-24:00;").unwrap();
+        assert_eq!(windows.len(), 2);
+        assert!(parse_pause_windows("").unwrap().is_empty());
+        assert!(parse_pause_windows("Mon 01:00-02:00;invalid").is_err());
+    }
+
+    #[test]
+    fn test_pause_window_contains() {
+        let window = PauseWindow::parse("Mon-Fri 13:00-14:00").unwrap();
+        assert!(!window.contains(at(0, 12, 59)));
+        assert!(window.contains(at(0, 13, 0)));
+        assert!(window.contains(at(4, 13, 59)));
+        assert!(!window.contains(at(4, 14, 0)));
+        assert!(!window.contains(at(5, 13, 30)));
+    }
+
+    #[test]
+    fn test_pause_window_crossing_midnight() {
+        let window = PauseWindow::parse("Sun 23:00-01:00").unwrap();
+        assert!(window.contains(at(6, 23, 30)));
+        assert!(window.contains(at(7, 0, 30)));
+        assert!(!window.contains(at(7, 1, 0)));
+        assert!(!window.contains(at(5, 23, 30)));
+        assert!(!window.contains(at(6, 0, 30)));
+    }
+
+    #[test]
+    fn test_pause_schedule_transitions() {
+        let schedule = PauseSchedule::new(vec![PauseWindow::parse("Mon 13:00-14:00").unwrap()]);
+        assert!(!schedule.is_paused_at(at(0, 12, 0)));
+        assert!(schedule.is_paused_at(at(0, 13, 0)));
+        assert!(schedule.is_paused_at(at(0, 13, 30)));
+        assert!(!schedule.is_paused_at(at(0, 14, 0)));
+
+        assert!(!PauseSchedule::new(vec![]).is_paused());
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use fixed::types::I80F48;
+use marginfi::constants::LIQUIDATION_LIQUIDATOR_FEE;
+use serde::Serialize;
+use solana_sdk::{
+    compute_budget, native_token::LAMPORTS_PER_SOL, pubkey::Pubkey,
+    transaction::VersionedTransaction,
+};
+
+use crate::{
+    cache::Cache,
+    config::Config,
+    liquidation::{simulation::MAX_COMPUTE_UNIT_LIMIT, LiquidationParams},
+};
+
+// The base fee of every signature.
+const LAMPORTS_PER_SIGNATURE: u64 = 5_000;
+// The rent exemption of a token account of 165 bytes.
+const TOKEN_ACCOUNT_RENT_LAMPORTS: u64 = 2_039_280;
+// The compute unit limit of every instruction of a transaction which does not set one, up to the maximum.
+const DEFAULT_INSTRUCTION_COMPUTE_UNITS: u64 = 200_000;
+// The tags of the SetComputeUnitLimit and SetComputeUnitPrice instructions of the Compute Budget program.
+const SET_COMPUTE_UNIT_LIMIT_TAG: u8 = 2;
+const SET_COMPUTE_UNIT_PRICE_TAG: u8 = 3;
+const WRAPPED_SOL_MINT: &str = "So11111111111111111111111111111111111111112";
+
+/// The expected profit of a liquidation and its components, in USD.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct ProfitEstimate {
+    // The liquidator fee on the seized collateral.
+    pub bonus_usd: f64,
+    // The signature and priority fees of the transactions.
+    pub priority_fee_usd: f64,
+    // The Jito tip, with the fee of its transaction.
+    pub tip_usd: f64,
+    // The Jito tip alone, in lamports.
+    pub tip_lamports: u64,
+    // Selling the seized collateral, unless it is held.
+    pub slippage_usd: f64,
+    // The wallet token account of the collateral, when it is missing.
+    pub rent_usd: f64,
+}
+
+impl ProfitEstimate {
+    pub fn profit_usd(&self) -> f64 {
+        self.bonus_usd - self.priority_fee_usd - self.tip_usd - self.slippage_usd - self.rent_usd
+    }
+
+    fn add(&mut self, other: &ProfitEstimate) {
+        self.bonus_usd += other.bonus_usd;
+        self.priority_fee_usd += other.priority_fee_usd;
+        self.tip_usd += other.tip_usd;
+        self.tip_lamports += other.tip_lamports;
+        self.slippage_usd += other.slippage_usd;
+        self.rent_usd += other.rent_usd;
+    }
+}
+
+impl fmt::Display for ProfitEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.4} USD (bonus {:.4}, priority fees {:.4}, tip {:.4}, slippage {:.4}, rent {:.4})",
+            self.profit_usd(),
+            self.bonus_usd,
+            self.priority_fee_usd,
+            self.tip_usd,
+            self.slippage_usd,
+            self.rent_usd
+        )
+    }
+}
+
+/// The estimates since the startup, as logged with the stats and served by the admin API.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct ProfitEstimateStats {
+    pub estimates: u64,
+    pub unprofitable: u64,
+    // The sum of the estimates.
+    pub total: ProfitEstimate,
+    pub last: Option<ProfitEstimate>,
+}
+
+impl ProfitEstimateStats {
+    fn record(&mut self, estimate: &ProfitEstimate) {
+        self.estimates += 1;
+        if estimate.profit_usd() <= 0.0 {
+            self.unprofitable += 1;
+        }
+        self.total.add(estimate);
+        self.last = Some(*estimate);
+    }
+}
+
+impl fmt::Display for ProfitEstimateStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} Estimates, {} Unprofitable, {:.2} USD Expected Profit",
+            self.estimates,
+            self.unprofitable,
+            self.total.profit_usd()
+        )
+    }
+}
+
+/// The profit estimates of the liquidation candidates.
+#[derive(Default)]
+pub struct ProfitEstimates {
+    stats: Mutex<ProfitEstimateStats>,
+}
+
+impl ProfitEstimates {
+    pub fn record(&self, estimate: &ProfitEstimate) -> Result<()> {
+        self.stats
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the profit estimates: {}", e))?
+            .record(estimate);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<ProfitEstimateStats> {
+        Ok(*self
+            .stats
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the profit estimates: {}", e))?)
+    }
+}
+
+/// The Jito tip of the bundles: a share of the expected profit of the liquidation before the tip, within bounds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BundleTip {
+    profit_bps: u64,
+    min_lamports: u64,
+    max_lamports: u64,
+}
+
+impl BundleTip {
+    fn lamports(&self, profit_lamports: f64) -> u64 {
+        let tip = profit_lamports.max(0.0) * self.profit_bps as f64 / 10_000.0;
+        (tip as u64).clamp(self.min_lamports, self.max_lamports)
+    }
+}
+
+/// Estimates the expected profit of the liquidations: the liquidator fee on the seized collateral, minus the
+/// transaction fees, the Jito tip, the slippage of selling the collateral and the rent of the token account
+/// created for it.
+pub struct ProfitEstimator {
+    cache: Arc<Cache>,
+    slippage_bps: u64,
+    // The transactions are only tipped when sent as Jito bundles.
+    bundle_tip: Option<BundleTip>,
+    profit_hold_mint: Option<Pubkey>,
+    wrapped_sol_mint: Pubkey,
+    min_profit_usd: f64,
+    // By collateral mint.
+    min_profit_usd_overrides: BTreeMap<Pubkey, f64>,
+}
+
+impl ProfitEstimator {
+    pub fn new(config: &Config, cache: Arc<Cache>) -> Self {
+        Self {
+            cache,
+            slippage_bps: config.liquidation_slippage_bps,
+            bundle_tip: config.jito_block_engine_url.as_ref().map(|_| BundleTip {
+                profit_bps: config.jito_tip_profit_bps,
+                min_lamports: config.jito_min_tip_lamports,
+                max_lamports: config.jito_max_tip_lamports,
+            }),
+            profit_hold_mint: config.profit_hold_mint,
+            wrapped_sol_mint: Pubkey::from_str(WRAPPED_SOL_MINT).expect("Invalid wSOL mint"),
+            min_profit_usd: config.min_profit_usd,
+            min_profit_usd_overrides: config.min_profit_usd_overrides.clone(),
+        }
+    }
+
+    /// The minimum profit of the liquidations seizing the collateral of the Bank, in USD.
+    pub fn min_profit_usd(&self, asset_bank: &Pubkey) -> Result<f64> {
+        let asset_bank = self.cache.banks.get_bank(asset_bank)?;
+        Ok(self
+            .min_profit_usd_overrides
+            .get(asset_bank.mint())
+            .copied()
+            .unwrap_or(self.min_profit_usd))
+    }
+
+    /// The Jito tip of the liquidation, in lamports: the one of its estimate, the minimum when it has none, 0
+    /// when the transactions are not sent as bundles.
+    pub fn tip_lamports(&self, estimate: Option<&ProfitEstimate>) -> u64 {
+        match (&self.bundle_tip, estimate) {
+            (None, _) => 0,
+            (Some(_), Some(estimate)) => estimate.tip_lamports,
+            (Some(bundle_tip), None) => bundle_tip.min_lamports,
+        }
+    }
+
+    /// The expected profit of the liquidation sent as the transactions, tipping a share of it when sent as a
+    /// Jito bundle. None when the collateral or SOL has no
+    /// valid price, SOL being priced by its Bank.
+    pub fn estimate(
+        &self,
+        params: &LiquidationParams,
+        transactions: &[VersionedTransaction],
+    ) -> Result<Option<ProfitEstimate>> {
+        let unix_timestamp = self.cache.get_extrapolated_clock()?.unix_timestamp;
+        let asset_bank = self.cache.banks.get_bank(&params.asset_bank)?;
+        let Some(asset_price) = self.cache.get_bank_price(&asset_bank, unix_timestamp) else {
+            return Ok(None);
+        };
+        let Some(sol_price) = self.sol_price(unix_timestamp)? else {
+            return Ok(None);
+        };
+
+        let collateral_value = 10u64
+            .checked_pow(asset_bank.mint_decimals() as u32)
+            .and_then(|unit| {
+                I80F48::from_num(params.asset_amount).checked_div(I80F48::from_num(unit))
+            })
+            .and_then(|amount| amount.checked_mul(asset_price))
+            .map_or(0.0, |value| value.to_num::<f64>());
+        let sells_collateral = Some(*asset_bank.mint()) != self.profit_hold_mint;
+        let rent_lamports = if self
+            .cache
+            .wallet_token_accounts
+            .by_mint()?
+            .contains_key(asset_bank.mint())
+        {
+            0
+        } else {
+            TOKEN_ACCOUNT_RENT_LAMPORTS
+        };
+
+        Ok(Some(estimate_profit(
+            collateral_value,
+            if sells_collateral {
+                self.slippage_bps
+            } else {
+                0
+            },
+            transactions.iter().map(transaction_fee_lamports).sum(),
+            self.bundle_tip.as_ref(),
+            rent_lamports,
+            sol_price.to_num::<f64>(),
+        )))
+    }
+
+    fn sol_price(&self, unix_timestamp: i64) -> Result<Option<I80F48>> {
+        Ok(self
+            .cache
+            .banks
+            .get_banks()?
+            .iter()
+            .filter(|bank| *bank.mint() == self.wrapped_sol_mint)
+            .find_map(|bank| self.cache.get_bank_price(bank, unix_timestamp)))
+    }
+}
+
+/// Parses the `,` separated `<mint>:<usd>` minimum profit overrides.
+pub fn parse_min_profit_overrides(overrides: &str) -> Result<BTreeMap<Pubkey, f64>> {
+    overrides
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (mint, min_profit_usd) = entry
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Expected <mint>:<usd>, got {:?}", entry))?;
+            let mint = Pubkey::from_str(mint.trim())
+                .map_err(|e| anyhow!("Invalid mint {:?}: {}", mint, e))?;
+            let min_profit_usd = min_profit_usd
+                .trim()
+                .parse::<f64>()
+                .map_err(|e| anyhow!("Invalid minimum profit {:?}: {}", entry, e))?;
+            Ok((mint, min_profit_usd))
+        })
+        .collect()
+}
+
+fn estimate_profit(
+    collateral_value_usd: f64,
+    slippage_bps: u64,
+    fee_lamports: u64,
+    bundle_tip: Option<&BundleTip>,
+    rent_lamports: u64,
+    sol_price_usd: f64,
+) -> ProfitEstimate {
+    let lamports_usd = |lamports: u64| lamports as f64 / LAMPORTS_PER_SOL as f64 * sol_price_usd;
+    let mut estimate = ProfitEstimate {
+        bonus_usd: collateral_value_usd * LIQUIDATION_LIQUIDATOR_FEE.to_num::<f64>(),
+        priority_fee_usd: lamports_usd(fee_lamports),
+        slippage_usd: collateral_value_usd * slippage_bps as f64 / 10_000.0,
+        rent_usd: lamports_usd(rent_lamports),
+        ..Default::default()
+    };
+    if let Some(bundle_tip) = bundle_tip {
+        let profit_lamports = estimate.profit_usd() / sol_price_usd * LAMPORTS_PER_SOL as f64;
+        estimate.tip_lamports = bundle_tip.lamports(profit_lamports);
+        estimate.tip_usd = lamports_usd(estimate.tip_lamports + LAMPORTS_PER_SIGNATURE);
+    }
+    estimate
+}
+
+/// The signature fees of the transaction plus its priority fee, the compute unit price of its Compute Budget
+/// instructions times its compute unit limit.
+pub fn transaction_fee_lamports(transaction: &VersionedTransaction) -> u64 {
+    let message = &transaction.message;
+    let account_keys = message.static_account_keys();
+    let mut compute_unit_limit = None;
+    let mut compute_unit_price = 0u64;
+    let mut instructions = 0u64;
+    for instruction in message.instructions() {
+        let program_id = account_keys.get(instruction.program_id_index as usize);
+        if program_id != Some(&compute_budget::id()) {
+            instructions += 1;
+            continue;
+        }
+        match instruction.data.split_first() {
+            Some((&SET_COMPUTE_UNIT_LIMIT_TAG, data)) => {
+                if let Ok(limit) = data.try_into().map(u32::from_le_bytes) {
+                    compute_unit_limit = Some(limit as u64);
+                }
+            }
+            Some((&SET_COMPUTE_UNIT_PRICE_TAG, data)) => {
+                if let Ok(price) = data.try_into().map(u64::from_le_bytes) {
+                    compute_unit_price = price;
+                }
+            }
+            _ => {}
+        }
+    }
+    let compute_unit_limit = compute_unit_limit
+        .unwrap_or(instructions * DEFAULT_INSTRUCTION_COMPUTE_UNITS)
+        .min(MAX_COMPUTE_UNIT_LIMIT as u64);
+    let priority_fee =
+        (compute_unit_price as u128 * compute_unit_limit as u128).div_ceil(1_000_000);
+    message.header().num_required_signatures as u64 * LAMPORTS_PER_SIGNATURE + priority_fee as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{
+        compute_budget::ComputeBudgetInstruction,
+        hash::Hash,
+        instruction::Instruction,
+        message::{v0, VersionedMessage},
+        signature::Keypair,
+        signer::Signer,
+    };
+
+    use super::*;
+
+    fn create_transaction(instructions: &[Instruction]) -> VersionedTransaction {
+        let payer = Keypair::new();
+        let message =
+            v0::Message::try_compile(&payer.pubkey(), instructions, &[], Hash::default()).unwrap();
+        VersionedTransaction::try_new(VersionedMessage::V0(message), &[&payer]).unwrap()
+    }
+
+    #[test]
+    fn test_transaction_fee_lamports() {
+        let memo = Instruction::new_with_bytes(Pubkey::new_unique(), &[], vec![]);
+        assert_eq!(
+            transaction_fee_lamports(&create_transaction(&[memo.clone()])),
+            LAMPORTS_PER_SIGNATURE
+        );
+
+        let transaction = create_transaction(&[
+            ComputeBudgetInstruction::set_compute_unit_limit(400_000),
+            ComputeBudgetInstruction::set_compute_unit_price(10_000),
+            memo.clone(),
+        ]);
+        assert_eq!(
+            transaction_fee_lamports(&transaction),
+            LAMPORTS_PER_SIGNATURE + 4_000
+        );
+
+        // Without a limit, every other instruction gets the default one.
+        let transaction = create_transaction(&[
+            ComputeBudgetInstruction::set_compute_unit_price(1_000),
+            memo.clone(),
+            memo,
+        ]);
+        assert_eq!(
+            transaction_fee_lamports(&transaction),
+            LAMPORTS_PER_SIGNATURE + 400
+        );
+    }
+
+    #[test]
+    fn test_parse_min_profit_overrides() {
+        let mint = Pubkey::new_unique();
+        assert_eq!(
+            parse_min_profit_overrides(&format!("{}:2.5, ", mint)).unwrap(),
+            BTreeMap::from([(mint, 2.5)])
+        );
+        assert!(parse_min_profit_overrides("").unwrap().is_empty());
+        assert!(parse_min_profit_overrides(&mint.to_string()).is_err());
+        assert!(parse_min_profit_overrides(&format!("{}:lots", mint)).is_err());
+        assert!(parse_min_profit_overrides("mint:1").is_err());
+    }
+
+    #[test]
+    fn test_estimate_profit() {
+        let estimate = estimate_profit(1_000.0, 50, 1_000_000, None, 2_000_000, 100.0);
+        assert!((estimate.bonus_usd - 25.0).abs() < 1e-9);
+        assert!((estimate.slippage_usd - 5.0).abs() < 1e-9);
+        assert!((estimate.priority_fee_usd - 0.1).abs() < 1e-9);
+        assert_eq!(estimate.tip_usd, 0.0);
+        assert!((estimate.rent_usd - 0.2).abs() < 1e-9);
+        assert!((estimate.profit_usd() - 19.7).abs() < 1e-9);
+
+        // The minimum tip and the fees exceed the bonus of a small liquidation.
+        let bundle_tip = BundleTip {
+            profit_bps: 5_000,
+            min_lamports: 1_000_000_000,
+            max_lamports: 1_000_000_000,
+        };
+        let estimate = estimate_profit(1.0, 0, 1_000_000, Some(&bundle_tip), 0, 100.0);
+        assert!(estimate.profit_usd() < 0.0);
+    }
+
+    #[test]
+    fn test_estimate_profit_tips_a_share_of_the_profit() {
+        let bundle_tip = BundleTip {
+            profit_bps: 5_000,
+            min_lamports: 10_000,
+            max_lamports: 100_000_000,
+        };
+        // 19.7 USD of profit before the tip, 0.197 SOL, half of it tipped.
+        let estimate = estimate_profit(1_000.0, 50, 1_000_000, Some(&bundle_tip), 2_000_000, 100.0);
+        assert!((98_499_999..=98_500_000).contains(&estimate.tip_lamports));
+        assert!((estimate.tip_usd - 9.8505).abs() < 1e-6);
+        assert!((estimate.profit_usd() - 9.8495).abs() < 1e-6);
+
+        // Within the bounds.
+        let estimate = estimate_profit(10_000.0, 0, 0, Some(&bundle_tip), 0, 100.0);
+        assert_eq!(estimate.tip_lamports, 100_000_000);
+        let estimate = estimate_profit(0.0, 0, 1_000_000, Some(&bundle_tip), 0, 100.0);
+        assert_eq!(estimate.tip_lamports, 10_000);
+    }
+
+    #[test]
+    fn test_profit_estimates() {
+        let estimates = ProfitEstimates::default();
+        let profitable = ProfitEstimate {
+            bonus_usd: 10.0,
+            priority_fee_usd: 1.0,
+            ..Default::default()
+        };
+        let unprofitable = ProfitEstimate {
+            bonus_usd: 1.0,
+            tip_usd: 2.0,
+            ..Default::default()
+        };
+        estimates.record(&profitable).unwrap();
+        estimates.record(&unprofitable).unwrap();
+        let stats = estimates.stats().unwrap();
+        assert_eq!(stats.estimates, 2);
+        assert_eq!(stats.unprofitable, 1);
+        assert_eq!(stats.total.profit_usd(), 8.0);
+        assert_eq!(stats.last, Some(unprofitable));
+    }
+}
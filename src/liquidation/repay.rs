@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use fixed::types::I80F48;
+use marginfi::constants::{LIQUIDATION_INSURANCE_FEE, LIQUIDATION_LIQUIDATOR_FEE};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::liquidation::wallet::WalletTokenAccount;
+
+/// Where the liquidator takes the funds to repay the liquidatee's liability from.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum RepaySource {
+    /// The liability is taken on by the liquidator's marginfi account, backed by its free collateral.
+    #[default]
+    LiquidatorAccount,
+    /// The liability tokens are first deposited from the wallet token account into the liquidator's
+    /// marginfi account, which then takes on the liability (deposit-then-liquidate).
+    WalletDeposit { token_account: Pubkey, amount: u64 },
+    /// The liquidation runs within a marginfi flashloan, which defers the health check of the liquidator
+    /// account to its end: the account takes on the liability, the seized collateral is withdrawn and swapped
+    /// into the liability mint, whose wallet tokens repay the liability before the flashloan ends. The repay
+    /// amount is the liability of the liquidator account, which the swap must cover.
+    Flashloan { repay_amount: u64 },
+}
+
+/// Picks the repay source for a liquidation: the liquidator's marginfi account when it has the borrowing
+/// capacity, otherwise the wallet balance of the liability mint, otherwise a flashloan repaying the given
+/// amount, when possible. Returns None when none can cover it. The deposit amount is the liability the
+/// liquidator account takes on, see `liquidator_liability`, plus the transfer fee of the mint if any.
+pub fn select_repay_source(
+    has_borrow_capacity: bool,
+    liab_mint: &Pubkey,
+    deposit_amount: u64,
+    wallet_token_accounts: &HashMap<Pubkey, WalletTokenAccount>,
+    flashloan_repay_amount: Option<u64>,
+) -> Option<RepaySource> {
+    if has_borrow_capacity {
+        return Some(RepaySource::LiquidatorAccount);
+    }
+
+    wallet_token_accounts
+        .get(liab_mint)
+        .filter(|token_account| token_account.amount >= deposit_amount)
+        .map(|token_account| RepaySource::WalletDeposit {
+            token_account: token_account.address,
+            amount: deposit_amount,
+        })
+        .or_else(|| {
+            flashloan_repay_amount.map(|repay_amount| RepaySource::Flashloan { repay_amount })
+        })
+}
+
+/// The liability the liquidator account takes on when repaying the liability amount of the liquidatee: the
+/// program values it net of the liquidator fee only, the liquidator paying the insurance fee on top of the
+/// repaid amount. None on overflow.
+pub fn liquidator_liability(liab_amount: u64) -> Option<u64> {
+    let liquidator_share = I80F48::ONE.checked_sub(LIQUIDATION_LIQUIDATOR_FEE)?;
+    let liquidatee_share = liquidator_share.checked_sub(LIQUIDATION_INSURANCE_FEE)?;
+    I80F48::from_num(liab_amount)
+        .checked_mul(liquidator_share)?
+        .checked_div(liquidatee_share)?
+        .checked_ceil()?
+        .checked_to_num()
+}
+
+/// The largest liability amount of the liquidatee whose repayment the liquidator liability covers, the
+/// inverse of `liquidator_liability`. None on overflow.
+pub fn repayable_amount(liquidator_liability: u64) -> Option<u64> {
+    let liquidator_share = I80F48::ONE.checked_sub(LIQUIDATION_LIQUIDATOR_FEE)?;
+    let liquidatee_share = liquidator_share.checked_sub(LIQUIDATION_INSURANCE_FEE)?;
+    I80F48::from_num(liquidator_liability)
+        .checked_mul(liquidatee_share)?
+        .checked_div(liquidator_share)?
+        .checked_floor()?
+        .checked_to_num()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wallet_with(
+        mint: Pubkey,
+        address: Pubkey,
+        amount: u64,
+    ) -> HashMap<Pubkey, WalletTokenAccount> {
+        HashMap::from([(
+            mint,
+            WalletTokenAccount {
+                address,
+                mint,
+                amount,
+            },
+        )])
+    }
+
+    #[test]
+    fn test_select_repay_source_prefers_liquidator_account() {
+        let mint = Pubkey::new_unique();
+        let wallet = wallet_with(mint, Pubkey::new_unique(), 1_000);
+
+        assert_eq!(
+            select_repay_source(true, &mint, 100, &wallet, None),
+            Some(RepaySource::LiquidatorAccount)
+        );
+    }
+
+    #[test]
+    fn test_select_repay_source_falls_back_to_wallet() {
+        let mint = Pubkey::new_unique();
+        let token_account = Pubkey::new_unique();
+        let wallet = wallet_with(mint, token_account, 1_000);
+
+        assert_eq!(
+            select_repay_source(false, &mint, 1_000, &wallet, None),
+            Some(RepaySource::WalletDeposit {
+                token_account,
+                amount: 1_000
+            })
+        );
+    }
+
+    #[test]
+    fn test_select_repay_source_insufficient_wallet_balance() {
+        let mint = Pubkey::new_unique();
+        let wallet = wallet_with(mint, Pubkey::new_unique(), 99);
+
+        assert_eq!(select_repay_source(false, &mint, 100, &wallet, None), None);
+    }
+
+    #[test]
+    fn test_select_repay_source_falls_back_to_flashloan() {
+        let mint = Pubkey::new_unique();
+        let token_account = Pubkey::new_unique();
+        let wallet = wallet_with(mint, token_account, 99);
+
+        assert_eq!(
+            select_repay_source(false, &mint, 100, &wallet, Some(102)),
+            Some(RepaySource::Flashloan { repay_amount: 102 })
+        );
+        // The wallet is preferred when it covers the liability.
+        assert_eq!(
+            select_repay_source(false, &mint, 99, &wallet, Some(101)),
+            Some(RepaySource::WalletDeposit {
+                token_account,
+                amount: 99
+            })
+        );
+    }
+
+    #[test]
+    fn test_select_repay_source_missing_token_account() {
+        let wallet = wallet_with(Pubkey::new_unique(), Pubkey::new_unique(), 1_000);
+
+        assert_eq!(
+            select_repay_source(false, &Pubkey::new_unique(), 1, &wallet, None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_liquidator_liability() {
+        // Repaying 950 tokens of the liquidatee costs the liquidator 975 tokens, net of its 2.5% fee only, up
+        // to the rounding.
+        let liability = liquidator_liability(950_000).unwrap();
+        assert!((975_000..=975_001).contains(&liability));
+        assert_eq!(liquidator_liability(0), Some(0));
+    }
+
+    #[test]
+    fn test_repayable_amount() {
+        let repayable = repayable_amount(975_000).unwrap();
+        assert!((949_999..=950_000).contains(&repayable));
+        assert!(liquidator_liability(repayable).unwrap() <= 975_000);
+        assert_eq!(repayable_amount(0), Some(0));
+    }
+}
@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use reqwest::{blocking::Client, header::CONTENT_TYPE};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    config::{redact_url, Config},
+    liquidation::LiquidationParams,
+};
+
+/// The decision of the external risk service on a liquidation opportunity.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "decision", rename_all = "lowercase")]
+pub enum RiskDecision {
+    Approve,
+    Deny {
+        #[serde(default)]
+        reason: String,
+    },
+    /// Liquidate a smaller part of the liability.
+    Resize {
+        liab_amount: u64,
+    },
+}
+
+/// Submits the liquidation opportunities to the external risk service of the teams that centralize their
+/// risk controls, before they are sent. When the service fails or does not answer in time, the opportunities
+/// are approved (fail-open) or denied (fail-closed).
+pub struct RiskHook {
+    client: Client,
+    url: String,
+    namespace: String,
+    fail_open: bool,
+}
+
+impl RiskHook {
+    /// None when no risk service is configured.
+    pub fn new(config: &Config) -> Result<Option<Self>> {
+        let Some(url) = config.risk_hook_url.clone() else {
+            return Ok(None);
+        };
+        let client = Client::builder()
+            .timeout(Duration::from_millis(config.risk_hook_timeout_ms))
+            .build()?;
+        Ok(Some(Self {
+            client,
+            url,
+            namespace: config.namespace().name.clone(),
+            fail_open: config.risk_hook_fail_open,
+        }))
+    }
+
+    pub fn review(&self, params: &LiquidationParams) -> RiskDecision {
+        match self.request(params) {
+            Ok(decision) => decision,
+            Err(err) if self.fail_open => {
+                warn!(
+                    "The risk service failed to review the liquidation of {}, approving it: {}",
+                    params.liquidatee, err
+                );
+                RiskDecision::Approve
+            }
+            Err(err) => RiskDecision::Deny {
+                reason: format!("the risk service failed: {}", err),
+            },
+        }
+    }
+
+    fn request(&self, params: &LiquidationParams) -> Result<RiskDecision> {
+        let body = json!({
+            "namespace": self.namespace,
+            "liquidatee": params.liquidatee.to_string(),
+            "liab_bank": params.liab_bank.to_string(),
+            "liab_mint": params.liab_mint.to_string(),
+            "liab_amount": params.liab_amount,
+        });
+        let response = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .map_err(|e| anyhow!("Failed to call {}: {}", redact_url(&self.url), e))?;
+        parse_decision(&response, params.liab_amount)
+    }
+}
+
+// The liquidations can only be resized down.
+fn parse_decision(body: &str, liab_amount: u64) -> Result<RiskDecision> {
+    let decision: RiskDecision = serde_json::from_str(body)
+        .map_err(|e| anyhow!("Invalid risk decision {:?}: {}", body, e))?;
+    match decision {
+        RiskDecision::Resize {
+            liab_amount: resized,
+        } if resized == 0 || resized > liab_amount => Err(anyhow!(
+            "Invalid resize of the liability amount {} to {}",
+            liab_amount,
+            resized
+        )),
+        decision => Ok(decision),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use solana_sdk::pubkey::Pubkey;
+    use tiny_http::{Response, Server};
+
+    use super::*;
+    use crate::{
+        config::test_util::create_dummy_config,
+        liquidation::{event_log::test_util::create_decision_accounts, repay::RepaySource},
+    };
+
+    fn create_params() -> LiquidationParams {
+        LiquidationParams {
+            liquidatee: Pubkey::new_unique(),
+            liab_bank: Pubkey::new_unique(),
+            liab_mint: Pubkey::new_unique(),
+            liab_amount: 100,
+            asset_bank: Pubkey::new_unique(),
+            asset_amount: 100,
+            repay_source: RepaySource::default(),
+            tip_lamports: 0,
+            collateral_swap: None,
+            decision_accounts: create_decision_accounts(),
+        }
+    }
+
+    fn create_risk_hook(url: String, fail_open: bool) -> RiskHook {
+        let mut config = create_dummy_config();
+        config.risk_hook_url = Some(url);
+        config.risk_hook_fail_open = fail_open;
+        RiskHook::new(&config).unwrap().unwrap()
+    }
+
+    // A URL nothing listens on.
+    fn unreachable_url() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/review", listener.local_addr().unwrap())
+    }
+
+    #[test]
+    fn test_parse_decision() {
+        assert_eq!(
+            parse_decision(r#"{"decision": "approve"}"#, 100).unwrap(),
+            RiskDecision::Approve
+        );
+        assert_eq!(
+            parse_decision(r#"{"decision": "deny", "reason": "exposure"}"#, 100).unwrap(),
+            RiskDecision::Deny {
+                reason: "exposure".to_string()
+            }
+        );
+        assert_eq!(
+            parse_decision(r#"{"decision": "resize", "liab_amount": 40}"#, 100).unwrap(),
+            RiskDecision::Resize { liab_amount: 40 }
+        );
+        assert!(parse_decision(r#"{"decision": "resize", "liab_amount": 101}"#, 100).is_err());
+        assert!(parse_decision(r#"{"decision": "resize", "liab_amount": 0}"#, 100).is_err());
+        assert!(parse_decision(r#"{"decision": "maybe"}"#, 100).is_err());
+    }
+
+    #[test]
+    fn test_review() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/review", server.server_addr());
+        thread::spawn(move || {
+            let request = server.incoming_requests().next().unwrap();
+            request
+                .respond(Response::from_string(
+                    r#"{"decision": "resize", "liab_amount": 50}"#,
+                ))
+                .unwrap();
+        });
+
+        assert_eq!(
+            create_risk_hook(url, false).review(&create_params()),
+            RiskDecision::Resize { liab_amount: 50 }
+        );
+    }
+
+    #[test]
+    fn test_review_unavailable_service() {
+        assert_eq!(
+            create_risk_hook(unreachable_url(), true).review(&create_params()),
+            RiskDecision::Approve
+        );
+        assert!(matches!(
+            create_risk_hook(unreachable_url(), false).review(&create_params()),
+            RiskDecision::Deny { .. }
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use log::{debug, error};
+use solana_sdk::{pubkey::Pubkey, transaction::VersionedTransaction};
+
+use crate::comms::CommsClient;
+
+/// The most compute units a transaction can consume, the limit of the transactions until they are simulated.
+pub const MAX_COMPUTE_UNIT_LIMIT: u32 = 1_400_000;
+
+/// The simulation result of all the transactions of a liquidation candidate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CandidateSimulation {
+    // The units consumed by every transaction, in order, 0 when the simulation does not report them.
+    Passed { units_consumed: Vec<u64> },
+    Failed { error: String },
+}
+
+/// Simulates the transactions of a candidate one after another, stopping at the first failure.
+pub fn simulate_candidate<T: CommsClient>(
+    comms_client: &T,
+    transactions: &[VersionedTransaction],
+) -> CandidateSimulation {
+    let mut units_consumed = Vec::with_capacity(transactions.len());
+    for transaction in transactions {
+        match comms_client.simulate_transaction(transaction) {
+            Ok(simulation) => {
+                if let Some(error) = simulation.error {
+                    return CandidateSimulation::Failed { error };
+                }
+                units_consumed.push(simulation.units_consumed.unwrap_or_default());
+            }
+            Err(err) => {
+                return CandidateSimulation::Failed {
+                    error: err.to_string(),
+                }
+            }
+        }
+    }
+    CandidateSimulation::Passed { units_consumed }
+}
+
+/// The compute unit limit of the simulated transactions: the most units consumed by one of them plus the
+/// margin, in bps, within the maximum. None when the simulation did not report the units consumed.
+pub fn compute_unit_limit(units_consumed: &[u64], margin_bps: u64) -> Option<u32> {
+    let units = units_consumed
+        .iter()
+        .copied()
+        .max()
+        .filter(|units| *units > 0)?;
+    let limit = units.saturating_add(units.saturating_mul(margin_bps).div_ceil(10_000));
+    Some(limit.min(MAX_COMPUTE_UNIT_LIMIT as u64) as u32)
+}
+
+/// Simulates the candidates concurrently, with at most `concurrency` simulations in flight.
+pub fn simulate_candidates<T: CommsClient>(
+    comms_client: &T,
+    candidates: Vec<(Pubkey, Vec<VersionedTransaction>)>,
+    concurrency: usize,
+) -> HashMap<Pubkey, CandidateSimulation> {
+    let next_candidate = AtomicUsize::new(0);
+    let simulations = Mutex::new(HashMap::with_capacity(candidates.len()));
+    let workers = concurrency.clamp(1, candidates.len().max(1));
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next_candidate.fetch_add(1, Ordering::Relaxed);
+                let Some((address, transactions)) = candidates.get(index) else {
+                    break;
+                };
+
+                let simulation = simulate_candidate(comms_client, transactions);
+                debug!("Simulated the liquidation of {}: {:?}", address, simulation);
+                match simulations.lock() {
+                    Ok(mut simulations) => {
+                        simulations.insert(*address, simulation);
+                    }
+                    Err(e) => error!("Failed to lock the candidate simulations: {}", e),
+                }
+            });
+        }
+    });
+
+    simulations
+        .into_inner()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{
+        account::Account, hash::Hash, signature::Keypair, signer::Signer, system_instruction,
+        transaction::Transaction,
+    };
+
+    use super::*;
+    use crate::comms::test_util::MockedCommsClient;
+
+    fn create_transaction(payer: &Keypair, instructions: usize) -> VersionedTransaction {
+        let instructions: Vec<_> = (0..instructions)
+            .map(|_| system_instruction::transfer(&payer.pubkey(), &Pubkey::new_unique(), 1))
+            .collect();
+        VersionedTransaction::from(Transaction::new_signed_with_payer(
+            &instructions,
+            Some(&payer.pubkey()),
+            &[payer],
+            Hash::new_unique(),
+        ))
+    }
+
+    fn create_client(payers: &[&Keypair]) -> MockedCommsClient {
+        MockedCommsClient::with_accounts(
+            payers
+                .iter()
+                .map(|payer| {
+                    (
+                        payer.pubkey(),
+                        Account::new(1_000_000, 0, &Pubkey::default()),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_simulate_candidate() {
+        let payer = Keypair::new();
+        let client = create_client(&[&payer]);
+
+        assert_eq!(
+            simulate_candidate(
+                &client,
+                &[create_transaction(&payer, 1), create_transaction(&payer, 2)]
+            ),
+            CandidateSimulation::Passed {
+                units_consumed: vec![1_000, 2_000]
+            }
+        );
+        assert_eq!(
+            simulate_candidate(&client, &[]),
+            CandidateSimulation::Passed {
+                units_consumed: vec![]
+            }
+        );
+    }
+
+    #[test]
+    fn test_compute_unit_limit() {
+        // The most consuming transaction plus the margin.
+        assert_eq!(compute_unit_limit(&[50_000, 100_000], 2_000), Some(120_000));
+        assert_eq!(compute_unit_limit(&[100_001], 1), Some(100_012));
+        assert_eq!(compute_unit_limit(&[100_000], 0), Some(100_000));
+        // Within the maximum.
+        assert_eq!(
+            compute_unit_limit(&[1_300_000], 2_000),
+            Some(MAX_COMPUTE_UNIT_LIMIT)
+        );
+        // Not reported.
+        assert_eq!(compute_unit_limit(&[0], 2_000), None);
+        assert_eq!(compute_unit_limit(&[], 2_000), None);
+    }
+
+    #[test]
+    fn test_simulate_candidate_stops_at_first_failure() {
+        let payer = Keypair::new();
+        let unknown_payer = Keypair::new();
+        let client = create_client(&[&payer]);
+
+        assert_eq!(
+            simulate_candidate(
+                &client,
+                &[
+                    create_transaction(&unknown_payer, 1),
+                    create_transaction(&payer, 1)
+                ]
+            ),
+            CandidateSimulation::Failed {
+                error: "AccountNotFound".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_simulate_candidates() {
+        let payer = Keypair::new();
+        let unknown_payer = Keypair::new();
+        let client = create_client(&[&payer]);
+
+        let candidates: Vec<(Pubkey, Vec<VersionedTransaction>)> = (0..10)
+            .map(|i| {
+                let signer = if i % 2 == 0 { &payer } else { &unknown_payer };
+                (Pubkey::new_unique(), vec![create_transaction(signer, 1)])
+            })
+            .collect();
+        let expected: HashMap<Pubkey, bool> = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, (address, _))| (*address, i % 2 == 0))
+            .collect();
+
+        let simulations = simulate_candidates(&client, candidates, 3);
+        assert_eq!(simulations.len(), 10);
+        for (address, passed) in expected {
+            assert_eq!(
+                matches!(simulations[&address], CandidateSimulation::Passed { .. }),
+                passed
+            );
+        }
+    }
+
+    #[test]
+    fn test_simulate_candidates_empty() {
+        let client = create_client(&[]);
+        assert!(simulate_candidates(&client, vec![], 4).is_empty());
+    }
+}
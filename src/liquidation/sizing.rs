@@ -0,0 +1,300 @@
+use fixed::types::I80F48;
+use marginfi::state::{marginfi_account::Balance, marginfi_group::WrappedI80F48};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{
+    cache::{banks::CachedBank, prices::CachedPrice},
+    liquidation::health::{
+        high_price, reconciled_emode_config, weighted_values, HealthComponents, RequirementType,
+    },
+};
+
+// The maintenance health left under the requirement, in bps of the weighted liabilities, for the prices to
+// move a little between the sizing and the execution of the liquidation.
+const HEALTH_BUFFER_BPS: u64 = 10;
+
+/// Sizes the liquidation of the account: the largest liability amount, up to `max_liab_amount`, whose
+/// repayment the program accepts. The program rejects the liquidations which do not improve the maintenance
+/// health of the account, or which bring it over the maintenance requirement, so the amount is adjusted
+/// iteratively, by bisection, for the health to end just under the requirement. `seized` gives the collateral
+/// amount seized for a liability amount, None when the deposit does not cover it. None when no amount is
+/// accepted.
+///
+/// The liquidator fee exceeding the slippage of the collateral sale, the largest amount is the most
+/// profitable one too.
+// TODO: bound the amount by the price impact of the collateral sale once the swaps are quoted
+pub fn size_liquidation(
+    positions: &[Balance],
+    priced_bank: impl Fn(&Pubkey) -> Option<(CachedBank, CachedPrice)>,
+    asset_bank: &Pubkey,
+    liab_bank: &Pubkey,
+    max_liab_amount: u64,
+    seized: impl Fn(u64) -> Option<u64>,
+    unix_timestamp: i64,
+) -> Option<u64> {
+    let health_after = |liab_amount: u64| {
+        maintenance_health_after(
+            positions,
+            &priced_bank,
+            (asset_bank, seized(liab_amount)?),
+            (liab_bank, liab_amount),
+            unix_timestamp,
+        )
+    };
+    let before = health_after(0)?;
+    let buffer = before
+        .liabilities
+        .checked_mul(I80F48::from_num(HEALTH_BUFFER_BPS))?
+        .checked_div(I80F48::from_num(10_000))?;
+    let accepted = |liab_amount: u64| {
+        health_after(liab_amount).is_some_and(|health| health.free_collateral() <= -buffer)
+    };
+
+    let liab_amount = if accepted(max_liab_amount) {
+        max_liab_amount
+    } else {
+        if !accepted(0) {
+            return None;
+        }
+        let (mut low, mut high) = (0, max_liab_amount);
+        while high - low > 1 {
+            let middle = low + (high - low) / 2;
+            if accepted(middle) {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        low
+    };
+
+    let after = health_after(liab_amount)?;
+    (liab_amount > 0 && after.free_collateral() > before.free_collateral()).then_some(liab_amount)
+}
+
+/// The liability amount the free collateral of the liquidator account can borrow at the initial
+/// requirement: valued at the price plus its confidence interval and weighted by the initial liability weight,
+/// like the program does.
+pub fn borrowable_amount(
+    free_collateral: I80F48,
+    liab_bank: &CachedBank,
+    liab_price: &CachedPrice,
+) -> Option<u64> {
+    if free_collateral <= I80F48::ZERO {
+        return Some(0);
+    }
+    let unit_value = high_price(liab_price)?
+        .checked_mul(I80F48::from(liab_bank.config().liability_weight_init))?;
+    free_collateral
+        .checked_mul(I80F48::from_num(
+            10u64.checked_pow(liab_bank.mint_decimals() as u32)?,
+        ))?
+        .checked_div(unit_value)?
+        .checked_floor()?
+        .checked_to_num::<u64>()
+}
+
+// The maintenance health of the positions once the collateral amount is withdrawn from the asset Bank and the
+// liability amount is repaid to the liability Bank.
+fn maintenance_health_after(
+    positions: &[Balance],
+    priced_bank: impl Fn(&Pubkey) -> Option<(CachedBank, CachedPrice)>,
+    (asset_bank, asset_amount): (&Pubkey, u64),
+    (liab_bank, liab_amount): (&Pubkey, u64),
+    unix_timestamp: i64,
+) -> Option<HealthComponents> {
+    let mut priced_positions = Vec::with_capacity(positions.len());
+    for balance in positions {
+        let (bank, price) = priced_bank(&balance.bank_pk)?;
+        let (asset_share_value, liability_share_value) =
+            bank.projected_share_values(unix_timestamp);
+        let mut balance = *balance;
+        if balance.bank_pk == *asset_bank {
+            balance.asset_shares =
+                remaining_shares(balance.asset_shares, asset_amount, asset_share_value)?;
+        }
+        if balance.bank_pk == *liab_bank {
+            balance.liability_shares =
+                remaining_shares(balance.liability_shares, liab_amount, liability_share_value)?;
+        }
+        priced_positions.push((balance, bank, price));
+    }
+    // The emode pairs change once a borrow is repaid in full.
+    let emode_config = reconciled_emode_config(
+        priced_positions
+            .iter()
+            .map(|(balance, bank, _)| (balance, bank)),
+    );
+
+    let mut health = HealthComponents::default();
+    for (balance, bank, price) in &priced_positions {
+        let (assets, liabilities) = weighted_values(
+            bank,
+            price,
+            balance,
+            &emode_config,
+            RequirementType::Maintenance,
+            unix_timestamp,
+        )?;
+        health.assets = health.assets.checked_add(assets)?;
+        health.liabilities = health.liabilities.checked_add(liabilities)?;
+    }
+    Some(health)
+}
+
+// The shares left once the amount is taken out of them, None when they do not cover it.
+fn remaining_shares(
+    shares: WrappedI80F48,
+    amount: u64,
+    share_value: I80F48,
+) -> Option<WrappedI80F48> {
+    let remaining =
+        I80F48::from(shares).checked_sub(I80F48::from_num(amount).checked_div(share_value)?)?;
+    (remaining >= I80F48::ZERO).then_some(remaining.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::marginfi_accounts::test_util::create_balance,
+        liquidation::health::test_util::{cached, create_bank, create_price, NOW},
+    };
+
+    // SOL-like collateral at 100 USD and USDC-like liability at 1 USD, the account 25 USD under its
+    // maintenance requirement.
+    fn create_liquidatee() -> (CachedBank, CachedBank, Vec<Balance>) {
+        let collateral = cached(create_bank(0.75, 0.875, 1.0, 1.0));
+        let liability = cached(create_bank(0.0, 0.0, 1.25, 1.125));
+        let positions = vec![
+            // 10 deposited
+            create_balance(collateral.address, 10_000_000, 0),
+            // 800 borrowed
+            create_balance(liability.address, 0, 800_000_000),
+        ];
+        (collateral, liability, positions)
+    }
+
+    // Seizes the collateral at 100 USD discounted by 5%.
+    fn seized(liab_amount: u64) -> Option<u64> {
+        let amount = (liab_amount as u128 * 100 / 95 / 100) as u64;
+        (amount <= 10_000_000).then_some(amount)
+    }
+
+    #[test]
+    fn test_size_liquidation_just_under_the_requirement() {
+        let (collateral, liability, positions) = create_liquidatee();
+        let priced_bank = |address: &Pubkey| {
+            if *address == collateral.address {
+                Some((collateral.clone(), create_price(100.0, 0.0)))
+            } else {
+                Some((liability.clone(), create_price(1.0, 0.0)))
+            }
+        };
+
+        let liab_amount = size_liquidation(
+            &positions,
+            priced_bank,
+            &collateral.address,
+            &liability.address,
+            800_000_000,
+            seized,
+            NOW,
+        )
+        .unwrap();
+        let after = maintenance_health_after(
+            &positions,
+            priced_bank,
+            (&collateral.address, seized(liab_amount).unwrap()),
+            (&liability.address, liab_amount),
+            NOW,
+        )
+        .unwrap();
+        // Under the requirement by the buffer of 0.9 USD, 10 bps of the liabilities, by less than a unit of
+        // the liability.
+        assert!(after.free_collateral() <= I80F48::from_num(-0.899));
+        let next = maintenance_health_after(
+            &positions,
+            priced_bank,
+            (&collateral.address, seized(liab_amount + 1).unwrap()),
+            (&liability.address, liab_amount + 1),
+            NOW,
+        )
+        .unwrap();
+        assert!(next.free_collateral() > I80F48::from_num(-0.901));
+
+        // Every repaid USD improves the health by 1.125 - 0.875 / 0.95 USD: about 118 USD are repaid.
+        assert!((117_000_000..120_000_000).contains(&liab_amount));
+
+        // Capped by the capital of the liquidator.
+        assert_eq!(
+            size_liquidation(
+                &positions,
+                priced_bank,
+                &collateral.address,
+                &liability.address,
+                100_000_000,
+                seized,
+                NOW,
+            ),
+            Some(100_000_000)
+        );
+        assert_eq!(
+            size_liquidation(
+                &positions,
+                priced_bank,
+                &collateral.address,
+                &liability.address,
+                0,
+                seized,
+                NOW,
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_size_liquidation_rejects_worse_health() {
+        // Unweighted: the seized collateral is worth more than the liability it repays, the health worsens.
+        let collateral = cached(create_bank(1.0, 1.0, 1.0, 1.0));
+        let liability = cached(create_bank(0.0, 0.0, 1.0, 1.0));
+        let positions = vec![
+            create_balance(collateral.address, 10_000_000, 0),
+            create_balance(liability.address, 0, 1_100_000_000),
+        ];
+        let priced_bank = |address: &Pubkey| {
+            if *address == collateral.address {
+                Some((collateral.clone(), create_price(100.0, 0.0)))
+            } else {
+                Some((liability.clone(), create_price(1.0, 0.0)))
+            }
+        };
+
+        assert_eq!(
+            size_liquidation(
+                &positions,
+                priced_bank,
+                &collateral.address,
+                &liability.address,
+                1_100_000_000,
+                seized,
+                NOW,
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_borrowable_amount() {
+        let liability = cached(create_bank(0.0, 0.0, 1.25, 1.125));
+        // 100 USD of free collateral borrow 100 / (1.25 * (0.75 + 0.25)) tokens.
+        assert_eq!(
+            borrowable_amount(I80F48::from_num(100), &liability, &create_price(0.75, 0.25)),
+            Some(80_000_000)
+        );
+        assert_eq!(
+            borrowable_amount(I80F48::from_num(-1), &liability, &create_price(1.0, 0.0)),
+            Some(0)
+        );
+    }
+}
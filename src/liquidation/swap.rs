@@ -0,0 +1,359 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{blocking::Client, header::CONTENT_TYPE};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+};
+
+use crate::config::{redact_url, Config};
+
+/// The Jupiter Swap API.
+pub const DEFAULT_SWAP_API_URL: &str = "https://lite-api.jup.ag/swap/v1";
+// The routes are limited in accounts for the swap to fit in the flashloan transaction, with the liquidation.
+const MAX_SWAP_ACCOUNTS: u32 = 30;
+
+/// The quote of the swap of an exact input amount.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwapQuote {
+    pub in_amount: u64,
+    pub out_amount: u64,
+    // The output amount at the maximum slippage, under which the swap fails.
+    pub min_out_amount: u64,
+    // Passed back as is for the swap instructions.
+    response: Value,
+}
+
+/// The instructions of a quoted swap, from and into the associated token accounts of the wallet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwapInstructions {
+    // E.g. creating the associated token account of the output mint.
+    pub setup: Vec<Instruction>,
+    pub swap: Instruction,
+    pub cleanup: Vec<Instruction>,
+    // The LUTs the swap transaction is compiled with.
+    pub lookup_tables: Vec<Pubkey>,
+}
+
+/// Quotes the swaps of the seized collateral into the liability mint, and gets their instructions, from the
+/// Jupiter Swap API or any API compatible with it.
+pub struct SwapClient {
+    client: Client,
+    url: String,
+    slippage_bps: u64,
+}
+
+impl SwapClient {
+    /// None unless the flashloan liquidations are enabled, the only ones swapping within the transaction.
+    pub fn new(config: &Config) -> Result<Option<Self>> {
+        if !config.flashloan_liquidations {
+            return Ok(None);
+        }
+        let client = Client::builder()
+            .timeout(Duration::from_secs(config.rpc_timeout_sec))
+            .build()?;
+        Ok(Some(Self {
+            client,
+            url: config.swap_api_url.trim_end_matches('/').to_string(),
+            slippage_bps: config.liquidation_slippage_bps,
+        }))
+    }
+
+    /// Quotes the swap of the input amount at the expected slippage of the collateral sales.
+    pub fn quote(
+        &self,
+        input_mint: &Pubkey,
+        output_mint: &Pubkey,
+        amount: u64,
+    ) -> Result<SwapQuote> {
+        let url = format!("{}/quote", self.url);
+        let response = self
+            .client
+            .get(&url)
+            .query(&[
+                ("inputMint", input_mint.to_string()),
+                ("outputMint", output_mint.to_string()),
+                ("amount", amount.to_string()),
+                ("slippageBps", self.slippage_bps.to_string()),
+                ("swapMode", "ExactIn".to_string()),
+                ("maxAccounts", MAX_SWAP_ACCOUNTS.to_string()),
+            ])
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .map_err(|e| anyhow!("Failed to call {}: {}", redact_url(&url), e))?;
+        parse_quote(&response)
+    }
+
+    /// The instructions of the quoted swap signed by the user. The native SOL is neither wrapped nor unwrapped,
+    /// the Banks hold wSOL.
+    pub fn instructions(&self, quote: &SwapQuote, user: &Pubkey) -> Result<SwapInstructions> {
+        let url = format!("{}/swap-instructions", self.url);
+        let body = json!({
+            "quoteResponse": quote.response,
+            "userPublicKey": user.to_string(),
+            "wrapAndUnwrapSol": false,
+        });
+        let response = self
+            .client
+            .post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .map_err(|e| anyhow!("Failed to call {}: {}", redact_url(&url), e))?;
+        parse_swap_instructions(&response)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuoteAmounts {
+    in_amount: String,
+    out_amount: String,
+    other_amount_threshold: String,
+}
+
+// The API returns the token amounts as strings.
+fn parse_quote(body: &str) -> Result<SwapQuote> {
+    let response: Value =
+        serde_json::from_str(body).map_err(|e| anyhow!("Invalid swap quote {:?}: {}", body, e))?;
+    let amounts: QuoteAmounts = serde_json::from_value(response.clone())
+        .map_err(|e| anyhow!("Invalid swap quote {:?}: {}", body, e))?;
+    let parse_amount = |amount: &str| {
+        amount
+            .parse::<u64>()
+            .map_err(|e| anyhow!("Invalid swap quote amount {:?}: {}", amount, e))
+    };
+    Ok(SwapQuote {
+        in_amount: parse_amount(&amounts.in_amount)?,
+        out_amount: parse_amount(&amounts.out_amount)?,
+        min_out_amount: parse_amount(&amounts.other_amount_threshold)?,
+        response,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SwapInstructionsResponse {
+    #[serde(default)]
+    setup_instructions: Vec<ApiInstruction>,
+    swap_instruction: ApiInstruction,
+    cleanup_instruction: Option<ApiInstruction>,
+    #[serde(default)]
+    address_lookup_table_addresses: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiInstruction {
+    program_id: String,
+    accounts: Vec<ApiAccountMeta>,
+    // Base64 encoded.
+    data: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiAccountMeta {
+    pubkey: String,
+    is_signer: bool,
+    is_writable: bool,
+}
+
+impl ApiInstruction {
+    fn into_instruction(self) -> Result<Instruction> {
+        Ok(Instruction {
+            program_id: parse_pubkey(&self.program_id)?,
+            accounts: self
+                .accounts
+                .iter()
+                .map(|account| {
+                    Ok(AccountMeta {
+                        pubkey: parse_pubkey(&account.pubkey)?,
+                        is_signer: account.is_signer,
+                        is_writable: account.is_writable,
+                    })
+                })
+                .collect::<Result<Vec<AccountMeta>>>()?,
+            data: STANDARD
+                .decode(&self.data)
+                .map_err(|e| anyhow!("Invalid swap instruction data {:?}: {}", self.data, e))?,
+        })
+    }
+}
+
+// The compute budget instructions of the API are left out, the liquidation sets its own.
+fn parse_swap_instructions(body: &str) -> Result<SwapInstructions> {
+    let response: SwapInstructionsResponse = serde_json::from_str(body)
+        .map_err(|e| anyhow!("Invalid swap instructions {:?}: {}", body, e))?;
+    Ok(SwapInstructions {
+        setup: response
+            .setup_instructions
+            .into_iter()
+            .map(ApiInstruction::into_instruction)
+            .collect::<Result<Vec<Instruction>>>()?,
+        swap: response.swap_instruction.into_instruction()?,
+        cleanup: response
+            .cleanup_instruction
+            .map(ApiInstruction::into_instruction)
+            .transpose()?
+            .into_iter()
+            .collect(),
+        lookup_tables: response
+            .address_lookup_table_addresses
+            .iter()
+            .map(String::as_str)
+            .map(parse_pubkey)
+            .collect::<Result<Vec<Pubkey>>>()?,
+    })
+}
+
+fn parse_pubkey(address: &str) -> Result<Pubkey> {
+    Pubkey::from_str(address).map_err(|e| anyhow!("Invalid address {:?}: {}", address, e))
+}
+
+#[cfg(test)]
+pub mod test_util {
+    use super::*;
+
+    pub fn create_swap_instructions(user: &Pubkey) -> SwapInstructions {
+        SwapInstructions {
+            setup: vec![],
+            swap: Instruction::new_with_bytes(
+                Pubkey::new_unique(),
+                &[1, 2, 3],
+                vec![AccountMeta::new_readonly(*user, true)],
+            ),
+            cleanup: vec![],
+            lookup_tables: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, thread};
+
+    use tiny_http::{Response, Server};
+
+    use super::*;
+    use crate::config::test_util::create_dummy_config;
+
+    const QUOTE: &str = r#"{
+        "inputMint": "So11111111111111111111111111111111111111112",
+        "inAmount": "1000000000",
+        "outputMint": "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v",
+        "outAmount": "150000000",
+        "otherAmountThreshold": "149250000",
+        "swapMode": "ExactIn",
+        "slippageBps": 50,
+        "routePlan": []
+    }"#;
+
+    fn swap_instructions_response(program_id: &Pubkey, user: &Pubkey, lut: &Pubkey) -> String {
+        let instruction = json!({
+            "programId": program_id.to_string(),
+            "accounts": [{"pubkey": user.to_string(), "isSigner": true, "isWritable": true}],
+            "data": STANDARD.encode([1, 2, 3]),
+        });
+        json!({
+            "computeBudgetInstructions": [instruction],
+            "setupInstructions": [instruction],
+            "swapInstruction": instruction,
+            "cleanupInstruction": null,
+            "addressLookupTableAddresses": [lut.to_string()],
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_parse_quote() {
+        let quote = parse_quote(QUOTE).unwrap();
+        assert_eq!(quote.in_amount, 1_000_000_000);
+        assert_eq!(quote.out_amount, 150_000_000);
+        assert_eq!(quote.min_out_amount, 149_250_000);
+        assert_eq!(quote.response["routePlan"], json!([]));
+
+        assert!(parse_quote(r#"{"inAmount": "1"}"#).is_err());
+        assert!(parse_quote(
+            r#"{"inAmount": "1", "outAmount": "lots", "otherAmountThreshold": "1"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_swap_instructions() {
+        let (program_id, user, lut) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let instructions =
+            parse_swap_instructions(&swap_instructions_response(&program_id, &user, &lut)).unwrap();
+        let expected =
+            Instruction::new_with_bytes(program_id, &[1, 2, 3], vec![AccountMeta::new(user, true)]);
+        assert_eq!(instructions.setup, vec![expected.clone()]);
+        assert_eq!(instructions.swap, expected);
+        assert!(instructions.cleanup.is_empty());
+        assert_eq!(instructions.lookup_tables, vec![lut]);
+
+        assert!(parse_swap_instructions(r#"{"setupInstructions": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_quote_and_instructions() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let mut config = create_dummy_config();
+        config.flashloan_liquidations = true;
+        config.swap_api_url = format!("http://{}/", server.server_addr());
+        let (program_id, user, lut) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let response = swap_instructions_response(&program_id, &user, &lut);
+        let handle = thread::spawn(move || {
+            let mut requests = server.incoming_requests();
+            let quote = requests.next().unwrap();
+            let quote_url = quote.url().to_string();
+            quote.respond(Response::from_string(QUOTE)).unwrap();
+            let mut instructions = requests.next().unwrap();
+            let mut body = String::new();
+            instructions.as_reader().read_to_string(&mut body).unwrap();
+            let instructions_url = instructions.url().to_string();
+            instructions
+                .respond(Response::from_string(response))
+                .unwrap();
+            (quote_url, instructions_url, body)
+        });
+
+        let client = SwapClient::new(&config).unwrap().unwrap();
+        let (input_mint, output_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let quote = client.quote(&input_mint, &output_mint, 1_000).unwrap();
+        assert_eq!(quote.min_out_amount, 149_250_000);
+        let instructions = client.instructions(&quote, &user).unwrap();
+        assert_eq!(instructions.swap.program_id, program_id);
+
+        let (quote_url, instructions_url, body) = handle.join().unwrap();
+        assert!(quote_url.starts_with("/quote?"));
+        assert!(quote_url.contains(&format!("inputMint={}", input_mint)));
+        assert!(quote_url.contains("amount=1000"));
+        assert!(quote_url.contains("maxAccounts=30"));
+        assert_eq!(instructions_url, "/swap-instructions");
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["userPublicKey"], user.to_string());
+        assert_eq!(body["wrapAndUnwrapSol"], false);
+        assert_eq!(body["quoteResponse"]["outAmount"], "150000000");
+    }
+
+    #[test]
+    fn test_swap_client_requires_flashloans() {
+        assert!(SwapClient::new(&create_dummy_config()).unwrap().is_none());
+    }
+}
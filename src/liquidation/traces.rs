@@ -0,0 +1,188 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::liquidation::latency_budget::{BudgetedAttempt, LiquidationStage};
+
+#[derive(Debug, Clone)]
+struct TraceStep {
+    elapsed: Duration,
+    event: String,
+}
+
+/// Everything that happened along a liquidation attempt: the decisions, the quotes, the simulations and
+/// the stage timings, up to its outcome.
+#[derive(Debug, Clone)]
+pub struct ExecutionTrace {
+    address: Pubkey,
+    started_at_unix_ms: u64,
+    started: Instant,
+    steps: Vec<TraceStep>,
+    outcome: Option<String>,
+}
+
+impl ExecutionTrace {
+    pub fn start(address: Pubkey) -> Self {
+        Self {
+            address,
+            started_at_unix_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or_default(),
+            started: Instant::now(),
+            steps: vec![],
+            outcome: None,
+        }
+    }
+
+    pub fn record(&mut self, event: impl Into<String>) {
+        self.steps.push(TraceStep {
+            elapsed: self.started.elapsed(),
+            event: event.into(),
+        });
+    }
+
+    /// The first outcome sticks, e.g. an error reported after the outcome does not override it.
+    pub fn finish(&mut self, outcome: impl Into<String>) {
+        if self.outcome.is_none() {
+            self.outcome = Some(outcome.into());
+        }
+    }
+
+    /// Completes the stage of the budgeted attempt, returning whether it is still within its budget.
+    pub fn finish_stage(&mut self, attempt: &mut BudgetedAttempt, stage: LiquidationStage) -> bool {
+        if attempt.finish_stage(&self.address, stage) {
+            self.record(format!("{} stage completed", stage));
+            return true;
+        }
+        self.finish(format!(
+            "abandoned: the {} stage blew the latency budget",
+            stage
+        ));
+        false
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "address": self.address.to_string(),
+            "started_at_unix_ms": self.started_at_unix_ms,
+            "steps": self.steps.iter().map(|step| json!({
+                "elapsed_ms": step.elapsed.as_secs_f64() * 1000.0,
+                "event": step.event,
+            })).collect::<Vec<_>>(),
+            "outcome": self.outcome,
+        })
+    }
+}
+
+/// The ring buffer of the latest execution traces, to tell why a candidate was or was not liquidated
+/// without grepping the logs.
+pub struct ExecutionTraces {
+    capacity: usize,
+    traces: Mutex<VecDeque<ExecutionTrace>>,
+}
+
+impl ExecutionTraces {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            traces: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, trace: ExecutionTrace) -> Result<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut traces = self
+            .traces
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the execution traces: {}", e))?;
+        if traces.len() >= self.capacity {
+            traces.pop_front();
+        }
+        traces.push_back(trace);
+        Ok(())
+    }
+
+    /// The retained traces of the account, the latest first.
+    pub fn for_account(&self, address: &Pubkey) -> Result<Value> {
+        let traces = self
+            .traces
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the execution traces: {}", e))?;
+        Ok(Value::Array(
+            traces
+                .iter()
+                .rev()
+                .filter(|trace| trace.address == *address)
+                .map(ExecutionTrace::to_json)
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::liquidation::latency_budget::LatencyBudget;
+
+    fn finished_trace(address: Pubkey, outcome: &str) -> ExecutionTrace {
+        let mut trace = ExecutionTrace::start(address);
+        trace.record("prepared");
+        trace.finish(outcome);
+        trace
+    }
+
+    #[test]
+    fn test_execution_traces_ring_buffer() {
+        let traces = ExecutionTraces::new(2);
+        let address = Pubkey::new_unique();
+        traces.push(finished_trace(address, "first")).unwrap();
+        traces
+            .push(finished_trace(Pubkey::new_unique(), "other"))
+            .unwrap();
+        traces.push(finished_trace(address, "second")).unwrap();
+
+        let json = traces.for_account(&address).unwrap();
+        let outcomes: Vec<&str> = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|trace| trace["outcome"].as_str().unwrap())
+            .collect();
+        // The first trace of the account was evicted by the third one.
+        assert_eq!(outcomes, vec!["second"]);
+        assert_eq!(json[0]["steps"][0]["event"], "prepared");
+    }
+
+    #[test]
+    fn test_execution_trace_first_outcome_sticks() {
+        let mut trace = ExecutionTrace::start(Pubkey::new_unique());
+        trace.finish("landed");
+        trace.finish("error: late");
+        assert_eq!(trace.outcome.as_deref(), Some("landed"));
+    }
+
+    #[test]
+    fn test_execution_trace_finish_stage() {
+        let budget = LatencyBudget::new(0, &BTreeMap::new());
+        let mut attempt = budget.start();
+        let mut trace = ExecutionTrace::start(Pubkey::new_unique());
+        std::thread::sleep(Duration::from_millis(1));
+
+        assert!(!trace.finish_stage(&mut attempt, LiquidationStage::Sizing));
+        assert_eq!(
+            trace.outcome.as_deref(),
+            Some("abandoned: the sizing stage blew the latency budget")
+        );
+    }
+}
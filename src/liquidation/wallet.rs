@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use solana_sdk::{
+    account::Account,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    system_program,
+};
+
+use crate::common::{ASSOCIATED_TOKEN_PROGRAM_ID, SPL_TOKEN_2022_PROGRAM_ID, SPL_TOKEN_PROGRAM_ID};
+
+// Offsets of the base SPL token account layout, shared by Token-2022 accounts.
+const TOKEN_ACCOUNT_MINT_OFFSET: usize = 0;
+pub const TOKEN_ACCOUNT_OWNER_OFFSET: usize = 32;
+const TOKEN_ACCOUNT_AMOUNT_OFFSET: usize = 64;
+const TOKEN_ACCOUNT_BASE_LEN: usize = 165;
+// The CreateIdempotent instruction of the Associated Token Account program.
+const CREATE_IDEMPOTENT_TAG: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalletTokenAccount {
+    pub address: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+impl WalletTokenAccount {
+    pub fn from_account(address: Pubkey, account: &Account) -> Option<Self> {
+        if account.owner != SPL_TOKEN_PROGRAM_ID && account.owner != SPL_TOKEN_2022_PROGRAM_ID {
+            return None;
+        }
+        if account.data.len() < TOKEN_ACCOUNT_BASE_LEN {
+            return None;
+        }
+
+        let mint = Pubkey::try_from(
+            &account.data[TOKEN_ACCOUNT_MINT_OFFSET..TOKEN_ACCOUNT_MINT_OFFSET + 32],
+        )
+        .ok()?;
+        let amount = u64::from_le_bytes(
+            account.data[TOKEN_ACCOUNT_AMOUNT_OFFSET..TOKEN_ACCOUNT_AMOUNT_OFFSET + 8]
+                .try_into()
+                .ok()?,
+        );
+
+        Some(Self {
+            address,
+            mint,
+            amount,
+        })
+    }
+}
+
+/// Indexes the wallet token accounts by mint, keeping the best funded account for every mint.
+pub fn index_by_mint(
+    token_accounts: impl IntoIterator<Item = WalletTokenAccount>,
+) -> HashMap<Pubkey, WalletTokenAccount> {
+    let mut by_mint: HashMap<Pubkey, WalletTokenAccount> = HashMap::new();
+    for token_account in token_accounts {
+        match by_mint.get(&token_account.mint) {
+            Some(existing) if existing.amount >= token_account.amount => {}
+            _ => {
+                by_mint.insert(token_account.mint, token_account);
+            }
+        }
+    }
+    by_mint
+}
+
+/// The associated token account of the owner for the mint of the token program.
+pub fn associated_token_address(owner: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[owner.as_ref(), token_program.as_ref(), mint.as_ref()],
+        &ASSOCIATED_TOKEN_PROGRAM_ID,
+    )
+    .0
+}
+
+/// Creates the associated token account of the owner for the mint, paid by the payer, unless it exists.
+pub fn create_associated_token_account_idempotent(
+    payer: &Pubkey,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: ASSOCIATED_TOKEN_PROGRAM_ID,
+        accounts: vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(associated_token_address(owner, mint, token_program), false),
+            AccountMeta::new_readonly(*owner, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(*token_program, false),
+        ],
+        data: vec![CREATE_IDEMPOTENT_TAG],
+    }
+}
+
+#[cfg(test)]
+pub mod test_util {
+    use solana_sdk::{account::Account, pubkey::Pubkey};
+
+    use crate::common::SPL_TOKEN_PROGRAM_ID;
+
+    pub fn create_token_account(mint: Pubkey, owner: Pubkey, amount: u64) -> Account {
+        let mut data = vec![0u8; 165];
+        data[0..32].copy_from_slice(&mint.to_bytes());
+        data[32..64].copy_from_slice(&owner.to_bytes());
+        data[64..72].copy_from_slice(&amount.to_le_bytes());
+        Account {
+            lamports: 2_039_280,
+            data,
+            owner: SPL_TOKEN_PROGRAM_ID,
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::create_token_account;
+    use super::*;
+    use crate::comms::{test_util::MockedCommsClient, CommsClient};
+
+    fn parse(token_accounts: &[(Pubkey, Account)]) -> Vec<WalletTokenAccount> {
+        token_accounts
+            .iter()
+            .filter_map(|(address, account)| WalletTokenAccount::from_account(*address, account))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_account() {
+        let address = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let account = create_token_account(mint, Pubkey::new_unique(), 42);
+
+        let token_account = WalletTokenAccount::from_account(address, &account).unwrap();
+        assert_eq!(token_account.address, address);
+        assert_eq!(token_account.mint, mint);
+        assert_eq!(token_account.amount, 42);
+    }
+
+    #[test]
+    fn test_from_account_rejects_non_token_accounts() {
+        let mut account = create_token_account(Pubkey::new_unique(), Pubkey::new_unique(), 1);
+        account.owner = Pubkey::new_unique();
+        assert!(WalletTokenAccount::from_account(Pubkey::new_unique(), &account).is_none());
+    }
+
+    #[test]
+    fn test_from_account_rejects_short_data() {
+        let mut account = create_token_account(Pubkey::new_unique(), Pubkey::new_unique(), 1);
+        account.data.truncate(100);
+        assert!(WalletTokenAccount::from_account(Pubkey::new_unique(), &account).is_none());
+    }
+
+    #[test]
+    fn test_index_by_mint_keeps_best_funded_account() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let small = Pubkey::new_unique();
+        let large = Pubkey::new_unique();
+        let token_accounts = vec![
+            (small, create_token_account(mint, owner, 10)),
+            (large, create_token_account(mint, owner, 100)),
+        ];
+
+        let by_mint = index_by_mint(parse(&token_accounts));
+        assert_eq!(by_mint.len(), 1);
+        assert_eq!(by_mint[&mint].address, large);
+        assert_eq!(by_mint[&mint].amount, 100);
+    }
+
+    #[test]
+    fn test_create_associated_token_account_idempotent() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let address = associated_token_address(&owner, &mint, &SPL_TOKEN_PROGRAM_ID);
+        assert_ne!(
+            address,
+            associated_token_address(&owner, &mint, &SPL_TOKEN_2022_PROGRAM_ID)
+        );
+
+        let instruction = create_associated_token_account_idempotent(
+            &owner,
+            &owner,
+            &mint,
+            &SPL_TOKEN_PROGRAM_ID,
+        );
+        assert_eq!(instruction.program_id, ASSOCIATED_TOKEN_PROGRAM_ID);
+        assert_eq!(instruction.accounts[0], AccountMeta::new(owner, true));
+        assert_eq!(instruction.accounts[1], AccountMeta::new(address, false));
+        assert_eq!(
+            instruction.accounts[5],
+            AccountMeta::new_readonly(SPL_TOKEN_PROGRAM_ID, false)
+        );
+        assert_eq!(instruction.data, vec![CREATE_IDEMPOTENT_TAG]);
+    }
+
+    #[test]
+    fn test_index_by_mint_of_mocked_wallet_token_accounts() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let ata = Pubkey::new_unique();
+        let accounts = HashMap::from([
+            (ata, create_token_account(mint, owner, 5)),
+            (
+                Pubkey::new_unique(),
+                create_token_account(mint, Pubkey::new_unique(), 500),
+            ),
+        ]);
+        let client = MockedCommsClient::with_accounts(accounts);
+
+        let by_mint = index_by_mint(parse(&client.get_token_accounts_by_owner(&owner).unwrap()));
+        assert_eq!(by_mint.len(), 1);
+        assert_eq!(by_mint[&mint].address, ata);
+        assert_eq!(by_mint[&mint].amount, 5);
+    }
+}
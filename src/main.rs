@@ -5,20 +5,32 @@ mod config;
 mod liquidation;
 mod service;
 
-use crate::comms::RpcCommsClient;
-use crate::{config::Config, service::ServiceManager};
+use crate::comms::{FileCommsClient, RpcCommsClient};
+use crate::{
+    cache::snapshot::diff::diff_cache_snapshots,
+    config::Config,
+    service::{InstanceLock, ServiceManager},
+};
 use env_logger::Builder;
 use log::info;
 use signal_hook::consts::{SIGINT, SIGTERM};
 use std::{
     backtrace::Backtrace,
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    thread,
 };
 
 fn main() -> anyhow::Result<()> {
+    // The offline tools run instead of the bot, e.g. `mary snapshot-diff <old> <new>`.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(tool) = args.first() {
+        return run_tool(tool, &args[1..]);
+    }
+
     println!("Initializing...");
 
     // Panic hook
@@ -58,9 +70,68 @@ fn main() -> anyhow::Result<()> {
     let config = Config::new()?;
     info!("Configuration: {}", config);
 
-    let service_manager: ServiceManager<RpcCommsClient> =
-        ServiceManager::<RpcCommsClient>::new(config, stop.clone())?;
-    service_manager.start()?;
+    // The namespaces share the wallet, the lock is held for the whole process.
+    let _instance_lock = if config.comms_fixtures_dir.is_some() {
+        InstanceLock::acquire::<FileCommsClient>(&config)?
+    } else {
+        InstanceLock::acquire::<RpcCommsClient>(&config)?
+    };
+
+    // Each namespace monitors its own marginfi deployment, failing one of them stops them all.
+    thread::scope(|scope| {
+        let namespaces: Vec<_> = config
+            .namespaced()
+            .into_iter()
+            .map(|config| {
+                let stop = stop.clone();
+                scope.spawn(move || {
+                    let namespace = config.namespace().name.clone();
+                    let result = run_namespace(config, stop.clone());
+                    if result.is_err() {
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                    result.map_err(|e| anyhow::anyhow!("Namespace {} failed: {}", namespace, e))
+                })
+            })
+            .collect();
+        namespaces
+            .into_iter()
+            .map(|namespace| {
+                namespace
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Namespace thread panicked")))
+            })
+            .collect::<anyhow::Result<Vec<()>>>()
+    })?;
 
     Ok(())
 }
+
+fn run_tool(tool: &str, args: &[String]) -> anyhow::Result<()> {
+    match (tool, args) {
+        ("snapshot-diff", [old_path, new_path]) => {
+            let diff = diff_cache_snapshots(Path::new(old_path), Path::new(new_path))?;
+            if diff.is_empty() {
+                println!("The snapshots hold the same Marginfi accounts and Bank configs");
+            } else {
+                print!("{}", diff);
+            }
+            Ok(())
+        }
+        _ => Err(anyhow::anyhow!(
+            "Usage: mary snapshot-diff <old snapshot> <new snapshot>"
+        )),
+    }
+}
+
+fn run_namespace(config: Config, stop: Arc<AtomicBool>) -> anyhow::Result<()> {
+    if config.comms_fixtures_dir.is_some() {
+        let service_manager: ServiceManager<FileCommsClient> =
+            ServiceManager::<FileCommsClient>::new(config, stop)?;
+        service_manager.start()
+    } else {
+        let service_manager: ServiceManager<RpcCommsClient> =
+            ServiceManager::<RpcCommsClient>::new(config, stop)?;
+        service_manager.start()
+    }
+}
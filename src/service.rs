@@ -1,43 +1,105 @@
+mod api_server;
+pub mod geyser_backend;
+pub mod geyser_channel;
+mod geyser_lag;
 mod geyser_processor;
+mod geyser_replay;
 mod geyser_subscriber;
+mod geyser_websocket;
+mod instance_lock;
 mod liquidation_service;
+mod public_api_server;
+mod snapshot_persister;
+
+pub use instance_lock::InstanceLock;
 
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{atomic::AtomicBool, Arc},
     thread,
-    time::{Duration, Instant},
 };
 
+use crate::liquidation::{
+    candidates::LatestCandidateDiff,
+    compute_unit_price::ComputeUnitPriceTuner,
+    event_log::LiquidationEventLog,
+    hooks::{default_hooks, PostLiquidationHooks},
+    inventory::CollateralInventory,
+    traces::ExecutionTraces,
+};
 use crate::{
     cache::{
-        snapshot::{persist_cache_snapshot, restore_cache_snapshot},
+        hydration::MarginfiAccountsHydrator,
+        reconciliation::{CacheReconciler, StreamGap},
+        snapshot::{default_tmp_dir, remove_orphaned_tmp_files, restore_cache_snapshot},
+        snapshot_mirror::{download_cache_snapshot, fetch_peer_cache_snapshot},
         Cache, CacheLoader,
     },
-    service::geyser_subscriber::{GeyserMessage, GeyserSubscriber},
+    service::{
+        api_server::ApiServer,
+        geyser_backend::{GeyserBackend, GeyserBackendKind},
+        geyser_channel::geyser_channel,
+        geyser_lag::GeyserLagMonitor,
+        geyser_replay::GeyserRecorder,
+        geyser_subscriber::{geyser_endpoints, GeyserStreams, GeyserSubscriber},
+        geyser_websocket::WebsocketSubscriber,
+        public_api_server::PublicApiServer,
+        snapshot_persister::SnapshotPersister,
+    },
+};
+use crate::{
+    comms::{circuit_breaker::circuit_breaker_states, create_transaction_sender, CommsClient},
+    service::geyser_processor::GeyserProcessor,
 };
-use crate::{comms::CommsClient, service::geyser_processor::GeyserProcessor};
 use crate::{config::Config, service::liquidation_service::LiquidationService};
 use anyhow::Result;
 use bincode::deserialize;
+use crossbeam::channel::Receiver;
 use log::{error, info, warn};
 use solana_sdk::clock::Clock;
+use solana_sdk::pubkey::Pubkey;
 use solana_sdk::sysvar;
 
 pub struct ServiceManager<T: CommsClient + 'static> {
+    namespace: String,
     stop: Arc<AtomicBool>,
     stats_interval_sec: u64,
-    snapshot_interval_sec: u64,
     snapshot_path: PathBuf,
+    snapshot_tmp_dir: PathBuf,
+    snapshot_persister: Arc<SnapshotPersister<T>>,
+    snapshot_errors: Receiver<anyhow::Error>,
+    snapshot_max_age_sec: Option<u64>,
+    snapshot_mirror_url: Option<String>,
+    snapshot_mirror_sha256: Option<String>,
+    snapshot_mirror_token: Option<String>,
+    snapshot_peer_url: Option<String>,
+    // The soft cap of the cache memory, if any.
+    cache_max_memory_bytes: Option<usize>,
     cache: Arc<Cache>,
     cache_loader: CacheLoader<T>,
-    geyser_subscriber: Arc<GeyserSubscriber>,
+    hydrator: Option<Arc<MarginfiAccountsHydrator<T>>>,
+    reconciler: Option<Arc<CacheReconciler<T>>>,
+    // The subscribers of the primary Geyser endpoint, then of the secondary one if any.
+    geyser_subscribers: Vec<Arc<dyn GeyserBackend>>,
+    geyser_lag_monitor: Option<GeyserLagMonitor<T>>,
+    geyser_bootstrap: bool,
     geyser_processor: Arc<GeyserProcessor>,
     liquidation_service: Arc<LiquidationService<T>>,
+    api_server: Option<Arc<ApiServer>>,
+    public_api_server: Option<Arc<PublicApiServer>>,
 }
 
 impl<T: CommsClient + 'static> ServiceManager<T> {
     pub fn new(config: Config, stop: Arc<AtomicBool>) -> Result<Self> {
+        let namespace = config.namespace().clone();
+        info!("Initializing the namespace {}...", namespace);
+        if !namespace.liquidation_enabled {
+            info!(
+                "The liquidations are disabled in the namespace {}, monitoring only.",
+                namespace.name
+            );
+        }
+
         // Fetch clock
         info!("Fetching the Solana Clock...");
         let comms_client = T::new(&config)?;
@@ -45,35 +107,248 @@ impl<T: CommsClient + 'static> ServiceManager<T> {
 
         // Init cache
         info!("Initializing the Cache...");
-        let cache = Arc::new(Cache::new(clock));
+        let cache = Arc::new(Cache::open(&config, clock)?);
 
         info!("Initializing the CacheLoader...");
         let cache_loader = CacheLoader::new(&config, cache.clone())?;
+        let hydrator = if config.cold_hydration {
+            Some(Arc::new(MarginfiAccountsHydrator::new(
+                &config,
+                stop.clone(),
+                cache.clone(),
+            )?))
+        } else {
+            None
+        };
+        // The reconciler also resyncs the cache after the gaps of the Geyser stream, if any, and loads the
+        // Mints and Oracles of the new Banks.
+        let (gaps_tx, gaps_rx) = crossbeam::channel::unbounded::<StreamGap>();
+        let (new_banks_tx, new_banks_rx) = crossbeam::channel::unbounded::<Pubkey>();
+        let reconciler =
+            if config.cache_reconcile_after_min > 0 || config.comms_fixtures_dir.is_none() {
+                Some(Arc::new(CacheReconciler::new(
+                    &config,
+                    stop.clone(),
+                    cache.clone(),
+                    gaps_rx,
+                    new_banks_rx,
+                )?))
+            } else {
+                None
+            };
 
         // Init Geyser services
-        let (geyser_tx, geyser_rx) = crossbeam::channel::unbounded::<GeyserMessage>();
+        let (geyser_tx, geyser_rx) = geyser_channel(
+            config.geyser_channel_capacity,
+            config.geyser_overflow_policy,
+        );
 
-        info!("Initializing the GeyserSubscriber...");
-        let geyser_subscriber =
-            GeyserSubscriber::new(&config, stop.clone(), cache.clone(), geyser_tx)?;
+        // There are no Geyser updates when running offline from the account fixtures.
+        let streams = Arc::new(GeyserStreams::default());
+        let (geyser_subscribers, geyser_lag_monitor) = match (
+            &config.geyser_replay_path,
+            &config.comms_fixtures_dir,
+        ) {
+            // The recorded updates are replayed in place of the streams, offline from the fixtures too.
+            (Some(replay_path), _) => {
+                info!(
+                    "Replaying the Geyser updates of {}, the Geyser streams are not subscribed.",
+                    replay_path
+                );
+                let endpoint = geyser_endpoints(&config).swap_remove(0);
+                let replayer: Arc<dyn GeyserBackend> = Arc::new(GeyserSubscriber::new(
+                    &config,
+                    endpoint,
+                    stop.clone(),
+                    cache.clone(),
+                    geyser_tx.clone(),
+                    streams.clone(),
+                    gaps_tx.clone(),
+                )?);
+                (vec![replayer], None)
+            }
+            (None, Some(fixtures_dir)) => {
+                info!(
+                    "Running offline from the account fixtures in {}, the GeyserSubscriber is disabled.",
+                    fixtures_dir
+                );
+                (vec![], None)
+            }
+            (None, None) => {
+                let recorder = match &config.geyser_record_path {
+                    Some(record_path) => {
+                        info!(
+                            "Recording the Geyser updates to {}, rotated every {} MB...",
+                            record_path, config.geyser_record_max_file_mb
+                        );
+                        Some(Arc::new(GeyserRecorder::create(
+                            Path::new(record_path),
+                            config.geyser_record_max_file_mb * 1024 * 1024,
+                            config.geyser_record_max_files,
+                        )?))
+                    }
+                    None => None,
+                };
+                let mut geyser_subscribers: Vec<Arc<dyn GeyserBackend>> = vec![];
+                for endpoint in geyser_endpoints(&config) {
+                    info!(
+                        "Initializing the {} GeyserSubscriber...",
+                        config.geyser_backend
+                    );
+                    geyser_subscribers.push(match config.geyser_backend {
+                        GeyserBackendKind::Yellowstone => Arc::new(
+                            GeyserSubscriber::new(
+                                &config,
+                                endpoint,
+                                stop.clone(),
+                                cache.clone(),
+                                geyser_tx.clone(),
+                                streams.clone(),
+                                gaps_tx.clone(),
+                            )?
+                            .with_recorder(recorder.clone()),
+                        ),
+                        GeyserBackendKind::Websocket => Arc::new(
+                            WebsocketSubscriber::new(
+                                &config,
+                                endpoint,
+                                stop.clone(),
+                                cache.clone(),
+                                geyser_tx.clone(),
+                                streams.clone(),
+                                gaps_tx.clone(),
+                            )?
+                            .with_recorder(recorder.clone()),
+                        ),
+                    });
+                }
+                (
+                    geyser_subscribers,
+                    Some(GeyserLagMonitor::new(&config, streams)?),
+                )
+            }
+        };
 
         info!("Initializing the GeyserProcessor...");
-        let geyser_processor = GeyserProcessor::new(stop.clone(), cache.clone(), geyser_rx);
+        let geyser_processor = GeyserProcessor::new(
+            stop.clone(),
+            cache.clone(),
+            geyser_rx,
+            new_banks_tx,
+            config.geyser_decode_workers,
+            config.geyser_price_lane_depth,
+        );
+
+        let event_log = Arc::new(LiquidationEventLog::open(Path::new(
+            &config.event_log_path,
+        ))?);
+        let inventory = Arc::new(CollateralInventory::open(Path::new(
+            &config.inventory_path,
+        ))?);
+
+        let candidate_diff = Arc::new(LatestCandidateDiff::default());
+        let execution_traces = Arc::new(ExecutionTraces::new(config.execution_trace_capacity));
+        let compute_unit_price_tuner = Arc::new(ComputeUnitPriceTuner::new(&config));
 
         info!("Initializing the LiquidationService...");
-        let liquidation_service: LiquidationService<T> =
-            LiquidationService::new(stop.clone(), cache.clone(), comms_client)?;
+        let liquidation_service: LiquidationService<T> = LiquidationService::new(
+            &config,
+            stop.clone(),
+            cache.clone(),
+            comms_client,
+            create_transaction_sender(&config)?,
+            compute_unit_price_tuner.clone(),
+            PostLiquidationHooks::new(default_hooks(inventory.clone())),
+            event_log.clone(),
+            candidate_diff.clone(),
+            execution_traces.clone(),
+        )?;
+
+        let api_server = match &config.api_bind_address {
+            Some(bind_address) => {
+                info!("Initializing the ApiServer on {}...", bind_address);
+                let archive_comms_client: Option<Box<dyn CommsClient>> =
+                    match config.archive_rpc_url {
+                        Some(_) => Some(Box::new(T::new(&config)?)),
+                        None => None,
+                    };
+                Some(Arc::new(ApiServer::new(
+                    &config,
+                    bind_address,
+                    stop.clone(),
+                    compute_unit_price_tuner,
+                    candidate_diff,
+                    execution_traces,
+                    inventory,
+                    cache.clone(),
+                    archive_comms_client,
+                )?))
+            }
+            None => None,
+        };
 
+        let public_api_server = match &config.public_api_bind_address {
+            Some(bind_address) => {
+                info!("Initializing the PublicApiServer on {}...", bind_address);
+                Some(Arc::new(PublicApiServer::new(
+                    &config,
+                    bind_address,
+                    stop.clone(),
+                    cache.clone(),
+                    event_log,
+                )?))
+            }
+            None => None,
+        };
+
+        let snapshot_tmp_dir = config
+            .cache_snapshot_tmp_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| default_tmp_dir(Path::new(&config.cache_snapshot_path)));
+        let (snapshot_errors_tx, snapshot_errors) = crossbeam::channel::unbounded();
+        let snapshot_persister = SnapshotPersister::new(
+            &config,
+            stop.clone(),
+            cache.clone(),
+            hydrator.clone(),
+            snapshot_tmp_dir.clone(),
+            snapshot_errors_tx,
+        );
         Ok(ServiceManager {
+            namespace: namespace.name,
             stop,
             stats_interval_sec: config.stats_interval_sec,
-            snapshot_interval_sec: config.cache_snapshot_interval_sec,
             snapshot_path: PathBuf::from(&config.cache_snapshot_path),
+            snapshot_persister: Arc::new(snapshot_persister),
+            snapshot_errors,
+            snapshot_tmp_dir,
+            snapshot_max_age_sec: Some(config.cache_snapshot_max_age_sec).filter(|age| *age > 0),
+            // Without a mirror, the snapshot uploaded to the object store is restored.
+            snapshot_mirror_url: config
+                .cache_snapshot_mirror_url
+                .clone()
+                .or_else(|| config.cache_snapshot_upload_url.clone()),
+            snapshot_mirror_sha256: config.cache_snapshot_mirror_sha256.clone(),
+            snapshot_mirror_token: config
+                .cache_snapshot_upload_token
+                .clone()
+                .filter(|_| config.cache_snapshot_mirror_url.is_none()),
+            snapshot_peer_url: config.cache_snapshot_peer_url.clone(),
+            cache_max_memory_bytes: Some(config.cache_max_memory_mb as usize * 1024 * 1024)
+                .filter(|bytes| *bytes > 0),
             cache,
             cache_loader,
-            geyser_subscriber: Arc::new(geyser_subscriber),
+            hydrator,
+            reconciler,
+            geyser_subscribers,
+            geyser_lag_monitor,
+            // The snapshot of the Geyser stream is not recorded.
+            geyser_bootstrap: config.geyser_bootstrap && config.geyser_replay_path.is_none(),
             geyser_processor: Arc::new(geyser_processor),
             liquidation_service: Arc::new(liquidation_service),
+            api_server,
+            public_api_server,
         })
     }
 
@@ -81,27 +356,86 @@ impl<T: CommsClient + 'static> ServiceManager<T> {
         info!("Starting services...");
 
         let snapshot_path = self.snapshot_path.as_path();
-        let snapshot_loaded = match restore_cache_snapshot(&self.cache, snapshot_path) {
-            Ok(true) => {
-                info!("Cache snapshot restored from {}", snapshot_path.display());
-                self.cache_loader.load_auxiliary_accounts()?;
-                true
+        let snapshot_tmp_dir = self.snapshot_tmp_dir.as_path();
+        match remove_orphaned_tmp_files(snapshot_path, snapshot_tmp_dir) {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {} orphaned cache snapshot temp files", removed),
+            Err(err) => warn!(
+                "Failed to remove the orphaned cache snapshot temp files: {}",
+                err
+            ),
+        }
+        // The cache of a running peer is fresher than the local snapshot, which it replaces.
+        if let Some(peer_url) = &self.snapshot_peer_url {
+            if let Err(err) = fetch_peer_cache_snapshot(peer_url, snapshot_path, snapshot_tmp_dir) {
+                warn!("Failed to fetch the cache snapshot from the peer: {}", err);
             }
-            Ok(false) => false,
-            Err(err) => {
-                warn!(
-                    "Failed to restore cache snapshot {}: {}",
-                    snapshot_path.display(),
-                    err
-                );
-                false
+        }
+        // A new deployment, or a rescheduled one with an ephemeral disk, starts from the published snapshot
+        // rather than loading all the accounts.
+        if let Some(mirror_url) = &self.snapshot_mirror_url {
+            if !snapshot_path.exists() {
+                if let Err(err) = download_cache_snapshot(
+                    mirror_url,
+                    self.snapshot_mirror_sha256.as_deref(),
+                    self.snapshot_mirror_token.as_deref(),
+                    snapshot_path,
+                    snapshot_tmp_dir,
+                ) {
+                    warn!(
+                        "Failed to download the cache snapshot from the mirror: {}",
+                        err
+                    );
+                }
             }
-        };
+        }
+        let snapshot_loaded =
+            match restore_cache_snapshot(&self.cache, snapshot_path, self.snapshot_max_age_sec) {
+                Ok(true) => {
+                    info!("Cache snapshot restored from {}", snapshot_path.display());
+                    self.cache_loader.load_missing_auxiliary_accounts()?;
+                    true
+                }
+                Ok(false) => false,
+                // E.g. a corrupted snapshot, the cache is fully reloaded instead.
+                Err(err) => {
+                    warn!(
+                        "Failed to restore cache snapshot {}: {:#}",
+                        snapshot_path.display(),
+                        err
+                    );
+                    false
+                }
+            };
 
         if !snapshot_loaded {
             info!("Inflating the Cache...");
-            self.cache_loader.load_cache()?;
-            if let Err(err) = persist_cache_snapshot(&self.cache, snapshot_path) {
+            match self
+                .geyser_subscribers
+                .first()
+                .filter(|_| self.geyser_bootstrap)
+            {
+                Some(geyser_subscriber) => {
+                    self.cache_loader.load_program_accounts(
+                        geyser_subscriber.fetch_program_accounts_snapshot()?,
+                    )?;
+                    self.cache_loader.load_auxiliary_accounts()?;
+                }
+                None => match &self.hydrator {
+                    Some(hydrator) => {
+                        hydrator.enqueue(self.cache_loader.load_account_headers()?)?;
+                        self.cache_loader.load_auxiliary_accounts()?;
+                        let hydrator = hydrator.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = hydrator.run() {
+                                error!("MarginfiAccountsHydrator failed! {:?}", e);
+                            }
+                        });
+                    }
+                    None => self.cache_loader.load_cache()?,
+                },
+            }
+            if let Err(err) = self.snapshot_persister.persist() {
                 warn!(
                     "Failed to persist initial cache snapshot {}: {}",
                     snapshot_path.display(),
@@ -118,14 +452,30 @@ impl<T: CommsClient + 'static> ServiceManager<T> {
             }
         });
 
-        let geyser_subscriber = self.geyser_subscriber.clone();
-        thread::spawn(move || {
-            if let Err(e) = geyser_subscriber.run() {
-                error!("GeyserSubscriber failed! {:?}", e);
-                panic!("Fatal error in GeyserSubscriber!");
+        for geyser_subscriber in self.geyser_subscribers.clone() {
+            thread::spawn(move || {
+                if let Err(e) = geyser_subscriber.run() {
+                    error!("GeyserSubscriber failed! {:?}", e);
+                    panic!("Fatal error in GeyserSubscriber!");
+                }
+            });
+        }
+
+        let snapshot_persister = self.snapshot_persister.clone();
+        let snapshot_persister_handle = thread::spawn(move || {
+            if let Err(e) = snapshot_persister.run() {
+                error!("SnapshotPersister failed! {:?}", e);
             }
         });
 
+        if let Some(reconciler) = self.reconciler.clone() {
+            thread::spawn(move || {
+                if let Err(e) = reconciler.run() {
+                    error!("CacheReconciler failed! {:?}", e);
+                }
+            });
+        }
+
         let liquidation_service = self.liquidation_service.clone();
         thread::spawn(move || {
             if let Err(e) = liquidation_service.run() {
@@ -134,25 +484,50 @@ impl<T: CommsClient + 'static> ServiceManager<T> {
             }
         });
 
+        if let Some(api_server) = self.api_server.clone() {
+            thread::spawn(move || {
+                if let Err(e) = api_server.run() {
+                    error!("ApiServer failed! {:?}", e);
+                }
+            });
+        }
+
+        if let Some(public_api_server) = self.public_api_server.clone() {
+            thread::spawn(move || {
+                if let Err(e) = public_api_server.run() {
+                    error!("PublicApiServer failed! {:?}", e);
+                }
+            });
+        }
+
         info!("Entering the Main loop.");
-        let mut last_snapshot = Instant::now();
-        let snapshot_interval = Duration::from_secs(self.snapshot_interval_sec);
         while !self.stop.load(std::sync::atomic::Ordering::SeqCst) {
-            if last_snapshot.elapsed() >= snapshot_interval {
-                if let Err(err) = persist_cache_snapshot(&self.cache, snapshot_path) {
-                    warn!(
-                        "Failed to persist cache snapshot {}: {}",
-                        snapshot_path.display(),
-                        err
-                    );
+            if let Some(max_bytes) = self.cache_max_memory_bytes {
+                match self.cache.enforce_memory_cap(max_bytes) {
+                    Ok(0) => {}
+                    Ok(evicted) => info!(
+                        "Evicted the {} healthiest Marginfi accounts over the cache memory cap",
+                        evicted
+                    ),
+                    Err(err) => warn!("Failed to enforce the cache memory cap: {}", err),
                 }
-                last_snapshot = Instant::now();
+            }
+            for err in self.snapshot_errors.try_iter() {
+                warn!(
+                    "Failed to persist cache snapshot {}: {}",
+                    snapshot_path.display(),
+                    err
+                );
             }
             if let Err(err) = self.log_stats() {
                 eprintln!("Error logging stats: {}", err);
             }
             thread::sleep(std::time::Duration::from_secs(self.stats_interval_sec));
         }
+        // The snapshot in flight is left to complete, for the next start not to restore an older one.
+        if snapshot_persister_handle.join().is_err() {
+            error!("SnapshotPersister panicked!");
+        }
         info!("The Main loop stopped.");
 
         Ok(())
@@ -161,9 +536,35 @@ impl<T: CommsClient + 'static> ServiceManager<T> {
     pub fn log_stats(&self) -> anyhow::Result<()> {
         let clock = self.cache.get_clock()?;
         let queue_depth = self.geyser_processor.queue_depth();
+        let dropped_updates = self.geyser_processor.dropped_updates();
+        let memory_usage = self.cache.memory_usage()?;
+        self.cache.metrics.sample_update_rates()?;
+        let health_metrics = self.cache.health_metrics()?;
+        let competitors = self.cache.competitors.competitors()?;
+        let profit_estimates = self.cache.profit_estimates.stats()?;
+        let geyser_lag = match &self.geyser_lag_monitor {
+            Some(monitor) => monitor.check().unwrap_or_else(|err| {
+                warn!("Failed to check the Geyser lag: {}", err);
+                None
+            }),
+            None => None,
+        };
         info!(
-            "Stats: [Latest Slot: {:?}; Geyser Queue Depth: {}]",
-            clock.slot, queue_depth
+            "Stats {}: [Latest Slot: {:?}; Geyser Lag: {}; Geyser Queue Depth: {}; Geyser Dropped Updates: {}; Competitor Liquidations: {} by {} Liquidators; Profit Estimates: [{}]; Cache Memory: [{}]; Cache: [{}]; RPC Circuits: [{}]]",
+            self.namespace,
+            clock.slot,
+            geyser_lag.map_or_else(|| "None".to_string(), |lag| format!("{} slots", lag)),
+            queue_depth,
+            dropped_updates,
+            competitors
+                .iter()
+                .map(|(_, stats)| stats.liquidations)
+                .sum::<u64>(),
+            competitors.len(),
+            profit_estimates,
+            memory_usage,
+            health_metrics,
+            circuit_breaker_states(&self.namespace).join(", ")
         );
         Ok(())
     }
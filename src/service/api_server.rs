@@ -0,0 +1,776 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use fixed::types::I80F48;
+use log::{debug, error, info};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{
+    cache::{marginfi_accounts::INVALID_HEALTH, snapshot::encode_cache_snapshot, Cache},
+    comms::CommsClient,
+    config::Config,
+    liquidation::{
+        candidates::LatestCandidateDiff, compute_unit_price::ComputeUnitPriceTuner,
+        inventory::CollateralInventory, traces::ExecutionTraces,
+    },
+};
+
+pub(super) const RECV_TIMEOUT: Duration = Duration::from_millis(500);
+const TRACES_PATH: &str = "/traces/";
+const ACCOUNTS_PATH: &str = "/accounts/";
+const AUTHORITIES_PATH: &str = "/authorities/";
+const CACHE_ACCOUNTS_PATH: &str = "/cache/accounts/";
+const CACHE_SNAPSHOT_PATH: &str = "/cache/snapshot";
+
+/// The admin HTTP API of the running instance.
+pub struct ApiServer {
+    stop: Arc<AtomicBool>,
+    server: Server,
+    // Served with the values tuned at runtime.
+    config: Config,
+    compute_unit_price_tuner: Arc<ComputeUnitPriceTuner>,
+    candidate_diff: Arc<LatestCandidateDiff>,
+    execution_traces: Arc<ExecutionTraces>,
+    inventory: Arc<CollateralInventory>,
+    cache: Arc<Cache>,
+    snapshot_zstd_level: i32,
+    // The client of the historical reads, when an archival endpoint is configured.
+    archive_comms_client: Option<Box<dyn CommsClient>>,
+}
+
+impl ApiServer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: &Config,
+        bind_address: &str,
+        stop: Arc<AtomicBool>,
+        compute_unit_price_tuner: Arc<ComputeUnitPriceTuner>,
+        candidate_diff: Arc<LatestCandidateDiff>,
+        execution_traces: Arc<ExecutionTraces>,
+        inventory: Arc<CollateralInventory>,
+        cache: Arc<Cache>,
+        archive_comms_client: Option<Box<dyn CommsClient>>,
+    ) -> Result<Self> {
+        let server = Server::http(bind_address)
+            .map_err(|e| anyhow!("Failed to bind the API server to {}: {}", bind_address, e))?;
+        Ok(Self {
+            stop,
+            server,
+            config: config.clone(),
+            compute_unit_price_tuner,
+            candidate_diff,
+            execution_traces,
+            inventory,
+            cache,
+            snapshot_zstd_level: config.cache_snapshot_zstd_level,
+            archive_comms_client,
+        })
+    }
+
+    pub fn run(&self) -> Result<()> {
+        info!("Entering the ApiServer loop.");
+        while !self.stop.load(Ordering::Relaxed) {
+            match self.server.recv_timeout(RECV_TIMEOUT) {
+                Ok(Some(request)) => self.handle(request),
+                Ok(None) => {}
+                Err(err) => error!("ApiServer failed to receive a request: {}", err),
+            }
+        }
+
+        info!("The ApiServer loop is stopped.");
+        Ok(())
+    }
+
+    fn handle(&self, request: Request) {
+        debug!("API request: {} {}", request.method(), request.url());
+        let path = request.url().split('?').next().unwrap_or_default();
+        if request.method() == &Method::Get && path == CACHE_SNAPSHOT_PATH {
+            return self.serve_cache_snapshot(request);
+        }
+        let (status, body) = self.route(request.method(), request.url());
+        respond_json(request, status, &body);
+    }
+
+    /// Encodes a full snapshot of the cache for a peer instance to restore it. The capture copies the cache
+    /// one shard at a time, so that a large snapshot holds up the API requests but not the Geyser updates.
+    fn serve_cache_snapshot(&self, request: Request) {
+        match encode_cache_snapshot(&self.cache, self.snapshot_zstd_level) {
+            Ok(snapshot) => {
+                info!("Serving a {} bytes cache snapshot", snapshot.len());
+                let response = Response::from_data(snapshot).with_header(
+                    Header::from_bytes(&b"Content-Type"[..], &b"application/octet-stream"[..])
+                        .expect("Invalid Content-Type header"),
+                );
+                if let Err(err) = request.respond(response) {
+                    error!("Failed to send the cache snapshot: {}", err);
+                }
+            }
+            Err(err) => {
+                error!("Failed to serve the cache snapshot: {}", err);
+                respond_json(request, 500, &json!({ "error": "Internal error" }));
+            }
+        }
+    }
+
+    fn route(&self, method: &Method, url: &str) -> (u16, Value) {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        match (method, path) {
+            (Method::Get, "/config") => serve("the config", self.config()),
+            (_, "/config") => (405, json!({ "error": "Method not allowed" })),
+            (Method::Get, "/candidates/diff") => match self.candidate_diff.to_json() {
+                Ok(diff) => (200, diff),
+                Err(err) => {
+                    error!("Failed to serve the liquidation candidates diff: {}", err);
+                    (500, json!({ "error": "Internal error" }))
+                }
+            },
+            (_, "/candidates/diff") => (405, json!({ "error": "Method not allowed" })),
+            (Method::Get, "/inventory") => serve("the collateral inventory", self.inventory()),
+            (_, "/inventory") => (405, json!({ "error": "Method not allowed" })),
+            (Method::Get, "/competitors") => {
+                serve("the competitor liquidations", self.competitors())
+            }
+            (_, "/competitors") => (405, json!({ "error": "Method not allowed" })),
+            (Method::Get, "/priority-fees") => serve("the priority fees", self.priority_fees()),
+            (_, "/priority-fees") => (405, json!({ "error": "Method not allowed" })),
+            (Method::Get, "/profit-estimates") => {
+                serve("the profit estimates", self.profit_estimates())
+            }
+            (_, "/profit-estimates") => (405, json!({ "error": "Method not allowed" })),
+            (Method::Get, path) if path.starts_with(TRACES_PATH) => {
+                self.traces(&path[TRACES_PATH.len()..])
+            }
+            (_, path) if path.starts_with(TRACES_PATH) => {
+                (405, json!({ "error": "Method not allowed" }))
+            }
+            (Method::Get, path) if path.starts_with(ACCOUNTS_PATH) => {
+                self.account_at_slot(&path[ACCOUNTS_PATH.len()..], query)
+            }
+            (_, path) if path.starts_with(ACCOUNTS_PATH) => {
+                (405, json!({ "error": "Method not allowed" }))
+            }
+            (Method::Get, path) if path.starts_with(AUTHORITIES_PATH) => {
+                match path[AUTHORITIES_PATH.len()..].strip_suffix("/accounts") {
+                    Some(authority) => self.accounts_by_authority(authority),
+                    None => (404, json!({ "error": "Not found" })),
+                }
+            }
+            (_, path) if path.starts_with(AUTHORITIES_PATH) => {
+                (405, json!({ "error": "Method not allowed" }))
+            }
+            (Method::Get, "/cache/clock") => serve("the cached clock", self.cached_clock()),
+            (Method::Get, "/cache/banks") => serve("the cached banks", self.cached_banks()),
+            (Method::Get, "/cache/metrics") => serve("the cache metrics", self.cache_metrics()),
+            (Method::Get, path) if path.starts_with(CACHE_ACCOUNTS_PATH) => {
+                self.cached_account(&path[CACHE_ACCOUNTS_PATH.len()..])
+            }
+            (_, "/cache/clock" | "/cache/banks" | "/cache/metrics" | CACHE_SNAPSHOT_PATH) => {
+                (405, json!({ "error": "Method not allowed" }))
+            }
+            (_, path) if path.starts_with(CACHE_ACCOUNTS_PATH) => {
+                (405, json!({ "error": "Method not allowed" }))
+            }
+            _ => (404, json!({ "error": "Not found" })),
+        }
+    }
+
+    fn traces(&self, address: &str) -> (u16, Value) {
+        let Ok(address) = address.parse::<Pubkey>() else {
+            return (400, json!({ "error": "Invalid account address" }));
+        };
+        match self.execution_traces.for_account(&address) {
+            Ok(traces) => (200, traces),
+            Err(err) => {
+                error!(
+                    "Failed to serve the execution traces of {}: {}",
+                    address, err
+                );
+                (500, json!({ "error": "Internal error" }))
+            }
+        }
+    }
+
+    fn competitors(&self) -> Result<Value> {
+        Ok(Value::Array(
+            self.cache
+                .competitors
+                .competitors()?
+                .into_iter()
+                .map(|(liquidator, stats)| {
+                    json!({
+                        "liquidator": liquidator.to_string(),
+                        "liquidations": stats.liquidations,
+                        "last_slot": stats.last_slot,
+                    })
+                })
+                .collect(),
+        ))
+    }
+
+    // Built for every request, for the values tuned at runtime to be current.
+    fn config(&self) -> Result<Value> {
+        let mut config = self.config.to_redacted_json();
+        config["compute_unit_price_premium"] = json!(self.compute_unit_price_tuner.premium()?);
+        Ok(config)
+    }
+
+    // The holdings valued at the current prices of their mints, when priced.
+    fn inventory(&self) -> Result<Value> {
+        let unix_timestamp = self.cache.get_extrapolated_clock()?.unix_timestamp;
+        self.inventory.to_json(|mint, amount| {
+            self.cache
+                .get_mint_value_usd(mint, amount, unix_timestamp)
+                .unwrap_or_else(|err| {
+                    error!(
+                        "Failed to value the inventory of the mint {}: {}",
+                        mint, err
+                    );
+                    None
+                })
+        })
+    }
+
+    fn priority_fees(&self) -> Result<Value> {
+        let stats = self.cache.priority_fees.stats()?;
+        Ok(json!({
+            "blocks": stats.blocks,
+            "transactions": stats.transactions,
+            "min": stats.min,
+            "median": stats.median,
+            "p75": stats.p75,
+            "p90": stats.p90,
+            "max": stats.max,
+        }))
+    }
+
+    fn cached_clock(&self) -> Result<Value> {
+        let clock = self.cache.get_clock()?;
+        let extrapolated = self.cache.get_extrapolated_clock()?;
+        Ok(json!({
+            "slot": clock.slot,
+            "epoch": clock.epoch,
+            "unix_timestamp": clock.unix_timestamp,
+            "extrapolated_slot": extrapolated.slot,
+            "extrapolated_unix_timestamp": extrapolated.unix_timestamp,
+        }))
+    }
+
+    fn cached_banks(&self) -> Result<Value> {
+        let mut banks = self.cache.banks.get_banks()?;
+        banks.sort_by_key(|bank| bank.address);
+        Ok(Value::Array(
+            banks
+                .iter()
+                .map(|bank| {
+                    json!({
+                        "address": bank.address.to_string(),
+                        "slot": bank.slot,
+                        "mint": bank.mint().to_string(),
+                        "mint_decimals": bank.mint_decimals(),
+                        "oracles": bank
+                            .oracle_addresses()
+                            .iter()
+                            .map(|oracle| {
+                                let cached_price = self.cache.oracles.get_cached_price(oracle);
+                                json!({
+                                    "address": oracle.to_string(),
+                                    "slot": self.cache.oracles.get_slot(oracle),
+                                    "price": self
+                                        .cache
+                                        .oracles
+                                        .get_price(oracle)
+                                        .map(|price| price.to_num::<f64>()),
+                                    "confidence": cached_price
+                                        .map(|price| price.confidence.to_num::<f64>()),
+                                    "publish_time": cached_price.map(|price| price.publish_time),
+                                })
+                            })
+                            .collect::<Vec<Value>>(),
+                        "total_assets": bank.total_assets().to_num::<f64>(),
+                        "total_liabilities": bank.total_liabilities().to_num::<f64>(),
+                    })
+                })
+                .collect(),
+        ))
+    }
+
+    fn profit_estimates(&self) -> Result<Value> {
+        Ok(serde_json::to_value(self.cache.profit_estimates.stats()?)?)
+    }
+
+    fn cache_metrics(&self) -> Result<Value> {
+        Ok(serde_json::to_value(self.cache.health_metrics()?)?)
+    }
+
+    /// The cached Marginfi account, with its positions and health.
+    fn cached_account(&self, address: &str) -> (u16, Value) {
+        let Ok(address) = address.parse::<Pubkey>() else {
+            return (400, json!({ "error": "Invalid account address" }));
+        };
+        let marginfi_accounts = &self.cache.marginfi_accounts;
+        match marginfi_accounts.find_account(&address) {
+            Ok(Some(account)) => (
+                200,
+                json!({
+                    "address": address.to_string(),
+                    "slot": account.slot(),
+                    "authority": account.authority().to_string(),
+                    "health": marginfi_accounts
+                        .get_health(&address)
+                        .filter(|health| *health != INVALID_HEALTH),
+                    "positions": account
+                        ._positions()
+                        .iter()
+                        .map(|balance| json!({
+                            "bank": balance.bank_pk.to_string(),
+                            "asset_shares": I80F48::from(balance.asset_shares).to_num::<f64>(),
+                            "liability_shares": I80F48::from(balance.liability_shares).to_num::<f64>(),
+                        }))
+                        .collect::<Vec<Value>>(),
+                }),
+            ),
+            Ok(None) => (404, json!({ "error": "Account not in the cache" })),
+            Err(err) => {
+                error!("Failed to serve the cached account {}: {}", address, err);
+                (500, json!({ "error": "Internal error" }))
+            }
+        }
+    }
+
+    /// The cached Marginfi accounts controlled by the authority, with their health.
+    fn accounts_by_authority(&self, authority: &str) -> (u16, Value) {
+        let Ok(authority) = authority.parse::<Pubkey>() else {
+            return (400, json!({ "error": "Invalid authority address" }));
+        };
+        let marginfi_accounts = &self.cache.marginfi_accounts;
+        let mut addresses = marginfi_accounts.get_accounts_by_authority(&authority);
+        addresses.sort();
+        let accounts: Vec<Value> = addresses
+            .into_iter()
+            .map(|address| {
+                let health = marginfi_accounts
+                    .get_health(&address)
+                    .filter(|health| *health != INVALID_HEALTH);
+                json!({ "address": address.to_string(), "health": health })
+            })
+            .collect();
+        (200, Value::Array(accounts))
+    }
+
+    /// The account as of the `slot` query parameter, read from the archival endpoint.
+    fn account_at_slot(&self, address: &str, query: &str) -> (u16, Value) {
+        let Some(archive_comms_client) = &self.archive_comms_client else {
+            return (501, json!({ "error": "No archival endpoint configured" }));
+        };
+        let Ok(address) = address.parse::<Pubkey>() else {
+            return (400, json!({ "error": "Invalid account address" }));
+        };
+        let Some(slot) = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("slot="))
+            .and_then(|slot| slot.parse::<u64>().ok())
+        else {
+            return (400, json!({ "error": "Missing or invalid slot" }));
+        };
+
+        match archive_comms_client.get_accounts_at_slot(&[address], slot) {
+            Ok(accounts) => match accounts.into_iter().next() {
+                Some((_, account)) => (
+                    200,
+                    json!({
+                        "address": address.to_string(),
+                        "slot": slot,
+                        "owner": account.owner.to_string(),
+                        "lamports": account.lamports,
+                        "data": STANDARD.encode(&account.data),
+                    }),
+                ),
+                None => (404, json!({ "error": "Account not found at the slot" })),
+            },
+            Err(err) => {
+                error!(
+                    "Failed to read the account {} at the slot {}: {}",
+                    address, slot, err
+                );
+                (
+                    502,
+                    json!({ "error": "Failed to read the account at the slot" }),
+                )
+            }
+        }
+    }
+}
+
+fn serve(what: &str, body: Result<Value>) -> (u16, Value) {
+    match body {
+        Ok(body) => (200, body),
+        Err(err) => {
+            error!("Failed to serve {}: {}", what, err);
+            (500, json!({ "error": "Internal error" }))
+        }
+    }
+}
+
+pub(super) fn respond_json(request: Request, status: u16, body: &Value) {
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(
+            Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                .expect("Invalid Content-Type header"),
+        );
+    if let Err(err) = request.respond(response) {
+        error!("Failed to send the API response: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, thread};
+
+    use solana_sdk::{account::Account, signature::Signature};
+
+    use super::*;
+    use crate::{
+        cache::{
+            competitors::ObservedLiquidation,
+            marginfi_accounts::test_util::{create_balance, create_marginfi_account},
+            snapshot::restore_cache_snapshot,
+            snapshot_mirror::fetch_peer_cache_snapshot,
+            test_util::{create_dummy_cache, generate_test_clock},
+        },
+        comms::test_util::MockedCommsClient,
+        config::test_util::create_dummy_config,
+        liquidation::profitability::ProfitEstimate,
+    };
+
+    fn create_api_server() -> ApiServer {
+        ApiServer::new(
+            &create_dummy_config(),
+            "127.0.0.1:0",
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(ComputeUnitPriceTuner::new(&create_dummy_config())),
+            Arc::new(LatestCandidateDiff::default()),
+            Arc::new(ExecutionTraces::new(10)),
+            Arc::new(
+                CollateralInventory::open(
+                    &std::env::temp_dir()
+                        .join(format!("mary_api_inventory_{}.json", std::process::id())),
+                )
+                .unwrap(),
+            ),
+            Arc::new(create_dummy_cache()),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_route_config() {
+        let config = create_dummy_config();
+        let server = create_api_server();
+
+        let (status, body) = server.route(&Method::Get, "/config?pretty");
+        assert_eq!(status, 200);
+        assert_eq!(body["stats_interval_sec"], config.stats_interval_sec);
+        assert_eq!(body["geyser_x_token"], "<redacted>");
+        assert_eq!(body["compute_unit_price_premium"], 1.0);
+
+        // The tuned premium is current.
+        server
+            .compute_unit_price_tuner
+            .record_outcome(false)
+            .unwrap();
+        let (_, body) = server.route(&Method::Get, "/config");
+        assert!(body["compute_unit_price_premium"].as_f64().unwrap() > 1.0);
+    }
+
+    #[test]
+    fn test_route_candidates_diff() {
+        let server = create_api_server();
+        assert_eq!(
+            server.route(&Method::Get, "/candidates/diff"),
+            (200, Value::Null)
+        );
+
+        server.candidate_diff.publish(Default::default()).unwrap();
+        let (status, body) = server.route(&Method::Get, "/candidates/diff");
+        assert_eq!(status, 200);
+        assert_eq!(body["resolved"], json!([]));
+    }
+
+    #[test]
+    fn test_route_competitors() {
+        let server = create_api_server();
+        let liquidator = Pubkey::new_unique();
+        server
+            .cache
+            .competitors
+            .record(ObservedLiquidation {
+                signature: Signature::new_unique(),
+                slot: 42,
+                liquidator,
+                liquidatee: Pubkey::new_unique(),
+                asset_bank: Pubkey::new_unique(),
+                liab_bank: Pubkey::new_unique(),
+                asset_amount: 100,
+            })
+            .unwrap();
+
+        let (status, body) = server.route(&Method::Get, "/competitors");
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            json!([{ "liquidator": liquidator.to_string(), "liquidations": 1, "last_slot": 42 }])
+        );
+        assert_eq!(server.route(&Method::Post, "/competitors").0, 405);
+    }
+
+    #[test]
+    fn test_route_priority_fees() {
+        let server = create_api_server();
+        server
+            .cache
+            .priority_fees
+            .record_transaction(42, Signature::new_unique(), 1_000)
+            .unwrap();
+        server.cache.priority_fees.complete_block(42).unwrap();
+
+        let (status, body) = server.route(&Method::Get, "/priority-fees");
+        assert_eq!(status, 200);
+        assert_eq!(body["blocks"], 1);
+        assert_eq!(body["median"], 1_000);
+        assert_eq!(server.route(&Method::Post, "/priority-fees").0, 405);
+    }
+
+    #[test]
+    fn test_route_profit_estimates() {
+        let server = create_api_server();
+        server
+            .cache
+            .profit_estimates
+            .record(&ProfitEstimate {
+                bonus_usd: 10.0,
+                slippage_usd: 2.0,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let (status, body) = server.route(&Method::Get, "/profit-estimates");
+        assert_eq!(status, 200);
+        assert_eq!(body["estimates"], 1);
+        assert_eq!(body["unprofitable"], 0);
+        assert_eq!(body["last"]["bonus_usd"], 10.0);
+        assert_eq!(server.route(&Method::Post, "/profit-estimates").0, 405);
+    }
+
+    #[test]
+    fn test_route_inventory() {
+        let (status, body) = create_api_server().route(&Method::Get, "/inventory");
+        assert_eq!(status, 200);
+        assert_eq!(body, json!([]));
+    }
+
+    #[test]
+    fn test_route_traces() {
+        let server = create_api_server();
+        let address = Pubkey::new_unique();
+
+        let (status, body) = server.route(&Method::Get, &format!("/traces/{}", address));
+        assert_eq!(status, 200);
+        assert_eq!(body, json!([]));
+        assert_eq!(server.route(&Method::Get, "/traces/not_a_pubkey").0, 400);
+        assert_eq!(
+            server
+                .route(&Method::Delete, &format!("/traces/{}", address))
+                .0,
+            405
+        );
+    }
+
+    #[test]
+    fn test_route_account_at_slot() {
+        let mut server = create_api_server();
+        let address = Pubkey::new_unique();
+        let url = format!("/accounts/{}?slot=42", address);
+        assert_eq!(server.route(&Method::Get, &url).0, 501);
+
+        let account = Account {
+            lamports: 1,
+            data: vec![1, 2, 3],
+            owner: Pubkey::new_unique(),
+            executable: false,
+            rent_epoch: 0,
+        };
+        server.archive_comms_client =
+            Some(Box::new(MockedCommsClient::with_accounts(HashMap::from([
+                (address, account.clone()),
+            ]))));
+
+        let (status, body) = server.route(&Method::Get, &url);
+        assert_eq!(status, 200);
+        assert_eq!(body["slot"], 42);
+        assert_eq!(body["owner"], account.owner.to_string());
+        assert_eq!(body["data"], STANDARD.encode(&account.data));
+        assert_eq!(
+            server
+                .route(&Method::Get, &format!("/accounts/{}", address))
+                .0,
+            400
+        );
+        assert_eq!(
+            server
+                .route(
+                    &Method::Get,
+                    &format!("/accounts/{}?slot=42", Pubkey::new_unique())
+                )
+                .0,
+            404
+        );
+    }
+
+    #[test]
+    fn test_route_accounts_by_authority() {
+        let server = create_api_server();
+        let authority = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        let mut marginfi_account = create_marginfi_account(
+            Pubkey::new_unique(),
+            vec![create_balance(Pubkey::new_unique(), 1, 0)],
+        );
+        marginfi_account.authority = authority;
+        server
+            .cache
+            .marginfi_accounts
+            .update(1, address, marginfi_account)
+            .unwrap();
+
+        let url = format!("/authorities/{}/accounts", authority);
+        let (status, body) = server.route(&Method::Get, &url);
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            json!([{ "address": address.to_string(), "health": null }])
+        );
+        assert_eq!(
+            server
+                .route(
+                    &Method::Get,
+                    &format!("/authorities/{}/accounts", Pubkey::new_unique())
+                )
+                .1,
+            json!([])
+        );
+        assert_eq!(
+            server
+                .route(&Method::Get, "/authorities/not_a_pubkey/accounts")
+                .0,
+            400
+        );
+        assert_eq!(
+            server
+                .route(&Method::Get, &format!("/authorities/{}", authority))
+                .0,
+            404
+        );
+        assert_eq!(server.route(&Method::Post, &url).0, 405);
+    }
+
+    #[test]
+    fn test_route_cache_views() {
+        let server = create_api_server();
+        let (status, body) = server.route(&Method::Get, "/cache/clock");
+        assert_eq!(status, 200);
+        assert_eq!(body["slot"], server.cache.get_clock().unwrap().slot);
+        assert!(body["extrapolated_slot"].as_u64() >= body["slot"].as_u64());
+        assert_eq!(server.route(&Method::Get, "/cache/banks"), (200, json!([])));
+
+        let address = Pubkey::new_unique();
+        let bank = Pubkey::new_unique();
+        server
+            .cache
+            .marginfi_accounts
+            .update(
+                7,
+                address,
+                create_marginfi_account(Pubkey::new_unique(), vec![create_balance(bank, 3, 0)]),
+            )
+            .unwrap();
+        let (status, body) = server.route(&Method::Get, &format!("/cache/accounts/{}", address));
+        assert_eq!(status, 200);
+        assert_eq!(body["slot"], 7);
+        assert_eq!(body["positions"][0]["bank"], bank.to_string());
+        assert_eq!(body["positions"][0]["asset_shares"], 3.0);
+        let (status, body) = server.route(&Method::Get, "/cache/metrics");
+        assert_eq!(status, 200);
+        assert_eq!(body["entries"]["marginfi_accounts"], 1);
+        assert_eq!(body["last_snapshot_age_sec"], Value::Null);
+        assert_eq!(body["confirmations"]["rolled_back"], 0);
+        assert_eq!(body["update_counts"]["decode_failures"], 0);
+        assert_eq!(
+            server
+                .route(
+                    &Method::Get,
+                    &format!("/cache/accounts/{}", Pubkey::new_unique())
+                )
+                .0,
+            404
+        );
+        assert_eq!(
+            server.route(&Method::Get, "/cache/accounts/not_a_pubkey").0,
+            400
+        );
+        assert_eq!(server.route(&Method::Post, "/cache/clock").0, 405);
+        assert_eq!(server.route(&Method::Post, "/cache/snapshot").0, 405);
+        assert_eq!(server.route(&Method::Get, "/cache/unknown").0, 404);
+    }
+
+    #[test]
+    fn test_serve_cache_snapshot() {
+        let server = Arc::new(create_api_server());
+        server.cache.update_clock(generate_test_clock(42)).unwrap();
+        let url = format!(
+            "http://{}{}",
+            server.server.server_addr(),
+            CACHE_SNAPSHOT_PATH
+        );
+        let handle = {
+            let server = server.clone();
+            thread::spawn(move || server.run())
+        };
+        let dir = std::env::temp_dir().join(format!("mary_api_snapshot_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.bin");
+
+        fetch_peer_cache_snapshot(&url, &path, &dir).unwrap();
+        let restored = create_dummy_cache();
+        assert!(restore_cache_snapshot(&restored, &path, None).unwrap());
+        assert_eq!(restored.get_clock().unwrap().slot, 42);
+
+        server.stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_route_config_wrong_method() {
+        let (status, _) = create_api_server().route(&Method::Post, "/config");
+        assert_eq!(status, 405);
+    }
+
+    #[test]
+    fn test_route_unknown_path() {
+        let (status, _) = create_api_server().route(&Method::Get, "/unknown");
+        assert_eq!(status, 404);
+    }
+}
@@ -0,0 +1,128 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Result};
+
+use crate::comms::ProgramAccounts;
+
+/// The protocol of the Geyser endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeyserBackendKind {
+    // The Yellowstone gRPC protocol.
+    Yellowstone,
+    // The Solana websocket PubSub protocol, as served by the RPC nodes or Triton Whirligig.
+    Websocket,
+}
+
+impl GeyserBackendKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Yellowstone => "yellowstone",
+            Self::Websocket => "websocket",
+        }
+    }
+}
+
+impl fmt::Display for GeyserBackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for GeyserBackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(kind: &str) -> Result<Self> {
+        match kind.trim().to_ascii_lowercase().as_str() {
+            "yellowstone" => Ok(Self::Yellowstone),
+            "websocket" | "whirligig" => Ok(Self::Websocket),
+            _ => Err(anyhow!("Unknown Geyser backend {:?}", kind)),
+        }
+    }
+}
+
+/// The compression of the messages of the Yellowstone gRPC connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeyserCompression {
+    Uncompressed,
+    Gzip,
+    Zstd,
+}
+
+impl GeyserCompression {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Uncompressed => "none",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+impl fmt::Display for GeyserCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for GeyserCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(compression: &str) -> Result<Self> {
+        match compression.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::Uncompressed),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(anyhow!("Unknown Geyser compression {:?}", compression)),
+        }
+    }
+}
+
+/// Streams the account updates of an endpoint to the GeyserProcessor, whatever its protocol.
+pub trait GeyserBackend: Send + Sync {
+    /// Streams the updates until the stop request, reconnecting whenever the stream drops.
+    fn run(&self) -> Result<()>;
+
+    /// Fetches the Marginfi program accounts, for the providers that have disabled getProgramAccounts.
+    fn fetch_program_accounts_snapshot(&self) -> Result<ProgramAccounts>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_geyser_backend_kind() {
+        assert_eq!(
+            " Yellowstone ".parse::<GeyserBackendKind>().unwrap(),
+            GeyserBackendKind::Yellowstone
+        );
+        assert_eq!(
+            "websocket".parse::<GeyserBackendKind>().unwrap(),
+            GeyserBackendKind::Websocket
+        );
+        assert_eq!(
+            "whirligig".parse::<GeyserBackendKind>().unwrap(),
+            GeyserBackendKind::Websocket
+        );
+        assert!("jetstream".parse::<GeyserBackendKind>().is_err());
+        assert_eq!(GeyserBackendKind::Websocket.to_string(), "websocket");
+    }
+
+    #[test]
+    fn test_parse_geyser_compression() {
+        assert_eq!(
+            "none".parse::<GeyserCompression>().unwrap(),
+            GeyserCompression::Uncompressed
+        );
+        assert_eq!(
+            "GZIP".parse::<GeyserCompression>().unwrap(),
+            GeyserCompression::Gzip
+        );
+        assert_eq!(
+            "zstd".parse::<GeyserCompression>().unwrap(),
+            GeyserCompression::Zstd
+        );
+        assert!("brotli".parse::<GeyserCompression>().is_err());
+        assert_eq!(GeyserCompression::Uncompressed.to_string(), "none");
+    }
+}
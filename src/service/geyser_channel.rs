@@ -0,0 +1,264 @@
+use std::{
+    fmt, iter,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use crossbeam::{
+    channel::{self, Receiver, RecvTimeoutError, Sender, TryRecvError, TrySendError},
+    select,
+};
+
+use crate::{common::MessageType, service::geyser_subscriber::GeyserMessage};
+
+/// What the GeyserSubscribers do with the Marginfi account updates when the channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // The oldest queued update is dropped for the new one.
+    DropOldest,
+    // The new update is dropped.
+    DropNewest,
+    // The subscribers wait for the GeyserProcessor, stalling the streams.
+    Block,
+}
+
+impl OverflowPolicy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::DropOldest => "drop-oldest",
+            Self::DropNewest => "drop-newest",
+            Self::Block => "block",
+        }
+    }
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy.trim().to_ascii_lowercase().as_str() {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            "block" => Ok(Self::Block),
+            _ => Err(anyhow!("Unknown overflow policy {:?}", policy)),
+        }
+    }
+}
+
+/// The bounded channel from the GeyserSubscribers to the GeyserProcessor, for the queued updates not to
+/// exhaust the memory while the processor stalls. The Marginfi account updates, most of the traffic, are
+/// queued apart and overflow as per the policy: a dropped update is superseded by the next one of the
+/// account, or refreshed by the reconciliation. The Oracle, Bank, Clock and token account updates and the account
+/// closures are never dropped, the subscribers waiting for room in their own queue.
+pub fn geyser_channel(capacity: usize, policy: OverflowPolicy) -> (GeyserSender, GeyserReceiver) {
+    let (accounts_tx, accounts_rx) = channel::bounded(capacity.max(1));
+    let (priority_tx, priority_rx) = channel::bounded(capacity.max(1));
+    let dropped = Arc::new(AtomicU64::new(0));
+    (
+        GeyserSender {
+            accounts_tx,
+            accounts_rx: accounts_rx.clone(),
+            priority_tx,
+            policy,
+            dropped: dropped.clone(),
+        },
+        GeyserReceiver {
+            accounts_rx,
+            priority_rx,
+            dropped,
+        },
+    )
+}
+
+#[derive(Clone)]
+pub struct GeyserSender {
+    accounts_tx: Sender<GeyserMessage>,
+    // To drop the oldest queued account updates.
+    accounts_rx: Receiver<GeyserMessage>,
+    priority_tx: Sender<GeyserMessage>,
+    policy: OverflowPolicy,
+    dropped: Arc<AtomicU64>,
+}
+
+impl GeyserSender {
+    pub fn send(&self, msg: GeyserMessage) -> Result<()> {
+        if msg.message_type != MessageType::MarginfiAccount {
+            return self
+                .priority_tx
+                .send(msg)
+                .map_err(|_| anyhow!("The Geyser channel is disconnected"));
+        }
+
+        let mut msg = msg;
+        loop {
+            match self.accounts_tx.try_send(msg) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(rejected)) => match self.policy {
+                    OverflowPolicy::DropOldest => {
+                        // The processor may have made room meanwhile, nothing is dropped then.
+                        if self.accounts_rx.try_recv().is_ok() {
+                            self.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                        msg = rejected;
+                    }
+                    OverflowPolicy::DropNewest => {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        return Ok(());
+                    }
+                    OverflowPolicy::Block => {
+                        return self
+                            .accounts_tx
+                            .send(rejected)
+                            .map_err(|_| anyhow!("The Geyser channel is disconnected"));
+                    }
+                },
+                Err(TrySendError::Disconnected(_)) => {
+                    return Err(anyhow!("The Geyser channel is disconnected"))
+                }
+            }
+        }
+    }
+}
+
+pub struct GeyserReceiver {
+    accounts_rx: Receiver<GeyserMessage>,
+    priority_rx: Receiver<GeyserMessage>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl GeyserReceiver {
+    /// Waits up to the timeout for the next message, the never dropped ones first.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<GeyserMessage, RecvTimeoutError> {
+        if let Ok(msg) = self.try_recv() {
+            return Ok(msg);
+        }
+        select! {
+            recv(self.priority_rx) -> msg => msg.map_err(|_| RecvTimeoutError::Disconnected),
+            recv(self.accounts_rx) -> msg => msg.map_err(|_| RecvTimeoutError::Disconnected),
+            default(timeout) => Err(RecvTimeoutError::Timeout),
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<GeyserMessage, TryRecvError> {
+        self.priority_rx
+            .try_recv()
+            .or_else(|_| self.accounts_rx.try_recv())
+    }
+
+    pub fn try_iter(&self) -> impl Iterator<Item = GeyserMessage> + '_ {
+        iter::from_fn(|| self.try_recv().ok())
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.priority_rx.len() + self.accounts_rx.len()
+    }
+
+    /// The Marginfi account updates dropped on overflow so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{account::Account, pubkey::Pubkey};
+
+    use super::*;
+
+    fn message(message_type: MessageType, slot: u64) -> GeyserMessage {
+        GeyserMessage {
+            message_type,
+            slot,
+            address: Pubkey::new_unique(),
+            write_version: 0,
+            account: Account::default(),
+        }
+    }
+
+    fn received_slots(rx: &GeyserReceiver) -> Vec<u64> {
+        rx.try_iter().map(|msg| msg.slot).collect()
+    }
+
+    #[test]
+    fn test_parse_overflow_policy() {
+        assert_eq!(
+            " Drop-Oldest".parse::<OverflowPolicy>().unwrap(),
+            OverflowPolicy::DropOldest
+        );
+        assert_eq!(
+            "drop-newest".parse::<OverflowPolicy>().unwrap(),
+            OverflowPolicy::DropNewest
+        );
+        assert_eq!(
+            "block".parse::<OverflowPolicy>().unwrap(),
+            OverflowPolicy::Block
+        );
+        assert!("drop".parse::<OverflowPolicy>().is_err());
+        assert_eq!(OverflowPolicy::DropOldest.to_string(), "drop-oldest");
+    }
+
+    #[test]
+    fn test_drop_oldest_account_updates() {
+        let (tx, rx) = geyser_channel(2, OverflowPolicy::DropOldest);
+        for slot in 1..=4 {
+            tx.send(message(MessageType::MarginfiAccount, slot))
+                .unwrap();
+        }
+        tx.send(message(MessageType::Oracle, 5)).unwrap();
+        assert_eq!(rx.queue_depth(), 3);
+        assert_eq!(rx.dropped(), 2);
+        // The never dropped updates are received first.
+        assert_eq!(received_slots(&rx), vec![5, 3, 4]);
+    }
+
+    #[test]
+    fn test_drop_newest_account_updates() {
+        let (tx, rx) = geyser_channel(2, OverflowPolicy::DropNewest);
+        for slot in 1..=4 {
+            tx.send(message(MessageType::MarginfiAccount, slot))
+                .unwrap();
+        }
+        assert_eq!(rx.dropped(), 2);
+        assert_eq!(received_slots(&rx), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_oracle_updates_are_never_dropped() {
+        let (tx, rx) = geyser_channel(2, OverflowPolicy::DropOldest);
+        tx.send(message(MessageType::Oracle, 1)).unwrap();
+        tx.send(message(MessageType::Oracle, 2)).unwrap();
+
+        let sender = std::thread::spawn(move || tx.send(message(MessageType::Oracle, 3)));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap().slot, 1);
+        sender.join().unwrap().unwrap();
+        assert_eq!(received_slots(&rx), vec![2, 3]);
+        assert_eq!(rx.dropped(), 0);
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let (tx, rx) = geyser_channel(2, OverflowPolicy::DropOldest);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(10)).unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+        tx.send(message(MessageType::MarginfiAccount, 1)).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_millis(10)).unwrap().slot, 1);
+        drop(tx);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(10)).unwrap_err(),
+            RecvTimeoutError::Disconnected
+        );
+    }
+}
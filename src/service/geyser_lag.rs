@@ -0,0 +1,82 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use anyhow::Result;
+use log::warn;
+
+use super::geyser_subscriber::GeyserStreams;
+use crate::{comms::CommsClient, config::Config};
+
+/// Compares the highest slot seen on the Geyser streams with the cluster slot, alerting when the streams lag
+/// behind by more than the threshold, and once they caught up.
+pub struct GeyserLagMonitor<T: CommsClient> {
+    comms_client: T,
+    streams: Arc<GeyserStreams>,
+    max_lag_slots: u64,
+    lagging: AtomicBool,
+}
+
+impl<T: CommsClient> GeyserLagMonitor<T> {
+    pub fn new(config: &Config, streams: Arc<GeyserStreams>) -> Result<Self> {
+        Ok(Self {
+            comms_client: T::new(config)?,
+            streams,
+            max_lag_slots: config.geyser_max_lag_slots,
+            lagging: AtomicBool::new(false),
+        })
+    }
+
+    /// The slots the Geyser streams lag behind the cluster, None until they streamed an update.
+    pub fn check(&self) -> Result<Option<u64>> {
+        let cluster_slot = self.comms_client.get_epoch_info()?.absolute_slot;
+        Ok(self.check_at(cluster_slot))
+    }
+
+    fn check_at(&self, cluster_slot: u64) -> Option<u64> {
+        let lag = cluster_slot.saturating_sub(self.streams.highest_slot()?);
+        let lagging = lag > self.max_lag_slots;
+        if self.lagging.swap(lagging, Ordering::Relaxed) != lagging {
+            if lagging {
+                warn!(
+                    "The Geyser streams lag {} slots behind the cluster, over the {} slots threshold.",
+                    lag, self.max_lag_slots
+                );
+            } else {
+                warn!(
+                    "The Geyser streams caught up with the cluster, lagging {} slots.",
+                    lag
+                );
+            }
+        }
+        Some(lag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{comms::test_util::MockedCommsClient, config::test_util::create_dummy_config};
+
+    #[test]
+    fn test_geyser_lag_monitor() {
+        let mut config = create_dummy_config();
+        config.geyser_max_lag_slots = 10;
+        let streams = Arc::new(GeyserStreams::default());
+        let monitor = GeyserLagMonitor::<MockedCommsClient>::new(&config, streams.clone()).unwrap();
+        assert_eq!(monitor.check_at(100), None);
+
+        streams.observe_slot(95);
+        assert_eq!(monitor.check_at(100), Some(5));
+        assert!(!monitor.lagging.load(Ordering::Relaxed));
+        assert_eq!(monitor.check_at(120), Some(25));
+        assert!(monitor.lagging.load(Ordering::Relaxed));
+
+        // The older updates do not lower the highest slot.
+        streams.observe_slot(118);
+        streams.observe_slot(50);
+        assert_eq!(monitor.check_at(120), Some(2));
+        assert!(!monitor.lagging.load(Ordering::Relaxed));
+    }
+}
@@ -1,83 +1,316 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
 };
 
-use anchor_lang::AccountDeserialize;
-use crossbeam::channel::Receiver;
-use log::{error, info, trace};
-use marginfi::state::{marginfi_account::MarginfiAccount, marginfi_group::Bank};
-use solana_sdk::clock::Clock;
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use fixed::types::I80F48;
+use log::{debug, error, info, trace};
+use solana_sdk::{clock::Clock, pubkey::Pubkey};
 
-use crate::{cache::Cache, common::MessageType, service::geyser_subscriber::GeyserMessage};
+use crate::{
+    cache::{
+        banks::CachedBank, events::CacheEvent, marginfi_accounts::CachedMarginfiAccount, Cache,
+    },
+    common::MessageType,
+    service::{geyser_channel::GeyserReceiver, geyser_subscriber::GeyserMessage},
+};
+
+// The most messages applied as a batch.
+const MAX_BATCH_LEN: usize = 1_024;
+
+// How long the idle stages wait for a message before checking for the stop request.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The account of a Geyser message as decoded ahead of the apply stage. The slots and the closures carry no account,
+// and the Oracles and the token accounts are decoded as applied.
+enum Decoded {
+    Clock(Clock),
+    Slot,
+    MarginfiAccount(CachedMarginfiAccount),
+    Bank(CachedBank),
+    Oracle,
+    TokenAccount,
+    ClosedAccount,
+}
+
+struct DecodedMessage {
+    msg: GeyserMessage,
+    decoded: anyhow::Result<Decoded>,
+}
+
+impl DecodedMessage {
+    // The Oracle and Bank updates, which price the positions.
+    fn is_price(&self) -> bool {
+        matches!(
+            self.decoded,
+            Ok(Decoded::Bank(_)) | Ok(Decoded::Oracle) | Ok(Decoded::Clock(_))
+        )
+    }
 
+    fn decode(msg: GeyserMessage) -> Self {
+        let decoded = match msg.message_type {
+            MessageType::Clock => bincode::deserialize::<Clock>(&msg.account.data)
+                .map(Decoded::Clock)
+                .map_err(anyhow::Error::from),
+            MessageType::MarginfiAccount => {
+                CachedMarginfiAccount::from_data(msg.slot, msg.address, &msg.account.data)
+                    .map(Decoded::MarginfiAccount)
+            }
+            MessageType::Bank => {
+                CachedBank::from_data(msg.slot, msg.address, &msg.account.data).map(Decoded::Bank)
+            }
+            MessageType::Slot => Ok(Decoded::Slot),
+            MessageType::Oracle => Ok(Decoded::Oracle),
+            MessageType::TokenAccount => Ok(Decoded::TokenAccount),
+            MessageType::ClosedAccount => Ok(Decoded::ClosedAccount),
+        };
+        Self { msg, decoded }
+    }
+}
+
+/// Applies the Geyser messages to the cache in two stages: the decode workers check the discriminators and
+/// deserialize the accounts in parallel, and a single apply stage updates the cache in batches. The workers
+/// may reorder the updates of an account, the older ones being dropped once a newer one is applied. When the
+/// queue is deep, the Oracle and Bank updates of a batch, which create the liquidation opportunities, are
+/// applied and published ahead of its Marginfi account updates.
 pub struct GeyserProcessor {
     stop: Arc<AtomicBool>,
     cache: Arc<Cache>,
-    geyser_rx: Receiver<GeyserMessage>,
+    geyser_rx: GeyserReceiver,
+    // The Banks created since the startup, for the CacheReconciler to load their Mints and Oracles.
+    new_banks: Sender<Pubkey>,
+    // 0 decodes the messages in the apply stage.
+    decode_workers: usize,
+    // The queued messages from which the price updates are applied first, always at 0.
+    price_lane_depth: usize,
 }
 
 impl GeyserProcessor {
     pub fn new(
         stop: Arc<AtomicBool>,
         cache: Arc<Cache>,
-        geyser_rx: Receiver<GeyserMessage>,
+        geyser_rx: GeyserReceiver,
+        new_banks: Sender<Pubkey>,
+        decode_workers: usize,
+        price_lane_depth: usize,
     ) -> Self {
         Self {
             stop,
             cache,
             geyser_rx,
+            new_banks,
+            decode_workers,
+            price_lane_depth,
         }
     }
 
     pub fn run(&self) -> anyhow::Result<()> {
-        info!("Entering the GeyserProcessor loop.");
+        info!(
+            "Entering the GeyserProcessor loop with {} decode workers.",
+            self.decode_workers
+        );
+        if self.decode_workers == 0 {
+            self.run_inline();
+        } else {
+            let (decoded_tx, decoded_rx) = channel::bounded(MAX_BATCH_LEN);
+            thread::scope(|scope| {
+                for _ in 0..self.decode_workers {
+                    let decoded_tx = decoded_tx.clone();
+                    scope.spawn(move || self.decode(decoded_tx));
+                }
+                // The apply stage ends once the workers are stopped.
+                drop(decoded_tx);
+                self.apply(decoded_rx);
+            });
+        }
+
+        info!("The GeyserProcessor loop is stopped.");
+        Ok(())
+    }
+
+    // Decodes and applies the messages in the same thread.
+    fn run_inline(&self) {
+        while !self.stop.load(Ordering::Relaxed) {
+            match self.geyser_rx.recv_timeout(STOP_POLL_INTERVAL) {
+                Ok(msg) => {
+                    // The burst queued meanwhile, e.g. at the slot boundary, is applied as a batch.
+                    let mut batch = vec![msg];
+                    batch.extend(self.geyser_rx.try_iter().take(MAX_BATCH_LEN - 1));
+                    self.log_failed_batch(
+                        batch.len(),
+                        self.process_batch(batch.into_iter().map(DecodedMessage::decode).collect()),
+                    );
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    error!("The Geyser channel is disconnected!");
+                    break;
+                }
+            }
+        }
+    }
+
+    // The decode stage, run by every worker.
+    fn decode(&self, decoded_tx: Sender<DecodedMessage>) {
         while !self.stop.load(Ordering::Relaxed) {
-            match self.geyser_rx.recv() {
-                Ok(mut msg) => {
-                    if let Err(err) = self.process_message(&mut msg) {
-                        error!("Failed to process Geyser message {:?}: {}", msg, err);
+            match self.geyser_rx.recv_timeout(STOP_POLL_INTERVAL) {
+                Ok(msg) => {
+                    if decoded_tx.send(DecodedMessage::decode(msg)).is_err() {
+                        break;
                     }
                 }
-                Err(error) => {
-                    error!("GeyserProcessor error: {}!", error);
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    error!("The Geyser channel is disconnected!");
+                    break;
                 }
             }
         }
+    }
 
-        info!("The GeyserProcessor loop is stopped.");
-        Ok(())
+    // The apply stage, until the decode workers are stopped.
+    fn apply(&self, decoded_rx: Receiver<DecodedMessage>) {
+        while let Ok(decoded) = decoded_rx.recv() {
+            let mut batch = vec![decoded];
+            batch.extend(decoded_rx.try_iter().take(MAX_BATCH_LEN - 1));
+            self.log_failed_batch(batch.len(), self.process_batch(batch));
+        }
     }
 
-    fn process_message(&self, msg: &mut GeyserMessage) -> anyhow::Result<()> {
-        trace!("Processing Geyser message: {}", msg);
-        match msg.message_type {
-            MessageType::Clock => {
-                let clock: Clock = bincode::deserialize::<Clock>(&msg.account.data)?;
-                self.cache.update_clock(clock)?;
+    fn log_failed_batch(&self, batch_len: usize, result: anyhow::Result<()>) {
+        if let Err(err) = result {
+            error!(
+                "Failed to process a batch of {} Geyser messages: {}",
+                batch_len, err
+            );
+        }
+    }
+
+    /// Applies the decoded messages in order, the price updates first when the batch and the messages still queued
+    /// reach the price lane depth.
+    fn process_batch(&self, batch: Vec<DecodedMessage>) -> anyhow::Result<()> {
+        if batch.len() + self.geyser_rx.queue_depth() < self.price_lane_depth {
+            return self.apply_batch(batch);
+        }
+        let (prices, others): (Vec<DecodedMessage>, Vec<DecodedMessage>) =
+            batch.into_iter().partition(DecodedMessage::is_price);
+        if !prices.is_empty() {
+            trace!(
+                "Applying {} price updates ahead of {} other Geyser messages",
+                prices.len(),
+                others.len()
+            );
+            self.apply_batch(prices)?;
+        }
+        self.apply_batch(others)
+    }
+
+    /// Applies the decoded messages in order. The Banks, Oracles and token accounts of the batch are applied
+    /// under a single acquisition of their cache lock, and the events published at once. The Marginfi accounts
+    /// are applied one by one, their sharded health index being locked by account. The failed messages are
+    /// logged and skipped.
+    fn apply_batch(&self, batch: Vec<DecodedMessage>) -> anyhow::Result<()> {
+        let mut banks = vec![];
+        let mut oracles = vec![];
+        let mut token_accounts = vec![];
+        let mut events = vec![];
+        for DecodedMessage { msg, decoded } in batch {
+            trace!("Processing Geyser message: {}", msg);
+            if !self
+                .cache
+                .update_versions
+                .advance(msg.address, msg.slot, msg.write_version)
+            {
+                trace!(
+                    "Dropping the replayed or out-of-order Geyser message: {}",
+                    msg
+                );
+                continue;
             }
-            MessageType::MarginfiAccount => {
-                let marginfi_account: MarginfiAccount =
-                    MarginfiAccount::try_deserialize(&mut msg.account.data.as_slice())?;
-                self.cache
+            self.cache.metrics.record_update(&msg.message_type);
+            let result = match decoded {
+                Ok(Decoded::Clock(clock)) => self.cache.update_clock(clock),
+                Ok(Decoded::Slot) => self.cache.advance_clock_slot(msg.slot),
+                Ok(Decoded::MarginfiAccount(account)) => self
+                    .cache
                     .marginfi_accounts
-                    .update(msg.slot, msg.address, marginfi_account)?;
-            }
-            MessageType::Bank => {
-                let bank: Bank = Bank::try_deserialize(&mut msg.account.data.as_slice())?;
-                self.cache.banks.update(msg.slot, msg.address, &bank)?;
+                    .update_cached(account)
+                    .map(|()| events.push(CacheEvent::AccountUpdated(msg.address))),
+                Ok(Decoded::ClosedAccount) => self
+                    .cache
+                    .remove_closed_account(msg.slot, &msg.address)
+                    .map(|removed| {
+                        if removed {
+                            debug!("Removed the closed Marginfi account {}", msg.address);
+                            events.push(CacheEvent::AccountClosed(msg.address));
+                        }
+                    }),
+                Ok(Decoded::Bank(bank)) => {
+                    banks.push(bank);
+                    events.push(CacheEvent::BankUpdated(msg.address));
+                    Ok(())
+                }
+                Ok(Decoded::Oracle) => {
+                    oracles.push(msg);
+                    continue;
+                }
+                Ok(Decoded::TokenAccount) => {
+                    token_accounts.push(msg);
+                    continue;
+                }
+                Err(err) => {
+                    self.cache.metrics.record_decode_failure();
+                    Err(err)
+                }
+            };
+            if let Err(err) = result {
+                error!("Failed to process Geyser message {:?}: {}", msg, err);
             }
-            MessageType::Oracle => {
-                self.cache
-                    .oracles
-                    .update(msg.slot, &msg.address, &mut msg.account)?;
+        }
+
+        // The Geyser subscriptions are updated with the Oracles of the new Banks.
+        self.cache.register_bank_oracles(&banks);
+        for bank in self.cache.banks.update_batch(banks)? {
+            info!("Observed the new Bank {}.", bank);
+            // Nothing loads its Mint and Oracles when the CacheReconciler is disabled.
+            let _ = self.new_banks.send(bank);
+        }
+        let mut previous_prices: Vec<(Pubkey, Option<I80F48>)> = vec![];
+        let mut seen = HashSet::new();
+        for msg in oracles.iter().filter(|msg| seen.insert(msg.address)) {
+            previous_prices.push((msg.address, self.cache.oracles.get_price(&msg.address)));
+        }
+        self.cache.oracles.update_batch(
+            oracles
+                .iter_mut()
+                .map(|msg| (msg.slot, msg.address, &mut msg.account)),
+        )?;
+        // Once per Oracle whose price changed over the batch.
+        for (address, previous_price) in previous_prices {
+            if self.cache.oracles.get_price(&address) != previous_price {
+                events.push(CacheEvent::OraclePriceChanged(address));
             }
         }
-        Ok(())
+        self.cache.wallet_token_accounts.update_batch(
+            token_accounts
+                .iter()
+                .map(|msg| (msg.slot, msg.address, &msg.account)),
+        )?;
+        self.cache.events.publish(&events)
     }
 
     pub fn queue_depth(&self) -> usize {
-        self.geyser_rx.len()
+        self.geyser_rx.queue_depth()
+    }
+
+    pub fn dropped_updates(&self) -> u64 {
+        self.geyser_rx.dropped()
     }
 }
 
@@ -86,31 +319,43 @@ mod tests {
     use super::*;
 
     use crate::cache::{
-        banks::test_util::create_bank_with_oracles,
-        marginfi_accounts::test_util::create_marginfi_account,
+        banks::{test_util::create_bank_with_oracles, CachedBank},
+        candidates::RankedCandidate,
+        marginfi_accounts::{test_util::create_marginfi_account, CachedMarginfiAccount},
         test_util::{create_dummy_cache, generate_test_clock},
         Cache,
     };
     use crate::common::MessageType;
+    use crate::service::geyser_channel::{geyser_channel, GeyserSender, OverflowPolicy};
     use crate::service::geyser_subscriber::GeyserMessage;
     use crossbeam::channel;
+    use marginfi::state::marginfi_group::Bank;
     use solana_sdk::{account::Account, clock::Clock, pubkey::Pubkey};
-    use std::sync::{atomic::AtomicBool, Arc};
-
-    fn setup_processor() -> (
-        GeyserProcessor,
-        channel::Sender<GeyserMessage>,
-        Arc<AtomicBool>,
-        Arc<Cache>,
-    ) {
+    use std::{
+        sync::{atomic::AtomicBool, Arc},
+        time::Instant,
+    };
+
+    fn setup_processor() -> (GeyserProcessor, GeyserSender, Arc<AtomicBool>, Arc<Cache>) {
         let stop = Arc::new(AtomicBool::new(false));
         let cache = Arc::new(create_dummy_cache());
 
-        let (tx, rx) = channel::unbounded();
-        let processor = GeyserProcessor::new(stop.clone(), cache.clone(), rx);
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
+        let processor = GeyserProcessor::new(
+            stop.clone(),
+            cache.clone(),
+            rx,
+            channel::unbounded().0,
+            2,
+            1_000,
+        );
         (processor, tx, stop, cache)
     }
 
+    fn decoded(batch: Vec<GeyserMessage>) -> Vec<DecodedMessage> {
+        batch.into_iter().map(DecodedMessage::decode).collect()
+    }
+
     #[test]
     fn test_queue_depth() {
         let (processor, tx, _, _) = setup_processor();
@@ -120,6 +365,7 @@ mod tests {
             message_type: MessageType::Clock,
             slot: 1,
             address: Pubkey::default(),
+            write_version: 0,
             account: Account::new(1, 2, &Pubkey::new_unique()),
         };
         tx.send(msg).unwrap();
@@ -135,6 +381,7 @@ mod tests {
             message_type: MessageType::Clock,
             slot: 1,
             address: Pubkey::default(),
+            write_version: 0,
             account: Account::new(1, 2, &Pubkey::new_unique()),
         };
         tx.send(msg).unwrap();
@@ -143,6 +390,19 @@ mod tests {
         // No panic means success; further asserts require Cache implementation details
     }
 
+    #[test]
+    fn test_process_slot_messages() {
+        let (processor, _, _, cache) = setup_processor();
+        processor
+            .process_batch(decoded(vec![
+                GeyserMessage::new_slot(5),
+                GeyserMessage::new_slot(4),
+                GeyserMessage::new_slot(5),
+            ]))
+            .unwrap();
+        assert_eq!(cache.get_clock().unwrap().slot, 5);
+    }
+
     #[test]
     fn test_process_marginfi_account_message() {
         let _marginfi_account = create_marginfi_account(Pubkey::new_unique(), vec![]);
@@ -162,6 +422,7 @@ mod tests {
             message_type: MessageType::Oracle,
             slot: 4,
             address: Pubkey::new_unique(),
+            write_version: 0,
             account: Account::new(1, 2, &Pubkey::new_unique()),
         };
         tx.send(msg).unwrap();
@@ -169,6 +430,370 @@ mod tests {
         processor.run().unwrap();
     }
 
+    #[test]
+    fn test_process_publishes_cache_events() {
+        let (processor, _, _, cache) = setup_processor();
+        let events = cache.events.subscribe().unwrap();
+        let address = Pubkey::new_unique();
+        let bank = Pubkey::new_unique();
+        let message = |message_type, address, data| GeyserMessage {
+            message_type,
+            slot: 5,
+            address,
+            write_version: 0,
+            account: Account {
+                lamports: 1,
+                data,
+                owner: Pubkey::new_unique(),
+                executable: false,
+                rent_epoch: 0,
+            },
+        };
+
+        let account_data = CachedMarginfiAccount::from(
+            0,
+            address,
+            create_marginfi_account(Pubkey::new_unique(), vec![]),
+        )
+        .to_snapshot_account()
+        .data;
+        processor
+            .process_batch(decoded(vec![message(
+                MessageType::MarginfiAccount,
+                address,
+                account_data,
+            )]))
+            .unwrap();
+        let bank_data = CachedBank::from(0, bank, create_bank_with_oracles(vec![]))
+            .to_snapshot_account()
+            .data;
+        processor
+            .process_batch(decoded(vec![message(MessageType::Bank, bank, bank_data)]))
+            .unwrap();
+        // An oracle update without a price change is not published.
+        processor
+            .process_batch(decoded(vec![message(
+                MessageType::Oracle,
+                Pubkey::new_unique(),
+                vec![],
+            )]))
+            .unwrap();
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                CacheEvent::AccountUpdated(address),
+                CacheEvent::BankUpdated(bank)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_drops_out_of_order_messages() {
+        let (processor, _, _, cache) = setup_processor();
+        let address = Pubkey::new_unique();
+        let banks: Vec<_> = (0..3).map(|_| create_bank_with_oracles(vec![])).collect();
+        let message = |bank: &Bank, write_version| GeyserMessage {
+            message_type: MessageType::Bank,
+            slot: 5,
+            address,
+            write_version,
+            account: Account {
+                lamports: 1,
+                data: CachedBank::from(0, address, *bank)
+                    .to_snapshot_account()
+                    .data,
+                owner: Pubkey::new_unique(),
+                executable: false,
+                rent_epoch: 0,
+            },
+        };
+        let cached_mint = || *cache.banks.get_bank(&address).unwrap().mint();
+
+        processor
+            .process_batch(decoded(vec![message(&banks[0], 2)]))
+            .unwrap();
+        // A replay, then an earlier write of the slot.
+        processor
+            .process_batch(decoded(vec![message(&banks[1], 2)]))
+            .unwrap();
+        processor
+            .process_batch(decoded(vec![message(&banks[1], 1)]))
+            .unwrap();
+        assert_eq!(cached_mint(), banks[0].mint);
+
+        processor
+            .process_batch(decoded(vec![message(&banks[2], 3)]))
+            .unwrap();
+        assert_eq!(cached_mint(), banks[2].mint);
+    }
+
+    #[test]
+    fn test_process_batch_in_order() {
+        let (processor, _, _, cache) = setup_processor();
+        let events = cache.events.subscribe().unwrap();
+        let (bank, address) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let banks: Vec<_> = (0..3).map(|_| create_bank_with_oracles(vec![])).collect();
+        let message = |message_type, address, write_version, data| GeyserMessage {
+            message_type,
+            slot: 5,
+            address,
+            write_version,
+            account: Account {
+                lamports: 1,
+                data,
+                owner: Pubkey::new_unique(),
+                executable: false,
+                rent_epoch: 0,
+            },
+        };
+        let bank_message = |bank_state: &Bank, write_version| {
+            message(
+                MessageType::Bank,
+                bank,
+                write_version,
+                CachedBank::from(0, bank, *bank_state)
+                    .to_snapshot_account()
+                    .data,
+            )
+        };
+        let account_data = CachedMarginfiAccount::from(
+            0,
+            address,
+            create_marginfi_account(Pubkey::new_unique(), vec![]),
+        )
+        .to_snapshot_account()
+        .data;
+
+        processor
+            .process_batch(decoded(vec![
+                bank_message(&banks[0], 1),
+                message(MessageType::MarginfiAccount, address, 0, account_data),
+                bank_message(&banks[1], 2),
+                // Out of order within the batch.
+                bank_message(&banks[2], 1),
+                // Not a Bank, skipped.
+                message(MessageType::Bank, Pubkey::new_unique(), 0, vec![]),
+            ]))
+            .unwrap();
+
+        assert_eq!(*cache.banks.get_bank(&bank).unwrap().mint(), banks[1].mint);
+        assert!(cache.marginfi_accounts.get_account(&address).is_ok());
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                CacheEvent::BankUpdated(bank),
+                CacheEvent::AccountUpdated(address),
+                CacheEvent::BankUpdated(bank)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_prices_first_when_deep() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let cache = Arc::new(create_dummy_cache());
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
+        let processor = GeyserProcessor::new(stop, cache.clone(), rx, channel::unbounded().0, 2, 3);
+        let events = cache.events.subscribe().unwrap();
+        let (bank, address) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let message = |message_type, slot, address, data| GeyserMessage {
+            message_type,
+            slot,
+            address,
+            write_version: 0,
+            account: Account {
+                lamports: 1,
+                data,
+                owner: Pubkey::new_unique(),
+                executable: false,
+                rent_epoch: 0,
+            },
+        };
+        let account_message = |slot| {
+            let data = CachedMarginfiAccount::from(
+                0,
+                address,
+                create_marginfi_account(Pubkey::new_unique(), vec![]),
+            )
+            .to_snapshot_account()
+            .data;
+            message(MessageType::MarginfiAccount, slot, address, data)
+        };
+        let bank_message = |slot| {
+            let data = CachedBank::from(0, bank, create_bank_with_oracles(vec![]))
+                .to_snapshot_account()
+                .data;
+            message(MessageType::Bank, slot, bank, data)
+        };
+
+        // A shallow queue is applied in order.
+        processor
+            .process_batch(decoded(vec![account_message(5), bank_message(5)]))
+            .unwrap();
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                CacheEvent::AccountUpdated(address),
+                CacheEvent::BankUpdated(bank)
+            ]
+        );
+
+        // The message still queued makes it deep.
+        tx.send(GeyserMessage::new_slot(6)).unwrap();
+        processor
+            .process_batch(decoded(vec![account_message(6), bank_message(6)]))
+            .unwrap();
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                CacheEvent::BankUpdated(bank),
+                CacheEvent::AccountUpdated(address)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_closed_account() {
+        let (processor, _, _, cache) = setup_processor();
+        let events = cache.events.subscribe().unwrap();
+        let address = Pubkey::new_unique();
+        let message = |message_type, slot, data| GeyserMessage {
+            message_type,
+            slot,
+            address,
+            write_version: 0,
+            account: Account {
+                lamports: 0,
+                data,
+                owner: Pubkey::default(),
+                executable: false,
+                rent_epoch: 0,
+            },
+        };
+        let account_data = CachedMarginfiAccount::from(
+            0,
+            address,
+            create_marginfi_account(Pubkey::new_unique(), vec![]),
+        )
+        .to_snapshot_account()
+        .data;
+        cache
+            .candidates
+            .replace(vec![RankedCandidate {
+                address,
+                health: -1,
+                liquidatable_since_unix: 0,
+                ranked_at_unix: 0,
+            }])
+            .unwrap();
+
+        processor
+            .process_batch(decoded(vec![
+                message(MessageType::MarginfiAccount, 5, account_data),
+                message(MessageType::ClosedAccount, 6, vec![]),
+            ]))
+            .unwrap();
+
+        assert!(cache
+            .marginfi_accounts
+            .find_account(&address)
+            .unwrap()
+            .is_none());
+        assert!(cache.candidates.get().unwrap().is_empty());
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                CacheEvent::AccountUpdated(address),
+                CacheEvent::AccountClosed(address)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_new_banks() {
+        let cache = Arc::new(create_dummy_cache());
+        let (new_banks_tx, new_banks_rx) = channel::unbounded();
+        let processor = GeyserProcessor::new(
+            Arc::new(AtomicBool::new(false)),
+            cache.clone(),
+            geyser_channel(16, OverflowPolicy::DropOldest).1,
+            new_banks_tx,
+            2,
+            1_000,
+        );
+        let bank = Pubkey::new_unique();
+        let oracle = Pubkey::new_unique();
+        let bank_message = |slot| GeyserMessage {
+            message_type: MessageType::Bank,
+            slot,
+            address: bank,
+            write_version: 0,
+            account: Account {
+                lamports: 1,
+                data: CachedBank::from(0, bank, create_bank_with_oracles(vec![oracle]))
+                    .to_snapshot_account()
+                    .data,
+                owner: Pubkey::new_unique(),
+                executable: false,
+                rent_epoch: 0,
+            },
+        };
+
+        processor
+            .process_batch(decoded(vec![bank_message(5), bank_message(6)]))
+            .unwrap();
+        processor
+            .process_batch(decoded(vec![bank_message(7)]))
+            .unwrap();
+        assert_eq!(new_banks_rx.try_iter().collect::<Vec<_>>(), vec![bank]);
+        assert_eq!(cache.oracles.get_oracle_addresses(), vec![oracle]);
+    }
+
+    #[test]
+    fn test_run_decode_workers() {
+        let (processor, tx, stop, cache) = setup_processor();
+        let processor = Arc::new(processor);
+        let handle = {
+            let processor = processor.clone();
+            std::thread::spawn(move || processor.run())
+        };
+
+        for slot in 1..=100 {
+            tx.send(GeyserMessage::new_slot(slot)).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while cache.get_clock().unwrap().slot < 100 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The slots reordered by the workers do not move the clock backwards.
+        assert_eq!(cache.get_clock().unwrap().slot, 100);
+        assert_eq!(processor.queue_depth(), 0);
+
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_run_inline() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let cache = Arc::new(create_dummy_cache());
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
+        let processor = GeyserProcessor::new(
+            stop.clone(),
+            cache.clone(),
+            rx,
+            channel::unbounded().0,
+            0,
+            1_000,
+        );
+        tx.send(GeyserMessage::new_slot(7)).unwrap();
+        // The processor stops once the channel is disconnected.
+        drop(tx);
+        processor.run().unwrap();
+        assert_eq!(cache.get_clock().unwrap().slot, 7);
+    }
+
     #[test]
     fn test_run_stops_on_stop_signal() {
         let (processor, _, stop, _) = setup_processor();
@@ -180,9 +805,16 @@ mod tests {
     fn test_run_handles_recv_error() {
         let stop = Arc::new(AtomicBool::new(false));
         let cache = Arc::new(create_dummy_cache());
-        let (tx, rx) = channel::bounded(0);
+        let (tx, rx) = geyser_channel(1, OverflowPolicy::DropOldest);
         drop(tx); // Close the channel
-        let processor = GeyserProcessor::new(stop.clone(), cache.clone(), rx);
+        let processor = GeyserProcessor::new(
+            stop.clone(),
+            cache.clone(),
+            rx,
+            channel::unbounded().0,
+            2,
+            1_000,
+        );
         stop.store(true, Ordering::Relaxed);
         assert!(processor.run().is_ok());
     }
@@ -0,0 +1,326 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use prost::Message;
+use yellowstone_grpc_proto::geyser::SubscribeUpdate;
+
+// The receive time in microseconds since the Unix epoch, then the length of the update.
+const RECORD_HEADER_LEN: usize = 12;
+
+/// A Geyser update as recorded, with the time it was received.
+#[derive(Debug)]
+pub struct RecordedUpdate {
+    pub received_at_micros: u64,
+    pub update: SubscribeUpdate,
+}
+
+// The file being recorded to, with its size.
+struct RecordingFile {
+    writer: BufWriter<File>,
+    len: u64,
+}
+
+impl RecordingFile {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open the Geyser recording {}", path.display()))?;
+        Ok(Self {
+            len: file.metadata()?.len(),
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+/// Appends the Geyser updates to a recording, for replaying them and for the forensics of a production incident.
+/// Every update is recorded as its receive time in microseconds since the Unix epoch, the length of its protobuf
+/// encoding and the encoding itself, the integers being little-endian. The subscribers of both endpoints share
+/// the recording.
+///
+/// The recording rotates once its file would exceed the maximum size: the file is renamed with the suffix `.1`,
+/// the former `.1` becoming `.2` and so on, the files beyond the maximum count being deleted.
+pub struct GeyserRecorder {
+    path: PathBuf,
+    max_file_bytes: u64,
+    // The files kept, the one being recorded to included.
+    max_files: usize,
+    file: Mutex<RecordingFile>,
+}
+
+impl GeyserRecorder {
+    pub fn create(path: &Path, max_file_bytes: u64, max_files: usize) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_file_bytes,
+            max_files: max_files.max(1),
+            file: Mutex::new(RecordingFile::open(path)?),
+        })
+    }
+
+    pub fn record(&self, update: &SubscribeUpdate) -> Result<()> {
+        let received_at_micros = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64;
+        self.record_at(received_at_micros, update)
+    }
+
+    fn record_at(&self, received_at_micros: u64, update: &SubscribeUpdate) -> Result<()> {
+        let encoded = update.encode_to_vec();
+        let record_len = (RECORD_HEADER_LEN + encoded.len()) as u64;
+        let mut file = self.lock()?;
+        // An update larger than the maximum size is recorded alone in its file.
+        if file.len > 0 && file.len + record_len > self.max_file_bytes {
+            file.writer.flush()?;
+            self.rotate()?;
+            *file = RecordingFile::open(&self.path)?;
+        }
+        file.writer.write_all(&received_at_micros.to_le_bytes())?;
+        file.writer
+            .write_all(&(encoded.len() as u32).to_le_bytes())?;
+        file.writer.write_all(&encoded)?;
+        // The recording is complete up to the last update should the bot crash.
+        file.writer.flush()?;
+        file.len += record_len;
+        Ok(())
+    }
+
+    fn rotate(&self) -> Result<()> {
+        for index in (1..self.max_files).rev() {
+            let from = match index {
+                1 => self.path.clone(),
+                _ => rotated_path(&self.path, index - 1),
+            };
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, index))?;
+            }
+        }
+        // Nothing is kept but the file being recorded to.
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, RecordingFile>> {
+        self.file
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the Geyser recording: {}", e))
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
+
+/// The files of the recording, the oldest rotated one first and the one recorded to last.
+fn recording_files(path: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = (1..)
+        .map(|index| rotated_path(path, index))
+        .take_while(|rotated| rotated.exists())
+        .collect();
+    files.reverse();
+    if path.exists() {
+        files.push(path.to_path_buf());
+    }
+    files
+}
+
+/// Reads the updates of a recording and of its rotated files in their order. The reading of a file ends at its
+/// end or at a truncated update.
+pub struct GeyserRecording {
+    files: VecDeque<PathBuf>,
+    reader: Option<BufReader<File>>,
+}
+
+impl GeyserRecording {
+    pub fn open(path: &Path) -> Result<Self> {
+        let files = recording_files(path);
+        if files.is_empty() {
+            return Err(anyhow!("No Geyser recording found at {}", path.display()));
+        }
+        Ok(Self {
+            files: files.into(),
+            reader: None,
+        })
+    }
+
+    fn read_update(&mut self) -> Result<Option<RecordedUpdate>> {
+        loop {
+            let reader = match &mut self.reader {
+                Some(reader) => reader,
+                None => {
+                    let Some(path) = self.files.pop_front() else {
+                        return Ok(None);
+                    };
+                    let file = File::open(&path).with_context(|| {
+                        format!("Failed to open the Geyser recording {}", path.display())
+                    })?;
+                    self.reader.insert(BufReader::new(file))
+                }
+            };
+            match read_record(reader)? {
+                Some(recorded) => return Ok(Some(recorded)),
+                None => self.reader = None,
+            }
+        }
+    }
+}
+
+impl Iterator for GeyserRecording {
+    type Item = Result<RecordedUpdate>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_update().transpose()
+    }
+}
+
+fn read_record(reader: &mut impl Read) -> Result<Option<RecordedUpdate>> {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        // The last update of a recording interrupted while writing is incomplete.
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let received_at_micros = u64::from_le_bytes(header[..8].try_into()?);
+    let len = u32::from_le_bytes(header[8..].try_into()?) as usize;
+    let mut encoded = vec![0u8; len];
+    match reader.read_exact(&mut encoded) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(Some(RecordedUpdate {
+        received_at_micros,
+        update: SubscribeUpdate::decode(encoded.as_slice())?,
+    }))
+}
+
+/// The delay of the update from the start of the replay, the recorded delay from the first update being
+/// shortened by the speed. The updates are replayed without any delay at the speed 0.
+pub fn replay_delay(
+    first_received_at_micros: u64,
+    received_at_micros: u64,
+    speed: u64,
+) -> Duration {
+    if speed == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_micros(received_at_micros.saturating_sub(first_received_at_micros) / speed)
+}
+
+#[cfg(test)]
+mod tests {
+    use yellowstone_grpc_proto::geyser::{subscribe_update, SubscribeUpdateSlot};
+
+    use super::*;
+    use crate::cache::snapshot::test_util::create_test_dir;
+
+    fn slot_update(slot: u64) -> SubscribeUpdate {
+        SubscribeUpdate {
+            update_oneof: Some(subscribe_update::UpdateOneof::Slot(SubscribeUpdateSlot {
+                slot,
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn recorded_slots(path: &Path) -> Vec<(u64, Option<u64>)> {
+        GeyserRecording::open(path)
+            .unwrap()
+            .map(|recorded| {
+                let recorded = recorded.unwrap();
+                let slot = match &recorded.update.update_oneof {
+                    Some(subscribe_update::UpdateOneof::Slot(slot)) => Some(slot.slot),
+                    _ => None,
+                };
+                (recorded.received_at_micros, slot)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_geyser_recording() {
+        let dir = create_test_dir("geyser_recording");
+        let path = dir.join("recording.bin");
+        assert!(GeyserRecording::open(&path).is_err());
+
+        let recorder = GeyserRecorder::create(&path, u64::MAX, 1).unwrap();
+        recorder.record_at(1_000_000, &slot_update(100)).unwrap();
+        recorder.record_at(1_500_000, &slot_update(101)).unwrap();
+        drop(recorder);
+        // An update cut short by a crash is not replayed.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&2_000_000u64.to_le_bytes()).unwrap();
+        file.write_all(&64u32.to_le_bytes()).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+
+        assert_eq!(
+            recorded_slots(&path),
+            vec![(1_000_000, Some(100)), (1_500_000, Some(101))]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_geyser_recording_rotation() {
+        let dir = create_test_dir("geyser_rotation");
+        let path = dir.join("recording.bin");
+        let record_len = (RECORD_HEADER_LEN + slot_update(100).encoded_len()) as u64;
+
+        // Two updates per file, three files kept.
+        let recorder = GeyserRecorder::create(&path, 2 * record_len, 3).unwrap();
+        for slot in 100..107 {
+            recorder
+                .record_at(slot * 1_000, &slot_update(slot))
+                .unwrap();
+        }
+        assert_eq!(
+            recording_files(&path),
+            vec![rotated_path(&path, 2), rotated_path(&path, 1), path.clone()]
+        );
+        assert!(!rotated_path(&path, 3).exists());
+        assert_eq!(fs::metadata(&path).unwrap().len(), record_len);
+        // The oldest updates are deleted with their file.
+        assert_eq!(
+            recorded_slots(&path),
+            (102..107)
+                .map(|slot| (slot * 1_000, Some(slot)))
+                .collect::<Vec<_>>()
+        );
+
+        // A restarted recorder appends to the file until it is full.
+        let recorder = GeyserRecorder::create(&path, 2 * record_len, 3).unwrap();
+        recorder.record_at(107_000, &slot_update(107)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 2 * record_len);
+        assert_eq!(recording_files(&path).len(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_delay() {
+        assert_eq!(
+            replay_delay(1_000_000, 3_000_000, 1),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            replay_delay(1_000_000, 3_000_000, 4),
+            Duration::from_millis(500)
+        );
+        assert_eq!(replay_delay(1_000_000, 3_000_000, 0), Duration::ZERO);
+        assert_eq!(replay_delay(3_000_000, 1_000_000, 1), Duration::ZERO);
+    }
+}
@@ -1,34 +1,91 @@
 use std::{
     collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
     },
+    thread,
+    time::{Duration, Instant},
 };
 use std::{collections::HashSet, fmt};
 
-use crate::common::{get_marginfi_message_type, MessageType};
-use crate::{cache::Cache, config::Config};
+use crate::common::{
+    get_marginfi_message_type, MessageType, MARGINFI_ACCOUNT_DISCRIMINATOR,
+    MARGINFI_BANK_DISCRIMINATOR, MARGINFI_LIQUIDATE_DISCRIMINATOR, SPL_TOKEN_2022_PROGRAM_ID,
+    SPL_TOKEN_PROGRAM_ID,
+};
+use crate::liquidation::wallet::TOKEN_ACCOUNT_OWNER_OFFSET;
+use crate::{
+    cache::{competitors::ObservedLiquidation, reconciliation::StreamGap, Cache},
+    comms::ProgramAccounts,
+    config::{redact_url, Config},
+    service::{
+        geyser_backend::{GeyserBackend, GeyserCompression},
+        geyser_channel::GeyserSender,
+        geyser_replay::{replay_delay, GeyserRecorder, GeyserRecording},
+    },
+};
 use anyhow::{anyhow, Result};
 use crossbeam::channel::Sender;
-use futures::stream::StreamExt; // Brings `next` into scope for streams
-use log::{error, info, trace};
-use solana_sdk::{account::Account, pubkey::Pubkey};
-use solana_sdk::{clock::Clock, sysvar};
+use futures::{
+    sink::SinkExt,
+    stream::{select_all, StreamExt},
+}; // Brings `send` and `next` into scope
+use log::{debug, error, info, trace, warn};
+use solana_sdk::{account::Account, pubkey::Pubkey, signature::Signature, signer::Signer};
+use solana_sdk::{clock::Clock, commitment_config::CommitmentLevel, compute_budget, sysvar};
 use tokio::runtime::{Builder, Runtime};
-use yellowstone_grpc_client::{ClientTlsConfig, GeyserGrpcClient};
+use yellowstone_grpc_client::{ClientTlsConfig, GeyserGrpcBuilder, GeyserGrpcClient};
 use yellowstone_grpc_proto::geyser::{
-    subscribe_update, SubscribeUpdate, SubscribeUpdateAccountInfo,
+    subscribe_request_filter_accounts_filter, subscribe_request_filter_accounts_filter_memcmp,
+    subscribe_update, CommitmentLevel as GeyserCommitmentLevel, SlotStatus,
+    SubscribeRequestFilterAccountsFilter, SubscribeRequestFilterAccountsFilterMemcmp,
+    SubscribeRequestFilterBlocksMeta, SubscribeRequestFilterSlots,
+    SubscribeRequestFilterTransactions, SubscribeRequestPing, SubscribeUpdate,
+    SubscribeUpdateAccountInfo, SubscribeUpdateTransaction,
 };
+use yellowstone_grpc_proto::tonic::{codec::CompressionEncoding, transport::Certificate};
 use yellowstone_grpc_proto::{geyser::SubscribeRequestFilterAccounts, prelude::SubscribeRequest};
 
 const SOLANA_CLOCK_BYTES: [u8; 32] = sysvar::clock::id().to_bytes();
+const SPL_TOKEN_PROGRAM_ID_BYTES: [u8; 32] = SPL_TOKEN_PROGRAM_ID.to_bytes();
+const SPL_TOKEN_2022_PROGRAM_ID_BYTES: [u8; 32] = SPL_TOKEN_2022_PROGRAM_ID.to_bytes();
+const COMPUTE_BUDGET_PROGRAM_ID_BYTES: [u8; 32] = compute_budget::id().to_bytes();
+
+// The tag of the SetComputeUnitPrice instruction of the compute budget program, followed by the price in
+// micro-lamports.
+const SET_COMPUTE_UNIT_PRICE_TAG: u8 = 3;
+
+// The accounts of the liquidation instruction, by position.
+const LIQUIDATE_ASSET_BANK_INDEX: usize = 1;
+const LIQUIDATE_LIAB_BANK_INDEX: usize = 2;
+const LIQUIDATE_AUTHORITY_INDEX: usize = 4;
+const LIQUIDATE_LIQUIDATEE_INDEX: usize = 5;
+
+// The first delay before reconnecting, doubled at every failed attempt up to the configured maximum.
+pub(super) const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+// The filters of the Marginfi accounts, which still match the updates closing them. The whole program was
+// subscribed as MarginfiProgram in the older recordings.
+const MARGINFI_ACCOUNT_FILTERS: [&str; 2] = ["MarginfiProgram", "MarginfiAccounts"];
+
+// The prefix of the filters of the confirmed stream, which reconciles the processed one.
+const CONFIRMED_FILTER_PREFIX: &str = "Confirmed";
+
+// The filter of the slot notifications at the commitment, which advance the clock.
+const SLOTS_FILTER: &str = "Slots";
+// The filter of all the slot statuses, for the dead slots of the processed streams.
+const SLOT_STATUSES_FILTER: &str = "SlotStatuses";
 
 #[derive(Debug)]
 pub struct GeyserMessage {
     pub(crate) message_type: MessageType,
     pub(crate) slot: u64,
     pub(crate) address: Pubkey,
+    // The order of the write within the slot.
+    pub(crate) write_version: u64,
     pub(crate) account: Account,
 }
 
@@ -48,6 +105,7 @@ impl GeyserMessage {
             message_type,
             slot,
             address,
+            write_version: geyser_update_account.write_version,
             account: Account {
                 lamports: geyser_update_account.lamports,
                 data: geyser_update_account.data,
@@ -57,37 +115,194 @@ impl GeyserMessage {
             },
         })
     }
+
+    /// A slot notification, versioned under the default address for the replays to be dropped.
+    pub fn new_slot(slot: u64) -> Self {
+        GeyserMessage {
+            message_type: MessageType::Slot,
+            slot,
+            address: Pubkey::default(),
+            write_version: 0,
+            account: Account::default(),
+        }
+    }
 }
 
 impl fmt::Display for GeyserMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "[type: {:?}, slot: {}, address: {}]",
-            self.message_type, self.slot, self.address,
+            "[type: {:?}, slot: {}, write version: {}, address: {}]",
+            self.message_type, self.slot, self.write_version, self.address,
         )
     }
 }
 
+/// A Geyser endpoint to subscribe to.
+#[derive(Clone)]
+pub struct GeyserEndpoint {
+    pub url: String,
+    pub x_token: String,
+    // The file the token is re-read from at every connection, if any.
+    pub x_token_path: Option<PathBuf>,
+}
+
+/// The primary endpoint, then the secondary one if any, which takes the primary token and its file unless it has
+/// its own token.
+pub fn geyser_endpoints(config: &Config) -> Vec<GeyserEndpoint> {
+    let x_token_path = config.geyser_x_token_path.as_ref().map(PathBuf::from);
+    let mut endpoints = vec![GeyserEndpoint {
+        url: config.geyser_endpoint.clone(),
+        x_token: config.geyser_x_token.clone(),
+        x_token_path: x_token_path.clone(),
+    }];
+    if let Some(url) = &config.geyser_secondary_endpoint {
+        endpoints.push(match &config.geyser_secondary_x_token {
+            Some(x_token) => GeyserEndpoint {
+                url: url.clone(),
+                x_token: x_token.clone(),
+                x_token_path: None,
+            },
+            None => GeyserEndpoint {
+                url: url.clone(),
+                x_token: config.geyser_x_token.clone(),
+                x_token_path,
+            },
+        });
+    }
+    endpoints
+}
+
+// The token of the file, which may end with a newline.
+fn read_x_token(path: &Path) -> Result<String> {
+    Ok(fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read the Geyser token {}: {}", path.display(), e))?
+        .trim()
+        .to_string())
+}
+
+/// The streams of the subscribers of all the endpoints. The updates are missed only while none of the streams
+/// is live, the gap lasting from the cache slot when the last one dropped until one is resubscribed.
+#[derive(Default)]
+pub struct GeyserStreams {
+    live: AtomicUsize,
+    dropped: Mutex<Option<(u64, Instant)>>,
+    // The highest slot of the updates received from any stream, 0 until the first one.
+    highest_slot: AtomicU64,
+}
+
+impl GeyserStreams {
+    pub fn highest_slot(&self) -> Option<u64> {
+        let slot = self.highest_slot.load(Ordering::Relaxed);
+        (slot > 0).then_some(slot)
+    }
+
+    pub fn observe_slot(&self, slot: u64) {
+        self.highest_slot.fetch_max(slot, Ordering::Relaxed);
+    }
+
+    /// Records a subscribed stream, returning the gap it closes, if any.
+    pub(super) fn subscribed(&self) -> Result<Option<StreamGap>> {
+        let mut dropped = self.lock_dropped()?;
+        self.live.fetch_add(1, Ordering::SeqCst);
+        Ok(dropped
+            .take()
+            .map(|(dropped_at_slot, dropped_at)| StreamGap {
+                dropped_at_slot,
+                resubscribed_after: dropped_at.elapsed(),
+            }))
+    }
+
+    /// Records a dropped stream, which opens a gap if it was the last live one.
+    pub(super) fn dropped(&self, slot: u64) -> Result<()> {
+        let mut dropped = self.lock_dropped()?;
+        if self.live.fetch_sub(1, Ordering::SeqCst) == 1 {
+            dropped.get_or_insert((slot, Instant::now()));
+        }
+        Ok(())
+    }
+
+    /// Records a failed subscription, which opens a gap if no stream is live, e.g. at startup.
+    pub(super) fn failed(&self, slot: u64) -> Result<()> {
+        let mut dropped = self.lock_dropped()?;
+        if self.live.load(Ordering::SeqCst) == 0 {
+            dropped.get_or_insert((slot, Instant::now()));
+        }
+        Ok(())
+    }
+
+    fn lock_dropped(&self) -> Result<MutexGuard<'_, Option<(u64, Instant)>>> {
+        self.dropped
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the Geyser streams: {}", e))
+    }
+}
+
+/// Subscribes to a Yellowstone gRPC endpoint. With several endpoints, the subscribers send the same updates to the
+/// GeyserProcessor, which drops the ones already applied by their slot and write version.
 pub struct GeyserSubscriber {
     endpoint: String,
-    x_token: String,
+    // The token of the latest connection, replaced when its file changes.
+    x_token: Mutex<String>,
+    x_token_path: Option<PathBuf>,
+    // The endpoint as logged.
+    name: String,
     stop: Arc<AtomicBool>,
     tls_config: ClientTlsConfig,
+    // None when the messages are not compressed.
+    compression: Option<CompressionEncoding>,
+    max_message_size: usize,
     tokio_rt: Runtime,
     cache: Arc<Cache>,
     marginfi_program_id: Pubkey,
-    geyser_tx: Sender<GeyserMessage>,
+    liquidator: Pubkey,
+    geyser_tx: GeyserSender,
+    bootstrap_idle_timeout: Duration,
+    // The longest the stream may go without any update, the slot notifications included, or a ping without its pong.
+    stall_timeout: Duration,
+    // None when the connection is not pinged.
+    ping_interval: Option<Duration>,
+    reconnect_max_backoff: Duration,
+    streams: Arc<GeyserStreams>,
+    commitment: CommitmentLevel,
+    // The commitment of the Oracle and Bank updates, which price the positions.
+    pricing_commitment: CommitmentLevel,
+    // The gaps of the streams, for the CacheReconciler to re-fetch the entries possibly missed.
+    gaps: Sender<StreamGap>,
+    recorder: Option<Arc<GeyserRecorder>>,
+    // The recording replayed instead of subscribing to the endpoint, with the speed factor.
+    replay_path: Option<PathBuf>,
+    replay_speed: u64,
+    // Some when the processed updates are reconciled with the confirmed stream, the ones still unconfirmed once
+    // the confirmed slot is past them by so many slots being rolled back.
+    rollback_after_slots: Option<u64>,
 }
 
 impl GeyserSubscriber {
     pub fn new(
         config: &Config,
+        endpoint: GeyserEndpoint,
         stop: Arc<AtomicBool>,
         cache: Arc<Cache>,
-        geyser_tx: Sender<GeyserMessage>,
+        geyser_tx: GeyserSender,
+        streams: Arc<GeyserStreams>,
+        gaps: Sender<StreamGap>,
     ) -> Result<Self> {
-        let tls_config = ClientTlsConfig::new().with_native_roots();
+        // A private deployment may be signed by its own CA rather than one of the native roots.
+        let tls_config = match &config.geyser_tls_ca_path {
+            Some(ca_path) => ClientTlsConfig::new()
+                .ca_certificate(Certificate::from_pem(fs::read(ca_path).map_err(|e| {
+                    anyhow!("Failed to read the Geyser TLS CA {}: {}", ca_path, e)
+                })?)),
+            None => ClientTlsConfig::new().with_native_roots(),
+        };
+
+        let (commitment, pricing_commitment) = if config.geyser_dual_commitment {
+            // Everything is acted upon as processed, the confirmed stream only reconciling it.
+            (CommitmentLevel::Processed, CommitmentLevel::Processed)
+        } else {
+            (config.geyser_commitment, config.geyser_pricing_commitment)
+        };
 
         let tokio_rt = Builder::new_multi_thread()
             .thread_name("GeyserService")
@@ -96,90 +311,691 @@ impl GeyserSubscriber {
             .build()?;
 
         Ok(Self {
-            endpoint: config.geyser_endpoint.clone(),
-            x_token: config.geyser_x_token.clone(),
+            name: redact_url(&endpoint.url),
+            endpoint: endpoint.url,
+            x_token: Mutex::new(endpoint.x_token),
+            x_token_path: endpoint.x_token_path,
             stop,
             tls_config,
+            compression: match config.geyser_compression {
+                GeyserCompression::Uncompressed => None,
+                GeyserCompression::Gzip => Some(CompressionEncoding::Gzip),
+                GeyserCompression::Zstd => Some(CompressionEncoding::Zstd),
+            },
+            max_message_size: config.geyser_max_message_mb * 1024 * 1024,
             tokio_rt,
             cache,
             marginfi_program_id: config.marginfi_program_id,
+            liquidator: config.wallet.pubkey(),
             geyser_tx,
+            bootstrap_idle_timeout: Duration::from_secs(config.geyser_bootstrap_idle_sec),
+            stall_timeout: Duration::from_secs(config.geyser_stall_timeout_sec),
+            ping_interval: (config.geyser_ping_interval_sec > 0)
+                .then(|| Duration::from_secs(config.geyser_ping_interval_sec)),
+            reconnect_max_backoff: Duration::from_secs(config.geyser_reconnect_max_backoff_sec)
+                .max(RECONNECT_INITIAL_BACKOFF),
+            streams,
+            commitment,
+            pricing_commitment,
+            gaps,
+            recorder: None,
+            replay_path: config.geyser_replay_path.as_ref().map(PathBuf::from),
+            replay_speed: config.geyser_replay_speed,
+            rollback_after_slots: config
+                .geyser_dual_commitment
+                .then_some(config.geyser_rollback_after_slots),
         })
     }
 
-    pub fn run(&self) -> Result<()> {
+    // The client of the endpoint, with its token unless it has none, its TLS settings, the compression and the
+    // maximum message size.
+    fn client_builder(&self) -> Result<GeyserGrpcBuilder> {
+        let x_token = self.refresh_x_token()?;
+        let mut builder = GeyserGrpcClient::build_from_shared(self.endpoint.clone())?
+            .x_token((!x_token.is_empty()).then_some(x_token))?
+            .tls_config(self.tls_config.clone())?
+            .max_decoding_message_size(self.max_message_size);
+        if let Some(compression) = self.compression {
+            builder = builder
+                .send_compressed(compression)
+                .accept_compressed(compression);
+        }
+        Ok(builder)
+    }
+
+    // The token for the next connection, re-read from its file if any, for the token to be rotated without a restart:
+    // the subscriptions in progress keep the token they were opened with until they drop. The previous token is kept
+    // when the file cannot be read.
+    fn refresh_x_token(&self) -> Result<String> {
+        let mut x_token = self
+            .x_token
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the Geyser token of {}: {}", self.name, e))?;
+        if let Some(path) = &self.x_token_path {
+            match read_x_token(path) {
+                Ok(read) if read != *x_token => {
+                    info!("Rotated the Geyser token of {}", self.name);
+                    *x_token = read;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Keeping the previous Geyser token of {}: {}", self.name, e),
+            }
+        }
+        Ok(x_token.clone())
+    }
+
+    /// Records the updates of the stream, the recorder being shared with the subscribers of the other endpoints.
+    pub fn with_recorder(mut self, recorder: Option<Arc<GeyserRecorder>>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Feeds the updates of the recording to the GeyserProcessor, at their recorded pace shortened by the speed
+    /// factor, as if they were streamed. The cache stays as of the last update once the recording is replayed.
+    fn replay(&self, replay_path: &Path) -> Result<()> {
+        info!(
+            "Replaying the Geyser updates of {} at the speed {}...",
+            replay_path.display(),
+            self.replay_speed
+        );
+        let mut oracles_generation = self.cache.oracles.generation();
+        let (mut oracle_addresses_bytes, _) = self.build_subscribe_requests()?;
+        let started_at = Instant::now();
+        let mut first_received_at_micros = None;
+        let mut replayed = 0;
+        for recorded in GeyserRecording::open(replay_path)? {
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let recorded = recorded?;
+            let delay = replay_delay(
+                *first_received_at_micros.get_or_insert(recorded.received_at_micros),
+                recorded.received_at_micros,
+                self.replay_speed,
+            );
+            sleep_unless_stopped(&self.stop, delay.saturating_sub(started_at.elapsed()));
+
+            // The Oracles of the Banks created during the recording are routed as they were subscribed.
+            let generation = self.cache.oracles.generation();
+            if generation != oracles_generation {
+                oracles_generation = generation;
+                oracle_addresses_bytes = self.build_subscribe_requests()?.0;
+            }
+            self.handle_update(&oracle_addresses_bytes, &recorded.update)?;
+            replayed += 1;
+        }
+        info!(
+            "Replayed {} Geyser updates of {} in {:?}.",
+            replayed,
+            replay_path.display(),
+            started_at.elapsed()
+        );
+
+        Ok(())
+    }
+
+    // Tracks the slots and the competitor liquidations of the update, and routes it to the GeyserProcessor.
+    fn handle_update(
+        &self,
+        oracle_addresses_bytes: &HashSet<[u8; 32]>,
+        event: &SubscribeUpdate,
+    ) -> Result<()> {
+        // The confirmed stream only reconciles the processed updates, which are the ones applied.
+        if let Some(rollback_after_slots) = self.rollback_after_slots {
+            if let Err(e) =
+                self.track_confirmations(oracle_addresses_bytes, rollback_after_slots, event)
+            {
+                error!(
+                    "Error tracking the confirmation of the Geyser update {:?}: {}",
+                    event, e
+                );
+            }
+            if is_confirmation(event) {
+                return Ok(());
+            }
+        }
+
+        if tracks_dead_slots(self.commitment, self.pricing_commitment) {
+            match self.track_dead_slots(oracle_addresses_bytes, event) {
+                Ok(true) => {
+                    trace!("Ignoring the Geyser update of a dead slot: {:?}", event);
+                    return Ok(());
+                }
+                Ok(false) => {}
+                Err(e) => error!(
+                    "Error tracking the slot of the Geyser update {:?}: {}",
+                    event, e
+                ),
+            }
+        }
+        if is_slot_status(event) {
+            return Ok(());
+        }
+
+        let marginfi_program_id_bytes = self.marginfi_program_id.to_bytes();
+        match &event.update_oneof {
+            Some(subscribe_update::UpdateOneof::Account(subscribe_account)) => {
+                self.streams.observe_slot(subscribe_account.slot)
+            }
+            Some(subscribe_update::UpdateOneof::Slot(subscribe_slot)) => {
+                self.streams.observe_slot(subscribe_slot.slot)
+            }
+            Some(subscribe_update::UpdateOneof::Transaction(subscribe_transaction)) => {
+                self.record_liquidations(&marginfi_program_id_bytes, subscribe_transaction);
+                self.record_priority_fee(subscribe_transaction);
+            }
+            Some(subscribe_update::UpdateOneof::BlockMeta(block_meta)) => {
+                if let Err(e) = self.cache.priority_fees.complete_block(block_meta.slot) {
+                    error!(
+                        "Failed to complete the priority fees of the block {}: {}",
+                        block_meta.slot, e
+                    );
+                }
+            }
+            _ => {}
+        }
+        match handle_event(
+            &marginfi_program_id_bytes,
+            oracle_addresses_bytes,
+            &self.liquidator.to_bytes(),
+            &self.cache.get_clock()?,
+            &self.geyser_tx,
+            event,
+        ) {
+            Ok(true) => self.cache.metrics.record_unknown_update(),
+            Ok(false) => {}
+            Err(e) => error!("Error handling Geyser update {:?}: {}", event, e),
+        }
+        Ok(())
+    }
+
+    // Tracks the processed Marginfi account, Bank and Oracle updates until the confirmed stream confirms them, and
+    // rolls back the ones the confirmed slot left behind.
+    fn track_confirmations(
+        &self,
+        oracle_addresses_bytes: &HashSet<[u8; 32]>,
+        rollback_after_slots: u64,
+        event: &SubscribeUpdate,
+    ) -> Result<()> {
+        let confirmations = &self.cache.confirmations;
+        match &event.update_oneof {
+            Some(subscribe_update::UpdateOneof::Account(subscribe_account)) => {
+                let Some(account) = &subscribe_account.account else {
+                    return Ok(());
+                };
+                let address = Pubkey::try_from(account.pubkey.as_slice())?;
+                if is_confirmation(event) {
+                    confirmations.confirm(&address, subscribe_account.slot)
+                } else if let Some(message_type) = processed_message_type(
+                    &self.marginfi_program_id.to_bytes(),
+                    oracle_addresses_bytes,
+                    account,
+                ) {
+                    confirmations.observe_processed(address, subscribe_account.slot, message_type)
+                } else {
+                    Ok(())
+                }
+            }
+            Some(subscribe_update::UpdateOneof::Slot(subscribe_slot)) if is_confirmation(event) => {
+                let rolled_back = confirmations
+                    .observe_confirmed_slot(subscribe_slot.slot, rollback_after_slots)?;
+                if rolled_back > 0 {
+                    warn!(
+                        "Rolling back {} processed updates not confirmed by the slot {}",
+                        rolled_back, subscribe_slot.slot
+                    );
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Records the processed account updates by slot, and rolls back the ones of the slots notified dead. Returns
+    // whether the update is an account update of a dead slot, e.g. streamed late by the other endpoint.
+    fn track_dead_slots(
+        &self,
+        oracle_addresses_bytes: &HashSet<[u8; 32]>,
+        event: &SubscribeUpdate,
+    ) -> Result<bool> {
+        let confirmations = &self.cache.confirmations;
+        match &event.update_oneof {
+            Some(subscribe_update::UpdateOneof::Account(subscribe_account)) => {
+                if confirmations.is_dead_slot(subscribe_account.slot)? {
+                    return Ok(true);
+                }
+                let Some(account) = &subscribe_account.account else {
+                    return Ok(false);
+                };
+                if let Some(message_type) = processed_message_type(
+                    &self.marginfi_program_id.to_bytes(),
+                    oracle_addresses_bytes,
+                    account,
+                ) {
+                    confirmations.observe_slot_update(
+                        Pubkey::try_from(account.pubkey.as_slice())?,
+                        subscribe_account.slot,
+                        message_type,
+                    )?;
+                }
+                Ok(false)
+            }
+            Some(subscribe_update::UpdateOneof::Slot(subscribe_slot))
+                if subscribe_slot.status == SlotStatus::SlotDead as i32 =>
+            {
+                let rolled_back = confirmations.observe_dead_slot(subscribe_slot.slot)?;
+                warn!(
+                    "The slot {} is dead ({}), rolling back its {} processed updates",
+                    subscribe_slot.slot,
+                    subscribe_slot.dead_error.as_deref().unwrap_or("no error"),
+                    rolled_back
+                );
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // The subscriptions of the cached Oracles, with their addresses for the routing of their updates.
+    fn build_subscribe_requests(&self) -> Result<(HashSet<[u8; 32]>, Vec<SubscribeRequest>)> {
         let oracle_addresses = self.cache.oracles.get_oracle_addresses();
+        let mut subscribe_reqs = build_geyser_subscribe_requests(
+            &self.marginfi_program_id,
+            &oracle_addresses,
+            &self.liquidator,
+            self.commitment,
+            self.pricing_commitment,
+        )?;
+        if self.rollback_after_slots.is_some() {
+            subscribe_reqs.push(build_geyser_confirmation_request(
+                &self.marginfi_program_id,
+                &oracle_addresses,
+            ));
+        }
+        Ok((
+            oracle_addresses.iter().map(|pk| pk.to_bytes()).collect(),
+            subscribe_reqs,
+        ))
+    }
+
+    // The liquidations of the competitors, the own ones being tracked by the LiquidationService.
+    fn record_liquidations(
+        &self,
+        marginfi_program_id_bytes: &[u8; 32],
+        subscribe_transaction: &SubscribeUpdateTransaction,
+    ) {
+        let liquidations =
+            match parse_liquidations(marginfi_program_id_bytes, subscribe_transaction) {
+                Ok(liquidations) => liquidations,
+                Err(e) => {
+                    error!(
+                        "Error parsing the liquidations of the Geyser transaction update {:?}: {}",
+                        subscribe_transaction, e
+                    );
+                    return;
+                }
+            };
+        for liquidation in liquidations {
+            if liquidation.liquidator == self.liquidator {
+                continue;
+            }
+            match self.cache.competitors.record(liquidation) {
+                Ok(true) => debug!(
+                    "{} liquidated {} at the slot {}, seizing {} from the Bank {} for the Bank {}",
+                    liquidation.liquidator,
+                    liquidation.liquidatee,
+                    liquidation.slot,
+                    liquidation.asset_amount,
+                    liquidation.asset_bank,
+                    liquidation.liab_bank
+                ),
+                Ok(false) => {}
+                Err(e) => error!(
+                    "Failed to record the liquidation {}: {}",
+                    liquidation.signature, e
+                ),
+            }
+        }
+    }
+
+    // The compute unit price of the Marginfi transaction, for the fee statistics of the blocks.
+    fn record_priority_fee(&self, subscribe_transaction: &SubscribeUpdateTransaction) {
+        let priority_fee = match parse_priority_fee(subscribe_transaction, |address| {
+            self.cache.banks.contains(address)
+        }) {
+            Ok(priority_fee) => priority_fee,
+            Err(e) => {
+                error!(
+                    "Error parsing the priority fee of the Geyser transaction update {:?}: {}",
+                    subscribe_transaction, e
+                );
+                return;
+            }
+        };
+        if let Some((signature, compute_unit_price)) = priority_fee {
+            if let Err(e) = self.cache.priority_fees.record_transaction(
+                subscribe_transaction.slot,
+                signature,
+                compute_unit_price,
+            ) {
+                error!("Failed to record the priority fee of {}: {}", signature, e);
+            }
+        }
+    }
+}
+
+impl GeyserBackend for GeyserSubscriber {
+    /// Fetches the Marginfi program accounts from the snapshot that the provider streams at subscription, for the
+    /// providers that have disabled getProgramAccounts. The snapshot accounts come as startup updates, and the
+    /// snapshot is complete at the first live update or once the stream goes idle.
+    fn fetch_program_accounts_snapshot(&self) -> Result<ProgramAccounts> {
+        info!("Fetching the Marginfi program accounts snapshot from Geyser...");
 
-        let subscribe_req =
-            build_geyser_subscribe_request(&self.marginfi_program_id, &oracle_addresses)?;
+        let mut client = self.tokio_rt.block_on(self.client_builder()?.connect())?;
+        let (_, mut stream) = self.tokio_rt.block_on(client.subscribe_with_request(Some(
+            build_geyser_snapshot_request(&self.marginfi_program_id),
+        )))?;
 
         let marginfi_program_id_bytes: [u8; 32] = self.marginfi_program_id.to_bytes();
-        let oracle_addresses_bytes: HashSet<[u8; 32]> =
-            oracle_addresses.iter().map(|pk| pk.to_bytes()).collect();
+        let mut snapshot = GeyserSnapshot::default();
+        loop {
+            if self.stop.load(Ordering::Relaxed) {
+                return Err(anyhow!("Stopped while fetching the Geyser snapshot"));
+            }
+
+            let next = self.tokio_rt.block_on(tokio::time::timeout(
+                self.bootstrap_idle_timeout,
+                stream.next(),
+            ));
+            match next {
+                Ok(Some(Ok(event))) => {
+                    if snapshot.add(&marginfi_program_id_bytes, &event)? {
+                        debug!("Received a live Geyser update, the snapshot is complete.");
+                        break;
+                    }
+                }
+                Ok(Some(Err(e))) => {
+                    return Err(anyhow!("Geyser failed while streaming the snapshot: {}", e))
+                }
+                Ok(None) => return Err(anyhow!("Geyser closed the stream of the snapshot")),
+                Err(_) => {
+                    debug!(
+                        "No Geyser update for {:?}, the snapshot is complete.",
+                        self.bootstrap_idle_timeout
+                    );
+                    break;
+                }
+            }
+        }
+
+        let program_accounts = snapshot.into_program_accounts()?;
+        info!(
+            "Fetched {} Marginfi program accounts from the Geyser snapshot at the slot {}",
+            program_accounts.accounts.len(),
+            program_accounts.context_slot
+        );
+        Ok(program_accounts)
+    }
+
+    fn run(&self) -> Result<()> {
+        if let Some(replay_path) = &self.replay_path {
+            return self.replay(replay_path);
+        }
 
-        info!("Entering the GeyserService loop.");
+        info!("Entering the GeyserService loop of {}.", self.name);
+        let mut backoff = RECONNECT_INITIAL_BACKOFF;
         while !self.stop.load(Ordering::Relaxed) {
-            info!("Connecting to Geyser...");
+            info!("Connecting to Geyser {}...", self.name);
+
+            let mut oracles_generation = self.cache.oracles.generation();
+            let (mut oracle_addresses_bytes, subscribe_reqs) = self.build_subscribe_requests()?;
+            let subscription = self.tokio_rt.block_on(async {
+                let mut client = self.client_builder()?.connect().await?;
+                // A stream per commitment, merged in their arrival order.
+                let mut sinks = vec![];
+                let mut streams = vec![];
+                for subscribe_req in subscribe_reqs {
+                    let (sink, stream) = client.subscribe_with_request(Some(subscribe_req)).await?;
+                    sinks.push(sink);
+                    streams.push(stream);
+                }
+                Ok::<_, anyhow::Error>((client, sinks, select_all(streams)))
+            });
+            let (_client, mut sinks, mut stream) = match subscription {
+                Ok(subscription) => subscription,
+                Err(e) => {
+                    warn!(
+                        "Failed to subscribe to Geyser {}, retrying in {:?}: {}",
+                        self.name, backoff, e
+                    );
+                    self.streams.failed(self.cache.get_clock()?.slot)?;
+                    sleep_unless_stopped(&self.stop, backoff);
+                    backoff = next_backoff(backoff, self.reconnect_max_backoff);
+                    continue;
+                }
+            };
+
+            if let Some(gap) = self.streams.subscribed()? {
+                info!(
+                    "Resubscribed to Geyser {} {:?} after the streams dropped at the slot {}",
+                    self.name, gap.resubscribed_after, gap.dropped_at_slot
+                );
+                // Nothing resyncs the cache when the CacheReconciler is disabled.
+                let _ = self.gaps.send(gap);
+            }
 
-            let mut client = self.tokio_rt.block_on(
-                GeyserGrpcClient::build_from_shared(self.endpoint.clone())?
-                    .x_token(Some(self.x_token.clone()))?
-                    .tls_config(self.tls_config.clone())?
-                    .connect(),
-            )?;
+            let mut keepalive =
+                GeyserKeepalive::new(self.ping_interval, self.stall_timeout, Instant::now());
+            loop {
+                // Breaking the loop on stop request
+                if self.stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                // A dead connection is reconnected at once rather than left to the TCP timeouts.
+                if let Some(reason) = keepalive.dead_reason(Instant::now()) {
+                    warn!("The Geyser connection of {} is dead: {}", self.name, reason);
+                    break;
+                }
+                if let Some(id) = keepalive.ping_due(Instant::now()) {
+                    let ping = SubscribeRequest {
+                        ping: Some(SubscribeRequestPing { id }),
+                        ..Default::default()
+                    };
+                    // The streams share the connection, pinging one of them probes it.
+                    if let Err(e) = self.tokio_rt.block_on(sinks[0].send(ping)) {
+                        warn!("Failed to ping Geyser {}, resubscribing: {}", self.name, e);
+                        break;
+                    }
+                }
 
-            let (_, mut stream) = self
-                .tokio_rt
-                .block_on(client.subscribe_with_request(Some(subscribe_req.clone())))?;
+                let next = self.tokio_rt.block_on(tokio::time::timeout(
+                    keepalive.until_next_check(Instant::now()),
+                    stream.next(),
+                ));
+                let msg = match next {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => break,
+                    Err(_) => continue,
+                };
+                // The Oracles of the new Banks, or of the Banks whose oracle changed, are subscribed at once.
+                let generation = self.cache.oracles.generation();
+                if generation != oracles_generation {
+                    oracles_generation = generation;
+                    let (addresses_bytes, subscribe_reqs) = self.build_subscribe_requests()?;
+                    let resubscribed = self.tokio_rt.block_on(async {
+                        for (sink, subscribe_req) in sinks.iter_mut().zip(subscribe_reqs) {
+                            sink.send(subscribe_req).await?;
+                        }
+                        Ok::<_, anyhow::Error>(())
+                    });
+                    if let Err(e) = resubscribed {
+                        warn!(
+                            "Failed to update the Geyser subscription of {}, resubscribing: {}",
+                            self.name, e
+                        );
+                        break;
+                    }
+                    info!(
+                        "Updated the Geyser subscription of {} to {} Oracles",
+                        self.name,
+                        addresses_bytes.len()
+                    );
+                    oracle_addresses_bytes = addresses_bytes;
+                }
 
-            while let Some(msg) = self.tokio_rt.block_on(stream.next()) {
                 match msg {
                     Ok(event) => {
-                        if let Err(e) = handle_event(
-                            &marginfi_program_id_bytes,
-                            &oracle_addresses_bytes,
-                            &self.cache.get_clock()?,
-                            &self.geyser_tx,
-                            &event,
-                        ) {
-                            error!("Error handling Geyser update {:?}: {}", event, e);
+                        // The stream is healthy again once it delivers.
+                        backoff = RECONNECT_INITIAL_BACKOFF;
+                        keepalive.received(Instant::now());
+                        match &event.update_oneof {
+                            // The pongs only prove the connection alive.
+                            Some(subscribe_update::UpdateOneof::Pong(pong)) => {
+                                keepalive.pong(pong.id)
+                            }
+                            _ => {
+                                if let Some(recorder) = &self.recorder {
+                                    if let Err(e) = recorder.record(&event) {
+                                        error!("Failed to record the Geyser update: {}", e);
+                                    }
+                                }
+                                self.handle_update(&oracle_addresses_bytes, &event)?;
+                            }
                         }
                     }
                     Err(e) => {
-                        error!("Received error from Geyser: {}", e);
+                        error!("Received error from Geyser {}: {}", self.name, e);
                         break;
                     }
                 }
+            }
 
-                // Breaking the loop on stop request
-                if self.stop.load(Ordering::Relaxed) {
-                    break;
-                }
+            let slot = self.cache.get_clock()?.slot;
+            self.streams.dropped(slot)?;
+            if !self.stop.load(Ordering::Relaxed) {
+                warn!(
+                    "The Geyser stream of {} dropped at the slot {}, reconnecting in {:?}",
+                    self.name, slot, backoff
+                );
+                sleep_unless_stopped(&self.stop, backoff);
+                backoff = next_backoff(backoff, self.reconnect_max_backoff);
             }
         }
-        info!("The GeyserService loop is stopped.");
+        info!("The GeyserService loop of {} is stopped.", self.name);
 
         Ok(())
     }
 }
 
-fn build_geyser_subscribe_request(
+pub(super) fn sleep_unless_stopped(stop: &AtomicBool, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !stop.load(Ordering::Relaxed) && Instant::now() < deadline {
+        thread::sleep(
+            deadline
+                .saturating_duration_since(Instant::now())
+                .min(Duration::from_millis(100)),
+        );
+    }
+}
+
+pub(super) fn next_backoff(backoff: Duration, max_backoff: Duration) -> Duration {
+    backoff.saturating_mul(2).min(max_backoff)
+}
+
+/// The liveness of a Geyser connection, pinged every interval. The connection is dead once it has received
+/// nothing, or no pong to the pending ping, for the timeout.
+struct GeyserKeepalive {
+    ping_interval: Option<Duration>,
+    timeout: Duration,
+    last_received: Instant,
+    last_ping: Instant,
+    next_ping_id: i32,
+    // The ping awaiting its pong, with when it was sent.
+    pending_ping: Option<(i32, Instant)>,
+}
+
+impl GeyserKeepalive {
+    fn new(ping_interval: Option<Duration>, timeout: Duration, now: Instant) -> Self {
+        Self {
+            ping_interval,
+            timeout,
+            last_received: now,
+            last_ping: now,
+            next_ping_id: 1,
+            pending_ping: None,
+        }
+    }
+
+    fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    fn pong(&mut self, id: i32) {
+        if self
+            .pending_ping
+            .is_some_and(|(pending_id, _)| pending_id == id)
+        {
+            self.pending_ping = None;
+        }
+    }
+
+    /// The id of the ping to send, if one is due and none is pending.
+    fn ping_due(&mut self, now: Instant) -> Option<i32> {
+        let ping_interval = self.ping_interval?;
+        if self.pending_ping.is_some() || now < self.last_ping + ping_interval {
+            return None;
+        }
+        let id = self.next_ping_id;
+        self.next_ping_id = self.next_ping_id.wrapping_add(1);
+        self.pending_ping = Some((id, now));
+        self.last_ping = now;
+        Some(id)
+    }
+
+    fn dead_reason(&self, now: Instant) -> Option<String> {
+        // The slots are notified several times a second, a silent stream has stalled.
+        if now.saturating_duration_since(self.last_received) >= self.timeout {
+            return Some(format!("no update for {:?}", self.timeout));
+        }
+        match self.pending_ping {
+            Some((id, sent_at)) if now.saturating_duration_since(sent_at) >= self.timeout => {
+                Some(format!("no pong to the ping {} for {:?}", id, self.timeout))
+            }
+            _ => None,
+        }
+    }
+
+    /// How long to wait for the next update before checking the connection again.
+    fn until_next_check(&self, now: Instant) -> Duration {
+        let mut next_check = self.last_received + self.timeout;
+        if let Some((_, sent_at)) = self.pending_ping {
+            next_check = next_check.min(sent_at + self.timeout);
+        } else if let Some(ping_interval) = self.ping_interval {
+            next_check = next_check.min(self.last_ping + ping_interval);
+        }
+        next_check.saturating_duration_since(now)
+    }
+}
+
+// The subscription of all the accounts at the commitment, unless the Oracles and the Banks are subscribed apart at
+// the pricing commitment. The Oracles are subscribed by address, an owner filter on their programs streaming all
+// their feeds.
+fn build_geyser_subscribe_requests(
     marginfi_program_id: &Pubkey,
     oracle_addresses: &[Pubkey],
-) -> Result<SubscribeRequest> {
+    liquidator: &Pubkey,
+    commitment: CommitmentLevel,
+    pricing_commitment: CommitmentLevel,
+) -> Result<Vec<SubscribeRequest>> {
     let mut account_filters: HashMap<String, SubscribeRequestFilterAccounts> = HashMap::new();
+    let mut pricing_account_filters: HashMap<String, SubscribeRequestFilterAccounts> =
+        HashMap::new();
 
+    // The clock is streamed with the Oracles, for their staleness to be checked against a timestamp as recent.
     let clock_filter = SubscribeRequestFilterAccounts {
         account: vec![sysvar::clock::id().to_string()],
         ..Default::default()
     };
-    account_filters.insert("SolanaClock".to_string(), clock_filter);
-
-    let marginfi_program_filter = SubscribeRequestFilterAccounts {
-        owner: vec![marginfi_program_id.to_string()],
-        ..Default::default()
-    };
-    account_filters.insert("MarginfiProgram".to_string(), marginfi_program_filter);
 
     let oracles = oracle_addresses
         .iter()
@@ -189,27 +1005,298 @@ fn build_geyser_subscribe_request(
         account: oracles,
         ..Default::default()
     };
-    account_filters.insert("Oracles".to_string(), oracle_filter);
 
-    Ok(SubscribeRequest {
+    let (marginfi_accounts_filter, banks_filter) = marginfi_program_filters(marginfi_program_id);
+    account_filters.insert("MarginfiAccounts".to_string(), marginfi_accounts_filter);
+    if pricing_commitment == commitment {
+        account_filters.insert("Banks".to_string(), banks_filter);
+        account_filters.insert("Oracles".to_string(), oracle_filter);
+        account_filters.insert("SolanaClock".to_string(), clock_filter);
+    } else {
+        pricing_account_filters.insert("Banks".to_string(), banks_filter);
+        pricing_account_filters.insert("Oracles".to_string(), oracle_filter);
+        pricing_account_filters.insert("SolanaClock".to_string(), clock_filter);
+    }
+
+    // The SPL Token and Token-2022 accounts of the liquidator wallet.
+    let wallet_token_accounts_filter = SubscribeRequestFilterAccounts {
+        owner: vec![
+            SPL_TOKEN_PROGRAM_ID.to_string(),
+            SPL_TOKEN_2022_PROGRAM_ID.to_string(),
+        ],
+        filters: vec![SubscribeRequestFilterAccountsFilter {
+            filter: Some(subscribe_request_filter_accounts_filter::Filter::Memcmp(
+                SubscribeRequestFilterAccountsFilterMemcmp {
+                    offset: TOKEN_ACCOUNT_OWNER_OFFSET as u64,
+                    data: Some(
+                        subscribe_request_filter_accounts_filter_memcmp::Data::Bytes(
+                            liquidator.to_bytes().to_vec(),
+                        ),
+                    ),
+                },
+            )),
+        }],
+        ..Default::default()
+    };
+    account_filters.insert(
+        "WalletTokenAccounts".to_string(),
+        wallet_token_accounts_filter,
+    );
+
+    // The slot notifications advance the cached clock between the clock account updates.
+    let slot_filter = SubscribeRequestFilterSlots {
+        filter_by_commitment: Some(true),
+        ..Default::default()
+    };
+
+    // The successful Marginfi transactions, for the liquidations of the competitors.
+    let marginfi_transactions_filter = SubscribeRequestFilterTransactions {
+        vote: Some(false),
+        failed: Some(false),
+        account_include: vec![marginfi_program_id.to_string()],
+        ..Default::default()
+    };
+
+    let mut slot_filters = HashMap::from([(SLOTS_FILTER.to_string(), slot_filter)]);
+    // The processed slots may die, skipped or on an abandoned fork.
+    if tracks_dead_slots(commitment, pricing_commitment) {
+        slot_filters.insert(
+            SLOT_STATUSES_FILTER.to_string(),
+            SubscribeRequestFilterSlots {
+                filter_by_commitment: Some(false),
+                interslot_updates: Some(true),
+            },
+        );
+    }
+
+    let mut subscribe_reqs = vec![SubscribeRequest {
         accounts: account_filters,
+        slots: slot_filters,
+        transactions: HashMap::from([(
+            "MarginfiTransactions".to_string(),
+            marginfi_transactions_filter,
+        )]),
+        // The block metas close the priority fees of their blocks.
+        blocks_meta: HashMap::from([(
+            "BlocksMeta".to_string(),
+            SubscribeRequestFilterBlocksMeta {},
+        )]),
+        commitment: Some(geyser_commitment(commitment) as i32),
+        ..Default::default()
+    }];
+    if !pricing_account_filters.is_empty() {
+        subscribe_reqs.push(SubscribeRequest {
+            accounts: pricing_account_filters,
+            commitment: Some(geyser_commitment(pricing_commitment) as i32),
+            ..Default::default()
+        });
+    }
+    Ok(subscribe_reqs)
+}
+
+// The confirmed stream of the accounts tracked from the processed one, with the confirmed slots to roll back the
+// updates it left behind.
+fn build_geyser_confirmation_request(
+    marginfi_program_id: &Pubkey,
+    oracle_addresses: &[Pubkey],
+) -> SubscribeRequest {
+    let (marginfi_accounts_filter, banks_filter) = marginfi_program_filters(marginfi_program_id);
+    let oracle_filter = SubscribeRequestFilterAccounts {
+        account: oracle_addresses.iter().map(|pk| pk.to_string()).collect(),
+        ..Default::default()
+    };
+    let slot_filter = SubscribeRequestFilterSlots {
+        filter_by_commitment: Some(true),
+        ..Default::default()
+    };
+
+    SubscribeRequest {
+        accounts: HashMap::from([
+            (
+                format!("{}MarginfiAccounts", CONFIRMED_FILTER_PREFIX),
+                marginfi_accounts_filter,
+            ),
+            (format!("{}Banks", CONFIRMED_FILTER_PREFIX), banks_filter),
+            (format!("{}Oracles", CONFIRMED_FILTER_PREFIX), oracle_filter),
+        ]),
+        slots: HashMap::from([(format!("{}Slots", CONFIRMED_FILTER_PREFIX), slot_filter)]),
+        commitment: Some(GeyserCommitmentLevel::Confirmed as i32),
+        ..Default::default()
+    }
+}
+
+fn is_confirmation(event: &SubscribeUpdate) -> bool {
+    event
+        .filters
+        .iter()
+        .any(|filter| filter.starts_with(CONFIRMED_FILTER_PREFIX))
+}
+
+// Whether an account stream is at the processed commitment, whose slots may die.
+fn tracks_dead_slots(commitment: CommitmentLevel, pricing_commitment: CommitmentLevel) -> bool {
+    commitment == CommitmentLevel::Processed || pricing_commitment == CommitmentLevel::Processed
+}
+
+// A slot status notification not at the commitment, which only tells the dead slots.
+fn is_slot_status(event: &SubscribeUpdate) -> bool {
+    matches!(
+        event.update_oneof,
+        Some(subscribe_update::UpdateOneof::Slot(_))
+    ) && !event.filters.iter().any(|filter| filter == SLOTS_FILTER)
+}
+
+// The type of the processed account update, if it is one rolled back when unconfirmed.
+fn processed_message_type(
+    marginfi_program_id_bytes: &[u8; 32],
+    oracle_addresses_bytes: &HashSet<[u8; 32]>,
+    account: &SubscribeUpdateAccountInfo,
+) -> Option<MessageType> {
+    if account.owner == marginfi_program_id_bytes {
+        get_marginfi_message_type(&account.data)
+    } else if oracle_addresses_bytes.contains(account.pubkey.as_slice()) {
+        Some(MessageType::Oracle)
+    } else {
+        None
+    }
+}
+
+fn discriminator_filter(discriminator: &[u8; 8]) -> SubscribeRequestFilterAccountsFilter {
+    SubscribeRequestFilterAccountsFilter {
+        filter: Some(subscribe_request_filter_accounts_filter::Filter::Memcmp(
+            SubscribeRequestFilterAccountsFilterMemcmp {
+                offset: 0,
+                data: Some(
+                    subscribe_request_filter_accounts_filter_memcmp::Data::Bytes(
+                        discriminator.to_vec(),
+                    ),
+                ),
+            },
+        )),
+    }
+}
+
+fn geyser_commitment(commitment: CommitmentLevel) -> GeyserCommitmentLevel {
+    match commitment {
+        CommitmentLevel::Processed => GeyserCommitmentLevel::Processed,
+        CommitmentLevel::Confirmed => GeyserCommitmentLevel::Confirmed,
+        CommitmentLevel::Finalized => GeyserCommitmentLevel::Finalized,
+    }
+}
+
+// Only the Marginfi accounts and the Banks are in the snapshot, the oracles and the clock are fetched separately.
+fn build_geyser_snapshot_request(marginfi_program_id: &Pubkey) -> SubscribeRequest {
+    let (marginfi_accounts_filter, banks_filter) = marginfi_program_filters(marginfi_program_id);
+
+    SubscribeRequest {
+        accounts: HashMap::from([
+            ("MarginfiAccounts".to_string(), marginfi_accounts_filter),
+            ("Banks".to_string(), banks_filter),
+        ]),
+        ..Default::default()
+    }
+}
+
+// The Marginfi accounts and the Banks, told apart by their discriminator, the other accounts of the program, e.g.
+// the groups, being of no use to the bot.
+fn marginfi_program_filters(
+    marginfi_program_id: &Pubkey,
+) -> (
+    SubscribeRequestFilterAccounts,
+    SubscribeRequestFilterAccounts,
+) {
+    let filter = |discriminator| SubscribeRequestFilterAccounts {
+        owner: vec![marginfi_program_id.to_string()],
+        filters: vec![discriminator_filter(discriminator)],
         ..Default::default()
-    })
+    };
+    (
+        filter(&MARGINFI_ACCOUNT_DISCRIMINATOR),
+        filter(&MARGINFI_BANK_DISCRIMINATOR),
+    )
+}
+
+/// The Marginfi program accounts received from the Geyser snapshot, with the latest update of each by slot
+/// and write version.
+#[derive(Default)]
+struct GeyserSnapshot {
+    accounts: HashMap<Pubkey, ((u64, u64), Account)>,
 }
 
-fn handle_event(
+impl GeyserSnapshot {
+    /// Adds the startup update of a Marginfi program account. Returns true at the first live update,
+    /// which means that the snapshot is complete.
+    fn add(
+        &mut self,
+        marginfi_program_id_bytes: &[u8; 32],
+        event: &SubscribeUpdate,
+    ) -> Result<bool> {
+        let Some(subscribe_update::UpdateOneof::Account(subscribe_account)) = &event.update_oneof
+        else {
+            return Ok(false);
+        };
+        if !subscribe_account.is_startup {
+            return Ok(true);
+        }
+
+        if let Some(account) = &subscribe_account.account {
+            if account.owner == marginfi_program_id_bytes {
+                if let Some(message_type) = get_marginfi_message_type(&account.data) {
+                    let msg =
+                        GeyserMessage::new(message_type, subscribe_account.slot, account.clone())?;
+                    match self.accounts.get(&msg.address) {
+                        Some((version, _)) if *version > (msg.slot, msg.write_version) => {}
+                        _ => {
+                            self.accounts
+                                .insert(msg.address, ((msg.slot, msg.write_version), msg.account));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // The snapshot is as of its latest slot.
+    fn into_program_accounts(self) -> Result<ProgramAccounts> {
+        let Some(context_slot) = self.accounts.values().map(|((slot, _), _)| *slot).max() else {
+            return Err(anyhow!(
+                "The Geyser snapshot has no Marginfi program accounts, the provider may not stream snapshots"
+            ));
+        };
+        Ok(ProgramAccounts {
+            context_slot,
+            accounts: self
+                .accounts
+                .into_iter()
+                .map(|(address, (_, account))| (address, account))
+                .collect(),
+        })
+    }
+}
+
+// Returns whether the update was an account update of no known type, which is not queued.
+pub(super) fn handle_event(
     marginfi_program_id_bytes: &[u8; 32],
     oracle_addresses_bytes: &HashSet<[u8; 32]>,
+    liquidator_bytes: &[u8; 32],
     clock: &Clock,
-    geyser_tx: &Sender<GeyserMessage>,
+    geyser_tx: &GeyserSender,
     event: &SubscribeUpdate,
-) -> Result<()> {
+) -> Result<bool> {
     match &event.update_oneof {
         Some(subscribe_update::UpdateOneof::Account(subscribe_account))
             if subscribe_account.slot >= clock.slot =>
         {
             if let Some(account) = &subscribe_account.account {
-                if account.owner == marginfi_program_id_bytes {
+                if is_closed_marginfi_account(marginfi_program_id_bytes, &event.filters, account) {
+                    trace!("Handling Marginfi account closure: {:?}", event);
+                    let msg = GeyserMessage::new(
+                        MessageType::ClosedAccount,
+                        subscribe_account.slot,
+                        account.clone(),
+                    )?;
+                    geyser_tx.send(msg)?;
+                } else if account.owner == marginfi_program_id_bytes {
                     trace!("Handling Marginfi update: {:?}", event);
                     if let Some(message_type) = get_marginfi_message_type(&account.data) {
                         let msg = GeyserMessage::new(
@@ -218,6 +1305,12 @@ fn handle_event(
                             account.clone(),
                         )?;
                         geyser_tx.send(msg)?;
+                    } else {
+                        trace!(
+                            "Ignoring update of unknown Marginfi account type: {:?}",
+                            event
+                        );
+                        return Ok(true);
                     }
                 } else if account.pubkey == SOLANA_CLOCK_BYTES {
                     trace!("Handling Solana clock update: {:?}", event);
@@ -235,32 +1328,211 @@ fn handle_event(
                         account.clone(),
                     )?;
                     geyser_tx.send(msg)?;
+                } else if is_wallet_token_account(liquidator_bytes, account) {
+                    trace!("Handling wallet token account update: {:?}", event);
+                    let msg = GeyserMessage::new(
+                        MessageType::TokenAccount,
+                        subscribe_account.slot,
+                        account.clone(),
+                    )?;
+                    geyser_tx.send(msg)?;
                 } else {
                     trace!("Ignoring update for unrecognized account: {:?}", event);
+                    return Ok(true);
                 }
             }
         }
+        Some(subscribe_update::UpdateOneof::Slot(subscribe_slot))
+            if subscribe_slot.slot > clock.slot =>
+        {
+            trace!("Handling slot update: {:?}", event);
+            geyser_tx.send(GeyserMessage::new_slot(subscribe_slot.slot))?;
+        }
         _ => {
             trace!("Handling Geyser update: {:?}", event);
         }
     }
 
-    Ok(())
+    Ok(false)
+}
+
+// A closed Marginfi account is drained of its lamports and reassigned, the closing update still matching the
+// Marginfi filters it was subscribed with.
+fn is_closed_marginfi_account(
+    marginfi_program_id_bytes: &[u8; 32],
+    filters: &[String],
+    account: &SubscribeUpdateAccountInfo,
+) -> bool {
+    if account.owner == marginfi_program_id_bytes {
+        account.lamports == 0
+    } else {
+        filters
+            .iter()
+            .any(|filter| MARGINFI_ACCOUNT_FILTERS.contains(&filter.as_str()))
+    }
+}
+
+/// The liquidations of a Marginfi transaction, its inner instructions included for the liquidations made by
+/// another program.
+fn parse_liquidations(
+    marginfi_program_id_bytes: &[u8; 32],
+    subscribe_transaction: &SubscribeUpdateTransaction,
+) -> Result<Vec<ObservedLiquidation>> {
+    let Some(info) = &subscribe_transaction.transaction else {
+        return Ok(vec![]);
+    };
+    let (Some(message), Some(meta)) = (
+        info.transaction
+            .as_ref()
+            .and_then(|transaction| transaction.message.as_ref()),
+        &info.meta,
+    ) else {
+        return Ok(vec![]);
+    };
+    // The static keys, then the ones loaded from the LUTs, the writable ones first.
+    let account_keys: Vec<&[u8]> = message
+        .account_keys
+        .iter()
+        .chain(&meta.loaded_writable_addresses)
+        .chain(&meta.loaded_readonly_addresses)
+        .map(Vec::as_slice)
+        .collect();
+    let instructions = message
+        .instructions
+        .iter()
+        .map(|ix| (ix.program_id_index, &ix.accounts, &ix.data))
+        .chain(
+            meta.inner_instructions
+                .iter()
+                .flat_map(|inner| &inner.instructions)
+                .map(|ix| (ix.program_id_index, &ix.accounts, &ix.data)),
+        );
+
+    let mut liquidations = vec![];
+    for (program_id_index, accounts, data) in instructions {
+        if account_keys.get(program_id_index as usize).copied()
+            != Some(&marginfi_program_id_bytes[..])
+            || !data.starts_with(&MARGINFI_LIQUIDATE_DISCRIMINATOR)
+        {
+            continue;
+        }
+        let account = |position: usize| {
+            accounts
+                .get(position)
+                .and_then(|index| account_keys.get(*index as usize))
+                .and_then(|key| Pubkey::try_from(*key).ok())
+                .ok_or_else(|| anyhow!("Invalid account {} of the liquidation", position))
+        };
+        let asset_amount = data
+            .get(MARGINFI_LIQUIDATE_DISCRIMINATOR.len()..MARGINFI_LIQUIDATE_DISCRIMINATOR.len() + 8)
+            .and_then(|amount| amount.try_into().ok())
+            .map(u64::from_le_bytes)
+            .ok_or_else(|| anyhow!("Invalid asset amount of the liquidation"))?;
+        liquidations.push(ObservedLiquidation {
+            signature: Signature::try_from(info.signature.as_slice())
+                .map_err(|err| anyhow!("Invalid signature of the transaction: {:?}", err))?,
+            slot: subscribe_transaction.slot,
+            liquidator: account(LIQUIDATE_AUTHORITY_INDEX)?,
+            liquidatee: account(LIQUIDATE_LIQUIDATEE_INDEX)?,
+            asset_bank: account(LIQUIDATE_ASSET_BANK_INDEX)?,
+            liab_bank: account(LIQUIDATE_LIAB_BANK_INDEX)?,
+            asset_amount,
+        });
+    }
+    Ok(liquidations)
+}
+
+/// The signature and the compute unit price of a Marginfi transaction touching a Bank, none when it touches none. A
+/// transaction without a SetComputeUnitPrice instruction paid no priority fee.
+fn parse_priority_fee(
+    subscribe_transaction: &SubscribeUpdateTransaction,
+    is_bank: impl Fn(&Pubkey) -> Result<bool>,
+) -> Result<Option<(Signature, u64)>> {
+    let Some(info) = &subscribe_transaction.transaction else {
+        return Ok(None);
+    };
+    let Some(message) = info
+        .transaction
+        .as_ref()
+        .and_then(|transaction| transaction.message.as_ref())
+    else {
+        return Ok(None);
+    };
+    let loaded_keys = info.meta.iter().flat_map(|meta| {
+        meta.loaded_writable_addresses
+            .iter()
+            .chain(&meta.loaded_readonly_addresses)
+    });
+    let mut touches_bank = false;
+    for key in message.account_keys.iter().chain(loaded_keys) {
+        if let Ok(address) = Pubkey::try_from(key.as_slice()) {
+            if is_bank(&address)? {
+                touches_bank = true;
+                break;
+            }
+        }
+    }
+    if !touches_bank {
+        return Ok(None);
+    }
+
+    // The compute budget instructions are top level, and their program is a static key.
+    let compute_unit_price = message
+        .instructions
+        .iter()
+        .filter(|ix| {
+            message
+                .account_keys
+                .get(ix.program_id_index as usize)
+                .is_some_and(|key| key.as_slice() == COMPUTE_BUDGET_PROGRAM_ID_BYTES)
+        })
+        .find_map(|ix| match ix.data.split_first() {
+            Some((&SET_COMPUTE_UNIT_PRICE_TAG, price)) => price
+                .get(..8)
+                .and_then(|price| price.try_into().ok())
+                .map(u64::from_le_bytes),
+            _ => None,
+        })
+        .unwrap_or_default();
+    let signature = Signature::try_from(info.signature.as_slice())
+        .map_err(|err| anyhow!("Invalid signature of the transaction: {:?}", err))?;
+    Ok(Some((signature, compute_unit_price)))
+}
+
+fn is_wallet_token_account(
+    liquidator_bytes: &[u8; 32],
+    account: &SubscribeUpdateAccountInfo,
+) -> bool {
+    (account.owner == SPL_TOKEN_PROGRAM_ID_BYTES
+        || account.owner == SPL_TOKEN_2022_PROGRAM_ID_BYTES)
+        && account
+            .data
+            .get(TOKEN_ACCOUNT_OWNER_OFFSET..TOKEN_ACCOUNT_OWNER_OFFSET + 32)
+            .is_some_and(|owner| owner == liquidator_bytes)
 }
 
 #[cfg(test)]
 mod tests {
-    use crossbeam::channel;
-    use yellowstone_grpc_proto::geyser::SubscribeUpdateAccount;
+    use yellowstone_grpc_proto::{
+        geyser::{SubscribeUpdateAccount, SubscribeUpdateSlot, SubscribeUpdateTransactionInfo},
+        prelude::{
+            CompiledInstruction, InnerInstruction, InnerInstructions, Message, Transaction,
+            TransactionStatusMeta,
+        },
+    };
 
     use crate::{
         cache::test_util::generate_test_clock,
-        common::{MARGINFI_ACCOUNT_DISCRIMINATOR, MARGINFI_ACCOUNT_DISCRIMINATOR_LEN},
+        common::MARGINFI_ACCOUNT_DISCRIMINATOR_LEN,
+        config::test_util::create_dummy_config,
+        liquidation::wallet::test_util::create_token_account,
+        service::geyser_channel::{geyser_channel, OverflowPolicy},
     };
 
     use super::*;
 
     static MARGINFI_PROGRAM_ID_BYTES: [u8; 32] = [1u8; 32];
+    static LIQUIDATOR_BYTES: [u8; 32] = [2u8; 32];
 
     fn make_account_info(pubkey: Pubkey, data: Vec<u8>) -> SubscribeUpdateAccountInfo {
         SubscribeUpdateAccountInfo {
@@ -275,9 +1547,90 @@ mod tests {
         }
     }
 
+    fn make_marginfi_account_event(
+        address: Pubkey,
+        slot: u64,
+        lamports: u64,
+        is_startup: bool,
+    ) -> SubscribeUpdate {
+        let mut data = vec![0; MARGINFI_ACCOUNT_DISCRIMINATOR_LEN + 1];
+        data[..MARGINFI_ACCOUNT_DISCRIMINATOR_LEN].copy_from_slice(&MARGINFI_ACCOUNT_DISCRIMINATOR);
+        let mut account_info = make_account_info(address, data);
+        account_info.owner = MARGINFI_PROGRAM_ID_BYTES.to_vec();
+        account_info.lamports = lamports;
+
+        SubscribeUpdate {
+            update_oneof: Some(subscribe_update::UpdateOneof::Account(
+                SubscribeUpdateAccount {
+                    slot,
+                    account: Some(account_info),
+                    is_startup,
+                },
+            )),
+            ..Default::default()
+        }
+    }
+
+    fn make_account_event_with_owner(owner: Pubkey, is_startup: bool) -> SubscribeUpdate {
+        let mut account_info = make_account_info(Pubkey::new_unique(), vec![]);
+        account_info.owner = owner.to_bytes().to_vec();
+        SubscribeUpdate {
+            update_oneof: Some(subscribe_update::UpdateOneof::Account(
+                SubscribeUpdateAccount {
+                    slot: 10,
+                    account: Some(account_info),
+                    is_startup,
+                },
+            )),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_geyser_snapshot() {
+        let mut snapshot = GeyserSnapshot::default();
+        let address = Pubkey::new_unique();
+        let other_address = Pubkey::new_unique();
+
+        for event in [
+            make_marginfi_account_event(address, 12, 2, true),
+            make_marginfi_account_event(address, 11, 1, true),
+            make_marginfi_account_event(other_address, 10, 3, true),
+            make_account_event_with_owner(Pubkey::new_unique(), true),
+            SubscribeUpdate::default(),
+        ] {
+            assert!(!snapshot.add(&MARGINFI_PROGRAM_ID_BYTES, &event).unwrap());
+        }
+        assert!(snapshot
+            .add(
+                &MARGINFI_PROGRAM_ID_BYTES,
+                &make_marginfi_account_event(address, 13, 4, false)
+            )
+            .unwrap());
+
+        let program_accounts = snapshot.into_program_accounts().unwrap();
+        assert_eq!(program_accounts.context_slot, 12);
+        let accounts: HashMap<Pubkey, Account> = program_accounts.accounts.into_iter().collect();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[&address].lamports, 2);
+        assert_eq!(accounts[&other_address].lamports, 3);
+    }
+
+    #[test]
+    fn test_geyser_snapshot_empty() {
+        let mut snapshot = GeyserSnapshot::default();
+        assert!(snapshot
+            .add(
+                &MARGINFI_PROGRAM_ID_BYTES,
+                &make_marginfi_account_event(Pubkey::new_unique(), 10, 1, false)
+            )
+            .unwrap());
+        assert!(snapshot.into_program_accounts().is_err());
+    }
+
     #[test]
     fn test_handle_event_clock_update() {
-        let (tx, rx) = channel::unbounded();
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
         let clock = generate_test_clock(1);
 
         let account_info = make_account_info(sysvar::clock::id(), vec![]);
@@ -296,11 +1649,12 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
+            &LIQUIDATOR_BYTES,
             &clock,
             &tx,
             &event,
         );
-        assert!(result.is_ok());
+        assert!(!result.unwrap());
 
         // Should have sent a message
         let msg = rx.try_recv().expect("Should have received a message");
@@ -310,9 +1664,198 @@ mod tests {
         assert_eq!(msg.account.lamports, 42);
     }
 
+    #[test]
+    fn test_handle_event_slot_update() {
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
+        let clock = generate_test_clock(10);
+        let slot_event = |slot| SubscribeUpdate {
+            update_oneof: Some(subscribe_update::UpdateOneof::Slot(SubscribeUpdateSlot {
+                slot,
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+
+        for slot in [9, 10, 11] {
+            handle_event(
+                &MARGINFI_PROGRAM_ID_BYTES,
+                &HashSet::new(),
+                &LIQUIDATOR_BYTES,
+                &clock,
+                &tx,
+                &slot_event(slot),
+            )
+            .unwrap();
+        }
+
+        // Only the slots past the cached clock.
+        let msg = rx.try_recv().expect("Should have received a message");
+        assert!(matches!(msg.message_type, MessageType::Slot));
+        assert_eq!(msg.slot, 11);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_parse_liquidations() {
+        let keys: Vec<Pubkey> = (0..6).map(|_| Pubkey::new_unique()).collect();
+        let signature = Signature::new_unique();
+        let liquidate_data = |amount: u64| {
+            let mut data = MARGINFI_LIQUIDATE_DISCRIMINATOR.to_vec();
+            data.extend_from_slice(&amount.to_le_bytes());
+            data
+        };
+        // The liquidatee and the Marginfi program are loaded from a LUT.
+        let marginfi_program_index = 6;
+        let account_keys: Vec<Vec<u8>> = keys[..5]
+            .iter()
+            .map(|key| key.to_bytes().to_vec())
+            .collect();
+        let subscribe_transaction = SubscribeUpdateTransaction {
+            transaction: Some(SubscribeUpdateTransactionInfo {
+                signature: signature.as_ref().to_vec(),
+                transaction: Some(Transaction {
+                    message: Some(Message {
+                        account_keys,
+                        instructions: vec![
+                            CompiledInstruction {
+                                program_id_index: marginfi_program_index,
+                                accounts: vec![0, 1, 2, 3, 4, 5],
+                                data: liquidate_data(100),
+                            },
+                            // Another Marginfi instruction.
+                            CompiledInstruction {
+                                program_id_index: marginfi_program_index,
+                                accounts: vec![0, 1],
+                                data: vec![1, 2, 3],
+                            },
+                        ],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                meta: Some(TransactionStatusMeta {
+                    loaded_writable_addresses: vec![keys[5].to_bytes().to_vec()],
+                    loaded_readonly_addresses: vec![MARGINFI_PROGRAM_ID_BYTES.to_vec()],
+                    inner_instructions: vec![InnerInstructions {
+                        index: 1,
+                        instructions: vec![InnerInstruction {
+                            program_id_index: marginfi_program_index,
+                            accounts: vec![0, 2, 1, 3, 4, 5],
+                            data: liquidate_data(200),
+                            stack_height: Some(2),
+                        }],
+                    }],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            slot: 42,
+        };
+
+        let liquidations =
+            parse_liquidations(&MARGINFI_PROGRAM_ID_BYTES, &subscribe_transaction).unwrap();
+        assert_eq!(
+            liquidations,
+            vec![
+                ObservedLiquidation {
+                    signature,
+                    slot: 42,
+                    liquidator: keys[4],
+                    liquidatee: keys[5],
+                    asset_bank: keys[1],
+                    liab_bank: keys[2],
+                    asset_amount: 100,
+                },
+                ObservedLiquidation {
+                    signature,
+                    slot: 42,
+                    liquidator: keys[4],
+                    liquidatee: keys[5],
+                    asset_bank: keys[2],
+                    liab_bank: keys[1],
+                    asset_amount: 200,
+                },
+            ]
+        );
+        assert!(parse_liquidations(
+            &MARGINFI_PROGRAM_ID_BYTES,
+            &SubscribeUpdateTransaction::default()
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[test]
+    fn test_parse_priority_fee() {
+        let (payer, bank) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let signature = Signature::new_unique();
+        let mut compute_unit_price = vec![SET_COMPUTE_UNIT_PRICE_TAG];
+        compute_unit_price.extend_from_slice(&25_000u64.to_le_bytes());
+        let transaction = |instructions: Vec<CompiledInstruction>| SubscribeUpdateTransaction {
+            transaction: Some(SubscribeUpdateTransactionInfo {
+                signature: signature.as_ref().to_vec(),
+                transaction: Some(Transaction {
+                    message: Some(Message {
+                        account_keys: vec![
+                            payer.to_bytes().to_vec(),
+                            COMPUTE_BUDGET_PROGRAM_ID_BYTES.to_vec(),
+                            MARGINFI_PROGRAM_ID_BYTES.to_vec(),
+                        ],
+                        instructions,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                // The Bank is loaded from a LUT.
+                meta: Some(TransactionStatusMeta {
+                    loaded_writable_addresses: vec![bank.to_bytes().to_vec()],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            slot: 42,
+        };
+        let is_bank = |address: &Pubkey| Ok(*address == bank);
+
+        let priced = transaction(vec![
+            // The SetComputeUnitLimit instruction.
+            CompiledInstruction {
+                program_id_index: 1,
+                accounts: vec![],
+                data: vec![2, 0, 0, 0, 0],
+            },
+            CompiledInstruction {
+                program_id_index: 1,
+                accounts: vec![],
+                data: compute_unit_price,
+            },
+            CompiledInstruction {
+                program_id_index: 2,
+                accounts: vec![0, 3],
+                data: vec![1, 2, 3],
+            },
+        ]);
+        assert_eq!(
+            parse_priority_fee(&priced, is_bank).unwrap(),
+            Some((signature, 25_000))
+        );
+        assert_eq!(
+            parse_priority_fee(&priced, |_: &Pubkey| Ok(false)).unwrap(),
+            None
+        );
+        assert_eq!(
+            parse_priority_fee(&transaction(vec![]), is_bank).unwrap(),
+            Some((signature, 0))
+        );
+        assert_eq!(
+            parse_priority_fee(&SubscribeUpdateTransaction::default(), is_bank).unwrap(),
+            None
+        );
+    }
+
     #[test]
     fn test_handle_event_non_clock_account() {
-        let (tx, rx) = channel::unbounded();
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
         let clock = generate_test_clock(1);
 
         let random_pubkey = Pubkey::new_unique();
@@ -331,6 +1874,7 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
+            &LIQUIDATOR_BYTES,
             &clock,
             &tx,
             &event,
@@ -343,7 +1887,7 @@ mod tests {
 
     #[test]
     fn test_handle_event_slot_too_low() {
-        let (tx, rx) = channel::unbounded();
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
         let clock = generate_test_clock(2);
 
         let account_info = make_account_info(sysvar::clock::id(), vec![]);
@@ -362,6 +1906,7 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
+            &LIQUIDATOR_BYTES,
             &clock,
             &tx,
             &event,
@@ -375,7 +1920,7 @@ mod tests {
     #[test]
     fn test_handle_event_no_account() {
         let clock = generate_test_clock(1);
-        let (tx, rx) = channel::unbounded();
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
         let subscribe_account = SubscribeUpdateAccount {
             slot: 10,
             account: None,
@@ -390,6 +1935,7 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
+            &LIQUIDATOR_BYTES,
             &clock,
             &tx,
             &event,
@@ -402,7 +1948,7 @@ mod tests {
 
     #[test]
     fn test_handle_event_other_update_type() {
-        let (tx, rx) = channel::unbounded();
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
         let clock = generate_test_clock(1);
         let event = SubscribeUpdate {
             update_oneof: None,
@@ -412,6 +1958,7 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
+            &LIQUIDATOR_BYTES,
             &clock,
             &tx,
             &event,
@@ -424,7 +1971,7 @@ mod tests {
 
     #[test]
     fn test_handle_event_marginfi_account_update() {
-        let (tx, rx) = channel::unbounded();
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
         let clock = generate_test_clock(1);
 
         // Use a pubkey that matches the marginfi_program_id
@@ -452,6 +1999,7 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
+            &LIQUIDATOR_BYTES,
             &clock,
             &tx,
             &event,
@@ -468,7 +2016,7 @@ mod tests {
 
     #[test]
     fn test_handle_event_oracle_account_update() {
-        let (tx, rx) = channel::unbounded();
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
         let clock = generate_test_clock(1);
 
         let oracle_pubkey = Pubkey::new_unique();
@@ -490,7 +2038,14 @@ mod tests {
             ..Default::default()
         };
 
-        let result = handle_event(&MARGINFI_PROGRAM_ID_BYTES, &oracle_set, &clock, &tx, &event);
+        let result = handle_event(
+            &MARGINFI_PROGRAM_ID_BYTES,
+            &oracle_set,
+            &LIQUIDATOR_BYTES,
+            &clock,
+            &tx,
+            &event,
+        );
         assert!(result.is_ok());
 
         // Should have sent a message
@@ -500,9 +2055,46 @@ mod tests {
         assert_eq!(msg.address, oracle_pubkey);
     }
 
+    #[test]
+    fn test_handle_event_closed_marginfi_account() {
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
+        let clock = generate_test_clock(1);
+        let handle = |event: &SubscribeUpdate| {
+            handle_event(
+                &MARGINFI_PROGRAM_ID_BYTES,
+                &HashSet::new(),
+                &LIQUIDATOR_BYTES,
+                &clock,
+                &tx,
+                event,
+            )
+            .unwrap()
+        };
+
+        // Drained of its lamports.
+        let drained = Pubkey::new_unique();
+        handle(&make_marginfi_account_event(drained, 10, 0, false));
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.message_type, MessageType::ClosedAccount);
+        assert_eq!((msg.address, msg.slot), (drained, 10));
+
+        // Reassigned to the system program, as streamed for the Marginfi filter.
+        let mut event = make_account_event_with_owner(Pubkey::default(), false);
+        event.filters = vec!["MarginfiAccounts".to_string()];
+        handle(&event);
+        assert_eq!(
+            rx.try_recv().unwrap().message_type,
+            MessageType::ClosedAccount
+        );
+        // Not for another filter.
+        event.filters = vec!["Oracles".to_string()];
+        handle(&event);
+        assert!(rx.try_recv().is_err());
+    }
+
     #[test]
     fn test_handle_event_ignores_unrecognized_account() {
-        let (tx, rx) = channel::unbounded();
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
         let clock = generate_test_clock(1);
 
         let random_pubkey = Pubkey::new_unique();
@@ -525,13 +2117,349 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
+            &LIQUIDATOR_BYTES,
             &clock,
             &tx,
             &event,
         );
-        assert!(result.is_ok());
+        assert!(result.unwrap());
 
         // Should NOT have sent a message
         assert!(rx.try_recv().is_err());
     }
+
+    #[test]
+    fn test_handle_event_wallet_token_account_update() {
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
+        let clock = generate_test_clock(1);
+
+        let token_account = Pubkey::new_unique();
+        let account = create_token_account(
+            Pubkey::new_unique(),
+            Pubkey::new_from_array(LIQUIDATOR_BYTES),
+            100,
+        );
+        let mut account_info = make_account_info(token_account, account.data);
+        account_info.owner = SPL_TOKEN_PROGRAM_ID.to_bytes().to_vec();
+        let mut other_account_info = account_info.clone();
+        other_account_info.data[TOKEN_ACCOUNT_OWNER_OFFSET..TOKEN_ACCOUNT_OWNER_OFFSET + 32]
+            .copy_from_slice(&Pubkey::new_unique().to_bytes());
+
+        for account_info in [account_info, other_account_info] {
+            let event = SubscribeUpdate {
+                update_oneof: Some(subscribe_update::UpdateOneof::Account(
+                    SubscribeUpdateAccount {
+                        slot: 10,
+                        account: Some(account_info),
+                        is_startup: false,
+                    },
+                )),
+                ..Default::default()
+            };
+            handle_event(
+                &MARGINFI_PROGRAM_ID_BYTES,
+                &HashSet::new(),
+                &LIQUIDATOR_BYTES,
+                &clock,
+                &tx,
+                &event,
+            )
+            .unwrap();
+        }
+
+        // Only the token account of the liquidator is handled.
+        let msg = rx.try_recv().expect("Should have received a message");
+        assert!(matches!(msg.message_type, MessageType::TokenAccount));
+        assert_eq!(msg.address, token_account);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_build_geyser_subscribe_requests() {
+        let (marginfi_program_id, liquidator) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let oracles = vec![Pubkey::new_unique()];
+
+        let subscribe_reqs = build_geyser_subscribe_requests(
+            &marginfi_program_id,
+            &oracles,
+            &liquidator,
+            CommitmentLevel::Confirmed,
+            CommitmentLevel::Confirmed,
+        )
+        .unwrap();
+        assert_eq!(subscribe_reqs.len(), 1);
+        assert_eq!(
+            subscribe_reqs[0].commitment,
+            Some(GeyserCommitmentLevel::Confirmed as i32)
+        );
+        let mut accounts: Vec<&String> = subscribe_reqs[0].accounts.keys().collect();
+        accounts.sort();
+        assert_eq!(
+            accounts,
+            vec![
+                "Banks",
+                "MarginfiAccounts",
+                "Oracles",
+                "SolanaClock",
+                "WalletTokenAccounts"
+            ]
+        );
+        assert_eq!(
+            subscribe_reqs[0].accounts["Banks"].filters,
+            vec![discriminator_filter(&MARGINFI_BANK_DISCRIMINATOR)]
+        );
+        assert!(!subscribe_reqs[0].slots.contains_key(SLOT_STATUSES_FILTER));
+        assert_eq!(
+            subscribe_reqs[0].accounts["Oracles"].account,
+            vec![oracles[0].to_string()]
+        );
+
+        // The Oracles and the Banks are streamed apart at the pricing commitment.
+        let subscribe_reqs = build_geyser_subscribe_requests(
+            &marginfi_program_id,
+            &oracles,
+            &liquidator,
+            CommitmentLevel::Confirmed,
+            CommitmentLevel::Processed,
+        )
+        .unwrap();
+        assert_eq!(subscribe_reqs.len(), 2);
+        let (subscribe_req, pricing_req) = (&subscribe_reqs[0], &subscribe_reqs[1]);
+        assert_eq!(
+            subscribe_req.commitment,
+            Some(GeyserCommitmentLevel::Confirmed as i32)
+        );
+        let mut accounts: Vec<&String> = subscribe_req.accounts.keys().collect();
+        accounts.sort();
+        assert_eq!(accounts, vec!["MarginfiAccounts", "WalletTokenAccounts"]);
+        assert_eq!(
+            subscribe_req.accounts["MarginfiAccounts"].filters,
+            vec![discriminator_filter(&MARGINFI_ACCOUNT_DISCRIMINATOR)]
+        );
+        assert!(subscribe_req.slots.contains_key("Slots"));
+        // The pricing stream is processed.
+        assert_eq!(
+            subscribe_req.slots[SLOT_STATUSES_FILTER].interslot_updates,
+            Some(true)
+        );
+        assert!(subscribe_req.blocks_meta.contains_key("BlocksMeta"));
+
+        assert_eq!(
+            pricing_req.commitment,
+            Some(GeyserCommitmentLevel::Processed as i32)
+        );
+        assert_eq!(
+            pricing_req.accounts["Banks"].filters,
+            vec![discriminator_filter(&MARGINFI_BANK_DISCRIMINATOR)]
+        );
+        assert_eq!(
+            pricing_req.accounts["Oracles"].account,
+            vec![oracles[0].to_string()]
+        );
+        assert_eq!(
+            pricing_req.accounts["SolanaClock"].account,
+            vec![sysvar::clock::id().to_string()]
+        );
+        assert!(pricing_req.slots.is_empty() && pricing_req.transactions.is_empty());
+        assert!(pricing_req.blocks_meta.is_empty());
+    }
+
+    #[test]
+    fn test_build_geyser_confirmation_request() {
+        let marginfi_program_id = Pubkey::new_unique();
+        let oracles = vec![Pubkey::new_unique()];
+
+        let confirmation_req = build_geyser_confirmation_request(&marginfi_program_id, &oracles);
+        assert_eq!(
+            confirmation_req.commitment,
+            Some(GeyserCommitmentLevel::Confirmed as i32)
+        );
+        assert_eq!(
+            confirmation_req.accounts["ConfirmedMarginfiAccounts"].owner,
+            vec![marginfi_program_id.to_string()]
+        );
+        assert_eq!(
+            confirmation_req.accounts["ConfirmedBanks"].filters,
+            vec![discriminator_filter(&MARGINFI_BANK_DISCRIMINATOR)]
+        );
+        assert_eq!(
+            confirmation_req.accounts["ConfirmedOracles"].account,
+            vec![oracles[0].to_string()]
+        );
+        assert!(confirmation_req.slots.contains_key("ConfirmedSlots"));
+        assert!(confirmation_req.transactions.is_empty());
+    }
+
+    #[test]
+    fn test_processed_confirmations() {
+        let marginfi_account = Pubkey::new_unique();
+        let mut event = make_marginfi_account_event(marginfi_account, 10, 1, false);
+        event.filters = vec!["MarginfiAccounts".to_string()];
+        assert!(!is_confirmation(&event));
+        event.filters = vec!["ConfirmedMarginfiAccounts".to_string()];
+        assert!(is_confirmation(&event));
+
+        let oracle = Pubkey::new_unique();
+        let oracle_addresses_bytes = HashSet::from([oracle.to_bytes()]);
+        let processed_type = |account: &SubscribeUpdateAccountInfo| {
+            processed_message_type(&MARGINFI_PROGRAM_ID_BYTES, &oracle_addresses_bytes, account)
+        };
+        let Some(subscribe_update::UpdateOneof::Account(subscribe_account)) = &event.update_oneof
+        else {
+            panic!("Expected an account update");
+        };
+        assert_eq!(
+            processed_type(subscribe_account.account.as_ref().unwrap()),
+            Some(MessageType::MarginfiAccount)
+        );
+        assert_eq!(
+            processed_type(&make_account_info(oracle, vec![])),
+            Some(MessageType::Oracle)
+        );
+        // The clock and the wallet token accounts are not rolled back.
+        assert_eq!(
+            processed_type(&make_account_info(sysvar::clock::id(), vec![])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_slot_status() {
+        let mut event = SubscribeUpdate {
+            update_oneof: Some(subscribe_update::UpdateOneof::Slot(SubscribeUpdateSlot {
+                slot: 10,
+                status: SlotStatus::SlotDead as i32,
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        event.filters = vec![SLOTS_FILTER.to_string(), SLOT_STATUSES_FILTER.to_string()];
+        assert!(!is_slot_status(&event));
+        event.filters = vec![SLOT_STATUSES_FILTER.to_string()];
+        assert!(is_slot_status(&event));
+        event.filters = vec!["MarginfiAccounts".to_string()];
+        event.update_oneof =
+            make_marginfi_account_event(Pubkey::new_unique(), 10, 1, false).update_oneof;
+        assert!(!is_slot_status(&event));
+
+        assert!(tracks_dead_slots(
+            CommitmentLevel::Confirmed,
+            CommitmentLevel::Processed
+        ));
+        assert!(!tracks_dead_slots(
+            CommitmentLevel::Confirmed,
+            CommitmentLevel::Confirmed
+        ));
+    }
+
+    #[test]
+    fn test_next_backoff() {
+        let max_backoff = Duration::from_secs(30);
+        let mut backoff = RECONNECT_INITIAL_BACKOFF;
+        let mut delays = vec![];
+        for _ in 0..7 {
+            delays.push(backoff.as_secs());
+            backoff = next_backoff(backoff, max_backoff);
+        }
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 30, 30]);
+    }
+
+    #[test]
+    fn test_geyser_keepalive() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut keepalive =
+            GeyserKeepalive::new(Some(Duration::from_secs(5)), Duration::from_secs(10), start);
+        assert_eq!(keepalive.ping_due(at(4)), None);
+        assert_eq!(keepalive.until_next_check(at(4)), Duration::from_secs(1));
+
+        assert_eq!(keepalive.ping_due(at(5)), Some(1));
+        // No other ping while the first one is pending.
+        assert_eq!(keepalive.ping_due(at(11)), None);
+        keepalive.received(at(9));
+        keepalive.pong(2);
+        assert_eq!(keepalive.dead_reason(at(14)), None);
+        assert_eq!(keepalive.until_next_check(at(14)), Duration::from_secs(1));
+        assert_eq!(
+            keepalive.dead_reason(at(15)),
+            Some("no pong to the ping 1 for 10s".to_string())
+        );
+
+        keepalive.pong(1);
+        assert_eq!(keepalive.dead_reason(at(15)), None);
+        assert_eq!(keepalive.ping_due(at(15)), Some(2));
+        assert_eq!(
+            keepalive.dead_reason(at(19)),
+            Some("no update for 10s".to_string())
+        );
+
+        // Without pings, only the silence of the stream tells a dead connection.
+        let mut keepalive = GeyserKeepalive::new(None, Duration::from_secs(10), start);
+        assert_eq!(keepalive.ping_due(at(60)), None);
+        assert_eq!(keepalive.until_next_check(at(4)), Duration::from_secs(6));
+        assert!(keepalive.dead_reason(at(10)).is_some());
+    }
+
+    #[test]
+    fn test_geyser_streams_gaps() {
+        let streams = GeyserStreams::default();
+        // At startup, nothing was missed.
+        assert_eq!(streams.subscribed().unwrap(), None);
+        assert_eq!(streams.subscribed().unwrap(), None);
+
+        // The other stream is still live.
+        streams.dropped(10).unwrap();
+        assert_eq!(streams.subscribed().unwrap(), None);
+
+        // Both streams dropped, the gap starting at the first slot while none was live.
+        streams.dropped(20).unwrap();
+        streams.dropped(30).unwrap();
+        streams.failed(40).unwrap();
+        let gap = streams.subscribed().unwrap().unwrap();
+        assert_eq!(gap.dropped_at_slot, 30);
+        assert_eq!(streams.subscribed().unwrap(), None);
+
+        // No stream could subscribe at all.
+        let streams = GeyserStreams::default();
+        streams.failed(50).unwrap();
+        assert_eq!(streams.subscribed().unwrap().unwrap().dropped_at_slot, 50);
+    }
+
+    #[test]
+    fn test_geyser_endpoints() {
+        let mut config = create_dummy_config();
+        let endpoints = geyser_endpoints(&config);
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(endpoints[0].url, config.geyser_endpoint);
+
+        config.geyser_secondary_endpoint = Some("http://secondary".to_string());
+        let endpoints = geyser_endpoints(&config);
+        assert_eq!(endpoints[1].url, "http://secondary");
+        assert_eq!(endpoints[1].x_token, config.geyser_x_token);
+
+        config.geyser_x_token_path = Some("/tmp/geyser_x_token".to_string());
+        let endpoints = geyser_endpoints(&config);
+        assert_eq!(
+            endpoints[1].x_token_path,
+            Some(PathBuf::from("/tmp/geyser_x_token"))
+        );
+
+        config.geyser_secondary_x_token = Some("secondary_token".to_string());
+        let endpoints = geyser_endpoints(&config);
+        assert_eq!(endpoints[1].x_token, "secondary_token");
+        assert_eq!(endpoints[1].x_token_path, None);
+        assert_eq!(
+            endpoints[0].x_token_path,
+            Some(PathBuf::from("/tmp/geyser_x_token"))
+        );
+    }
+
+    #[test]
+    fn test_read_x_token() {
+        let path = std::env::temp_dir().join(format!("mary_geyser_x_token_{}", std::process::id()));
+        fs::write(&path, "rotated_token\n").unwrap();
+        assert_eq!(read_x_token(&path).unwrap(), "rotated_token");
+        fs::remove_file(&path).unwrap();
+        assert!(read_x_token(&path).is_err());
+    }
 }
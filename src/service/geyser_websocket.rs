@@ -0,0 +1,570 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use crossbeam::channel::Sender;
+use futures::stream::{select_all, BoxStream, SelectAll, StreamExt};
+use log::{error, info, trace, warn};
+use solana_account_decoder::{UiAccount, UiAccountEncoding};
+use solana_client::{
+    nonblocking::pubsub_client::PubsubClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::{Memcmp, RpcFilterType},
+};
+use solana_sdk::{
+    account::Account,
+    commitment_config::{CommitmentConfig, CommitmentLevel},
+    pubkey::Pubkey,
+    signer::Signer,
+    sysvar,
+};
+use tokio::runtime::{Builder, Runtime};
+use yellowstone_grpc_proto::geyser::{
+    subscribe_update, SubscribeUpdate, SubscribeUpdateAccount, SubscribeUpdateAccountInfo,
+};
+
+use crate::{
+    cache::{reconciliation::StreamGap, Cache},
+    common::{
+        MARGINFI_ACCOUNT_DISCRIMINATOR, MARGINFI_BANK_DISCRIMINATOR, SPL_TOKEN_2022_PROGRAM_ID,
+        SPL_TOKEN_PROGRAM_ID,
+    },
+    comms::ProgramAccounts,
+    config::{redact_url, Config},
+    liquidation::wallet::TOKEN_ACCOUNT_OWNER_OFFSET,
+    service::{
+        geyser_backend::GeyserBackend,
+        geyser_channel::GeyserSender,
+        geyser_replay::GeyserRecorder,
+        geyser_subscriber::{
+            handle_event, next_backoff, sleep_unless_stopped, GeyserEndpoint, GeyserStreams,
+            RECONNECT_INITIAL_BACKOFF,
+        },
+    },
+};
+
+// How often the stop request is checked while the websocket is silent.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+// An account notification of the websocket, named after the Yellowstone filter it stands for.
+struct WebsocketUpdate {
+    filter: &'static str,
+    slot: u64,
+    pubkey: String,
+    account: UiAccount,
+}
+
+// A program subscription of the websocket, named after the Yellowstone filter it stands for.
+struct ProgramSubscription {
+    filter: &'static str,
+    program_id: Pubkey,
+    filters: Vec<RpcFilterType>,
+    commitment: CommitmentLevel,
+}
+
+/// Subscribes to a Solana websocket PubSub endpoint, e.g. Triton Whirligig, for the operators without a Yellowstone
+/// gRPC endpoint. The notifications are converted to the Yellowstone updates, and routed to the GeyserProcessor
+/// alike. The websocket streams no transactions, so the competitor liquidations are not observed, and no
+/// snapshot of the program accounts.
+pub struct WebsocketSubscriber {
+    endpoint: String,
+    // The endpoint as logged.
+    name: String,
+    stop: Arc<AtomicBool>,
+    tokio_rt: Runtime,
+    cache: Arc<Cache>,
+    marginfi_program_id: Pubkey,
+    liquidator: Pubkey,
+    geyser_tx: GeyserSender,
+    // The longest the websocket may go without any notification, the clock being notified at every slot.
+    stall_timeout: Duration,
+    reconnect_max_backoff: Duration,
+    streams: Arc<GeyserStreams>,
+    commitment: CommitmentLevel,
+    // The commitment of the Oracle and Bank updates, which price the positions.
+    pricing_commitment: CommitmentLevel,
+    // The gaps of the streams, for the CacheReconciler to re-fetch the entries possibly missed.
+    gaps: Sender<StreamGap>,
+    recorder: Option<Arc<GeyserRecorder>>,
+    // The notifications carry no write version, the updates of an account are versioned in their arrival order.
+    write_version: AtomicU64,
+}
+
+impl WebsocketSubscriber {
+    pub fn new(
+        config: &Config,
+        endpoint: GeyserEndpoint,
+        stop: Arc<AtomicBool>,
+        cache: Arc<Cache>,
+        geyser_tx: GeyserSender,
+        streams: Arc<GeyserStreams>,
+        gaps: Sender<StreamGap>,
+    ) -> Result<Self> {
+        if config.geyser_dual_commitment {
+            return Err(anyhow!(
+                "GEYSER_DUAL_COMMITMENT requires the yellowstone Geyser backend"
+            ));
+        }
+
+        let tokio_rt = Builder::new_multi_thread()
+            .thread_name("GeyserWebsocket")
+            .worker_threads(2)
+            .enable_all()
+            .build()?;
+
+        Ok(Self {
+            name: redact_url(&endpoint.url),
+            endpoint: endpoint.url,
+            stop,
+            tokio_rt,
+            cache,
+            marginfi_program_id: config.marginfi_program_id,
+            liquidator: config.wallet.pubkey(),
+            geyser_tx,
+            stall_timeout: Duration::from_secs(config.geyser_stall_timeout_sec),
+            reconnect_max_backoff: Duration::from_secs(config.geyser_reconnect_max_backoff_sec)
+                .max(RECONNECT_INITIAL_BACKOFF),
+            streams,
+            commitment: config.geyser_commitment,
+            pricing_commitment: config.geyser_pricing_commitment,
+            gaps,
+            recorder: None,
+            write_version: AtomicU64::new(0),
+        })
+    }
+
+    /// Records the updates of the websocket, the recorder being shared with the subscribers of the other endpoints.
+    pub fn with_recorder(mut self, recorder: Option<Arc<GeyserRecorder>>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    // Fails only when the subscriptions fail, a dropped websocket being logged.
+    async fn subscribe_and_stream(&self, backoff: &mut Duration) -> Result<()> {
+        let client = PubsubClient::new(&self.endpoint).await?;
+        let mut updates = select_all(Vec::new());
+        for subscription in program_subscriptions(
+            &self.marginfi_program_id,
+            &self.liquidator,
+            self.commitment,
+            self.pricing_commitment,
+        ) {
+            updates.push(subscribe_program(&client, subscription).await?);
+        }
+        // The clock at the commitment of the Oracles, for their staleness checks.
+        updates.push(
+            subscribe_account(
+                &client,
+                "SolanaClock",
+                sysvar::clock::id(),
+                self.pricing_commitment,
+            )
+            .await?,
+        );
+        let mut oracles_generation = self.cache.oracles.generation();
+        let mut subscribed_oracles = HashSet::new();
+        self.subscribe_oracles(&client, &mut updates, &mut subscribed_oracles)
+            .await?;
+
+        if let Some(gap) = self.streams.subscribed()? {
+            info!(
+                "Resubscribed to the Geyser websocket {} {:?} after the streams dropped at the slot {}",
+                self.name, gap.resubscribed_after, gap.dropped_at_slot
+            );
+            // Nothing resyncs the cache when the CacheReconciler is disabled.
+            let _ = self.gaps.send(gap);
+        }
+
+        let mut last_received = Instant::now();
+        let dropped = loop {
+            if self.stop.load(Ordering::Relaxed) {
+                break None;
+            }
+            if last_received.elapsed() >= self.stall_timeout {
+                break Some(format!("no notification for {:?}", self.stall_timeout));
+            }
+            // The Oracles of the new Banks, or of the Banks whose oracle changed, are subscribed at once.
+            let generation = self.cache.oracles.generation();
+            if generation != oracles_generation {
+                oracles_generation = generation;
+                if let Err(e) = self
+                    .subscribe_oracles(&client, &mut updates, &mut subscribed_oracles)
+                    .await
+                {
+                    break Some(format!("failed to subscribe to the new Oracles: {}", e));
+                }
+                info!(
+                    "Updated the websocket subscription of {} to {} Oracles",
+                    self.name,
+                    subscribed_oracles.len()
+                );
+            }
+
+            let update = match tokio::time::timeout(STOP_CHECK_INTERVAL, updates.next()).await {
+                Ok(Some(update)) => update,
+                Ok(None) => break Some("the websocket closed".to_string()),
+                Err(_) => continue,
+            };
+            // The websocket is healthy again once it delivers.
+            *backoff = RECONNECT_INITIAL_BACKOFF;
+            last_received = Instant::now();
+            let write_version = self.write_version.fetch_add(1, Ordering::Relaxed);
+            match to_subscribe_update(update, write_version) {
+                Ok(event) => {
+                    if let Err(e) = self.handle_update(&subscribed_oracles, &event) {
+                        break Some(format!("failed to handle an update: {}", e));
+                    }
+                }
+                Err(e) => {
+                    self.cache.metrics.record_decode_failure();
+                    error!("Failed to decode the websocket notification: {}", e);
+                }
+            }
+        };
+        if let Some(reason) = dropped {
+            warn!(
+                "The Geyser websocket stream of {} dropped: {}",
+                self.name, reason
+            );
+        }
+        Ok(())
+    }
+
+    // Subscribes to the cached Oracles not subscribed yet.
+    async fn subscribe_oracles<'a>(
+        &self,
+        client: &'a PubsubClient,
+        updates: &mut SelectAll<BoxStream<'a, WebsocketUpdate>>,
+        subscribed_oracles: &mut HashSet<[u8; 32]>,
+    ) -> Result<()> {
+        for oracle in self.cache.oracles.get_oracle_addresses() {
+            if !subscribed_oracles.contains(&oracle.to_bytes()) {
+                updates.push(
+                    subscribe_account(client, "Oracles", oracle, self.pricing_commitment).await?,
+                );
+                subscribed_oracles.insert(oracle.to_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    // Tracks the slot of the update, and routes it to the GeyserProcessor.
+    fn handle_update(
+        &self,
+        oracle_addresses_bytes: &HashSet<[u8; 32]>,
+        event: &SubscribeUpdate,
+    ) -> Result<()> {
+        if let Some(subscribe_update::UpdateOneof::Account(subscribe_account)) = &event.update_oneof
+        {
+            self.streams.observe_slot(subscribe_account.slot);
+        }
+        if let Some(recorder) = &self.recorder {
+            if let Err(e) = recorder.record(event) {
+                error!("Failed to record the Geyser update: {}", e);
+            }
+        }
+        match handle_event(
+            &self.marginfi_program_id.to_bytes(),
+            oracle_addresses_bytes,
+            &self.liquidator.to_bytes(),
+            &self.cache.get_clock()?,
+            &self.geyser_tx,
+            event,
+        ) {
+            Ok(true) => self.cache.metrics.record_unknown_update(),
+            Ok(false) => {}
+            Err(e) => error!("Error handling Geyser update {:?}: {}", event, e),
+        }
+        Ok(())
+    }
+}
+
+impl GeyserBackend for WebsocketSubscriber {
+    fn run(&self) -> Result<()> {
+        info!(
+            "Entering the websocket GeyserService loop of {}.",
+            self.name
+        );
+        let mut backoff = RECONNECT_INITIAL_BACKOFF;
+        while !self.stop.load(Ordering::Relaxed) {
+            info!("Connecting to the Geyser websocket {}...", self.name);
+            let slot = match self
+                .tokio_rt
+                .block_on(self.subscribe_and_stream(&mut backoff))
+            {
+                Ok(()) => {
+                    let slot = self.cache.get_clock()?.slot;
+                    self.streams.dropped(slot)?;
+                    slot
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to subscribe to the Geyser websocket {}: {}",
+                        self.name, e
+                    );
+                    let slot = self.cache.get_clock()?.slot;
+                    self.streams.failed(slot)?;
+                    slot
+                }
+            };
+            if !self.stop.load(Ordering::Relaxed) {
+                warn!(
+                    "The Geyser websocket of {} is down at the slot {}, reconnecting in {:?}",
+                    self.name, slot, backoff
+                );
+                sleep_unless_stopped(&self.stop, backoff);
+                backoff = next_backoff(backoff, self.reconnect_max_backoff);
+            }
+        }
+        info!(
+            "The websocket GeyserService loop of {} is stopped.",
+            self.name
+        );
+
+        Ok(())
+    }
+
+    fn fetch_program_accounts_snapshot(&self) -> Result<ProgramAccounts> {
+        Err(anyhow!(
+            "The Geyser websocket {} streams no program accounts snapshot, GEYSER_BOOTSTRAP requires the yellowstone Geyser backend",
+            self.name
+        ))
+    }
+}
+
+// The subscriptions of the Marginfi accounts and of the Banks at the pricing commitment, and of the SPL Token and
+// Token-2022 accounts of the liquidator wallet.
+fn program_subscriptions(
+    marginfi_program_id: &Pubkey,
+    liquidator: &Pubkey,
+    commitment: CommitmentLevel,
+    pricing_commitment: CommitmentLevel,
+) -> Vec<ProgramSubscription> {
+    let mut subscriptions = vec![
+        ProgramSubscription {
+            filter: "MarginfiAccounts",
+            program_id: *marginfi_program_id,
+            filters: vec![discriminator_filter(&MARGINFI_ACCOUNT_DISCRIMINATOR)],
+            commitment,
+        },
+        ProgramSubscription {
+            filter: "Banks",
+            program_id: *marginfi_program_id,
+            filters: vec![discriminator_filter(&MARGINFI_BANK_DISCRIMINATOR)],
+            commitment: pricing_commitment,
+        },
+    ];
+    for token_program_id in [SPL_TOKEN_PROGRAM_ID, SPL_TOKEN_2022_PROGRAM_ID] {
+        subscriptions.push(ProgramSubscription {
+            filter: "WalletTokenAccounts",
+            program_id: token_program_id,
+            filters: vec![RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
+                TOKEN_ACCOUNT_OWNER_OFFSET,
+                liquidator.to_bytes().to_vec(),
+            ))],
+            commitment,
+        });
+    }
+    subscriptions
+}
+
+fn discriminator_filter(discriminator: &[u8; 8]) -> RpcFilterType {
+    RpcFilterType::Memcmp(Memcmp::new_raw_bytes(0, discriminator.to_vec()))
+}
+
+fn account_config(commitment: CommitmentLevel) -> RpcAccountInfoConfig {
+    RpcAccountInfoConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        data_slice: None,
+        commitment: Some(CommitmentConfig { commitment }),
+        min_context_slot: None,
+    }
+}
+
+async fn subscribe_program<'a>(
+    client: &'a PubsubClient,
+    subscription: ProgramSubscription,
+) -> Result<BoxStream<'a, WebsocketUpdate>> {
+    let filter = subscription.filter;
+    let (stream, _unsubscribe) = client
+        .program_subscribe(
+            &subscription.program_id,
+            Some(RpcProgramAccountsConfig {
+                filters: Some(subscription.filters),
+                account_config: account_config(subscription.commitment),
+                with_context: Some(true),
+                sort_results: None,
+            }),
+        )
+        .await
+        .map_err(|e| anyhow!("Failed to subscribe to the {} accounts: {}", filter, e))?;
+    Ok(stream
+        .map(move |response| WebsocketUpdate {
+            filter,
+            slot: response.context.slot,
+            pubkey: response.value.pubkey,
+            account: response.value.account,
+        })
+        .boxed())
+}
+
+async fn subscribe_account<'a>(
+    client: &'a PubsubClient,
+    filter: &'static str,
+    address: Pubkey,
+    commitment: CommitmentLevel,
+) -> Result<BoxStream<'a, WebsocketUpdate>> {
+    let (stream, _unsubscribe) = client
+        .account_subscribe(&address, Some(account_config(commitment)))
+        .await
+        .map_err(|e| anyhow!("Failed to subscribe to the account {}: {}", address, e))?;
+    Ok(stream
+        .map(move |response| WebsocketUpdate {
+            filter,
+            slot: response.context.slot,
+            pubkey: address.to_string(),
+            account: response.value,
+        })
+        .boxed())
+}
+
+// The Yellowstone update of the notification, for it to be routed, recorded and replayed as the streamed ones.
+fn to_subscribe_update(update: WebsocketUpdate, write_version: u64) -> Result<SubscribeUpdate> {
+    let address: Pubkey = update
+        .pubkey
+        .parse()
+        .map_err(|e| anyhow!("Invalid address {:?}: {}", update.pubkey, e))?;
+    let account: Account = update
+        .account
+        .decode()
+        .ok_or_else(|| anyhow!("Failed to decode the account {}", address))?;
+    trace!(
+        "Received the websocket notification of {} at the slot {}",
+        address,
+        update.slot
+    );
+    Ok(SubscribeUpdate {
+        filters: vec![update.filter.to_string()],
+        update_oneof: Some(subscribe_update::UpdateOneof::Account(
+            SubscribeUpdateAccount {
+                slot: update.slot,
+                account: Some(SubscribeUpdateAccountInfo {
+                    pubkey: address.to_bytes().to_vec(),
+                    lamports: account.lamports,
+                    owner: account.owner.to_bytes().to_vec(),
+                    executable: account.executable,
+                    rent_epoch: account.rent_epoch,
+                    data: account.data,
+                    write_version,
+                    txn_signature: None,
+                }),
+                is_startup: false,
+            },
+        )),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        cache::test_util::generate_test_clock,
+        common::MessageType,
+        service::geyser_channel::{geyser_channel, OverflowPolicy},
+    };
+
+    #[test]
+    fn test_program_subscriptions() {
+        let (program_id, liquidator) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let filters = |subscriptions: Vec<ProgramSubscription>| {
+            subscriptions
+                .into_iter()
+                .map(|subscription| (subscription.filter, subscription.commitment))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            filters(program_subscriptions(
+                &program_id,
+                &liquidator,
+                CommitmentLevel::Confirmed,
+                CommitmentLevel::Confirmed
+            )),
+            vec![
+                ("MarginfiAccounts", CommitmentLevel::Confirmed),
+                ("Banks", CommitmentLevel::Confirmed),
+                ("WalletTokenAccounts", CommitmentLevel::Confirmed),
+                ("WalletTokenAccounts", CommitmentLevel::Confirmed),
+            ]
+        );
+        assert_eq!(
+            filters(program_subscriptions(
+                &program_id,
+                &liquidator,
+                CommitmentLevel::Confirmed,
+                CommitmentLevel::Processed
+            )),
+            vec![
+                ("MarginfiAccounts", CommitmentLevel::Confirmed),
+                ("Banks", CommitmentLevel::Processed),
+                ("WalletTokenAccounts", CommitmentLevel::Confirmed),
+                ("WalletTokenAccounts", CommitmentLevel::Confirmed),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_route_websocket_notification() {
+        let (tx, rx) = geyser_channel(16, OverflowPolicy::DropOldest);
+        let oracle = Pubkey::new_unique();
+        let account = Account {
+            lamports: 42,
+            data: vec![1, 2, 3],
+            owner: Pubkey::new_unique(),
+            executable: false,
+            rent_epoch: 0,
+        };
+        let update = WebsocketUpdate {
+            filter: "Oracles",
+            slot: 10,
+            pubkey: oracle.to_string(),
+            account: UiAccount::encode(&oracle, &account, UiAccountEncoding::Base64, None, None),
+        };
+
+        let event = to_subscribe_update(update, 7).unwrap();
+        assert_eq!(event.filters, vec!["Oracles".to_string()]);
+        let unknown = handle_event(
+            &Pubkey::new_unique().to_bytes(),
+            &HashSet::from([oracle.to_bytes()]),
+            &Pubkey::new_unique().to_bytes(),
+            &generate_test_clock(1),
+            &tx,
+            &event,
+        )
+        .unwrap();
+        assert!(!unknown);
+
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.message_type, MessageType::Oracle);
+        assert_eq!(msg.slot, 10);
+        assert_eq!(msg.address, oracle);
+        assert_eq!(msg.write_version, 7);
+        assert_eq!(msg.account, account);
+
+        let invalid = WebsocketUpdate {
+            filter: "Oracles",
+            slot: 10,
+            pubkey: "invalid".to_string(),
+            account: UiAccount::encode(&oracle, &account, UiAccountEncoding::Base64, None, None),
+        };
+        assert!(to_subscribe_update(invalid, 8).is_err());
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use solana_sdk::{pubkey::Pubkey, signer::Signer};
+
+use crate::{
+    cache::snapshot::{default_tmp_dir, write_atomically},
+    comms::CommsClient,
+    config::Config,
+};
+
+// The heartbeats an instance can miss before its lock is considered stale, e.g. left by a crash.
+const STALE_HEARTBEATS: u64 = 3;
+
+/// The lock as left by the last instance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LockState {
+    heartbeat_at_unix: u64,
+    // Whether the instance stopped cleanly.
+    released: bool,
+}
+
+/// The lock of the liquidator wallet, held by the running instance and refreshed with a heartbeat, so that
+/// two bots do not race each other with the same wallet. Another instance is detected either by its fresh
+/// lock on the same host, or by the transactions of the wallet landed since the last heartbeat of the lock
+/// on another host. The released lock is kept for attributing the transactions to the stopped instance.
+/// The lock is only checked and written under an exclusive `flock`, so that two instances starting together
+/// cannot both take it.
+pub struct InstanceLock {
+    path: PathBuf,
+    wallet: Pubkey,
+    stop: Arc<AtomicBool>,
+    heartbeat: Option<JoinHandle<()>>,
+    // Held until the instance stops, released by the OS when it crashes. None for a duplicate instance started
+    // anyway, which leaves the lock to the instance holding it.
+    exclusive_lock: Option<File>,
+}
+
+impl InstanceLock {
+    pub fn acquire<T: CommsClient>(config: &Config) -> Result<Self> {
+        let wallet = config.wallet.pubkey();
+        let path = lock_path(config, &wallet);
+        let heartbeat_interval = Duration::from_secs(config.instance_heartbeat_sec.max(1));
+        let now = now_unix();
+
+        let exclusive_lock = lock_exclusively(&exclusive_lock_path(&path))?;
+        let last_lock = read_lock(&path)?;
+        let latest_transaction_time = T::new(config)?
+            .get_latest_transaction_time(&wallet)
+            .unwrap_or_else(|err| {
+                warn!(
+                    "Failed to check the latest transaction of the wallet {}: {}",
+                    wallet, err
+                );
+                None
+            });
+        let duplicate = match exclusive_lock {
+            Some(_) => detect_duplicate(
+                now,
+                heartbeat_interval.as_secs(),
+                last_lock,
+                latest_transaction_time,
+            ),
+            None => Some("it holds the instance lock".to_string()),
+        };
+        if let Some(reason) = duplicate {
+            if !config.allow_duplicate_instance {
+                return Err(anyhow!(
+                    "Another instance is running with the wallet {}: {}. Stop it, or set \
+                     ALLOW_DUPLICATE_INSTANCE=true if this is intended.",
+                    wallet,
+                    reason
+                ));
+            }
+            warn!(
+                "Another instance is running with the wallet {}: {}, starting anyway.",
+                wallet, reason
+            );
+        }
+
+        if exclusive_lock.is_none() {
+            return Ok(Self {
+                path,
+                wallet,
+                stop: Arc::new(AtomicBool::new(false)),
+                heartbeat: None,
+                exclusive_lock,
+            });
+        }
+
+        write_lock(&path, &wallet, now, false)?;
+        info!(
+            "Acquired the instance lock {} of the wallet {}",
+            path.display(),
+            wallet
+        );
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let heartbeat = {
+            let path = path.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    thread::park_timeout(heartbeat_interval);
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    if let Err(err) = write_lock(&path, &wallet, now_unix(), false) {
+                        error!("Failed to refresh the instance lock: {}", err);
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            path,
+            wallet,
+            stop,
+            heartbeat: Some(heartbeat),
+            exclusive_lock,
+        })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        if self.exclusive_lock.is_none() {
+            return;
+        }
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.thread().unpark();
+            let _ = heartbeat.join();
+        }
+        if let Err(err) = write_lock(&self.path, &self.wallet, now_unix(), true) {
+            warn!(
+                "Failed to release the instance lock {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+fn lock_path(config: &Config, wallet: &Pubkey) -> PathBuf {
+    config
+        .instance_lock_path
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join(format!("mary_{}.lock", wallet)))
+}
+
+// The lock itself is replaced by every write, so that the exclusive lock is taken on a file next to it.
+fn exclusive_lock_path(path: &Path) -> PathBuf {
+    let mut exclusive_lock_path = path.as_os_str().to_owned();
+    exclusive_lock_path.push(".flock");
+    PathBuf::from(exclusive_lock_path)
+}
+
+/// None when another instance holds the exclusive lock.
+fn lock_exclusively(path: &Path) -> Result<Option<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open the instance lock {}", path.display()))?;
+    // SAFETY: the descriptor is owned by the file, open for the call.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(Some(file));
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::WouldBlock {
+        Ok(None)
+    } else {
+        Err(anyhow!(
+            "Failed to lock the instance lock {}: {}",
+            path.display(),
+            err
+        ))
+    }
+}
+
+/// None when there is no lock.
+fn read_lock(path: &Path) -> Result<Option<LockState>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the instance lock {}", path.display()))?;
+    let value: Value = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse the instance lock {}", path.display()))?;
+    Ok(Some(LockState {
+        heartbeat_at_unix: value["heartbeat_at_unix"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("Missing heartbeat in the instance lock {}", path.display()))?,
+        released: value["released"].as_bool().unwrap_or_default(),
+    }))
+}
+
+fn write_lock(path: &Path, wallet: &Pubkey, heartbeat_at_unix: u64, released: bool) -> Result<()> {
+    let lock = json!({
+        "pid": std::process::id(),
+        "wallet": wallet.to_string(),
+        "heartbeat_at_unix": heartbeat_at_unix,
+        "released": released,
+    });
+    write_atomically(path, &default_tmp_dir(path), lock.to_string().as_bytes())
+}
+
+/// Why another instance is considered live, if it is. The transactions landed up to a heartbeat interval
+/// after the last heartbeat are attributed to the instance which left the lock.
+fn detect_duplicate(
+    now: u64,
+    heartbeat_interval: u64,
+    last_lock: Option<LockState>,
+    latest_transaction_time: Option<i64>,
+) -> Option<String> {
+    let stale_after = heartbeat_interval * STALE_HEARTBEATS;
+    if let Some(last_lock) = last_lock {
+        let age = now.saturating_sub(last_lock.heartbeat_at_unix);
+        if !last_lock.released && age < stale_after {
+            return Some(format!("its lock was refreshed {}s ago", age));
+        }
+    }
+
+    let latest_transaction_time = u64::try_from(latest_transaction_time?).ok()?;
+    let attributed_until = last_lock.map_or(0, |lock| lock.heartbeat_at_unix + heartbeat_interval);
+    (now.saturating_sub(latest_transaction_time) < stale_after
+        && latest_transaction_time > attributed_until)
+        .then(|| {
+            format!(
+                "the wallet landed a transaction {}s ago",
+                now.saturating_sub(latest_transaction_time)
+            )
+        })
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{comms::test_util::MockedCommsClient, config::test_util::create_dummy_config};
+
+    fn lock(heartbeat_at_unix: u64, released: bool) -> Option<LockState> {
+        Some(LockState {
+            heartbeat_at_unix,
+            released,
+        })
+    }
+
+    #[test]
+    fn test_detect_duplicate() {
+        // No lock and no recent transaction.
+        assert!(detect_duplicate(1_000, 10, None, None).is_none());
+        assert!(detect_duplicate(1_000, 10, None, Some(900)).is_none());
+        // A fresh lock on the same host, unless released.
+        assert!(detect_duplicate(1_000, 10, lock(990, false), None).is_some());
+        assert!(detect_duplicate(1_000, 10, lock(990, true), Some(985)).is_none());
+        // A stale lock, with the transactions of the instance which left it.
+        assert!(detect_duplicate(1_000, 10, lock(960, false), Some(965)).is_none());
+        // A recent transaction since the stale lock, or with no lock, is from another host.
+        assert!(detect_duplicate(1_000, 10, lock(960, false), Some(995)).is_some());
+        assert!(detect_duplicate(1_000, 10, None, Some(995)).is_some());
+    }
+
+    #[test]
+    fn test_acquire() {
+        let path = std::env::temp_dir().join(format!("mary_instance_{}.lock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut config = create_dummy_config();
+        config.instance_lock_path = Some(path.to_string_lossy().into_owned());
+
+        let instance_lock = InstanceLock::acquire::<MockedCommsClient>(&config).unwrap();
+        assert!(!read_lock(&path).unwrap().unwrap().released);
+        assert!(InstanceLock::acquire::<MockedCommsClient>(&config).is_err());
+        // A duplicate started anyway leaves the lock to the instance holding it.
+        config.allow_duplicate_instance = true;
+        drop(InstanceLock::acquire::<MockedCommsClient>(&config).unwrap());
+        assert!(!read_lock(&path).unwrap().unwrap().released);
+        config.allow_duplicate_instance = false;
+
+        drop(instance_lock);
+        assert!(read_lock(&path).unwrap().unwrap().released);
+        drop(InstanceLock::acquire::<MockedCommsClient>(&config).unwrap());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(exclusive_lock_path(&path)).unwrap();
+    }
+}
@@ -2,21 +2,67 @@ use std::{
     collections::HashMap,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-use log::{error, info};
-use solana_sdk::pubkey::Pubkey;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::{debug, error, info};
+use solana_sdk::{
+    hash::Hash, pubkey::Pubkey, signature::Signature, transaction::VersionedTransaction,
+};
 
 use crate::{
-    cache::Cache,
-    comms::CommsClient,
-    liquidation::{choose_liquidation_strategy, LiquidationStrategy},
+    cache::{
+        candidates::RankedCandidate, events::CacheEvent, marginfi_accounts::CachedMarginfiAccount,
+        snapshot::now_unix, Cache,
+    },
+    comms::{
+        leader_schedule::{LeaderTarget, LeaderTracker},
+        CommsClient, PendingSignatures, SignatureStatus, SignatureSubscriber, TransactionSender,
+    },
+    config::Config,
+    liquidation::{
+        candidates::{is_liquidatable, CandidateTracker, LatestCandidateDiff},
+        choose_liquidation_strategy,
+        compute_unit_price::ComputeUnitPriceTuner,
+        event_log::{DecisionAccounts, EventOutcome, LiquidationEventLog},
+        hooks::{LiquidationOutcome, PostLiquidationHooks},
+        latency_budget::{BudgetedAttempt, LatencyBudget, LiquidationStage},
+        pause_windows::PauseSchedule,
+        profitability::{ProfitEstimate, ProfitEstimator},
+        repay::{liquidator_liability, select_repay_source, RepaySource},
+        risk_hook::{RiskDecision, RiskHook},
+        simulation::{
+            compute_unit_limit, simulate_candidate, simulate_candidates, CandidateSimulation,
+            MAX_COMPUTE_UNIT_LIMIT,
+        },
+        sizing::borrowable_amount,
+        swap::SwapClient,
+        traces::{ExecutionTrace, ExecutionTraces},
+        LiquidationParams, LiquidationStrategy, Liquidator,
+    },
 };
 
+// The longest wait for a cache change between the cycles, e.g. for the paused liquidations to resume.
+const MAX_CYCLE_INTERVAL: Duration = Duration::from_secs(5);
+// The blockhashes expire after 150 blocks, about a minute.
+const BLOCKHASH_REFRESH_INTERVAL: Duration = Duration::from_secs(20);
+// How often the stop request is checked while no liquidation awaits its confirmation.
+const CONFIRMATION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// A sent liquidation, confirmed off the liquidation loop.
+struct SentLiquidation {
+    signatures: Vec<Signature>,
+    pending_signatures: Result<PendingSignatures>,
+    decision_accounts: DecisionAccounts,
+    outcome: LiquidationOutcome,
+}
+
 pub struct LiquidationService<T>
 where
     T: CommsClient + 'static,
@@ -24,31 +70,154 @@ where
     stop: Arc<AtomicBool>,
     cache: Arc<Cache>,
     comms_client: T,
+    candidate_tracker: Mutex<CandidateTracker>,
+    liquidator: Arc<Liquidator>,
+    profit_estimator: ProfitEstimator,
+    compute_unit_price_tuner: Arc<ComputeUnitPriceTuner>,
+    // Over the units consumed by the simulations, in bps.
+    compute_unit_limit_margin_bps: u64,
+    // The blockhash the transactions are signed with, with the time it was fetched at.
+    recent_blockhash: Mutex<Option<(Hash, Instant)>>,
+    simulation_top_k: usize,
+    simulation_concurrency: usize,
+    // The accounts whose Banks or Oracles lag them by more slots are not acted on.
+    max_dependency_slot_lag: Option<u64>,
+    // The simulations of the top candidates, with the repay source and the liability amount simulated.
+    simulations: Mutex<HashMap<Pubkey, (RepaySource, u64, CandidateSimulation)>>,
+    transaction_sender: Box<dyn TransactionSender>,
+    signature_subscriber: SignatureSubscriber,
+    // The sent liquidations, with their execution traces, queued for their confirmation.
+    sent_liquidations: (
+        Sender<(Pubkey, SentLiquidation, ExecutionTrace)>,
+        Receiver<(Pubkey, SentLiquidation, ExecutionTrace)>,
+    ),
+    post_liquidation_hooks: PostLiquidationHooks,
+    // Monitoring only when disabled for the namespace.
+    liquidation_enabled: bool,
+    pause_schedule: PauseSchedule,
+    latency_budget: LatencyBudget,
+    event_log: Arc<LiquidationEventLog>,
+    leader_tracker: LeaderTracker,
+    candidate_diff: Arc<LatestCandidateDiff>,
+    risk_hook: Option<RiskHook>,
+    // Quotes the collateral swaps of the flashloan liquidations, when enabled.
+    swap_client: Option<SwapClient>,
+    execution_traces: Arc<ExecutionTraces>,
+    // The next cycle starts on a cache change.
+    cache_events: Receiver<CacheEvent>,
 }
 
 impl<T: CommsClient> LiquidationService<T> {
-    pub fn new(stop: Arc<AtomicBool>, cache: Arc<Cache>, comms_client: T) -> Result<Self> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: &Config,
+        stop: Arc<AtomicBool>,
+        cache: Arc<Cache>,
+        comms_client: T,
+        transaction_sender: Box<dyn TransactionSender>,
+        compute_unit_price_tuner: Arc<ComputeUnitPriceTuner>,
+        post_liquidation_hooks: PostLiquidationHooks,
+        event_log: Arc<LiquidationEventLog>,
+        candidate_diff: Arc<LatestCandidateDiff>,
+        execution_traces: Arc<ExecutionTraces>,
+    ) -> Result<Self> {
         Ok(Self {
             stop,
+            cache_events: cache.events.subscribe()?,
+            profit_estimator: ProfitEstimator::new(config, cache.clone()),
+            compute_unit_price_tuner,
+            compute_unit_limit_margin_bps: config.compute_unit_limit_margin_bps,
             cache,
             comms_client,
+            candidate_tracker: Mutex::new(CandidateTracker::new(Duration::from_secs(
+                config.candidate_starvation_threshold_sec,
+            ))),
+            liquidator: Arc::new(Liquidator::new(config)),
+            recent_blockhash: Mutex::new(None),
+            simulation_top_k: config.simulation_top_k,
+            simulation_concurrency: config.simulation_concurrency,
+            max_dependency_slot_lag: config.max_dependency_slot_lag,
+            simulations: Mutex::new(HashMap::new()),
+            transaction_sender,
+            signature_subscriber: SignatureSubscriber::new(config)?,
+            sent_liquidations: unbounded(),
+            post_liquidation_hooks,
+            liquidation_enabled: config.namespace().liquidation_enabled,
+            pause_schedule: PauseSchedule::new(config.pause_windows.clone()),
+            latency_budget: LatencyBudget::new(config.latency_budget_ms, &config.stage_timeouts_ms),
+            event_log,
+            leader_tracker: LeaderTracker::new(config),
+            candidate_diff,
+            risk_hook: RiskHook::new(config)?,
+            swap_client: SwapClient::new(config)?,
+            execution_traces,
         })
     }
 
     pub fn run(&self) -> anyhow::Result<()> {
+        // The liquidations sent are confirmed even when the loop fails.
+        let stopped = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| self.confirm_liquidations(&stopped));
+            let result = self.run_cycles();
+            stopped.store(true, Ordering::Relaxed);
+            result
+        })
+    }
+
+    fn run_cycles(&self) -> anyhow::Result<()> {
         info!("Entering the LiquidationService loop.");
+        let paused = !self.liquidation_enabled || self.pause_schedule.is_paused();
+        if let Err(err) = self.process_restored_candidates(paused) {
+            error!(
+                "Failed to process the restored liquidation candidates: {}",
+                err
+            );
+        }
         while !self.stop.load(Ordering::Relaxed) {
             info!("Starting the Liquidation cycle...");
+            let paused = !self.liquidation_enabled || self.pause_schedule.is_paused();
+            if let Err(err) = self.refresh_leader_schedule() {
+                error!("Failed to refresh the leader schedule: {}", err);
+            }
+            match self.cache.refresh_health() {
+                Ok(recomputed) => debug!("Recomputed the health of {} accounts.", recomputed),
+                Err(err) => error!("Failed to refresh the accounts health: {}", err),
+            }
             match self.cache.marginfi_accounts.get_accounts_with_health() {
                 Ok(accounts_by_health) => {
-                    let sorted_accounts = sort_accounts_by_health(&accounts_by_health);
-                    for account_address in sorted_accounts {
-                        if let Err(err) = self.process_account(account_address) {
-                            error!(
-                                "Failed to process the Marginfi account {}: {}",
-                                account_address, err
-                            );
+                    match self.cache.marginfi_accounts.get_accounts_sorted_by_health() {
+                        Ok(sorted_accounts) => {
+                            // The candidates are processed by health for the cycle when they cannot be
+                            // prioritized.
+                            let prioritized_accounts = self
+                                .prioritize_candidates(&accounts_by_health, sorted_accounts.clone())
+                                .unwrap_or_else(|err| {
+                                    error!(
+                                        "Failed to prioritize the liquidation candidates: {}",
+                                        err
+                                    );
+                                    sorted_accounts
+                                });
+                            if let Err(err) = self.simulate_top_candidates(&accounts_by_health) {
+                                error!(
+                                    "Failed to simulate the top liquidation candidates: {}",
+                                    err
+                                );
+                            }
+                            for account_address in prioritized_accounts {
+                                if let Err(err) = self.process_account(account_address, paused) {
+                                    error!(
+                                        "Failed to process the Marginfi account {}: {}",
+                                        account_address, err
+                                    );
+                                }
+                            }
                         }
+                        Err(err) => error!(
+                            "Failed to sort the Marginfi accounts by health, skipping the cycle: {}",
+                            err
+                        ),
                     }
                 }
                 Err(e) => {
@@ -56,27 +225,818 @@ impl<T: CommsClient> LiquidationService<T> {
                     continue;
                 }
             };
+            let aborts = self.latency_budget.aborts();
+            if !aborts.is_empty() {
+                info!(
+                    "Liquidation attempts abandoned over the latency budget by stage: {:?}",
+                    aborts
+                );
+            }
             info!("Liquidation cycle is completed.");
-            // Temporary hack to avoid busy spin
-            std::thread::sleep(std::time::Duration::from_secs(5));
+            let changes = self.wait_for_cache_changes();
+            debug!("Starting the next cycle on {} cache changes.", changes);
         }
 
         info!("The LiquidationService loop is stopped.");
         Ok(())
     }
 
-    fn process_account(&self, address: Pubkey) -> Result<()> {
+    /// Waits for the next cache change, or the max cycle interval, and drains the changes queued meanwhile:
+    /// the next cycle covers all of them. Returns the number of changes.
+    fn wait_for_cache_changes(&self) -> usize {
+        match self.cache_events.recv_timeout(MAX_CYCLE_INTERVAL) {
+            Ok(_) => 1 + self.cache_events.try_iter().count(),
+            Err(_) => 0,
+        }
+    }
+
+    fn process_account(&self, address: Pubkey, paused: bool) -> Result<()> {
+        let attempt = self.latency_budget.start();
         let account = self.cache.marginfi_accounts.get_account(&address)?;
-        let liquidation_strategy = choose_liquidation_strategy(&account, &self.cache)?;
-        if let Some(lq_params) = liquidation_strategy.prepare(&account)? {
-            liquidation_strategy.liquidate(lq_params, &self.comms_client)?;
+        if !self.is_consistent(&account)? {
+            return Ok(());
+        }
+        let liquidation_strategy =
+            choose_liquidation_strategy(&account, &self.cache, &self.liquidator)?;
+        let Some(lq_params) = liquidation_strategy.prepare(&account)? else {
+            return Ok(());
+        };
+
+        let mut trace = ExecutionTrace::start(address);
+        trace.record(format!(
+            "prepared: repay {} of the mint {} to the Bank {} for {} of the Bank {}",
+            lq_params.liab_amount,
+            lq_params.liab_mint,
+            lq_params.liab_bank,
+            lq_params.asset_amount,
+            lq_params.asset_bank
+        ));
+        let result = self.attempt_liquidation(
+            &account,
+            &liquidation_strategy,
+            lq_params,
+            attempt,
+            paused,
+            &mut trace,
+        );
+        match result {
+            // The trace is kept once the liquidation is confirmed.
+            Ok(Some(sent)) => {
+                return self
+                    .sent_liquidations
+                    .0
+                    .send((address, sent, trace))
+                    .map_err(|e| {
+                        anyhow!("Failed to queue the confirmation of {}: {}", address, e)
+                    });
+            }
+            Ok(None) => {}
+            Err(ref err) => trace.finish(format!("error: {}", err)),
+        }
+        if let Err(err) = self.execution_traces.push(trace) {
+            error!("Failed to keep the execution trace of {}: {}", address, err);
+        }
+        result.map(|_| ())
+    }
+
+    fn attempt_liquidation(
+        &self,
+        account: &CachedMarginfiAccount,
+        liquidation_strategy: &impl LiquidationStrategy,
+        mut lq_params: LiquidationParams,
+        mut attempt: BudgetedAttempt,
+        paused: bool,
+        trace: &mut ExecutionTrace,
+    ) -> Result<Option<SentLiquidation>> {
+        let address = account.address();
+        // Nothing is submitted while paused, the risk service is not bothered.
+        if let (Some(risk_hook), false) = (&self.risk_hook, paused) {
+            let decision = risk_hook.review(&lq_params);
+            trace.record(format!("risk service decision: {:?}", decision));
+            match decision {
+                RiskDecision::Approve => {}
+                RiskDecision::Deny { reason } => {
+                    info!(
+                        "Skipping the liquidation of {}: denied by the risk service: {}",
+                        address, reason
+                    );
+                    trace.finish("skipped: denied by the risk service");
+                    return Ok(None);
+                }
+                RiskDecision::Resize { liab_amount } => {
+                    info!(
+                        "The risk service resized the liquidation of {} from {} to {}",
+                        address, lq_params.liab_amount, liab_amount
+                    );
+                    lq_params.resize(liab_amount);
+                }
+            }
+        }
+
+        // The liquidator account takes on more than the repaid amount, the insurance fee being on top of it.
+        let liquidator_liability =
+            liquidator_liability(lq_params.liab_amount).ok_or_else(|| {
+                anyhow!(
+                    "The liquidator liability of {} overflows",
+                    lq_params.liab_amount
+                )
+            })?;
+        let repay_source = select_repay_source(
+            self.has_borrow_capacity(&lq_params, liquidator_liability)?,
+            &lq_params.liab_mint,
+            self.gross_transfer_amount(&lq_params.liab_mint, liquidator_liability)?,
+            &self.cache.wallet_token_accounts.by_mint()?,
+            self.flashloan_repay_amount(&lq_params)?,
+        );
+        match repay_source {
+            Some(repay_source) => {
+                trace.record(format!("repay source: {:?}", repay_source));
+                lq_params.repay_source = repay_source;
+            }
+            None => {
+                info!(
+                    "Skipping the liquidation of {}: neither the liquidator account, the wallet nor a flashloan can repay {} of the mint {}",
+                    address, lq_params.liab_amount, lq_params.liab_mint
+                );
+                trace.finish("skipped: no repay source");
+                return Ok(None);
+            }
+        }
+        if !trace.finish_stage(&mut attempt, LiquidationStage::Sizing) {
+            return Ok(None);
+        }
+
+        // The flashloans repay the liability with the seized collateral, swapped within the transaction.
+        if let RepaySource::Flashloan { repay_amount } = lq_params.repay_source {
+            if !self.quote_collateral_swap(&mut lq_params, repay_amount, trace)? {
+                return Ok(None);
+            }
+            if !trace.finish_stage(&mut attempt, LiquidationStage::Quote) {
+                return Ok(None);
+            }
+        }
+
+        // Built with the maximum compute unit limit for the simulation, then with the units it consumed.
+        let recent_blockhash = self.recent_blockhash()?;
+        let compute_unit_price = self.compute_unit_price()?;
+        let mut transactions = liquidation_strategy.build_transactions(
+            &lq_params,
+            recent_blockhash,
+            compute_unit_price,
+            MAX_COMPUTE_UNIT_LIMIT,
+        )?;
+        trace.record(format!(
+            "built {} transactions at the compute unit price of {}",
+            transactions.len(),
+            compute_unit_price
+        ));
+        if !trace.finish_stage(&mut attempt, LiquidationStage::Build) {
+            return Ok(None);
+        }
+
+        // The top candidates are simulated at the beginning of the cycle, the rest on demand, as are the top
+        // ones whose repay source or size changed since.
+        let simulation = match self.cached_simulation(&address, &lq_params)? {
+            Some(simulation) => simulation,
+            None => {
+                self.validate_transactions(&transactions)?;
+                simulate_candidate(&self.comms_client, &transactions)
+            }
+        };
+        trace.record(format!("simulation: {:?}", simulation));
+        let units_consumed = match simulation {
+            CandidateSimulation::Passed { units_consumed } => units_consumed,
+            CandidateSimulation::Failed { error } => {
+                info!(
+                    "Skipping the liquidation of {}: the simulation failed: {}",
+                    address, error
+                );
+                trace.finish("skipped: the simulation failed");
+                return Ok(None);
+            }
+        };
+        // The maximum is kept when the simulation does not report the units consumed.
+        if let Some(compute_unit_limit) =
+            compute_unit_limit(&units_consumed, self.compute_unit_limit_margin_bps)
+        {
+            transactions = liquidation_strategy.build_transactions(
+                &lq_params,
+                recent_blockhash,
+                compute_unit_price,
+                compute_unit_limit,
+            )?;
+            trace.record(format!("compute unit limit: {}", compute_unit_limit));
+        }
+        if !trace.finish_stage(&mut attempt, LiquidationStage::Simulate) {
+            return Ok(None);
+        }
+
+        let estimate = self.estimate_profit(&lq_params, &transactions, trace);
+        if let Some(estimate) = &estimate {
+            let min_profit_usd = self
+                .profit_estimator
+                .min_profit_usd(&lq_params.asset_bank)?;
+            if estimate.profit_usd() < min_profit_usd {
+                info!(
+                    "Skipping the liquidation of {}: the expected profit of {:.4} USD is under the minimum of {} USD",
+                    address,
+                    estimate.profit_usd(),
+                    min_profit_usd
+                );
+                trace.finish("skipped: under the minimum profit");
+                return Ok(None);
+            }
+        }
+        lq_params.tip_lamports = self.profit_estimator.tip_lamports(estimate.as_ref());
+        if lq_params.tip_lamports > 0 {
+            trace.record(format!("jito tip: {} lamports", lq_params.tip_lamports));
+        }
+
+        if paused {
+            info!(
+                "Monitoring only, the liquidations are paused: {} is liquidatable for {} of the mint {}",
+                address, lq_params.liab_amount, lq_params.liab_mint
+            );
+            trace.finish("skipped: the liquidations are paused");
+            return Ok(None);
+        }
+
+        // The upcoming leaders are the ones of the current slot, even when the clock updates lag.
+        let leader_target = self
+            .leader_tracker
+            .target(self.cache.get_extrapolated_clock()?.slot);
+        trace.record(format!("leader target: {:?}", leader_target));
+        if leader_target == LeaderTarget::NoEligibleLeader {
+            info!(
+                "Deferring the liquidation of {}: no Jito-enabled leader in the upcoming slots",
+                address
+            );
+            trace.finish("deferred: no Jito-enabled leader in the upcoming slots");
+            return Ok(None);
+        }
+
+        self.record_attempt(&address)?;
+        let outcome = self.liquidation_outcome(&lq_params)?;
+        // Subscribed before the transactions are sent, for their confirmation not to be missed.
+        let signatures: Vec<Signature> = transactions
+            .iter()
+            .map(|transaction| transaction.signatures[0])
+            .collect();
+        let pending_signatures = self.signature_subscriber.subscribe(&signatures);
+        let signatures = match liquidation_strategy.liquidate(
+            &lq_params,
+            &transactions,
+            &self.comms_client,
+            self.transaction_sender.as_ref(),
+        ) {
+            Ok(signatures) => signatures,
+            Err(err) => {
+                self.record_event(
+                    &address,
+                    EventOutcome::Failed,
+                    Some(&err.to_string()),
+                    &[],
+                    &lq_params.decision_accounts,
+                );
+                return Err(err);
+            }
+        };
+        trace.record(format!("sent the transactions {:?}", signatures));
+        // Sent anyway, the overrun is only recorded.
+        attempt.finish_stage(&address, LiquidationStage::Send);
+        if let LeaderTarget::Leader(slot, leader) = leader_target {
+            info!(
+                "Sent the liquidation of {} targeting the leader {} of the slot {}",
+                address, leader, slot
+            );
+        }
+        Ok(Some(SentLiquidation {
+            signatures,
+            pending_signatures,
+            decision_accounts: lq_params.decision_accounts,
+            outcome,
+        }))
+    }
+
+    /// Confirms the sent liquidations off the liquidation loop, until the loop ends and the pending ones are confirmed.
+    fn confirm_liquidations(&self, stopped: &AtomicBool) {
+        while !stopped.load(Ordering::Relaxed) {
+            if let Ok((address, sent, trace)) = self
+                .sent_liquidations
+                .1
+                .recv_timeout(CONFIRMATION_POLL_INTERVAL)
+            {
+                self.confirm_liquidation(address, sent, trace);
+            }
+        }
+        for (address, sent, trace) in self.sent_liquidations.1.try_iter() {
+            self.confirm_liquidation(address, sent, trace);
+        }
+    }
+
+    fn confirm_liquidation(
+        &self,
+        address: Pubkey,
+        sent: SentLiquidation,
+        mut trace: ExecutionTrace,
+    ) {
+        let SentLiquidation {
+            signatures,
+            pending_signatures,
+            decision_accounts,
+            outcome,
+        } = sent;
+        match pending_signatures.map(PendingSignatures::wait) {
+            Ok(statuses) => match statuses
+                .iter()
+                .find(|status| !matches!(status, SignatureStatus::Landed { .. }))
+            {
+                None => {
+                    self.record_event(
+                        &address,
+                        EventOutcome::Landed,
+                        None,
+                        &signatures,
+                        &decision_accounts,
+                    );
+                    trace.finish("landed");
+                    self.record_landing(true);
+                    self.post_liquidation_hooks.run(&outcome);
+                }
+                Some(status) => {
+                    info!("The liquidation of {} did not land: {:?}", address, status);
+                    self.record_landing(false);
+                    self.record_event(
+                        &address,
+                        EventOutcome::Failed,
+                        Some(&format!("{:?}", status)),
+                        &signatures,
+                        &decision_accounts,
+                    );
+                    trace.finish(format!("failed: {:?}", status));
+                }
+            },
+            Err(err) => {
+                error!("Failed to confirm the liquidation of {}: {}", address, err);
+                self.record_event(
+                    &address,
+                    EventOutcome::Unconfirmed,
+                    Some(&err.to_string()),
+                    &signatures,
+                    &decision_accounts,
+                );
+                trace.finish(format!("unconfirmed: {}", err));
+            }
+        }
+        if let Err(err) = self.execution_traces.push(trace) {
+            error!("Failed to keep the execution trace of {}: {}", address, err);
+        }
+    }
+
+    fn record_event(
+        &self,
+        address: &Pubkey,
+        outcome: EventOutcome,
+        detail: Option<&str>,
+        signatures: &[Signature],
+        decision_accounts: &DecisionAccounts,
+    ) {
+        if let Err(err) =
+            self.event_log
+                .record(address, outcome, detail, signatures, decision_accounts)
+        {
+            error!(
+                "Failed to record the liquidation of {} in the event log: {}",
+                address, err
+            );
+        }
+    }
+
+    // Whether the cached Banks and Oracles of the account are recent enough, relative to the account, to act on it,
+    // and whether the account reflects the latest liquidation of a competitor.
+    fn is_consistent(&self, account: &CachedMarginfiAccount) -> Result<bool> {
+        if let Some(liquidation) = self
+            .cache
+            .competitors
+            .pending_liquidation(&account.address(), account.slot())?
+        {
+            debug!(
+                "Skipping the Marginfi account {} at the slot {}: {} liquidated it at the slot {}.",
+                account.address(),
+                account.slot(),
+                liquidation.liquidator,
+                liquidation.slot
+            );
+            return Ok(false);
+        }
+        let Some(max_lag) = self.max_dependency_slot_lag else {
+            return Ok(true);
+        };
+        let Some((dependency, slot)) = self.cache.stalest_dependency(account)? else {
+            return Ok(true);
+        };
+        let lag = account.slot().saturating_sub(slot);
+        if lag > max_lag {
+            debug!(
+                "Skipping the Marginfi account {} at the slot {}: {} was last updated {} slots before.",
+                account.address(),
+                account.slot(),
+                dependency,
+                lag
+            );
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    // Simulates the transactions of the top candidates, the most underwater liquidatable accounts, up front, so
+    // that the simulations are out of the critical path of the submissions. A candidate which cannot be
+    // prepared is left to be simulated on demand.
+    fn simulate_top_candidates(&self, accounts_by_health: &HashMap<Pubkey, i64>) -> Result<()> {
+        let mut liquidatable: Vec<(i64, Pubkey)> = accounts_by_health
+            .iter()
+            .filter(|(_, health)| is_liquidatable(**health))
+            .map(|(address, health)| (*health, *address))
+            .collect();
+        liquidatable.sort_unstable();
+
+        let mut candidates = Vec::with_capacity(self.simulation_top_k);
+        let mut simulated = HashMap::with_capacity(self.simulation_top_k);
+        for (_, address) in liquidatable {
+            if candidates.len() >= self.simulation_top_k {
+                break;
+            }
+            match self.prepare_simulation(&address) {
+                Ok(Some((lq_params, transactions))) => {
+                    simulated.insert(address, (lq_params.repay_source, lq_params.liab_amount));
+                    candidates.push((address, transactions));
+                }
+                Ok(None) => {}
+                Err(err) => error!(
+                    "Failed to prepare the simulation of the Marginfi account {}: {}",
+                    address, err
+                ),
+            }
         }
+
+        let simulations: HashMap<_, _> =
+            simulate_candidates(&self.comms_client, candidates, self.simulation_concurrency)
+                .into_iter()
+                .filter_map(|(address, simulation)| {
+                    let (repay_source, liab_amount) = simulated.remove(&address)?;
+                    Some((address, (repay_source, liab_amount, simulation)))
+                })
+                .collect();
+        debug!("Simulated {} liquidation candidates.", simulations.len());
+        *self
+            .simulations
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the candidate simulations: {}", e))? = simulations;
         Ok(())
     }
-}
 
-fn sort_accounts_by_health(accounts: &HashMap<Pubkey, i64>) -> Vec<Pubkey> {
-    let mut sorted: Vec<(Pubkey, i64)> = accounts.iter().map(|(&k, &v)| (k, v)).collect();
-    sorted.sort_by(|a, b| b.1.cmp(&a.1));
-    sorted.into_iter().map(|(k, _)| k).collect()
+    // The liquidation of the candidate as repaid from the liquidator account, at the size of the preparation,
+    // with its transactions. None when the account is not to be liquidated.
+    fn prepare_simulation(
+        &self,
+        address: &Pubkey,
+    ) -> Result<Option<(LiquidationParams, Vec<VersionedTransaction>)>> {
+        let account = self.cache.marginfi_accounts.get_account(address)?;
+        if !self.is_consistent(&account)? {
+            return Ok(None);
+        }
+        let liquidation_strategy =
+            choose_liquidation_strategy(&account, &self.cache, &self.liquidator)?;
+        let Some(lq_params) = liquidation_strategy.prepare(&account)? else {
+            return Ok(None);
+        };
+        let transactions = liquidation_strategy.build_transactions(
+            &lq_params,
+            self.recent_blockhash()?,
+            self.compute_unit_price()?,
+            MAX_COMPUTE_UNIT_LIMIT,
+        )?;
+        self.validate_transactions(&transactions)?;
+        Ok(Some((lq_params, transactions)))
+    }
+
+    // Makes sure that the transactions can be expanded with the LUTs they use, fetching the LUTs that are not cached yet.
+    fn validate_transactions(&self, transactions: &[VersionedTransaction]) -> Result<()> {
+        let lut_addresses: Vec<Pubkey> = transactions
+            .iter()
+            .filter_map(|transaction| transaction.message.address_table_lookups())
+            .flatten()
+            .map(|lookup| lookup.account_key)
+            .collect();
+        self.fetch_missing_luts(&lut_addresses)?;
+
+        for transaction in transactions {
+            self.cache.luts.resolve_message(&transaction.message)?;
+        }
+        Ok(())
+    }
+
+    fn fetch_missing_luts(&self, lut_addresses: &[Pubkey]) -> Result<()> {
+        let missing_luts = self.cache.luts.missing(lut_addresses)?;
+        if !missing_luts.is_empty() {
+            for lut in self.comms_client.get_address_lookup_tables(&missing_luts)? {
+                debug!("Caching the LUT {} used by a liquidation", lut.key);
+                self.cache.luts.insert(lut)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Quotes the swap of the seized collateral into the liability mint and sets its instructions, false when the
+    // swap does not cover the liability of the flashloan, which is then skipped.
+    fn quote_collateral_swap(
+        &self,
+        lq_params: &mut LiquidationParams,
+        repay_amount: u64,
+        trace: &mut ExecutionTrace,
+    ) -> Result<bool> {
+        let swap_client = self.swap_client.as_ref().ok_or_else(|| {
+            anyhow!(
+                "Cannot liquidate {} within a flashloan without a swap client",
+                lq_params.liquidatee
+            )
+        })?;
+        let asset_mint = *self.cache.banks.get_bank(&lq_params.asset_bank)?.mint();
+        // Less a native unit, for the rounding of the withdrawn shares.
+        let quote = swap_client.quote(
+            &asset_mint,
+            &lq_params.liab_mint,
+            lq_params.asset_amount.saturating_sub(1),
+        )?;
+        trace.record(format!(
+            "collateral swap: {} of the mint {} for {} (at least {}) of the mint {}",
+            quote.in_amount,
+            asset_mint,
+            quote.out_amount,
+            quote.min_out_amount,
+            lq_params.liab_mint
+        ));
+        if quote.min_out_amount < repay_amount {
+            info!(
+                "Skipping the liquidation of {}: the collateral swap yields at least {} of the mint {}, under the {} to repay",
+                lq_params.liquidatee, quote.min_out_amount, lq_params.liab_mint, repay_amount
+            );
+            trace.finish("skipped: the collateral swap does not cover the liability");
+            return Ok(false);
+        }
+
+        let swap = swap_client.instructions(&quote, &self.liquidator.wallet.pubkey())?;
+        self.fetch_missing_luts(&swap.lookup_tables)?;
+        lq_params.collateral_swap = Some(swap);
+        Ok(true)
+    }
+
+    // The simulation of the candidate from the beginning of the cycle, when of the same repay source and
+    // liability amount as the liquidation.
+    fn cached_simulation(
+        &self,
+        address: &Pubkey,
+        lq_params: &LiquidationParams,
+    ) -> Result<Option<CandidateSimulation>> {
+        Ok(self
+            .simulations
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the candidate simulations: {}", e))?
+            .get(address)
+            .filter(|(repay_source, liab_amount, _)| {
+                *repay_source == lq_params.repay_source && *liab_amount == lq_params.liab_amount
+            })
+            .map(|(_, _, simulation)| simulation.clone()))
+    }
+
+    // The outcome of the liquidation once landed, the seized collateral costing the liability repaid for it.
+    fn liquidation_outcome(&self, lq_params: &LiquidationParams) -> Result<LiquidationOutcome> {
+        let unix_timestamp = self.cache.get_extrapolated_clock()?.unix_timestamp;
+        let asset_bank = self.cache.banks.get_bank(&lq_params.asset_bank)?;
+        let liab_bank = self.cache.banks.get_bank(&lq_params.liab_bank)?;
+        Ok(LiquidationOutcome::new(
+            lq_params,
+            *asset_bank.mint(),
+            self.cache
+                .get_value_usd(&liab_bank, lq_params.liab_amount, unix_timestamp),
+        ))
+    }
+
+    // The liquidator account borrows like any other, against its initial free collateral, which must cover the
+    // liability it takes on.
+    fn has_borrow_capacity(
+        &self,
+        lq_params: &LiquidationParams,
+        liquidator_liability: u64,
+    ) -> Result<bool> {
+        let Some(liquidator_account) = self.liquidator.account else {
+            return Ok(false);
+        };
+        let account = self
+            .cache
+            .marginfi_accounts
+            .get_account(&liquidator_account)?;
+        let unix_timestamp = self.cache.get_extrapolated_clock()?.unix_timestamp;
+        let borrowable = self
+            .cache
+            .compute_account_health(&account, unix_timestamp)
+            .zip(
+                self.cache
+                    .get_priced_bank(&lq_params.liab_bank, unix_timestamp),
+            )
+            .and_then(|(health, (liab_bank, liab_price))| {
+                borrowable_amount(health.initial.free_collateral(), &liab_bank, &liab_price)
+            });
+        Ok(match borrowable {
+            Some(borrowable) => borrowable >= liquidator_liability,
+            None => account.asset_value_maint() > account.liability_value_maint(),
+        })
+    }
+
+    // The liability the swapped collateral repays within a flashloan: the liability of the liquidator account,
+    // plus the transfer fee of the mint if any. None when the flashloans are disabled, or when the liquidator
+    // account already has a balance in the asset or the liability Bank, as the flashloan closes both.
+    fn flashloan_repay_amount(&self, lq_params: &LiquidationParams) -> Result<Option<u64>> {
+        let (true, Some(liquidator_account)) =
+            (self.liquidator.flashloans, self.liquidator.account)
+        else {
+            return Ok(None);
+        };
+        let banks = self
+            .cache
+            .marginfi_accounts
+            .get_account(&liquidator_account)?
+            .bank_addresses();
+        if banks.contains(&lq_params.asset_bank) || banks.contains(&lq_params.liab_bank) {
+            return Ok(None);
+        }
+        liquidator_liability(lq_params.liab_amount)
+            .map(|amount| self.gross_transfer_amount(&lq_params.liab_mint, amount))
+            .transpose()
+    }
+
+    // The wallet transfers of the Token-2022 mints with a transfer fee must cover the fee too.
+    fn gross_transfer_amount(&self, mint: &Pubkey, amount: u64) -> Result<u64> {
+        Ok(match self.cache.mints.get(mint)? {
+            Some(mint) => mint.gross_transfer_amount(amount, self.cache.get_clock()?.epoch),
+            None => amount,
+        })
+    }
+
+    // The blockhash is fetched again once it is older than BLOCKHASH_REFRESH_INTERVAL, well before it expires.
+    fn recent_blockhash(&self) -> Result<Hash> {
+        let mut recent_blockhash = self
+            .recent_blockhash
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the recent blockhash: {}", e))?;
+        if let Some((blockhash, fetched_at)) = *recent_blockhash {
+            if fetched_at.elapsed() < BLOCKHASH_REFRESH_INTERVAL {
+                return Ok(blockhash);
+            }
+        }
+        let blockhash = self.comms_client.get_latest_blockhash()?;
+        *recent_blockhash = Some((blockhash, Instant::now()));
+        Ok(blockhash)
+    }
+
+    // The compute unit price of the liquidations, tuned to the recent competitor fees and outcomes.
+    fn compute_unit_price(&self) -> Result<u64> {
+        self.compute_unit_price_tuner
+            .price(&self.cache.priority_fees.stats()?)
+    }
+
+    fn record_landing(&self, landed: bool) {
+        match self.compute_unit_price_tuner.record_outcome(landed) {
+            Ok(premium) => debug!(
+                "The compute unit price premium over the competitor fees is now {:.3}",
+                premium
+            ),
+            Err(err) => error!("Failed to tune the compute unit price: {}", err),
+        }
+    }
+
+    // The liquidations which cannot be estimated are not held back, a failure is only reported.
+    fn estimate_profit(
+        &self,
+        lq_params: &LiquidationParams,
+        transactions: &[VersionedTransaction],
+        trace: &mut ExecutionTrace,
+    ) -> Option<ProfitEstimate> {
+        match self.profit_estimator.estimate(lq_params, transactions) {
+            Ok(Some(estimate)) => {
+                info!(
+                    "The expected profit of the liquidation of {} is {}",
+                    lq_params.liquidatee, estimate
+                );
+                trace.record(format!("expected profit: {}", estimate));
+                if let Err(err) = self.cache.profit_estimates.record(&estimate) {
+                    error!("Failed to record the profit estimate: {}", err);
+                }
+                Some(estimate)
+            }
+            Ok(None) => {
+                debug!(
+                    "Cannot estimate the profit of the liquidation of {}: the collateral or SOL has no valid price",
+                    lq_params.liquidatee
+                );
+                None
+            }
+            Err(err) => {
+                error!(
+                    "Failed to estimate the profit of the liquidation of {}: {}",
+                    lq_params.liquidatee, err
+                );
+                None
+            }
+        }
+    }
+
+    fn refresh_leader_schedule(&self) -> Result<()> {
+        let slot = self.cache.get_clock()?.slot;
+        self.leader_tracker.refresh(&self.comms_client, slot)
+    }
+
+    fn prioritize_candidates(
+        &self,
+        accounts_by_health: &HashMap<Pubkey, i64>,
+        sorted_accounts: Vec<Pubkey>,
+    ) -> Result<Vec<Pubkey>> {
+        let now = Instant::now();
+        let mut candidate_tracker = self
+            .candidate_tracker
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the candidate tracker: {}", e))?;
+        let diff = candidate_tracker.refresh(accounts_by_health, now);
+        info!(
+            "Tracking {} liquidation candidates: {}.",
+            candidate_tracker.count(),
+            diff
+        );
+        debug!(
+            "Newly liquidatable: {:?}, resolved: {:?}",
+            diff.newly_liquidatable, diff.resolved
+        );
+        if let Err(err) = self.candidate_diff.publish(diff) {
+            error!("Failed to publish the liquidation candidates diff: {}", err);
+        }
+        let prioritized = candidate_tracker.prioritize(sorted_accounts, now);
+
+        let now_unix = now_unix();
+        let ranked = prioritized
+            .iter()
+            .filter_map(|address| {
+                let health = *accounts_by_health.get(address)?;
+                let liquidatable_for = candidate_tracker.liquidatable_for(address, now)?;
+                Some(RankedCandidate {
+                    address: *address,
+                    health,
+                    liquidatable_since_unix: now_unix.saturating_sub(liquidatable_for.as_secs()),
+                    ranked_at_unix: now_unix,
+                })
+            })
+            .collect();
+        self.cache.candidates.replace(ranked)?;
+        Ok(prioritized)
+    }
+
+    /// Processes the candidates ranked before the restart, as restored from the snapshot, ahead of the first
+    /// cycle. The health of the accounts updated since the snapshot, or priced by the Banks and Oracles updated
+    /// since, is recomputed first.
+    fn process_restored_candidates(&self, paused: bool) -> Result<()> {
+        let restored = self.cache.candidates.get()?;
+        if restored.is_empty() {
+            return Ok(());
+        }
+        let recomputed = self.cache.refresh_health()?;
+        debug!("Recomputed the health of {} accounts.", recomputed);
+        info!(
+            "Processing the {} liquidation candidates of the restored snapshot.",
+            restored.len()
+        );
+        for candidate in restored {
+            if let Err(err) = self.process_account(candidate.address, paused) {
+                error!(
+                    "Failed to process the restored liquidation candidate {}: {}",
+                    candidate.address, err
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn record_attempt(&self, address: &Pubkey) -> Result<()> {
+        let now = Instant::now();
+        let mut candidate_tracker = self
+            .candidate_tracker
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the candidate tracker: {}", e))?;
+        if let Some(waiting_time) = candidate_tracker.waiting_time(address, now) {
+            debug!(
+                "Liquidation attempted for {} after waiting {:?}",
+                address, waiting_time
+            );
+        }
+        candidate_tracker.record_attempt(address, now);
+        Ok(())
+    }
 }
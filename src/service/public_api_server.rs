@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, error, info};
+use serde_json::{json, Value};
+use tiny_http::{Method, Request, Server};
+
+use crate::{
+    cache::Cache,
+    config::Config,
+    liquidation::event_log::LiquidationEventLog,
+    service::api_server::{respond_json, RECV_TIMEOUT},
+};
+
+const DEFAULT_LIQUIDATIONS_LIMIT: usize = 20;
+const MAX_LIQUIDATIONS_LIMIT: usize = 100;
+// The most clients tracked at once, the new ones being rejected beyond it until the next eviction.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+// How often the clients with a full bucket, i.e. idle for long enough, are forgotten.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The unauthenticated read-only HTTP API for the public dashboards. It is served on its own address,
+/// apart from the admin API, and every client is rate limited.
+pub struct PublicApiServer {
+    stop: Arc<AtomicBool>,
+    server: Server,
+    cache: Arc<Cache>,
+    event_log: Arc<LiquidationEventLog>,
+    rate_limiter: RateLimiter,
+}
+
+impl PublicApiServer {
+    pub fn new(
+        config: &Config,
+        bind_address: &str,
+        stop: Arc<AtomicBool>,
+        cache: Arc<Cache>,
+        event_log: Arc<LiquidationEventLog>,
+    ) -> Result<Self> {
+        let server = Server::http(bind_address).map_err(|e| {
+            anyhow!(
+                "Failed to bind the public API server to {}: {}",
+                bind_address,
+                e
+            )
+        })?;
+        Ok(Self {
+            stop,
+            server,
+            cache,
+            event_log,
+            rate_limiter: RateLimiter::new(config.public_api_rate_limit_per_min),
+        })
+    }
+
+    pub fn run(&self) -> Result<()> {
+        info!("Entering the PublicApiServer loop.");
+        while !self.stop.load(Ordering::Relaxed) {
+            match self.server.recv_timeout(RECV_TIMEOUT) {
+                Ok(Some(request)) => self.handle(request),
+                Ok(None) => {}
+                Err(err) => error!("PublicApiServer failed to receive a request: {}", err),
+            }
+        }
+
+        info!("The PublicApiServer loop is stopped.");
+        Ok(())
+    }
+
+    fn handle(&self, request: Request) {
+        debug!("Public API request: {} {}", request.method(), request.url());
+        let allowed = match request.remote_addr() {
+            Some(address) => self.rate_limiter.allow(address.ip(), Instant::now()),
+            None => false,
+        };
+        if !allowed {
+            respond_json(request, 429, &json!({ "error": "Too many requests" }));
+            return;
+        }
+
+        let (status, body) = match self.route(request.method(), request.url()) {
+            Ok(response) => response,
+            Err(err) => {
+                error!("Failed to serve the public API request: {}", err);
+                (500, json!({ "error": "Internal error" }))
+            }
+        };
+        respond_json(request, status, &body);
+    }
+
+    fn route(&self, method: &Method, url: &str) -> Result<(u16, Value)> {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        if !matches!(
+            path,
+            "/health-distribution" | "/banks" | "/liquidations/recent"
+        ) {
+            return Ok((404, json!({ "error": "Not found" })));
+        }
+        if *method != Method::Get {
+            return Ok((405, json!({ "error": "Method not allowed" })));
+        }
+
+        let body = match path {
+            "/health-distribution" => self.health_distribution()?,
+            "/banks" => self.banks()?,
+            _ => self.recent_liquidations(query)?,
+        };
+        Ok((200, body))
+    }
+
+    fn health_distribution(&self) -> Result<Value> {
+        let distribution = self.cache.marginfi_accounts.get_health_distribution()?;
+        Ok(json!({
+            "hot": distribution.hot,
+            "warm": distribution.warm,
+            "cold": distribution.cold,
+            "invalid": distribution.invalid,
+            "total": distribution.total(),
+        }))
+    }
+
+    fn banks(&self) -> Result<Value> {
+        let mut banks = self.cache.banks.get_banks()?;
+        banks.sort_by_key(|bank| bank.address);
+        Ok(Value::Array(
+            banks
+                .iter()
+                .map(|bank| {
+                    json!({
+                        "address": bank.address.to_string(),
+                        "mint": bank.mint().to_string(),
+                        "mint_decimals": bank.mint_decimals(),
+                        "total_assets": bank.total_assets().to_num::<f64>(),
+                        "total_liabilities": bank.total_liabilities().to_num::<f64>(),
+                        "slot": bank.slot,
+                    })
+                })
+                .collect(),
+        ))
+    }
+
+    fn recent_liquidations(&self, query: &str) -> Result<Value> {
+        let limit = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("limit="))
+            .and_then(|limit| limit.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_LIQUIDATIONS_LIMIT)
+            .min(MAX_LIQUIDATIONS_LIMIT);
+        Ok(Value::Array(self.event_log.recent(limit)?))
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    // The tokens of every client, as of their latest request.
+    by_client: HashMap<IpAddr, (f64, Instant)>,
+    evicted_at: Option<Instant>,
+}
+
+/// A token bucket per client IP, refilled at the allowed number of requests per minute. Behind a reverse
+/// proxy, all the clients share the bucket of the proxy address.
+struct RateLimiter {
+    requests_per_min: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    fn new(requests_per_min: u32) -> Self {
+        Self {
+            requests_per_min: requests_per_min.max(1) as f64,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    fn allow(&self, client: IpAddr, now: Instant) -> bool {
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(err) => {
+                error!("Failed to lock the rate limiter: {}", err);
+                return false;
+            }
+        };
+        if buckets
+            .evicted_at
+            .is_none_or(|evicted_at| now.duration_since(evicted_at) >= EVICTION_INTERVAL)
+        {
+            let capacity = self.requests_per_min;
+            buckets.by_client.retain(|_, (tokens, updated_at)| {
+                *tokens + refill(capacity, now.duration_since(*updated_at).as_secs_f64()) < capacity
+            });
+            buckets.evicted_at = Some(now);
+        }
+        if buckets.by_client.len() >= MAX_TRACKED_CLIENTS
+            && !buckets.by_client.contains_key(&client)
+        {
+            return false;
+        }
+
+        let (tokens, updated_at) = buckets
+            .by_client
+            .entry(client)
+            .or_insert((self.requests_per_min, now));
+        *tokens = (*tokens
+            + refill(
+                self.requests_per_min,
+                now.duration_since(*updated_at).as_secs_f64(),
+            ))
+        .min(self.requests_per_min);
+        *updated_at = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+fn refill(requests_per_min: f64, elapsed_sec: f64) -> f64 {
+    requests_per_min * elapsed_sec / 60.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::*;
+    use crate::cache::test_util::create_dummy_cache;
+    use crate::config::test_util::create_dummy_config;
+
+    fn create_public_api_server(name: &str) -> (PublicApiServer, PathBuf) {
+        let event_log_path = std::env::temp_dir().join(format!(
+            "mary_public_api_{}_{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&event_log_path);
+        let server = PublicApiServer::new(
+            &create_dummy_config(),
+            "127.0.0.1:0",
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(create_dummy_cache()),
+            Arc::new(LiquidationEventLog::open(&event_log_path).unwrap()),
+        )
+        .unwrap();
+        (server, event_log_path)
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2);
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let other_client: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.allow(client, now));
+        assert!(limiter.allow(client, now));
+        assert!(!limiter.allow(client, now));
+        assert!(limiter.allow(other_client, now));
+        // One request is refilled every 30 seconds.
+        assert!(limiter.allow(client, now + Duration::from_secs(30)));
+        assert!(!limiter.allow(client, now + Duration::from_secs(31)));
+    }
+
+    #[test]
+    fn test_rate_limiter_caps_the_tracked_clients() {
+        let limiter = RateLimiter::new(60);
+        let now = Instant::now();
+        for client in 0..MAX_TRACKED_CLIENTS as u32 {
+            assert!(limiter.allow(IpAddr::from(client.to_be_bytes()), now));
+        }
+        let new_client: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(!limiter.allow(new_client, now));
+
+        // The clients whose bucket refilled are forgotten at the next eviction.
+        assert!(limiter.allow(new_client, now + EVICTION_INTERVAL));
+        assert_eq!(limiter.buckets.lock().unwrap().by_client.len(), 1);
+    }
+
+    #[test]
+    fn test_route_public_endpoints() {
+        let (server, event_log_path) = create_public_api_server("routes");
+
+        let (status, body) = server.route(&Method::Get, "/health-distribution").unwrap();
+        assert_eq!(status, 200);
+        assert!(body["total"].is_u64());
+
+        let (status, body) = server.route(&Method::Get, "/banks").unwrap();
+        assert_eq!(status, 200);
+        assert!(body.is_array());
+
+        let (status, body) = server
+            .route(&Method::Get, "/liquidations/recent?limit=5")
+            .unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, json!([]));
+
+        let _ = std::fs::remove_file(&event_log_path);
+    }
+
+    #[test]
+    fn test_route_no_admin_endpoints() {
+        let (server, event_log_path) = create_public_api_server("admin");
+
+        assert_eq!(server.route(&Method::Get, "/config").unwrap().0, 404);
+        assert_eq!(server.route(&Method::Post, "/banks").unwrap().0, 405);
+
+        let _ = std::fs::remove_file(&event_log_path);
+    }
+}
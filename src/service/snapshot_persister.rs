@@ -0,0 +1,161 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use crossbeam::channel::Sender;
+use log::{info, warn};
+
+use crate::{
+    cache::{
+        hydration::MarginfiAccountsHydrator, snapshot::SnapshotWriter,
+        snapshot_mirror::upload_cache_snapshot, Cache,
+    },
+    comms::CommsClient,
+    config::Config,
+};
+
+/// Persists the cache snapshots at their own interval on a dedicated thread, so that a slow disk delays
+/// neither the stats nor the stop of the main loop. The full snapshots are then uploaded to the object
+/// store, if any. The failures are sent to the error channel, for the main loop to report them.
+pub struct SnapshotPersister<T: CommsClient> {
+    stop: Arc<AtomicBool>,
+    cache: Arc<Cache>,
+    path: PathBuf,
+    writer: SnapshotWriter,
+    hydrator: Option<Arc<MarginfiAccountsHydrator<T>>>,
+    interval: Duration,
+    upload_url: Option<String>,
+    upload_token: Option<String>,
+    errors: Sender<anyhow::Error>,
+}
+
+impl<T: CommsClient> SnapshotPersister<T> {
+    pub fn new(
+        config: &Config,
+        stop: Arc<AtomicBool>,
+        cache: Arc<Cache>,
+        hydrator: Option<Arc<MarginfiAccountsHydrator<T>>>,
+        tmp_dir: PathBuf,
+        errors: Sender<anyhow::Error>,
+    ) -> Self {
+        let path = PathBuf::from(&config.cache_snapshot_path);
+        Self {
+            stop,
+            cache,
+            writer: SnapshotWriter::new(
+                path.clone(),
+                tmp_dir,
+                config.cache_snapshot_zstd_level,
+                config.cache_snapshot_full_every,
+                config.cache_snapshot_retain,
+            ),
+            path,
+            hydrator,
+            interval: Duration::from_secs(config.cache_snapshot_interval_sec),
+            upload_url: config.cache_snapshot_upload_url.clone(),
+            upload_token: config.cache_snapshot_upload_token.clone(),
+            errors,
+        }
+    }
+
+    pub fn run(&self) -> Result<()> {
+        info!("Entering the SnapshotPersister loop.");
+        let mut last_snapshot = Instant::now();
+        while !self.stop.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_secs(1));
+            if last_snapshot.elapsed() < self.interval {
+                continue;
+            }
+            if let Err(err) = self.persist() {
+                // The main loop is gone once the receiver is dropped, so is the error.
+                let _ = self.errors.send(err);
+            }
+            last_snapshot = Instant::now();
+        }
+
+        info!("The SnapshotPersister loop is stopped.");
+        Ok(())
+    }
+
+    /// Persists a snapshot unless the cache is being hydrated: it would miss the accounts not hydrated yet.
+    /// The deltas are not uploaded, the uploaded snapshot lagging by up to a full snapshot interval.
+    pub fn persist(&self) -> Result<()> {
+        if self.is_hydrating() {
+            return Ok(());
+        }
+        if self.writer.persist(&self.cache)? {
+            if let Some(upload_url) = &self.upload_url {
+                upload_cache_snapshot(upload_url, self.upload_token.as_deref(), &self.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_hydrating(&self) -> bool {
+        self.hydrator.as_ref().is_some_and(|hydrator| {
+            hydrator.is_hydrating().unwrap_or_else(|err| {
+                warn!("Failed to check the hydration of the cache: {}", err);
+                true
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crossbeam::channel;
+
+    use super::*;
+    use crate::{
+        cache::{snapshot::restore_cache_snapshot, test_util::create_dummy_cache},
+        comms::test_util::MockedCommsClient,
+        config::test_util::create_dummy_config,
+    };
+
+    #[test]
+    fn test_snapshot_persister() {
+        let dir =
+            std::env::temp_dir().join(format!("mary_snapshot_persister_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.bin");
+        let (errors_tx, errors_rx) = channel::unbounded();
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut config = create_dummy_config();
+        config.cache_snapshot_path = path.to_string_lossy().into_owned();
+        config.cache_snapshot_interval_sec = 0;
+        let persister = Arc::new(SnapshotPersister::<MockedCommsClient>::new(
+            &config,
+            stop.clone(),
+            Arc::new(create_dummy_cache()),
+            None,
+            dir.clone(),
+            errors_tx,
+        ));
+
+        let handle = {
+            let persister = persister.clone();
+            thread::spawn(move || persister.run())
+        };
+        while !path.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap().unwrap();
+        assert!(restore_cache_snapshot(&create_dummy_cache(), &path, None).unwrap());
+        assert!(errors_rx.try_recv().is_err());
+
+        // E.g. the snapshot directory is gone.
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(persister.persist().is_err());
+    }
+}